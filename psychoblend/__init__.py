@@ -105,7 +105,7 @@ class PsychopathMesh(bpy.types.PropertyGroup):
 class PsychopathMaterial(bpy.types.PropertyGroup):
     surface_shader_type = EnumProperty(
         name="Surface Shader Type", description="",
-        items=[('Emit', 'Emit', ""), ('Lambert', 'Lambert', ""), ('GGX', 'GGX', "")],
+        items=[('Emit', 'Emit', ""), ('Lambert', 'Lambert', ""), ('GGX', 'GGX', ""), ('Hair', 'Hair', "")],
         default="Lambert"
         )
 
@@ -146,6 +146,21 @@ class PsychopathMaterial(bpy.types.PropertyGroup):
         min=0.0, max=1.0, soft_min=0.0, soft_max=1.0, default=0.9
         )
 
+    melanin = FloatProperty(
+        name="Melanin", description="Amount of melanin in the hair, from white to black",
+        min=0.0, max=1.0, default=0.5
+        )
+
+    melanin_redness = FloatProperty(
+        name="Melanin Redness", description="Fraction of the melanin that's the red pheomelanin",
+        min=0.0, max=1.0, default=0.0
+        )
+
+    azimuthal_roughness = FloatProperty(
+        name="Azimuthal Roughness", description="Roughness around the hair, as opposed to along it",
+        min=0.0, max=1.0, default=0.3
+        )
+
 
 # Addon Preferences
 class PsychopathPreferences(AddonPreferences):
@@ -197,6 +197,38 @@ class Mesh:
         w.write(" ".join([("%d"%v) for p in self.time_meshes[0].polygons for v in p.vertices]), False)
         w.write("]\n", False)
 
+        # Write texture coordinates, if any
+        uv_layer = self.time_meshes[0].uv_layers.active
+        if uv_layer != None and self.ob.data.psychopath.is_subdivision_surface == False:
+            w.write("Primvar $uv {\n")
+            w.indent()
+            w.write("Type [float2]\n")
+            w.write("Interpolation [facevarying]\n")
+            w.write("Values [")
+            w.write(" ".join([("%f" % i) for p in self.time_meshes[0].polygons for li in p.loop_indices for i in uv_layer.data[li].uv]), False)
+            w.write("]\n", False)
+            w.unindent()
+            w.write("}\n")
+
+        # Write face groups, for meshes with more than one material.  Faces
+        # of the first material use the one bound to the instance.
+        if self.ob.data.psychopath.is_subdivision_surface == False:
+            for mi in range(1, len(self.ob.material_slots)):
+                ms = self.ob.material_slots[mi]
+                if ms == None or ms.material == None:
+                    continue
+                faces = [p.index for p in self.time_meshes[0].polygons if p.material_index == mi]
+                if len(faces) == 0:
+                    continue
+                w.write("FaceGroup {\n")
+                w.indent()
+                w.write("SurfaceShaderBind [$%s]\n" % escape_name(ms.material.name))
+                w.write("Faces [")
+                w.write(" ".join([("%d" % i) for i in faces]), False)
+                w.write("]\n", False)
+                w.unindent()
+                w.write("}\n")
+
         # MeshSurface/SubdivisionSurface section end
         w.unindent()
         w.write("}\n")
@@ -389,6 +421,12 @@ class Material:
                 ))
             w.write("Roughness [%f]\n" % self.mat.psychopath.roughness)
             w.write("Fresnel [%f]\n" % self.mat.psychopath.fresnel)
+        elif self.mat.psychopath.surface_shader_type == 'Hair':
+            w.write("Type [Hair]\n")
+            w.write("Melanin [%f]\n" % self.mat.psychopath.melanin)
+            w.write("MelaninRedness [%f]\n" % self.mat.psychopath.melanin_redness)
+            w.write("Roughness [%f]\n" % min(max(self.mat.psychopath.roughness, 0.0), 1.0))
+            w.write("AzimuthalRoughness [%f]\n" % self.mat.psychopath.azimuthal_roughness)
         else:
             raise "Unsupported surface shader type '%s'" % self.mat.psychopath.surface_shader_type
         w.unindent()
@@ -251,11 +251,12 @@ class MATERIAL_PT_psychopath_surface(PsychopathPanel, bpy.types.Panel):
         mat = context.material
         col.prop(mat.psychopath, "surface_shader_type")
 
-        col.prop(mat.psychopath, "color_type")
-        if mat.psychopath.color_type == 'Rec709':
-            col.prop(mat.psychopath, "color")
-        elif mat.psychopath.color_type == 'Blackbody' or mat.psychopath.color_type == 'ColorTemperature':
-            col.prop(mat.psychopath, "color_blackbody_temp")
+        if mat.psychopath.surface_shader_type != 'Hair':
+            col.prop(mat.psychopath, "color_type")
+            if mat.psychopath.color_type == 'Rec709':
+                col.prop(mat.psychopath, "color")
+            elif mat.psychopath.color_type == 'Blackbody' or mat.psychopath.color_type == 'ColorTemperature':
+                col.prop(mat.psychopath, "color_blackbody_temp")
 
         if mat.psychopath.surface_shader_type == 'GTR':
             layout.prop(mat.psychopath, "roughness")
@@ -266,6 +267,12 @@ class MATERIAL_PT_psychopath_surface(PsychopathPanel, bpy.types.Panel):
             layout.prop(mat.psychopath, "roughness")
             layout.prop(mat.psychopath, "fresnel")
 
+        if mat.psychopath.surface_shader_type == 'Hair':
+            layout.prop(mat.psychopath, "melanin")
+            layout.prop(mat.psychopath, "melanin_redness")
+            layout.prop(mat.psychopath, "roughness")
+            layout.prop(mat.psychopath, "azimuthal_roughness")
+
 
 def register():
     bpy.utils.register_class(RENDER_PT_psychopath_render_settings)
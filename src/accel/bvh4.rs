@@ -20,7 +20,7 @@ use crate::{
 };
 
 use super::{
-    bvh_base::{BVHBase, BVHBaseNode, BVH_MAX_DEPTH},
+    bvh_base::{BVHBase, BVHBaseNode, BVHStats, BVH_MAX_DEPTH},
     ACCEL_NODE_RAY_TESTS,
 };
 
@@ -38,6 +38,7 @@ pub struct BVH4<'a> {
     root: Option<&'a BVH4Node<'a>>,
     depth: usize,
     node_count: usize,
+    stats: BVHStats,
     _bounds: Option<&'a [BBox]>,
 }
 
@@ -69,10 +70,12 @@ impl<'a> BVH4<'a> {
                 root: None,
                 depth: 0,
                 node_count: 0,
+                stats: BVHStats::default(),
                 _bounds: None,
             }
         } else {
-            let base = BVHBase::from_objects(objects, objects_per_leaf, bounder);
+            let base =
+                BVHBase::from_objects(objects, super::objects_per_leaf(objects_per_leaf), bounder);
 
             let fill_node = arena.alloc_align_uninit::<BVH4Node>(32);
             let node_count = BVH4::construct_from_base(
@@ -86,6 +89,7 @@ impl<'a> BVH4<'a> {
                 root: Some(unsafe { transmute(fill_node) }),
                 depth: (base.depth / 2) + 1,
                 node_count: node_count,
+                stats: base.stats(),
                 _bounds: {
                     let range = base.nodes[base.root_node_index()].bounds_range();
                     Some(arena.copy_slice(&base.bounds[range.0..range.1]))
@@ -98,7 +102,54 @@ impl<'a> BVH4<'a> {
         self.depth
     }
 
-    pub fn traverse<F>(&self, rays: &mut RayBatch, ray_stack: &mut RayStack, mut obj_ray_test: F)
+    /// Quality metrics of the binary tree the BVH was collapsed from.
+    pub fn stats(&self) -> BVHStats {
+        self.stats
+    }
+
+    /// The approximate memory used by the BVH's nodes.
+    pub fn size_in_bytes(&self) -> usize {
+        self.node_count * (std::mem::size_of::<BVH4Node>() + std::mem::size_of::<BBox4>())
+    }
+
+    /// Traverses the rays of the next task on `ray_stack` through the
+    /// BVH, calling `obj_ray_test` with the range of objects in each leaf
+    /// that some of the rays reach.
+    ///
+    /// Dispatched to a copy compiled for the best SIMD instruction set the
+    /// CPU supports.  `obj_ray_test` is usually inlined into it, so the
+    /// objects' intersection tests get compiled for it too.
+    pub fn traverse<F>(&self, rays: &mut RayBatch, ray_stack: &mut RayStack, obj_ray_test: F)
+    where
+        F: FnMut(std::ops::Range<usize>, &mut RayBatch, &mut RayStack),
+    {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if crate::cpu::simd_level().has_avx2() {
+                // SAFETY: the CPU supports AVX2.
+                unsafe { self.traverse_avx2(rays, ray_stack, obj_ray_test) };
+                return;
+            }
+        }
+
+        self.traverse_impl(rays, ray_stack, obj_ray_test);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn traverse_avx2<F>(
+        &self,
+        rays: &mut RayBatch,
+        ray_stack: &mut RayStack,
+        obj_ray_test: F,
+    ) where
+        F: FnMut(std::ops::Range<usize>, &mut RayBatch, &mut RayStack),
+    {
+        self.traverse_impl(rays, ray_stack, obj_ray_test);
+    }
+
+    #[inline(always)]
+    fn traverse_impl<F>(&self, rays: &mut RayBatch, ray_stack: &mut RayStack, mut obj_ray_test: F)
     where
         F: FnMut(std::ops::Range<usize>, &mut RayBatch, &mut RayStack),
     {
@@ -20,6 +20,26 @@ pub struct BVHBase {
     bounds_cache: Vec<BBox>,
 }
 
+/// Quality metrics of a built BVH, for diagnosing slow traversal.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BVHStats {
+    /// Expected number of node and object tests for a ray that hits the
+    /// root, by the surface area heuristic.  Lower is better.
+    pub sah_cost: f32,
+    pub leaf_count: usize,
+    pub object_count: usize,
+    pub max_depth: usize,
+    /// How much sibling nodes overlap, as the average fraction of their
+    /// parent's surface area that the overlap covers.
+    pub overlap: f32,
+}
+
+impl BVHStats {
+    pub fn average_leaf_size(&self) -> f32 {
+        self.object_count as f32 / self.leaf_count.max(1) as f32
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum BVHBaseNode {
     Internal {
@@ -67,6 +87,62 @@ impl BVHBase {
         0
     }
 
+    /// Computes quality metrics of the tree.
+    pub fn stats(&self) -> BVHStats {
+        let node_bounds = |node: &BVHBaseNode| {
+            let range = node.bounds_range();
+            self.bounds[range.0..range.1]
+                .iter()
+                .fold(BBox::new(), |b1, b2| b1 | *b2)
+        };
+
+        let mut stats = BVHStats {
+            max_depth: self.depth,
+            ..BVHStats::default()
+        };
+        if self.nodes.is_empty() {
+            return stats;
+        }
+
+        let root_area = node_bounds(&self.nodes[self.root_node_index()]).surface_area();
+        let mut internal_count = 0;
+        for node in &self.nodes {
+            let bounds = node_bounds(node);
+            let area = bounds.surface_area();
+            let hit_chance = if root_area > 0.0 {
+                area / root_area
+            } else {
+                1.0
+            };
+            match *node {
+                BVHBaseNode::Internal {
+                    children_indices, ..
+                } => {
+                    stats.sah_cost += hit_chance;
+                    internal_count += 1;
+                    if area > 0.0 {
+                        let overlap = overlap_area(
+                            node_bounds(&self.nodes[children_indices.0]),
+                            node_bounds(&self.nodes[children_indices.1]),
+                        );
+                        stats.overlap += overlap / area;
+                    }
+                }
+                BVHBaseNode::Leaf { object_range, .. } => {
+                    let count = object_range.1 - object_range.0;
+                    stats.sah_cost += hit_chance * count as f32;
+                    stats.leaf_count += 1;
+                    stats.object_count += count;
+                }
+            }
+        }
+        if internal_count > 0 {
+            stats.overlap /= internal_count as f32;
+        }
+
+        stats
+    }
+
     fn acc_bounds<'a, T, F>(&mut self, objects: &mut [T], bounder: &F)
     where
         F: 'a + Fn(&T) -> &'a [BBox],
@@ -214,3 +290,51 @@ impl BVHBase {
         }
     }
 }
+
+/// The surface area of the intersection of two boxes.
+fn overlap_area(b1: BBox, b2: BBox) -> f32 {
+    let min = b1.min.max(b2.min);
+    let max = b1.max.min(b2.max);
+    if max.x() < min.x() || max.y() < min.y() || max.z() < min.z() {
+        0.0
+    } else {
+        BBox::from_points(min, max).surface_area()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::Point;
+
+    #[test]
+    fn stats_of_separate_and_overlapping_objects() {
+        let unit_box =
+            |x: f32| BBox::from_points(Point::new(x, 0.0, 0.0), Point::new(x + 1.0, 1.0, 1.0));
+        let stats = |boxes: &[BBox], objects_per_leaf: usize| {
+            let mut objects: Vec<usize> = (0..boxes.len()).collect();
+            BVHBase::from_objects(&mut objects[..], objects_per_leaf, |&i| &boxes[i..(i + 1)])
+                .stats()
+        };
+
+        // Two boxes far apart: one split, no overlap, and each leaf is
+        // hit by a small fraction of the rays that hit the root.
+        let far_apart = stats(&[unit_box(0.0), unit_box(10.0)], 1);
+        assert_eq!(far_apart.leaf_count, 2);
+        assert_eq!(far_apart.object_count, 2);
+        assert_eq!(far_apart.max_depth, 1);
+        assert_eq!(far_apart.overlap, 0.0);
+        assert!(far_apart.sah_cost > 1.0 && far_apart.sah_cost < 1.5);
+
+        // The same box twice can't be split usefully.
+        let same = stats(&[unit_box(0.0), unit_box(0.0)], 1);
+        assert_eq!(same.overlap, 1.0);
+        assert!((same.sah_cost - 3.0).abs() < 1.0e-5);
+
+        // Both in one leaf.
+        let one_leaf = stats(&[unit_box(0.0), unit_box(0.0)], 2);
+        assert_eq!(one_leaf.leaf_count, 1);
+        assert_eq!(one_leaf.average_leaf_size(), 2.0);
+        assert!((one_leaf.sah_cost - 2.0).abs() < 1.0e-5);
+    }
+}
@@ -0,0 +1,394 @@
+//! A BVH for curve segments, with oriented bounding boxes.
+//!
+//! Hair strands are long, thin, and rarely aligned with the coordinate
+//! axes, so axis-aligned boxes around them are mostly empty space.  The
+//! nodes of this BVH are instead boxes aligned with the average direction
+//! of the segments they contain, which for neighboring hairs is usually
+//! close to the direction of each of them.
+
+#![allow(dead_code)]
+
+use kioku::Arena;
+
+use crate::{
+    algorithm::quick_select,
+    bbox::BBox,
+    boundable::Boundable,
+    lerp::Lerp,
+    math::{coordinate_system_from_vector, dot, Point, Vector},
+    ray::RayBatch,
+};
+
+use super::{bvh_base::BVH_MAX_DEPTH, ACCEL_NODE_RAY_TESTS};
+
+/// A bounding box oriented along an arbitrary set of orthonormal axes.
+#[derive(Copy, Clone, Debug)]
+pub struct OrientedBBox {
+    axes: [Vector; 3],
+    bbox: BBox, // In the coordinates of `axes`
+}
+
+impl OrientedBBox {
+    /// Creates the box that tightly bounds the given segments, oriented
+    /// along their average direction.
+    ///
+    /// Each segment is given as its two end points and its largest radius.
+    /// If the segments don't share a direction well enough for that to
+    /// help, the box is axis-aligned instead.
+    pub fn from_segments(segments: &[(Point, Point, f32)]) -> OrientedBBox {
+        let aabb = OrientedBBox::with_axes(
+            [
+                Vector::new(1.0, 0.0, 0.0),
+                Vector::new(0.0, 1.0, 0.0),
+                Vector::new(0.0, 0.0, 1.0),
+            ],
+            segments,
+        );
+
+        // Average direction, with the segments flipped to agree with the
+        // first one, since a segment's direction along its strand
+        // doesn't matter for bounding.
+        let mut dir_sum = Vector::new(0.0, 0.0, 0.0);
+        let mut first = None;
+        for seg in segments {
+            let d = seg.1 - seg.0;
+            if d.length2() <= 0.0 {
+                continue;
+            }
+            let d = d.normalized();
+            let first = *first.get_or_insert(d);
+            dir_sum = dir_sum + if dot(d, first) < 0.0 { d * -1.0 } else { d };
+        }
+        if dir_sum.length2() <= 0.0 {
+            return aabb;
+        }
+
+        let (a0, a1, a2) = coordinate_system_from_vector(dir_sum.normalized());
+        let obb = OrientedBBox::with_axes([a0, a1, a2], segments);
+
+        if obb.bbox.surface_area() < aabb.bbox.surface_area() {
+            obb
+        } else {
+            aabb
+        }
+    }
+
+    fn with_axes(axes: [Vector; 3], segments: &[(Point, Point, f32)]) -> OrientedBBox {
+        let mut obb = OrientedBBox {
+            axes: axes,
+            bbox: BBox::new(),
+        };
+        for seg in segments {
+            let p0 = obb.local_point(seg.0);
+            let p1 = obb.local_point(seg.1);
+            let r = Vector::new(seg.2, seg.2, seg.2);
+            obb.bbox.min = obb.bbox.min.min(p0.min(p1) - r);
+            obb.bbox.max = obb.bbox.max.max(p0.max(p1) + r);
+        }
+        obb
+    }
+
+    /// Transforms a point into the box's coordinates.
+    #[inline(always)]
+    fn local_point(&self, p: Point) -> Point {
+        let v = p.into_vector();
+        Point::new(
+            dot(v, self.axes[0]),
+            dot(v, self.axes[1]),
+            dot(v, self.axes[2]),
+        )
+    }
+
+    /// Returns whether the given ray intersects with the box.
+    #[inline(always)]
+    pub fn intersect_ray(&self, orig: Point, dir: Vector, max_t: f32) -> bool {
+        let dir_local = Vector::new(
+            dot(dir, self.axes[0]),
+            dot(dir, self.axes[1]),
+            dot(dir, self.axes[2]),
+        );
+        self.bbox.intersect_ray(
+            self.local_point(orig),
+            Vector {
+                co: dir_local.co.reciprocal(),
+            },
+            max_t,
+        )
+    }
+
+    /// The axis-aligned box that bounds this box.
+    pub fn bounds(&self) -> BBox {
+        let mut b = BBox::new();
+        for i in 0..8 {
+            let c = Vector::new(
+                if i & 1 == 0 {
+                    self.bbox.min.x()
+                } else {
+                    self.bbox.max.x()
+                },
+                if i & 2 == 0 {
+                    self.bbox.min.y()
+                } else {
+                    self.bbox.max.y()
+                },
+                if i & 4 == 0 {
+                    self.bbox.min.z()
+                } else {
+                    self.bbox.max.z()
+                },
+            );
+            let p =
+                (self.axes[0] * c.x() + self.axes[1] * c.y() + self.axes[2] * c.z()).into_point();
+            b.min = b.min.min(p);
+            b.max = b.max.max(p);
+        }
+        b
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CurveBVH<'a> {
+    nodes: &'a [CurveBVHNode],
+    bounds: &'a [BBox],
+    depth: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum CurveBVHNode {
+    // The first child immediately follows its parent.
+    Internal {
+        obb: OrientedBBox,
+        second_child: usize,
+    },
+
+    Leaf {
+        obb: OrientedBBox,
+        object_range: (usize, usize),
+    },
+}
+
+impl<'a> CurveBVH<'a> {
+    /// Builds a BVH over curve segments.
+    ///
+    /// `segment` returns the end points and largest radius of an object's
+    /// segment.  The objects are reordered to match the BVH's leaves.
+    pub fn from_objects<T, F>(
+        arena: &'a Arena,
+        objects: &mut [T],
+        objects_per_leaf: usize,
+        segment: F,
+    ) -> CurveBVH<'a>
+    where
+        F: Fn(&T) -> (Point, Point, f32),
+    {
+        if objects.is_empty() {
+            return CurveBVH {
+                nodes: &[],
+                bounds: &[],
+                depth: 0,
+            };
+        }
+
+        let mut nodes = Vec::new();
+        let objects_per_leaf = super::objects_per_leaf(objects_per_leaf);
+        let depth = recursive_build(&mut nodes, 0, 0, objects_per_leaf, objects, &segment);
+
+        let bounds = match nodes[0] {
+            CurveBVHNode::Internal { obb, .. } | CurveBVHNode::Leaf { obb, .. } => obb.bounds(),
+        };
+
+        CurveBVH {
+            nodes: arena.copy_slice(&nodes),
+            bounds: arena.copy_slice(&[bounds]),
+            depth: depth,
+        }
+    }
+
+    pub fn tree_depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The memory used by the BVH's nodes.
+    pub fn size_in_bytes(&self) -> usize {
+        std::mem::size_of_val(self.nodes)
+    }
+
+    /// Traverses the BVH with a single ray, given in the BVH's space.
+    ///
+    /// `obj_ray_test` is called with the range of objects in each leaf the
+    /// ray reaches.  It's expected to shorten the ray's max t on hits, and
+    /// to mark the ray done if it should stop traversal.
+    pub fn traverse<F>(
+        &self,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+        orig: Point,
+        dir: Vector,
+        mut obj_ray_test: F,
+    ) where
+        F: FnMut(std::ops::Range<usize>, &mut RayBatch),
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut node_tests: u64 = 0;
+        let mut node_stack = [0usize; BVH_MAX_DEPTH + 2];
+        let mut stack_ptr = 1;
+
+        while stack_ptr > 0 && !rays.is_done(ray_idx) {
+            stack_ptr -= 1;
+            let node_i = node_stack[stack_ptr];
+            node_tests += 1;
+            match self.nodes[node_i] {
+                CurveBVHNode::Internal { obb, second_child } => {
+                    if obb.intersect_ray(orig, dir, rays.max_t(ray_idx)) {
+                        node_stack[stack_ptr] = second_child;
+                        node_stack[stack_ptr + 1] = node_i + 1;
+                        stack_ptr += 2;
+                    }
+                }
+
+                CurveBVHNode::Leaf { obb, object_range } => {
+                    if obb.intersect_ray(orig, dir, rays.max_t(ray_idx)) {
+                        obj_ray_test(object_range.0..object_range.1, rays);
+                    }
+                }
+            }
+        }
+
+        ACCEL_NODE_RAY_TESTS.with(|anv| {
+            let n = anv.get();
+            anv.set(n + node_tests);
+        });
+    }
+}
+
+impl<'a> Boundable for CurveBVH<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.bounds
+    }
+}
+
+/// Builds the nodes for `objects`, appending them to `nodes`.
+///
+/// Returns the depth of the deepest leaf.
+fn recursive_build<T, F>(
+    nodes: &mut Vec<CurveBVHNode>,
+    offset: usize,
+    depth: usize,
+    objects_per_leaf: usize,
+    objects: &mut [T],
+    segment: &F,
+) -> usize
+where
+    F: Fn(&T) -> (Point, Point, f32),
+{
+    let segments: Vec<_> = objects.iter().map(segment).collect();
+    let obb = OrientedBBox::from_segments(&segments);
+
+    if objects.len() <= objects_per_leaf || depth >= BVH_MAX_DEPTH {
+        nodes.push(CurveBVHNode::Leaf {
+            obb: obb,
+            object_range: (offset, offset + objects.len()),
+        });
+        return depth;
+    }
+
+    // Split at the median of the segment centers, along the axis of the
+    // box that they're most spread out on.
+    let center = |obj: &T| {
+        let seg = segment(obj);
+        obb.local_point(seg.0.lerp(seg.1, 0.5))
+    };
+    let split_axis = {
+        let mut cb = BBox::new();
+        for obj in objects.iter() {
+            let c = center(obj);
+            cb.min = cb.min.min(c);
+            cb.max = cb.max.max(c);
+        }
+        let d = cb.max - cb.min;
+        if d.x() >= d.y() && d.x() >= d.z() {
+            0
+        } else if d.y() >= d.z() {
+            1
+        } else {
+            2
+        }
+    };
+    let split_index = objects.len() / 2;
+    quick_select(objects, split_index, |a, b| {
+        center(a)
+            .get_n(split_axis)
+            .partial_cmp(&center(b).get_n(split_axis))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let me = nodes.len();
+    nodes.push(CurveBVHNode::Internal {
+        obb: obb,
+        second_child: 0,
+    });
+    let (objects1, objects2) = objects.split_at_mut(split_index);
+    let depth1 = recursive_build(
+        nodes,
+        offset,
+        depth + 1,
+        objects_per_leaf,
+        objects1,
+        segment,
+    );
+    let second_child = nodes.len();
+    let depth2 = recursive_build(
+        nodes,
+        offset + split_index,
+        depth + 1,
+        objects_per_leaf,
+        objects2,
+        segment,
+    );
+    nodes[me] = CurveBVHNode::Internal {
+        obb: obb,
+        second_child: second_child,
+    };
+
+    depth1.max(depth2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn obb_follows_diagonal_segments() {
+        let segments = [
+            (Point::new(0.0, 0.0, 0.0), Point::new(1.0, 1.0, 1.0), 0.01),
+            (Point::new(0.1, 0.0, 0.0), Point::new(1.1, 1.0, 1.0), 0.01),
+        ];
+        let obb = OrientedBBox::from_segments(&segments);
+        let aabb = obb.bounds();
+
+        // Much tighter than the axis-aligned box, which contains it.
+        assert!(obb.bbox.surface_area() < aabb.surface_area() * 0.25);
+        assert!(aabb.min.x() <= -0.01 && aabb.max.x() >= 1.11);
+
+        // Hits along the segments, but not in the empty corners.
+        let dir = Vector::new(0.0, 0.0, 1.0);
+        assert!(obb.intersect_ray(Point::new(0.5, 0.5, -1.0), dir, 10.0));
+        assert!(!obb.intersect_ray(Point::new(0.9, 0.1, -1.0), dir, 10.0));
+        assert!(!obb.intersect_ray(Point::new(0.5, 0.5, -1.0), dir, 1.0));
+    }
+
+    #[test]
+    fn obb_falls_back_to_axis_aligned() {
+        let segments = [
+            (Point::new(0.0, 0.0, 0.0), Point::new(1.0, 0.0, 0.0), 0.1),
+            (Point::new(0.0, 0.0, 0.0), Point::new(0.0, 1.0, 0.0), 0.1),
+        ];
+        let obb = OrientedBBox::from_segments(&segments);
+        assert_eq!(obb.axes[0].x(), 1.0);
+        assert_eq!(obb.axes[1].y(), 1.0);
+        assert_eq!(obb.axes[2].z(), 1.0);
+    }
+}
@@ -0,0 +1,281 @@
+#![allow(dead_code)]
+
+use std::cmp::Ordering;
+
+use kioku::Arena;
+
+use crate::{algorithm::quick_select, math::Point};
+
+/// A balanced k-d tree of points, for finding the points nearest to a
+/// position, such as the records of a cache near a shading point.
+///
+/// The tree is implicit: the nodes are stored in a single slice, with
+/// each node in the middle of its subtree's range and its two children's
+/// subtrees before and after it.  Since each node is the median of its
+/// subtree, the tree is always balanced.
+#[derive(Copy, Clone, Debug)]
+pub struct KdTree<'a> {
+    nodes: &'a [KdNode],
+}
+
+#[derive(Copy, Clone, Debug)]
+struct KdNode {
+    pos: Point,
+    index: u32, // Index of the object the point is from
+    axis: u8,   // Axis that the node splits its subtrees on
+}
+
+impl<'a> KdTree<'a> {
+    /// Builds a k-d tree of `objects`, where `position` gives the point of
+    /// each.  Queries return the indices of the objects in the slice.
+    pub fn from_objects<T, F>(arena: &'a Arena, objects: &[T], position: F) -> KdTree<'a>
+    where
+        F: Fn(&T) -> Point,
+    {
+        assert!(objects.len() <= std::u32::MAX as usize);
+        let mut nodes: Vec<KdNode> = objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| KdNode {
+                pos: position(object),
+                index: i as u32,
+                axis: 0,
+            })
+            .collect();
+        build(&mut nodes);
+
+        KdTree {
+            nodes: arena.copy_slice(&nodes),
+        }
+    }
+
+    /// The number of points in the tree.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns the index and squared distance of the point nearest to
+    /// `pos`, or None if the tree is empty.
+    pub fn nearest(&self, pos: Point) -> Option<(usize, f32)> {
+        let mut found = Vec::with_capacity(1);
+        self.k_nearest(pos, 1, std::f32::INFINITY, &mut found);
+        found.first().cloned()
+    }
+
+    /// Finds the (up to) `k` points nearest to `pos` that are within
+    /// `max_radius` of it.
+    ///
+    /// `found` is filled with their indices and squared distances, nearest
+    /// first.
+    pub fn k_nearest(&self, pos: Point, k: usize, max_radius: f32, found: &mut Vec<(usize, f32)>) {
+        found.clear();
+        if k > 0 {
+            k_nearest(self.nodes, pos, k, max_radius * max_radius, found);
+        }
+    }
+
+    /// Calls `f` with the index and squared distance of every point within
+    /// `radius` of `pos`, in no particular order.
+    pub fn for_each_within<F>(&self, pos: Point, radius: f32, mut f: F)
+    where
+        F: FnMut(usize, f32),
+    {
+        for_each_within(self.nodes, pos, radius * radius, &mut f);
+    }
+}
+
+fn build(nodes: &mut [KdNode]) {
+    if nodes.len() <= 1 {
+        return;
+    }
+
+    // Split on the axis that the points are most spread out along.
+    let (min, max) = nodes
+        .iter()
+        .fold((nodes[0].pos, nodes[0].pos), |(min, max), node| {
+            (min.min(node.pos), max.max(node.pos))
+        });
+    let extent = max - min;
+    let axis = if extent.x() >= extent.y() && extent.x() >= extent.z() {
+        0
+    } else if extent.y() >= extent.z() {
+        1
+    } else {
+        2
+    };
+
+    let mid = nodes.len() / 2;
+    quick_select(nodes, mid, |a, b| {
+        a.pos
+            .get_n(axis)
+            .partial_cmp(&b.pos.get_n(axis))
+            .unwrap_or(Ordering::Equal)
+    });
+    nodes[mid].axis = axis as u8;
+
+    let (before, after) = nodes.split_at_mut(mid);
+    build(before);
+    build(&mut after[1..]);
+}
+
+/// The node in the middle of `nodes`, and the subtree on the side of it
+/// that `pos` is on followed by the one on the other side.  Also returns
+/// the distance from `pos` to the node's splitting plane.
+fn split(nodes: &[KdNode], pos: Point) -> (&KdNode, &[KdNode], &[KdNode], f32) {
+    let mid = nodes.len() / 2;
+    let node = &nodes[mid];
+    let axis = node.axis as usize;
+    let plane_dist = pos.get_n(axis) - node.pos.get_n(axis);
+    if plane_dist < 0.0 {
+        (node, &nodes[..mid], &nodes[(mid + 1)..], plane_dist)
+    } else {
+        (node, &nodes[(mid + 1)..], &nodes[..mid], plane_dist)
+    }
+}
+
+fn k_nearest(nodes: &[KdNode], pos: Point, k: usize, radius2: f32, found: &mut Vec<(usize, f32)>) {
+    if nodes.is_empty() {
+        return;
+    }
+    let (node, near, far, plane_dist) = split(nodes, pos);
+
+    k_nearest(near, pos, k, radius2, found);
+
+    // The distance that a point must be within to be one of the nearest.
+    let bound = |found: &Vec<(usize, f32)>| {
+        if found.len() < k {
+            radius2
+        } else {
+            found[k - 1].1
+        }
+    };
+
+    let dist2 = (node.pos - pos).length2();
+    if dist2 <= bound(found) {
+        let i = found
+            .iter()
+            .position(|&(_, d)| d > dist2)
+            .unwrap_or(found.len());
+        found.insert(i, (node.index as usize, dist2));
+        found.truncate(k);
+    }
+
+    if plane_dist * plane_dist <= bound(found) {
+        k_nearest(far, pos, k, radius2, found);
+    }
+}
+
+fn for_each_within<F>(nodes: &[KdNode], pos: Point, radius2: f32, f: &mut F)
+where
+    F: FnMut(usize, f32),
+{
+    if nodes.is_empty() {
+        return;
+    }
+    let (node, near, far, plane_dist) = split(nodes, pos);
+
+    for_each_within(near, pos, radius2, f);
+    let dist2 = (node.pos - pos).length2();
+    if dist2 <= radius2 {
+        f(node.index as usize, dist2);
+    }
+    if plane_dist * plane_dist <= radius2 {
+        for_each_within(far, pos, radius2, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::hash_u32_to_f32;
+
+    fn random_point(i: u32, seed: u32) -> Point {
+        Point::new(
+            hash_u32_to_f32(i, seed) * 4.0 - 2.0,
+            hash_u32_to_f32(i, seed + 1) * 2.0 - 1.0,
+            hash_u32_to_f32(i, seed + 2) * 0.5,
+        )
+    }
+
+    fn brute_force(points: &[Point], pos: Point) -> Vec<(usize, f32)> {
+        let mut all: Vec<(usize, f32)> = points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (i, (*p - pos).length2()))
+            .collect();
+        all.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then(a.0.cmp(&b.0)));
+        all
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let arena = Arena::new();
+        let points: Vec<Point> = (0..2000).map(|i| random_point(i, 0)).collect();
+        let tree = KdTree::from_objects(&arena, &points, |p| *p);
+        assert_eq!(tree.len(), points.len());
+
+        let mut found = Vec::new();
+        for i in 0..100 {
+            let pos = random_point(i, 10);
+            let expected = brute_force(&points, pos);
+            assert_eq!(tree.nearest(pos), Some(expected[0]));
+
+            tree.k_nearest(pos, 8, std::f32::INFINITY, &mut found);
+            let distances: Vec<f32> = found.iter().map(|&(_, d)| d).collect();
+            let expected_distances: Vec<f32> = expected[..8].iter().map(|&(_, d)| d).collect();
+            assert_eq!(distances, expected_distances);
+
+            // The radius limits what's found.
+            let radius = 0.1;
+            tree.k_nearest(pos, 8, radius, &mut found);
+            let within = expected[..8]
+                .iter()
+                .filter(|&&(_, d)| d <= radius * radius)
+                .count();
+            assert_eq!(found.len(), within);
+        }
+    }
+
+    #[test]
+    fn within_radius_matches_brute_force() {
+        let arena = Arena::new();
+        let points: Vec<Point> = (0..2000).map(|i| random_point(i, 0)).collect();
+        let tree = KdTree::from_objects(&arena, &points, |p| *p);
+
+        for i in 0..100 {
+            let pos = random_point(i, 20);
+            let radius = hash_u32_to_f32(i, 30) * 0.5;
+            let mut found = Vec::new();
+            tree.for_each_within(pos, radius, |i, _| found.push(i));
+            found.sort_unstable();
+
+            let mut expected: Vec<usize> = brute_force(&points, pos)
+                .iter()
+                .filter(|&&(_, d)| d <= radius * radius)
+                .map(|&(i, _)| i)
+                .collect();
+            expected.sort_unstable();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn degenerate_trees() {
+        let arena = Arena::new();
+        let empty = KdTree::from_objects(&arena, &[] as &[Point], |p| *p);
+        assert!(empty.is_empty());
+        assert_eq!(empty.nearest(Point::new(0.0, 0.0, 0.0)), None);
+
+        // All at the same place.
+        let points = vec![Point::new(1.0, 2.0, 3.0); 10];
+        let tree = KdTree::from_objects(&arena, &points, |p| *p);
+        let mut found = Vec::new();
+        tree.k_nearest(Point::new(1.0, 2.0, 3.5), 20, 1.0, &mut found);
+        assert_eq!(found.len(), 10);
+        assert!(found.iter().all(|&(_, d)| d == 0.25));
+    }
+}
@@ -39,21 +39,9 @@ impl<'a> LightArray<'a> {
             aprx_energy: aprx_energy,
         }
     }
-}
-
-impl<'a> LightAccel for LightArray<'a> {
-    fn select(
-        &self,
-        inc: Vector,
-        pos: Point,
-        nor: Normal,
-        nor_g: Normal,
-        sc: &SurfaceClosure,
-        time: f32,
-        n: f32,
-    ) -> Option<(usize, f32, f32)> {
-        let _ = (inc, pos, nor, nor_g, sc, time); // Not using these, silence warnings
 
+    /// Selects a light with uniform probability.
+    fn select_uniform(&self, n: f32) -> Option<(usize, f32, f32)> {
         assert!(n >= 0.0 && n <= 1.0);
 
         if self.indices.is_empty() {
@@ -72,6 +60,28 @@ impl<'a> LightAccel for LightArray<'a> {
 
         Some((i, pdf, whittled_n))
     }
+}
+
+impl<'a> LightAccel for LightArray<'a> {
+    fn select(
+        &self,
+        inc: Vector,
+        pos: Point,
+        nor: Normal,
+        nor_g: Normal,
+        sc: &SurfaceClosure,
+        time: f32,
+        n: f32,
+    ) -> Option<(usize, f32, f32)> {
+        let _ = (inc, pos, nor, nor_g, sc, time); // Not using these, silence warnings
+
+        self.select_uniform(n)
+    }
+
+    fn select_by_energy(&self, n: f32) -> Option<(usize, f32, f32)> {
+        // The array doesn't keep the lights' energies.
+        self.select_uniform(n)
+    }
 
     fn approximate_energy(&self) -> f32 {
         self.aprx_energy
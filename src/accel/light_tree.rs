@@ -1,5 +1,3 @@
-use std::mem::{transmute, MaybeUninit};
-
 use kioku::Arena;
 
 use crate::{
@@ -17,44 +15,30 @@ const ARITY_LOG2: usize = 3; // Determines how much to collapse the binary tree,
                              // tree.
 const ARITY: usize = 1 << ARITY_LOG2; // Arity of the final tree
 
+/// A tree over the lights of an assembly, for choosing lights in
+/// proportion to their estimated contribution.
+///
+/// The nodes are stored flat, with the children of each node stored
+/// contiguously, and refer to their bounds and children by index.  This
+/// keeps the tree small for scenes with very many lights.
 #[derive(Copy, Clone, Debug)]
 pub struct LightTree<'a> {
-    root: Option<&'a Node<'a>>,
+    nodes: &'a [Node], // The root is the first node, if any
+    bounds: &'a [BBox],
     depth: usize,
 }
 
 #[derive(Copy, Clone, Debug)]
-enum Node<'a> {
-    Inner {
-        children: &'a [Node<'a>],
-        bounds: &'a [BBox],
-        energy: f32,
-    },
-    Leaf {
-        light_index: usize,
-        bounds: &'a [BBox],
-        energy: f32,
-    },
+struct Node {
+    bounds_range: (u32, u32),
+    energy: f32,
+    index: u32,       // First child for inner nodes, the light for leaves
+    child_count: u32, // Zero for leaves
 }
 
-impl<'a> Node<'a> {
-    fn bounds(&self) -> &'a [BBox] {
-        match *self {
-            Node::Inner { bounds, .. } | Node::Leaf { bounds, .. } => bounds,
-        }
-    }
-
-    fn energy(&self) -> f32 {
-        match *self {
-            Node::Inner { energy, .. } | Node::Leaf { energy, .. } => energy,
-        }
-    }
-
-    fn light_index(&self) -> usize {
-        match *self {
-            Node::Inner { .. } => panic!(),
-            Node::Leaf { light_index, .. } => light_index,
-        }
+impl Node {
+    fn is_leaf(&self) -> bool {
+        self.child_count == 0
     }
 }
 
@@ -69,97 +53,106 @@ impl<'a> LightTree<'a> {
     {
         if objects.is_empty() {
             LightTree {
-                root: None,
+                nodes: &[],
+                bounds: &[],
                 depth: 0,
             }
         } else {
             let mut builder = LightTreeBuilder::new();
             builder.recursive_build(0, 0, objects, &info_getter);
 
-            let root = arena.alloc_uninit::<Node>();
-            LightTree::construct_from_builder(arena, &builder, builder.root_node_index(), root);
+            // Flatten the collapsed tree, with the children of each node
+            // next to each other.
+            let mut nodes = vec![Node {
+                bounds_range: (0, 0),
+                energy: 0.0,
+                index: 0,
+                child_count: 0,
+            }];
+            let mut bounds = Vec::new();
+            LightTree::construct_from_builder(
+                &builder,
+                builder.root_node_index(),
+                0,
+                &mut nodes,
+                &mut bounds,
+            );
 
             LightTree {
-                root: Some(unsafe { transmute(root) }),
+                nodes: arena.copy_slice(&nodes),
+                bounds: arena.copy_slice(&bounds),
                 depth: builder.depth,
             }
         }
     }
 
     fn construct_from_builder(
-        arena: &'a Arena,
         base: &LightTreeBuilder,
         node_index: usize,
-        node_mem: &mut MaybeUninit<Node<'a>>,
+        flat_index: usize,
+        nodes: &mut Vec<Node>,
+        bounds: &mut Vec<BBox>,
     ) {
-        if base.nodes[node_index].is_leaf {
-            // Leaf
-            let bounds_range = base.nodes[node_index].bounds_range;
-            let bounds = arena.copy_slice(&base.bounds[bounds_range.0..bounds_range.1]);
-
-            unsafe {
-                *node_mem.as_mut_ptr() = Node::Leaf {
-                    light_index: base.nodes[node_index].child_index,
-                    bounds: bounds,
-                    energy: base.nodes[node_index].energy,
-                };
-            }
+        let base_node = &base.nodes[node_index];
+        let bounds_start = bounds.len() as u32;
+        bounds.extend(&base.bounds[base_node.bounds_range.0..base_node.bounds_range.1]);
+        let bounds_range = (bounds_start, bounds.len() as u32);
+
+        if base_node.is_leaf {
+            nodes[flat_index] = Node {
+                bounds_range: bounds_range,
+                energy: base_node.energy,
+                index: base_node.child_index as u32,
+                child_count: 0,
+            };
         } else {
-            // Inner
-            let bounds_range = base.nodes[node_index].bounds_range;
-            let bounds = arena.copy_slice(&base.bounds[bounds_range.0..bounds_range.1]);
-
             let child_count = base.node_child_count(node_index);
-            let children = arena.alloc_array_uninit::<Node>(child_count);
+            let first_child = nodes.len();
+            let placeholder = nodes[flat_index];
+            nodes.extend(std::iter::repeat(placeholder).take(child_count));
+            nodes[flat_index] = Node {
+                bounds_range: bounds_range,
+                energy: base_node.energy,
+                index: first_child as u32,
+                child_count: child_count as u32,
+            };
             for i in 0..child_count {
                 LightTree::construct_from_builder(
-                    arena,
                     base,
                     base.node_nth_child_index(node_index, i),
-                    &mut children[i],
+                    first_child + i,
+                    nodes,
+                    bounds,
                 );
             }
-
-            unsafe {
-                *node_mem.as_mut_ptr() = Node::Inner {
-                    children: transmute(children),
-                    bounds: bounds,
-                    energy: base.nodes[node_index].energy,
-                };
-            }
         }
     }
-}
 
-impl<'a> LightAccel for LightTree<'a> {
-    fn select(
-        &self,
-        inc: Vector,
-        pos: Point,
-        nor: Normal,
-        nor_g: Normal,
-        sc: &SurfaceClosure,
-        time: f32,
-        n: f32,
-    ) -> Option<(usize, f32, f32)> {
-        // Calculates the selection probability for a node
-        let node_prob = |node_ref: &Node| {
-            let bbox = lerp_slice(node_ref.bounds(), time);
-            let d = bbox.center() - pos;
-            let r2 = bbox.diagonal2() * 0.25;
-            let inv_surface_area = 1.0 / r2;
+    fn node_bounds(&self, node: &Node) -> &'a [BBox] {
+        &self.bounds[node.bounds_range.0 as usize..node.bounds_range.1 as usize]
+    }
 
-            // Get the approximate amount of light contribution from the
-            // composite light source.
-            let approx_contrib = sc.estimate_eval_over_sphere_light(inc, d, r2, nor, nor_g);
-            node_ref.energy() * inv_surface_area * approx_contrib
-        };
+    /// The memory used by the tree, in bytes.
+    pub fn size_in_bytes(&self) -> usize {
+        std::mem::size_of_val(self.nodes) + std::mem::size_of_val(self.bounds)
+    }
 
+    /// Traverses down the tree to a light, choosing between the children
+    /// of each node in proportion to `node_prob`.
+    ///
+    /// Returns (index_of_light, selection_pdf, whittled_n)
+    fn traverse<F>(&self, n: f32, node_prob: F) -> Option<(usize, f32, f32)>
+    where
+        F: Fn(&Node) -> f32,
+    {
         // Traverse down the tree, keeping track of the relative probabilities
-        let mut node = self.root?;
+        let mut node = self.nodes.first()?;
         let mut tot_prob = 1.0;
         let mut n = n;
-        while let Node::Inner { children, .. } = *node {
+        while !node.is_leaf() {
+            let children =
+                &self.nodes[node.index as usize..(node.index + node.child_count) as usize];
+
             // Calculate the relative probabilities of the children
             let ps = {
                 let mut ps = [0.0; ARITY];
@@ -197,15 +190,45 @@ impl<'a> LightAccel for LightTree<'a> {
         }
 
         // Found our light!
-        Some((node.light_index(), tot_prob, n))
+        Some((node.index as usize, tot_prob, n))
+    }
+}
+
+impl<'a> LightAccel for LightTree<'a> {
+    fn select(
+        &self,
+        inc: Vector,
+        pos: Point,
+        nor: Normal,
+        nor_g: Normal,
+        sc: &SurfaceClosure,
+        time: f32,
+        n: f32,
+    ) -> Option<(usize, f32, f32)> {
+        // Calculates the selection probability for a node
+        let node_prob = |node_ref: &Node| {
+            let bbox = lerp_slice(self.node_bounds(node_ref), time);
+            let d = bbox.center() - pos;
+            // Clamped to a small fraction of the distance, so that lights
+            // with zero extent (e.g. point lights) don't produce infinities.
+            let r2 = (bbox.diagonal2() * 0.25).max(d.length2() * 0.0001);
+            let inv_surface_area = 1.0 / r2;
+
+            // Get the approximate amount of light contribution from the
+            // composite light source.
+            let approx_contrib = sc.estimate_eval_over_sphere_light(inc, d, r2, nor, nor_g);
+            node_ref.energy * inv_surface_area * approx_contrib
+        };
+
+        self.traverse(n, node_prob)
+    }
+
+    fn select_by_energy(&self, n: f32) -> Option<(usize, f32, f32)> {
+        self.traverse(n, |node_ref| node_ref.energy)
     }
 
     fn approximate_energy(&self) -> f32 {
-        if let Some(node) = self.root {
-            node.energy()
-        } else {
-            0.0
-        }
+        self.nodes.first().map(|node| node.energy).unwrap_or(0.0)
     }
 }
 
@@ -371,3 +394,40 @@ impl LightTreeBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_by_energy_pdfs() {
+        let arena = Arena::new();
+        let bounds: Vec<BBox> = (0..20)
+            .map(|i| {
+                let p = Point::new(i as f32, (i % 3) as f32, 0.0);
+                BBox::from_points(p, p)
+            })
+            .collect();
+        let energies: Vec<f32> = (0..20).map(|i| (i + 1) as f32).collect();
+        let total: f32 = energies.iter().sum();
+        let mut lights: Vec<usize> = (0..20).collect();
+        let tree = LightTree::from_objects(&arena, &mut lights[..], |&i| {
+            (&bounds[i..(i + 1)], energies[i])
+        });
+
+        assert!((tree.approximate_energy() - total).abs() < 1.0e-3);
+        assert!(tree.size_in_bytes() > 0);
+
+        let mut seen = vec![false; energies.len()];
+        for i in 0..10000 {
+            let n = (i as f32 + 0.5) / 10000.0;
+            let (light_i, pdf, _) = tree.select_by_energy(n).unwrap();
+            seen[light_i] = true;
+            // The lights are reordered by the build, so look up the
+            // energy of the selected one rather than its original index.
+            let energy = energies[lights[light_i]];
+            assert!((pdf - (energy / total)).abs() < 1.0e-5);
+        }
+        assert!(seen.iter().all(|&s| s));
+    }
+}
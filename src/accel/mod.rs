@@ -1,11 +1,16 @@
 // mod bvh;
 mod bvh4;
 mod bvh_base;
+mod curve_bvh;
+mod kd_tree;
 mod light_array;
 mod light_tree;
 mod objects_split;
 
-use std::cell::Cell;
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::{
     math::{Normal, Point, Vector},
@@ -15,15 +20,38 @@ use crate::{
 pub use self::{
     // bvh::{BVHNode, BVH},
     bvh4::{ray_code, BVH4Node, BVH4},
+    bvh_base::BVHStats,
+    curve_bvh::CurveBVH,
     light_array::LightArray,
     light_tree::LightTree,
 };
 
+// Shared infrastructure for point caches, which nothing uses yet.
+#[allow(unused_imports)]
+pub use self::kd_tree::KdTree;
+
 // Track BVH traversal time
 thread_local! {
     pub static ACCEL_NODE_RAY_TESTS: Cell<u64> = Cell::new(0);
 }
 
+// Number of objects per leaf that BVHs are built with, when not zero,
+// instead of what each kind of geometry asks for.
+static OBJECTS_PER_LEAF_OVERRIDE: AtomicUsize = AtomicUsize::new(0);
+
+/// Overrides the number of objects per leaf for all BVHs built after
+/// this, for diagnosing slow traversal.  `None` restores the defaults.
+pub fn override_objects_per_leaf(objects_per_leaf: Option<usize>) {
+    OBJECTS_PER_LEAF_OVERRIDE.store(objects_per_leaf.unwrap_or(0), Ordering::Relaxed);
+}
+
+fn objects_per_leaf(default: usize) -> usize {
+    match OBJECTS_PER_LEAF_OVERRIDE.load(Ordering::Relaxed) {
+        0 => default,
+        n => n,
+    }
+}
+
 pub trait LightAccel {
     /// Returns (index_of_light, selection_pdf, whittled_n)
     fn select(
@@ -37,5 +65,11 @@ pub trait LightAccel {
         n: f32,
     ) -> Option<(usize, f32, f32)>;
 
+    /// Selects a light in proportion to its approximate energy, for when
+    /// there's no point being lit to select for.
+    ///
+    /// Returns (index_of_light, selection_pdf, whittled_n)
+    fn select_by_energy(&self, n: f32) -> Option<(usize, f32, f32)>;
+
     fn approximate_energy(&self) -> f32;
 }
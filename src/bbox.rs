@@ -7,7 +7,7 @@ use std::{
 
 use crate::{
     lerp::{lerp, lerp_slice, Lerp},
-    math::{fast_minf32, Matrix4x4, Point, Vector},
+    math::{fast_minf32, max_element, Matrix4x4, Point, Vector},
 };
 
 const BBOX_MAXT_ADJUST: f32 = 1.000_000_24;
@@ -48,7 +48,7 @@ impl BBox {
         let far_t = t1.max(t2).extend(std::f32::INFINITY);
         let near_t = t1.min(t2).extend(0.0);
         let far_hit_t = fast_minf32(far_t.min_element() * BBOX_MAXT_ADJUST, max_t);
-        let near_hit_t = near_t.max_element();
+        let near_hit_t = max_element(near_t);
 
         // Did we hit?
         near_hit_t <= far_hit_t
@@ -79,6 +79,15 @@ impl BBox {
         b
     }
 
+    /// Creates a new BBox grown by `amount` on every side.
+    pub fn expanded(&self, amount: f32) -> BBox {
+        let pad = Vector::new(amount, amount, amount);
+        BBox {
+            min: self.min - pad,
+            max: self.max + pad,
+        }
+    }
+
     pub fn surface_area(&self) -> f32 {
         let d = self.max - self.min;
         ((d.x() * d.y()) + (d.y() * d.z()) + (d.z() * d.x())) * 2.0
@@ -60,6 +60,12 @@ impl BBox4 {
     }
 
     // Returns whether the given ray intersects with the bboxes.
+    #[cfg(not(all(
+        target_arch = "aarch64",
+        target_feature = "neon",
+        not(feature = "scalar_math")
+    )))]
+    #[inline(always)]
     pub fn intersect_ray(&self, orig: Point, dir_inv: Vector, max_t: f32) -> Vec4Mask {
         // Get the ray data into SIMD format.
         let ro_x = Vec4::splat(orig.co.x());
@@ -95,6 +101,71 @@ impl BBox4 {
         // Hit results
         near_t.cmplt(far_t)
     }
+
+    // Returns whether the given ray intersects with the bboxes.
+    //
+    // glam's only SIMD backend is SSE2, so on aarch64 this is written with
+    // NEON directly rather than letting it fall back to scalar math.  The
+    // min/max used are the ones that ignore NaNs, like the scalar ones.
+    #[cfg(all(
+        target_arch = "aarch64",
+        target_feature = "neon",
+        not(feature = "scalar_math")
+    ))]
+    pub fn intersect_ray(&self, orig: Point, dir_inv: Vector, max_t: f32) -> Vec4Mask {
+        use std::arch::aarch64::*;
+
+        unsafe {
+            let load = |v: &Vec4| {
+                let v: &[f32; 4] = v.as_ref();
+                vld1q_f32(v.as_ptr())
+            };
+
+            // Get the ray data into SIMD format.
+            let ro_x = vdupq_n_f32(orig.x());
+            let ro_y = vdupq_n_f32(orig.y());
+            let ro_z = vdupq_n_f32(orig.z());
+            let rdi_x = vdupq_n_f32(dir_inv.x());
+            let rdi_y = vdupq_n_f32(dir_inv.y());
+            let rdi_z = vdupq_n_f32(dir_inv.z());
+
+            // Slab tests
+            let t1_x = vmulq_f32(vsubq_f32(load(&self.x.0), ro_x), rdi_x);
+            let t1_y = vmulq_f32(vsubq_f32(load(&self.y.0), ro_y), rdi_y);
+            let t1_z = vmulq_f32(vsubq_f32(load(&self.z.0), ro_z), rdi_z);
+            let t2_x = vmulq_f32(vsubq_f32(load(&self.x.1), ro_x), rdi_x);
+            let t2_y = vmulq_f32(vsubq_f32(load(&self.y.1), ro_y), rdi_y);
+            let t2_z = vmulq_f32(vsubq_f32(load(&self.z.1), ro_z), rdi_z);
+
+            // Get the far and near t hits for each axis.
+            let t_far_x = vmaxnmq_f32(t1_x, t2_x);
+            let t_far_y = vmaxnmq_f32(t1_y, t2_y);
+            let t_far_z = vmaxnmq_f32(t1_z, t2_z);
+            let t_near_x = vminnmq_f32(t1_x, t2_x);
+            let t_near_y = vminnmq_f32(t1_y, t2_y);
+            let t_near_z = vminnmq_f32(t1_z, t2_z);
+
+            // Calculate over-all far t hit.
+            let far_t = vminnmq_f32(
+                vmulq_n_f32(
+                    vminnmq_f32(t_far_x, vminnmq_f32(t_far_y, t_far_z)),
+                    BBOX_MAXT_ADJUST,
+                ),
+                vdupq_n_f32(max_t),
+            );
+
+            // Calculate over-all near t hit.
+            let near_t = vmaxnmq_f32(
+                vmaxnmq_f32(t_near_x, t_near_y),
+                vmaxnmq_f32(t_near_z, vdupq_n_f32(0.0)),
+            );
+
+            // Hit results
+            let mut hits = [0u32; 4];
+            vst1q_u32(hits.as_mut_ptr(), vcltq_f32(near_t, far_t));
+            Vec4Mask::new(hits[0] != 0, hits[1] != 0, hits[2] != 0, hits[3] != 0)
+        }
+    }
 }
 
 /// Union of two BBoxes.
@@ -134,3 +205,72 @@ impl Lerp for BBox4 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::hash_u32_to_f32;
+
+    fn rand_point(i: u32, seed: u32) -> Point {
+        Point::new(
+            hash_u32_to_f32(i, seed) * 4.0 - 2.0,
+            hash_u32_to_f32(i, seed + 1) * 4.0 - 2.0,
+            hash_u32_to_f32(i, seed + 2) * 4.0 - 2.0,
+        )
+    }
+
+    fn bboxes() -> [BBox; 4] {
+        let mut bbs = [BBox::new(); 4];
+        for (i, bb) in bbs.iter_mut().enumerate() {
+            *bb = BBox::new() | rand_point(i as u32, 0) | rand_point(i as u32, 10);
+        }
+        bbs
+    }
+
+    // The SIMD ray tests should give the same answers as the scalar `BBox`
+    // ones, whichever of its backends glam is using.
+    #[test]
+    fn intersect_ray_matches_bbox() {
+        let bbs = bboxes();
+        let bb4 = BBox4::from_bboxes(bbs[0], bbs[1], bbs[2], bbs[3]);
+        for i in 0..1000 {
+            let orig = rand_point(i, 20);
+            let dir = rand_point(i, 30).into_vector();
+            let dir_inv = Vector::new(1.0 / dir.x(), 1.0 / dir.y(), 1.0 / dir.z());
+            let max_t = if i % 2 == 0 {
+                std::f32::INFINITY
+            } else {
+                hash_u32_to_f32(i, 40) * 2.0
+            };
+
+            let hits = bb4.intersect_ray(orig, dir_inv, max_t).bitmask();
+            for (lane, bb) in bbs.iter().enumerate() {
+                assert_eq!(
+                    (hits >> lane) & 1 == 1,
+                    bb.intersect_ray(orig, dir_inv, max_t),
+                    "ray {}, box {}",
+                    i,
+                    lane
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn union_matches_bbox() {
+        let a = bboxes();
+        let b = [a[3], a[0], a[2], a[1]];
+        let u =
+            BBox4::from_bboxes(a[0], a[1], a[2], a[3]) | BBox4::from_bboxes(b[0], b[1], b[2], b[3]);
+        let lanes = |v: Vec4| [v.x(), v.y(), v.z(), v.w()];
+        for lane in 0..4 {
+            let bb = a[lane] | b[lane];
+            assert_eq!(lanes(u.x.0)[lane], bb.min.x());
+            assert_eq!(lanes(u.x.1)[lane], bb.max.x());
+            assert_eq!(lanes(u.y.0)[lane], bb.min.y());
+            assert_eq!(lanes(u.y.1)[lane], bb.max.y());
+            assert_eq!(lanes(u.z.0)[lane], bb.min.z());
+            assert_eq!(lanes(u.z.1)[lane], bb.max.z());
+        }
+    }
+}
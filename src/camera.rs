@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+use std::fmt::Debug;
+
 use kioku::Arena;
 
 use crate::{
@@ -9,23 +11,86 @@ use crate::{
     sampling::square_to_circle,
 };
 
+/// Physical camera exposure settings.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Exposure {
+    pub iso: f32,
+    pub shutter_speed: f32, // In seconds
+    pub f_stop: f32,
+}
+
+impl Default for Exposure {
+    fn default() -> Exposure {
+        Exposure {
+            iso: 100.0,
+            shutter_speed: 1.0,
+            f_stop: 1.0,
+        }
+    }
+}
+
+impl Exposure {
+    /// The factor to scale scene radiance by to get the exposed image.
+    ///
+    /// This is relative to ISO 100, a one second shutter, and f/1, which
+    /// gives a scale of 1.0.  E.g. the "sunny 16" rule (ISO 100, 1/100s,
+    /// f/16) puts a mid-grey surface lit by 100,000 lux at about 0.2.
+    pub fn scale(&self) -> f32 {
+        (self.shutter_speed * self.iso * 0.01) / (self.f_stop * self.f_stop)
+    }
+
+    /// The aperture radius for a lens with the given field of view, in the
+    /// same units as `sensor_width`.
+    pub fn aperture_radius(&self, fov: f32, sensor_width: f32) -> f32 {
+        let focal_length = (sensor_width * 0.5) / (fov * 0.5).tan();
+        focal_length / (2.0 * self.f_stop)
+    }
+}
+
+/// A camera model, which generates the rays that camera paths start from.
+///
+/// New projections can be added as types implementing this, without
+/// touching the renderer, which only maps pixels to the image plane.
+pub trait Camera: Debug + Sync {
+    /// Generates a ray through the point (`x`, `y`) on the image plane at
+    /// `time`, where x is in [-1, 1] and y is in the same units.  `u` and
+    /// `v` are in [0, 1), for sampling the lens.
+    fn generate_ray(&self, x: f32, y: f32, time: f32, wavelength: f32, u: f32, v: f32) -> Ray;
+
+    /// The factor to scale scene radiance by to get the exposed image.
+    fn exposure(&self) -> f32;
+
+    /// A one-line description of the camera at the middle of the shutter.
+    fn describe(&self) -> String;
+
+    /// The position of the camera at each of its time samples, for dicing.
+    fn positions(&self) -> Vec<Point>;
+
+    /// The smallest half-width of the image plane at distance 1.0 over
+    /// the shutter, for dicing.
+    fn min_half_width(&self) -> f32;
+}
+
+/// A pinhole or thin lens camera.
 #[derive(Copy, Clone, Debug)]
-pub struct Camera<'a> {
+pub struct PerspectiveCamera<'a> {
     transforms: &'a [Matrix4x4],
     fovs: &'a [f32],
     tfovs: &'a [f32],
     aperture_radii: &'a [f32],
     focus_distances: &'a [f32],
+    exposure: f32,
 }
 
-impl<'a> Camera<'a> {
+impl<'a> PerspectiveCamera<'a> {
     pub fn new(
         arena: &'a Arena,
         transforms: &[Matrix4x4],
         fovs: &[f32],
         mut aperture_radii: &[f32],
         mut focus_distances: &[f32],
-    ) -> Camera<'a> {
+        exposure: Exposure,
+    ) -> PerspectiveCamera<'a> {
         assert!(!transforms.is_empty(), "Camera has no transform(s)!");
         assert!(!fovs.is_empty(), "Camera has no fov(s)!");
 
@@ -62,16 +127,39 @@ impl<'a> Camera<'a> {
             .map(|n| (n / 2.0).sin() / (n / 2.0).cos())
             .collect();
 
-        Camera {
+        PerspectiveCamera {
             transforms: arena.copy_slice(&transforms),
             fovs: arena.copy_slice(&fovs),
             tfovs: arena.copy_slice(&tfovs),
             aperture_radii: arena.copy_slice(&aperture_radii),
             focus_distances: arena.copy_slice(&focus_distances),
+            exposure: exposure.scale(),
         }
     }
+}
+
+impl<'a> Camera for PerspectiveCamera<'a> {
+    fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    fn describe(&self) -> String {
+        let position = Point::new(0.0, 0.0, 0.0) * lerp_slice(self.transforms, 0.5);
+        format!(
+            "fov {:.2} degrees, aperture radius {}, focus distance {}, exposure {}, \
+             position ({}, {}, {}), {} transform sample(s)",
+            lerp_slice(self.fovs, 0.5).to_degrees(),
+            lerp_slice(self.aperture_radii, 0.5),
+            lerp_slice(self.focus_distances, 0.5),
+            self.exposure,
+            position.x(),
+            position.y(),
+            position.z(),
+            self.transforms.len(),
+        )
+    }
 
-    pub fn generate_ray(&self, x: f32, y: f32, time: f32, wavelength: f32, u: f32, v: f32) -> Ray {
+    fn generate_ray(&self, x: f32, y: f32, time: f32, wavelength: f32, u: f32, v: f32) -> Ray {
         // Get time-interpolated camera settings
         let transform = lerp_slice(self.transforms, time);
         let tfov = lerp_slice(self.tfovs, time);
@@ -100,4 +188,103 @@ impl<'a> Camera<'a> {
             max_t: std::f32::INFINITY,
         }
     }
+
+    fn positions(&self) -> Vec<Point> {
+        self.transforms
+            .iter()
+            .map(|xform| Point::new(0.0, 0.0, 0.0) * *xform)
+            .collect()
+    }
+
+    fn min_half_width(&self) -> f32 {
+        self.tfovs.iter().fold(f32::INFINITY, |a, &b| a.min(b))
+    }
+}
+
+/// Estimates how many micropolygons geometry should be diced into, from
+/// how large it appears through a camera.
+///
+/// This is based only on distance from the camera, not on where the
+/// geometry is in the frame, since geometry outside of the frame can
+/// still be seen in reflections and shadows.
+#[derive(Clone, Debug)]
+pub struct DicingCamera {
+    positions: Vec<Point>, // One per time sample
+    pixel_size: f32,       // Narrower side of a pixel at distance 1.0
+    dicing_rate: f32,      // Target micropolygon edge length, in pixels
+}
+
+impl DicingCamera {
+    /// `pixel_aspect` is the width of each pixel over its height.
+    pub fn new(
+        camera: &dyn Camera,
+        resolution: (usize, usize),
+        pixel_aspect: f32,
+        dicing_rate: f32,
+    ) -> DicingCamera {
+        // The narrowest field of view gives the smallest pixels.
+        let tfov = camera.min_half_width();
+
+        DicingCamera {
+            positions: camera.positions(),
+            pixel_size: (2.0 * tfov) / resolution.0.max(1) as f32 * (1.0 / pixel_aspect).min(1.0),
+            dicing_rate: dicing_rate,
+        }
+    }
+
+    /// The number of micropolygons the edge between two world-space points
+    /// should be diced into.
+    ///
+    /// The edge is treated as if all of it were as close to the camera as
+    /// its nearest end point.
+    pub fn edge_rate(&self, p1: Point, p2: Point) -> f32 {
+        let dist = self
+            .positions
+            .iter()
+            .map(|&cam| (p1 - cam).length().min((p2 - cam).length()))
+            .fold(f32::INFINITY, f32::min);
+        let micropoly_size = dist.max(1.0e-6) * self.pixel_size * self.dicing_rate;
+
+        (p2 - p1).length() / micropoly_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dicing_rate_from_distance() {
+        let arena = Arena::new();
+        let camera = PerspectiveCamera::new(
+            &arena,
+            &[Matrix4x4::new()],
+            &[std::f32::consts::FRAC_PI_2], // tfov of 1.0
+            &[],
+            &[],
+            Exposure::default(),
+        );
+
+        // Pixels are 0.01 wide at distance 1.0, and twice that at 2.0.
+        let dicer = DicingCamera::new(&camera, (200, 100), 1.0, 1.0);
+        let rate = dicer.edge_rate(Point::new(0.0, 0.0, 1.0), Point::new(0.1, 0.0, 1.0));
+        assert!((rate - 10.0).abs() < 1.0e-3);
+        let rate = dicer.edge_rate(Point::new(0.0, 0.0, 2.0), Point::new(0.0, 0.1, 2.0));
+        assert!((rate - 5.0).abs() < 1.0e-3);
+
+        // Coarser dicing rates give fewer micropolygons, and the nearest
+        // end of the edge is what counts.
+        let dicer = DicingCamera::new(&camera, (200, 100), 1.0, 4.0);
+        let rate = dicer.edge_rate(Point::new(0.0, 0.0, 1.0), Point::new(0.0, 0.0, 5.0));
+        assert!((rate - 100.0).abs() < 1.0e-2);
+
+        // Wide pixels are diced for their height, and tall ones for their
+        // width.
+        let dicer = DicingCamera::new(&camera, (200, 100), 2.0, 1.0);
+        let rate = dicer.edge_rate(Point::new(0.0, 0.0, 1.0), Point::new(0.1, 0.0, 1.0));
+        assert!((rate - 20.0).abs() < 1.0e-3);
+        let dicer = DicingCamera::new(&camera, (200, 100), 0.5, 1.0);
+        let rate = dicer.edge_rate(Point::new(0.0, 0.0, 1.0), Point::new(0.1, 0.0, 1.0));
+        assert!((rate - 10.0).abs() < 1.0e-3);
+    }
 }
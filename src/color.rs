@@ -335,7 +335,7 @@ impl Lerp for Color {
     }
 }
 
-fn plancks_law(temperature: f32, wavelength: f32) -> f32 {
+pub fn plancks_law(temperature: f32, wavelength: f32) -> f32 {
     const C: f32 = 299_792_458.0; // Speed of light
     const H: f32 = 6.626_070_15e-34; // Planck constant
     const KB: f32 = 1.380_648_52e-23; // Boltzmann constant
@@ -418,6 +418,11 @@ impl SpectralSample {
         }
     }
 
+    /// Returns all four wavelengths of the sample.
+    pub fn wavelengths(&self) -> Vec4 {
+        wavelengths(self.hero_wavelength)
+    }
+
     /// Returns the nth wavelength
     fn wl_n(&self, n: usize) -> f32 {
         let wl = self.hero_wavelength + (WL_RANGE_Q * n as f32);
@@ -530,6 +535,10 @@ impl XYZ {
     pub fn to_tuple(&self) -> (f32, f32, f32) {
         (self.x, self.y, self.z)
     }
+
+    pub fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
 }
 
 impl Lerp for XYZ {
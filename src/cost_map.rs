@@ -0,0 +1,177 @@
+//! A map of how long each part of the image takes to render, measured
+//! from finished buckets, for laying out the buckets of later passes so
+//! that the slow parts of the image are spread over the threads.
+
+/// The size of the map's cells, in pixels.
+const CELL_SIZE: u32 = 16;
+
+/// Render time per sample per pixel, accumulated in a grid over the
+/// rendered region of the image.
+#[derive(Debug)]
+pub struct CostMap {
+    origin: (u32, u32),
+    res: (u32, u32),
+    cells: Vec<Cell>,
+    total: Cell,
+    bucket_times: Vec<f64>, // Of the buckets recorded since the last `take_bucket_times()`
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Cell {
+    seconds: f64,
+    pixel_samples: f64,
+}
+
+impl Cell {
+    fn cost(&self) -> Option<f64> {
+        if self.pixel_samples > 0.0 {
+            Some(self.seconds / self.pixel_samples)
+        } else {
+            None
+        }
+    }
+}
+
+impl CostMap {
+    /// Creates an empty map of the region from `origin` of size `size`,
+    /// in pixels.
+    pub fn new(origin: (u32, u32), size: (u32, u32)) -> CostMap {
+        let res = (
+            (size.0.max(1) - 1) / CELL_SIZE + 1,
+            (size.1.max(1) - 1) / CELL_SIZE + 1,
+        );
+        CostMap {
+            origin: origin,
+            res: res,
+            cells: vec![Cell::default(); (res.0 * res.1) as usize],
+            total: Cell::default(),
+            bucket_times: Vec::new(),
+        }
+    }
+
+    /// Records that rendering `samples` samples in each pixel of the
+    /// rectangle (x, y, w, h) took `seconds`.  The time is assumed to be
+    /// spread evenly over the rectangle.
+    pub fn record(&mut self, rect: (u32, u32, u32, u32), samples: u32, seconds: f64) {
+        let area = rect.2 as f64 * rect.3 as f64;
+        if area == 0.0 || samples == 0 {
+            return;
+        }
+        for (i, overlap) in overlapping_cells(self.origin, self.res, rect) {
+            let cell = &mut self.cells[i];
+            cell.seconds += seconds * overlap / area;
+            cell.pixel_samples += overlap * samples as f64;
+        }
+        self.total.seconds += seconds;
+        self.total.pixel_samples += area * samples as f64;
+        self.bucket_times.push(seconds);
+    }
+
+    /// The estimated time to render `samples` samples in each pixel of
+    /// the rectangle (x, y, w, h), or `None` if nothing's been recorded
+    /// yet.  Parts of the rectangle that haven't been measured are
+    /// assumed to cost the average.
+    pub fn estimate(&self, rect: (u32, u32, u32, u32), samples: u32) -> Option<f64> {
+        let average = self.total.cost()?;
+        let seconds_per_sample: f64 = overlapping_cells(self.origin, self.res, rect)
+            .map(|(i, overlap)| self.cells[i].cost().unwrap_or(average) * overlap)
+            .sum();
+        Some(seconds_per_sample * samples as f64)
+    }
+
+    /// The mean and standard deviation of the times of the buckets
+    /// recorded since the last call, or `None` if there weren't any.
+    pub fn take_bucket_times(&mut self) -> Option<(f64, f64)> {
+        if self.bucket_times.is_empty() {
+            return None;
+        }
+        let n = self.bucket_times.len() as f64;
+        let mean = self.bucket_times.iter().sum::<f64>() / n;
+        let variance = self
+            .bucket_times
+            .iter()
+            .map(|t| (t - mean) * (t - mean))
+            .sum::<f64>()
+            / n;
+        self.bucket_times.clear();
+        Some((mean, variance.sqrt()))
+    }
+}
+
+/// The index of each cell of a map that the rectangle overlaps, and how
+/// many of its pixels it overlaps.
+fn overlapping_cells(
+    origin: (u32, u32),
+    res: (u32, u32),
+    rect: (u32, u32, u32, u32),
+) -> impl Iterator<Item = (usize, f64)> {
+    // In pixels relative to the origin, clipped to the map.
+    let map_w = res.0 * CELL_SIZE;
+    let map_h = res.1 * CELL_SIZE;
+    let x0 = rect.0.saturating_sub(origin.0).min(map_w);
+    let y0 = rect.1.saturating_sub(origin.1).min(map_h);
+    let x1 = (rect.0 + rect.2).saturating_sub(origin.0).min(map_w);
+    let y1 = (rect.1 + rect.3).saturating_sub(origin.1).min(map_h);
+
+    let cells_x = (x0 / CELL_SIZE)..((x1.max(1) - 1) / CELL_SIZE + 1);
+    let cells_y = (y0 / CELL_SIZE)..((y1.max(1) - 1) / CELL_SIZE + 1);
+    cells_y.flat_map(move |cy| {
+        cells_x.clone().map(move |cx| {
+            let overlap_w = x1.min((cx + 1) * CELL_SIZE) - x0.max(cx * CELL_SIZE);
+            let overlap_h = y1.min((cy + 1) * CELL_SIZE) - y0.max(cy * CELL_SIZE);
+            (
+                (cy * res.0 + cx) as usize,
+                overlap_w as f64 * overlap_h as f64,
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_follow_recorded_costs() {
+        let mut map = CostMap::new((10, 20), (100, 50));
+        assert_eq!(map.estimate((10, 20, 100, 50), 1), None);
+
+        // The left half is ten times as slow as the right half.
+        map.record((10, 20, 48, 50), 4, 40.0);
+        map.record((58, 20, 52, 50), 4, 52.0 / 48.0 * 4.0);
+
+        let slow = map.estimate((10, 20, 32, 32), 1).unwrap();
+        let fast = map.estimate((74, 20, 32, 32), 1).unwrap();
+        assert!((slow / fast - 10.0).abs() < 1.0e-9);
+        assert!((slow - 40.0 / 4.0 * (32.0 * 32.0) / (48.0 * 50.0)).abs() < 1.0e-9);
+
+        // It scales with the samples.
+        assert!((map.estimate((10, 20, 32, 32), 3).unwrap() - slow * 3.0).abs() < 1.0e-9);
+
+        // The whole region adds up to the time recorded.
+        let total = map.estimate((10, 20, 100, 50), 4).unwrap();
+        assert!((total - (40.0 + 52.0 / 48.0 * 4.0)).abs() < 1.0e-9);
+    }
+
+    #[test]
+    fn unmeasured_parts_cost_the_average() {
+        let mut map = CostMap::new((0, 0), (64, 64));
+        map.record((0, 0, 32, 32), 1, 2.0);
+        let measured = map.estimate((0, 0, 32, 32), 1).unwrap();
+        let unmeasured = map.estimate((32, 32, 32, 32), 1).unwrap();
+        assert!((measured - unmeasured).abs() < 1.0e-9);
+
+        // Outside the map doesn't count.
+        assert_eq!(map.estimate((64, 64, 32, 32), 1), Some(0.0));
+    }
+
+    #[test]
+    fn bucket_time_stats() {
+        let mut map = CostMap::new((0, 0), (64, 64));
+        assert_eq!(map.take_bucket_times(), None);
+        map.record((0, 0, 32, 32), 1, 1.0);
+        map.record((32, 0, 32, 32), 1, 3.0);
+        assert_eq!(map.take_bucket_times(), Some((2.0, 1.0)));
+        assert_eq!(map.take_bucket_times(), None);
+    }
+}
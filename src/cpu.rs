@@ -0,0 +1,138 @@
+//! Runtime detection of the CPU's SIMD instruction sets.
+//!
+//! The binary is built for the baseline of its target (SSE2 on x86-64),
+//! so that it runs anywhere.  The hottest kernels (BVH traversal with its
+//! batched bounding box tests, triangle intersection, and transforming
+//! rays into instance space) also have copies compiled for newer
+//! instruction sets, and the best one the CPU supports is picked when
+//! they're called.
+//!
+//! The pattern for a dispatched kernel is an `#[inline(always)]` body,
+//! a `#[target_feature]` wrapper of it per instruction set, and the
+//! public function choosing between them with `simd_level()`.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// The SIMD instruction sets that kernels have copies compiled for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SimdLevel {
+    /// Only what the target guarantees, e.g. SSE2 on x86-64.
+    Baseline,
+    Avx2,
+    /// AVX-512 CPUs run the AVX2 copies, since the kernels are at most
+    /// 4 wide, but it's reported separately.
+    Avx512,
+    /// NEON is part of the aarch64 baseline, so the NEON kernels are
+    /// always compiled in directly rather than dispatched.
+    Neon,
+}
+
+impl SimdLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            SimdLevel::Baseline => "baseline",
+            SimdLevel::Avx2 => "avx2",
+            SimdLevel::Avx512 => "avx512",
+            SimdLevel::Neon => "neon",
+        }
+    }
+
+    /// Whether the AVX2 copies of the kernels can be used.
+    #[inline(always)]
+    pub fn has_avx2(self) -> bool {
+        self == SimdLevel::Avx2 || self == SimdLevel::Avx512
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            SimdLevel::Baseline => 1,
+            SimdLevel::Avx2 => 2,
+            SimdLevel::Avx512 => 3,
+            SimdLevel::Neon => 4,
+        }
+    }
+
+    fn from_u8(n: u8) -> Option<SimdLevel> {
+        match n {
+            1 => Some(SimdLevel::Baseline),
+            2 => Some(SimdLevel::Avx2),
+            3 => Some(SimdLevel::Avx512),
+            4 => Some(SimdLevel::Neon),
+            _ => None,
+        }
+    }
+}
+
+// The level in use, or zero if it hasn't been detected yet.
+static SIMD_LEVEL: AtomicU8 = AtomicU8::new(0);
+
+/// The SIMD level that dispatched kernels use: the best that the CPU
+/// supports, unless `use_baseline_simd()` was called.
+#[inline(always)]
+pub fn simd_level() -> SimdLevel {
+    match SimdLevel::from_u8(SIMD_LEVEL.load(Ordering::Relaxed)) {
+        Some(level) => level,
+        None => {
+            let level = detect();
+            SIMD_LEVEL.store(level.to_u8(), Ordering::Relaxed);
+            level
+        }
+    }
+}
+
+/// Makes dispatched kernels use their baseline copies from now on, for
+/// comparing performance or ruling out the other copies when debugging.
+pub fn use_baseline_simd() {
+    SIMD_LEVEL.store(SimdLevel::Baseline.to_u8(), Ordering::Relaxed);
+}
+
+/// The best SIMD level that the CPU supports.
+pub fn detect() -> SimdLevel {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if !is_x86_feature_detected!("avx2") {
+            SimdLevel::Baseline
+        } else if is_x86_feature_detected!("avx512f") {
+            SimdLevel::Avx512
+        } else {
+            SimdLevel::Avx2
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        SimdLevel::Neon
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        SimdLevel::Baseline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_round_trips() {
+        for &level in &[
+            SimdLevel::Baseline,
+            SimdLevel::Avx2,
+            SimdLevel::Avx512,
+            SimdLevel::Neon,
+        ] {
+            assert_eq!(SimdLevel::from_u8(level.to_u8()), Some(level));
+        }
+        assert_eq!(SimdLevel::from_u8(0), None);
+    }
+
+    #[test]
+    fn detected_level_is_supported() {
+        let level = detect();
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(level.has_avx2(), is_x86_feature_detected!("avx2"));
+        #[cfg(not(target_arch = "x86_64"))]
+        assert!(!level.has_avx2());
+    }
+}
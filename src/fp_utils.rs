@@ -3,7 +3,7 @@
 //! This is based on the work in section 3.9 of "Physically Based Rendering:
 //! From Theory to Implementation" 3rd edition by Pharr et al.
 
-use crate::math::{dot, Normal, Point, Vector};
+use crate::math::{dot, Matrix4x4, Normal, Point, Vector};
 
 #[inline(always)]
 pub fn fp_gamma(n: u32) -> f32 {
@@ -42,6 +42,41 @@ pub fn decrement_ulp(v: f32) -> f32 {
     }
 }
 
+/// Transforms a point by an affine transform, tracking the error that
+/// introduces.
+///
+/// `p_err` is the error magnitude of the untransformed point.  Returns the
+/// transformed point and its error magnitude.
+pub fn transform_point_err(p: Point, p_err: f32, xform: &Matrix4x4) -> (Point, f32) {
+    let m = xform.0.to_cols_array_2d();
+    let co = [p.x(), p.y(), p.z()];
+
+    let mut err: f32 = 0.0;
+    for row in 0..3 {
+        let mut abs_sum = m[3][row].abs();
+        let mut m_sum = 0.0;
+        for col in 0..3 {
+            abs_sum += (m[col][row] * co[col]).abs();
+            m_sum += m[col][row].abs();
+        }
+        err = err.max((fp_gamma(3) * abs_sum) + ((1.0 + fp_gamma(3)) * m_sum * p_err));
+    }
+
+    (p * *xform, err)
+}
+
+/// Calculates the point at `t` along a ray, and the error magnitude that
+/// introduces.
+///
+/// Error bounds scale with the magnitude of the coordinates involved, so
+/// this stays tight near the origin and loose far from it, however big
+/// the scene is.
+pub fn ray_point_err(orig: Point, dir: Vector, t: f32) -> (Point, f32) {
+    let offset = dir * t;
+    let err = fp_gamma(2) * (orig.into_vector().abs() + offset.abs()).co.max_element();
+    (orig + offset, err)
+}
+
 pub fn robust_ray_origin(pos: Point, pos_err: f32, nor: Normal, ray_dir: Vector) -> Point {
     // Get surface normal pointing in the same
     // direction as ray_dir.
@@ -121,6 +156,36 @@ mod tests {
         assert_eq!(decrement_ulp(increment_ulp(-1.2)), -1.2);
     }
 
+    #[test]
+    fn transform_point_err_scales() {
+        let p = Point::new(1.0, -2.0, 3.0);
+        let (tp, err) = transform_point_err(p, 0.0, &Matrix4x4::new());
+        assert_eq!(tp, p);
+        assert!(err > 0.0 && err < 0.00001);
+
+        // Large translations and scales both grow the error.
+        let big = Matrix4x4::new_from_values(
+            1000.0, 0.0, 0.0, 100000.0, 0.0, 1000.0, 0.0, 0.0, 0.0, 0.0, 1000.0, 0.0, 0.0, 0.0,
+            0.0, 1.0,
+        );
+        let (tp, big_err) = transform_point_err(p, 0.001, &big);
+        assert_eq!(tp, Point::new(101000.0, -2000.0, 3000.0));
+        assert!(big_err >= 1.0);
+        assert!(big_err > fp_gamma(3) * 101000.0);
+    }
+
+    #[test]
+    fn ray_point_err_scales() {
+        let dir = Vector::new(0.0, 0.0, 1.0);
+        let (p, err) = ray_point_err(Point::new(0.0, 0.0, 0.0), dir, 1.0);
+        assert_eq!(p, Point::new(0.0, 0.0, 1.0));
+        assert!(err > 0.0 && err < 0.00001);
+
+        // City-scale coordinates need proportionally bigger offsets.
+        let (_, big_err) = ray_point_err(Point::new(50000.0, 0.0, 0.0), dir, 1.0);
+        assert!(big_err > err * 10000.0);
+    }
+
     #[test]
     fn dec_inc_ulp() {
         assert_eq!(increment_ulp(decrement_ulp(1.0)), 1.0);
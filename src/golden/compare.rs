@@ -0,0 +1,127 @@
+//! Comparison of renders against reference images.
+
+use crate::image::RgbImage;
+
+/// How far a render may stray from its reference.
+#[derive(Debug, Copy, Clone)]
+pub struct Tolerance {
+    /// Largest allowed absolute difference of a single channel before the
+    /// pixel counts as an outlier.
+    pub channel: f32,
+
+    /// Largest allowed fraction of outlier pixels.
+    pub outlier_fraction: f32,
+
+    /// Largest allowed root mean square error over all channels.
+    pub rmse: f32,
+}
+
+/// The measured difference between two images.
+#[derive(Debug, Copy, Clone)]
+pub struct Difference {
+    pub max_channel_error: f32,
+    pub outlier_fraction: f32,
+    pub rmse: f32,
+}
+
+impl Difference {
+    pub fn is_within(&self, tolerance: &Tolerance) -> bool {
+        self.outlier_fraction <= tolerance.outlier_fraction && self.rmse <= tolerance.rmse
+    }
+}
+
+/// Measures the difference between two images of the same size.
+///
+/// Non-finite channels always count as outliers, and make the rmse
+/// infinite.
+pub fn compare(image: &RgbImage, reference: &RgbImage, tolerance: &Tolerance) -> Difference {
+    assert_eq!(
+        (image.width, image.height),
+        (reference.width, reference.height),
+        "image dimensions differ from the reference"
+    );
+
+    let mut max_channel_error = 0.0f32;
+    let mut outliers = 0usize;
+    let mut squared_error_sum = 0.0f64;
+    for (a, b) in image.data.iter().zip(reference.data.iter()) {
+        let errors = [(a.0 - b.0).abs(), (a.1 - b.1).abs(), (a.2 - b.2).abs()];
+        let mut is_outlier = false;
+        for &e in &errors {
+            if e.is_finite() {
+                max_channel_error = max_channel_error.max(e);
+                squared_error_sum += e as f64 * e as f64;
+            } else {
+                max_channel_error = f32::INFINITY;
+                squared_error_sum = f64::INFINITY;
+            }
+            if e > tolerance.channel || e.is_nan() {
+                is_outlier = true;
+            }
+        }
+        if is_outlier {
+            outliers += 1;
+        }
+    }
+
+    let pixel_count = image.data.len().max(1);
+    Difference {
+        max_channel_error: max_channel_error,
+        outlier_fraction: outliers as f32 / pixel_count as f32,
+        rmse: (squared_error_sum / (pixel_count * 3) as f64).sqrt() as f32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(data: Vec<(f32, f32, f32)>) -> RgbImage {
+        RgbImage {
+            width: data.len(),
+            height: 1,
+            data: data,
+        }
+    }
+
+    const TOLERANCE: Tolerance = Tolerance {
+        channel: 0.1,
+        outlier_fraction: 0.25,
+        rmse: 0.1,
+    };
+
+    #[test]
+    fn compare_identical() {
+        let a = image(vec![(0.5, 0.25, 1.0); 4]);
+        let diff = compare(&a, &a, &TOLERANCE);
+        assert_eq!(diff.max_channel_error, 0.0);
+        assert_eq!(diff.outlier_fraction, 0.0);
+        assert_eq!(diff.rmse, 0.0);
+        assert!(diff.is_within(&TOLERANCE));
+    }
+
+    #[test]
+    fn compare_outliers() {
+        let a = image(vec![(0.0, 0.0, 0.0); 4]);
+        let mut b = a.clone();
+        b.data[0].1 = 0.2;
+        let diff = compare(&a, &b, &TOLERANCE);
+        assert_eq!(diff.outlier_fraction, 0.25);
+        assert!(diff.is_within(&TOLERANCE));
+
+        b.data[1].2 = 0.2;
+        let diff = compare(&a, &b, &TOLERANCE);
+        assert_eq!(diff.outlier_fraction, 0.5);
+        assert!(!diff.is_within(&TOLERANCE));
+    }
+
+    #[test]
+    fn compare_non_finite() {
+        let a = image(vec![(0.0, 0.0, 0.0); 4]);
+        let mut b = a.clone();
+        b.data[3].0 = f32::NAN;
+        let diff = compare(&a, &b, &TOLERANCE);
+        assert_eq!(diff.outlier_fraction, 0.25);
+        assert!(!diff.is_within(&TOLERANCE));
+    }
+}
@@ -0,0 +1,235 @@
+//! Golden image regression tests.
+//!
+//! Each scene in `tests/golden/` is rendered and compared against the
+//! reference image next to it, with the same name but a `.pfm` extension.
+//! The scenes are small and low spp with fixed seeds, but rendering them
+//! is still too slow for the default test run, so these tests are ignored.
+//! Run them with:
+//!
+//! ```text
+//! cargo test --release -- --ignored
+//! ```
+//!
+//! When a change is meant to alter the rendered results, set the
+//! `PSYCHOPATH_BLESS` environment variable when running them to replace
+//! the reference images with new renders instead.
+
+mod compare;
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use kioku::Arena;
+
+use crate::{
+    color::xyz_to_rec709_e,
+    image::{read_pfm, Image, RgbImage},
+    logger::Logger,
+    parse::{parse_scene, DataTree},
+    render_settings::Integrator,
+    renderer::Renderer,
+};
+
+use self::compare::{compare, Tolerance};
+
+/// Leaves room for differences in floating point behavior across
+/// platforms, and nothing more.  Renders are deterministic, so on any one
+/// platform they should match their reference exactly.
+const TOLERANCE: Tolerance = Tolerance {
+    channel: 0.02,
+    outlier_fraction: 0.002,
+    rmse: 0.002,
+};
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+/// Builds the first scene in `tests/golden/<name>.psy` and calls `f`
+/// with its renderer.
+fn with_renderer<F, T>(name: &str, f: F) -> T
+where
+    F: FnOnce(&mut Renderer) -> T,
+{
+    let path = golden_dir().join(format!("{}.psy", name));
+    let contents = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("couldn't read '{}': {}", path.display(), e));
+    let tree = DataTree::from_str(&contents).unwrap();
+    let scene = if let DataTree::Internal { ref children, .. } = tree {
+        children
+            .iter()
+            .find(|child| child.type_name() == "Scene")
+            .expect("no scene in golden scene file")
+    } else {
+        unreachable!()
+    };
+
+    let arena = Arena::new().with_block_size((1 << 20) * 4);
+    let mut renderer = match parse_scene(&arena, scene, |_| {}, &mut Vec::new()) {
+        Ok(r) => r,
+        Err(e) => panic!("{}", e.message(&contents)),
+    };
+    f(&mut renderer)
+}
+
+/// Renders the first scene in `tests/golden/<name>.psy`.
+fn render_image(name: &str, thread_count: u32) -> Image {
+    with_renderer(name, |renderer| {
+        let (image, _) = renderer.render(1024, None, thread_count, false, None, &Logger::new());
+        image
+    })
+}
+
+/// Same as `render_image()`, but converted to linear rec709 like the
+/// reference images.
+fn render(name: &str, thread_count: u32) -> RgbImage {
+    let mut image = render_image(name, thread_count);
+    let (width, height) = (image.width(), image.height());
+    let mut data = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            data.push(xyz_to_rec709_e(image.get(x, y).to_tuple()));
+        }
+    }
+
+    RgbImage {
+        width: width,
+        height: height,
+        data: data,
+    }
+}
+
+/// Renders a golden scene and checks it against its reference image, or
+/// replaces the reference if blessing.
+fn check(name: &str) {
+    let reference_path = golden_dir().join(format!("{}.pfm", name));
+
+    if env::var_os("PSYCHOPATH_BLESS").is_some() {
+        render_image(name, 4).write_pfm(&reference_path).unwrap();
+        return;
+    }
+
+    let image = render(name, 4);
+    let reference = read_pfm(&reference_path).unwrap_or_else(|e| {
+        panic!(
+            "couldn't read reference image '{}': {}",
+            reference_path.display(),
+            e
+        )
+    });
+    let diff = compare(&image, &reference, &TOLERANCE);
+    assert!(
+        diff.is_within(&TOLERANCE),
+        "'{}' differs from its reference image: {:?}",
+        name,
+        diff
+    );
+}
+
+#[test]
+#[ignore]
+fn golden_cornell_box() {
+    check("cornell_box");
+}
+
+#[test]
+#[ignore]
+fn golden_lights() {
+    check("lights");
+}
+
+#[test]
+#[ignore]
+fn golden_thread_count_invariance() {
+    // Renders must be bit-identical regardless of threading.
+    let single = render("cornell_box", 1);
+    let multi = render("cornell_box", 7);
+    assert!(single == multi, "render differs with thread count");
+}
+
+#[test]
+#[ignore]
+fn golden_restir_ignores_auto_buckets() {
+    // ReSTIR's results depend on the buckets, so they mustn't be laid out
+    // by timing.
+    let render_restir = |auto_buckets: bool, thread_count: u32| {
+        with_renderer("cornell_box", |renderer| {
+            renderer.settings.restir = true;
+            renderer.settings.auto_buckets = auto_buckets;
+            let (mut image, _) =
+                renderer.render(1024, None, thread_count, false, None, &Logger::new());
+            let (width, height) = (image.width(), image.height());
+            let mut pixels = Vec::with_capacity(width * height);
+            for y in 0..height {
+                for x in 0..width {
+                    pixels.push(image.get(x, y).to_tuple());
+                }
+            }
+            pixels
+        })
+    };
+    let without = render_restir(false, 1);
+    let with = render_restir(true, 7);
+    assert!(without == with, "restir render differs with auto_buckets");
+}
+
+#[test]
+#[ignore]
+fn golden_traced_pixel_matches_render() {
+    // A pixel's traced samples add up to what the renderer gives it.
+    with_renderer("cornell_box", |renderer| {
+        let (mut image, _) = renderer.render(1024, None, 4, false, None, &Logger::new());
+        for &(x, y) in &[(10, 10), (24, 30), (16, 4)] {
+            let spp = renderer.settings.spp;
+            let mut sum = (0.0, 0.0, 0.0);
+            for si in 0..spp {
+                let (trace, col) = renderer.trace_pixel(x, y, si as u32);
+                assert!(trace.len() > 2);
+                let col = col.to_tuple();
+                sum = (sum.0 + col.0, sum.1 + col.1, sum.2 + col.2);
+            }
+
+            let expected = image.get(x as usize, y as usize).to_tuple();
+            for &(a, b) in &[
+                (sum.0 / spp as f32, expected.0),
+                (sum.1 / spp as f32, expected.1),
+                (sum.2 / spp as f32, expected.2),
+            ] {
+                assert!((a - b).abs() <= 1.0e-4 * (1.0 + b.abs()), "{} vs {}", a, b);
+            }
+        }
+    });
+}
+
+#[test]
+#[ignore]
+fn golden_path_tracing_matches_reference() {
+    // The path tracer should agree with the brute force reference
+    // integrator to within noise.  The images are compared in quadrants to
+    // average most of the noise away.
+    with_renderer("cornell_box", |renderer| {
+        renderer.settings.spp = 1024;
+        let quadrant_sums = |renderer: &Renderer| {
+            let (mut image, _) = renderer.render(1024, None, 4, false, None, &Logger::new());
+            let (w, h) = (image.width(), image.height());
+            let mut sums = [0.0f64; 4];
+            for y in 0..h {
+                for x in 0..w {
+                    let col = image.get(x, y).to_tuple();
+                    let q = (x * 2 / w) + (y * 2 / h) * 2;
+                    sums[q] += (col.0 + col.1 + col.2) as f64;
+                }
+            }
+            sums
+        };
+
+        let path_traced = quadrant_sums(renderer);
+        renderer.settings.integrator = Integrator::Reference;
+        let reference = quadrant_sums(renderer);
+        for (a, b) in path_traced.iter().zip(reference.iter()) {
+            assert!((a - b).abs() <= 0.03 * b, "{} vs {}", a, b);
+        }
+    });
+}
@@ -62,6 +62,81 @@ pub fn d2xy(d: u32) -> (u32, u32) {
     (x, y)
 }
 
+/// Generates a Hilbert-like curve that covers a `width` x `height` grid,
+/// for any width and height.
+///
+/// This is the "generalized Hilbert" curve of Jakub Červený's gilbert
+/// algorithm: the grid is recursively split in a way that keeps the curve
+/// local, like a Hilbert curve, without padding the grid out to a power of
+/// two.  Consecutive cells are always neighbors, except for at most one
+/// diagonal step when the grid has odd dimensions.
+///
+/// Returns the (x, y) coordinates of every cell, in curve order.
+pub fn generalized_curve(width: u32, height: u32) -> Vec<(u32, u32)> {
+    let mut cells = Vec::with_capacity(width as usize * height as usize);
+    if width == 0 || height == 0 {
+        return cells;
+    }
+
+    let (w, h) = (width as i32, height as i32);
+    if width >= height {
+        generalized_curve_rec((0, 0), (w, 0), (0, h), &mut cells);
+    } else {
+        generalized_curve_rec((0, 0), (0, h), (w, 0), &mut cells);
+    }
+
+    cells
+}
+
+/// Covers the rectangle with corner `p`, major axis `a`, and minor axis
+/// `b`, going along `a` overall.
+fn generalized_curve_rec(p: (i32, i32), a: (i32, i32), b: (i32, i32), cells: &mut Vec<(u32, u32)>) {
+    let w = (a.0 + a.1).abs();
+    let h = (b.0 + b.1).abs();
+    let da = (a.0.signum(), a.1.signum());
+    let db = (b.0.signum(), b.1.signum());
+
+    // Single row or column.
+    if h == 1 || w == 1 {
+        let (d, n) = if h == 1 { (da, w) } else { (db, h) };
+        for i in 0..n {
+            cells.push(((p.0 + d.0 * i) as u32, (p.1 + d.1 * i) as u32));
+        }
+        return;
+    }
+
+    let mut a2 = (a.0.div_euclid(2), a.1.div_euclid(2));
+    let mut b2 = (b.0.div_euclid(2), b.1.div_euclid(2));
+    let w2 = (a2.0 + a2.1).abs();
+    let h2 = (b2.0 + b2.1).abs();
+
+    if 2 * w > 3 * h {
+        // Long rectangle, so split it in two along its length.  Splits at
+        // even steps keep the halves' curves connected.
+        if w2 % 2 != 0 && w > 2 {
+            a2 = (a2.0 + da.0, a2.1 + da.1);
+        }
+        generalized_curve_rec(p, a2, b, cells);
+        generalized_curve_rec((p.0 + a2.0, p.1 + a2.1), (a.0 - a2.0, a.1 - a2.1), b, cells);
+    } else {
+        // Otherwise split it in three: up, across, and back down.
+        if h2 % 2 != 0 && h > 2 {
+            b2 = (b2.0 + db.0, b2.1 + db.1);
+        }
+        generalized_curve_rec(p, b2, a2, cells);
+        generalized_curve_rec((p.0 + b2.0, p.1 + b2.1), a, (b.0 - b2.0, b.1 - b2.1), cells);
+        generalized_curve_rec(
+            (
+                p.0 + (a.0 - da.0) + (b2.0 - db.0),
+                p.1 + (a.1 - da.1) + (b2.1 - db.1),
+            ),
+            (-b2.0, -b2.1),
+            (-(a.0 - a2.0), -(a.1 - a2.1)),
+            cells,
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,4 +149,51 @@ mod tests {
 
         assert_eq!(d, d2);
     }
+
+    #[test]
+    fn generalized_covers_grid() {
+        for &(w, h) in &[
+            (1, 1),
+            (1, 7),
+            (5, 1),
+            (4, 4),
+            (16, 9),
+            (9, 16),
+            (13, 7),
+            (30, 31),
+        ] {
+            let cells = generalized_curve(w, h);
+            assert_eq!(cells.len(), (w * h) as usize);
+
+            let mut seen = vec![false; (w * h) as usize];
+            for &(x, y) in &cells {
+                assert!(x < w && y < h);
+                assert!(!seen[(y * w + x) as usize], "{}x{} repeats a cell", w, h);
+                seen[(y * w + x) as usize] = true;
+            }
+
+            // Only neighboring steps, with at most one diagonal one.
+            let mut diagonal_steps = 0;
+            for pair in cells.windows(2) {
+                let dx = (pair[0].0 as i32 - pair[1].0 as i32).abs();
+                let dy = (pair[0].1 as i32 - pair[1].1 as i32).abs();
+                assert!(dx <= 1 && dy <= 1, "{}x{} jumps", w, h);
+                if dx + dy == 2 {
+                    diagonal_steps += 1;
+                }
+            }
+            assert!(diagonal_steps <= 1);
+        }
+    }
+
+    #[test]
+    fn generalized_even_is_connected() {
+        for &(w, h) in &[(8, 8), (12, 6), (6, 10), (32, 18)] {
+            for pair in generalized_curve(w, h).windows(2) {
+                let dx = (pair[0].0 as i32 - pair[1].0 as i32).abs();
+                let dy = (pair[0].1 as i32 - pair[1].1 as i32).abs();
+                assert_eq!(dx + dy, 1, "{}x{}", w, h);
+            }
+        }
+    }
 }
@@ -5,7 +5,7 @@ use std::{
     cmp,
     fs::File,
     io,
-    io::Write,
+    io::{BufRead, BufReader, Read, Write},
     marker::PhantomData,
     path::Path,
     sync::Mutex,
@@ -20,6 +20,8 @@ use crate::color::{xyz_to_rec709_e, XYZ};
 pub struct Image {
     data: UnsafeCell<Vec<XYZ>>,
     res: (usize, usize),
+    overscan: usize,   // Pixels on each side outside of the display window
+    pixel_aspect: f32, // Width of each pixel over its height
     checked_out_blocks: Mutex<RefCell<Vec<((u32, u32), (u32, u32))>>>, // (min, max)
 }
 
@@ -30,10 +32,29 @@ impl Image {
         Image {
             data: UnsafeCell::new(vec![XYZ::new(0.0, 0.0, 0.0); width * height]),
             res: (width, height),
+            overscan: 0,
+            pixel_aspect: 1.0,
             checked_out_blocks: Mutex::new(RefCell::new(Vec::new())),
         }
     }
 
+    /// Marks a border of `overscan` pixels on each side of the image as
+    /// being outside of the display window.  Exr files keep the border as
+    /// part of their data window, and the other formats leave it out.
+    pub fn with_overscan(mut self, overscan: usize) -> Image {
+        assert!(overscan * 2 < self.res.0.min(self.res.1));
+        self.overscan = overscan;
+        self
+    }
+
+    /// Sets the shape of the image's pixels, as their width over their
+    /// height, to be recorded in files that support it: exr, tiff, and
+    /// 16-bit png.
+    pub fn with_pixel_aspect(mut self, pixel_aspect: f32) -> Image {
+        self.pixel_aspect = pixel_aspect;
+        self
+    }
+
     pub fn width(&self) -> usize {
         self.res.0
     }
@@ -95,6 +116,43 @@ impl Image {
         }
     }
 
+    /// Returns a copy of the image's current contents, which can be taken
+    /// while buckets are still being rendered.
+    ///
+    /// Pixels in currently checked out buckets are still being written to,
+    /// so they are left black in the copy.
+    pub fn snapshot(&self) -> Image {
+        // Holding the lock prevents buckets from being checked out or
+        // returned while we copy.
+        let tmp = self.checked_out_blocks.lock().unwrap();
+        let bucket_list = tmp.borrow();
+
+        let data: &Vec<XYZ> = unsafe { &*self.data.get() };
+        let mut copy = vec![XYZ::new(0.0, 0.0, 0.0); data.len()];
+        for y in 0..self.res.1 {
+            for x in 0..self.res.0 {
+                let checked_out = bucket_list.iter().any(|&(min, max)| {
+                    (x as u32) >= min.0
+                        && (x as u32) < max.0
+                        && (y as u32) >= min.1
+                        && (y as u32) < max.1
+                });
+                if !checked_out {
+                    let i = self.res.0 * y + x;
+                    copy[i] = data[i];
+                }
+            }
+        }
+
+        Image {
+            data: UnsafeCell::new(copy),
+            res: self.res,
+            overscan: self.overscan,
+            pixel_aspect: self.pixel_aspect,
+            checked_out_blocks: Mutex::new(RefCell::new(Vec::new())),
+        }
+    }
+
     pub fn write_ascii_ppm(&mut self, path: &Path) -> io::Result<()> {
         // Open file.
         let mut f = io::BufWriter::new(File::create(path)?);
@@ -135,7 +193,69 @@ impl Image {
         Ok(())
     }
 
-    pub fn write_png(&mut self, path: &Path) -> io::Result<()> {
+    /// Writes the image in the given format, with `encoding` for the
+    /// integer formats.
+    pub fn write(
+        &mut self,
+        path: &Path,
+        format: ImageFormat,
+        encoding: ImageEncoding,
+    ) -> io::Result<()> {
+        if self.overscan > 0 && format != ImageFormat::Exr {
+            return self.display_window().write(path, format, encoding);
+        }
+        match format {
+            ImageFormat::Png => self.write_png(path, encoding),
+            ImageFormat::Tiff => self.write_tiff(path, encoding),
+            ImageFormat::Exr => {
+                self.write_exr(path);
+                Ok(())
+            }
+            ImageFormat::Pfm => self.write_pfm(path),
+        }
+    }
+
+    /// A copy of the part of the image inside the display window.
+    fn display_window(&mut self) -> Image {
+        let o = self.overscan;
+        let mut window = Image::new(self.res.0 - (o * 2), self.res.1 - (o * 2))
+            .with_pixel_aspect(self.pixel_aspect);
+        for y in 0..window.res.1 {
+            for x in 0..window.res.0 {
+                let value = self.get(x + o, y + o);
+                window.set(x, y, value);
+            }
+        }
+        window
+    }
+
+    /// The pixel at (x, y) in rec709 space, encoded with `transfer`.
+    fn encoded_rgb(&mut self, x: usize, y: usize, transfer: Transfer) -> (f32, f32, f32) {
+        let (r, g, b) = xyz_to_rec709_e(self.get(x, y).to_tuple());
+        (transfer.encode(r), transfer.encode(g), transfer.encode(b))
+    }
+
+    pub fn write_png(&mut self, path: &Path, encoding: ImageEncoding) -> io::Result<()> {
+        if encoding.bit_depth == 16 {
+            let mut rows = Vec::with_capacity(self.res.1);
+            for y in 0..self.res.1 {
+                let mut row = Vec::with_capacity(self.res.0 * 6);
+                for x in 0..self.res.0 {
+                    let (r, g, b) = self.encoded_rgb(x, y, encoding.transfer);
+                    for n in &[r, g, b] {
+                        row.extend_from_slice(&quantize_16(*n).to_be_bytes());
+                    }
+                }
+                rows.push(row);
+            }
+            return write_png_rgb16(
+                &mut io::BufWriter::new(File::create(path)?),
+                (self.res.0 as u32, self.res.1 as u32),
+                self.pixel_aspect,
+                &rows,
+            );
+        }
+
         let mut image = Vec::new();
 
         // Convert pixels
@@ -144,7 +264,7 @@ impl Image {
         for y in 0..res_y {
             for x in 0..res_x {
                 let (r, g, b) =
-                    quantize_tri_255(xyz_to_srgbe(self.get(x, res_y - 1 - y).to_tuple()));
+                    quantize_tri_255(self.encoded_rgb(x, res_y - 1 - y, encoding.transfer));
                 image.push(r);
                 image.push(g);
                 image.push(b);
@@ -164,6 +284,59 @@ impl Image {
         Ok(())
     }
 
+    /// Writes the image as an uncompressed RGB TIFF, with 8 or 16 bits per
+    /// channel.
+    pub fn write_tiff(&mut self, path: &Path, encoding: ImageEncoding) -> io::Result<()> {
+        let bytes_per_channel = (encoding.bit_depth / 8) as usize;
+        let mut data = Vec::with_capacity(self.res.0 * self.res.1 * 3 * bytes_per_channel);
+        for y in 0..self.res.1 {
+            for x in 0..self.res.0 {
+                let (r, g, b) = self.encoded_rgb(x, y, encoding.transfer);
+                if bytes_per_channel == 2 {
+                    for n in &[r, g, b] {
+                        data.extend_from_slice(&quantize_16(*n).to_le_bytes());
+                    }
+                } else {
+                    let (r, g, b) = quantize_tri_255((r, g, b));
+                    data.extend_from_slice(&[r, g, b]);
+                }
+            }
+        }
+
+        write_tiff_rgb(
+            &mut io::BufWriter::new(File::create(path)?),
+            (self.res.0 as u32, self.res.1 as u32),
+            self.pixel_aspect,
+            encoding.bit_depth as u16,
+            &data,
+        )
+    }
+
+    /// Writes the image as a Portable Float Map, in linear rec709 space.
+    ///
+    /// Unlike png this is lossless, which makes it handy for comparing
+    /// renders.
+    pub fn write_pfm(&mut self, path: &Path) -> io::Result<()> {
+        // Open file.
+        let mut f = io::BufWriter::new(File::create(path)?);
+
+        // Write header.  The negative scale indicates little endian data.
+        write!(f, "PF\n{} {}\n-1.0\n", self.res.0, self.res.1)?;
+
+        // Write pixels.  PFM scanlines go from bottom to top.
+        for y in (0..self.res.1).rev() {
+            for x in 0..self.res.0 {
+                let (r, g, b) = xyz_to_rec709_e(self.get(x, y).to_tuple());
+                f.write_all(&r.to_le_bytes())?;
+                f.write_all(&g.to_le_bytes())?;
+                f.write_all(&b.to_le_bytes())?;
+            }
+        }
+
+        // Done
+        Ok(())
+    }
+
     pub fn write_exr(&mut self, path: &Path) {
         let mut image = Vec::new();
 
@@ -175,11 +348,17 @@ impl Image {
             }
         }
 
+        // The display window starts at the origin, and any overscan extends
+        // the data window past it.
+        let o = self.overscan as i32;
+        let (w, h) = (self.res.0 as i32, self.res.1 as i32);
         let mut file = io::BufWriter::new(File::create(path).unwrap());
         let mut wr = openexr::ScanlineOutputFile::new(
             &mut file,
             openexr::Header::new()
-                .set_resolution(self.res.0 as u32, self.res.1 as u32)
+                .set_resolution((w - (o * 2)) as u32, (h - (o * 2)) as u32)
+                .set_data_window((-o, -o), (w - o - 1, h - o - 1))
+                .set_pixel_aspect_ratio(self.pixel_aspect)
                 .add_channel("R", openexr::PixelType::HALF)
                 .add_channel("G", openexr::PixelType::HALF)
                 .add_channel("B", openexr::PixelType::HALF)
@@ -188,13 +367,173 @@ impl Image {
         .unwrap();
 
         wr.write_pixels(
-            openexr::FrameBuffer::new(self.res.0 as u32, self.res.1 as u32)
+            openexr::FrameBuffer::new_with_origin(-o, -o, w as u32, h as u32)
                 .insert_channels(&["R", "G", "B"], &image),
         )
         .unwrap();
     }
 }
 
+/// A file format that images can be written in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Tiff,
+    Exr,
+    Pfm,
+}
+
+impl ImageFormat {
+    /// The format to write a file in, from its extension.
+    pub fn from_path(path: &str) -> Option<ImageFormat> {
+        let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "png" => Some(ImageFormat::Png),
+            "tif" | "tiff" => Some(ImageFormat::Tiff),
+            "exr" => Some(ImageFormat::Exr),
+            "pfm" => Some(ImageFormat::Pfm),
+            _ => None,
+        }
+    }
+}
+
+/// The transfer function that maps linear values to the integer values
+/// stored in png and tiff files.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Transfer {
+    Srgb,
+    Linear,
+
+    /// SMPTE ST 2084, for HDR displays.  A linear value of 1.0 is taken to
+    /// be 100 nits, of the 10000 that the curve covers.
+    Pq,
+}
+
+impl Transfer {
+    pub fn from_spec(spec: &str) -> Result<Transfer, String> {
+        match spec.trim() {
+            "srgb" => Ok(Transfer::Srgb),
+            "linear" => Ok(Transfer::Linear),
+            "pq" => Ok(Transfer::Pq),
+            _ => Err(format!(
+                "unknown transfer function '{}', expected srgb, linear, or pq",
+                spec
+            )),
+        }
+    }
+
+    /// Maps a linear value to the 0-1 range that's stored.
+    pub fn encode(self, n: f32) -> f32 {
+        match self {
+            Transfer::Srgb => srgb_gamma(n),
+            Transfer::Linear => n,
+            Transfer::Pq => {
+                const M1: f32 = 2610.0 / 16384.0;
+                const M2: f32 = 2523.0 / 4096.0 * 128.0;
+                const C1: f32 = 3424.0 / 4096.0;
+                const C2: f32 = 2413.0 / 4096.0 * 32.0;
+                const C3: f32 = 2392.0 / 4096.0 * 32.0;
+                let l = (n * 0.01).max(0.0).powf(M1);
+                ((C1 + (C2 * l)) / (1.0 + (C3 * l))).powf(M2)
+            }
+        }
+    }
+}
+
+/// How pixels are stored in the integer formats, png and tiff.  The float
+/// formats are always linear, and ignore this.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ImageEncoding {
+    pub bit_depth: u32, // 8 or 16
+    pub transfer: Transfer,
+}
+
+impl Default for ImageEncoding {
+    fn default() -> ImageEncoding {
+        ImageEncoding {
+            bit_depth: 8,
+            transfer: Transfer::Srgb,
+        }
+    }
+}
+
+/// How a pixel's samples are summed.
+///
+/// Single precision is fastest, but at very high sample counts each new
+/// sample is tiny compared to the running sum and loses most of its
+/// precision when added.  The other modes keep reference renders accurate.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Accumulation {
+    Single, // Plain f32 sums
+    Double, // f64 sums
+    Kahan,  // f32 sums with Kahan compensation
+}
+
+impl Accumulation {
+    pub fn from_spec(spec: &str) -> Result<Accumulation, String> {
+        match spec {
+            "f32" => Ok(Accumulation::Single),
+            "f64" => Ok(Accumulation::Double),
+            "kahan" => Ok(Accumulation::Kahan),
+            _ => Err(format!(
+                "unknown accumulation '{}', expected 'f32', 'f64' or 'kahan'",
+                spec
+            )),
+        }
+    }
+}
+
+/// The running sum of a pixel's samples, in any of the `Accumulation`
+/// modes.
+///
+/// Single precision sums are rounded to f32 after every addition, so they
+/// match summing in an `XYZ` exactly.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PixelSum {
+    sum: [f64; 3],
+    compensation: [f32; 3], // Low-order bits lost from `sum`, for Kahan
+}
+
+impl PixelSum {
+    pub fn new() -> PixelSum {
+        PixelSum::default()
+    }
+
+    /// A sum that's exactly the given color.
+    pub fn from_xyz(col: XYZ) -> PixelSum {
+        PixelSum {
+            sum: [col.x as f64, col.y as f64, col.z as f64],
+            compensation: [0.0; 3],
+        }
+    }
+
+    #[inline]
+    pub fn add(&mut self, col: XYZ, mode: Accumulation) {
+        let col = [col.x, col.y, col.z];
+        for i in 0..3 {
+            match mode {
+                Accumulation::Single => {
+                    self.sum[i] = (self.sum[i] as f32 + col[i]) as f64;
+                }
+                Accumulation::Double => {
+                    self.sum[i] += col[i] as f64;
+                }
+                Accumulation::Kahan => {
+                    let sum = self.sum[i] as f32;
+                    let y = col[i] - self.compensation[i];
+                    let t = sum + y;
+                    self.compensation[i] = (t - sum) - y;
+                    self.sum[i] = t as f64;
+                }
+            }
+        }
+    }
+
+    pub fn total(&self) -> XYZ {
+        XYZ::new(self.sum[0] as f32, self.sum[1] as f32, self.sum[2] as f32)
+    }
+}
+
 #[derive(Debug)]
 pub struct Bucket<'a> {
     min: (u32, u32),
@@ -293,6 +632,10 @@ fn xyz_to_srgbe(xyz: (f32, f32, f32)) -> (f32, f32, f32) {
     (srgb_gamma(rgb.0), srgb_gamma(rgb.1), srgb_gamma(rgb.2))
 }
 
+fn quantize_16(n: f32) -> u16 {
+    (1.0f32.min(0.0f32.max(n)) * 65535.0).round() as u16
+}
+
 fn quantize_tri_255(tri: (f32, f32, f32)) -> (u8, u8, u8) {
     fn quantize(n: f32) -> u8 {
         let n = 1.0f32.min(0.0f32.max(n)) * 255.0;
@@ -301,3 +644,331 @@ fn quantize_tri_255(tri: (f32, f32, f32)) -> (u8, u8, u8) {
 
     (quantize(tri.0), quantize(tri.1), quantize(tri.2))
 }
+
+/// Writes a 16-bit RGB png, given its rows of big-endian samples.
+///
+/// The image data is stored rather than compressed, which png_encode_mini
+/// also does for 8-bit images, and keeps this simple.
+fn write_png_rgb16<W: Write>(
+    w: &mut W,
+    res: (u32, u32),
+    pixel_aspect: f32,
+    rows: &[Vec<u8>],
+) -> io::Result<()> {
+    fn chunk<W: Write>(w: &mut W, chunk_type: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        w.write_all(&(data.len() as u32).to_be_bytes())?;
+        w.write_all(chunk_type)?;
+        w.write_all(data)?;
+        let crc = crc32(crc32(0, chunk_type), data);
+        w.write_all(&crc.to_be_bytes())
+    }
+
+    w.write_all(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'])?;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&res.0.to_be_bytes());
+    header.extend_from_slice(&res.1.to_be_bytes());
+    header.extend_from_slice(&[16, 2, 0, 0, 0]); // Depth, RGB, compression, filter, interlace
+    chunk(w, b"IHDR", &header)?;
+
+    // Non-square pixels, as a ratio of pixels per unit with no unit.
+    if pixel_aspect != 1.0 {
+        let (x, y) = aspect_ratio_fraction(pixel_aspect);
+        let mut phys = Vec::new();
+        phys.extend_from_slice(&x.to_be_bytes());
+        phys.extend_from_slice(&y.to_be_bytes());
+        phys.push(0);
+        chunk(w, b"pHYs", &phys)?;
+    }
+
+    // Each row starts with its filter type, which is none.
+    let mut raw = Vec::new();
+    for row in rows {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // A zlib stream of stored deflate blocks.
+    let mut zlib = vec![0x78, 0x01];
+    let block_count = raw.chunks(0xffff).count();
+    for (i, block) in raw.chunks(0xffff).enumerate() {
+        let len = block.len() as u16;
+        zlib.push(if i + 1 == block_count { 1 } else { 0 });
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    if raw.is_empty() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+    chunk(w, b"IDAT", &zlib)?;
+
+    chunk(w, b"IEND", &[])
+}
+
+/// Writes a single-strip, uncompressed, little-endian RGB tiff.
+fn write_tiff_rgb<W: Write>(
+    w: &mut W,
+    res: (u32, u32),
+    pixel_aspect: f32,
+    bits_per_sample: u16,
+    data: &[u8],
+) -> io::Result<()> {
+    // Layout: header, bits per sample, resolution, image data, then the
+    // directory of tags.
+    const BITS_OFFSET: u32 = 8;
+    const RESOLUTION_OFFSET: u32 = BITS_OFFSET + 6;
+    const DATA_OFFSET: u32 = RESOLUTION_OFFSET + 16;
+    let ifd_offset = DATA_OFFSET + data.len() as u32 + (data.len() as u32 & 1);
+
+    w.write_all(b"II")?;
+    w.write_all(&42u16.to_le_bytes())?;
+    w.write_all(&ifd_offset.to_le_bytes())?;
+    for _ in 0..3 {
+        w.write_all(&bits_per_sample.to_le_bytes())?;
+    }
+    // Pixels per unit, horizontally then vertically, with no unit.
+    let (x, y) = aspect_ratio_fraction(pixel_aspect);
+    for &n in &[x, 1, y, 1] {
+        w.write_all(&n.to_le_bytes())?;
+    }
+    w.write_all(data)?;
+    if data.len() & 1 == 1 {
+        w.write_all(&[0])?; // The directory has to start on a word boundary
+    }
+
+    // (tag, type, count, value), with types 3 = short, 4 = long and
+    // 5 = rational.
+    let entries: [(u16, u16, u32, u32); 12] = [
+        (256, 4, 1, res.0),                 // Width
+        (257, 4, 1, res.1),                 // Height
+        (258, 3, 3, BITS_OFFSET),           // Bits per sample
+        (259, 3, 1, 1),                     // No compression
+        (262, 3, 1, 2),                     // RGB
+        (273, 4, 1, DATA_OFFSET),           // Strip offset
+        (277, 3, 1, 3),                     // Samples per pixel
+        (278, 4, 1, res.1),                 // Rows per strip
+        (279, 4, 1, data.len() as u32),     // Strip byte count
+        (282, 5, 1, RESOLUTION_OFFSET),     // X resolution
+        (283, 5, 1, RESOLUTION_OFFSET + 8), // Y resolution
+        (296, 3, 1, 1),                     // No resolution unit
+    ];
+    w.write_all(&(entries.len() as u16).to_le_bytes())?;
+    for &(tag, field_type, count, value) in &entries {
+        w.write_all(&tag.to_le_bytes())?;
+        w.write_all(&field_type.to_le_bytes())?;
+        w.write_all(&count.to_le_bytes())?;
+        if field_type == 3 && count == 1 {
+            // Shorts are packed into the start of the value.
+            w.write_all(&(value as u16).to_le_bytes())?;
+            w.write_all(&[0, 0])?;
+        } else {
+            w.write_all(&value.to_le_bytes())?;
+        }
+    }
+    w.write_all(&0u32.to_le_bytes()) // No more directories
+}
+
+/// Horizontal and vertical pixel densities, as integers, for pixels of
+/// the given aspect ratio.  Wide pixels are fewer per unit across.
+fn aspect_ratio_fraction(pixel_aspect: f32) -> (u32, u32) {
+    if pixel_aspect == 1.0 {
+        (1, 1)
+    } else {
+        (10_000, (pixel_aspect * 10_000.0).round().max(1.0) as u32)
+    }
+}
+
+/// A linear rgb image, stored in scanline order from top to bottom.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<(f32, f32, f32)>,
+}
+
+/// Reads a little-endian color Portable Float Map, as written by
+/// `Image::write_pfm()`.
+pub fn read_pfm(path: &Path) -> io::Result<RgbImage> {
+    let mut f = BufReader::new(File::open(path)?);
+    let bad_header = || io::Error::new(io::ErrorKind::InvalidData, "malformed pfm header");
+
+    // Read header, which is three whitespace-terminated lines.
+    let mut header = String::new();
+    for _ in 0..3 {
+        if f.read_line(&mut header)? == 0 {
+            return Err(bad_header());
+        }
+    }
+    let mut tokens = header.split_whitespace();
+    if tokens.next() != Some("PF") {
+        return Err(bad_header());
+    }
+    let width: usize = tokens
+        .next()
+        .and_then(|t| t.parse().ok())
+        .ok_or_else(bad_header)?;
+    let height: usize = tokens
+        .next()
+        .and_then(|t| t.parse().ok())
+        .ok_or_else(bad_header)?;
+    let scale: f32 = tokens
+        .next()
+        .and_then(|t| t.parse().ok())
+        .ok_or_else(bad_header)?;
+    if scale >= 0.0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "big endian pfm files aren't supported",
+        ));
+    }
+
+    // Read pixels, flipping the scanlines from bottom-to-top order.
+    let mut data = vec![(0.0, 0.0, 0.0); width * height];
+    let mut buf = [0u8; 12];
+    for y in (0..height).rev() {
+        for x in 0..width {
+            f.read_exact(&mut buf)?;
+            data[y * width + x] = (
+                f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+                f32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+                f32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            );
+        }
+    }
+
+    Ok(RgbImage {
+        width: width,
+        height: height,
+        data: data,
+    })
+}
+
+/// Continues the CRC-32 `crc` (0 to start) over `data`, as used by png.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// The Adler-32 checksum of `data`, as used by zlib.
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sum_samples(mode: Accumulation, count: usize) -> f32 {
+        let mut sum = PixelSum::new();
+        for _ in 0..count {
+            sum.add(XYZ::new(0.1, 0.1, 0.1), mode);
+        }
+        sum.total().y
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(0, b"IEND"), 0xae42_6082);
+        assert_eq!(crc32(crc32(0, b"IE"), b"ND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn transfer_functions() {
+        assert_eq!(Transfer::Linear.encode(0.25), 0.25);
+        assert!((Transfer::Srgb.encode(0.5) - 0.735_357).abs() < 1.0e-5);
+
+        // 10000 nits is the top of the curve, and 100 nits about half way.
+        assert!((Transfer::Pq.encode(100.0) - 1.0).abs() < 1.0e-5);
+        assert!((Transfer::Pq.encode(1.0) - 0.508).abs() < 1.0e-3);
+        assert!(Transfer::Pq.encode(0.0) < 1.0e-5);
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(ImageFormat::from_path("a/b.png"), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::from_path("b.TIF"), Some(ImageFormat::Tiff));
+        assert_eq!(ImageFormat::from_path("b.tiff"), Some(ImageFormat::Tiff));
+        assert_eq!(ImageFormat::from_path("b.exr"), Some(ImageFormat::Exr));
+        assert_eq!(ImageFormat::from_path("b.jpg"), None);
+        assert_eq!(ImageFormat::from_path("png"), None);
+    }
+
+    #[test]
+    fn tiff_layout() {
+        let data = [1u8, 2, 3, 4, 5, 6, 7, 8, 9];
+        let mut file = Vec::new();
+        write_tiff_rgb(&mut file, (3, 1), 2.0, 8, &data).unwrap();
+
+        // The directory comes after the (padded) data, and has 12 entries
+        // of 12 bytes each.
+        let ifd = u32::from_le_bytes([file[4], file[5], file[6], file[7]]) as usize;
+        assert_eq!(&file[..4], b"II*\0");
+        assert_eq!(ifd, 30 + 10);
+        assert_eq!(&file[30..39], &data);
+
+        // Wide pixels are half as dense across as down.
+        let density =
+            |i: usize| u32::from_le_bytes([file[i], file[i + 1], file[i + 2], file[i + 3]]);
+        assert_eq!(density(14) * 2, density(22));
+        assert_eq!(file.len(), ifd + 2 + (12 * 12) + 4);
+    }
+
+    #[test]
+    fn png16_layout() {
+        let rows = vec![vec![0u8; 12], vec![0xff; 12]];
+        let mut file = Vec::new();
+        write_png_rgb16(&mut file, (2, 2), 1.0, &rows).unwrap();
+        assert_eq!(&file[1..4], b"PNG");
+        assert_eq!(&file[12..16], b"IHDR");
+        assert_eq!(file[24], 16); // Bit depth
+
+        // Stored zlib data: two rows of a filter byte and twelve samples.
+        let idat = 8 + 25;
+        assert_eq!(&file[idat + 4..idat + 8], b"IDAT");
+        let len = u32::from_be_bytes([file[idat], file[idat + 1], file[idat + 2], file[idat + 3]]);
+        assert_eq!(len, 2 + 5 + 26 + 4);
+        assert_eq!(&file[file.len() - 8..file.len() - 4], b"IEND");
+    }
+
+    #[test]
+    fn precise_accumulation() {
+        // One million samples of 0.1 should sum to 100000.
+        let expected = 100_000.0;
+        let single = sum_samples(Accumulation::Single, 1_000_000);
+        let double = sum_samples(Accumulation::Double, 1_000_000);
+        let kahan = sum_samples(Accumulation::Kahan, 1_000_000);
+        assert!((single - expected).abs() > 100.0);
+        assert!((double - expected).abs() < 0.01);
+        assert!((kahan - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn single_matches_xyz_sum() {
+        let mut sum = PixelSum::new();
+        let mut xyz = XYZ::new(0.0, 0.0, 0.0);
+        for i in 0..1000 {
+            let col = XYZ::new(i as f32 * 0.37, 1.0 / (i + 1) as f32, 0.01);
+            sum.add(col, Accumulation::Single);
+            xyz += col;
+        }
+        assert_eq!(sum.total().to_tuple(), xyz.to_tuple());
+    }
+}
@@ -0,0 +1,559 @@
+//! An irradiance cache, for fast but biased diffuse interreflection.
+//!
+//! This follows "A Ray Tracing Solution for Diffuse Interreflection" by
+//! Ward et al.  Irradiance is computed sparsely at cache records, by
+//! gathering the radiance arriving over a stratified hemisphere, and is
+//! interpolated between them.  Each record also stores the rotational and
+//! translational gradients of its irradiance, from "Irradiance Gradients"
+//! by Ward and Heckbert, which are used to extrapolate it to nearby points.
+//!
+//! Records are stored in an octree, to quickly find the records whose
+//! area of influence overlaps a point.
+
+use std::f32::consts::PI;
+
+use crate::{
+    bbox::BBox,
+    color::XYZ,
+    math::{clamp, coordinate_system_from_vector, cross, dot, Point, Vector},
+};
+
+/// The deepest the octree is subdivided.
+const MAX_DEPTH: u32 = 20;
+
+/// A stratified hemisphere of directions to gather the radiance for a
+/// record from.
+///
+/// The strata are laid out in theta and phi, such that each has the same
+/// projected solid angle.  So the directions are cosine distributed, and
+/// the irradiance is simply the average radiance times pi.
+#[derive(Debug, Copy, Clone)]
+pub struct Hemisphere {
+    nor: Vector,
+    tangent_1: Vector,
+    tangent_2: Vector,
+    theta_strata: usize,
+    phi_strata: usize,
+}
+
+/// The radiance gathered from a direction of a `Hemisphere`, and the
+/// distance to the nearest surface in that direction.
+#[derive(Debug, Copy, Clone)]
+pub struct GatherSample {
+    pub dir: Vector,
+    pub radiance: XYZ,
+    pub distance: f32,
+}
+
+impl Hemisphere {
+    /// Creates a hemisphere around `nor` with roughly `sample_count`
+    /// strata, split between theta and phi in the 1:pi ratio Ward et al.
+    /// recommend.
+    pub fn new(nor: Vector, sample_count: usize) -> Hemisphere {
+        let (nor, tangent_1, tangent_2) = coordinate_system_from_vector(nor.normalized());
+        let theta_strata = ((sample_count as f32 / PI).sqrt().round() as usize).max(2);
+        let phi_strata = (sample_count / theta_strata).max(3);
+        Hemisphere {
+            nor: nor,
+            tangent_1: tangent_1,
+            tangent_2: tangent_2,
+            theta_strata: theta_strata,
+            phi_strata: phi_strata,
+        }
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.theta_strata * self.phi_strata
+    }
+
+    /// The direction of sample `i`, jittered within its stratum by `uv`.
+    pub fn dir(&self, i: usize, uv: (f32, f32)) -> Vector {
+        let (j, k) = (i / self.phi_strata, i % self.phi_strata);
+        let cos_theta = (1.0 - ((j as f32 + uv.0) / self.theta_strata as f32))
+            .max(0.0)
+            .sqrt();
+        let sin_theta = (1.0 - (cos_theta * cos_theta)).max(0.0).sqrt();
+        let phi = 2.0 * PI * (k as f32 + uv.1) / self.phi_strata as f32;
+        self.world_dir(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+
+    fn world_dir(&self, x: f32, y: f32, z: f32) -> Vector {
+        (self.tangent_1 * x) + (self.tangent_2 * y) + (self.nor * z)
+    }
+
+    /// Computes a record at `pos` from the samples gathered for each of
+    /// the hemisphere's directions, in order.
+    ///
+    /// The record's radius is the harmonic mean distance to the
+    /// surrounding surfaces, limited by the irradiance gradient as in
+    /// "Radiance Caching for Efficient Global Illumination Computation" by
+    /// Krivanek et al., and then clamped to `radius_range`.
+    pub fn record(
+        &self,
+        pos: Point,
+        samples: &[GatherSample],
+        radius_range: (f32, f32),
+    ) -> IrradianceRecord {
+        assert_eq!(samples.len(), self.sample_count());
+        let (m, n) = (self.theta_strata, self.phi_strata);
+        let radiance = |j: usize, k: usize| -> [f32; 3] {
+            let l = samples[j * n + k].radiance;
+            [l.x, l.y, l.z]
+        };
+        let distance = |j: usize, k: usize| samples[j * n + k].distance;
+
+        let mut irradiance = [0.0f32; 3];
+        let mut rot_gradient = [Vector::new(0.0, 0.0, 0.0); 3];
+        let mut trans_gradient = [Vector::new(0.0, 0.0, 0.0); 3];
+        let mut inv_distance_sum = 0.0f32;
+
+        let sample_weight = PI / samples.len() as f32;
+        for s in samples {
+            let l = [s.radiance.x, s.radiance.y, s.radiance.z];
+
+            // Rotating the normal by a small angle about some axis changes
+            // the cosine weight of the sample by the angle times the
+            // component of nor x dir along that axis.
+            let cos_theta = dot(s.dir, self.nor).max(0.0001);
+            let rot = cross(self.nor, s.dir) * (sample_weight / cos_theta);
+            for c in 0..3 {
+                irradiance[c] += l[c] * sample_weight;
+                rot_gradient[c] = rot_gradient[c] + (rot * l[c]);
+            }
+            if s.distance > 0.0 {
+                inv_distance_sum += 1.0 / s.distance;
+            }
+        }
+
+        // The translational gradient comes from how the boundaries between
+        // strata move as the record moves, which depends on the distance
+        // to what's seen through them.
+        for k in 0..n {
+            let k_prev = (k + n - 1) % n;
+            let phi_center = 2.0 * PI * (k as f32 + 0.5) / n as f32;
+            let phi_edge = 2.0 * PI * k as f32 / n as f32;
+            let u = self.world_dir(phi_center.cos(), phi_center.sin(), 0.0);
+            let v = self.world_dir(-phi_edge.sin(), phi_edge.cos(), 0.0);
+            for j in 0..m {
+                let l = radiance(j, k);
+
+                // Boundary with the previous stratum in theta.
+                if j > 0 {
+                    let cos_min = (1.0 - (j as f32 / m as f32)).sqrt();
+                    let sin_min = (j as f32 / m as f32).sqrt();
+                    let dist = distance(j, k).min(distance(j - 1, k));
+                    let fac = (2.0 * PI / n as f32) * sin_min * cos_min * cos_min / dist;
+                    let l_prev = radiance(j - 1, k);
+                    for c in 0..3 {
+                        trans_gradient[c] = trans_gradient[c] + (u * (fac * (l[c] - l_prev[c])));
+                    }
+                }
+
+                // Boundary with the previous stratum in phi.
+                let sin_min = (j as f32 / m as f32).sqrt();
+                let sin_max = ((j + 1) as f32 / m as f32).sqrt();
+                let dist = distance(j, k).min(distance(j, k_prev));
+                let fac = (sin_max - sin_min) / dist;
+                let l_prev = radiance(j, k_prev);
+                for c in 0..3 {
+                    trans_gradient[c] = trans_gradient[c] + (v * (fac * (l[c] - l_prev[c])));
+                }
+            }
+        }
+
+        let radius = {
+            let harmonic_mean = if inv_distance_sum > 0.0 {
+                samples.len() as f32 / inv_distance_sum
+            } else {
+                f32::INFINITY
+            };
+            // Limit by the gradient of luminance.
+            let gradient_len = trans_gradient[1].length();
+            let gradient_limit = if gradient_len > 0.0 {
+                irradiance[1] / gradient_len
+            } else {
+                f32::INFINITY
+            };
+            clamp(
+                harmonic_mean.min(gradient_limit),
+                radius_range.0,
+                radius_range.1,
+            )
+        };
+
+        IrradianceRecord {
+            pos: pos,
+            nor: self.nor,
+            radius: radius,
+            irradiance: XYZ::new(irradiance[0], irradiance[1], irradiance[2]),
+            rot_gradient: rot_gradient,
+            trans_gradient: trans_gradient,
+        }
+    }
+}
+
+/// A cache record: the irradiance at a point, and its gradients.
+#[derive(Debug, Copy, Clone)]
+pub struct IrradianceRecord {
+    pos: Point,
+    nor: Vector,
+    radius: f32,
+    irradiance: XYZ,
+    rot_gradient: [Vector; 3],   // Per XYZ channel
+    trans_gradient: [Vector; 3], // Per XYZ channel
+}
+
+impl IrradianceRecord {
+    /// The record's irradiance extrapolated to a nearby point and normal
+    /// with its gradients.
+    fn extrapolate(&self, pos: Point, nor: Vector) -> XYZ {
+        let offset = pos - self.pos;
+        let rotation = cross(self.nor, nor);
+        let e = [self.irradiance.x, self.irradiance.y, self.irradiance.z];
+        let mut out = [0.0f32; 3];
+        for c in 0..3 {
+            out[c] =
+                (e[c] + dot(rotation, self.rot_gradient[c]) + dot(offset, self.trans_gradient[c]))
+                    .max(0.0);
+        }
+        XYZ::new(out[0], out[1], out[2])
+    }
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    records: Vec<usize>,
+    children: Option<Box<[Node; 8]>>,
+}
+
+/// A collection of irradiance records, which interpolates between them.
+#[derive(Debug)]
+pub struct IrradianceCache {
+    accuracy: f32,
+    bounds: BBox,
+    root: Node,
+    records: Vec<IrradianceRecord>,
+}
+
+impl IrradianceCache {
+    /// Creates an empty cache for records within `bounds`.
+    ///
+    /// `accuracy` is the largest interpolation error allowed, in the terms
+    /// of Ward et al.'s error estimate.  Smaller values use each record
+    /// over a smaller area, so more records are needed.
+    pub fn new(bounds: BBox, accuracy: f32) -> IrradianceCache {
+        // Make the bounds a cube, so the octree nodes are too.
+        let center = center(&bounds);
+        let half_size = (bounds.max - bounds.min).co.max_element() * 0.5;
+        let half_diag = Vector::new(half_size, half_size, half_size);
+        IrradianceCache {
+            accuracy: accuracy,
+            bounds: BBox::from_points(center - half_diag, center + half_diag),
+            root: Node::default(),
+            records: Vec::new(),
+        }
+    }
+
+    pub fn record_count(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Adds a record to the cache.
+    pub fn insert(&mut self, record: IrradianceRecord) {
+        let idx = self.records.len();
+        self.records.push(record);
+
+        // The area the record is used over.
+        let reach = record.radius * self.accuracy;
+        let reach = Vector::new(reach, reach, reach);
+        let rec_bounds = BBox::from_points(record.pos - reach, record.pos + reach);
+
+        insert_into(&mut self.root, self.bounds, idx, rec_bounds, 0);
+    }
+
+    /// The interpolated irradiance at a point on a surface with normal
+    /// `nor`, or `None` if no records are close enough to it.
+    pub fn irradiance(&self, pos: Point, nor: Vector) -> Option<XYZ> {
+        if !contains(&self.bounds, pos) {
+            return None;
+        }
+        let nor = nor.normalized();
+
+        let mut sum = XYZ::new(0.0, 0.0, 0.0);
+        let mut weight_sum = 0.0f32;
+        let mut node = &self.root;
+        let mut bounds = self.bounds;
+        loop {
+            for &i in &node.records {
+                let rec = &self.records[i];
+                let offset = pos - rec.pos;
+
+                // Ward et al.'s estimate of the error of using the record
+                // here.
+                let cos_nor = dot(nor, rec.nor);
+                if cos_nor <= 0.0 {
+                    continue;
+                }
+                let error = (offset.length() / rec.radius) + (1.0 - cos_nor).max(0.0).sqrt();
+                if error >= self.accuracy {
+                    continue;
+                }
+
+                // Skip records in front of the point, which are likely to
+                // see things that it doesn't.
+                if dot(offset, nor + rec.nor) * 0.5 < rec.radius * -0.01 {
+                    continue;
+                }
+
+                // Falls off to zero at the accuracy limit, to avoid seams.
+                let weight = (1.0 / error.max(0.0001)) - (1.0 / self.accuracy);
+                sum += rec.extrapolate(pos, nor) * weight;
+                weight_sum += weight;
+            }
+
+            if let Some(ref children) = node.children {
+                let (ci, child_bounds) = child_containing(&bounds, pos);
+                node = &children[ci];
+                bounds = child_bounds;
+            } else {
+                break;
+            }
+        }
+
+        if weight_sum > 0.0 {
+            Some(sum / weight_sum)
+        } else {
+            None
+        }
+    }
+}
+
+fn insert_into(node: &mut Node, bounds: BBox, idx: usize, rec_bounds: BBox, depth: u32) {
+    // Stop at nodes about the size of the record's area.
+    let node_diag = (bounds.max - bounds.min).length2();
+    let rec_diag = (rec_bounds.max - rec_bounds.min).length2();
+    if depth == MAX_DEPTH || node_diag < rec_diag * 4.0 {
+        node.records.push(idx);
+        return;
+    }
+
+    let children = node.children.get_or_insert_with(Box::default);
+    for (ci, child) in children.iter_mut().enumerate() {
+        let child_bounds = child_bounds(&bounds, ci);
+        if overlaps(&child_bounds, &rec_bounds) {
+            insert_into(child, child_bounds, idx, rec_bounds, depth + 1);
+        }
+    }
+}
+
+fn child_bounds(bounds: &BBox, ci: usize) -> BBox {
+    let center = center(bounds);
+    let mut min = bounds.min;
+    let mut max = center;
+    if ci & 1 != 0 {
+        min.set_x(center.x());
+        max.set_x(bounds.max.x());
+    }
+    if ci & 2 != 0 {
+        min.set_y(center.y());
+        max.set_y(bounds.max.y());
+    }
+    if ci & 4 != 0 {
+        min.set_z(center.z());
+        max.set_z(bounds.max.z());
+    }
+    BBox::from_points(min, max)
+}
+
+fn child_containing(bounds: &BBox, pos: Point) -> (usize, BBox) {
+    let center = center(bounds);
+    let ci = (pos.x() >= center.x()) as usize
+        | (((pos.y() >= center.y()) as usize) << 1)
+        | (((pos.z() >= center.z()) as usize) << 2);
+    (ci, child_bounds(bounds, ci))
+}
+
+fn center(bounds: &BBox) -> Point {
+    bounds.min + ((bounds.max - bounds.min) * 0.5)
+}
+
+fn contains(bounds: &BBox, pos: Point) -> bool {
+    pos.x() >= bounds.min.x()
+        && pos.y() >= bounds.min.y()
+        && pos.z() >= bounds.min.z()
+        && pos.x() <= bounds.max.x()
+        && pos.y() <= bounds.max.y()
+        && pos.z() <= bounds.max.z()
+}
+
+fn overlaps(a: &BBox, b: &BBox) -> bool {
+    a.min.x() <= b.max.x()
+        && a.min.y() <= b.max.y()
+        && a.min.z() <= b.max.z()
+        && b.min.x() <= a.max.x()
+        && b.min.y() <= a.max.y()
+        && b.min.z() <= a.max.z()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A unit radiance emitter, as the corners of a convex polygon.
+    const EMITTER: [(f32, f32, f32); 4] = [
+        (0.5, -0.5, 1.0),
+        (1.5, -0.5, 1.0),
+        (1.5, 0.5, 1.0),
+        (0.5, 0.5, 1.0),
+    ];
+
+    /// The exact irradiance from `EMITTER`, via Lambert's formula for
+    /// polygonal sources.
+    fn exact_irradiance(pos: Point, nor: Vector) -> f32 {
+        let corners: Vec<Vector> = EMITTER
+            .iter()
+            .map(|&(x, y, z)| (Point::new(x, y, z) - pos).normalized())
+            .collect();
+        let mut sum = 0.0;
+        for i in 0..corners.len() {
+            let a = corners[i];
+            let b = corners[(i + 1) % corners.len()];
+            let angle = clamp(dot(a, b), -1.0, 1.0).acos();
+            sum += angle * dot(cross(a, b).normalized(), nor);
+        }
+        (sum * 0.5).abs()
+    }
+
+    /// Gathers the radiance from `EMITTER` by ray casting.
+    fn gather(hemi: &Hemisphere, pos: Point) -> Vec<GatherSample> {
+        (0..hemi.sample_count())
+            .map(|i| {
+                let dir = hemi.dir(i, (0.5, 0.5));
+                let t = (EMITTER[0].2 - pos.z()) / dir.z();
+                let hit = pos + (dir * t);
+                let (x0, y0, x1, y1) = (EMITTER[0].0, EMITTER[0].1, EMITTER[2].0, EMITTER[2].1);
+                if t > 0.0 && hit.x() > x0 && hit.x() < x1 && hit.y() > y0 && hit.y() < y1 {
+                    GatherSample {
+                        dir: dir,
+                        radiance: XYZ::new(1.0, 1.0, 1.0),
+                        distance: t,
+                    }
+                } else {
+                    GatherSample {
+                        dir: dir,
+                        radiance: XYZ::new(0.0, 0.0, 0.0),
+                        distance: f32::INFINITY,
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn record_at(pos: Point, nor: Vector) -> IrradianceRecord {
+        let hemi = Hemisphere::new(nor, 8192);
+        hemi.record(pos, &gather(&hemi, pos), (0.0, f32::INFINITY))
+    }
+
+    #[test]
+    fn uniform_environment() {
+        let hemi = Hemisphere::new(Vector::new(0.3, -0.2, 1.0), 200);
+        let samples: Vec<_> = (0..hemi.sample_count())
+            .map(|i| GatherSample {
+                dir: hemi.dir(i, (0.3, 0.7)),
+                radiance: XYZ::new(1.0, 2.0, 0.5),
+                distance: 2.0,
+            })
+            .collect();
+        let rec = hemi.record(Point::new(0.0, 0.0, 0.0), &samples, (0.0, 100.0));
+        let e = rec.irradiance;
+        assert!((e.y - 2.0 * PI).abs() < 0.0001);
+        assert!((e.x - PI).abs() < 0.0001);
+        assert!((rec.radius - 2.0).abs() < 0.0001);
+        for c in 0..3 {
+            assert!(rec.trans_gradient[c].length() < 0.0001);
+            assert!(rec.rot_gradient[c].length() < 0.01);
+        }
+    }
+
+    #[test]
+    fn irradiance_matches_exact() {
+        let pos = Point::new(0.1, 0.2, 0.0);
+        let nor = Vector::new(0.0, 0.0, 1.0);
+        let rec = record_at(pos, nor);
+        let exact = exact_irradiance(pos, nor);
+        assert!((rec.irradiance.y - exact).abs() < exact * 0.01);
+    }
+
+    #[test]
+    fn translational_gradient() {
+        let pos = Point::new(0.1, 0.2, 0.0);
+        let nor = Vector::new(0.0, 0.0, 1.0);
+        let gradient = record_at(pos, nor).trans_gradient[1];
+
+        let h = 0.001;
+        let dx = Vector::new(h, 0.0, 0.0);
+        let dy = Vector::new(0.0, h, 0.0);
+        let exact = Vector::new(
+            (exact_irradiance(pos + dx, nor) - exact_irradiance(pos - dx, nor)) / (2.0 * h),
+            (exact_irradiance(pos + dy, nor) - exact_irradiance(pos - dy, nor)) / (2.0 * h),
+            0.0,
+        );
+        let error = (Vector::new(gradient.x(), gradient.y(), 0.0) - exact).length();
+        assert!(
+            error < exact.length() * 0.05,
+            "expected {:?}, got {:?}",
+            exact,
+            gradient
+        );
+    }
+
+    #[test]
+    fn rotational_gradient() {
+        let pos = Point::new(0.1, 0.2, 0.0);
+        let nor = Vector::new(0.0, 0.0, 1.0);
+        let gradient = record_at(pos, nor).rot_gradient[1];
+
+        // Tilting the normal towards +x is a rotation about +y, and
+        // towards +y is a rotation about -x.
+        let h = 0.001f32;
+        let tilt_x = Vector::new(h.sin(), 0.0, h.cos());
+        let tilt_y = Vector::new(0.0, h.sin(), h.cos());
+        let exact = Vector::new(
+            -(exact_irradiance(pos, tilt_y) - exact_irradiance(pos, nor)) / h,
+            (exact_irradiance(pos, tilt_x) - exact_irradiance(pos, nor)) / h,
+            0.0,
+        );
+        let error = (Vector::new(gradient.x(), gradient.y(), 0.0) - exact).length();
+        assert!(
+            error < exact.length() * 0.02,
+            "expected {:?}, got {:?}",
+            exact,
+            gradient
+        );
+    }
+
+    #[test]
+    fn lookup() {
+        let nor = Vector::new(0.0, 0.0, 1.0);
+        let bounds = BBox::from_points(Point::new(-4.0, -4.0, -1.0), Point::new(4.0, 4.0, 1.0));
+        let mut cache = IrradianceCache::new(bounds, 0.5);
+        let mut rec = record_at(Point::new(0.0, 0.0, 0.0), nor);
+        rec.radius = 1.0;
+        cache.insert(rec);
+        assert_eq!(cache.record_count(), 1);
+
+        // At the record itself.
+        let e = cache.irradiance(Point::new(0.0, 0.0, 0.0), nor).unwrap();
+        assert!((e.y - rec.irradiance.y).abs() < 0.0001);
+
+        // Nearby, extrapolated with the gradients.
+        let pos = Point::new(0.1, -0.1, 0.0);
+        let e = cache.irradiance(pos, nor).unwrap();
+        assert!((e.y - rec.extrapolate(pos, nor).y).abs() < 0.0001);
+
+        // Too far away, facing the other way, or outside the cache.
+        assert!(cache.irradiance(Point::new(0.6, 0.0, 0.0), nor).is_none());
+        assert!(cache.irradiance(Point::new(0.0, 0.0, 0.0), -nor).is_none());
+        assert!(cache.irradiance(Point::new(9.0, 0.0, 0.0), nor).is_none());
+    }
+}
@@ -0,0 +1,346 @@
+use std::f64::consts::PI as PI_64;
+
+use kioku::Arena;
+
+use crate::{
+    bbox::BBox,
+    boundable::Boundable,
+    color::{Color, SpectralSample},
+    fp_utils::transform_point_err,
+    lerp::lerp_slice,
+    math::{cross, dot, Matrix4x4, Normal, Point, Vector},
+    sampling::square_to_circle,
+    shading::surface_closure::SurfaceClosure,
+    surface::{
+        arbitrary_tangents, HitQuery, IntersectContext, ShadeContext, Surface, SurfaceHit,
+        SurfaceIntersection, SurfaceIntersectionData, SurfaceStats,
+    },
+};
+
+use super::{area_scale, cosine_emission_dir, normalize_colors, LightUnits, SurfaceLight};
+
+/// A circular area light, lying in the xy plane of its local space with its
+/// front face pointing down +z.
+#[derive(Copy, Clone, Debug)]
+pub struct DiskLight<'a> {
+    radii: &'a [f32],
+    colors: &'a [Color],
+    bounds_: &'a [BBox],
+    two_sided: bool,
+}
+
+impl<'a> DiskLight<'a> {
+    pub fn new<'b>(
+        arena: &'b Arena,
+        radii: &[f32],
+        colors: &[Color],
+        two_sided: bool,
+        units: LightUnits,
+    ) -> DiskLight<'b> {
+        let bbs: Vec<_> = radii
+            .iter()
+            .map(|r| BBox {
+                min: Point::new(-*r, -*r, 0.0),
+                max: Point::new(*r, *r, 0.0),
+            })
+            .collect();
+        let colors = normalize_colors(colors, units, |time| {
+            radiance_scale(lerp_slice(radii, time), two_sided)
+        });
+        DiskLight {
+            radii: arena.copy_slice(&radii),
+            colors: arena.copy_slice(&colors),
+            bounds_: arena.copy_slice(&bbs),
+            two_sided: two_sided,
+        }
+    }
+
+    /// Scale factor from the light's color to its emitted radiance.
+    fn radiance_scale(&self, radius: f32) -> f32 {
+        radiance_scale(radius, self.two_sided)
+    }
+
+    /// Solid angle pdf of sampling the local-space point `sample_local` on
+    /// the disk from the world-space point `arr`.
+    fn sample_pdf(&self, space: &Matrix4x4, arr: Point, sample_local: Point, radius: f32) -> f32 {
+        if !self.two_sided && (arr * *space).z() <= 0.0 {
+            return 0.0;
+        }
+
+        let inv_space = space.inverse();
+
+        // Area of the disk in world space, accounting for any scaling in
+        // the transform.
+        let area_scale = cross(
+            Vector::new(1.0, 0.0, 0.0) * inv_space,
+            Vector::new(0.0, 1.0, 0.0) * inv_space,
+        )
+        .length();
+        let area = (PI_64 * radius as f64 * radius as f64) as f32 * area_scale;
+
+        let normal = Normal::new(0.0, 0.0, 1.0) * inv_space;
+        let shadow_vec = (sample_local * inv_space) - arr;
+        let cos = dot(shadow_vec.normalized(), normal.into_vector().normalized()).abs();
+
+        shadow_vec.length2() / (cos * area)
+    }
+}
+
+/// Scale factor from a disk light's color to its emitted radiance.
+fn radiance_scale(radius: f32, two_sided: bool) -> f32 {
+    let surface_area_inv = (1.0 / (PI_64 * radius as f64 * radius as f64)) as f32;
+    if two_sided {
+        // Power is split between the two faces.
+        surface_area_inv * 0.5
+    } else {
+        surface_area_inv
+    }
+}
+
+impl<'a> SurfaceLight for DiskLight<'a> {
+    fn sample_from_point(
+        &self,
+        space: &Matrix4x4,
+        arr: Point,
+        u: f32,
+        v: f32,
+        wavelength: f32,
+        time: f32,
+    ) -> (SpectralSample, (Point, Normal, f32), f32) {
+        // Calculate time interpolated values
+        let radius = lerp_slice(self.radii, time);
+        let col = lerp_slice(self.colors, time);
+
+        let inv_space = space.inverse();
+        let normal = Normal::new(0.0, 0.0, 1.0) * inv_space;
+
+        // Sample the disk uniformly by area.
+        let sample_local = {
+            let (x, y) = square_to_circle((u * 2.0) - 1.0, (v * 2.0) - 1.0);
+            Point::new(x * radius, y * radius, 0.0)
+        };
+        // The point is exactly on the disk's plane, so the only error that
+        // matters is from the transform.
+        let (sample_point, sample_point_err) = transform_point_err(sample_local, 0.0, &inv_space);
+
+        let pdf = self.sample_pdf(space, arr, sample_local, radius);
+        let spectral_sample = col.to_spectral_sample(wavelength) * self.radiance_scale(radius);
+
+        (
+            spectral_sample,
+            (sample_point, normal, sample_point_err),
+            pdf,
+        )
+    }
+
+    fn sample_emission(
+        &self,
+        space: &Matrix4x4,
+        uv: (f32, f32),
+        dir_uv: (f32, f32),
+        wavelength: f32,
+        time: f32,
+    ) -> (SpectralSample, (Point, Normal, f32), Vector) {
+        let radius = lerp_slice(self.radii, time);
+        let col = lerp_slice(self.colors, time);
+
+        let inv_space = space.inverse();
+
+        // Sample the disk uniformly by area.
+        let (sample_point, sample_point_err) = {
+            let (x, y) = square_to_circle((uv.0 * 2.0) - 1.0, (uv.1 * 2.0) - 1.0);
+            transform_point_err(Point::new(x * radius, y * radius, 0.0), 0.0, &inv_space)
+        };
+
+        // Two-sided lights emit half of their light from each side, so
+        // pick one.
+        let (normal_local, dir_uv) = if !self.two_sided || dir_uv.0 < 0.5 {
+            let u = if self.two_sided {
+                dir_uv.0 * 2.0
+            } else {
+                dir_uv.0
+            };
+            (Normal::new(0.0, 0.0, 1.0), (u, dir_uv.1))
+        } else {
+            (
+                Normal::new(0.0, 0.0, -1.0),
+                ((dir_uv.0 - 0.5) * 2.0, dir_uv.1),
+            )
+        };
+        let normal = normal_local * inv_space;
+        let dir = cosine_emission_dir(normal, dir_uv);
+
+        let area_scale = area_scale(
+            &inv_space,
+            Vector::new(1.0, 0.0, 0.0),
+            Vector::new(0.0, 1.0, 0.0),
+        );
+        let flux = col.to_spectral_sample(wavelength) * (std::f32::consts::PI * area_scale);
+
+        (flux, (sample_point, normal, sample_point_err), dir)
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn approximate_energy(&self) -> f32 {
+        self.colors
+            .iter()
+            .fold(0.0, |a, &b| a + b.approximate_energy())
+            / self.colors.len() as f32
+    }
+}
+
+impl<'a> Surface for DiskLight<'a> {
+    fn stats(&self) -> SurfaceStats {
+        SurfaceStats {
+            primitive_type: "disk light",
+            primitive_count: 1,
+            bytes: std::mem::size_of::<Self>()
+                + std::mem::size_of_val(self.radii)
+                + std::mem::size_of_val(self.colors)
+                + std::mem::size_of_val(self.bounds_),
+            bvh: None,
+        }
+    }
+
+    fn intersect_closest(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Closest);
+    }
+
+    fn intersect_any(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Any);
+    }
+
+    fn shade_hit(&self, ctx: ShadeContext) -> SurfaceIntersection {
+        let ShadeContext {
+            hit,
+            rays,
+            ray_idx,
+            space,
+            object_id,
+            instance_id,
+            instance_index,
+            ..
+        } = ctx;
+        let t = hit.t;
+        let time = rays.time(ray_idx);
+        let xform = lerp_slice(space, time);
+        let inv_xform = xform.inverse();
+        let radius = lerp_slice(self.radii, time);
+        let dir = rays.dir(ray_idx) * xform;
+        let hit_local = Point::new(hit.coords.0, hit.coords.1, 0.0);
+
+        // The hit point was snapped onto the disk's plane, so the only error
+        // that matters is from the transform.
+        let (pos, pos_err) = transform_point_err(hit_local, 0.0, &inv_xform);
+
+        let normal = Normal::new(0.0, 0.0, 1.0) * inv_xform;
+        let (dpdu, dpdv) = arbitrary_tangents(normal);
+
+        let intersection_data = SurfaceIntersectionData {
+            incoming: rays.dir(ray_idx),
+            t: t,
+            pos: pos,
+            pos_err: pos_err,
+            nor: normal,
+            nor_g: normal,
+            uv: (0.0, 0.0),
+            dpdu: dpdu,
+            dpdv: dpdv,
+            local_space: xform,
+            footprint: rays.spread(ray_idx) * t,
+            object_id: object_id,
+            instance_id: instance_id,
+            instance_index: instance_index,
+            sample_pdf: self.sample_pdf(&xform, rays.orig(ray_idx), hit_local, radius),
+        };
+
+        let closure = {
+            // Rays coming from behind only see emission if the light is
+            // two-sided.
+            let scale = if self.two_sided || dir.z() < 0.0 {
+                self.radiance_scale(radius)
+            } else {
+                0.0
+            };
+            let color = lerp_slice(self.colors, time) * scale;
+            SurfaceClosure::Emit(color)
+        };
+
+        SurfaceIntersection::Hit {
+            intersection_data: intersection_data,
+            closure: closure,
+        }
+    }
+}
+
+impl<'a> DiskLight<'a> {
+    fn intersect(&self, ctx: IntersectContext, query: HitQuery) {
+        let IntersectContext {
+            rays,
+            ray_stack,
+            hits,
+            shader,
+            space,
+            source,
+            ..
+        } = ctx;
+
+        let _ = shader; // Silence 'unused' warning
+
+        ray_stack.pop_do_next_task(|ray_idx| {
+            let time = rays.time(ray_idx);
+
+            // Get the transform space
+            let xform = lerp_slice(space, time);
+
+            // Get the radius of the disk at the ray's time
+            let radius = lerp_slice(self.radii, time);
+
+            // Get the ray origin and direction in local space
+            let orig = rays.orig_local(ray_idx);
+            let dir = rays.dir(ray_idx) * xform;
+
+            // Intersect with the xy plane, and then check against the radius.
+            if dir.z() == 0.0 {
+                return;
+            }
+            let t = -orig.z() / dir.z();
+            if t <= 0.0 || t > rays.max_t(ray_idx) {
+                return;
+            }
+            let hit_local = {
+                let mut p = orig + (dir * t);
+                p.set_z(0.0);
+                p
+            };
+            if ((hit_local.x() * hit_local.x()) + (hit_local.y() * hit_local.y()))
+                > (radius * radius)
+            {
+                return;
+            }
+
+            // We hit the disk, so record the hit, with its local position
+            // on the disk.
+            hits[ray_idx] = Some(SurfaceHit::new(
+                t,
+                0,
+                (hit_local.x(), hit_local.y(), 0.0),
+                source,
+            ));
+            if query == HitQuery::Any {
+                rays.mark_done(ray_idx);
+            } else {
+                rays.set_max_t(ray_idx, t);
+            }
+        });
+    }
+}
+
+impl<'a> Boundable for DiskLight<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.bounds_
+    }
+}
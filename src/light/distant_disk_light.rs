@@ -5,14 +5,27 @@ use kioku::Arena;
 use crate::{
     color::{Color, SpectralSample},
     lerp::lerp_slice,
-    math::{coordinate_system_from_vector, Vector},
+    math::{coordinate_system_from_vector, dot, Vector},
     sampling::{uniform_sample_cone, uniform_sample_cone_pdf},
 };
 
-use super::WorldLightSource;
+use super::{normalize_colors, LightUnits, WorldLightSource};
 
-// TODO: handle case where radius = 0.0.
+/// The sun's angular radius as seen from the earth, in radians: half of
+/// its 0.53 degree angular diameter.
+pub const SUN_RADIUS: f32 = 0.004_625;
 
+/// The sun's color temperature, in kelvin.
+pub const SUN_TEMPERATURE: f32 = 5778.0;
+
+/// The irradiance of clear midday sunlight, roughly 100,000 lux, in the
+/// units that `camera::Exposure` expects.
+pub const SUN_IRRADIANCE: f32 = 100_000.0;
+
+/// A light infinitely far away, covering a disk of the sky.
+///
+/// Its radius is the disk's angular radius in radians, and a radius of
+/// zero makes it a directional light with perfectly sharp shadows.
 #[derive(Copy, Clone, Debug)]
 pub struct DistantDiskLight<'a> {
     radii: &'a [f32],
@@ -26,7 +39,15 @@ impl<'a> DistantDiskLight<'a> {
         radii: &[f32],
         directions: &[Vector],
         colors: &[Color],
+        units: LightUnits,
     ) -> DistantDiskLight<'a> {
+        // Distant lights have no surface area, so normalized colors are
+        // divided by the solid angle instead.  Power isn't meaningful.
+        debug_assert!(units != LightUnits::Power);
+        let colors = normalize_colors(colors, units, |time| {
+            let radius = lerp_slice(radii, time) as f64;
+            (1.0 / (2.0 * PI_64 * (1.0 - radius.cos()))) as f32
+        });
         DistantDiskLight {
             radii: arena.copy_slice(&radii),
             directions: arena.copy_slice(&directions),
@@ -34,6 +55,23 @@ impl<'a> DistantDiskLight<'a> {
         }
     }
 
+    /// Makes a light that delivers the given irradiance to a surface facing
+    /// it, rather than taking a normalized color.
+    pub fn from_irradiance(
+        arena: &'a Arena,
+        radii: &[f32],
+        directions: &[Vector],
+        irradiances: &[Color],
+    ) -> DistantDiskLight<'a> {
+        // A normalized color is the radiance times the solid angle, and the
+        // average cosine over the disk is (1 + cos(radius)) / 2.
+        let colors = normalize_colors(irradiances, LightUnits::Radiance, |time| {
+            let radius = lerp_slice(radii, time) as f64;
+            ((1.0 + radius.cos()) * 0.5) as f32
+        });
+        DistantDiskLight::new(arena, radii, directions, &colors, LightUnits::Normalized)
+    }
+
     // fn sample_pdf(&self, sample_dir: Vector, wavelength: f32, time: f32) -> f32 {
     //     // We're not using these, silence warnings
     //     let _ = (sample_dir, wavelength);
@@ -67,6 +105,16 @@ impl<'a> WorldLightSource for DistantDiskLight<'a> {
         let radius: f64 = lerp_slice(self.radii, time) as f64;
         let direction = lerp_slice(self.directions, time);
         let col = lerp_slice(self.colors, time);
+
+        // Zero-radius lights are directional, and the color is then the
+        // irradiance they deliver.
+        if radius <= 0.0 {
+            return (
+                col.to_spectral_sample(wavelength),
+                -direction.normalized(),
+                1.0,
+            );
+        }
         let solid_angle_inv = 1.0 / (2.0 * PI_64 * (1.0 - radius.cos()));
 
         // Create a coordinate system from the vector pointing at the center of
@@ -84,8 +132,29 @@ impl<'a> WorldLightSource for DistantDiskLight<'a> {
         (spectral_sample, shadow_vec, pdf as f32)
     }
 
+    fn evaluate(&self, dir: Vector, wavelength: f32, time: f32) -> (SpectralSample, f32) {
+        // Calculate time interpolated values
+        let radius: f64 = lerp_slice(self.radii, time) as f64;
+        let direction = lerp_slice(self.directions, time);
+        let cos_theta_max: f64 = radius.cos();
+
+        // Check if the direction is within the cone subtended by the light.
+        // Zero-radius lights can't be seen.
+        let cos_theta = dot(dir.normalized(), -direction.normalized()) as f64;
+        if radius <= 0.0 || cos_theta < cos_theta_max {
+            return (SpectralSample::new(wavelength), 0.0);
+        }
+
+        let col = lerp_slice(self.colors, time);
+        let solid_angle_inv = 1.0 / (2.0 * PI_64 * (1.0 - cos_theta_max));
+        (
+            col.to_spectral_sample(wavelength) * solid_angle_inv as f32,
+            uniform_sample_cone_pdf(cos_theta_max) as f32,
+        )
+    }
+
     fn is_delta(&self) -> bool {
-        false
+        self.radii.iter().all(|r| *r <= 0.0)
     }
 
     fn approximate_energy(&self) -> f32 {
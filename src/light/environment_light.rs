@@ -0,0 +1,395 @@
+use std::f32::consts::PI as PI_32;
+
+use kioku::Arena;
+
+use crate::{
+    color::{rec709_e_to_xyz, Color, SpectralSample},
+    image::RgbImage,
+    math::Vector,
+};
+
+use super::WorldLightSource;
+
+/// How the pixels of an environment image map to directions.  +Z is up
+/// for all of them.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EnvironmentMapping {
+    /// Latitude-longitude, with +Y at the center of the image and +X to
+    /// the right of it, and rows running from straight up to straight
+    /// down.
+    Equirect,
+
+    /// A photograph of a mirror ball taken from the -Y side, cropped to
+    /// the ball: -Y is at the center of the image, +Y is around its rim,
+    /// and +X is to the right.
+    MirrorBall,
+
+    /// The same layout as a mirror ball, but with the distance from the
+    /// center proportional to the angle from -Y, like Debevec's light
+    /// probes.
+    Angular,
+}
+
+/// Light from an image surrounding the scene, e.g. a captured HDRI.
+///
+/// Whatever its mapping, the image is stored in latitude-longitude form,
+/// and sampled in proportion to the brightness of its texels.
+#[derive(Copy, Clone, Debug)]
+pub struct EnvironmentLight<'a> {
+    width: usize,
+    height: usize,
+    texels: &'a [Color],   // Radiance, row after row from the top
+    rotation: (f32, f32),  // Sine and cosine of the rotation about +Z
+    row_cdf: &'a [f32],    // Cumulative weights of the rows, `height + 1` of them
+    texel_cdfs: &'a [f32], // Cumulative weights of each row's texels, `width + 1` per row
+    energy: f32,
+    camera_visible: bool,
+}
+
+impl<'a> EnvironmentLight<'a> {
+    /// Makes an environment light from an image of linear rec709 colors
+    /// with an equal-energy white point.
+    ///
+    /// `rotation` turns the environment about +Z, in radians.
+    /// `saturation` scales the colors' saturation, and `intensity` their
+    /// brightness.
+    pub fn new(
+        arena: &'a Arena,
+        image: &RgbImage,
+        mapping: EnvironmentMapping,
+        rotation: f32,
+        intensity: f32,
+        saturation: f32,
+    ) -> EnvironmentLight<'a> {
+        assert!(image.width > 0 && image.height > 0);
+
+        // Resample to latitude-longitude.
+        let (width, height) = match mapping {
+            EnvironmentMapping::Equirect => (image.width, image.height),
+            _ => (image.height * 2, image.height),
+        };
+        let mut rgbs = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let (ix, iy) = match mapping {
+                    EnvironmentMapping::Equirect => (x, y),
+                    _ => {
+                        let dir = latlong_to_dir(
+                            (x as f32 + 0.5) / width as f32,
+                            (y as f32 + 0.5) / height as f32,
+                        );
+                        let (u, v) = dir_to_ball(dir, mapping);
+                        (
+                            ((u * image.width as f32) as usize).min(image.width - 1),
+                            ((v * image.height as f32) as usize).min(image.height - 1),
+                        )
+                    }
+                };
+                let (r, g, b) = image.data[(iy * image.width) + ix];
+                let luma = luminance((r, g, b));
+                let saturate = |c: f32| ((luma + ((c - luma) * saturation)) * intensity).max(0.0);
+                rgbs.push((saturate(r), saturate(g), saturate(b)));
+            }
+        }
+
+        // Texels are chosen in proportion to their brightness times the
+        // solid angle they cover.  A black image falls back to just the
+        // solid angle.
+        let mut weights: Vec<f32> = rgbs
+            .iter()
+            .enumerate()
+            .map(|(i, &rgb)| luminance(rgb) * row_sin_theta(i / width, height))
+            .collect();
+        if weights.iter().sum::<f32>() <= 0.0 {
+            for (i, weight) in weights.iter_mut().enumerate() {
+                *weight = row_sin_theta(i / width, height);
+            }
+        }
+        let mut texel_cdfs = Vec::with_capacity((width + 1) * height);
+        let mut row_cdf = Vec::with_capacity(height + 1);
+        row_cdf.push(0.0);
+        for row in weights.chunks(width) {
+            let start = texel_cdfs.len();
+            texel_cdfs.push(0.0);
+            for &weight in row {
+                texel_cdfs.push(texel_cdfs[texel_cdfs.len() - 1] + weight);
+            }
+            let row_weight = texel_cdfs[texel_cdfs.len() - 1] - texel_cdfs[start];
+            row_cdf.push(row_cdf[row_cdf.len() - 1] + row_weight);
+        }
+
+        // The energy is the radiance integrated over the sphere.
+        let texel_solid_angle = 2.0 * PI_32 * PI_32 / (width * height) as f32;
+        let texels: Vec<Color> = rgbs
+            .iter()
+            .map(|&rgb| Color::new_xyz(rec709_e_to_xyz(rgb)))
+            .collect();
+        let energy = texels
+            .iter()
+            .enumerate()
+            .map(|(i, texel)| {
+                texel.approximate_energy() * row_sin_theta(i / width, height) * texel_solid_angle
+            })
+            .sum();
+
+        EnvironmentLight {
+            width: width,
+            height: height,
+            texels: arena.copy_slice(&texels),
+            rotation: rotation.sin_cos(),
+            row_cdf: arena.copy_slice(&row_cdf),
+            texel_cdfs: arena.copy_slice(&texel_cdfs),
+            energy: energy,
+            camera_visible: true,
+        }
+    }
+
+    /// Sets whether camera rays see the environment.  When they don't, it
+    /// still lights the scene, and the background shows through instead.
+    pub fn with_camera_visibility(mut self, camera_visible: bool) -> EnvironmentLight<'a> {
+        self.camera_visible = camera_visible;
+        self
+    }
+
+    /// The pdf of sampling a texel, per unit of latitude-longitude area.
+    fn texel_pdf(&self, x: usize, y: usize) -> f32 {
+        let cdf = &self.texel_cdfs[(y * (self.width + 1))..((y + 1) * (self.width + 1))];
+        let total = self.row_cdf[self.height];
+        (cdf[x + 1] - cdf[x]) / total * (self.width * self.height) as f32
+    }
+
+    /// Converts a pdf per unit of latitude-longitude area to one per unit
+    /// solid angle, at the given direction.
+    fn solid_angle_pdf(latlong_pdf: f32, dir: Vector) -> f32 {
+        let sin_theta = (1.0 - (dir.z() * dir.z())).max(0.0).sqrt();
+        if sin_theta > 0.0 {
+            latlong_pdf / (2.0 * PI_32 * PI_32 * sin_theta)
+        } else {
+            0.0
+        }
+    }
+}
+
+impl<'a> WorldLightSource for EnvironmentLight<'a> {
+    fn sample_from_point(
+        &self,
+        u: f32,
+        v: f32,
+        wavelength: f32,
+        _time: f32,
+    ) -> (SpectralSample, Vector, f32) {
+        let (y, y_offset) = sample_cdf(self.row_cdf, v);
+        let cdf = &self.texel_cdfs[(y * (self.width + 1))..((y + 1) * (self.width + 1))];
+        let (x, x_offset) = sample_cdf(cdf, u);
+
+        let dir = latlong_to_dir(
+            (x as f32 + x_offset) / self.width as f32,
+            (y as f32 + y_offset) / self.height as f32,
+        );
+        let pdf = Self::solid_angle_pdf(self.texel_pdf(x, y), dir);
+        let color = self.texels[(y * self.width) + x].to_spectral_sample(wavelength);
+
+        // Rotate into place.
+        let (sin, cos) = self.rotation;
+        let dir = Vector::new(
+            (dir.x() * cos) - (dir.y() * sin),
+            (dir.x() * sin) + (dir.y() * cos),
+            dir.z(),
+        );
+
+        (color, dir, pdf)
+    }
+
+    fn evaluate(&self, dir: Vector, wavelength: f32, _time: f32) -> (SpectralSample, f32) {
+        // Undo the rotation.
+        let (sin, cos) = self.rotation;
+        let dir = dir.normalized();
+        let dir = Vector::new(
+            (dir.x() * cos) + (dir.y() * sin),
+            (dir.y() * cos) - (dir.x() * sin),
+            dir.z(),
+        );
+
+        let (u, v) = dir_to_latlong(dir);
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        (
+            self.texels[(y * self.width) + x].to_spectral_sample(wavelength),
+            Self::solid_angle_pdf(self.texel_pdf(x, y), dir),
+        )
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn approximate_energy(&self) -> f32 {
+        self.energy
+    }
+
+    fn is_camera_visible(&self) -> bool {
+        self.camera_visible
+    }
+}
+
+/// Rec.709 luminance.
+fn luminance(rgb: (f32, f32, f32)) -> f32 {
+    (rgb.0 * 0.2126) + (rgb.1 * 0.7152) + (rgb.2 * 0.0722)
+}
+
+/// The sine of the angle from +Z at the center of a latitude-longitude
+/// row.
+fn row_sin_theta(row: usize, height: usize) -> f32 {
+    ((row as f32 + 0.5) / height as f32 * PI_32).sin()
+}
+
+/// The direction at a point of a latitude-longitude image, both in [0, 1].
+fn latlong_to_dir(u: f32, v: f32) -> Vector {
+    let (sin_theta, cos_theta) = (v * PI_32).sin_cos();
+    let (sin_phi, cos_phi) = ((u - 0.5) * 2.0 * PI_32).sin_cos();
+    Vector::new(sin_theta * sin_phi, sin_theta * cos_phi, cos_theta)
+}
+
+/// The inverse of `latlong_to_dir()`, for a normalized direction.
+fn dir_to_latlong(dir: Vector) -> (f32, f32) {
+    let u = 0.5 + (dir.x().atan2(dir.y()) / (2.0 * PI_32));
+    let v = dir.z().max(-1.0).min(1.0).acos() / PI_32;
+    (u, v)
+}
+
+/// Where a normalized direction is in a mirror ball or angular image,
+/// from (0, 0) at the top left to (1, 1) at the bottom right.
+fn dir_to_ball(dir: Vector, mapping: EnvironmentMapping) -> (f32, f32) {
+    let theta = (-dir.y()).max(-1.0).min(1.0).acos(); // Angle from -Y
+    let r = match mapping {
+        EnvironmentMapping::Angular => theta / PI_32,
+        _ => (theta * 0.5).sin(),
+    };
+    let len = ((dir.x() * dir.x()) + (dir.z() * dir.z())).sqrt();
+    let (x, y) = if len > 0.0 {
+        (dir.x() / len * r, dir.z() / len * r)
+    } else {
+        (0.0, 0.0)
+    };
+    ((x + 1.0) * 0.5, (1.0 - y) * 0.5)
+}
+
+/// Chooses a bin of a cumulative distribution with `n` in [0, 1),
+/// returning its index and where in the bin `n` landed, in [0, 1).
+fn sample_cdf(cdf: &[f32], n: f32) -> (usize, f32) {
+    let x = n * cdf[cdf.len() - 1];
+    let i = cdf.partition_point(|&c| c <= x).max(1).min(cdf.len() - 1) - 1;
+    let width = cdf[i + 1] - cdf[i];
+    let offset = if width > 0.0 {
+        ((x - cdf[i]) / width).max(0.0).min(0.999_999)
+    } else {
+        0.5
+    };
+    (i, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: usize, height: usize, data: Vec<(f32, f32, f32)>) -> RgbImage {
+        RgbImage {
+            width: width,
+            height: height,
+            data: data,
+        }
+    }
+
+    #[test]
+    fn latlong_round_trip() {
+        for &(u, v) in &[(0.5, 0.5), (0.75, 0.25), (0.1, 0.9), (0.3, 0.6)] {
+            let (u2, v2) = dir_to_latlong(latlong_to_dir(u, v));
+            assert!((u - u2).abs() < 1.0e-5 && (v - v2).abs() < 1.0e-5);
+        }
+        let center = latlong_to_dir(0.5, 0.5);
+        assert!((center.y() - 1.0).abs() < 1.0e-5);
+        let right = latlong_to_dir(0.75, 0.5);
+        assert!((right.x() - 1.0).abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn ball_mappings() {
+        for &mapping in &[EnvironmentMapping::MirrorBall, EnvironmentMapping::Angular] {
+            let (u, v) = dir_to_ball(Vector::new(0.0, -1.0, 0.0), mapping);
+            assert!((u - 0.5).abs() < 1.0e-5 && (v - 0.5).abs() < 1.0e-5);
+            let (_, v) = dir_to_ball(Vector::new(0.0, 0.0, 1.0), mapping);
+            assert!(v < 0.5);
+            let (u, _) = dir_to_ball(Vector::new(1.0, 0.0, 0.0), mapping);
+            assert!(u > 0.5);
+        }
+
+        // Sideways directions are at 0.707 of the radius on a mirror ball,
+        // and half of it on an angular map.
+        let (u, _) = dir_to_ball(Vector::new(1.0, 0.0, 0.0), EnvironmentMapping::MirrorBall);
+        assert!((u - (0.5 + (0.5f32.sqrt() * 0.5))).abs() < 1.0e-5);
+        let (u, _) = dir_to_ball(Vector::new(1.0, 0.0, 0.0), EnvironmentMapping::Angular);
+        assert!((u - 0.75).abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn samples_match_evaluation() {
+        let arena = Arena::new();
+        let mut data = vec![(0.1, 0.1, 0.1); 16 * 8];
+        data[(3 * 16) + 5] = (50.0, 40.0, 30.0);
+        let light = EnvironmentLight::new(
+            &arena,
+            &image(16, 8, data),
+            EnvironmentMapping::Equirect,
+            0.7,
+            1.0,
+            1.0,
+        );
+
+        let mut bright = 0;
+        for i in 0..64 {
+            let u = (i % 8) as f32 / 8.0 + 0.03;
+            let v = (i / 8) as f32 / 8.0 + 0.05;
+            let (color, dir, pdf) = light.sample_from_point(u, v, 550.0, 0.0);
+            let (color2, pdf2) = light.evaluate(dir, 550.0, 0.0);
+            assert!((pdf - pdf2).abs() <= pdf * 1.0e-3);
+            assert!((color.e.x() - color2.e.x()).abs() <= color.e.x() * 1.0e-3);
+            if color.e.x() > 1.0 {
+                bright += 1;
+            }
+        }
+
+        // The bright texel is sampled far more often than its size.
+        assert!(bright > 32);
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let arena = Arena::new();
+        let data = (0..32 * 16)
+            .map(|i| (i as f32 * 0.01, 0.5, (i % 7) as f32))
+            .collect();
+        let light = EnvironmentLight::new(
+            &arena,
+            &image(32, 16, data),
+            EnvironmentMapping::Equirect,
+            0.0,
+            1.0,
+            0.5,
+        );
+
+        // Integrate over the sphere with uniformly distributed directions.
+        let n = 128;
+        let mut total = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                let dir = crate::sampling::uniform_sample_sphere(
+                    (i as f32 + 0.5) / n as f32,
+                    (j as f32 + 0.5) / n as f32,
+                );
+                total += light.evaluate(dir, 550.0, 0.0).1;
+            }
+        }
+        let integral = total * 4.0 * PI_32 / (n * n) as f32;
+        assert!((integral - 1.0).abs() < 0.02);
+    }
+}
@@ -1,20 +1,101 @@
+mod disk_light;
 mod distant_disk_light;
+mod environment_light;
+mod point_light;
 mod rectangle_light;
 mod sphere_light;
+mod tube_light;
+
+#[cfg(test)]
+mod sampling_tests;
 
 use std::fmt::Debug;
 
 use crate::{
-    color::SpectralSample,
-    math::{Matrix4x4, Normal, Point, Vector},
+    color::{Color, SpectralSample},
+    math::{cross, zup_to_vec, Matrix4x4, Normal, Point, Vector},
+    sampling::cosine_sample_hemisphere,
     surface::Surface,
 };
 
 pub use self::{
-    distant_disk_light::DistantDiskLight, rectangle_light::RectangleLight,
+    disk_light::DiskLight,
+    distant_disk_light::{DistantDiskLight, SUN_IRRADIANCE, SUN_RADIUS, SUN_TEMPERATURE},
+    environment_light::{EnvironmentLight, EnvironmentMapping},
+    point_light::PointLight,
+    rectangle_light::RectangleLight,
     sphere_light::SphereLight,
+    tube_light::TubeLight,
 };
 
+/// How a light's color is interpreted.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum LightUnits {
+    /// The color is divided by the light's surface area, so resizing the
+    /// light doesn't change its total output.  This is the default.
+    #[default]
+    Normalized,
+
+    /// The color is the total power emitted by the light.
+    Power,
+
+    /// The color is the radiance leaving the light's surface, so the light's
+    /// total output scales with its size.
+    Radiance,
+}
+
+/// Converts light colors given in `units` to the normalized colors the
+/// lights use internally.
+///
+/// `radiance_scale` gives the factor from a normalized color to radiance at
+/// a given time, and is only called for `LightUnits::Radiance`.  Colors are
+/// assumed to be evenly spaced over the shutter interval.
+fn normalize_colors<F>(colors: &[Color], units: LightUnits, radiance_scale: F) -> Vec<Color>
+where
+    F: Fn(f32) -> f32,
+{
+    match units {
+        LightUnits::Normalized => colors.to_vec(),
+
+        // Total power is PI times the normalized color, from integrating
+        // the radiance over the hemisphere and the surface.
+        LightUnits::Power => colors
+            .iter()
+            .map(|c| *c * std::f32::consts::FRAC_1_PI)
+            .collect(),
+
+        LightUnits::Radiance => colors
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let time = if colors.len() > 1 {
+                    i as f32 / (colors.len() - 1) as f32
+                } else {
+                    0.5
+                };
+                *c * (1.0 / radiance_scale(time))
+            })
+            .collect(),
+    }
+}
+
+/// Samples a direction for light leaving a surface with the world-space
+/// normal `nor`, with a cosine distribution around it.
+///
+/// Dividing uniform radiance times the cosine by this direction's pdf
+/// always gives PI, which is where the PI in the lights' emitted flux
+/// comes from.
+fn cosine_emission_dir(nor: Normal, uv: (f32, f32)) -> Vector {
+    zup_to_vec(cosine_sample_hemisphere(uv.0, uv.1), nor.into_vector()).normalized()
+}
+
+/// The ratio of world-space to local-space surface area at a point on a
+/// light, given two orthogonal unit tangents of the surface there in the
+/// light's local space.
+fn area_scale(inv_space: &Matrix4x4, tangent_1: Vector, tangent_2: Vector) -> f32 {
+    cross(tangent_1 * *inv_space, tangent_2 * *inv_space).length()
+}
+
 /// A finite light source that can be bounded in space.
 pub trait SurfaceLight: Surface {
     /// Samples the surface given a point to be illuminated.
@@ -42,6 +123,33 @@ pub trait SurfaceLight: Surface {
         time: f32,
     ) -> (SpectralSample, (Point, Normal, f32), f32);
 
+    /// Samples light leaving the surface, for tracing paths from the light
+    /// rather than to it.
+    ///
+    /// - `space`: The world-to-object space transform of the light.
+    /// - `uv`: Random parameters for the point on the light.
+    /// - `dir_uv`: Random parameters for the direction.
+    /// - `wavelength`: The wavelength of light to sample at.
+    /// - `time`: The time to sample at.
+    ///
+    /// Returns:
+    /// - The flux carried by the sample, i.e. the emitted radiance times
+    ///   the cosine, divided by the pdf of both the point and the
+    ///   direction.  Averaged over many samples, this is the light's total
+    ///   power.
+    /// - A tuple with the point on the light, the surface normal at that
+    ///   point, and the point's error magnitude, as for
+    ///   `sample_from_point()`.
+    /// - The direction the light leaves in.
+    fn sample_emission(
+        &self,
+        space: &Matrix4x4,
+        uv: (f32, f32),
+        dir_uv: (f32, f32),
+        wavelength: f32,
+        time: f32,
+    ) -> (SpectralSample, (Point, Normal, f32), Vector);
+
     /// Returns whether the light has a delta distribution.
     ///
     /// If a light has no chance of a ray hitting it through random process
@@ -77,6 +185,18 @@ pub trait WorldLightSource: Debug + Sync {
         time: f32,
     ) -> (SpectralSample, Vector, f32);
 
+    /// Evaluates the light source in a given direction, for rays that
+    /// escape the scene.
+    ///
+    ///     - dir: The direction towards the light source.
+    ///     - wavelength: The wavelength of light to evaluate at.
+    ///     - time: The time to evaluate at.
+    ///
+    /// Returns: The light arriving from that direction, and the pdf of
+    /// `sample_from_point()` generating it.  Both are zero if the light
+    /// can't be seen in that direction.
+    fn evaluate(&self, dir: Vector, wavelength: f32, time: f32) -> (SpectralSample, f32);
+
     /// Returns whether the light has a delta distribution.
     ///
     /// If a light has no chance of a ray hitting it through random process
@@ -91,4 +211,10 @@ pub trait WorldLightSource: Debug + Sync {
     /// for any light that emits any light.  This is used for importance
     /// sampling.
     fn approximate_energy(&self) -> f32;
+
+    /// Returns whether camera rays see the light.  Lights that camera rays
+    /// don't see still light the scene.
+    fn is_camera_visible(&self) -> bool {
+        true
+    }
 }
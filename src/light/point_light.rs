@@ -0,0 +1,166 @@
+use kioku::Arena;
+
+use crate::{
+    bbox::BBox,
+    boundable::Boundable,
+    color::{Color, SpectralSample},
+    lerp::lerp_slice,
+    math::{Matrix4x4, Normal, Point, Vector},
+    sampling::uniform_sample_sphere,
+    surface::{IntersectContext, ShadeContext, Surface, SurfaceIntersection, SurfaceStats},
+};
+
+use super::{normalize_colors, LightUnits, SurfaceLight};
+
+/// An infinitesimally small light, emitting equally in all directions from
+/// the origin of its local space.
+///
+/// The color has the same meaning as for `SphereLight`, so a point light
+/// matches a sphere light of the same color as the sphere's radius goes to
+/// zero.
+///
+/// Being a delta light, it can't be hit by rays and is only ever found by
+/// light sampling.
+#[derive(Copy, Clone, Debug)]
+pub struct PointLight<'a> {
+    colors: &'a [Color],
+    bounds_: &'a [BBox],
+}
+
+impl<'a> PointLight<'a> {
+    /// Point lights have no surface, so `units` must not be
+    /// `LightUnits::Radiance`.
+    pub fn new<'b>(arena: &'b Arena, colors: &[Color], units: LightUnits) -> PointLight<'b> {
+        debug_assert!(units != LightUnits::Radiance);
+        let bbs = [BBox {
+            min: Point::new(0.0, 0.0, 0.0),
+            max: Point::new(0.0, 0.0, 0.0),
+        }];
+        let colors = normalize_colors(colors, units, |_| 1.0);
+        PointLight {
+            colors: arena.copy_slice(&colors),
+            bounds_: arena.copy_slice(&bbs),
+        }
+    }
+}
+
+/// Samples a point light at the origin of `space` as seen from `arr`.
+///
+/// The returned pdf is always 1.0, since there is only one direction to
+/// choose.  Callers must not apply MIS to the sample.
+pub(super) fn sample_point(
+    space: &Matrix4x4,
+    arr: Point,
+    color: Color,
+    wavelength: f32,
+) -> (SpectralSample, (Point, Normal, f32), f32) {
+    let pos = Point::new(0.0, 0.0, 0.0) * space.inverse();
+    let to_arr = arr - pos;
+    let d2 = to_arr.length2();
+
+    // Radiant intensity of a sphere light is its color times the
+    // projected area over the surface area, which is a constant 1/4.
+    let spectral_sample = if d2 > 0.0 {
+        color.to_spectral_sample(wavelength) * (0.25 / d2)
+    } else {
+        SpectralSample::new(wavelength)
+    };
+
+    (
+        spectral_sample,
+        (pos, to_arr.normalized().into_normal(), 0.0),
+        1.0,
+    )
+}
+
+/// Samples light leaving a point light at the origin of `space`, in a
+/// uniformly chosen direction.
+pub(super) fn sample_point_emission(
+    space: &Matrix4x4,
+    color: Color,
+    dir_uv: (f32, f32),
+    wavelength: f32,
+) -> (SpectralSample, (Point, Normal, f32), Vector) {
+    let pos = Point::new(0.0, 0.0, 0.0) * space.inverse();
+    let dir = uniform_sample_sphere(dir_uv.0, dir_uv.1).normalized();
+
+    // The intensity of 1/4 the color over the 4*PI steradians of the
+    // sphere.
+    let flux = color.to_spectral_sample(wavelength) * std::f32::consts::PI;
+
+    (flux, (pos, dir.into_normal(), 0.0), dir)
+}
+
+impl<'a> SurfaceLight for PointLight<'a> {
+    fn sample_from_point(
+        &self,
+        space: &Matrix4x4,
+        arr: Point,
+        u: f32,
+        v: f32,
+        wavelength: f32,
+        time: f32,
+    ) -> (SpectralSample, (Point, Normal, f32), f32) {
+        // We're not using these, silence warnings
+        let _ = (u, v);
+
+        sample_point(space, arr, lerp_slice(self.colors, time), wavelength)
+    }
+
+    fn sample_emission(
+        &self,
+        space: &Matrix4x4,
+        uv: (f32, f32),
+        dir_uv: (f32, f32),
+        wavelength: f32,
+        time: f32,
+    ) -> (SpectralSample, (Point, Normal, f32), Vector) {
+        let _ = uv; // Not using this, silence warning
+
+        sample_point_emission(space, lerp_slice(self.colors, time), dir_uv, wavelength)
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn approximate_energy(&self) -> f32 {
+        self.colors
+            .iter()
+            .fold(0.0, |a, &b| a + b.approximate_energy())
+            / self.colors.len() as f32
+    }
+}
+
+impl<'a> Surface for PointLight<'a> {
+    fn stats(&self) -> SurfaceStats {
+        SurfaceStats {
+            primitive_type: "point light",
+            primitive_count: 1,
+            bytes: std::mem::size_of::<Self>()
+                + std::mem::size_of_val(self.colors)
+                + std::mem::size_of_val(self.bounds_),
+            bvh: None,
+        }
+    }
+
+    // Point lights have no surface to hit.
+    fn intersect_closest(&self, ctx: IntersectContext) {
+        ctx.ray_stack.pop_task();
+    }
+
+    fn intersect_any(&self, ctx: IntersectContext) {
+        ctx.ray_stack.pop_task();
+    }
+
+    fn shade_hit(&self, _ctx: ShadeContext) -> SurfaceIntersection {
+        // Point lights never record hits.
+        SurfaceIntersection::Miss
+    }
+}
+
+impl<'a> Boundable for PointLight<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.bounds_
+    }
+}
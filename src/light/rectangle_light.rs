@@ -4,34 +4,127 @@ use crate::{
     bbox::BBox,
     boundable::Boundable,
     color::{Color, SpectralSample},
+    fp_utils::{fp_gamma, transform_point_err},
     lerp::lerp_slice,
-    math::{cross, dot, Matrix4x4, Normal, Point, Vector},
-    ray::{RayBatch, RayStack},
+    math::{cross, dot, zup_to_vec, Matrix4x4, Normal, Point, Vector},
     sampling::{
-        spherical_triangle_solid_angle, triangle_surface_area, uniform_sample_spherical_triangle,
-        uniform_sample_triangle,
+        spherical_triangle_solid_angle, square_to_circle, triangle_surface_area,
+        uniform_sample_spherical_triangle, uniform_sample_triangle, SphericalRectangle,
     },
     shading::surface_closure::SurfaceClosure,
-    shading::SurfaceShader,
-    surface::{triangle, Surface, SurfaceIntersection, SurfaceIntersectionData},
+    surface::{
+        arbitrary_tangents, triangle, HitQuery, IntersectContext, ShadeContext, Surface,
+        SurfaceHit, SurfaceIntersection, SurfaceIntersectionData, SurfaceStats,
+    },
 };
 
-use super::SurfaceLight;
+use super::{area_scale, normalize_colors, LightUnits, SurfaceLight};
 
 const SIMPLE_SAMPLING_THRESHOLD: f32 = 0.01;
 
+/// How close to perpendicular the sampled region's edges have to be, as
+/// the cosine between them, for it to be sampled as a spherical rectangle.
+const RECTANGLE_TOLERANCE: f32 = 1.0e-4;
+
+/// The sampled region of a rectangle light projected onto the unit sphere
+/// around the point being lit.
+enum Projection {
+    /// The region is a rectangle in world space.
+    Rectangle(SphericalRectangle),
+
+    /// The light's transform shears the region into a parallelogram, so
+    /// it's split into two spherical triangles instead: the corners of
+    /// both on the unit sphere, and their solid angles.
+    Triangles([Vector; 4], f32, f32),
+}
+
+impl Projection {
+    /// Projects the world-space corners `p1` through `p4` of the sampled
+    /// region, in order around it, from `arr`.
+    fn new(arr: Point, p1: Point, p2: Point, p3: Point, p4: Point) -> Projection {
+        let ex = p4 - p3;
+        let ey = p2 - p3;
+        if dot(ex.normalized(), ey.normalized()).abs() < RECTANGLE_TOLERANCE {
+            Projection::Rectangle(SphericalRectangle::new(p3 - arr, ex, ey))
+        } else {
+            let sp1 = (p1 - arr).normalized();
+            let sp2 = (p2 - arr).normalized();
+            let sp3 = (p3 - arr).normalized();
+            let sp4 = (p4 - arr).normalized();
+            Projection::Triangles(
+                [sp1, sp2, sp3, sp4],
+                spherical_triangle_solid_angle(sp2, sp1, sp3),
+                spherical_triangle_solid_angle(sp4, sp1, sp3),
+            )
+        }
+    }
+
+    fn solid_angle(&self) -> f32 {
+        match *self {
+            Projection::Rectangle(ref rect) => rect.solid_angle(),
+            Projection::Triangles(_, area_1, area_2) => area_1 + area_2,
+        }
+    }
+
+    /// Samples a direction within the projection uniformly by solid angle.
+    fn sample(&self, u: f32, v: f32) -> Vector {
+        match *self {
+            Projection::Rectangle(ref rect) => rect.sample(u, v),
+            Projection::Triangles([sp1, sp2, sp3, sp4], area_1, area_2) => {
+                // Normalize the solid angles for selection purposes
+                let prob_1 = if area_1.is_infinite() {
+                    1.0
+                } else if area_2.is_infinite() {
+                    0.0
+                } else {
+                    area_1 / (area_1 + area_2)
+                };
+                let prob_2 = 1.0 - prob_1;
+
+                // Select one of the triangles and sample it
+                if u < prob_1 {
+                    uniform_sample_spherical_triangle(sp2, sp1, sp3, v, u / prob_1)
+                } else {
+                    uniform_sample_spherical_triangle(
+                        sp4,
+                        sp1,
+                        sp3,
+                        v,
+                        1.0 - ((u - prob_1) / prob_2),
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// A rectangular area light, lying in the xy plane of its local space with
+/// its front face pointing down +z.
+///
+/// The light can optionally emit from its front face only, and can have its
+/// emission restricted to a cone around the surface normal (the "spread"),
+/// which behaves like the grid on a softbox: light outside the cone is
+/// blocked rather than redistributed.
 #[derive(Copy, Clone, Debug)]
 pub struct RectangleLight<'a> {
     dimensions: &'a [(f32, f32)],
     colors: &'a [Color],
     bounds_: &'a [BBox],
+    two_sided: bool,
+    spread_cos: f32, // Cosine of the spread cone's half-angle
+    spread_tan: f32, // Tangent of the spread cone's half-angle
 }
 
 impl<'a> RectangleLight<'a> {
+    /// `spread` is the full angle of the emission cone in radians, where
+    /// PI (or greater) means no restriction.
     pub fn new<'b>(
         arena: &'b Arena,
         dimensions: &[(f32, f32)],
         colors: &[Color],
+        two_sided: bool,
+        spread: f32,
+        units: LightUnits,
     ) -> RectangleLight<'b> {
         let bbs: Vec<_> = dimensions
             .iter()
@@ -40,14 +133,74 @@ impl<'a> RectangleLight<'a> {
                 max: Point::new(d.0 * 0.5, d.1 * 0.5, 0.0),
             })
             .collect();
+
+        let half_angle = (spread * 0.5).max(0.0);
+        let (spread_cos, spread_tan) = if half_angle >= (std::f32::consts::PI * 0.5) {
+            (0.0, std::f32::INFINITY)
+        } else {
+            (half_angle.cos(), half_angle.tan())
+        };
+
+        let colors = normalize_colors(colors, units, |time| {
+            radiance_scale(lerp_slice(dimensions, time), two_sided)
+        });
+
         RectangleLight {
             dimensions: arena.copy_slice(&dimensions),
             colors: arena.copy_slice(&colors),
             bounds_: arena.copy_slice(&bbs),
+            two_sided: two_sided,
+            spread_cos: spread_cos,
+            spread_tan: spread_tan,
+        }
+    }
+
+    /// Returns the local-space bounds `(min_x, max_x, min_y, max_y)` of the
+    /// part of the rectangle that can emit light towards `arr_local`, or
+    /// `None` if no part of it can.
+    ///
+    /// With a restricted spread this is the rectangle clipped to the square
+    /// enclosing the spread cone's footprint, so sampling only this region
+    /// still covers every point that can contribute.
+    fn emitting_bounds(&self, dim: (f32, f32), arr_local: Point) -> Option<(f32, f32, f32, f32)> {
+        let z = arr_local.z();
+        if !self.two_sided && z <= 0.0 {
+            return None;
+        }
+
+        let (hx, hy) = (dim.0 * 0.5, dim.1 * 0.5);
+        if self.spread_tan.is_infinite() {
+            return Some((-hx, hx, -hy, hy));
+        }
+
+        let r = z.abs() * self.spread_tan;
+        let min_x = (arr_local.x() - r).max(-hx);
+        let max_x = (arr_local.x() + r).min(hx);
+        let min_y = (arr_local.y() - r).max(-hy);
+        let max_y = (arr_local.y() + r).min(hy);
+        if min_x < max_x && min_y < max_y {
+            Some((min_x, max_x, min_y, max_y))
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether light leaves the surface in the given local-space
+    /// direction.
+    fn emits_towards(&self, dir_local: Vector) -> bool {
+        let z = dir_local.z();
+        if !self.two_sided && z <= 0.0 {
+            return false;
         }
+        z.abs() >= (dir_local.length() * self.spread_cos)
     }
 
-    // TODO: this is only used from within `intersect_rays`, and could be done
+    /// Scale factor from the light's color to its emitted radiance.
+    fn radiance_scale(&self, dim: (f32, f32)) -> f32 {
+        radiance_scale(dim, self.two_sided)
+    }
+
+    // TODO: this is only used from within `intersect()`, and could be done
     // more efficiently by inlining it there.
     fn sample_pdf(
         &self,
@@ -63,35 +216,36 @@ impl<'a> RectangleLight<'a> {
 
         let dim = lerp_slice(self.dimensions, time);
 
-        // Get the four corners of the rectangle, transformed into world space
+        // Only the part of the rectangle that can emit towards arr is sampled.
+        let (min_x, max_x, min_y, max_y) =
+            if let Some(bounds) = self.emitting_bounds(dim, arr * *space) {
+                bounds
+            } else {
+                return 0.0;
+            };
+
+        // Get the four corners of the sampled region, transformed into world
+        // space
         let space_inv = space.inverse();
-        let p1 = Point::new(dim.0 * 0.5, dim.1 * 0.5, 0.0) * space_inv;
-        let p2 = Point::new(dim.0 * -0.5, dim.1 * 0.5, 0.0) * space_inv;
-        let p3 = Point::new(dim.0 * -0.5, dim.1 * -0.5, 0.0) * space_inv;
-        let p4 = Point::new(dim.0 * 0.5, dim.1 * -0.5, 0.0) * space_inv;
-
-        // Get the four corners of the rectangle, projected on to the unit
-        // sphere centered around arr.
-        let sp1 = (p1 - arr).normalized();
-        let sp2 = (p2 - arr).normalized();
-        let sp3 = (p3 - arr).normalized();
-        let sp4 = (p4 - arr).normalized();
-
-        // Get the solid angles of the rectangle split into two triangles
-        let area_1 = spherical_triangle_solid_angle(sp2, sp1, sp3);
-        let area_2 = spherical_triangle_solid_angle(sp4, sp1, sp3);
+        let p1 = Point::new(max_x, max_y, 0.0) * space_inv;
+        let p2 = Point::new(min_x, max_y, 0.0) * space_inv;
+        let p3 = Point::new(min_x, min_y, 0.0) * space_inv;
+        let p4 = Point::new(max_x, min_y, 0.0) * space_inv;
+
+        // Get the solid angle of the sampled region.
+        let solid_angle = Projection::new(arr, p1, p2, p3, p4).solid_angle();
 
         // World-space surface normal
         let normal = Normal::new(0.0, 0.0, 1.0) * space_inv;
 
         // PDF
-        if (area_1 + area_2) < SIMPLE_SAMPLING_THRESHOLD {
+        if solid_angle < SIMPLE_SAMPLING_THRESHOLD {
             let area = triangle_surface_area(p2, p1, p3) + triangle_surface_area(p4, p1, p3);
             (hit_point - arr).length2()
                 / dot(sample_dir.normalized(), normal.into_vector().normalized()).abs()
                 / area
         } else {
-            1.0 / (area_1 + area_2)
+            1.0 / solid_angle
         }
     }
 
@@ -117,6 +271,17 @@ impl<'a> RectangleLight<'a> {
     // }
 }
 
+/// Scale factor from a rectangle light's color to its emitted radiance.
+fn radiance_scale(dim: (f32, f32), two_sided: bool) -> f32 {
+    let surface_area_inv = (1.0 / (dim.0 as f64 * dim.1 as f64)) as f32;
+    if two_sided {
+        // Power is split between the two faces.
+        surface_area_inv * 0.5
+    } else {
+        surface_area_inv
+    }
+}
+
 impl<'a> SurfaceLight for RectangleLight<'a> {
     fn sample_from_point(
         &self,
@@ -130,37 +295,35 @@ impl<'a> SurfaceLight for RectangleLight<'a> {
         // Calculate time interpolated values
         let dim = lerp_slice(self.dimensions, time);
         let col = lerp_slice(self.colors, time);
+        let radiance_scale = self.radiance_scale(dim);
 
-        let surface_area: f64 = dim.0 as f64 * dim.1 as f64;
-        let surface_area_inv: f64 = 1.0 / surface_area;
-
-        // Get the four corners of the rectangle, transformed into world space
         let space_inv = space.inverse();
-        let p1 = Point::new(dim.0 * 0.5, dim.1 * 0.5, 0.0) * space_inv;
-        let p2 = Point::new(dim.0 * -0.5, dim.1 * 0.5, 0.0) * space_inv;
-        let p3 = Point::new(dim.0 * -0.5, dim.1 * -0.5, 0.0) * space_inv;
-        let p4 = Point::new(dim.0 * 0.5, dim.1 * -0.5, 0.0) * space_inv;
-
-        // Get the four corners of the rectangle relative to arr.
-        let lp1 = p1 - arr;
-        let lp2 = p2 - arr;
-        let lp3 = p3 - arr;
-        let lp4 = p4 - arr;
-
-        // Four corners projected on to the unit sphere.
-        let sp1 = lp1.normalized();
-        let sp2 = lp2.normalized();
-        let sp3 = lp3.normalized();
-        let sp4 = lp4.normalized();
-
-        // Get the solid angles of the rectangle split into two triangles
-        let area_1 = spherical_triangle_solid_angle(sp2, sp1, sp3);
-        let area_2 = spherical_triangle_solid_angle(sp4, sp1, sp3);
+        let arr_local = arr * *space;
 
         // Calculate world-space surface normal
         let normal = Normal::new(0.0, 0.0, 1.0) * space_inv;
 
-        if (area_1 + area_2) < SIMPLE_SAMPLING_THRESHOLD {
+        // Only the part of the rectangle that can emit towards arr is sampled.
+        let (min_x, max_x, min_y, max_y) =
+            if let Some(bounds) = self.emitting_bounds(dim, arr_local) {
+                bounds
+            } else {
+                let center = Point::new(0.0, 0.0, 0.0) * space_inv;
+                return (SpectralSample::new(wavelength), (center, normal, 0.0), 0.0);
+            };
+
+        // Get the four corners of the sampled region, transformed into world
+        // space
+        let p1 = Point::new(max_x, max_y, 0.0) * space_inv;
+        let p2 = Point::new(min_x, max_y, 0.0) * space_inv;
+        let p3 = Point::new(min_x, min_y, 0.0) * space_inv;
+        let p4 = Point::new(max_x, min_y, 0.0) * space_inv;
+
+        // Project the sampled region onto the unit sphere around arr.
+        let projection = Projection::new(arr, p1, p2, p3, p4);
+        let solid_angle = projection.solid_angle();
+
+        if solid_angle < SIMPLE_SAMPLING_THRESHOLD {
             // Simple sampling for more distant lights
             let surface_area_1 = triangle_surface_area(p2, p1, p3);
             let surface_area_2 = triangle_surface_area(p4, p1, p3);
@@ -186,53 +349,48 @@ impl<'a> SurfaceLight for RectangleLight<'a> {
                 }
             }
             .into_point();
+            // The point is on the same world-space triangles that rays are
+            // tested against, so its error is like a triangle hit's.
+            let point_err = fp_gamma(7)
+                * [p1, p2, p3, p4]
+                    .iter()
+                    .fold(0.0f32, |a, p| a.max(p.into_vector().abs().co.max_element()));
+
             let shadow_vec = sample_point - arr;
-            let spectral_sample =
-                (col).to_spectral_sample(wavelength) * surface_area_inv as f32 * 0.5;
+            let spectral_sample = if self.emits_towards(arr_local - (sample_point * *space)) {
+                col.to_spectral_sample(wavelength) * radiance_scale
+            } else {
+                SpectralSample::new(wavelength)
+            };
             let pdf = (sample_point - arr).length2()
                 / dot(shadow_vec.normalized(), normal.into_vector().normalized()).abs()
                 / (surface_area_1 + surface_area_2);
-            let point_err = 0.0001; // TODO: this is a hack, do properly.
             (spectral_sample, (sample_point, normal, point_err), pdf)
         } else {
-            // Sophisticated sampling for close lights.
-
-            // Normalize the solid angles for selection purposes
-            let prob_1 = if area_1.is_infinite() {
-                1.0
-            } else if area_2.is_infinite() {
-                0.0
-            } else {
-                area_1 / (area_1 + area_2)
-            };
-            let prob_2 = 1.0 - prob_1;
-
-            // Select one of the triangles and sample it
-            let shadow_vec = if u < prob_1 {
-                uniform_sample_spherical_triangle(sp2, sp1, sp3, v, u / prob_1)
-            } else {
-                uniform_sample_spherical_triangle(sp4, sp1, sp3, v, 1.0 - ((u - prob_1) / prob_2))
-            };
+            // Sample the solid angle for close lights.
+            let shadow_vec = projection.sample(u, v);
 
             // Project shadow_vec back onto the light's surface
-            let arr_local = arr * *space;
             let shadow_vec_local = shadow_vec * *space;
             let shadow_vec_local = shadow_vec_local * (-arr_local.z() / shadow_vec_local.z());
             let mut sample_point_local = arr_local + shadow_vec_local;
             {
-                let x = sample_point_local.x().max(dim.0 * -0.5).min(dim.0 * 0.5);
-                let y = sample_point_local.y().max(dim.1 * -0.5).min(dim.1 * 0.5);
+                let x = sample_point_local.x().max(min_x).min(max_x);
+                let y = sample_point_local.y().max(min_y).min(max_y);
                 sample_point_local.set_x(x);
                 sample_point_local.set_y(y);
                 sample_point_local.set_z(0.0);
             }
-            let sample_point = sample_point_local * space_inv;
-            let point_err = 0.0001; // TODO: this is a hack, do properly.
+            let (sample_point, point_err) =
+                transform_point_err(sample_point_local, 0.0, &space_inv);
 
             // Calculate pdf and light energy
-            let pdf = 1.0 / (area_1 + area_2); // PDF of the ray direction being sampled
-            let spectral_sample =
-                col.to_spectral_sample(wavelength) * surface_area_inv as f32 * 0.5;
+            let pdf = 1.0 / solid_angle; // PDF of the ray direction being sampled
+            let spectral_sample = if self.emits_towards(arr_local - sample_point_local) {
+                col.to_spectral_sample(wavelength) * radiance_scale
+            } else {
+                SpectralSample::new(wavelength)
+            };
 
             (
                 spectral_sample,
@@ -242,6 +400,64 @@ impl<'a> SurfaceLight for RectangleLight<'a> {
         }
     }
 
+    fn sample_emission(
+        &self,
+        space: &Matrix4x4,
+        uv: (f32, f32),
+        dir_uv: (f32, f32),
+        wavelength: f32,
+        time: f32,
+    ) -> (SpectralSample, (Point, Normal, f32), Vector) {
+        let dim = lerp_slice(self.dimensions, time);
+        let col = lerp_slice(self.colors, time);
+
+        let inv_space = space.inverse();
+
+        // Sample the rectangle uniformly by area.
+        let (sample_point, point_err) = transform_point_err(
+            Point::new((uv.0 - 0.5) * dim.0, (uv.1 - 0.5) * dim.1, 0.0),
+            0.0,
+            &inv_space,
+        );
+
+        // Two-sided lights emit half of their light from each side, so
+        // pick one.
+        let (normal_local, dir_uv) = if !self.two_sided || dir_uv.0 < 0.5 {
+            let u = if self.two_sided {
+                dir_uv.0 * 2.0
+            } else {
+                dir_uv.0
+            };
+            (Normal::new(0.0, 0.0, 1.0), (u, dir_uv.1))
+        } else {
+            (
+                Normal::new(0.0, 0.0, -1.0),
+                ((dir_uv.0 - 0.5) * 2.0, dir_uv.1),
+            )
+        };
+        let normal = normal_local * inv_space;
+
+        // Cosine-sample the directions within the spread cone, which
+        // projects to a disk of radius sin(half-angle) on the surface.
+        let sin_max2 = 1.0 - (self.spread_cos * self.spread_cos);
+        let dir = {
+            let (x, y) = square_to_circle((dir_uv.0 * 2.0) - 1.0, (dir_uv.1 * 2.0) - 1.0);
+            let (x, y) = (x * sin_max2.sqrt(), y * sin_max2.sqrt());
+            let z = (1.0 - (x * x) - (y * y)).max(0.0).sqrt();
+            zup_to_vec(Vector::new(x, y, z), normal.into_vector()).normalized()
+        };
+
+        let area_scale = area_scale(
+            &inv_space,
+            Vector::new(1.0, 0.0, 0.0),
+            Vector::new(0.0, 1.0, 0.0),
+        );
+        let flux =
+            col.to_spectral_sample(wavelength) * (std::f32::consts::PI * sin_max2 * area_scale);
+
+        (flux, (sample_point, normal, point_err), dir)
+    }
+
     fn is_delta(&self) -> bool {
         false
     }
@@ -255,14 +471,96 @@ impl<'a> SurfaceLight for RectangleLight<'a> {
 }
 
 impl<'a> Surface for RectangleLight<'a> {
-    fn intersect_rays(
-        &self,
-        rays: &mut RayBatch,
-        ray_stack: &mut RayStack,
-        isects: &mut [SurfaceIntersection],
-        shader: &dyn SurfaceShader,
-        space: &[Matrix4x4],
-    ) {
+    fn stats(&self) -> SurfaceStats {
+        SurfaceStats {
+            primitive_type: "rectangle light",
+            primitive_count: 1,
+            bytes: std::mem::size_of::<Self>()
+                + std::mem::size_of_val(self.dimensions)
+                + std::mem::size_of_val(self.colors)
+                + std::mem::size_of_val(self.bounds_),
+            bvh: None,
+        }
+    }
+
+    fn intersect_closest(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Closest);
+    }
+
+    fn intersect_any(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Any);
+    }
+
+    fn shade_hit(&self, ctx: ShadeContext) -> SurfaceIntersection {
+        let ShadeContext {
+            hit,
+            rays,
+            ray_idx,
+            space,
+            object_id,
+            instance_id,
+            instance_index,
+            ..
+        } = ctx;
+        let t = hit.t;
+        let time = rays.time(ray_idx);
+        let orig = rays.orig(ray_idx);
+        let dir = rays.dir(ray_idx);
+        let dim = lerp_slice(self.dimensions, time);
+        let xform = lerp_slice(space, time);
+        let tri = self.triangles(dim, &xform.inverse())[hit.prim as usize];
+
+        let (pos, pos_err) = triangle::surface_point(tri, hit.coords);
+        let normal = cross(tri.0 - tri.1, tri.0 - tri.2).into_normal();
+        let (dpdu, dpdv) = arbitrary_tangents(normal);
+
+        let intersection_data = SurfaceIntersectionData {
+            incoming: dir,
+            t: t,
+            pos: pos,
+            pos_err: pos_err,
+            nor: normal,
+            nor_g: normal,
+            uv: (0.0, 0.0),
+            dpdu: dpdu,
+            dpdv: dpdv,
+            local_space: xform,
+            footprint: rays.spread(ray_idx) * t,
+            object_id: object_id,
+            instance_id: instance_id,
+            instance_index: instance_index,
+            sample_pdf: self.sample_pdf(&xform, orig, dir, pos, rays.wavelength(ray_idx), time),
+        };
+
+        let closure = {
+            let scale = if self.emits_towards(-(dir * xform)) {
+                self.radiance_scale(dim)
+            } else {
+                0.0
+            };
+            let color = lerp_slice(self.colors, time) * scale;
+            SurfaceClosure::Emit(color)
+        };
+
+        SurfaceIntersection::Hit {
+            intersection_data: intersection_data,
+            closure: closure,
+        }
+    }
+}
+
+impl<'a> RectangleLight<'a> {
+    fn intersect(&self, ctx: IntersectContext, query: HitQuery) {
+        let IntersectContext {
+            rays,
+            ray_stack,
+            hits,
+            shader,
+            space,
+            source,
+            ..
+        } = ctx;
+
         let _ = shader; // Silence 'unused' warning
 
         ray_stack.pop_do_next_task(|ray_idx| {
@@ -277,55 +575,15 @@ impl<'a> Surface for RectangleLight<'a> {
 
             let space_inv = xform.inverse();
 
-            // Get the four corners of the rectangle, transformed into world space
-            let p1 = Point::new(dim.0 * 0.5, dim.1 * 0.5, 0.0) * space_inv;
-            let p2 = Point::new(dim.0 * -0.5, dim.1 * 0.5, 0.0) * space_inv;
-            let p3 = Point::new(dim.0 * -0.5, dim.1 * -0.5, 0.0) * space_inv;
-            let p4 = Point::new(dim.0 * 0.5, dim.1 * -0.5, 0.0) * space_inv;
-
             // Test against two triangles that make up the light
             let ray_pre = triangle::RayTriPrecompute::new(dir);
-            for tri in &[(p1, p2, p3), (p3, p4, p1)] {
+            for (tri_i, tri) in self.triangles(dim, &space_inv).iter().enumerate() {
                 if let Some((t, b0, b1, b2)) = triangle::intersect_ray(orig, ray_pre, max_t, *tri) {
                     if t < max_t {
-                        if rays.is_occlusion(ray_idx) {
-                            isects[ray_idx] = SurfaceIntersection::Occlude;
+                        hits[ray_idx] = Some(SurfaceHit::new(t, tri_i, (b0, b1, b2), source));
+                        if query == HitQuery::Any {
                             rays.mark_done(ray_idx);
                         } else {
-                            let (pos, pos_err) = triangle::surface_point(*tri, (b0, b1, b2));
-                            let normal = cross(tri.0 - tri.1, tri.0 - tri.2).into_normal();
-
-                            let intersection_data = SurfaceIntersectionData {
-                                incoming: dir,
-                                t: t,
-                                pos: pos,
-                                pos_err: pos_err,
-                                nor: normal,
-                                nor_g: normal,
-                                local_space: xform,
-                                sample_pdf: self.sample_pdf(
-                                    &xform,
-                                    orig,
-                                    dir,
-                                    pos,
-                                    rays.wavelength(ray_idx),
-                                    time,
-                                ),
-                            };
-
-                            let closure = {
-                                let inv_surface_area = (1.0 / (dim.0 as f64 * dim.1 as f64)) as f32;
-                                let color = lerp_slice(self.colors, time) * inv_surface_area;
-                                SurfaceClosure::Emit(color)
-                            };
-
-                            // Fill in intersection
-                            isects[ray_idx] = SurfaceIntersection::Hit {
-                                intersection_data: intersection_data,
-                                closure: closure,
-                            };
-
-                            // Set ray's max t
                             rays.set_max_t(ray_idx, t);
                         }
 
@@ -335,6 +593,16 @@ impl<'a> Surface for RectangleLight<'a> {
             }
         });
     }
+
+    /// The two triangles that make up the rectangle, transformed into world
+    /// space.
+    fn triangles(&self, dim: (f32, f32), space_inv: &Matrix4x4) -> [(Point, Point, Point); 2] {
+        let p1 = Point::new(dim.0 * 0.5, dim.1 * 0.5, 0.0) * *space_inv;
+        let p2 = Point::new(dim.0 * -0.5, dim.1 * 0.5, 0.0) * *space_inv;
+        let p3 = Point::new(dim.0 * -0.5, dim.1 * -0.5, 0.0) * *space_inv;
+        let p4 = Point::new(dim.0 * 0.5, dim.1 * -0.5, 0.0) * *space_inv;
+        [(p1, p2, p3), (p3, p4, p1)]
+    }
 }
 
 impl<'a> Boundable for RectangleLight<'a> {
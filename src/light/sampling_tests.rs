@@ -0,0 +1,374 @@
+//! Validation of the lights' sampling routines.
+//!
+//! Each light's total emitted power is estimated by Monte Carlo integration
+//! of the flux through a sphere surrounding it, using only the light's own
+//! `sample_from_point()`.  That's then compared to the power the light
+//! should emit analytically.  Any mismatch between the radiance a light
+//! reports and the pdf it reports shows up as a power error.
+//!
+//! The power of the light's `sample_emission()` is checked against the
+//! same analytic power.
+//!
+//! Any new light type should be added here.
+
+use std::f64::consts::PI as PI_64;
+
+use kioku::Arena;
+
+use crate::{
+    color::Color,
+    hash::hash_u32_to_f32,
+    math::{dot, Matrix4x4, Point, Vector},
+    sampling::{spherical_triangle_solid_angle, uniform_sample_sphere},
+};
+
+use super::{
+    DiskLight, DistantDiskLight, LightUnits, PointLight, RectangleLight, SphereLight, SurfaceLight,
+    TubeLight, WorldLightSource, SUN_RADIUS,
+};
+
+const WAVELENGTH: f32 = 550.0;
+const TIME: f32 = 0.5;
+const SAMPLES: u32 = 200_000;
+
+/// Largest allowed relative error of the estimated power.
+const TOLERANCE: f64 = 0.02;
+
+fn white() -> Color {
+    Color::new_xyz((1.0, 1.0, 1.0))
+}
+
+/// The spectral value of white, for normalizing away the spectral
+/// upsampling.
+fn white_value() -> f64 {
+    white().to_spectral_sample(WAVELENGTH).e.x() as f64
+}
+
+/// A rigid world-to-object transform, so the lights are tested away from
+/// their local coordinate axes.
+fn test_space() -> Matrix4x4 {
+    let (s, c) = 0.7f32.sin_cos();
+    let rotation = Matrix4x4::new_from_values(
+        1.0, 0.0, 0.0, 0.0, //
+        0.0, c, -s, 0.0, //
+        0.0, s, c, 0.0, //
+        0.0, 0.0, 0.0, 1.0,
+    );
+    let translation = Matrix4x4::from_location(Point::new(0.2, -0.3, 0.1));
+    (rotation * translation).inverse()
+}
+
+/// Estimates the power emitted by a light, relative to white, by
+/// integrating the flux arriving at points on a sphere of the given radius
+/// around the origin.
+fn estimate_power(light: &dyn SurfaceLight, space: &Matrix4x4, radius: f32) -> f64 {
+    let mut sum = 0.0f64;
+    for i in 0..SAMPLES {
+        let arr = (uniform_sample_sphere(hash_u32_to_f32(i, 0), hash_u32_to_f32(i, 1)) * radius)
+            .into_point();
+        let (light_color, (sample_point, _, _), pdf) = light.sample_from_point(
+            space,
+            arr,
+            hash_u32_to_f32(i, 2),
+            hash_u32_to_f32(i, 3),
+            WAVELENGTH,
+            TIME,
+        );
+        if pdf > 0.0 {
+            // Flux leaving the sphere through its surface at `arr`.
+            let to_light = (sample_point - arr).normalized();
+            let cos = dot(to_light, -arr.into_vector().normalized()).max(0.0);
+            sum += (light_color.e.x() * cos / pdf) as f64;
+        }
+    }
+
+    let sphere_area = 4.0 * PI_64 * radius as f64 * radius as f64;
+    sum / SAMPLES as f64 * sphere_area / white_value()
+}
+
+/// Estimates the power emitted by a light, relative to white, by averaging
+/// the flux of its emission samples.
+///
+/// Also checks that the light leaves from the side of the surface the
+/// sample's normal is on.
+fn estimate_emitted_power(name: &str, light: &dyn SurfaceLight, space: &Matrix4x4) -> f64 {
+    let mut sum = 0.0f64;
+    for i in 0..SAMPLES {
+        let (flux, (_, nor, _), dir) = light.sample_emission(
+            space,
+            (hash_u32_to_f32(i, 0), hash_u32_to_f32(i, 1)),
+            (hash_u32_to_f32(i, 2), hash_u32_to_f32(i, 3)),
+            WAVELENGTH,
+            TIME,
+        );
+        assert!(
+            dot(dir, nor.into_vector()) >= 0.0,
+            "{} emits light into its own surface",
+            name
+        );
+        sum += flux.e.x() as f64;
+    }
+
+    sum / SAMPLES as f64 / white_value()
+}
+
+/// Checks a light's estimated power against `expected` from both close up
+/// and far away, which exercises different sampling strategies in some
+/// lights, and from its emission sampling.
+fn check_power(name: &str, light: &dyn SurfaceLight, expected: f64) {
+    let space = test_space();
+    let power = estimate_emitted_power(name, light, &space);
+    assert!(
+        ((power - expected) / expected).abs() <= TOLERANCE,
+        "{} emits the wrong power from emission sampling: expected {}, got {}",
+        name,
+        expected,
+        power
+    );
+
+    for &radius in &[3.0, 50.0] {
+        let power = estimate_power(light, &space, radius);
+        assert!(
+            ((power - expected) / expected).abs() <= TOLERANCE,
+            "{} emits the wrong power when sampled from distance {}: expected {}, got {}",
+            name,
+            radius,
+            expected,
+            power
+        );
+    }
+}
+
+// Normalized light colors are the light's power divided by PI.
+const NORMALIZED_POWER: f64 = PI_64;
+
+#[test]
+fn sphere_light_power() {
+    let arena = Arena::new();
+    let light = SphereLight::new(&arena, &[0.5], &[white()], LightUnits::Normalized);
+    check_power("SphereLight", &light, NORMALIZED_POWER);
+}
+
+#[test]
+fn rectangle_light_power() {
+    let arena = Arena::new();
+    for &two_sided in &[false, true] {
+        let light = RectangleLight::new(
+            &arena,
+            &[(1.0, 2.0)],
+            &[white()],
+            two_sided,
+            std::f32::consts::PI,
+            LightUnits::Normalized,
+        );
+        check_power("RectangleLight", &light, NORMALIZED_POWER);
+    }
+}
+
+#[test]
+fn rectangle_light_spread_power() {
+    // The spread blocks light outside of the cone rather than
+    // redistributing it, so only the cosine-weighted fraction of the
+    // hemisphere inside the cone, sin^2 of the half-angle, is emitted.
+    let arena = Arena::new();
+    let spread = std::f32::consts::FRAC_PI_2;
+    let light = RectangleLight::new(
+        &arena,
+        &[(1.0, 2.0)],
+        &[white()],
+        false,
+        spread,
+        LightUnits::Normalized,
+    );
+    let fraction = (spread as f64 * 0.5).sin().powi(2);
+    check_power(
+        "RectangleLight with spread",
+        &light,
+        NORMALIZED_POWER * fraction,
+    );
+}
+
+#[test]
+fn rectangle_light_close_samples() {
+    // Close up, samples are uniform in solid angle, which is checked
+    // against the solid angle of the rectangle split into two spherical
+    // triangles.  The samples have to land on the rectangle, too.
+    let arena = Arena::new();
+    let light = RectangleLight::new(
+        &arena,
+        &[(1.0, 2.0)],
+        &[white()],
+        true,
+        std::f32::consts::PI,
+        LightUnits::Normalized,
+    );
+    let space = Matrix4x4::new();
+    for &arr in &[
+        Point::new(0.1, 0.2, 0.3),
+        Point::new(2.0, -1.5, -0.5),
+        Point::new(-0.4, 0.9, 0.05),
+    ] {
+        let corners = [(0.5, 1.0), (-0.5, 1.0), (-0.5, -1.0), (0.5, -1.0)];
+        let sp: Vec<Vector> = corners
+            .iter()
+            .map(|&(x, y)| (Point::new(x, y, 0.0) - arr).normalized())
+            .collect();
+        let solid_angle = spherical_triangle_solid_angle(sp[1], sp[0], sp[2])
+            + spherical_triangle_solid_angle(sp[3], sp[0], sp[2]);
+
+        for i in 0..64 {
+            let (_, (sample_point, _, _), pdf) = light.sample_from_point(
+                &space,
+                arr,
+                hash_u32_to_f32(i, 0),
+                hash_u32_to_f32(i, 1),
+                WAVELENGTH,
+                TIME,
+            );
+            assert!((pdf * solid_angle - 1.0).abs() < 1.0e-3);
+            assert!(sample_point.z().abs() < 1.0e-5);
+            assert!(sample_point.x().abs() <= 0.5 + 1.0e-5);
+            assert!(sample_point.y().abs() <= 1.0 + 1.0e-5);
+        }
+    }
+}
+
+#[test]
+fn disk_light_power() {
+    let arena = Arena::new();
+    for &two_sided in &[false, true] {
+        let light = DiskLight::new(
+            &arena,
+            &[0.75],
+            &[white()],
+            two_sided,
+            LightUnits::Normalized,
+        );
+        check_power("DiskLight", &light, NORMALIZED_POWER);
+    }
+}
+
+#[test]
+fn tube_light_power() {
+    let arena = Arena::new();
+    let light = TubeLight::new(&arena, &[0.2], &[1.5], &[white()], LightUnits::Normalized);
+    check_power("TubeLight", &light, NORMALIZED_POWER);
+}
+
+#[test]
+fn point_light_power() {
+    let arena = Arena::new();
+    let light = PointLight::new(&arena, &[white()], LightUnits::Normalized);
+    check_power("PointLight", &light, NORMALIZED_POWER);
+}
+
+#[test]
+fn scaled_emission_power() {
+    // Normalized colors are relative to the light's local size, so scaling
+    // the light up scales its power with its surface area.
+    let arena = Arena::new();
+    let space = Matrix4x4::new_from_values(
+        2.0, 0.0, 0.0, 0.0, //
+        0.0, 2.0, 0.0, 0.0, //
+        0.0, 0.0, 2.0, 0.0, //
+        0.0, 0.0, 0.0, 1.0,
+    )
+    .inverse();
+    let light = DiskLight::new(&arena, &[0.75], &[white()], false, LightUnits::Normalized);
+    let power = estimate_emitted_power("Scaled DiskLight", &light, &space);
+    let expected = NORMALIZED_POWER * 4.0;
+    assert!(
+        ((power - expected) / expected).abs() <= TOLERANCE,
+        "scaled DiskLight emits the wrong power: expected {}, got {}",
+        expected,
+        power
+    );
+}
+
+#[test]
+fn power_units() {
+    // In power units the color is the total power.
+    let arena = Arena::new();
+    let light = SphereLight::new(&arena, &[0.5], &[white()], LightUnits::Power);
+    check_power("SphereLight in power units", &light, 1.0);
+    let light = RectangleLight::new(
+        &arena,
+        &[(1.0, 2.0)],
+        &[white()],
+        true,
+        std::f32::consts::PI,
+        LightUnits::Power,
+    );
+    check_power("RectangleLight in power units", &light, 1.0);
+}
+
+/// Estimates the irradiance a distant light delivers to a surface facing
+/// it, relative to white.
+fn estimate_irradiance(light: &dyn WorldLightSource, direction: Vector) -> f64 {
+    let nor = -direction.normalized();
+    let mut sum = 0.0f64;
+    for i in 0..SAMPLES {
+        let (light_color, shadow_vec, pdf) = light.sample_from_point(
+            hash_u32_to_f32(i, 0),
+            hash_u32_to_f32(i, 1),
+            WAVELENGTH,
+            TIME,
+        );
+        if pdf > 0.0 {
+            let cos = dot(shadow_vec.normalized(), nor).max(0.0);
+            sum += (light_color.e.x() * cos / pdf) as f64;
+        }
+    }
+    sum / SAMPLES as f64 / white_value()
+}
+
+#[test]
+fn distant_disk_light_irradiance() {
+    // Distant lights have infinite power, so check the irradiance they
+    // deliver to a surface facing them instead.  A normalized color is the
+    // radiance times the solid angle, so the irradiance is the color times
+    // the average cosine over the disk, (1 + cos(radius)) / 2.
+    let arena = Arena::new();
+    let radius = 0.2f32;
+    let direction = Vector::new(0.3, -1.0, 0.2);
+    let light = DistantDiskLight::new(
+        &arena,
+        &[radius],
+        &[direction],
+        &[white()],
+        LightUnits::Normalized,
+    );
+
+    let irradiance = estimate_irradiance(&light, direction);
+    let expected = (1.0 + (radius as f64).cos()) * 0.5;
+    assert!(
+        ((irradiance - expected) / expected).abs() <= TOLERANCE,
+        "DistantDiskLight delivers the wrong irradiance: expected {}, got {}",
+        expected,
+        irradiance
+    );
+}
+
+#[test]
+fn distant_disk_light_given_irradiance() {
+    // Lights made from an irradiance deliver exactly that, whatever their
+    // size, down to sharp directional lights.
+    let arena = Arena::new();
+    let direction = Vector::new(0.3, -1.0, 0.2);
+    for &radius in &[0.5, 0.2, SUN_RADIUS, 0.0] {
+        let light = DistantDiskLight::from_irradiance(&arena, &[radius], &[direction], &[white()]);
+        assert_eq!(light.is_delta(), radius == 0.0);
+
+        let irradiance = estimate_irradiance(&light, direction);
+        assert!(
+            (irradiance - 1.0).abs() <= TOLERANCE,
+            "DistantDiskLight with radius {} delivers the wrong irradiance: expected 1, got {}",
+            radius,
+            irradiance
+        );
+
+        // Only lights with a size can be seen.
+        let (_, pdf) = light.evaluate(-direction, WAVELENGTH, TIME);
+        assert_eq!(pdf > 0.0, radius > 0.0);
+    }
+}
@@ -6,23 +6,35 @@ use crate::{
     bbox::BBox,
     boundable::Boundable,
     color::{Color, SpectralSample},
+    fp_utils::{fp_gamma, transform_point_err},
     lerp::lerp_slice,
-    math::{coordinate_system_from_vector, dot, Matrix4x4, Normal, Point, Vector},
-    ray::{RayBatch, RayStack},
+    math::{coordinate_system_from_vector, Matrix4x4, Normal, Point, Vector},
     sampling::{uniform_sample_cone, uniform_sample_cone_pdf, uniform_sample_sphere},
     shading::surface_closure::SurfaceClosure,
-    shading::SurfaceShader,
-    surface::{Surface, SurfaceIntersection, SurfaceIntersectionData},
+    surface::{
+        arbitrary_tangents, HitQuery, IntersectContext, ShadeContext, Surface, SurfaceHit,
+        SurfaceIntersection, SurfaceIntersectionData, SurfaceStats,
+    },
 };
 
-use super::SurfaceLight;
-
-// TODO: use proper error bounds for sample generation to avoid self-shadowing
-// instead of these fudge factors.
-const SAMPLE_POINT_FUDGE: f32 = 0.001;
+use super::{
+    area_scale, cosine_emission_dir, normalize_colors,
+    point_light::{sample_point, sample_point_emission},
+    LightUnits, SurfaceLight,
+};
 
-// TODO: handle case where radius = 0.0.
+/// Transforms a local-space point that's been projected onto the sphere's
+/// surface into world space, returning the point and its error magnitude.
+fn surface_point(p: Vector, inv_space: &Matrix4x4) -> (Point, f32) {
+    // The projection leaves an error of a few ulps, as in section 3.9.4
+    // of PBRT 3rd edition.
+    let err = fp_gamma(5) * p.abs().co.max_element();
+    transform_point_err(p.into_point(), err, inv_space)
+}
 
+/// A spherical light centered on the origin of its local space.
+///
+/// A radius of zero is handled as a point light.
 #[derive(Copy, Clone, Debug)]
 pub struct SphereLight<'a> {
     radii: &'a [f32],
@@ -31,7 +43,12 @@ pub struct SphereLight<'a> {
 }
 
 impl<'a> SphereLight<'a> {
-    pub fn new<'b>(arena: &'b Arena, radii: &[f32], colors: &[Color]) -> SphereLight<'b> {
+    pub fn new<'b>(
+        arena: &'b Arena,
+        radii: &[f32],
+        colors: &[Color],
+        units: LightUnits,
+    ) -> SphereLight<'b> {
         let bbs: Vec<_> = radii
             .iter()
             .map(|r| BBox {
@@ -39,6 +56,10 @@ impl<'a> SphereLight<'a> {
                 max: Point::new(*r, *r, *r),
             })
             .collect();
+        let colors = normalize_colors(colors, units, |time| {
+            let radius = lerp_slice(radii, time) as f64;
+            (1.0 / (4.0 * PI_64 * radius * radius)) as f32
+        });
         SphereLight {
             radii: arena.copy_slice(&radii),
             colors: arena.copy_slice(&colors),
@@ -46,7 +67,7 @@ impl<'a> SphereLight<'a> {
         }
     }
 
-    // TODO: this is only used from within `intersect_rays`, and could be done
+    // TODO: this is only used from within `intersect()`, and could be done
     // more efficiently by inlining it there.
     fn sample_pdf(
         &self,
@@ -91,6 +112,11 @@ impl<'a> SurfaceLight for SphereLight<'a> {
         wavelength: f32,
         time: f32,
     ) -> (SpectralSample, (Point, Normal, f32), f32) {
+        // Zero-radius spheres are point lights.
+        if lerp_slice(self.radii, time) <= 0.0 {
+            return sample_point(space, arr, lerp_slice(self.colors, time), wavelength);
+        }
+
         // TODO: track fp error due to transforms
         let arr = arr * *space;
         let pos = Point::new(0.0, 0.0, 0.0);
@@ -111,14 +137,6 @@ impl<'a> SurfaceLight for SphereLight<'a> {
         let (z, x, y) = coordinate_system_from_vector(z);
         let (x, y, z) = (x.normalized(), y.normalized(), z.normalized());
 
-        // Pre-calculate sample point error magnitude.
-        // TODO: do this properly.  This is a total hack.
-        let sample_point_err = {
-            let v = Vector::new(radius as f32, radius as f32, radius as f32);
-            let v2 = v * inv_space;
-            v2.length() * SAMPLE_POINT_FUDGE
-        };
-
         // If we're outside the sphere, sample the surface based on
         // the angle it subtends from the point being lit.
         if d > radius {
@@ -154,12 +172,12 @@ impl<'a> SurfaceLight for SphereLight<'a> {
             );
 
             // Calculate the final values and return everything.
-            let (sample_point, normal) = {
+            let ((sample_point, sample_point_err), normal) = {
                 let sample_vec = (x * sample.x()) + (y * sample.y()) + (z * sample.z());
                 let normal = (arr + sample_vec).into_vector().normalized();
                 let point = normal * radius as f32;
                 (
-                    point.into_point() * inv_space,
+                    surface_point(point, &inv_space),
                     normal.into_normal() * inv_space,
                 )
             };
@@ -172,12 +190,12 @@ impl<'a> SurfaceLight for SphereLight<'a> {
             );
         } else {
             // If we're inside the sphere, there's light from every direction.
-            let (sample_point, normal) = {
+            let ((sample_point, sample_point_err), normal) = {
                 let sample_vec = uniform_sample_sphere(u, v);
                 let normal = (arr + sample_vec).into_vector().normalized();
                 let point = normal * radius as f32;
                 (
-                    point.into_point() * inv_space,
+                    surface_point(point, &inv_space),
                     normal.into_normal() * inv_space,
                 )
             };
@@ -191,8 +209,39 @@ impl<'a> SurfaceLight for SphereLight<'a> {
         }
     }
 
+    fn sample_emission(
+        &self,
+        space: &Matrix4x4,
+        uv: (f32, f32),
+        dir_uv: (f32, f32),
+        wavelength: f32,
+        time: f32,
+    ) -> (SpectralSample, (Point, Normal, f32), Vector) {
+        let radius = lerp_slice(self.radii, time);
+        let col = lerp_slice(self.colors, time);
+
+        // Zero-radius spheres are point lights.
+        if radius <= 0.0 {
+            return sample_point_emission(space, col, dir_uv, wavelength);
+        }
+
+        let inv_space = space.inverse();
+
+        // Sample the sphere uniformly by (local) area.
+        let normal_local = uniform_sample_sphere(uv.0, uv.1).normalized();
+        let (sample_point, sample_point_err) = surface_point(normal_local * radius, &inv_space);
+        let normal = normal_local.into_normal() * inv_space;
+        let dir = cosine_emission_dir(normal, dir_uv);
+
+        let (_, tangent_1, tangent_2) = coordinate_system_from_vector(normal_local);
+        let flux = col.to_spectral_sample(wavelength)
+            * (std::f32::consts::PI * area_scale(&inv_space, tangent_1, tangent_2));
+
+        (flux, (sample_point, normal, sample_point_err), dir)
+    }
+
     fn is_delta(&self) -> bool {
-        false
+        self.radii.iter().all(|r| *r <= 0.0)
     }
 
     fn approximate_energy(&self) -> f32 {
@@ -204,14 +253,105 @@ impl<'a> SurfaceLight for SphereLight<'a> {
 }
 
 impl<'a> Surface for SphereLight<'a> {
-    fn intersect_rays(
-        &self,
-        rays: &mut RayBatch,
-        ray_stack: &mut RayStack,
-        isects: &mut [SurfaceIntersection],
-        shader: &dyn SurfaceShader,
-        space: &[Matrix4x4],
-    ) {
+    fn stats(&self) -> SurfaceStats {
+        SurfaceStats {
+            primitive_type: "sphere light",
+            primitive_count: 1,
+            bytes: std::mem::size_of::<Self>()
+                + std::mem::size_of_val(self.radii)
+                + std::mem::size_of_val(self.colors)
+                + std::mem::size_of_val(self.bounds_),
+            bvh: None,
+        }
+    }
+
+    fn intersect_closest(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Closest);
+    }
+
+    fn intersect_any(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Any);
+    }
+
+    fn shade_hit(&self, ctx: ShadeContext) -> SurfaceIntersection {
+        let ShadeContext {
+            hit,
+            rays,
+            ray_idx,
+            space,
+            object_id,
+            instance_id,
+            instance_index,
+            ..
+        } = ctx;
+        let t = hit.t;
+        let time = rays.time(ray_idx);
+        let xform = lerp_slice(space, time);
+        let inv_xform = xform.inverse();
+        let radius = lerp_slice(self.radii, time);
+
+        // Position is calculated from the local-space ray and t, and then
+        // re-projected onto the surface of the sphere.
+        let orig = (rays.orig(ray_idx) * xform).into_vector();
+        let dir = rays.dir(ray_idx) * xform;
+        let t_pos = orig + (dir * t);
+        let unit_pos = t_pos.normalized();
+        let (pos, pos_err) = surface_point(unit_pos * radius, &inv_xform);
+
+        let normal = unit_pos.into_normal() * inv_xform;
+        let (dpdu, dpdv) = arbitrary_tangents(normal);
+
+        let intersection_data = SurfaceIntersectionData {
+            incoming: rays.dir(ray_idx),
+            t: t,
+            pos: pos,
+            pos_err: pos_err,
+            nor: normal,
+            nor_g: normal,
+            uv: (0.0, 0.0),
+            dpdu: dpdu,
+            dpdv: dpdv,
+            local_space: xform,
+            footprint: rays.spread(ray_idx) * t,
+            object_id: object_id,
+            instance_id: instance_id,
+            instance_index: instance_index,
+            sample_pdf: self.sample_pdf(
+                &xform,
+                rays.orig(ray_idx),
+                rays.dir(ray_idx),
+                0.0,
+                0.0,
+                rays.wavelength(ray_idx),
+                time,
+            ),
+        };
+
+        let closure = {
+            let inv_surface_area = (1.0 / (4.0 * PI_64 * radius as f64 * radius as f64)) as f32;
+            let color = lerp_slice(self.colors, time) * inv_surface_area;
+            SurfaceClosure::Emit(color)
+        };
+
+        SurfaceIntersection::Hit {
+            intersection_data: intersection_data,
+            closure: closure,
+        }
+    }
+}
+
+impl<'a> SphereLight<'a> {
+    fn intersect(&self, ctx: IntersectContext, query: HitQuery) {
+        let IntersectContext {
+            rays,
+            ray_stack,
+            hits,
+            shader,
+            space,
+            source,
+            ..
+        } = ctx;
+
         let _ = shader; // Silence 'unused' warning
 
         ray_stack.pop_do_next_task(|ray_idx| {
@@ -222,6 +362,10 @@ impl<'a> Surface for SphereLight<'a> {
 
             // Get the radius of the sphere at the ray's time
             let radius = lerp_slice(self.radii, time); // Radius of the sphere
+            if radius <= 0.0 {
+                // Zero-radius spheres are point lights, which can't be hit.
+                return;
+            }
 
             // Get the ray origin and direction in local space
             let orig = rays.orig_local(ray_idx).into_vector();
@@ -233,11 +377,17 @@ impl<'a> Surface for SphereLight<'a> {
             // the solution using the quadratic formula.  Note that there is a
             // slightly more stable form of it when computing it on a computer, and
             // we use that method to keep everything accurate.
+            //
+            // It's also done in double precision: shadow rays end within a
+            // tiny error bound of the light's surface, and single precision
+            // isn't accurate enough to reliably tell which side they end on.
 
             // Calculate quadratic coeffs
-            let a = dir.length2();
-            let b = 2.0 * dot(dir, orig);
-            let c = orig.length2() - (radius * radius);
+            let (ox, oy, oz) = (orig.x() as f64, orig.y() as f64, orig.z() as f64);
+            let (dx, dy, dz) = (dir.x() as f64, dir.y() as f64, dir.z() as f64);
+            let a = (dx * dx) + (dy * dy) + (dz * dz);
+            let b = 2.0 * ((dx * ox) + (dy * oy) + (dz * oz));
+            let c = (ox * ox) + (oy * oy) + (oz * oz) - (radius as f64 * radius as f64);
 
             let discriminant = (b * b) - (4.0 * a * c);
             if discriminant < 0.0 {
@@ -256,8 +406,12 @@ impl<'a> Surface for SphereLight<'a> {
             };
 
             // Get our final parametric values
-            let mut t0 = q / a;
-            let mut t1 = if q != 0.0 { c / q } else { rays.max_t(ray_idx) };
+            let mut t0 = (q / a) as f32;
+            let mut t1 = if q != 0.0 {
+                (c / q) as f32
+            } else {
+                rays.max_t(ray_idx)
+            };
 
             // Swap them so they are ordered right
             if t0 > t1 {
@@ -281,57 +435,11 @@ impl<'a> Surface for SphereLight<'a> {
                 return;
             };
 
-            // We hit the sphere, so calculate intersection info.
-            if rays.is_occlusion(ray_idx) {
-                isects[ray_idx] = SurfaceIntersection::Occlude;
+            // We hit the sphere, so record the hit.
+            hits[ray_idx] = Some(SurfaceHit::new(t, 0, (0.0, 0.0, 0.0), source));
+            if query == HitQuery::Any {
                 rays.mark_done(ray_idx);
             } else {
-                let inv_xform = xform.inverse();
-
-                // Position is calculated from the local-space ray and t, and then
-                // re-projected onto the surface of the sphere.
-                let t_pos = orig + (dir * t);
-                let unit_pos = t_pos.normalized();
-                let pos = (unit_pos * radius * inv_xform).into_point();
-
-                // TODO: proper error bounds.
-                let pos_err = 0.001;
-
-                let normal = unit_pos.into_normal() * inv_xform;
-
-                let intersection_data = SurfaceIntersectionData {
-                    incoming: rays.dir(ray_idx),
-                    t: t,
-                    pos: pos,
-                    pos_err: pos_err,
-                    nor: normal,
-                    nor_g: normal,
-                    local_space: xform,
-                    sample_pdf: self.sample_pdf(
-                        &xform,
-                        rays.orig(ray_idx),
-                        rays.dir(ray_idx),
-                        0.0,
-                        0.0,
-                        rays.wavelength(ray_idx),
-                        time,
-                    ),
-                };
-
-                let closure = {
-                    let inv_surface_area =
-                        (1.0 / (4.0 * PI_64 * radius as f64 * radius as f64)) as f32;
-                    let color = lerp_slice(self.colors, time) * inv_surface_area;
-                    SurfaceClosure::Emit(color)
-                };
-
-                // Fill in intersection
-                isects[ray_idx] = SurfaceIntersection::Hit {
-                    intersection_data: intersection_data,
-                    closure: closure,
-                };
-
-                // Set ray's max t
                 rays.set_max_t(ray_idx, t);
             }
         });
@@ -0,0 +1,391 @@
+use std::f32::consts::PI as PI_32;
+use std::f64::consts::PI as PI_64;
+
+use kioku::Arena;
+
+use crate::{
+    bbox::BBox,
+    boundable::Boundable,
+    color::{Color, SpectralSample},
+    fp_utils::{fp_gamma, transform_point_err},
+    lerp::lerp_slice,
+    math::{cross, dot, Matrix4x4, Normal, Point, Vector},
+    shading::surface_closure::SurfaceClosure,
+    surface::{
+        arbitrary_tangents, HitQuery, IntersectContext, ShadeContext, Surface, SurfaceHit,
+        SurfaceIntersection, SurfaceIntersectionData, SurfaceStats,
+    },
+};
+
+use super::{area_scale, cosine_emission_dir, normalize_colors, LightUnits, SurfaceLight};
+
+/// Transforms a local-space point on the tube's surface into world space,
+/// returning the point and its error magnitude.
+fn surface_point(p: Point, inv_space: &Matrix4x4) -> (Point, f32) {
+    // Points on the tube are off by a few ulps in x and y, as for the
+    // cylinders in section 3.9.4 of PBRT 3rd edition.
+    let err = fp_gamma(3) * p.x().abs().max(p.y().abs());
+    transform_point_err(p, err, inv_space)
+}
+
+/// A cylindrical light, like a fluorescent tube.
+///
+/// The tube is centered on the origin of its local space and runs along the
+/// z axis.  It's open-ended and only emits from its outside surface.
+#[derive(Copy, Clone, Debug)]
+pub struct TubeLight<'a> {
+    radii: &'a [f32],
+    lengths: &'a [f32],
+    colors: &'a [Color],
+    bounds_: &'a [BBox],
+}
+
+impl<'a> TubeLight<'a> {
+    pub fn new<'b>(
+        arena: &'b Arena,
+        radii: &[f32],
+        lengths: &[f32],
+        colors: &[Color],
+        units: LightUnits,
+    ) -> TubeLight<'b> {
+        let max_length = lengths.iter().fold(0.0f32, |a, &b| a.max(b));
+        let bbs: Vec<_> = radii
+            .iter()
+            .map(|r| BBox {
+                min: Point::new(-*r, -*r, max_length * -0.5),
+                max: Point::new(*r, *r, max_length * 0.5),
+            })
+            .collect();
+        let colors = normalize_colors(colors, units, |time| {
+            radiance_scale(lerp_slice(radii, time), lerp_slice(lengths, time))
+        });
+        TubeLight {
+            radii: arena.copy_slice(&radii),
+            lengths: arena.copy_slice(&lengths),
+            colors: arena.copy_slice(&colors),
+            bounds_: arena.copy_slice(&bbs),
+        }
+    }
+
+    /// Returns the center and half-width of the range of angles around the
+    /// tube's axis that are visible from `arr_local`.
+    ///
+    /// Only that part of the tube is sampled, since the rest of it faces
+    /// away from the point.
+    fn visible_angles(arr_local: Point, radius: f32) -> (f32, f32) {
+        let d_perp = ((arr_local.x() * arr_local.x()) + (arr_local.y() * arr_local.y())).sqrt();
+        if d_perp <= radius {
+            (0.0, PI_32)
+        } else {
+            (
+                arr_local.y().atan2(arr_local.x()),
+                (radius / d_perp).min(1.0).acos(),
+            )
+        }
+    }
+
+    /// Solid angle pdf of sampling the local-space point `sample_local` on
+    /// the tube from the world-space point `arr`.
+    fn sample_pdf(
+        &self,
+        space: &Matrix4x4,
+        arr: Point,
+        sample_local: Point,
+        radius: f32,
+        length: f32,
+    ) -> f32 {
+        let inv_space = space.inverse();
+        let (_, half_angle) = Self::visible_angles(arr * *space, radius);
+
+        // Local-space tangents at the sample point are orthonormal, so the
+        // length of their world-space cross product is the area scale.
+        let phi = sample_local.y().atan2(sample_local.x());
+        let tangent_phi = Vector::new(-phi.sin(), phi.cos(), 0.0) * inv_space;
+        let tangent_z = Vector::new(0.0, 0.0, 1.0) * inv_space;
+        let area_scale = cross(tangent_phi, tangent_z).length();
+
+        let area = 2.0 * half_angle * radius * length * area_scale;
+
+        let normal = Normal::new(phi.cos(), phi.sin(), 0.0) * inv_space;
+        let shadow_vec = (sample_local * inv_space) - arr;
+        let cos = dot(shadow_vec.normalized(), normal.into_vector().normalized()).abs();
+
+        shadow_vec.length2() / (cos * area)
+    }
+}
+
+/// Scale factor from a tube light's color to its emitted radiance.
+fn radiance_scale(radius: f32, length: f32) -> f32 {
+    (1.0 / (2.0 * PI_64 * radius as f64 * length as f64)) as f32
+}
+
+impl<'a> SurfaceLight for TubeLight<'a> {
+    fn sample_from_point(
+        &self,
+        space: &Matrix4x4,
+        arr: Point,
+        u: f32,
+        v: f32,
+        wavelength: f32,
+        time: f32,
+    ) -> (SpectralSample, (Point, Normal, f32), f32) {
+        // Calculate time interpolated values
+        let radius = lerp_slice(self.radii, time);
+        let length = lerp_slice(self.lengths, time);
+        let col = lerp_slice(self.colors, time);
+        let surface_area_inv = radiance_scale(radius, length);
+
+        let inv_space = space.inverse();
+        let arr_local = arr * *space;
+
+        // Sample the visible part of the tube uniformly by area.
+        let (center_angle, half_angle) = Self::visible_angles(arr_local, radius);
+        let phi = center_angle + (((u * 2.0) - 1.0) * half_angle);
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let sample_local = Point::new(cos_phi * radius, sin_phi * radius, (v - 0.5) * length);
+        let normal_local = Normal::new(cos_phi, sin_phi, 0.0);
+
+        let (sample_point, sample_point_err) = surface_point(sample_local, &inv_space);
+        let normal = normal_local * inv_space;
+
+        let pdf = self.sample_pdf(space, arr, sample_local, radius, length);
+
+        // Only the outside of the tube emits.
+        let spectral_sample = if dot(arr_local - sample_local, normal_local.into_vector()) > 0.0 {
+            col.to_spectral_sample(wavelength) * surface_area_inv
+        } else {
+            SpectralSample::new(wavelength)
+        };
+
+        (
+            spectral_sample,
+            (sample_point, normal, sample_point_err),
+            pdf,
+        )
+    }
+
+    fn sample_emission(
+        &self,
+        space: &Matrix4x4,
+        uv: (f32, f32),
+        dir_uv: (f32, f32),
+        wavelength: f32,
+        time: f32,
+    ) -> (SpectralSample, (Point, Normal, f32), Vector) {
+        let radius = lerp_slice(self.radii, time);
+        let length = lerp_slice(self.lengths, time);
+        let col = lerp_slice(self.colors, time);
+
+        let inv_space = space.inverse();
+
+        // Sample the tube uniformly by (local) area.
+        let phi = uv.0 * 2.0 * PI_32;
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let (sample_point, sample_point_err) = surface_point(
+            Point::new(cos_phi * radius, sin_phi * radius, (uv.1 - 0.5) * length),
+            &inv_space,
+        );
+        let normal = Normal::new(cos_phi, sin_phi, 0.0) * inv_space;
+        let dir = cosine_emission_dir(normal, dir_uv);
+
+        let area_scale = area_scale(
+            &inv_space,
+            Vector::new(-sin_phi, cos_phi, 0.0),
+            Vector::new(0.0, 0.0, 1.0),
+        );
+        let flux = col.to_spectral_sample(wavelength) * (PI_32 * area_scale);
+
+        (flux, (sample_point, normal, sample_point_err), dir)
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn approximate_energy(&self) -> f32 {
+        self.colors
+            .iter()
+            .fold(0.0, |a, &b| a + b.approximate_energy())
+            / self.colors.len() as f32
+    }
+}
+
+impl<'a> Surface for TubeLight<'a> {
+    fn stats(&self) -> SurfaceStats {
+        SurfaceStats {
+            primitive_type: "tube light",
+            primitive_count: 1,
+            bytes: std::mem::size_of::<Self>()
+                + std::mem::size_of_val(self.radii)
+                + std::mem::size_of_val(self.lengths)
+                + std::mem::size_of_val(self.colors)
+                + std::mem::size_of_val(self.bounds_),
+            bvh: None,
+        }
+    }
+
+    fn intersect_closest(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Closest);
+    }
+
+    fn intersect_any(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Any);
+    }
+
+    fn shade_hit(&self, ctx: ShadeContext) -> SurfaceIntersection {
+        let ShadeContext {
+            hit,
+            rays,
+            ray_idx,
+            space,
+            object_id,
+            instance_id,
+            instance_index,
+            ..
+        } = ctx;
+        let t = hit.t;
+        let time = rays.time(ray_idx);
+        let xform = lerp_slice(space, time);
+        let inv_xform = xform.inverse();
+        let radius = lerp_slice(self.radii, time);
+        let length = lerp_slice(self.lengths, time);
+        let dir = rays.dir(ray_idx) * xform;
+
+        // The hit's local position is re-projected onto the surface of the
+        // tube.
+        let (x, y, z) = hit.coords;
+        let normal_local = Normal::new(x, y, 0.0).normalized();
+        let hit_local = Point::new(normal_local.x() * radius, normal_local.y() * radius, z);
+        let (pos, pos_err) = surface_point(hit_local, &inv_xform);
+
+        let normal = normal_local * inv_xform;
+        let (dpdu, dpdv) = arbitrary_tangents(normal);
+
+        let intersection_data = SurfaceIntersectionData {
+            incoming: rays.dir(ray_idx),
+            t: t,
+            pos: pos,
+            pos_err: pos_err,
+            nor: normal,
+            nor_g: normal,
+            uv: (0.0, 0.0),
+            dpdu: dpdu,
+            dpdv: dpdv,
+            local_space: xform,
+            footprint: rays.spread(ray_idx) * t,
+            object_id: object_id,
+            instance_id: instance_id,
+            instance_index: instance_index,
+            sample_pdf: self.sample_pdf(&xform, rays.orig(ray_idx), hit_local, radius, length),
+        };
+
+        let closure = {
+            // Only the outside of the tube emits.
+            let scale = if dot(dir, normal_local.into_vector()) < 0.0 {
+                radiance_scale(radius, length)
+            } else {
+                0.0
+            };
+            let color = lerp_slice(self.colors, time) * scale;
+            SurfaceClosure::Emit(color)
+        };
+
+        SurfaceIntersection::Hit {
+            intersection_data: intersection_data,
+            closure: closure,
+        }
+    }
+}
+
+impl<'a> TubeLight<'a> {
+    fn intersect(&self, ctx: IntersectContext, query: HitQuery) {
+        let IntersectContext {
+            rays,
+            ray_stack,
+            hits,
+            shader,
+            space,
+            source,
+            ..
+        } = ctx;
+
+        let _ = shader; // Silence 'unused' warning
+
+        ray_stack.pop_do_next_task(|ray_idx| {
+            let time = rays.time(ray_idx);
+
+            // Get the transform space
+            let xform = lerp_slice(space, time);
+
+            // Get the dimensions of the tube at the ray's time
+            let radius = lerp_slice(self.radii, time);
+            let length = lerp_slice(self.lengths, time);
+
+            // Get the ray origin and direction in local space
+            let orig = rays.orig_local(ray_idx);
+            let dir = rays.dir(ray_idx) * xform;
+            let max_t = rays.max_t(ray_idx);
+
+            // Intersect with the infinite cylinder, ignoring z.  Same
+            // stable quadratic formulation as the sphere light, and also
+            // done in double precision.
+            let (ox, oy) = (orig.x() as f64, orig.y() as f64);
+            let (dx, dy) = (dir.x() as f64, dir.y() as f64);
+            let a = (dx * dx) + (dy * dy);
+            if a == 0.0 {
+                // Ray is parallel to the tube's axis.
+                return;
+            }
+            let b = 2.0 * ((dx * ox) + (dy * oy));
+            let c = (ox * ox) + (oy * oy) - (radius as f64 * radius as f64);
+
+            let discriminant = (b * b) - (4.0 * a * c);
+            if discriminant < 0.0 {
+                return;
+            }
+            let discriminant = discriminant.sqrt();
+            let q = if b < 0.0 {
+                -0.5 * (b - discriminant)
+            } else {
+                -0.5 * (b + discriminant)
+            };
+            let mut t0 = (q / a) as f32;
+            let mut t1 = if q != 0.0 { (c / q) as f32 } else { max_t };
+            if t0 > t1 {
+                use std::mem::swap;
+                swap(&mut t0, &mut t1);
+            }
+
+            // Take the nearest hit that's within the ray's extents and the
+            // tube's length.
+            let half_length = length * 0.5;
+            let t = if t0 > 0.0 && t0 <= max_t && (orig.z() + (dir.z() * t0)).abs() <= half_length {
+                t0
+            } else if t1 > 0.0 && t1 <= max_t && (orig.z() + (dir.z() * t1)).abs() <= half_length {
+                t1
+            } else {
+                return;
+            };
+
+            // We hit the tube, so record the hit, with its local position.
+            let t_pos = orig + (dir * t);
+            hits[ray_idx] = Some(SurfaceHit::new(
+                t,
+                0,
+                (t_pos.x(), t_pos.y(), t_pos.z()),
+                source,
+            ));
+            if query == HitQuery::Any {
+                rays.mark_done(ray_idx);
+            } else {
+                rays.set_max_t(ray_idx, t);
+            }
+        });
+    }
+}
+
+impl<'a> Boundable for TubeLight<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.bounds_
+    }
+}
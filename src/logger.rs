@@ -0,0 +1,603 @@
+//! A small logging facade for reporting what the renderer is up to.
+//!
+//! Everything worth reporting goes through a `Logger` as an `Event`, which
+//! passes it on to any number of sinks.  Printing to the console is just
+//! one such sink, so other front ends can observe the same events without
+//! scraping stdout.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    sync::Mutex,
+};
+
+use rustc_serialize::json::{Json, ToJson};
+
+use crate::{renderer::RenderStats, timer::Timer};
+
+/// How much the console output should say.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,   // Only warnings
+    Normal,  // Progress and timings
+    Verbose, // Everything
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Event<'a> {
+    /// General information about what's happening.
+    Info(&'a str),
+
+    /// Extra detail that's only interesting when digging into things.
+    Detail(&'a str),
+
+    /// Something that is probably a mistake, but doesn't stop rendering.
+    Warning(&'a str),
+
+    /// Something went wrong, e.g. a scene failed to parse or its image
+    /// couldn't be written.
+    Error(&'a str),
+
+    /// The scene file has been read and parsed into a data tree.
+    FileParsed { seconds: f32 },
+
+    /// A scene has been built and is ready to render.
+    SceneBuilt { name: Option<&'a str>, seconds: f32 },
+
+    /// Rendering of a scene is about to start.
+    RenderStarted {
+        total_pixels: usize,
+        spp: usize,
+        thread_count: u32,
+    },
+
+    /// A bucket has finished rendering.  `rays` is the number of rays
+    /// traced for the bucket.
+    BucketDone {
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+        rays: u64,
+    },
+
+    /// Rendering of a scene has finished.
+    RenderDone { seconds: f32, stats: RenderStats },
+
+    /// A rendered image has been written to disk.
+    ImageWritten { path: &'a str, seconds: f32 },
+
+    /// A snapshot of an in-progress render has been written to disk.
+    CheckpointWritten { path: &'a str, seconds: f32 },
+
+    /// All scenes have been processed.
+    Finished {
+        rendered_scenes: usize,
+        failed_scenes: usize,
+    },
+}
+
+/// Something that wants to be told about events.
+pub trait LogSink: Send + Sync {
+    fn log(&self, event: &Event);
+}
+
+/// Dispatches events to a set of sinks.
+pub struct Logger {
+    sinks: Vec<Box<dyn LogSink>>,
+}
+
+impl Logger {
+    /// Creates a logger with no sinks, which discards all events.
+    pub fn new() -> Logger {
+        Logger { sinks: Vec::new() }
+    }
+
+    pub fn add_sink<S: LogSink + 'static>(&mut self, sink: S) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    pub fn log(&self, event: &Event) {
+        for sink in &self.sinks {
+            sink.log(event);
+        }
+    }
+
+    pub fn info(&self, message: &str) {
+        self.log(&Event::Info(message));
+    }
+
+    pub fn detail(&self, message: &str) {
+        self.log(&Event::Detail(message));
+    }
+
+    pub fn warning(&self, message: &str) {
+        self.log(&Event::Warning(message));
+    }
+
+    pub fn error(&self, message: &str) {
+        self.log(&Event::Error(message));
+    }
+}
+
+/// Prints events to stdout, drawing a progress bar while rendering.
+pub struct ConsoleLog {
+    verbosity: Verbosity,
+    progress: Mutex<Progress>,
+}
+
+struct Progress {
+    timer: Timer,
+    last_draw: f32,
+    total_pixels: usize,
+    pixels_done: usize,
+    spp: usize,
+    rays: u64,
+}
+
+/// Minimum time in seconds between progress bar redraws.
+const PROGRESS_REDRAW_INTERVAL: f32 = 0.1;
+
+/// Width of the progress bar in characters, not counting the brackets.
+const PROGRESS_BAR_WIDTH: usize = 30;
+
+impl ConsoleLog {
+    pub fn new(verbosity: Verbosity) -> ConsoleLog {
+        ConsoleLog {
+            verbosity: verbosity,
+            progress: Mutex::new(Progress {
+                timer: Timer::new(),
+                last_draw: 0.0,
+                total_pixels: 0,
+                pixels_done: 0,
+                spp: 0,
+                rays: 0,
+            }),
+        }
+    }
+}
+
+impl LogSink for ConsoleLog {
+    fn log(&self, event: &Event) {
+        match *event {
+            Event::Info(message) => {
+                if self.verbosity >= Verbosity::Normal {
+                    println!("{}", message);
+                }
+            }
+
+            Event::Detail(message) => {
+                if self.verbosity >= Verbosity::Verbose {
+                    println!("{}", message);
+                }
+            }
+
+            Event::Warning(message) => {
+                println!("Warning: {}", message);
+            }
+
+            Event::Error(message) => {
+                println!("{}", message);
+            }
+
+            Event::FileParsed { seconds } => {
+                if self.verbosity >= Verbosity::Normal {
+                    println!("\tParsed scene file in {:.3}s", seconds);
+                }
+            }
+
+            Event::SceneBuilt { seconds, .. } => {
+                if self.verbosity >= Verbosity::Normal {
+                    println!("\tBuilt scene in {:.3}s", seconds);
+                }
+            }
+
+            Event::RenderStarted {
+                total_pixels,
+                spp,
+                thread_count,
+            } => {
+                if self.verbosity >= Verbosity::Normal {
+                    println!("Rendering scene with {} threads...", thread_count);
+                    let mut progress = self.progress.lock().unwrap();
+                    progress.timer.tick();
+                    progress.last_draw = 0.0;
+                    progress.total_pixels = total_pixels;
+                    progress.pixels_done = 0;
+                    progress.spp = spp;
+                    progress.rays = 0;
+                    print!("\r{}", progress.line(0.0));
+                    let _ = io::stdout().flush();
+                }
+            }
+
+            Event::BucketDone { w, h, rays, .. } => {
+                if self.verbosity >= Verbosity::Normal {
+                    let mut progress = self.progress.lock().unwrap();
+                    progress.pixels_done += w as usize * h as usize;
+                    progress.rays += rays;
+
+                    let elapsed = progress.timer.elapsed();
+                    let finished = progress.pixels_done >= progress.total_pixels;
+                    if finished || (elapsed - progress.last_draw) >= PROGRESS_REDRAW_INTERVAL {
+                        progress.last_draw = elapsed;
+                        print!("\r{}", progress.line(elapsed));
+                        let _ = io::stdout().flush();
+                    }
+                }
+            }
+
+            Event::RenderDone { seconds, stats } => {
+                if self.verbosity >= Verbosity::Normal {
+                    // Clear the progress bar.
+                    print!("\r{:1$}\r", "", PROGRESS_BAR_WIDTH + 60);
+
+                    let ntime = seconds as f64 / stats.total_time;
+                    println!("\tRendered scene in {:.3}s", seconds);
+                    println!("\t\tRays traced:  {}", stats.ray_count);
+                    println!(
+                        "\t\tRays/sec:     {}",
+                        (stats.ray_count as f64 / (ntime * stats.trace_time)) as u64
+                    );
+                }
+                if self.verbosity >= Verbosity::Verbose {
+                    let ntime = seconds as f64 / stats.total_time;
+                    println!(
+                        "\t\tTrace:                  {:.3}s",
+                        ntime * stats.trace_time
+                    );
+                    println!("\t\t\tRay/node tests:       {}", stats.accel_node_visits);
+                    if stats.diced_micropolys > 0 {
+                        println!("\t\t\tDiced micropolygons:  {}", stats.diced_micropolys);
+                    }
+                    println!(
+                        "\t\tInitial ray generation: {:.3}s",
+                        ntime * stats.initial_ray_generation_time
+                    );
+                    println!(
+                        "\t\tRay generation:         {:.3}s",
+                        ntime * stats.ray_generation_time
+                    );
+                    println!(
+                        "\t\tSample writing:         {:.3}s",
+                        ntime * stats.sample_writing_time
+                    );
+                    println!(
+                        "\t\tBucket merging:         {:.3}s",
+                        ntime * stats.merge_time
+                    );
+                    println!(
+                        "\t\tThread idle:            {:.3}s total, {:.3}s at most",
+                        stats.idle_time, stats.max_idle_time
+                    );
+                    if stats.path_lengths.iter().any(|&n| n > 0) {
+                        println!("\t\tPaths by bounces:");
+                        let bins = stats.path_lengths.len();
+                        for (i, (&count, &time)) in stats
+                            .path_lengths
+                            .iter()
+                            .zip(stats.bounce_times.iter())
+                            .enumerate()
+                        {
+                            if count > 0 || time > 0.0 {
+                                println!(
+                                    "\t\t\t{:>3}{} {:>12} ended, {:.3}s spent",
+                                    i,
+                                    if i == bins - 1 { "+" } else { " " },
+                                    count,
+                                    ntime * time
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+
+            Event::ImageWritten { seconds, .. } => {
+                if self.verbosity >= Verbosity::Normal {
+                    println!("\tWrote image in {:.3}s", seconds);
+                }
+            }
+
+            // Printing these would break up the progress bar.
+            Event::CheckpointWritten { .. } => {}
+
+            Event::Finished { .. } => {}
+        }
+    }
+}
+
+/// Prints only warnings and errors, to stderr, for when stdout is taken
+/// by something else (e.g. the serialized output that Blender reads).
+pub struct StderrLog;
+
+impl LogSink for StderrLog {
+    fn log(&self, event: &Event) {
+        match *event {
+            Event::Warning(message) => eprintln!("Warning: {}", message),
+            Event::Error(message) => eprintln!("{}", message),
+            _ => {}
+        }
+    }
+}
+
+/// Writes events as JSON lines, one object per event, for consumption by
+/// other programs (e.g. render farm wrappers).
+///
+/// Every object has an `event` field naming the event and a `time` field
+/// with the seconds elapsed since the sink was created.  Each line is
+/// flushed as soon as it's written.
+pub struct JsonLog {
+    out: Mutex<Box<dyn Write + Send>>,
+    timer: Timer,
+}
+
+impl JsonLog {
+    pub fn new(out: Box<dyn Write + Send>) -> JsonLog {
+        JsonLog {
+            out: Mutex::new(out),
+            timer: Timer::new(),
+        }
+    }
+}
+
+impl LogSink for JsonLog {
+    fn log(&self, event: &Event) {
+        let line = json_line(event, self.timer.elapsed());
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}", line);
+        let _ = out.flush();
+    }
+}
+
+/// Builds the JSON object for an event.
+fn json_line(event: &Event, time: f32) -> String {
+    let (name, line) = match *event {
+        Event::Info(message) => ("info", JsonObject::new().field("message", message)),
+        Event::Detail(message) => ("detail", JsonObject::new().field("message", message)),
+        Event::Warning(message) => ("warning", JsonObject::new().field("message", message)),
+        Event::Error(message) => ("error", JsonObject::new().field("message", message)),
+
+        Event::FileParsed { seconds } => {
+            ("file_parsed", JsonObject::new().field("seconds", &seconds))
+        }
+
+        Event::SceneBuilt { name, seconds } => (
+            "scene_built",
+            JsonObject::new()
+                .field("name", &name.map(str::to_string))
+                .field("seconds", &seconds),
+        ),
+
+        Event::RenderStarted {
+            total_pixels,
+            spp,
+            thread_count,
+        } => (
+            "render_started",
+            JsonObject::new()
+                .field("total_pixels", &total_pixels)
+                .field("spp", &spp)
+                .field("threads", &thread_count),
+        ),
+
+        Event::BucketDone { x, y, w, h, rays } => (
+            "bucket_done",
+            JsonObject::new()
+                .field("x", &x)
+                .field("y", &y)
+                .field("w", &w)
+                .field("h", &h)
+                .field("rays", &rays),
+        ),
+
+        Event::RenderDone { seconds, stats } => (
+            "render_done",
+            JsonObject::new()
+                .field("seconds", &seconds)
+                .field("spp", &stats.spp)
+                .field("rays", &stats.ray_count)
+                .field("accel_node_visits", &stats.accel_node_visits)
+                .field("diced_micropolys", &stats.diced_micropolys)
+                .field("trace_time", &stats.trace_time)
+                .field(
+                    "initial_ray_generation_time",
+                    &stats.initial_ray_generation_time,
+                )
+                .field("ray_generation_time", &stats.ray_generation_time)
+                .field("sample_writing_time", &stats.sample_writing_time)
+                .field("merge_time", &stats.merge_time)
+                .field("nonfinite_samples", &stats.nonfinite_samples)
+                .field("thread_time", &stats.total_time)
+                .field("idle_time", &stats.idle_time)
+                .field("max_idle_time", &stats.max_idle_time),
+        ),
+
+        Event::ImageWritten { path, seconds } => (
+            "image_written",
+            JsonObject::new()
+                .field("path", path)
+                .field("seconds", &seconds),
+        ),
+
+        Event::CheckpointWritten { path, seconds } => (
+            "checkpoint_written",
+            JsonObject::new()
+                .field("path", path)
+                .field("seconds", &seconds),
+        ),
+
+        Event::Finished {
+            rendered_scenes,
+            failed_scenes,
+        } => (
+            "finished",
+            JsonObject::new()
+                .field("rendered_scenes", &rendered_scenes)
+                .field("failed_scenes", &failed_scenes),
+        ),
+    };
+
+    JsonObject::new()
+        .field("event", name)
+        .field("time", &time)
+        .append(line)
+        .finish()
+}
+
+/// A builder for flat JSON objects.
+struct JsonObject {
+    fields: BTreeMap<String, Json>,
+}
+
+impl JsonObject {
+    fn new() -> JsonObject {
+        JsonObject {
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Non-finite floats aren't representable in JSON, and are written as
+    /// null.
+    fn field<T: ToJson + ?Sized>(mut self, key: &str, value: &T) -> JsonObject {
+        self.fields.insert(key.to_string(), value.to_json());
+        self
+    }
+
+    /// Adds all of the fields of another object.
+    fn append(mut self, other: JsonObject) -> JsonObject {
+        self.fields.extend(other.fields);
+        self
+    }
+
+    fn finish(self) -> String {
+        Json::Object(self.fields).to_string()
+    }
+}
+
+impl Progress {
+    /// Builds the progress bar line for the current state.
+    fn line(&self, elapsed: f32) -> String {
+        let fraction = if self.total_pixels > 0 {
+            (self.pixels_done as f64 / self.total_pixels as f64).min(1.0)
+        } else {
+            0.0
+        };
+        let filled = (fraction * PROGRESS_BAR_WIDTH as f64) as usize;
+
+        let mut line = format!(
+            "[{}{}] {:6.2}%",
+            "=".repeat(filled),
+            " ".repeat(PROGRESS_BAR_WIDTH - filled),
+            fraction * 100.0
+        );
+
+        if elapsed > 0.0 && self.pixels_done > 0 {
+            let samples = (self.pixels_done * self.spp) as f64;
+            let remaining = elapsed as f64 * (1.0 - fraction) / fraction;
+            line.push_str(&format!(
+                "  {} samples/s  {} rays/s  ETA {}",
+                format_rate(samples / elapsed as f64),
+                format_rate(self.rays as f64 / elapsed as f64),
+                format_duration(remaining)
+            ));
+        }
+
+        // Pad so that a shorter line fully overwrites a longer one.
+        format!("{:1$}", line, PROGRESS_BAR_WIDTH + 60)
+    }
+}
+
+/// Formats a per-second rate compactly, e.g. "12.3M".
+fn format_rate(rate: f64) -> String {
+    if rate >= 1.0e9 {
+        format!("{:.1}G", rate / 1.0e9)
+    } else if rate >= 1.0e6 {
+        format!("{:.1}M", rate / 1.0e6)
+    } else if rate >= 1.0e3 {
+        format!("{:.1}K", rate / 1.0e3)
+    } else {
+        format!("{:.0}", rate)
+    }
+}
+
+/// Formats a duration in seconds as "M:SS" or "H:MM:SS".
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    let (h, m, s) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+    if h > 0 {
+        format!("{}:{:02}:{:02}", h, m, s)
+    } else {
+        format!("{}:{:02}", m, s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_rate_1() {
+        assert_eq!(format_rate(0.0), "0");
+        assert_eq!(format_rate(999.0), "999");
+        assert_eq!(format_rate(12_345.0), "12.3K");
+        assert_eq!(format_rate(4_560_000.0), "4.6M");
+        assert_eq!(format_rate(2.0e9), "2.0G");
+    }
+
+    #[test]
+    fn format_duration_1() {
+        assert_eq!(format_duration(0.0), "0:00");
+        assert_eq!(format_duration(65.4), "1:05");
+        assert_eq!(format_duration(3723.0), "1:02:03");
+        assert_eq!(format_duration(-3.0), "0:00");
+    }
+
+    #[test]
+    fn progress_line_width() {
+        let progress = Progress {
+            timer: Timer::new(),
+            last_draw: 0.0,
+            total_pixels: 100,
+            pixels_done: 50,
+            spp: 16,
+            rays: 10_000,
+        };
+        let line = progress.line(2.0);
+        assert!(line.starts_with("[===============               ]  50.00%"));
+        assert!(line.contains("ETA 0:02"));
+        assert_eq!(progress.line(0.0).len(), PROGRESS_BAR_WIDTH + 60);
+    }
+
+    #[test]
+    fn json_line_1() {
+        let event = Event::BucketDone {
+            x: 16,
+            y: 32,
+            w: 8,
+            h: 4,
+            rays: 1000,
+        };
+        assert_eq!(
+            json_line(&event, 1.5),
+            r#"{"event":"bucket_done","h":4,"rays":1000,"time":1.5,"w":8,"x":16,"y":32}"#
+        );
+    }
+
+    #[test]
+    fn json_line_escapes() {
+        let event = Event::Warning("a \"b\"\\c\n\u{1}");
+        assert_eq!(
+            json_line(&event, 0.0),
+            r#"{"event":"warning","message":"a \"b\"\\c\n\u0001","time":0.0}"#
+        );
+        let event = Event::SceneBuilt {
+            name: None,
+            seconds: f32::INFINITY,
+        };
+        assert_eq!(
+            json_line(&event, 0.0),
+            r#"{"event":"scene_built","name":null,"seconds":null,"time":0.0}"#
+        );
+    }
+}
@@ -21,26 +21,42 @@ mod bbox4;
 mod boundable;
 mod camera;
 mod color;
+mod cost_map;
+mod cpu;
 mod fp_utils;
+#[cfg(test)]
+mod golden;
 mod hash;
 mod hilbert;
 mod image;
+mod irradiance_cache;
 mod lerp;
 mod light;
+mod logger;
 mod math;
 mod mis;
+mod output;
 mod parse;
+mod photon_map;
+mod probes;
 mod ray;
+mod reference;
+mod render_settings;
 mod renderer;
+mod restir;
 mod sampling;
 mod scene;
+mod sensor;
 mod shading;
+mod sppm;
 mod surface;
 mod timer;
+mod trace_engine;
 mod tracer;
 mod transform_stack;
+mod work_queue;
 
-use std::{fs::File, io, io::Read, mem, path::Path, str::FromStr};
+use std::{collections::HashSet, fs::File, io, io::Read, mem, path::Path, str::FromStr};
 
 use clap::{App, Arg};
 use nom::bytes::complete::take_until;
@@ -50,10 +66,15 @@ use kioku::Arena;
 use crate::{
     accel::BVH4Node,
     bbox::BBox,
-    parse::{parse_scene, DataTree},
+    boundable::Boundable,
+    logger::{ConsoleLog, Event, JsonLog, Logger, StderrLog, Verbosity},
+    output::{resolve_output_path, write_image, Checkpointer},
+    parse::{find_unknown_nodes, parse_scene, parse_scene_name, schema_json, DataTree},
+    render_settings::{MaterialOverride, RenderSettings, Sanitize},
     renderer::LightPath,
     surface::SurfaceIntersection,
     timer::Timer,
+    trace_engine::TraceEngine,
 };
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -73,7 +94,26 @@ fn main() {
                 .value_name("FILE")
                 .help("Input .psy file")
                 .takes_value(true)
-                .required_unless_one(&["dev", "use_stdin"]),
+                .required_unless_one(&["dev", "use_stdin", "schema"]),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("TEMPLATE")
+                .help(
+                    "Output image path, overriding the scene file.  '%s' is replaced \
+                     with the scene name, '%d' with the scene's index in the file, \
+                     and '%0Nd' with the index zero-padded to N digits.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scene")
+                .long("scene")
+                .value_name("NAME")
+                .help("Only render the scene with the given name")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("spp")
@@ -88,6 +128,90 @@ fn main() {
                         .or(Err("must be an integer".to_string()))
                 }),
         )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .value_name("KEY=VALUE")
+                .help(
+                    "Override a render setting from the scene file, e.g. 'max_bounces=4', \
+                     'filter=gaussian:2' or 'mis=power:3'.  May be given multiple times, \
+                     with later values taking precedence.  Dedicated flags such as --spp \
+                     take precedence over all --set values.  Available keys: resolution, \
+                     overscan, pixel_aspect, spp, seed, max_bounces, roulette, filter, mis, \
+                     light_splits, bounce_splits, irradiance_cache, restir, ic_accuracy, \
+                     ic_samples, integrator, photons, photon_radius, bucket_order, \
+                     split_buckets, auto_buckets, check_numerics, numerics_color, \
+                     dicing_rate, material_override, sanitize, proxy_rays, accumulation, \
+                     sensor_response, white_balance, importance_mask, progressive.  The \
+                     splits set how many light and bounce samples to take where each camera \
+                     ray hits, for faster direct lighting.  'irradiance_cache=on' \
+                     interpolates diffuse indirect light from a sparse cache, which is fast \
+                     but biased.  'restir=on' picks the light sampled at each camera ray \
+                     hit out of many candidates, shared between neighboring pixels, for \
+                     scenes with many lights.  'integrator=sppm' renders with progressive \
+                     photon mapping instead of path tracing, for caustics, running spp \
+                     iterations of the given number of photons.  'integrator=reference' \
+                     renders with a slow, brute force path tracer for checking the others \
+                     against.  'roulette=N' ends paths by Russian roulette after N bounces, \
+                     so that paths that lose little light (e.g. between mirrors) don't run \
+                     all the way to max_bounces.  'split_buckets=off' stops the last \
+                     buckets of each pass from being split up for threads that would \
+                     otherwise wait.  They're never split with restir, which shares light \
+                     samples between the pixels of a bucket.  'auto_buckets=on' starts with \
+                     large buckets, and splits the buckets of the slow parts of the image \
+                     in each pass by the times measured in the ones before it.  Without a \
+                     time limit or progressive rendering, the first sample per pixel is \
+                     then rendered as a pass of its own to measure.  It's ignored with \
+                     restir, for the same reason as splitting.  'dicing_rate' is the target \
+                     micropolygon size in pixels for surfaces that are diced, such as \
+                     bilinear patches.  'proxy_rays' lists the kinds of rays (camera, \
+                     specular, diffuse, shadow) that see instances' Proxy geometry in place \
+                     of the full object, 'diffuse,shadow' by default, or 'none'.  \
+                     'accumulation=f64' or 'accumulation=kahan' sums samples more precisely \
+                     than the default 'f32', for very high sample count reference renders.  \
+                     'sensor_response' is how the camera turns spectra into colors: \
+                     'cie1931' (the default) for the standard observer, 'cmos' for a \
+                     generic camera sensor, or the path of a CSV of wavelength and red, \
+                     green and blue sensitivities.  'white_balance' is the light that comes \
+                     out neutral: an illuminant (e, d50, d55, d65, d75, a) or a temperature \
+                     in kelvin with an optional tint, e.g. '3200' or '5600,0.002'.  It \
+                     defaults to 'e', which leaves colors as they are.  'importance_mask' \
+                     is the path of a grayscale pfm image, stretched over the frame, of how \
+                     many samples each pixel takes: white is the full spp and darker areas \
+                     take proportionally fewer.  It's only used by the path tracer.",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s| RenderSettings::default().apply_override_str(&s)),
+        )
+        .arg(
+            Arg::with_name("override_material")
+                .long("override-material")
+                .value_name("MATERIAL")
+                .help(
+                    "Replace every surface shader in the scene, for checking lighting \
+                     and geometry.  'clay' is a neutral gray diffuse.  'ao' is a white \
+                     diffuse lit only by a uniform white background, with the scene's \
+                     lights and bounces beyond the first left out.  'normal' shows \
+                     shading normals as colors.",
+                )
+                .takes_value(true)
+                .possible_values(&["clay", "ao", "normal"]),
+        )
+        .arg(
+            Arg::with_name("sanitize")
+                .long("sanitize")
+                .value_name("LEVEL")
+                .help(
+                    "How much to check geometry as the scene is built.  'warn', the \
+                     default, fails scenes with NaN or infinite vertices, and warns about \
+                     degenerate triangles and inconsistent winding.  'fix' also removes \
+                     degenerate triangles.  'off' does no checks.",
+                )
+                .takes_value(true)
+                .possible_values(&["off", "warn", "fix"]),
+        )
         .arg(
             Arg::with_name("max_bucket_samples")
                 .short("b")
@@ -117,6 +241,84 @@ fn main() {
                         .or(Err("must be four integers".to_string()))
                 }),
         )
+        .arg(Arg::with_name("progressive").long("progressive").help(
+            "Render in passes that double the samples per pixel (1, 2, 4, 8...), \
+             writing the image after each pass next to the output file with its \
+             spp added to the name, e.g. 'beauty_016spp.png', so convergence can \
+             be compared and the render stopped once it's good enough.  Only the \
+             path tracing integrator renders progressively.",
+        ))
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .value_name("SECONDS")
+                .help(
+                    "Periodically write the in-progress image to the output file, at most \
+                     SECONDS apart.",
+                )
+                .takes_value(true)
+                .validator(|s| {
+                    f32::from_str(&s)
+                        .ok()
+                        .filter(|n| *n > 0.0)
+                        .and(Some(()))
+                        .ok_or("must be a positive number".to_string())
+                }),
+        )
+        .arg(
+            Arg::with_name("checkpoint_buckets")
+                .long("checkpoint-buckets")
+                .value_name("N")
+                .help(
+                    "Periodically write the in-progress image to the output file, every N buckets.",
+                )
+                .takes_value(true)
+                .validator(|s| {
+                    usize::from_str(&s)
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .and(Some(()))
+                        .ok_or("must be a positive integer".to_string())
+                }),
+        )
+        .arg(
+            Arg::with_name("max_time")
+                .long("max-time")
+                .visible_alias("time-limit")
+                .value_name("SECONDS")
+                .help(
+                    "Stop rendering each scene after about SECONDS, writing the image with \
+                     however many samples per pixel it reached.  The samples are taken in \
+                     progressive passes over the whole image, starting with one sample per \
+                     pixel, and a pass is only started if it's expected to finish in time.  \
+                     So a preview always shows the whole frame, never half-finished buckets.",
+                )
+                .takes_value(true)
+                .validator(|s| {
+                    f32::from_str(&s)
+                        .ok()
+                        .filter(|n| *n > 0.0)
+                        .and(Some(()))
+                        .ok_or("must be a positive number".to_string())
+                }),
+        )
+        .arg(
+            Arg::with_name("max_memory")
+                .long("max-memory")
+                .value_name("MIB")
+                .help(
+                    "Skip scenes that are estimated to need more than MIB mebibytes of \
+                     memory to render, after building them.",
+                )
+                .takes_value(true)
+                .validator(|s| {
+                    usize::from_str(&s)
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .and(Some(()))
+                        .ok_or("must be a positive integer".to_string())
+                }),
+        )
         .arg(
             Arg::with_name("threads")
                 .short("t")
@@ -133,10 +335,127 @@ fn main() {
                         .or(Err("must be an integer".to_string()))
                 }),
         )
+        .arg(
+            Arg::with_name("check_numerics")
+                .long("check-numerics")
+                .help(
+                    "Check every sample for NaN and infinite values.  Bad samples are \
+                     reported along with their pixel and path, and their pixels are set \
+                     to a debug color, which defaults to magenta and can be changed with \
+                     '--set numerics_color=R,G,B'.  Only the path tracing integrator \
+                     checks its samples.",
+                ),
+        )
+        .arg(Arg::with_name("strict").long("strict").help(
+            "Fail scenes that contain nodes of unknown types or geometry problems, \
+             instead of skipping those nodes or rendering anyway with a warning.",
+        ))
+        .arg(Arg::with_name("schema").long("schema").help(
+            "Print the scene file node types, their allowed counts, and the form \
+             of their contents that this version accepts, as JSON.",
+        ))
+        .arg(Arg::with_name("info").long("info").help(
+            "Print a summary of each scene instead of rendering it: its camera, \
+             assemblies, objects and their instance counts, lights, and an \
+             estimate of its memory use.",
+        ))
+        .arg(
+            Arg::with_name("trace_pixel")
+                .long("trace-pixel")
+                .value_name("X Y S")
+                .help(
+                    "Instead of rendering, trace sample S of pixel (X, Y) with the path \
+                     tracer and print every event along its path: hits, light samples, \
+                     bounces, their pdfs, and the throughput and color so far.",
+                )
+                .takes_value(true)
+                .number_of_values(3)
+                .validator(|s| {
+                    u32::from_str(&s)
+                        .and(Ok(()))
+                        .or(Err("must be three integers".to_string()))
+                })
+                .conflicts_with("info"),
+        )
+        .arg(
+            Arg::with_name("trace_rays")
+                .long("trace-rays")
+                .value_name("FILE")
+                .help(
+                    "Instead of rendering, trace the rays in FILE against the scene and \
+                     print what each hits, one line per ray.  Rays are given one per \
+                     line as 'ox oy oz dx dy dz [time]', in world space.  Hits are \
+                     printed as 'hit object_id instance_id t u v nx ny nz', and misses \
+                     as 'miss'.  Use '-' to read the rays from stdin.",
+                )
+                .takes_value(true)
+                .conflicts_with_all(&["info", "trace_pixel"]),
+        )
+        .arg(
+            Arg::with_name("bake_probes")
+                .long("bake-probes")
+                .value_name("FILE")
+                .help(
+                    "Instead of rendering, bake light probes at the positions in FILE, \
+                     given one per line as 'x y z' in world space.  Or with \
+                     'spacing:DIST', place them evenly through the scene's bounds, at \
+                     least DIST apart, as a Poisson disk set.  The light arriving \
+                     at each is path traced with the scene's spp and projected onto \
+                     spherical harmonics up to band 2.  The probes are written as JSON \
+                     to the output path, with its extension changed to '.json'.",
+                )
+                .takes_value(true)
+                .conflicts_with_all(&["info", "trace_pixel", "trace_rays"]),
+        )
         .arg(
             Arg::with_name("stats")
                 .long("stats")
-                .help("Print additional statistics about rendering"),
+                .help("Print additional statistics about rendering, including BVH quality"),
+        )
+        .arg(
+            Arg::with_name("bvh_leaf_size")
+                .long("bvh_leaf_size")
+                .value_name("N")
+                .help(
+                    "Number of objects to put in each BVH leaf, overriding the default for \
+                     each kind of geometry.  For diagnosing slow traversal, along with \
+                     --stats.",
+                )
+                .takes_value(true)
+                .validator(|s| match usize::from_str(&s) {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err("must be a positive integer".to_string()),
+                }),
+        )
+        .arg(Arg::with_name("baseline_simd").long("baseline-simd").help(
+            "Don't use the copies of the hottest kernels that are compiled for newer \
+             SIMD instruction sets (e.g. AVX2), even if the CPU supports them.  For \
+             comparing performance, or ruling them out when debugging.",
+        ))
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Only print warnings and errors")
+                .conflicts_with("verbose"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Print extra details about parsing and rendering"),
+        )
+        .arg(
+            Arg::with_name("log_json")
+                .long("log-json")
+                .value_name("FILE")
+                .help(
+                    "Write render events (parsing, building, finished buckets, warnings, \
+                     errors, and final stats) to FILE as JSON lines.  If FILE is '-' they \
+                     are written to standard output instead of the normal console output.",
+                )
+                .takes_value(true)
+                .conflicts_with("serialized_output"),
         )
         .arg(
             Arg::with_name("dev")
@@ -170,6 +489,11 @@ fn main() {
         return;
     }
 
+    if args.is_present("schema") {
+        println!("{}", schema_json());
+        return;
+    }
+
     let crop = args.values_of("crop").map(|mut vals| {
         let coords = (
             u32::from_str(vals.next().unwrap()).unwrap(),
@@ -186,10 +510,52 @@ fn main() {
         coords
     });
 
-    // Parse data tree of scene file
-    if !args.is_present("serialized_output") {
-        println!("Parsing scene file...",);
+    // Set up logging.  Serialized output and JSON logging to stdout both
+    // need stdout to themselves, so the console output is moved to stderr
+    // or disabled in those cases.
+    let mut log = Logger::new();
+    let json_to_stdout = args.value_of("log_json") == Some("-");
+    if args.is_present("serialized_output") {
+        log.add_sink(StderrLog);
+    } else if !json_to_stdout {
+        let verbosity = if args.is_present("quiet") {
+            Verbosity::Quiet
+        } else if args.is_present("verbose") {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        };
+        log.add_sink(ConsoleLog::new(verbosity));
+    }
+    if let Some(path) = args.value_of("log_json") {
+        if json_to_stdout {
+            log.add_sink(JsonLog::new(Box::new(io::stdout())));
+        } else {
+            match File::create(path) {
+                Ok(f) => log.add_sink(JsonLog::new(Box::new(f))),
+                Err(e) => {
+                    println!("Unable to create JSON log file '{}': {}", path, e);
+                    std::process::exit(1);
+                }
+            }
+        }
     }
+
+    if let Some(n) = args.value_of("bvh_leaf_size") {
+        accel::override_objects_per_leaf(Some(usize::from_str(n).unwrap()));
+    }
+
+    if args.is_present("baseline_simd") {
+        cpu::use_baseline_simd();
+    }
+    log.detail(&format!(
+        "SIMD instruction set: {} (detected {})",
+        cpu::simd_level().name(),
+        cpu::detect().name()
+    ));
+
+    // Parse data tree of scene file
+    log.info("Parsing scene file...");
     t.tick();
     let psy_contents = if args.is_present("use_stdin") {
         // Read from stdin
@@ -233,30 +599,181 @@ fn main() {
     };
 
     let dt = DataTree::from_str(&psy_contents).unwrap();
-    if !args.is_present("serialized_output") {
-        println!("\tParsed scene file in {:.3}s", t.tick());
-    }
+    log.log(&Event::FileParsed { seconds: t.tick() });
 
     // Iterate through scenes and render them
+    let mut scene_index = 0;
+    let mut scene_found = false;
+    let mut rendered_scenes = 0;
+    let mut failed_scenes = 0;
+    let mut written_paths = HashSet::new();
+    let mut parse_warnings = Vec::new();
     if let DataTree::Internal { ref children, .. } = dt {
         for child in children {
             t.tick();
             if child.type_name() == "Scene" {
-                if !args.is_present("serialized_output") {
-                    println!("Building scene...");
+                let index = scene_index;
+                scene_index += 1;
+
+                let scene_name = match parse_scene_name(child) {
+                    Ok(name) => name,
+                    Err(e) => {
+                        log.error(&e.message(&psy_contents));
+                        failed_scenes += 1;
+                        continue;
+                    }
+                };
+
+                // Skip scenes that weren't asked for.
+                if let Some(wanted) = args.value_of("scene") {
+                    if scene_name.as_deref() != Some(wanted) {
+                        continue;
+                    }
+                }
+                scene_found = true;
+
+                if let Some(ref name) = scene_name {
+                    log.info(&format!("Building scene '{}'...", name));
+                } else {
+                    log.info("Building scene...");
                 }
 
+                // Unknown nodes are skipped by the parser, e.g. ones from
+                // newer exporters, unless they're asked to be errors.
+                let mut unknown_nodes = find_unknown_nodes(child);
+                if args.is_present("strict") && !unknown_nodes.is_empty() {
+                    let e = unknown_nodes.remove(0).into_error();
+                    log.error(&e.message(&psy_contents));
+                    log.error("\tSkipping scene due to parse error.");
+                    failed_scenes += 1;
+                    continue;
+                }
+                parse_warnings.extend(
+                    unknown_nodes
+                        .iter()
+                        .map(|warning| warning.message(&psy_contents)),
+                );
+
                 let arena = Arena::new().with_block_size((1 << 20) * 4);
-                let mut r = parse_scene(&arena, child).unwrap_or_else(|e| {
-                    e.print(&psy_contents);
-                    panic!("Parse error.");
-                });
+                // Apply setting overrides, in order of increasing precedence.
+                let override_settings = |settings: &mut RenderSettings| {
+                    if let Some(overrides) = args.values_of("set") {
+                        for key_value in overrides {
+                            log.info(&format!("\tOverriding scene setting: {}", key_value));
+                            settings.apply_override_str(key_value).unwrap();
+                        }
+                    }
+                    if let Some(spp) = args.value_of("spp") {
+                        log.info(&format!("\tOverriding scene spp: {}", spp));
+                        settings.spp = usize::from_str(spp).unwrap();
+                    }
+                    if args.is_present("check_numerics") {
+                        settings.check_numerics = true;
+                    }
+                    if let Some(max_time) = args.value_of("max_time") {
+                        settings.max_time = Some(f32::from_str(max_time).unwrap());
+                    }
+                    if args.is_present("progressive") {
+                        settings.progressive = true;
+                    }
+                    if let Some(material) = args.value_of("override_material") {
+                        log.info(&format!("\tOverriding materials: {}", material));
+                        settings.material_override = MaterialOverride::from_spec(material).unwrap();
+                    }
+                    if let Some(level) = args.value_of("sanitize") {
+                        settings.sanitize = Sanitize::from_spec(level).unwrap();
+                    }
+                };
+                let mut geometry_warnings = Vec::new();
+                let mut r =
+                    match parse_scene(&arena, child, override_settings, &mut geometry_warnings) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            log.error(&e.message(&psy_contents));
+                            log.error("\tSkipping scene due to parse error.");
+                            failed_scenes += 1;
+                            continue;
+                        }
+                    };
+                if args.is_present("strict") && !geometry_warnings.is_empty() {
+                    let e = geometry_warnings.remove(0).into_error();
+                    log.error(&e.message(&psy_contents));
+                    log.error("\tSkipping scene due to parse error.");
+                    failed_scenes += 1;
+                    continue;
+                }
+                parse_warnings.extend(
+                    geometry_warnings
+                        .iter()
+                        .map(|warning| warning.message(&psy_contents)),
+                );
+
+                r.output_file = resolve_output_path(
+                    args.value_of("output"),
+                    &r.output_file,
+                    scene_name.as_deref(),
+                    index,
+                );
 
-                if let Some(spp) = args.value_of("spp") {
-                    if !args.is_present("serialized_output") {
-                        println!("\tOverriding scene spp: {}", spp);
+                if args.is_present("info") {
+                    println!(
+                        "Scene {}",
+                        scene_name.as_deref().unwrap_or(&format!("#{}", index))
+                    );
+                    println!("Output: {}", r.output_file);
+                    println!(
+                        "Render settings: {}x{}, {} spp, max bounces: {}",
+                        r.settings.resolution.0,
+                        r.settings.resolution.1,
+                        r.settings.spp,
+                        r.settings.max_bounces,
+                    );
+                    print!("{}", r.scene.info());
+                    continue;
+                }
+
+                if let Some(path) = args.value_of("trace_rays") {
+                    let mut engine = TraceEngine::new(&r.scene.root);
+                    let stdout = io::stdout();
+                    let result = if path == "-" {
+                        let stdin = io::stdin();
+                        trace_engine::serve(&mut engine, stdin.lock(), stdout.lock())
+                    } else {
+                        File::open(path).and_then(|f| {
+                            trace_engine::serve(&mut engine, io::BufReader::new(f), stdout.lock())
+                        })
+                    };
+                    if let Err(e) = result {
+                        log.error(&format!("Couldn't trace rays from '{}': {}", path, e));
+                        failed_scenes += 1;
                     }
-                    r.spp = usize::from_str(spp).unwrap();
+                    continue;
+                }
+
+                if let Some(mut vals) = args.values_of("trace_pixel") {
+                    let x = u32::from_str(vals.next().unwrap()).unwrap();
+                    let y = u32::from_str(vals.next().unwrap()).unwrap();
+                    let si = u32::from_str(vals.next().unwrap()).unwrap();
+                    let (width, height) = r.settings.image_size();
+                    if x >= width as u32 || y >= height as u32 {
+                        log.error(&format!(
+                            "Pixel ({}, {}) is outside of the {}x{} image.",
+                            x, y, width, height
+                        ));
+                        failed_scenes += 1;
+                        continue;
+                    }
+
+                    let (trace, col) = r.trace_pixel(x, y, si);
+                    for line in &trace {
+                        println!("{}", line);
+                    }
+                    let rgb = color::xyz_to_rec709_e(col.to_tuple());
+                    println!(
+                        "Sample color (rec709, exposed): {} {} {}",
+                        rgb.0, rgb.1, rgb.2
+                    );
+                    continue;
                 }
 
                 let max_samples_per_bucket =
@@ -272,65 +789,162 @@ fn main() {
                     num_cpus::get() as u32
                 };
 
-                if !args.is_present("serialized_output") {
-                    println!("\tBuilt scene in {:.3}s", t.tick());
+                if let Some(path) = args.value_of("bake_probes") {
+                    let positions = if path.starts_with("spacing:") {
+                        let bounds = r
+                            .scene
+                            .root
+                            .bounds()
+                            .iter()
+                            .fold(BBox::new(), |bounds, &b| bounds | b);
+                        f32::from_str(&path["spacing:".len()..])
+                            .map_err(|_| format!("invalid probe spacing '{}'", path))
+                            .and_then(|spacing| probes::spaced_positions(bounds, spacing))
+                    } else {
+                        File::open(path)
+                            .and_then(|f| probes::read_positions(io::BufReader::new(f)))
+                            .map_err(|e| format!("couldn't read probes from '{}': {}", path, e))
+                    };
+                    let positions = match positions {
+                        Ok(positions) => positions,
+                        Err(e) => {
+                            log.error(&format!("Couldn't place probes: {}", e));
+                            failed_scenes += 1;
+                            continue;
+                        }
+                    };
+                    log.info(&format!(
+                        "Baking {} probes at {} spp...",
+                        positions.len(),
+                        r.settings.spp
+                    ));
+                    let baked = r.bake_probes(&positions, thread_count);
+                    let out_path = Path::new(&r.output_file).with_extension("json");
+                    if let Err(e) = File::create(&out_path)
+                        .and_then(|f| probes::write_json(io::BufWriter::new(f), &baked))
+                    {
+                        log.error(&format!(
+                            "Couldn't write probes to '{}': {}",
+                            out_path.display(),
+                            e
+                        ));
+                        failed_scenes += 1;
+                        continue;
+                    }
+                    log.info(&format!("Wrote probes to '{}'.", out_path.display()));
+                    continue;
                 }
 
-                if !args.is_present("serialized_output") {
-                    println!("Rendering scene with {} threads...", thread_count);
+                if let Some(max_memory) = args.value_of("max_memory") {
+                    let max_bytes = usize::from_str(max_memory).unwrap() << 20;
+                    let estimate = r.memory_estimate(max_samples_per_bucket, thread_count);
+                    if estimate > max_bytes {
+                        log.error(&format!(
+                            "Scene needs an estimated {:.1} MiB of memory to render, more than \
+                             the --max-memory limit of {} MiB.",
+                            estimate as f64 / 1_048_576.0,
+                            max_memory
+                        ));
+                        log.error("\tSkipping scene.");
+                        failed_scenes += 1;
+                        continue;
+                    }
                 }
+
+                log.log(&Event::SceneBuilt {
+                    name: scene_name.as_deref(),
+                    seconds: t.tick(),
+                });
+                log.detail(&format!(
+                    "\tResolution: {}x{}, {} spp, max bounces: {}, seed: {}, filter: {:?}, \
+                     mis: {:?}, splits: {}/{}",
+                    r.settings.resolution.0,
+                    r.settings.resolution.1,
+                    r.settings.spp,
+                    r.settings.max_bounces,
+                    r.settings.seed,
+                    r.settings.filter,
+                    r.settings.mis,
+                    r.settings.light_splits,
+                    r.settings.bounce_splits,
+                ));
+
+                let checkpoint_seconds = args
+                    .value_of("checkpoint")
+                    .map(|s| f32::from_str(s).unwrap());
+                let checkpoint_buckets = args
+                    .value_of("checkpoint_buckets")
+                    .map(|s| usize::from_str(s).unwrap());
+                let checkpointer = if (checkpoint_seconds.is_some()
+                    || checkpoint_buckets.is_some()
+                    || r.settings.progressive)
+                    && !args.is_present("serialized_output")
+                {
+                    Some(Checkpointer::new(
+                        &r.output_file,
+                        r.output_encoding,
+                        checkpoint_seconds,
+                        checkpoint_buckets,
+                    ))
+                } else {
+                    None
+                };
+
                 let (mut image, rstats) = r.render(
                     max_samples_per_bucket,
                     crop,
                     thread_count,
                     args.is_present("serialized_output"),
+                    checkpointer.as_ref(),
+                    &log,
                 );
-                // Print render stats
-                if !args.is_present("serialized_output") {
-                    let rtime = t.tick();
-                    let ntime = rtime as f64 / rstats.total_time;
-                    println!("\tRendered scene in {:.3}s", rtime);
-                    println!(
-                        "\t\tTrace:                  {:.3}s",
-                        ntime * rstats.trace_time
-                    );
-                    println!("\t\t\tRays traced:          {}", rstats.ray_count);
-                    println!(
-                        "\t\t\tRays/sec:             {}",
-                        (rstats.ray_count as f64 / (ntime * rstats.trace_time) as f64) as u64
-                    );
-                    println!("\t\t\tRay/node tests:       {}", rstats.accel_node_visits);
-                    println!(
-                        "\t\tInitial ray generation: {:.3}s",
-                        ntime * rstats.initial_ray_generation_time
-                    );
-                    println!(
-                        "\t\tRay generation:         {:.3}s",
-                        ntime * rstats.ray_generation_time
-                    );
-                    println!(
-                        "\t\tSample writing:         {:.3}s",
-                        ntime * rstats.sample_writing_time
-                    );
+                log.log(&Event::RenderDone {
+                    seconds: t.tick(),
+                    stats: rstats,
+                });
+                if rstats.spp < r.settings.spp {
+                    log.warning(&format!(
+                        "time limit reached, rendered {} of {} spp.",
+                        rstats.spp, r.settings.spp
+                    ));
+                }
+                if rstats.nonfinite_samples > 0 {
+                    log.warning(&format!(
+                        "{} samples were NaN or infinite.",
+                        rstats.nonfinite_samples
+                    ));
                 }
 
                 // Write to disk
                 if !args.is_present("serialized_output") {
-                    println!("Writing image to disk into '{}'...", r.output_file);
-                    if r.output_file.ends_with(".png") {
-                        image
-                            .write_png(Path::new(&r.output_file))
-                            .expect("Failed to write png...");
-                    } else if r.output_file.ends_with(".exr") {
-                        image.write_exr(Path::new(&r.output_file));
-                    } else {
-                        panic!("Unknown output file extension.");
+                    if !written_paths.insert(r.output_file.clone()) {
+                        log.warning(&format!(
+                            "overwriting '{}', which was already written by an earlier scene.",
+                            r.output_file
+                        ));
+                    }
+                    log.info(&format!(
+                        "Writing image to disk into '{}'...",
+                        r.output_file
+                    ));
+                    match write_image(&mut image, &r.output_file, r.output_encoding) {
+                        Ok(()) => log.log(&Event::ImageWritten {
+                            path: &r.output_file,
+                            seconds: t.tick(),
+                        }),
+                        Err(e) => {
+                            log.error(&format!("\t{}", e));
+                            failed_scenes += 1;
+                            continue;
+                        }
                     }
-                    println!("\tWrote image in {:.3}s", t.tick());
                 }
+                rendered_scenes += 1;
 
-                // Print memory stats if stats are wanted.
+                // Print memory and BVH stats if stats are wanted.
                 if args.is_present("stats") {
+                    print!("{}", r.scene.bvh_info());
+
                     // let arena_stats = arena.stats();
                     // let mib_occupied = arena_stats.0 as f64 / 1_048_576.0;
                     // let mib_allocated = arena_stats.1 as f64 / 1_048_576.0;
@@ -355,6 +969,37 @@ fn main() {
         }
     }
 
+    if let Some(wanted) = args.value_of("scene") {
+        if !scene_found {
+            log.error(&format!(
+                "No scene named '{}' found in the scene file.",
+                wanted
+            ));
+            log.log(&Event::Finished {
+                rendered_scenes: rendered_scenes,
+                failed_scenes: failed_scenes + 1,
+            });
+            std::process::exit(1);
+        }
+    }
+
+    // Gathered up here so they don't get lost among the render output.
+    for warning in &parse_warnings {
+        log.warning(warning);
+    }
+
+    log.log(&Event::Finished {
+        rendered_scenes: rendered_scenes,
+        failed_scenes: failed_scenes,
+    });
+
     // End with blank line
-    println!();
+    if !json_to_stdout {
+        println!();
+    }
+
+    if failed_scenes > 0 {
+        log.error(&format!("{} scene(s) failed.", failed_scenes));
+        std::process::exit(1);
+    }
 }
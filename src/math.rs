@@ -2,6 +2,8 @@
 
 use std::f32;
 
+use glam::Vec4;
+
 pub use math3d::{cross, dot, CrossProduct, DotProduct, Matrix4x4, Normal, Point, Vector};
 
 /// Clamps a value between a min and max.
@@ -33,6 +35,18 @@ pub fn fast_maxf32(a: f32, b: f32) -> f32 {
     }
 }
 
+/// The largest of a `Vec4`'s elements.
+///
+/// Use this instead of `Vec4::max_element()`, which the version of glam
+/// we're on gets wrong in its scalar implementation (it takes the min of z
+/// and w).  That's what glam uses on platforms without SSE2, or with its
+/// `scalar-math` feature.  `min_element()` is correct in both, and still
+/// uses SIMD where available.
+#[inline(always)]
+pub fn max_element(v: Vec4) -> f32 {
+    -(-v).min_element()
+}
+
 /// Rounds an integer up to the next power of two.
 pub fn upper_power_of_two(mut v: u32) -> u32 {
     v -= 1;
@@ -190,6 +204,15 @@ pub fn faster_exp(p: f32) -> f32 {
 mod tests {
     use super::*;
 
+    #[test]
+    fn max_element_test() {
+        assert_eq!(max_element(Vec4::new(4.0, 1.0, 2.0, 3.0)), 4.0);
+        assert_eq!(max_element(Vec4::new(1.0, 4.0, 2.0, 3.0)), 4.0);
+        assert_eq!(max_element(Vec4::new(1.0, 2.0, 4.0, 3.0)), 4.0);
+        assert_eq!(max_element(Vec4::new(1.0, 2.0, 3.0, 4.0)), 4.0);
+        assert_eq!(max_element(Vec4::new(-1.0, -2.0, -3.0, -4.0)), -1.0);
+    }
+
     #[test]
     fn log2_64_test() {
         assert_eq!(0, log2_64(0));
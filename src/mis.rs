@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+use std::str::FromStr;
+
 pub fn balance_heuristic(a: f32, b: f32) -> f32 {
     if a.is_infinite() {
         a
@@ -19,3 +21,131 @@ pub fn power_heuristic(a: f32, b: f32) -> f32 {
         a / mis_fac
     }
 }
+
+/// Power heuristic with an arbitrary exponent.
+pub fn power_heuristic_exp(a: f32, b: f32, exponent: f32) -> f32 {
+    if a.is_infinite() {
+        a
+    } else {
+        // Normalized to the larger pdf, to avoid overflow with large
+        // exponents.
+        let max = a.max(b);
+        let ae = (a / max).powf(exponent);
+        let be = (b / max).powf(exponent);
+        let mis_fac = ae / (ae + be);
+        a / mis_fac
+    }
+}
+
+/// The heuristic used to weight samples from different strategies with
+/// multiple importance sampling.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MisHeuristic {
+    Balance,
+    Power(f32), // Exponent
+}
+
+impl Default for MisHeuristic {
+    fn default() -> MisHeuristic {
+        MisHeuristic::Power(2.0)
+    }
+}
+
+impl MisHeuristic {
+    /// Like the heuristic functions above, returns the pdf `a` divided by
+    /// the MIS weight of its strategy when competing with a strategy of
+    /// pdf `b`.  Dividing a sample by this instead of by `a` both applies
+    /// the pdf and the weight.
+    #[inline]
+    pub fn mis_pdf(&self, a: f32, b: f32) -> f32 {
+        match *self {
+            MisHeuristic::Balance => balance_heuristic(a, b),
+            MisHeuristic::Power(e) => {
+                if e == 2.0 {
+                    power_heuristic(a, b)
+                } else {
+                    power_heuristic_exp(a, b, e)
+                }
+            }
+        }
+    }
+
+    /// Parses a heuristic specification of the form `name` or
+    /// `name:exponent`, e.g. `balance` or `power:3`.
+    pub fn from_spec(spec: &str) -> Result<MisHeuristic, String> {
+        let mut parts = spec.splitn(2, ':');
+        let name = parts.next().unwrap().trim();
+        let exponent = if let Some(e) = parts.next() {
+            let e = f32::from_str(e.trim())
+                .map_err(|_| format!("invalid heuristic exponent '{}'", e.trim()))?;
+            if e <= 0.0 || e.is_nan() {
+                return Err("heuristic exponent must be positive".to_string());
+            }
+            Some(e)
+        } else {
+            None
+        };
+
+        match (name, exponent) {
+            ("balance", None) => Ok(MisHeuristic::Balance),
+            ("balance", Some(_)) => Err("the balance heuristic has no exponent".to_string()),
+            ("power", e) => Ok(MisHeuristic::Power(e.unwrap_or(2.0))),
+            _ => Err(format!(
+                "unknown MIS heuristic '{}', expected 'balance' or 'power'",
+                name
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_sum_to_one() {
+        let heuristics = [
+            MisHeuristic::Balance,
+            MisHeuristic::Power(2.0),
+            MisHeuristic::Power(3.5),
+        ];
+        for h in &heuristics {
+            for &(a, b) in &[(1.0, 1.0), (0.5, 4.0), (100.0, 0.01), (3.0, 0.001)] {
+                let wa = a / h.mis_pdf(a, b);
+                let wb = b / h.mis_pdf(b, a);
+                assert!((wa + wb - 1.0).abs() < 0.0001, "{:?} {} {}", h, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn power_exp_matches_power() {
+        for &(a, b) in &[(1.0, 2.0), (0.25, 7.0), (9.0, 0.5)] {
+            let p = power_heuristic(a, b);
+            let pe = power_heuristic_exp(a, b, 2.0);
+            assert!((p - pe).abs() <= p * 0.0001);
+            let p = balance_heuristic(a, b);
+            let pe = power_heuristic_exp(a, b, 1.0);
+            assert!((p - pe).abs() <= p * 0.0001);
+        }
+    }
+
+    #[test]
+    fn from_spec() {
+        assert_eq!(
+            MisHeuristic::from_spec("balance"),
+            Ok(MisHeuristic::Balance)
+        );
+        assert_eq!(
+            MisHeuristic::from_spec("power"),
+            Ok(MisHeuristic::Power(2.0))
+        );
+        assert_eq!(
+            MisHeuristic::from_spec("power:3"),
+            Ok(MisHeuristic::Power(3.0))
+        );
+        assert!(MisHeuristic::from_spec("power:0").is_err());
+        assert!(MisHeuristic::from_spec("balance:2").is_err());
+        assert!(MisHeuristic::from_spec("maximum").is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! Resolution of per-scene output file paths, and writing of images to
+//! them.
+
+use std::{fs, path::Path, sync::Mutex};
+
+use crate::{
+    image::{Image, ImageEncoding, ImageFormat},
+    timer::Timer,
+};
+
+/// Default template used when neither the command line nor the scene
+/// file specify an output path.
+pub const DEFAULT_OUTPUT_TEMPLATE: &str = "%s.png";
+
+/// Expands an output path template for a particular scene.
+///
+/// Supported substitutions:
+///
+/// - `%s`: the scene name.
+/// - `%d`: the scene's index within the file (zero-based).
+/// - `%0Nd`: the scene index, zero padded to N digits (e.g. `%04d`).
+/// - `%%`: a literal `%`.
+///
+/// Anything else following a `%` is passed through untouched.
+pub fn expand_output_template(template: &str, scene_name: &str, scene_index: usize) -> String {
+    let mut out = String::with_capacity(template.len() + scene_name.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.peek().cloned() {
+            Some('s') => {
+                chars.next();
+                out.push_str(scene_name);
+            }
+            Some('d') => {
+                chars.next();
+                out.push_str(&format!("{}", scene_index));
+            }
+            Some('%') => {
+                chars.next();
+                out.push('%');
+            }
+            Some('0') => {
+                // Zero-padded index.  Only consumed if it's well formed,
+                // otherwise passed through verbatim.
+                let mut lookahead = chars.clone();
+                lookahead.next();
+                let mut width = String::new();
+                while let Some(&d) = lookahead.peek() {
+                    if d.is_ascii_digit() {
+                        width.push(d);
+                        lookahead.next();
+                    } else {
+                        break;
+                    }
+                }
+                if lookahead.peek() == Some(&'d') && !width.is_empty() {
+                    lookahead.next();
+                    chars = lookahead;
+                    let width: usize = width.parse().unwrap();
+                    out.push_str(&format!("{:0width$}", scene_index, width = width));
+                } else {
+                    out.push('%');
+                }
+            }
+            _ => {
+                out.push('%');
+            }
+        }
+    }
+
+    out
+}
+
+/// Picks and expands the output path for a scene.
+///
+/// The command line template takes precedence over the scene file's
+/// output path, and an empty scene file path falls back to
+/// `DEFAULT_OUTPUT_TEMPLATE`.
+pub fn resolve_output_path(
+    cli_template: Option<&str>,
+    scene_path: &str,
+    scene_name: Option<&str>,
+    scene_index: usize,
+) -> String {
+    let template = if let Some(template) = cli_template {
+        template
+    } else if !scene_path.is_empty() {
+        scene_path
+    } else {
+        DEFAULT_OUTPUT_TEMPLATE
+    };
+
+    let fallback_name = format!("scene_{}", scene_index);
+    let name = scene_name.unwrap_or(&fallback_name);
+
+    expand_output_template(template, name, scene_index)
+}
+
+/// The path that a progressive render's image after `spp` samples per
+/// pixel is written to: `path` with the spp, zero padded to the width of
+/// `total_spp`, added before the extension.  For example, 'beauty.png'
+/// after 4 of 256 spp is 'beauty_004spp.png'.
+pub fn pass_output_path(path: &str, spp: usize, total_spp: usize) -> String {
+    let suffix = format!("_{:0width$}spp", spp, width = total_spp.to_string().len());
+    let name_start = path.rfind(|c| c == '/' || c == '\\').map_or(0, |i| i + 1);
+    match path[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => {
+            let (stem, extension) = path.split_at(name_start + dot);
+            format!("{}{}{}", stem, suffix, extension)
+        }
+        _ => format!("{}{}", path, suffix),
+    }
+}
+
+/// Writes an image to `path`, picking the format from its extension.
+/// `encoding` is used for the integer formats.
+///
+/// The image is first written to a temporary file next to `path` and then
+/// renamed into place, so an existing file at `path` is never left
+/// partially written.
+pub fn write_image(image: &mut Image, path: &str, encoding: ImageEncoding) -> Result<(), String> {
+    let tmp_path = format!("{}.tmp", path);
+
+    let format = ImageFormat::from_path(path).ok_or_else(|| {
+        format!(
+            "Unknown output file extension, skipping write of '{}'.",
+            path
+        )
+    })?;
+    image
+        .write(Path::new(&tmp_path), format, encoding)
+        .map_err(|e| format!("Failed to write {:?} image: {}", format, e))?;
+
+    fs::rename(&tmp_path, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp_path);
+        format!("Failed to move '{}' into place: {}", tmp_path, e)
+    })
+}
+
+/// Periodically writes the in-progress image of a render to disk, so that
+/// a crash or kill doesn't lose more than the most recent interval.
+pub struct Checkpointer {
+    path: String,
+    encoding: ImageEncoding,
+    seconds: Option<f32>,
+    buckets: Option<usize>,
+    state: Mutex<CheckpointState>,
+    writing: Mutex<()>,
+}
+
+struct CheckpointState {
+    timer: Timer,
+    buckets: usize,
+}
+
+impl Checkpointer {
+    /// Creates a checkpointer that writes to `path` every `seconds` and/or
+    /// every `buckets` finished buckets, whichever comes first.
+    pub fn new(
+        path: &str,
+        encoding: ImageEncoding,
+        seconds: Option<f32>,
+        buckets: Option<usize>,
+    ) -> Checkpointer {
+        Checkpointer {
+            path: path.to_string(),
+            encoding: encoding,
+            seconds: seconds,
+            buckets: buckets,
+            state: Mutex::new(CheckpointState {
+                timer: Timer::new(),
+                buckets: 0,
+            }),
+            writing: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Records a finished bucket, and returns whether a checkpoint is due.
+    /// If it is, the interval starts over.
+    pub fn bucket_done(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.buckets += 1;
+
+        let due_by_time = match self.seconds {
+            Some(seconds) => state.timer.elapsed() >= seconds,
+            None => false,
+        };
+        let due_by_buckets = match self.buckets {
+            Some(buckets) => state.buckets >= buckets,
+            None => false,
+        };
+
+        if due_by_time || due_by_buckets {
+            state.timer.tick();
+            state.buckets = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Writes a snapshot of `image` to the checkpoint path.
+    pub fn write(&self, image: &Image) -> Result<(), String> {
+        let _guard = self.writing.lock().unwrap();
+        write_image(&mut image.snapshot(), &self.path, self.encoding)
+    }
+
+    /// Writes `image`, the render after `spp` of `total_spp` samples per
+    /// pixel, to its own file as given by `pass_output_path()`.  Returns
+    /// the path written.
+    pub fn write_pass(
+        &self,
+        image: &mut Image,
+        spp: usize,
+        total_spp: usize,
+    ) -> Result<String, String> {
+        let path = pass_output_path(&self.path, spp, total_spp);
+        write_image(image, &path, self.encoding)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_plain() {
+        assert_eq!(expand_output_template("out.png", "a", 3), "out.png");
+    }
+
+    #[test]
+    fn expand_name_and_index() {
+        assert_eq!(expand_output_template("%s_%d.exr", "shot", 7), "shot_7.exr");
+        assert_eq!(
+            expand_output_template("%s_%04d.png", "shot", 12),
+            "shot_0012.png"
+        );
+    }
+
+    #[test]
+    fn expand_escapes_and_malformed() {
+        assert_eq!(expand_output_template("100%%.png", "a", 0), "100%.png");
+        assert_eq!(expand_output_template("%x%0d%04", "a", 0), "%x%0d%04");
+        assert_eq!(expand_output_template("end%", "a", 0), "end%");
+    }
+
+    #[test]
+    fn resolve_precedence() {
+        assert_eq!(
+            resolve_output_path(Some("%s.exr"), "scene.png", Some("a"), 0),
+            "a.exr"
+        );
+        assert_eq!(
+            resolve_output_path(None, "scene_%d.png", Some("a"), 2),
+            "scene_2.png"
+        );
+        assert_eq!(resolve_output_path(None, "", Some("a"), 0), "a.png");
+        assert_eq!(resolve_output_path(None, "", None, 4), "scene_4.png");
+    }
+
+    #[test]
+    fn checkpoint_by_buckets() {
+        let checkpointer = Checkpointer::new("out.png", ImageEncoding::default(), None, Some(3));
+        let due: Vec<_> = (0..7).map(|_| checkpointer.bucket_done()).collect();
+        assert_eq!(due, [false, false, true, false, false, true, false]);
+
+        let never = Checkpointer::new("out.png", ImageEncoding::default(), None, None);
+        assert!(!(0..10).any(|_| never.bucket_done()));
+    }
+
+    #[test]
+    fn pass_paths() {
+        assert_eq!(pass_output_path("beauty.png", 4, 256), "beauty_004spp.png");
+        assert_eq!(
+            pass_output_path("out/shot.v2/f_0001.exr", 16, 16),
+            "out/shot.v2/f_0001_16spp.exr"
+        );
+        assert_eq!(pass_output_path("render", 1, 8), "render_1spp");
+        assert_eq!(
+            pass_output_path("renders.d/.hidden", 2, 8),
+            "renders.d/.hidden_2spp"
+        );
+    }
+}
@@ -4,8 +4,10 @@
 use std::str::{self, FromStr};
 
 use nom::{
+    branch::alt,
+    bytes::complete::tag,
     character::complete::{digit1, multispace0, one_of},
-    combinator::{map_res, opt, recognize},
+    combinator::{map_res, opt, recognize, value},
     number::complete::float,
     sequence::{delimited, tuple},
     IResult,
@@ -36,6 +38,14 @@ pub fn ws_i32(input: &str) -> IResult<&str, i32, ()> {
     )(input)
 }
 
+pub fn ws_bool(input: &str) -> IResult<&str, bool, ()> {
+    delimited(
+        multispace0,
+        alt((value(true, tag("true")), value(false, tag("false")))),
+        multispace0,
+    )(input)
+}
+
 // ========================================================
 
 #[cfg(test)]
@@ -113,4 +123,12 @@ mod test {
         assert_eq!(all_consuming(ws_f32)("0abc").is_err(), true);
         assert_eq!(tuple((ws_f32, ws_f32))("0.abc 1.2").is_err(), true);
     }
+
+    #[test]
+    fn ws_bool_1() {
+        assert_eq!(ws_bool("true"), Ok((&""[..], true)));
+        assert_eq!(ws_bool("   false"), Ok((&""[..], false)));
+        assert_eq!(ws_bool("  true   false"), Ok((&"false"[..], true)));
+        assert_eq!(ws_bool("1").is_err(), true);
+    }
 }
@@ -2,8 +2,15 @@ pub mod basics;
 mod data_tree;
 mod psy;
 mod psy_assembly;
+mod psy_bilinear_patch;
+mod psy_curve_surface;
 mod psy_light;
 mod psy_mesh_surface;
+mod psy_schema;
 mod psy_surface_shader;
 
-pub use self::{data_tree::DataTree, psy::parse_scene};
+pub use self::{
+    data_tree::DataTree,
+    psy::{parse_scene, parse_scene_name},
+    psy_schema::{find_unknown_nodes, schema_json},
+};
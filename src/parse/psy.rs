@@ -7,19 +7,26 @@ use nom::{combinator::all_consuming, sequence::tuple, IResult};
 use kioku::Arena;
 
 use crate::{
-    camera::Camera,
+    bbox::BBox,
+    boundable::Boundable,
+    camera::{Camera, DicingCamera, Exposure, PerspectiveCamera},
     color::{rec709_e_to_xyz, Color},
+    image::{read_pfm, ImageEncoding, Transfer},
     light::WorldLightSource,
     math::Matrix4x4,
+    render_settings::{ImportanceMask, MaterialOverride, RenderSettings},
     renderer::Renderer,
     scene::Scene,
-    scene::World,
+    scene::{Assembly, AssemblyBuilder, Atmosphere, Backplate, World},
+    sensor::{SensorResponse, WhiteBalance},
+    shading::{NormalSurfaceShader, SimpleSurfaceShader, SurfaceShader},
 };
 
 use super::{
     basics::{ws_f32, ws_u32},
     psy_assembly::parse_assembly,
-    psy_light::parse_distant_disk_light,
+    psy_light::{parse_distant_disk_light, parse_environment_light},
+    psy_schema::PsyParseWarning,
     DataTree,
 };
 
@@ -35,67 +42,93 @@ pub enum PsyParseError {
     IncorrectLeafData(usize, &'static str),     // Error message
     WrongNodeCount(usize, &'static str, usize), // Error message, sections found
     InstancedMissingData(usize, &'static str, String), // Error message, data name
+    UnknownNode(usize, String, String),         // Node type, section type
+    BadGeometry(usize, String, String),         // Object name, problem
 }
 
 impl PsyParseError {
-    pub fn print(&self, psy_content: &str) {
+    /// Returns a human-readable description of the error, including the
+    /// line it occured on.
+    pub fn message(&self, psy_content: &str) -> String {
         match *self {
             PsyParseError::UnknownError(offset) => {
                 let line = line_count_to_byte_offset(psy_content, offset);
-                println!(
+                format!(
                     "Line {}: Unknown parse error.  If you get this message, please report \
                      it to the developers so they can improve the error messages.",
                     line
-                );
+                )
             }
 
             PsyParseError::UnknownVariant(offset, error) => {
                 let line = line_count_to_byte_offset(psy_content, offset);
-                println!("Line {}: {}", line, error);
+                format!("Line {}: {}", line, error)
             }
 
             PsyParseError::ExpectedInternalNode(offset, error) => {
                 let line = line_count_to_byte_offset(psy_content, offset);
-                println!("Line {}: {}", line, error);
+                format!("Line {}: {}", line, error)
             }
 
             PsyParseError::ExpectedLeafNode(offset, error) => {
                 let line = line_count_to_byte_offset(psy_content, offset);
-                println!("Line {}: {}", line, error);
+                format!("Line {}: {}", line, error)
             }
 
             PsyParseError::MissingNode(offset, error) => {
                 let line = line_count_to_byte_offset(psy_content, offset);
-                println!("Line {}: {}", line, error);
+                format!("Line {}: {}", line, error)
             }
 
             PsyParseError::IncorrectLeafData(offset, error) => {
                 let line = line_count_to_byte_offset(psy_content, offset);
-                println!("Line {}: {}", line, error);
+                format!("Line {}: {}", line, error)
             }
 
             PsyParseError::WrongNodeCount(offset, error, count) => {
                 let line = line_count_to_byte_offset(psy_content, offset);
-                println!("Line {}: {}  Found: {}", line, error, count);
+                format!("Line {}: {}  Found: {}", line, error, count)
             }
 
             PsyParseError::InstancedMissingData(offset, error, ref data_name) => {
                 let line = line_count_to_byte_offset(psy_content, offset);
-                println!("Line {}: {} Data name: '{}'", line, error, data_name);
+                format!("Line {}: {} Data name: '{}'", line, error, data_name)
+            }
+
+            PsyParseError::UnknownNode(offset, ref type_name, ref section) => {
+                let line = line_count_to_byte_offset(psy_content, offset);
+                format!("Line {}: Unknown {} in {}.", line, type_name, section)
+            }
+
+            PsyParseError::BadGeometry(offset, ref name, ref problem) => {
+                let line = line_count_to_byte_offset(psy_content, offset);
+                format!("Line {}: Bad geometry in {}: {}.", line, name, problem)
             }
         }
     }
 }
 
-fn line_count_to_byte_offset(text: &str, offset: usize) -> usize {
+pub fn line_count_to_byte_offset(text: &str, offset: usize) -> usize {
     text[..offset].matches('\n').count() + 1
 }
 
 /// Takes in a `DataTree` representing a Scene node and returns
-pub fn parse_scene<'a>(
+///
+/// `override_settings` is given the parsed render settings to modify
+/// before the scene's geometry is built, since some of them, such as the
+/// dicing rate, affect how it's built.
+///
+/// Problems in the scene's geometry that it can still be rendered with
+/// are added to `warnings`.
+pub fn parse_scene<'a, F>(
     arena: &'a Arena,
     tree: &'a DataTree,
-) -> Result<Renderer<'a>, PsyParseError> {
+    override_settings: F,
+    warnings: &mut Vec<PsyParseWarning>,
+) -> Result<Renderer<'a>, PsyParseError>
+where
+    F: FnOnce(&mut RenderSettings),
+{
     // Verify we have the right number of each section
     if tree.iter_children_with_type("Output").count() != 1 {
         let count = tree.iter_children_with_type("Output").count();
@@ -146,37 +179,78 @@ pub fn parse_scene<'a>(
     let output_info = parse_output_info(tree.iter_children_with_type("Output").nth(0).unwrap())?;
 
     // Parse render settings
-    let render_settings = parse_render_settings(
+    let mut render_settings = parse_render_settings(
         tree.iter_children_with_type("RenderSettings")
             .nth(0)
             .unwrap(),
     )?;
+    override_settings(&mut render_settings);
+
+    // Parse the scene's units and up axis
+    let conversion = parse_scene_conversion(tree)?;
 
     // Parse camera
-    let camera = parse_camera(
+    let camera: &dyn Camera = arena.alloc(parse_camera(
         arena,
         tree.iter_children_with_type("Camera").nth(0).unwrap(),
-    )?;
+        &conversion,
+    )?);
 
     // Parse world
-    let world = parse_world(arena, tree.iter_children_with_type("World").nth(0).unwrap())?;
+    let mut world = parse_world(
+        arena,
+        tree.iter_children_with_type("World").nth(0).unwrap(),
+        &conversion,
+    )?;
+
+    // Ambient occlusion is the light that reaches each camera ray hit
+    // directly from a uniform white background, and nothing else.
+    let skip_lights = render_settings.material_override == Some(MaterialOverride::AmbientOcclusion);
+    if skip_lights {
+        world = World {
+            background_color: Color::new_xyz(rec709_e_to_xyz((1.0, 1.0, 1.0))),
+            lights: &[],
+            atmosphere: None,
+            backplate: None,
+        };
+        render_settings.max_bounces = 1;
+    }
 
     // Parse root scene assembly
-    let assembly = parse_assembly(
+    let dicing_camera = DicingCamera::new(
+        camera,
+        render_settings.resolution,
+        render_settings.pixel_aspect,
+        render_settings.dicing_rate,
+    );
+    let scene_from_world = conversion.world_from_scene().inverse();
+    let mut assembly = parse_assembly(
         arena,
         tree.iter_children_with_type("Assembly").nth(0).unwrap(),
+        &dicing_camera,
+        &[scene_from_world],
+        None,
+        skip_lights,
+        render_settings.sanitize,
+        warnings,
     )?;
+    if !conversion.is_identity() {
+        assembly = place_root(arena, assembly, scene_from_world);
+    }
+    if let Some(material_override) = render_settings.material_override {
+        assembly = override_materials(arena, assembly, material_override);
+    }
+
+    // The atmosphere fills the scene.
+    if let Some(ref mut atmosphere) = world.atmosphere {
+        atmosphere.bounds = assembly
+            .bounds()
+            .iter()
+            .fold(BBox::new(), |bounds, b| bounds | *b);
+    }
 
     // Put scene together
-    let scene_name = if let DataTree::Internal { ident, .. } = *tree {
-        if let Some(name) = ident {
-            Some(name.to_string())
-        } else {
-            None
-        }
-    } else {
-        None
-    };
+    let scene_name = parse_scene_name(tree)?;
     let scene = Scene {
         name: scene_name,
         camera: camera,
@@ -186,23 +260,177 @@ pub fn parse_scene<'a>(
 
     // Put renderer together
     let renderer = Renderer {
-        output_file: output_info.clone(),
-        resolution: (
-            (render_settings.0).0 as usize,
-            (render_settings.0).1 as usize,
-        ),
-        spp: render_settings.1 as usize,
-        seed: render_settings.2,
+        output_file: output_info.0,
+        output_encoding: output_info.1,
+        settings: render_settings,
         scene: scene,
     };
 
     return Ok(renderer);
 }
 
-fn parse_output_info(tree: &DataTree) -> Result<String, PsyParseError> {
+/// How to convert a scene from the units and up axis it was authored in
+/// to the renderer's, which has +Z up.
+#[derive(Debug, Copy, Clone)]
+struct SceneConversion {
+    units_scale: f32, // Renderer units per scene unit
+    axes: Matrix4x4,  // Rotation from the scene's axes to the renderer's
+}
+
+impl SceneConversion {
+    fn is_identity(&self) -> bool {
+        self.units_scale == 1.0 && self.axes == Matrix4x4::new()
+    }
+
+    /// The transform from the scene's space to the renderer's world space.
+    fn world_from_scene(&self) -> Matrix4x4 {
+        self.axes * uniform_scale(self.units_scale)
+    }
+
+    /// Converts a camera-to-world transform.  Camera space is scaled
+    /// along with the rest of the scene, so that the converted transform
+    /// doesn't scale the camera's rays.
+    fn camera_transform(&self, xform: Matrix4x4) -> Matrix4x4 {
+        uniform_scale(1.0 / self.units_scale) * xform * self.world_from_scene()
+    }
+}
+
+fn uniform_scale(scale: f32) -> Matrix4x4 {
+    Matrix4x4::new_from_values(
+        scale, 0.0, 0.0, 0.0, 0.0, scale, 0.0, 0.0, 0.0, 0.0, scale, 0.0, 0.0, 0.0, 0.0, 1.0,
+    )
+}
+
+/// Parses the scene's `UnitsScale` and `UpAxis`, which default to no
+/// conversion at all.
+fn parse_scene_conversion(tree: &DataTree) -> Result<SceneConversion, PsyParseError> {
+    let units_scale = if let Some((_, contents, byte_offset)) =
+        tree.iter_leaf_children_with_type("UnitsScale").next()
+    {
+        match all_consuming(ws_f32)(contents) {
+            IResult::Ok((_, scale)) if scale > 0.0 && scale.is_finite() => scale,
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "UnitsScale should be a positive number of renderer units per scene \
+                     unit, specified in the form '[scale]'.",
+                ));
+            }
+        }
+    } else {
+        1.0
+    };
+
+    let axes = if let Some((_, contents, byte_offset)) =
+        tree.iter_leaf_children_with_type("UpAxis").next()
+    {
+        match contents.trim() {
+            "Z" => Matrix4x4::new(),
+            // (x, y, z) -> (x, -z, y)
+            "Y" => Matrix4x4::new_from_values(
+                1.0, 0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+            ),
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "UpAxis should be either 'Y' or 'Z'.",
+                ));
+            }
+        }
+    } else {
+        Matrix4x4::new()
+    };
+
+    Ok(SceneConversion {
+        units_scale: units_scale,
+        axes: axes,
+    })
+}
+
+/// Wraps the root assembly in an instance with the given transform.
+fn place_root<'a>(arena: &'a Arena, root: Assembly<'a>, xform: Matrix4x4) -> Assembly<'a> {
+    let mut builder = AssemblyBuilder::new(arena);
+    builder.add_assembly("root", root);
+    builder.add_instance("root", None, None, Some(&[xform]));
+    builder.build()
+}
+
+/// Wraps the root assembly in an instance that overrides the shaders of
+/// all of its surfaces.
+fn override_materials<'a>(
+    arena: &'a Arena,
+    root: Assembly<'a>,
+    material_override: MaterialOverride,
+) -> Assembly<'a> {
+    let shader: &dyn SurfaceShader = match material_override {
+        MaterialOverride::Clay => arena.alloc(SimpleSurfaceShader::Lambert {
+            color: Color::new_xyz(rec709_e_to_xyz((0.5, 0.5, 0.5))),
+        }),
+        MaterialOverride::AmbientOcclusion => arena.alloc(SimpleSurfaceShader::Lambert {
+            color: Color::new_xyz(rec709_e_to_xyz((1.0, 1.0, 1.0))),
+        }),
+        MaterialOverride::Normal => arena.alloc(NormalSurfaceShader),
+    };
+
+    let mut builder = AssemblyBuilder::new(arena);
+    builder.add_surface_shader("override", shader);
+    builder.add_assembly("root", root);
+    builder.add_instance("root", None, Some("override"), None);
+    builder.build()
+}
+
+/// Returns the name of a Scene node.
+///
+/// An explicit `Name` leaf takes precedence over the node's identifier.
+pub fn parse_scene_name(tree: &DataTree) -> Result<Option<String>, PsyParseError> {
+    if let Some(DataTree::Leaf {
+        contents,
+        byte_offset,
+        ..
+    }) = tree.iter_children_with_type("Name").nth(0)
+    {
+        return Ok(Some(
+            parse_quoted_string(contents, *byte_offset)?.to_string(),
+        ));
+    }
+
+    if let DataTree::Internal {
+        ident: Some(name), ..
+    } = *tree
+    {
+        return Ok(Some(name.to_string()));
+    }
+
+    return Ok(None);
+}
+
+/// Strips the surrounding quotes from a quoted string leaf.
+pub fn parse_quoted_string(contents: &str, byte_offset: usize) -> Result<&str, PsyParseError> {
+    // Trim and validate
+    let tc = contents.trim();
+    if tc.chars().count() < 2 {
+        return Err(PsyParseError::IncorrectLeafData(
+            byte_offset,
+            "String format is incorrect.",
+        ));
+    }
+    if tc.chars().nth(0).unwrap() != '"' || !tc.ends_with('"') {
+        return Err(PsyParseError::IncorrectLeafData(
+            byte_offset,
+            "Strings must be surrounded by quotes.",
+        ));
+    }
+    let len = tc.len();
+
+    // TODO: proper string escaping
+    return Ok(&tc[1..len - 1]);
+}
+
+fn parse_output_info(tree: &DataTree) -> Result<(String, ImageEncoding), PsyParseError> {
     if let DataTree::Internal { ref children, .. } = *tree {
         let mut found_path = false;
         let mut path = String::new();
+        let mut encoding = ImageEncoding::default();
 
         for child in children {
             match *child {
@@ -211,29 +439,41 @@ fn parse_output_info(tree: &DataTree) -> Result<String, PsyParseError> {
                     contents,
                     byte_offset,
                 } if type_name == "Path" => {
-                    // Trim and validate
-                    let tc = contents.trim();
-                    if tc.chars().count() < 2 {
+                    found_path = true;
+                    path = parse_quoted_string(contents, byte_offset)?.to_string();
+                }
+
+                // Bits per channel of png and tiff output
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "BitDepth" => match all_consuming(ws_u32)(contents) {
+                    IResult::Ok((_, bits)) if bits == 8 || bits == 16 => {
+                        encoding.bit_depth = bits;
+                    }
+                    _ => {
                         return Err(PsyParseError::IncorrectLeafData(
                             byte_offset,
-                            "File path format is \
-                             incorrect.",
+                            "BitDepth should be 8 or 16.",
                         ));
                     }
-                    if tc.chars().nth(0).unwrap() != '"' || !tc.ends_with('"') {
+                },
+
+                // Transfer function of png and tiff output
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Transfer" => {
+                    if let Ok(transfer) = Transfer::from_spec(contents) {
+                        encoding.transfer = transfer;
+                    } else {
                         return Err(PsyParseError::IncorrectLeafData(
                             byte_offset,
-                            "File paths must be \
-                             surrounded by quotes.",
+                            "Transfer should be srgb, linear, or pq.",
                         ));
                     }
-                    let len = tc.len();
-                    let tc = &tc[1..len - 1];
-
-                    // Parse
-                    // TODO: proper string escaping
-                    found_path = true;
-                    path = tc.to_string();
                 }
 
                 _ => {}
@@ -241,7 +481,7 @@ fn parse_output_info(tree: &DataTree) -> Result<String, PsyParseError> {
         }
 
         if found_path {
-            return Ok(path);
+            return Ok((path, encoding));
         } else {
             return Err(PsyParseError::MissingNode(
                 tree.byte_offset(),
@@ -257,13 +497,11 @@ fn parse_output_info(tree: &DataTree) -> Result<String, PsyParseError> {
     };
 }
 
-fn parse_render_settings(tree: &DataTree) -> Result<((u32, u32), u32, u32), PsyParseError> {
+fn parse_render_settings(tree: &DataTree) -> Result<RenderSettings, PsyParseError> {
     if let DataTree::Internal { ref children, .. } = *tree {
         let mut found_res = false;
         let mut found_spp = false;
-        let mut res = (0, 0);
-        let mut spp = 0;
-        let mut seed = 0;
+        let mut settings = RenderSettings::default();
 
         for child in children {
             match *child {
@@ -276,8 +514,14 @@ fn parse_render_settings(tree: &DataTree) -> Result<((u32, u32), u32, u32), PsyP
                     if let IResult::Ok((_, (w, h))) =
                         all_consuming(tuple((ws_u32, ws_u32)))(contents)
                     {
+                        if w == 0 || h == 0 {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "Resolution should be at least 1 by 1.",
+                            ));
+                        }
                         found_res = true;
-                        res = (w, h);
+                        settings.resolution = (w as usize, h as usize);
                     } else {
                         // Found Resolution, but its contents is not in the right format
                         return Err(PsyParseError::IncorrectLeafData(
@@ -288,6 +532,23 @@ fn parse_render_settings(tree: &DataTree) -> Result<((u32, u32), u32, u32), PsyP
                     }
                 }
 
+                // Overscan
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Overscan" => {
+                    if let IResult::Ok((_, n)) = all_consuming(ws_u32)(contents) {
+                        settings.overscan = n as usize;
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Overscan should be an integer number of \
+                             pixels, specified in the form '[pixels]'.",
+                        ));
+                    }
+                }
+
                 // SamplesPerPixel
                 DataTree::Leaf {
                     type_name,
@@ -295,8 +556,14 @@ fn parse_render_settings(tree: &DataTree) -> Result<((u32, u32), u32, u32), PsyP
                     byte_offset,
                 } if type_name == "SamplesPerPixel" => {
                     if let IResult::Ok((_, n)) = all_consuming(ws_u32)(contents) {
+                        if n == 0 {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "SamplesPerPixel should be at least 1.",
+                            ));
+                        }
                         found_spp = true;
-                        spp = n;
+                        settings.spp = n as usize;
                     } else {
                         // Found SamplesPerPixel, but its contents is not in the right format
                         return Err(PsyParseError::IncorrectLeafData(
@@ -315,7 +582,7 @@ fn parse_render_settings(tree: &DataTree) -> Result<((u32, u32), u32, u32), PsyP
                     byte_offset,
                 } if type_name == "Seed" => {
                     if let IResult::Ok((_, n)) = all_consuming(ws_u32)(contents) {
-                        seed = n;
+                        settings.seed = n;
                     } else {
                         // Found Seed, but its contents is not in the right format
                         return Err(PsyParseError::IncorrectLeafData(
@@ -327,12 +594,125 @@ fn parse_render_settings(tree: &DataTree) -> Result<((u32, u32), u32, u32), PsyP
                     }
                 }
 
+                // MaxBounces
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "MaxBounces" => {
+                    if let IResult::Ok((_, n)) = all_consuming(ws_u32)(contents) {
+                        settings.max_bounces = n;
+                    } else {
+                        // Found MaxBounces, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "MaxBounces should be an integer \
+                             specified in the form '[bounces]'.",
+                        ));
+                    }
+                }
+
+                // PixelAspect
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "PixelAspect" => match all_consuming(ws_f32)(contents) {
+                    IResult::Ok((_, aspect)) if aspect > 0.0 && aspect.is_finite() => {
+                        settings.pixel_aspect = aspect;
+                    }
+                    _ => {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "PixelAspect should be a positive number, the width \
+                                 of each pixel over its height, specified in the \
+                                 form '[aspect]'.",
+                        ));
+                    }
+                },
+
+                // DicingRate
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "DicingRate" => match all_consuming(ws_f32)(contents) {
+                    IResult::Ok((_, rate)) if rate > 0.0 && rate.is_finite() => {
+                        settings.dicing_rate = rate;
+                    }
+                    _ => {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "DicingRate should be a positive number of pixels, \
+                                 specified in the form '[rate]'.",
+                        ));
+                    }
+                },
+
+                // SensorResponse, either a preset or a quoted path
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "SensorResponse" => {
+                    let spec = if contents.trim().starts_with('"') {
+                        parse_quoted_string(contents, byte_offset)?
+                    } else {
+                        contents.trim()
+                    };
+                    if let Ok(response) = SensorResponse::from_spec(spec) {
+                        settings.sensor_response = response;
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "SensorResponse should be 'cie1931', 'cmos', or the quoted \
+                             path of a readable sensitivity file.",
+                        ));
+                    }
+                }
+
+                // ImportanceMask
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "ImportanceMask" => {
+                    let path = parse_quoted_string(contents, byte_offset)?;
+                    if let Ok(mask) = ImportanceMask::from_spec(path) {
+                        settings.importance_mask = mask;
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "ImportanceMask should be the quoted path of a readable pfm \
+                             image, in the form '[\"path.pfm\"]'.",
+                        ));
+                    }
+                }
+
+                // WhiteBalance
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "WhiteBalance" => {
+                    if let Ok(white_balance) = WhiteBalance::from_spec(contents) {
+                        settings.white_balance = white_balance;
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "WhiteBalance should be an illuminant (e, d50, d55, d65, d75, \
+                             or a), or a temperature in kelvin and optional tint, in the \
+                             form '[kelvin]' or '[kelvin tint]'.",
+                        ));
+                    }
+                }
+
                 _ => {}
             }
         }
 
         if found_res && found_spp {
-            return Ok((res, spp, seed));
+            return Ok(settings);
         } else {
             return Err(PsyParseError::MissingNode(
                 tree.byte_offset(),
@@ -350,12 +730,19 @@ fn parse_render_settings(tree: &DataTree) -> Result<((u32, u32), u32, u32), PsyP
     };
 }
 
-fn parse_camera<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Camera<'a>, PsyParseError> {
+fn parse_camera<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+    conversion: &SceneConversion,
+) -> Result<PerspectiveCamera<'a>, PsyParseError> {
     if let DataTree::Internal { ref children, .. } = *tree {
         let mut mats = Vec::new();
         let mut fovs = Vec::new();
         let mut focus_distances = Vec::new();
         let mut aperture_radii = Vec::new();
+        let mut exposure = Exposure::default();
+        let mut found_f_stop = false;
+        let mut sensor_width = 0.036; // 35mm full frame, in meters
 
         // Parse
         for child in children.iter() {
@@ -386,7 +773,7 @@ fn parse_camera<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Camera<'a>,
                     byte_offset,
                 } if type_name == "FocalDistance" => {
                     if let IResult::Ok((_, fd)) = all_consuming(ws_f32)(contents) {
-                        focus_distances.push(fd);
+                        focus_distances.push(fd * conversion.units_scale);
                     } else {
                         // Found FocalDistance, but its contents is not in the right format
                         return Err(PsyParseError::IncorrectLeafData(
@@ -405,7 +792,7 @@ fn parse_camera<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Camera<'a>,
                     byte_offset,
                 } if type_name == "ApertureRadius" => {
                     if let IResult::Ok((_, ar)) = all_consuming(ws_f32)(contents) {
-                        aperture_radii.push(ar);
+                        aperture_radii.push(ar * conversion.units_scale);
                     } else {
                         // Found ApertureRadius, but its contents is not in the right format
                         return Err(PsyParseError::IncorrectLeafData(
@@ -424,23 +811,118 @@ fn parse_camera<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Camera<'a>,
                     byte_offset,
                 } if type_name == "Transform" => {
                     if let Ok(mat) = parse_matrix(contents) {
-                        mats.push(mat);
+                        mats.push(conversion.camera_transform(mat));
                     } else {
                         // Found Transform, but its contents is not in the right format
                         return Err(make_transform_format_error(byte_offset));
                     }
                 }
 
+                // Iso
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Iso" => {
+                    if let IResult::Ok((_, iso)) = all_consuming(ws_f32)(contents) {
+                        if iso <= 0.0 {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "Iso must be greater than zero.",
+                            ));
+                        }
+                        exposure.iso = iso;
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Iso should be a decimal number specified in the form '[iso]'.",
+                        ));
+                    }
+                }
+
+                // ShutterSpeed
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "ShutterSpeed" => {
+                    if let IResult::Ok((_, speed)) = all_consuming(ws_f32)(contents) {
+                        if speed <= 0.0 {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "ShutterSpeed must be greater than zero.",
+                            ));
+                        }
+                        exposure.shutter_speed = speed;
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "ShutterSpeed should be a decimal number of seconds \
+                             specified in the form '[seconds]'.",
+                        ));
+                    }
+                }
+
+                // FStop
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "FStop" => {
+                    if let IResult::Ok((_, f_stop)) = all_consuming(ws_f32)(contents) {
+                        if f_stop <= 0.0 {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "FStop must be greater than zero.",
+                            ));
+                        }
+                        exposure.f_stop = f_stop;
+                        found_f_stop = true;
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "FStop should be a decimal number specified in the form '[f_stop]'.",
+                        ));
+                    }
+                }
+
+                // SensorWidth
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "SensorWidth" => {
+                    if let IResult::Ok((_, width)) = all_consuming(ws_f32)(contents) {
+                        sensor_width = width;
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "SensorWidth should be a decimal number specified in the \
+                             form '[width]'.",
+                        ));
+                    }
+                }
+
                 _ => {}
             }
         }
 
-        return Ok(Camera::new(
+        // If there's an f-stop but no explicit aperture, derive the aperture
+        // from the f-stop so that depth of field matches the exposure.
+        if found_f_stop && aperture_radii.is_empty() {
+            aperture_radii = fovs
+                .iter()
+                .map(|fov| exposure.aperture_radius(*fov, sensor_width))
+                .collect();
+        }
+
+        return Ok(PerspectiveCamera::new(
             arena,
             &mats,
             &fovs,
             &aperture_radii,
             &focus_distances,
+            exposure,
         ));
     } else {
         return Err(PsyParseError::ExpectedInternalNode(
@@ -452,7 +934,11 @@ fn parse_camera<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Camera<'a>,
     }
 }
 
-fn parse_world<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<World<'a>, PsyParseError> {
+fn parse_world<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+    conversion: &SceneConversion,
+) -> Result<World<'a>, PsyParseError> {
     if tree.is_internal() {
         let background_color;
         let mut lights: Vec<&dyn WorldLightSource> = Vec::new();
@@ -531,17 +1017,62 @@ fn parse_world<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<World<'a>, Ps
         for child in tree.iter_children() {
             match *child {
                 DataTree::Internal { type_name, .. } if type_name == "DistantDiskLight" => {
-                    lights.push(arena.alloc(parse_distant_disk_light(arena, child)?));
+                    lights.push(arena.alloc(parse_distant_disk_light(
+                        arena,
+                        child,
+                        &conversion.axes,
+                    )?));
+                }
+
+                DataTree::Internal { type_name, .. } if type_name == "EnvironmentLight" => {
+                    lights.push(arena.alloc(parse_environment_light(arena, child)?));
                 }
 
                 _ => {}
             }
         }
 
+        // Parse atmosphere
+        let atmosphere = match tree.iter_children_with_type("Atmosphere").count() {
+            0 => None,
+            1 => Some(parse_atmosphere(
+                tree.iter_children_with_type("Atmosphere").next().unwrap(),
+                conversion,
+            )?),
+            count => {
+                return Err(PsyParseError::WrongNodeCount(
+                    tree.byte_offset(),
+                    "World should have at most one Atmosphere section.",
+                    count,
+                ));
+            }
+        };
+
+        // Parse backplate
+        let mut backplate = None;
+        if let Some(&DataTree::Leaf {
+            contents,
+            byte_offset,
+            ..
+        }) = tree.iter_children_with_type("Backplate").nth(0)
+        {
+            let path = parse_quoted_string(contents, byte_offset)?;
+            if let Ok(image) = read_pfm(path.as_ref()) {
+                backplate = Some(Backplate::new(arena, &image));
+            } else {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Couldn't read the backplate image.  It should be a PFM file.",
+                ));
+            }
+        }
+
         // Build and return the world
         return Ok(World {
             background_color: background_color,
             lights: arena.copy_slice(&lights),
+            atmosphere: atmosphere,
+            backplate: backplate,
         });
     } else {
         return Err(PsyParseError::ExpectedInternalNode(
@@ -553,21 +1084,92 @@ fn parse_world<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<World<'a>, Ps
     }
 }
 
+/// Parses an Atmosphere section.  Its bounds are left empty, to be set
+/// once the scene's extent is known.
+fn parse_atmosphere(
+    tree: &DataTree,
+    conversion: &SceneConversion,
+) -> Result<Atmosphere, PsyParseError> {
+    let color = |type_name, default: Option<Color>| {
+        if let Some((_, contents, byte_offset)) =
+            tree.iter_leaf_children_with_type(type_name).next()
+        {
+            if let Ok(color) = parse_color(contents) {
+                Ok(color)
+            } else {
+                Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Atmosphere coefficients should be specified as colors.",
+                ))
+            }
+        } else {
+            default.ok_or(PsyParseError::MissingNode(
+                tree.byte_offset(),
+                "Expected a Scattering field in Atmosphere.",
+            ))
+        }
+    };
+    let black = Color::new_xyz((0.0, 0.0, 0.0));
+    let scattering = color("Scattering", None)?;
+    let absorption = color("Absorption", Some(black))?;
+
+    let anisotropy = if let Some((_, contents, byte_offset)) =
+        tree.iter_leaf_children_with_type("Anisotropy").next()
+    {
+        if let IResult::Ok((_, g)) = all_consuming(ws_f32)(contents) {
+            if g <= -1.0 || g >= 1.0 {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Atmosphere Anisotropy should be between -1 and 1.",
+                ));
+            }
+            g
+        } else {
+            return Err(PsyParseError::UnknownError(byte_offset));
+        }
+    } else {
+        0.0
+    };
+
+    // The coefficients are per scene unit of distance.
+    let per_unit = 1.0 / conversion.units_scale;
+    return Ok(Atmosphere {
+        scattering: scattering * per_unit,
+        absorption: absorption * per_unit,
+        anisotropy: anisotropy,
+        bounds: BBox::new(),
+    });
+}
+
 pub fn parse_matrix(contents: &str) -> Result<Matrix4x4, PsyParseError> {
-    if let IResult::Ok((leftover, ns)) = all_consuming(tuple((
+    match parse_matrices(contents) {
+        Ok(ref matrices) if matrices.len() == 1 => Ok(matrices[0]),
+        _ => Err(PsyParseError::UnknownError(0)),
+    }
+}
+
+/// Parses any number of matrices, one after another, each in the same
+/// form as for `parse_matrix()`.
+pub fn parse_matrices(contents: &str) -> Result<Vec<Matrix4x4>, PsyParseError> {
+    let mut text = contents;
+    let mut matrices = Vec::new();
+    while let IResult::Ok((remaining, ns)) = tuple((
         ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32,
         ws_f32, ws_f32, ws_f32, ws_f32, ws_f32,
-    )))(contents)
+    ))(text)
     {
-        if leftover.is_empty() {
-            return Ok(Matrix4x4::new_from_values(
-                ns.0, ns.4, ns.8, ns.12, ns.1, ns.5, ns.9, ns.13, ns.2, ns.6, ns.10, ns.14, ns.3,
-                ns.7, ns.11, ns.15,
-            ));
-        }
+        text = remaining;
+        matrices.push(Matrix4x4::new_from_values(
+            ns.0, ns.4, ns.8, ns.12, ns.1, ns.5, ns.9, ns.13, ns.2, ns.6, ns.10, ns.14, ns.3, ns.7,
+            ns.11, ns.15,
+        ));
     }
 
-    return Err(PsyParseError::UnknownError(0));
+    if text.trim().is_empty() {
+        Ok(matrices)
+    } else {
+        Err(PsyParseError::UnknownError(0))
+    }
 }
 
 pub fn make_transform_format_error(byte_offset: usize) -> PsyParseError {
@@ -612,3 +1214,85 @@ pub fn parse_color(contents: &str) -> Result<Color, PsyParseError> {
         _ => return Err(PsyParseError::UnknownError(0)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::{max_element, Point, Vector};
+
+    #[test]
+    fn y_up_centimeters_to_z_up() {
+        let tree = DataTree::from_str("UnitsScale [0.01] UpAxis [Y]").unwrap();
+        let conversion = parse_scene_conversion(&tree).unwrap();
+        assert!(!conversion.is_identity());
+
+        // Up in the scene is up in the renderer, and forward (+Z in a
+        // Y-up scene) is toward the viewer (-Y).
+        let p = Point::new(100.0, 200.0, 300.0) * conversion.world_from_scene();
+        assert!((p.x() - 1.0).abs() < 1.0e-5);
+        assert!((p.y() + 3.0).abs() < 1.0e-5);
+        assert!((p.z() - 2.0).abs() < 1.0e-5);
+
+        // Converted camera transforms move the camera, but don't scale
+        // its rays.
+        let cam = Matrix4x4::from_location(Point::new(0.0, 0.0, 500.0));
+        let cam = conversion.camera_transform(cam);
+        let orig = Point::new(0.0, 0.0, 0.0) * cam;
+        let dir = Vector::new(0.0, 0.0, 1.0) * cam;
+        assert!((orig.y() + 5.0).abs() < 1.0e-5);
+        assert!((dir.length() - 1.0).abs() < 1.0e-5);
+        assert!((dir.y() + 1.0).abs() < 1.0e-5);
+
+        let tree = DataTree::from_str("UpAxis [X]").unwrap();
+        assert!(parse_scene_conversion(&tree).is_err());
+        let tree = DataTree::from_str("").unwrap();
+        assert!(parse_scene_conversion(&tree).unwrap().is_identity());
+    }
+
+    #[test]
+    fn atmosphere_coefficients_are_per_scene_unit() {
+        let tree = DataTree::from_str("UnitsScale [0.01]").unwrap();
+        let conversion = parse_scene_conversion(&tree).unwrap();
+        let tree =
+            DataTree::from_str("Atmosphere { Scattering [rec709, 0.5 0.5 0.5] Anisotropy [0.3] }")
+                .unwrap();
+        let atmosphere =
+            parse_atmosphere(tree.iter_children().next().unwrap(), &conversion).unwrap();
+
+        // Per centimeter in the scene is per hundred renderer units.
+        let (scattering, extinction) = atmosphere.coefficients(550.0);
+        let expected = parse_color("rec709, 50 50 50")
+            .unwrap()
+            .to_spectral_sample(550.0)
+            .e;
+        assert!(max_element((scattering - expected).abs()) < 1.0e-3);
+        assert!(max_element((extinction - expected).abs()) < 1.0e-3);
+        assert_eq!(atmosphere.anisotropy, 0.3);
+
+        let tree = DataTree::from_str("Atmosphere { Absorption [rec709, 1 1 1] }").unwrap();
+        assert!(parse_atmosphere(tree.iter_children().next().unwrap(), &conversion).is_err());
+        let tree = DataTree::from_str("Atmosphere { Scattering [rec709, 1 1 1] Anisotropy [1.0] }")
+            .unwrap();
+        assert!(parse_atmosphere(tree.iter_children().next().unwrap(), &conversion).is_err());
+    }
+
+    #[test]
+    fn camera_exposure_must_be_positive() {
+        let arena = Arena::new();
+        let conversion = parse_scene_conversion(&DataTree::from_str("").unwrap()).unwrap();
+        let parse = |text: &str| {
+            let tree = DataTree::from_str(text).unwrap();
+            let camera = tree.iter_children().next().unwrap();
+            parse_camera(&arena, camera, &conversion).is_ok()
+        };
+
+        assert!(parse(
+            "Camera { Fov [39.0] Transform [1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1] \
+             Iso [400] ShutterSpeed [0.01] }"
+        ));
+        for field in &["Iso", "ShutterSpeed", "FStop"] {
+            assert!(!parse(&format!("Camera {{ {} [0] }}", field)));
+            assert!(!parse(&format!("Camera {{ {} [-1.5] }}", field)));
+        }
+    }
+}
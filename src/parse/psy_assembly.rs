@@ -4,21 +4,57 @@ use std::result::Result;
 
 use kioku::Arena;
 
-use crate::scene::{Assembly, AssemblyBuilder, Object};
+use crate::{
+    camera::DicingCamera,
+    lerp::lerp_slice,
+    math::Matrix4x4,
+    render_settings::Sanitize,
+    scene::{Assembly, AssemblyBuilder, Object},
+};
 
 use super::{
-    psy::{parse_matrix, PsyParseError},
-    psy_light::{parse_rectangle_light, parse_sphere_light},
+    psy::{make_transform_format_error, parse_matrices, parse_matrix, PsyParseError},
+    psy_bilinear_patch::parse_bilinear_patch,
+    psy_curve_surface::{parse_curve_surface, parse_groom_surface},
+    psy_light::{
+        parse_disk_light, parse_point_light, parse_rectangle_light, parse_sphere_light,
+        parse_tube_light,
+    },
     psy_mesh_surface::parse_mesh_surface,
+    psy_schema::{is_known_node, PsyParseWarning},
     psy_surface_shader::parse_surface_shader,
     DataTree,
 };
 
+/// Parses an assembly.
+///
+/// `placements` are the world-to-assembly transforms, at the middle of the
+/// shutter, of each place the assembly is instanced in the scene.  They're
+/// used to dice the assembly's surfaces based on how large they'll appear
+/// through `dicing_camera`.
+///
+/// `base_xform`, if given, is the static transform of the assembly's one
+/// instance, which is composed into the transforms of the assembly's own
+/// instances and spaces instead.  Chains of static transforms are folded
+/// down this way so that they don't cost anything during traversal.
+///
+/// If `skip_lights` is true, instances of lights are left out, so that
+/// the scene isn't lit by them.
+///
+/// Geometry is checked according to `sanitize`, with problems that it
+/// can still be rendered with added to `warnings`.
 pub fn parse_assembly<'a>(
     arena: &'a Arena,
     tree: &'a DataTree,
+    dicing_camera: &DicingCamera,
+    placements: &[Matrix4x4],
+    base_xform: Option<Matrix4x4>,
+    skip_lights: bool,
+    sanitize: Sanitize,
+    warnings: &mut Vec<PsyParseWarning>,
 ) -> Result<Assembly<'a>, PsyParseError> {
     let mut builder = AssemblyBuilder::new(arena);
+    let mut folded_assemblies = Vec::new(); // Sub-assemblies with their instance's transform folded in
 
     if tree.is_internal() {
         for child in tree.iter_children() {
@@ -29,7 +65,30 @@ pub fn parse_assembly<'a>(
                         ident: Some(ident), ..
                     } = *child
                     {
-                        builder.add_assembly(ident, parse_assembly(arena, child)?);
+                        let sub_placements = instance_placements(tree, ident, placements);
+                        let sub_base_xform = match static_instance_transform(tree, ident) {
+                            Some(Some(xform)) => {
+                                Some(base_xform.map_or(xform, |base| base * xform))
+                            }
+                            Some(None) => base_xform,
+                            None => None,
+                        };
+                        if sub_base_xform.is_some() {
+                            folded_assemblies.push(ident);
+                        }
+                        builder.add_assembly(
+                            ident,
+                            parse_assembly(
+                                arena,
+                                child,
+                                dicing_camera,
+                                &sub_placements,
+                                sub_base_xform,
+                                skip_lights,
+                                sanitize,
+                                warnings,
+                            )?,
+                        );
                     } else {
                         return Err(PsyParseError::UnknownError(child.byte_offset()));
                     }
@@ -67,15 +126,91 @@ pub fn parse_assembly<'a>(
                         None
                     };
 
+                    // Get surface shader override, if any.
+                    let surface_shader_override = match child
+                        .iter_leaf_children_with_type("SurfaceShaderOverride")
+                        .next()
+                    {
+                        Some((_, contents, byte_offset)) => {
+                            let shader_name = contents.trim();
+                            if !builder.surface_shader_exists(shader_name) {
+                                return Err(PsyParseError::IncorrectLeafData(
+                                    byte_offset,
+                                    "SurfaceShaderOverride should name a surface shader \
+                                     defined before the instance, in the same assembly.",
+                                ));
+                            }
+                            Some(shader_name)
+                        }
+                        None => None,
+                    };
+
+                    // Get proxy geometry, if any.
+                    let proxy = child
+                        .iter_leaf_children_with_type("Proxy")
+                        .next()
+                        .map(|(_, contents, byte_offset)| (contents.trim(), byte_offset));
+
                     // Get xforms
                     let mut xforms = Vec::new();
-                    for (_, contents, _) in child.iter_leaf_children_with_type("Transform") {
-                        xforms.push(parse_matrix(contents)?);
+                    for (_, contents, byte_offset) in
+                        child.iter_leaf_children_with_type("Transform")
+                    {
+                        let xform = parse_matrix(contents)
+                            .map_err(|_| make_transform_format_error(byte_offset))?;
+                        xforms.push(xform);
+                    }
+                    let instance_name = format!("instance of {}", name);
+                    match fix_transforms(&mut xforms) {
+                        Ok(problems) => {
+                            for problem in problems {
+                                warnings.push(PsyParseWarning::BadGeometry(
+                                    child.byte_offset(),
+                                    instance_name.clone(),
+                                    problem,
+                                ));
+                            }
+                        }
+                        Err(problem) => {
+                            warnings.push(PsyParseWarning::BadGeometry(
+                                child.byte_offset(),
+                                instance_name,
+                                format!("{}, so it's skipped", problem),
+                            ));
+                            continue;
+                        }
+                    }
+                    if folded_assemblies.contains(&name) {
+                        xforms.clear();
+                    } else if let Some(base) = base_xform {
+                        compose_base_xform(base, &mut xforms);
                     }
 
                     // Add instance
-                    if builder.name_exists(name) {
-                        builder.add_instance(name, surface_shader_name, Some(&xforms));
+                    if skip_lights && builder.is_light(name) {
+                        continue;
+                    } else if is_unknown_data(tree, name) {
+                        // Skipped along with its data, which is reported
+                        // as an unknown node.
+                        continue;
+                    } else if builder.name_exists(name) {
+                        builder.add_instance(
+                            name,
+                            surface_shader_name,
+                            surface_shader_override,
+                            Some(&xforms),
+                        );
+                        if let Some((proxy_name, byte_offset)) = proxy {
+                            if !builder.is_surface(name) || !builder.is_surface(proxy_name) {
+                                return Err(PsyParseError::IncorrectLeafData(
+                                    byte_offset,
+                                    "Proxy should name a surface defined before the \
+                                     instance, and only instances of surfaces can have \
+                                     proxies.",
+                                ));
+                            }
+                            builder.set_instance_proxy(proxy_name);
+                        }
                     } else {
                         return Err(PsyParseError::InstancedMissingData(
                             child.iter_leaf_children_with_type("Data").nth(0).unwrap().2,
@@ -88,6 +223,135 @@ pub fn parse_assembly<'a>(
                     }
                 }
 
+                // Many instances of one surface
+                "InstanceArray" => {
+                    if !child.is_internal() {
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+
+                    let (_, name, data_offset) =
+                        match child.iter_leaf_children_with_type("Data").next() {
+                            Some(data) => data,
+                            None => return Err(PsyParseError::UnknownError(child.byte_offset())),
+                        };
+                    if !builder.is_surface(name) {
+                        return Err(PsyParseError::InstancedMissingData(
+                            data_offset,
+                            "Instance arrays should be of a surface defined before them.",
+                            name.to_string(),
+                        ));
+                    }
+                    let surface_shader_name = match child
+                        .iter_leaf_children_with_type("SurfaceShaderBind")
+                        .next()
+                    {
+                        Some((_, contents, byte_offset)) => {
+                            check_surface_shader(&builder, contents.trim(), byte_offset)?;
+                            Some(contents.trim())
+                        }
+                        None => None,
+                    };
+
+                    // One `Transforms` per time sample, each with a
+                    // transform for every element.
+                    let mut samples: Vec<Vec<Matrix4x4>> = Vec::new();
+                    for (_, contents, byte_offset) in
+                        child.iter_leaf_children_with_type("Transforms")
+                    {
+                        let xforms = parse_matrices(contents).map_err(|_| {
+                            PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "Transforms should be sixteen numbers for each element, \
+                                 in the form '[# # # # # # # # # # # # # # # #  ...]'.",
+                            )
+                        })?;
+                        if !samples.is_empty() && xforms.len() != samples[0].len() {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "Each Transforms of an InstanceArray should have the same \
+                                 number of transforms.",
+                            ));
+                        }
+                        samples.push(xforms);
+                    }
+                    if samples.is_empty() {
+                        return Err(PsyParseError::MissingNode(
+                            child.byte_offset(),
+                            "InstanceArray should have at least one Transforms.",
+                        ));
+                    }
+
+                    // Gather the samples of each element together, skipping
+                    // elements that can't be traced.
+                    let sample_count = samples.len();
+                    let mut xforms = Vec::with_capacity(sample_count * samples[0].len());
+                    let mut element_xforms = Vec::with_capacity(sample_count);
+                    let mut skipped = 0;
+                    for e in 0..samples[0].len() {
+                        element_xforms.clear();
+                        element_xforms.extend(samples.iter().map(|sample| sample[e]));
+                        if fix_transforms(&mut element_xforms).is_err() {
+                            skipped += 1;
+                            continue;
+                        }
+                        if let Some(base) = base_xform {
+                            compose_base_xform(base, &mut element_xforms);
+                        }
+                        xforms.extend_from_slice(&element_xforms);
+                    }
+                    if skipped > 0 {
+                        warnings.push(PsyParseWarning::BadGeometry(
+                            child.byte_offset(),
+                            format!("instance array of {}", name),
+                            format!(
+                                "{} element(s) have singular transforms, e.g. from a scale \
+                                 of zero, so they're skipped",
+                                skipped
+                            ),
+                        ));
+                    }
+
+                    builder.add_instance_array(name, surface_shader_name, &xforms, sample_count);
+                }
+
+                // Named coordinate space
+                "Space" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        if builder.space_exists(ident) {
+                            return Err(PsyParseError::UnknownVariant(
+                                child.byte_offset(),
+                                "Space has the same name as another space in its assembly, \
+                                 or as a built-in space (\"world\" or \"object\").",
+                            ));
+                        }
+
+                        let mut xforms = Vec::new();
+                        for (_, contents, byte_offset) in
+                            child.iter_leaf_children_with_type("Transform")
+                        {
+                            let xform = parse_matrix(contents)
+                                .map_err(|_| make_transform_format_error(byte_offset))?;
+                            xforms.push(xform);
+                        }
+                        if xforms.is_empty() {
+                            return Err(PsyParseError::MissingNode(
+                                child.byte_offset(),
+                                "Space should have at least one Transform.",
+                            ));
+                        }
+                        if let Some(base) = base_xform {
+                            compose_base_xform(base, &mut xforms);
+                        }
+                        builder.add_space(ident, &xforms);
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
                 // SurfaceShader
                 "SurfaceShader" => {
                     if let DataTree::Internal {
@@ -111,10 +375,15 @@ pub fn parse_assembly<'a>(
                         ident: Some(ident), ..
                     } = *child
                     {
-                        builder.add_object(
-                            ident,
-                            Object::Surface(arena.alloc(parse_mesh_surface(arena, child)?)),
-                        );
+                        for group in child.iter_children_with_type("FaceGroup") {
+                            for (_, contents, byte_offset) in
+                                group.iter_leaf_children_with_type("SurfaceShaderBind")
+                            {
+                                check_surface_shader(&builder, contents.trim(), byte_offset)?;
+                            }
+                        }
+                        let mesh = parse_mesh_surface(arena, child, sanitize, warnings)?;
+                        builder.add_object(ident, Object::Surface(arena.alloc(mesh)));
                     } else {
                         // TODO: error condition of some kind, because no ident
                         panic!(
@@ -125,6 +394,82 @@ pub fn parse_assembly<'a>(
                     }
                 }
 
+                // CurveSurface
+                "CurveSurface" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(arena.alloc(parse_curve_surface(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // GroomSurface
+                "GroomSurface" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(arena.alloc(parse_groom_surface(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // BilinearPatch
+                "BilinearPatch" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        let patch = parse_bilinear_patch(arena, child)?;
+
+                        // Dice finely enough for the instance that appears
+                        // the largest.
+                        let object_to_worlds: Vec<_> = instance_placements(tree, ident, placements)
+                            .iter()
+                            .map(|xform| xform.inverse())
+                            .collect();
+                        let diced = patch.dice(arena, |p1, p2| {
+                            object_to_worlds
+                                .iter()
+                                .map(|&xform| dicing_camera.edge_rate(p1 * xform, p2 * xform))
+                                .fold(0.0, f32::max)
+                        });
+
+                        builder.add_object(ident, Object::Surface(arena.alloc(diced)));
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Point Light
+                "PointLight" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        builder.add_object(
+                            ident,
+                            Object::SurfaceLight(arena.alloc(parse_point_light(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
                 // Sphere Light
                 "SphereLight" => {
                     if let DataTree::Internal {
@@ -157,14 +502,42 @@ pub fn parse_assembly<'a>(
                     }
                 }
 
+                // Disk Light
+                "DiskLight" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        builder.add_object(
+                            ident,
+                            Object::SurfaceLight(arena.alloc(parse_disk_light(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Tube Light
+                "TubeLight" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        builder.add_object(
+                            ident,
+                            Object::SurfaceLight(arena.alloc(parse_tube_light(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
                 _ => {
-                    // TODO: some kind of error, because not a known type name
-                } // // Bilinear Patch
-                  // "BilinearPatch" => {
-                  //     assembly->add_object(child.name, parse_bilinear_patch(child));
-                  // }
-                  //
-                  // // Bicubic Patch
+                    // Unknown type name, which is skipped.  See
+                    // `find_unknown_nodes()`.
+                } // // Bicubic Patch
                   // else if (child.type == "BicubicPatch") {
                   //     assembly->add_object(child.name, parse_bicubic_patch(child));
                   // }
@@ -186,3 +559,432 @@ pub fn parse_assembly<'a>(
 
     return Ok(builder.build());
 }
+
+/// Returns the world-to-object transforms, at the middle of the shutter, of
+/// each place the named data is instanced, including by instance arrays,
+/// given the placements of the assembly it's instanced in.
+///
+/// Malformed instances are skipped, since they're reported when the
+/// instances themselves are parsed.
+fn instance_placements(tree: &DataTree, name: &str, placements: &[Matrix4x4]) -> Vec<Matrix4x4> {
+    let mut instance_placements = Vec::new();
+    for instance in tree.iter_children_with_type("Instance") {
+        if instance
+            .iter_leaf_children_with_type("Data")
+            .next()
+            .map(|d| d.1)
+            != Some(name)
+        {
+            continue;
+        }
+
+        let xforms: Vec<_> = instance
+            .iter_leaf_children_with_type("Transform")
+            .filter_map(|(_, contents, _)| parse_matrix(contents).ok())
+            .collect();
+        let xform = if xforms.is_empty() {
+            Matrix4x4::new()
+        } else {
+            lerp_slice(&xforms, 0.5)
+        };
+
+        for &placement in placements {
+            instance_placements.push(placement * xform);
+        }
+    }
+
+    for array in tree.iter_children_with_type("InstanceArray") {
+        if array
+            .iter_leaf_children_with_type("Data")
+            .next()
+            .map(|d| d.1)
+            != Some(name)
+        {
+            continue;
+        }
+
+        let samples: Vec<_> = array
+            .iter_leaf_children_with_type("Transforms")
+            .filter_map(|(_, contents, _)| parse_matrices(contents).ok())
+            .collect();
+        let element_count = samples.iter().map(|s| s.len()).min().unwrap_or(0);
+        let mut element_xforms = Vec::with_capacity(samples.len());
+        for e in 0..element_count {
+            element_xforms.clear();
+            element_xforms.extend(samples.iter().map(|sample| sample[e]));
+            let xform = lerp_slice(&element_xforms, 0.5);
+            for &placement in placements {
+                instance_placements.push(placement * xform);
+            }
+        }
+    }
+
+    instance_placements
+}
+
+/// If the named data is instanced exactly once in the assembly `tree`,
+/// with at most one transform sample, returns that instance's transform
+/// (`None` if it has none).
+///
+/// Such a transform can be composed into the data itself without changing
+/// anything, even if the data contains animated transforms.
+fn static_instance_transform(tree: &DataTree, name: &str) -> Option<Option<Matrix4x4>> {
+    let mut instances = tree.iter_children_with_type("Instance").filter(|instance| {
+        instance
+            .iter_leaf_children_with_type("Data")
+            .next()
+            .map(|d| d.1)
+            == Some(name)
+    });
+    let instance = instances.next()?;
+    if instances.next().is_some() {
+        return None;
+    }
+
+    let mut xforms = instance.iter_leaf_children_with_type("Transform");
+    let xform = match xforms.next() {
+        Some((_, contents, _)) => parse_matrix(contents).ok()?,
+        None => return Some(None),
+    };
+    if xforms.next().is_some() || xform.try_inverse().is_none() {
+        return None;
+    }
+    Some(Some(xform))
+}
+
+/// Composes a static transform from outside an assembly into the transform
+/// samples of something in it.
+fn compose_base_xform(base: Matrix4x4, xforms: &mut Vec<Matrix4x4>) {
+    if xforms.is_empty() {
+        xforms.push(base);
+    } else {
+        for xform in xforms.iter_mut() {
+            *xform = base * *xform;
+        }
+    }
+}
+
+/// Checks an instance's transform samples, fixing what can be fixed.
+///
+/// Singular samples, e.g. from a scale of zero, can't be inverted to
+/// trace rays with, so they're replaced by the nearest invertible sample.
+/// Returns descriptions of the problems found, or an error if none of
+/// the samples can be inverted.
+fn fix_transforms(xforms: &mut [Matrix4x4]) -> Result<Vec<String>, String> {
+    let mut problems = Vec::new();
+
+    let invertible: Vec<usize> = (0..xforms.len())
+        .filter(|&i| xforms[i].try_inverse().is_some())
+        .collect();
+    if invertible.is_empty() && !xforms.is_empty() {
+        return Err("transform is singular, e.g. from a scale of zero".to_string());
+    }
+    for i in 0..xforms.len() {
+        if invertible.binary_search(&i).is_err() {
+            let nearest = *invertible
+                .iter()
+                .min_by_key(|&&j| (j as isize - i as isize).abs())
+                .unwrap();
+            xforms[i] = xforms[nearest];
+            problems.push(format!(
+                "transform sample {} is singular, e.g. from a scale of zero, \
+                 and was replaced by sample {}",
+                i, nearest
+            ));
+        }
+    }
+
+    // Mirroring is fine, but changing handedness during the shutter means
+    // passing through a zero scale somewhere in between.
+    let mirrored = xforms.iter().filter(|x| x.determinant() < 0.0).count();
+    if mirrored > 0 && mirrored < xforms.len() {
+        problems.push(
+            "transform samples are mirrored at some times and not others, so \
+             the instance collapses through a zero scale during the shutter"
+                .to_string(),
+        );
+    }
+
+    Ok(problems)
+}
+
+/// Whether `name` is the name of a node in the assembly `tree` with a type
+/// that isn't known, and so wasn't parsed.
+/// Checks that a `SurfaceShaderBind` at `byte_offset` names a surface
+/// shader that's already been added to the assembly.
+fn check_surface_shader(
+    builder: &AssemblyBuilder,
+    name: &str,
+    byte_offset: usize,
+) -> Result<(), PsyParseError> {
+    if builder.surface_shader_exists(name) {
+        Ok(())
+    } else {
+        Err(PsyParseError::IncorrectLeafData(
+            byte_offset,
+            "SurfaceShaderBind should name a surface shader defined before it, in the \
+             same assembly.",
+        ))
+    }
+}
+
+fn is_unknown_data(tree: &DataTree, name: &str) -> bool {
+    tree.iter_children().any(|child| match *child {
+        DataTree::Internal {
+            type_name,
+            ident: Some(ident),
+            ..
+        } => ident == name && !is_known_node("Assembly", type_name),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::{Exposure, PerspectiveCamera},
+        math::Point,
+    };
+
+    #[test]
+    fn singular_transforms_are_replaced() {
+        let moving = Matrix4x4::from_location(Point::new(1.0, 0.0, 0.0));
+        let scale = |x, y, z| {
+            Matrix4x4::new_from_values(
+                x, 0.0, 0.0, 0.0, 0.0, y, 0.0, 0.0, 0.0, 0.0, z, 0.0, 0.0, 0.0, 0.0, 1.0,
+            )
+        };
+        let flat = scale(1.0, 1.0, 0.0);
+        let mirrored = scale(-1.0, 1.0, 1.0);
+
+        let mut xforms = [flat, Matrix4x4::new(), flat, moving];
+        let problems = fix_transforms(&mut xforms).unwrap();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("sample 0 is singular"));
+        assert!(xforms[0].aprx_eq(Matrix4x4::new(), 0.0));
+        assert!(xforms[2].aprx_eq(Matrix4x4::new(), 0.0));
+
+        // Consistently mirrored is fine, flipping partway isn't.
+        assert!(fix_transforms(&mut [mirrored, mirrored])
+            .unwrap()
+            .is_empty());
+        let problems = fix_transforms(&mut [mirrored, Matrix4x4::new()]).unwrap();
+        assert!(problems[0].contains("mirrored at some times"));
+
+        assert!(fix_transforms(&mut [flat]).is_err());
+        assert!(fix_transforms(&mut []).unwrap().is_empty());
+    }
+
+    #[test]
+    fn instance_arrays() {
+        let arena = Arena::new();
+        let camera = PerspectiveCamera::new(
+            &arena,
+            &[Matrix4x4::new()],
+            &[1.0],
+            &[],
+            &[],
+            Exposure::default(),
+        );
+        let dicer = DicingCamera::new(&camera, (64, 64), 1.0, 1.0);
+        let parse = |transforms: &str| -> Result<(usize, usize, usize), PsyParseError> {
+            let text = format!(
+                "Assembly {{
+                    MeshSurface $quad {{
+                        Vertices [0 0 0  1 0 0  0 1 0  1 1 0]
+                        FaceVertCounts [4]
+                        FaceVertIndices [0 1 3 2]
+                    }}
+                    InstanceArray {{
+                        Data [$quad]
+                        {}
+                    }}
+                }}",
+                transforms
+            );
+            let tree = DataTree::from_str(&text).unwrap();
+            let mut warnings = Vec::new();
+            let root = parse_assembly(
+                &arena,
+                tree.iter_children().next().unwrap(),
+                &dicer,
+                &[Matrix4x4::new()],
+                None,
+                false,
+                Sanitize::Off,
+                &mut warnings,
+            )?;
+            let array = &root.instance_arrays[0];
+            Ok((array.len(), array.sample_count, warnings.len()))
+        };
+        let identity = "1 0 0 0  0 1 0 0  0 0 1 0  0 0 0 1";
+        let flat = "1 0 0 0  0 1 0 0  0 0 0 0  0 0 0 1";
+
+        // Two elements, with two time samples each.
+        let result = parse(&format!(
+            "Transforms [{0} {0}] Transforms [{0} {0}]",
+            identity
+        ));
+        assert_eq!(result.unwrap(), (2, 2, 0));
+
+        // Singular elements are skipped.
+        let result = parse(&format!("Transforms [{} {}]", identity, flat));
+        assert_eq!(result.unwrap(), (1, 1, 1));
+
+        // Samples must agree on the element count.
+        let result = parse(&format!("Transforms [{0} {0}] Transforms [{0}]", identity));
+        assert!(matches!(result, Err(PsyParseError::IncorrectLeafData(..))));
+    }
+
+    #[test]
+    fn unknown_surface_shaders_are_errors() {
+        let arena = Arena::new();
+        let camera = PerspectiveCamera::new(
+            &arena,
+            &[Matrix4x4::new()],
+            &[1.0],
+            &[],
+            &[],
+            Exposure::default(),
+        );
+        let dicer = DicingCamera::new(&camera, (64, 64), 1.0, 1.0);
+        let parse = |instance: &str| -> Result<(), PsyParseError> {
+            let text = format!(
+                "Assembly {{
+                    SurfaceShader $clay {{
+                        Type [Lambert]
+                        Color [rec709, 0.8 0.8 0.8]
+                    }}
+                    MeshSurface $quad {{
+                        Vertices [0 0 0  1 0 0  0 1 0  1 1 0]
+                        FaceVertCounts [4]
+                        FaceVertIndices [0 1 3 2]
+                    }}
+                    {}
+                }}",
+                instance
+            );
+            let tree = DataTree::from_str(&text).unwrap();
+            parse_assembly(
+                &arena,
+                tree.iter_children().next().unwrap(),
+                &dicer,
+                &[Matrix4x4::new()],
+                None,
+                false,
+                Sanitize::Off,
+                &mut Vec::new(),
+            )?;
+            Ok(())
+        };
+
+        assert!(parse("Instance { Data [$quad] SurfaceShaderOverride [$clay] }").is_ok());
+        assert!(matches!(
+            parse("Instance { Data [$quad] SurfaceShaderOverride [$wax] }"),
+            Err(PsyParseError::IncorrectLeafData(..))
+        ));
+
+        let identity = "1 0 0 0  0 1 0 0  0 0 1 0  0 0 0 1";
+        let array = |shader| {
+            format!(
+                "InstanceArray {{ Data [$quad] SurfaceShaderBind [{}] Transforms [{}] }}",
+                shader, identity
+            )
+        };
+        assert!(parse(&array("$clay")).is_ok());
+        assert!(matches!(
+            parse(&array("$wax")),
+            Err(PsyParseError::IncorrectLeafData(..))
+        ));
+
+        let grouped = |shader| {
+            format!(
+                "MeshSurface $grouped {{
+                    Vertices [0 0 0  1 0 0  0 1 0  1 1 0]
+                    FaceVertCounts [4]
+                    FaceVertIndices [0 1 3 2]
+                    FaceGroup {{ SurfaceShaderBind [{}] Faces [0] }}
+                }}",
+                shader
+            )
+        };
+        assert!(parse(&grouped("$clay")).is_ok());
+        assert!(matches!(
+            parse(&grouped("$wax")),
+            Err(PsyParseError::IncorrectLeafData(..))
+        ));
+    }
+
+    #[test]
+    fn static_transforms_are_folded() {
+        let arena = Arena::new();
+        let camera = PerspectiveCamera::new(
+            &arena,
+            &[Matrix4x4::new()],
+            &[1.0],
+            &[],
+            &[],
+            Exposure::default(),
+        );
+        let dicer = DicingCamera::new(&camera, (64, 64), 1.0, 1.0);
+        let tree = DataTree::from_str(
+            "Assembly {
+                Assembly $outer {
+                    Assembly $inner {
+                        MeshSurface $quad {
+                            Vertices [0 0 0  1 0 0  0 1 0  1 1 0]
+                            FaceVertCounts [4]
+                            FaceVertIndices [0 1 3 2]
+                        }
+                        Instance {
+                            Data [$quad]
+                            Transform [1 0 0 0  0 1 0 0  0 0 1 0  0 0 1 1]
+                            Transform [1 0 0 0  0 1 0 0  0 0 1 0  0 0 2 1]
+                        }
+                    }
+                    Instance { Data [$inner] }
+                    Instance { Data [$inner] Transform [1 0 0 0  0 1 0 0  0 0 1 0  2 0 0 1] }
+                }
+                Instance { Data [$outer] Transform [1 0 0 0  0 1 0 0  0 0 1 0  0 3 0 1] }
+            }",
+        )
+        .unwrap();
+        let root = parse_assembly(
+            &arena,
+            tree.iter_children().next().unwrap(),
+            &dicer,
+            &[Matrix4x4::new()],
+            None,
+            false,
+            Sanitize::Off,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        let matrix = |x: f32, y: f32, z: f32| {
+            parse_matrix(&format!("1 0 0 0  0 1 0 0  0 0 1 0  {} {} {} 1", x, y, z)).unwrap()
+        };
+        let xforms = |asmb: &Assembly, i: usize| {
+            // The instances are reordered for the BVH, but keep their ids.
+            let instance = asmb.instances.iter().find(|inst| inst.id == i).unwrap();
+            instance
+                .transform_indices
+                .map(|(start, end)| asmb.xforms[start..end].to_vec())
+                .unwrap_or_default()
+        };
+
+        // The outer assembly is only instanced once, so its transform
+        // moves into its instances.
+        assert!(xforms(&root, 0).is_empty());
+        let outer = &root.assemblies[0];
+        let outer_xforms = [xforms(outer, 0), xforms(outer, 1)];
+        assert_eq!(outer_xforms[0].len(), 1);
+        assert!(outer_xforms[0][0].aprx_eq(matrix(0.0, 3.0, 0.0), 1.0e-6));
+        assert!(outer_xforms[1][0].aprx_eq(matrix(0.0, 3.0, 0.0) * matrix(2.0, 0.0, 0.0), 1.0e-6));
+
+        // The inner assembly is instanced twice, so it's left alone.
+        let inner_xforms = xforms(&outer.assemblies[0], 0);
+        assert_eq!(inner_xforms.len(), 2);
+        assert!(inner_xforms[1].aprx_eq(matrix(0.0, 0.0, 2.0), 1.0e-6));
+    }
+}
@@ -0,0 +1,47 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::{combinator::all_consuming, sequence::tuple, IResult};
+
+use kioku::Arena;
+
+use crate::{math::Point, surface::bilinear_patch::BilinearPatch};
+
+use super::{basics::ws_f32, psy::PsyParseError, DataTree};
+
+pub fn parse_bilinear_patch<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+) -> Result<BilinearPatch<'a>, PsyParseError> {
+    // Get the control points, one Vertices field per time sample
+    let mut control_points = Vec::new();
+    for (_, text, byte_offset) in tree.iter_leaf_children_with_type("Vertices") {
+        let point = || tuple((ws_f32, ws_f32, ws_f32));
+        if let IResult::Ok((_, (p0, p1, p2, p3))) =
+            all_consuming(tuple((point(), point(), point(), point())))(text)
+        {
+            control_points.push([
+                Point::new(p0.0, p0.1, p0.2),
+                Point::new(p1.0, p1.1, p1.2),
+                Point::new(p2.0, p2.1, p2.2),
+                Point::new(p3.0, p3.1, p3.2),
+            ]);
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Vertices should be the patch's four corner points, in the form \
+                 '[x y z  x y z  x y z  x y z]'.",
+            ));
+        }
+    }
+
+    if control_points.is_empty() {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected a Vertices field in BilinearPatch.",
+        ));
+    }
+
+    Ok(BilinearPatch::new(arena, &control_points))
+}
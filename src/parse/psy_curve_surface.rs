@@ -0,0 +1,300 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::{combinator::all_consuming, sequence::tuple, IResult};
+
+use kioku::Arena;
+
+use crate::{
+    math::Point,
+    surface::{
+        curves::Curves,
+        groom::{grow_children, GroomSettings, Strands},
+    },
+};
+
+use super::{
+    basics::{ws_f32, ws_u32, ws_usize},
+    psy::PsyParseError,
+    psy_mesh_surface::parse_primvar,
+    DataTree,
+};
+
+pub fn parse_curve_surface<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+) -> Result<Curves<'a>, PsyParseError> {
+    let strands = parse_strands(tree)?;
+
+    // Get primvars.  Each strand counts as a face, and each of its
+    // vertices as its own face-vertex.
+    let mut primvars = Vec::new();
+    for child in tree.iter_children_with_type("Primvar") {
+        primvars.push(parse_primvar(
+            arena,
+            child,
+            (
+                strands.vert_counts.len(),
+                strands.verts.len(),
+                strands.verts.len(),
+            ),
+        )?);
+    }
+
+    let curves = Curves::from_strands(arena, &strands.verts, &strands.radii, &strands.vert_counts);
+    if primvars.is_empty() {
+        Ok(curves)
+    } else {
+        Ok(curves.with_primvars(arena, &primvars, &strands.vert_counts))
+    }
+}
+
+/// Parses a groom: guide strands and a scalp, from which child strands
+/// are grown.
+pub fn parse_groom_surface<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+) -> Result<Curves<'a>, PsyParseError> {
+    let guides = parse_strands(tree)?;
+    if guides.vert_counts.is_empty() {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected at least one guide strand in GroomSurface.",
+        ));
+    }
+
+    // Get the scalp, as triangles
+    let mut scalp_verts = Vec::new();
+    if let Some((_, mut text, _)) = tree.iter_leaf_children_with_type("ScalpVertices").next() {
+        while let IResult::Ok((remaining, vert)) = tuple((ws_f32, ws_f32, ws_f32))(text) {
+            text = remaining;
+
+            scalp_verts.push(Point::new(vert.0, vert.1, vert.2));
+        }
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected a ScalpVertices field in GroomSurface.",
+        ));
+    }
+    let mut face_vert_counts = Vec::new();
+    if let Some((_, mut text, _)) = tree
+        .iter_leaf_children_with_type("ScalpFaceVertCounts")
+        .next()
+    {
+        while let IResult::Ok((remaining, count)) = ws_usize(text) {
+            text = remaining;
+
+            face_vert_counts.push(count);
+        }
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected a ScalpFaceVertCounts field in GroomSurface.",
+        ));
+    }
+    let mut face_vert_indices = Vec::new();
+    let byte_offset = if let Some((_, mut text, byte_offset)) = tree
+        .iter_leaf_children_with_type("ScalpFaceVertIndices")
+        .next()
+    {
+        while let IResult::Ok((remaining, index)) = ws_usize(text) {
+            text = remaining;
+
+            face_vert_indices.push(index);
+        }
+        byte_offset
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected a ScalpFaceVertIndices field in GroomSurface.",
+        ));
+    };
+    if face_vert_counts.iter().any(|&count| count < 3)
+        || face_vert_counts.iter().sum::<usize>() != face_vert_indices.len()
+        || face_vert_indices.iter().any(|&i| i >= scalp_verts.len())
+    {
+        return Err(PsyParseError::IncorrectLeafData(
+            byte_offset,
+            "The scalp should have at least three vertices per face, and its face \
+             vertex indices should match its face vertex counts and vertices.",
+        ));
+    }
+    let mut scalp = Vec::new();
+    let mut ii = 0;
+    for &count in &face_vert_counts {
+        // Fan triangulation
+        for fi in 1..(count - 1) {
+            scalp.push((
+                scalp_verts[face_vert_indices[ii]],
+                scalp_verts[face_vert_indices[ii + fi]],
+                scalp_verts[face_vert_indices[ii + fi + 1]],
+            ));
+        }
+        ii += count;
+    }
+    if scalp.is_empty() {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected at least one face in the scalp of GroomSurface.",
+        ));
+    }
+
+    // Get the settings for growing the children
+    let child_count = match tree.iter_leaf_children_with_type("ChildCount").next() {
+        Some((_, contents, byte_offset)) => match all_consuming(ws_usize)(contents) {
+            IResult::Ok((_, count)) if count > 0 => count,
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "ChildCount should be a positive integer, in the form '[count]'.",
+                ));
+            }
+        },
+        None => {
+            return Err(PsyParseError::MissingNode(
+                tree.byte_offset(),
+                "Expected a ChildCount field in GroomSurface.",
+            ));
+        }
+    };
+    let setting = |leaf_type: &'static str, default: f32, error: &'static str| match tree
+        .iter_leaf_children_with_type(leaf_type)
+        .next()
+    {
+        Some((_, contents, byte_offset)) => match all_consuming(ws_f32)(contents) {
+            IResult::Ok((_, n)) if n >= 0.0 && n.is_finite() => Ok(n),
+            _ => Err(PsyParseError::IncorrectLeafData(byte_offset, error)),
+        },
+        None => Ok(default),
+    };
+    let clump = setting(
+        "Clump",
+        0.0,
+        "Clump should be a number between 0.0 and 1.0, in the form '[amount]'.",
+    )?
+    .min(1.0);
+    let frizz = setting(
+        "Frizz",
+        0.0,
+        "Frizz should be a non-negative number, in the form '[amount]'.",
+    )?;
+    let frizz_frequency = setting(
+        "FrizzFrequency",
+        4.0,
+        "FrizzFrequency should be a non-negative number, in the form '[waves]'.",
+    )?;
+    let seed = match tree.iter_leaf_children_with_type("Seed").next() {
+        Some((_, contents, byte_offset)) => match all_consuming(ws_u32)(contents) {
+            IResult::Ok((_, seed)) => seed,
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Seed should be an integer, in the form '[seed]'.",
+                ));
+            }
+        },
+        None => 0,
+    };
+
+    let children = grow_children(
+        &scalp,
+        &guides,
+        &GroomSettings {
+            child_count: child_count,
+            clump: clump,
+            frizz: frizz,
+            frizz_frequency: frizz_frequency,
+            seed: seed,
+        },
+    );
+
+    Ok(Curves::from_strands(
+        arena,
+        &children.verts,
+        &children.radii,
+        &children.vert_counts,
+    ))
+}
+
+/// Parses the strands of a CurveSurface or GroomSurface.
+fn parse_strands(tree: &DataTree) -> Result<Strands, PsyParseError> {
+    // Get verts
+    if tree.iter_leaf_children_with_type("Vertices").count() > 1 {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "Strands don't support deformation motion blur yet, so there \
+             should be only one Vertices field.",
+        ));
+    }
+    let mut verts = Vec::new();
+    if let Some((_, mut text, _)) = tree.iter_leaf_children_with_type("Vertices").next() {
+        while let IResult::Ok((remaining, vert)) = tuple((ws_f32, ws_f32, ws_f32))(text) {
+            text = remaining;
+
+            verts.push(Point::new(vert.0, vert.1, vert.2));
+        }
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected a Vertices field.",
+        ));
+    }
+
+    // Get radii, either one per vertex or one for all of them
+    let mut radii = Vec::new();
+    if let Some((_, mut text, byte_offset)) = tree.iter_leaf_children_with_type("Radii").next() {
+        while let IResult::Ok((remaining, radius)) = ws_f32(text) {
+            text = remaining;
+
+            radii.push(radius);
+        }
+        if radii.len() == 1 {
+            radii.resize(verts.len(), radii[0]);
+        }
+        if radii.len() != verts.len() || radii.iter().any(|&r| !(r >= 0.0 && r.is_finite())) {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Radii should be either one non-negative radius, or one for each vertex.",
+            ));
+        }
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected a Radii field.",
+        ));
+    }
+
+    // Get strand vert counts
+    let mut strand_vert_counts = Vec::new();
+    if let Some((_, mut text, byte_offset)) =
+        tree.iter_leaf_children_with_type("StrandVertCounts").next()
+    {
+        while let IResult::Ok((remaining, count)) = ws_usize(text) {
+            text = remaining;
+
+            strand_vert_counts.push(count);
+        }
+        if strand_vert_counts.iter().any(|&count| count < 2)
+            || strand_vert_counts.iter().sum::<usize>() != verts.len()
+        {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "StrandVertCounts should have at least two vertices per strand, and add \
+                 up to the number of vertices.",
+            ));
+        }
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected a StrandVertCounts field.",
+        ));
+    }
+
+    Ok(Strands {
+        verts: verts,
+        radii: radii,
+        vert_counts: strand_vert_counts,
+    })
+}
@@ -7,24 +7,71 @@ use nom::{combinator::all_consuming, sequence::tuple, IResult};
 use kioku::Arena;
 
 use crate::{
-    light::{DistantDiskLight, RectangleLight, SphereLight},
-    math::Vector,
+    color::Color,
+    image::read_pfm,
+    light::{
+        DiskLight, DistantDiskLight, EnvironmentLight, EnvironmentMapping, LightUnits, PointLight,
+        RectangleLight, SphereLight, TubeLight, SUN_IRRADIANCE, SUN_RADIUS, SUN_TEMPERATURE,
+    },
+    math::{Matrix4x4, Vector},
 };
 
 use super::{
-    basics::ws_f32,
-    psy::{parse_color, PsyParseError},
+    basics::{ws_bool, ws_f32},
+    psy::{parse_color, parse_quoted_string, PsyParseError},
     DataTree,
 };
 
+/// Parses the contents of a light's `Units` leaf.
+fn parse_light_units(contents: &str, byte_offset: usize) -> Result<LightUnits, PsyParseError> {
+    match contents.trim() {
+        "normalized" => Ok(LightUnits::Normalized),
+        "power" => Ok(LightUnits::Power),
+        "radiance" => Ok(LightUnits::Radiance),
+        _ => Err(PsyParseError::UnknownVariant(
+            byte_offset,
+            "Units should be one of 'normalized', 'power', or 'radiance'.",
+        )),
+    }
+}
+
+/// Checks that a new radius time sample agrees with the earlier ones
+/// about whether the light has any size.
+///
+/// A radius of zero makes a light a delta light, which isn't something
+/// that can change over time: light sampling and MIS need to know for all
+/// times which kind of light it is.
+fn check_radius(radii: &[f32], radius: f32, byte_offset: usize) -> Result<(), PsyParseError> {
+    if let Some(&first) = radii.first() {
+        if (first <= 0.0) != (radius <= 0.0) {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Radius should be zero for either all time samples or none of them.",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Parses a distant disk light.  `axes` rotates its directions from the
+/// scene's axes to the renderer's.
+///
+/// Its brightness is either given by `Color`, as for other lights, or as
+/// the `Irradiance` it delivers to a surface facing it.  `Preset [sun]`
+/// makes it the size and color of the sun, and as bright as clear midday
+/// sunlight, unless those are given too.
 pub fn parse_distant_disk_light<'a>(
     arena: &'a Arena,
     tree: &'a DataTree,
+    axes: &Matrix4x4,
 ) -> Result<DistantDiskLight<'a>, PsyParseError> {
     if let DataTree::Internal { ref children, .. } = *tree {
         let mut radii = Vec::new();
         let mut directions = Vec::new();
         let mut colors = Vec::new();
+        let mut irradiances = Vec::new();
+        let mut units = LightUnits::default();
+        let mut is_sun = false;
 
         // Parse
         for child in children.iter() {
@@ -36,6 +83,7 @@ pub fn parse_distant_disk_light<'a>(
                     byte_offset,
                 } if type_name == "Radius" => {
                     if let IResult::Ok((_, radius)) = all_consuming(ws_f32)(contents) {
+                        check_radius(&radii, radius, byte_offset)?;
                         radii.push(radius);
                     } else {
                         // Found radius, but its contents is not in the right format
@@ -52,7 +100,7 @@ pub fn parse_distant_disk_light<'a>(
                     if let IResult::Ok((_, direction)) =
                         all_consuming(tuple((ws_f32, ws_f32, ws_f32)))(contents)
                     {
-                        directions.push(Vector::new(direction.0, direction.1, direction.2));
+                        directions.push(Vector::new(direction.0, direction.1, direction.2) * *axes);
                     } else {
                         // Found direction, but its contents is not in the right format
                         return Err(PsyParseError::UnknownError(byte_offset));
@@ -73,11 +121,219 @@ pub fn parse_distant_disk_light<'a>(
                     }
                 }
 
+                // Irradiance
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Irradiance" => {
+                    if let Ok(color) = parse_color(contents) {
+                        irradiances.push(color);
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Irradiance should be a color.",
+                        ));
+                    }
+                }
+
+                // Units
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Units" => {
+                    units = parse_light_units(contents, byte_offset)?;
+                    if units == LightUnits::Power {
+                        return Err(PsyParseError::UnknownVariant(
+                            byte_offset,
+                            "DistantDiskLight doesn't support power units.",
+                        ));
+                    }
+                }
+
+                // Preset
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Preset" => {
+                    if contents.trim() == "sun" {
+                        is_sun = true;
+                    } else {
+                        return Err(PsyParseError::UnknownVariant(
+                            byte_offset,
+                            "The only DistantDiskLight Preset is 'sun'.",
+                        ));
+                    }
+                }
+
                 _ => {}
             }
         }
 
-        return Ok(DistantDiskLight::new(arena, &radii, &directions, &colors));
+        // Fill in what the preset provides.
+        if is_sun {
+            if radii.is_empty() {
+                radii.push(SUN_RADIUS);
+            }
+            if colors.is_empty() && irradiances.is_empty() {
+                irradiances.push(Color::new_temperature(SUN_TEMPERATURE, SUN_IRRADIANCE));
+            }
+        }
+
+        if radii.is_empty() || directions.is_empty() {
+            return Err(PsyParseError::MissingNode(
+                tree.byte_offset(),
+                "DistantDiskLight should have a Radius and Direction, \
+                 unless it uses a Preset.",
+            ));
+        }
+        if colors.is_empty() && irradiances.is_empty() {
+            return Err(PsyParseError::MissingNode(
+                tree.byte_offset(),
+                "DistantDiskLight should have a Color or Irradiance, \
+                 unless it uses a Preset.",
+            ));
+        }
+        if !colors.is_empty() && !irradiances.is_empty() {
+            return Err(PsyParseError::IncorrectLeafData(
+                tree.byte_offset(),
+                "DistantDiskLight can't have both Color and Irradiance.",
+            ));
+        }
+
+        if irradiances.is_empty() {
+            return Ok(DistantDiskLight::new(
+                arena,
+                &radii,
+                &directions,
+                &colors,
+                units,
+            ));
+        } else {
+            return Ok(DistantDiskLight::from_irradiance(
+                arena,
+                &radii,
+                &directions,
+                &irradiances,
+            ));
+        }
+    } else {
+        return Err(PsyParseError::UnknownError(tree.byte_offset()));
+    }
+}
+
+/// Parses an environment light.  Its image is read from disk right away.
+///
+/// Environment images are always Z-up, regardless of the scene's axes.
+pub fn parse_environment_light<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+) -> Result<EnvironmentLight<'a>, PsyParseError> {
+    if let DataTree::Internal { ref children, .. } = *tree {
+        let mut image = None;
+        let mut mapping = EnvironmentMapping::Equirect;
+        let mut rotation = 0.0;
+        let mut intensity = 1.0;
+        let mut saturation = 1.0;
+        let mut camera_visible = true;
+
+        // Parse
+        for child in children.iter() {
+            match *child {
+                // Image
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Image" => {
+                    let path = parse_quoted_string(contents, byte_offset)?;
+                    if let Ok(img) = read_pfm(path.as_ref()) {
+                        image = Some(img);
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Couldn't read the environment image.  It should be a PFM file.",
+                        ));
+                    }
+                }
+
+                // Mapping
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Mapping" => {
+                    mapping = match contents.trim() {
+                        "equirect" => EnvironmentMapping::Equirect,
+                        "mirror_ball" => EnvironmentMapping::MirrorBall,
+                        "angular" => EnvironmentMapping::Angular,
+                        _ => {
+                            return Err(PsyParseError::UnknownVariant(
+                                byte_offset,
+                                "Mapping should be one of 'equirect', 'mirror_ball', \
+                                 or 'angular'.",
+                            ));
+                        }
+                    };
+                }
+
+                // Rotation, intensity, and saturation
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Rotation"
+                    || type_name == "Intensity"
+                    || type_name == "Saturation" =>
+                {
+                    if let IResult::Ok((_, n)) = all_consuming(ws_f32)(contents) {
+                        match type_name {
+                            "Rotation" => rotation = n.to_radians(),
+                            "Intensity" => intensity = n,
+                            _ => saturation = n,
+                        }
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Rotation, Intensity, and Saturation should each be \
+                             a single number.",
+                        ));
+                    }
+                }
+
+                // Camera visibility
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "CameraVisible" => {
+                    if let IResult::Ok((_, visible)) = all_consuming(ws_bool)(contents) {
+                        camera_visible = visible;
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "CameraVisible should be 'true' or 'false'.",
+                        ));
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        if let Some(image) = image {
+            return Ok(EnvironmentLight::new(
+                arena, &image, mapping, rotation, intensity, saturation,
+            )
+            .with_camera_visibility(camera_visible));
+        } else {
+            return Err(PsyParseError::MissingNode(
+                tree.byte_offset(),
+                "EnvironmentLight should have an Image.",
+            ));
+        }
     } else {
         return Err(PsyParseError::UnknownError(tree.byte_offset()));
     }
@@ -90,6 +346,7 @@ pub fn parse_sphere_light<'a>(
     if let DataTree::Internal { ref children, .. } = *tree {
         let mut radii = Vec::new();
         let mut colors = Vec::new();
+        let mut units = LightUnits::default();
 
         // Parse
         for child in children.iter() {
@@ -101,6 +358,7 @@ pub fn parse_sphere_light<'a>(
                     byte_offset,
                 } if type_name == "Radius" => {
                     if let IResult::Ok((_, radius)) = all_consuming(ws_f32)(contents) {
+                        check_radius(&radii, radius, byte_offset)?;
                         radii.push(radius);
                     } else {
                         // Found radius, but its contents is not in the right format
@@ -122,11 +380,20 @@ pub fn parse_sphere_light<'a>(
                     }
                 }
 
+                // Units
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Units" => {
+                    units = parse_light_units(contents, byte_offset)?;
+                }
+
                 _ => {}
             }
         }
 
-        return Ok(SphereLight::new(arena, &radii, &colors));
+        return Ok(SphereLight::new(arena, &radii, &colors, units));
     } else {
         return Err(PsyParseError::UnknownError(tree.byte_offset()));
     }
@@ -139,6 +406,9 @@ pub fn parse_rectangle_light<'a>(
     if let DataTree::Internal { ref children, .. } = *tree {
         let mut dimensions = Vec::new();
         let mut colors = Vec::new();
+        let mut units = LightUnits::default();
+        let mut two_sided = true;
+        let mut spread = std::f32::consts::PI;
 
         // Parse
         for child in children.iter() {
@@ -173,12 +443,293 @@ pub fn parse_rectangle_light<'a>(
                     }
                 }
 
+                // TwoSided
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "TwoSided" => {
+                    if let IResult::Ok((_, b)) = all_consuming(ws_bool)(contents) {
+                        two_sided = b;
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "TwoSided should be either 'true' or 'false'.",
+                        ));
+                    }
+                }
+
+                // Spread
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Spread" => {
+                    if let IResult::Ok((_, angle)) = all_consuming(ws_f32)(contents) {
+                        // Convert from degrees to radians
+                        spread = angle.max(0.0).min(180.0) * (std::f32::consts::PI / 180.0);
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Spread should be an angle in degrees, in the form '[angle]'.",
+                        ));
+                    }
+                }
+
+                // Units
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Units" => {
+                    units = parse_light_units(contents, byte_offset)?;
+                }
+
+                _ => {}
+            }
+        }
+
+        return Ok(RectangleLight::new(
+            arena,
+            &dimensions,
+            &colors,
+            two_sided,
+            spread,
+            units,
+        ));
+    } else {
+        return Err(PsyParseError::UnknownError(tree.byte_offset()));
+    }
+}
+
+pub fn parse_disk_light<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+) -> Result<DiskLight<'a>, PsyParseError> {
+    if let DataTree::Internal { ref children, .. } = *tree {
+        let mut radii = Vec::new();
+        let mut colors = Vec::new();
+        let mut units = LightUnits::default();
+        let mut two_sided = true;
+
+        // Parse
+        for child in children.iter() {
+            match *child {
+                // Radius
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Radius" => {
+                    if let IResult::Ok((_, radius)) = all_consuming(ws_f32)(contents) {
+                        radii.push(radius);
+                    } else {
+                        // Found radius, but its contents is not in the right format
+                        return Err(PsyParseError::UnknownError(byte_offset));
+                    }
+                }
+
+                // Color
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Color" => {
+                    if let Ok(color) = parse_color(contents) {
+                        colors.push(color);
+                    } else {
+                        // Found color, but its contents is not in the right format
+                        return Err(PsyParseError::UnknownError(byte_offset));
+                    }
+                }
+
+                // TwoSided
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "TwoSided" => {
+                    if let IResult::Ok((_, b)) = all_consuming(ws_bool)(contents) {
+                        two_sided = b;
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "TwoSided should be either 'true' or 'false'.",
+                        ));
+                    }
+                }
+
+                // Units
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Units" => {
+                    units = parse_light_units(contents, byte_offset)?;
+                }
+
                 _ => {}
             }
         }
 
-        return Ok(RectangleLight::new(arena, &dimensions, &colors));
+        return Ok(DiskLight::new(arena, &radii, &colors, two_sided, units));
     } else {
         return Err(PsyParseError::UnknownError(tree.byte_offset()));
     }
 }
+
+pub fn parse_tube_light<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+) -> Result<TubeLight<'a>, PsyParseError> {
+    if let DataTree::Internal { ref children, .. } = *tree {
+        let mut radii = Vec::new();
+        let mut lengths = Vec::new();
+        let mut colors = Vec::new();
+        let mut units = LightUnits::default();
+
+        // Parse
+        for child in children.iter() {
+            match *child {
+                // Radius
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Radius" => {
+                    if let IResult::Ok((_, radius)) = all_consuming(ws_f32)(contents) {
+                        radii.push(radius);
+                    } else {
+                        // Found radius, but its contents is not in the right format
+                        return Err(PsyParseError::UnknownError(byte_offset));
+                    }
+                }
+
+                // Length
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Length" => {
+                    if let IResult::Ok((_, length)) = all_consuming(ws_f32)(contents) {
+                        lengths.push(length);
+                    } else {
+                        // Found length, but its contents is not in the right format
+                        return Err(PsyParseError::UnknownError(byte_offset));
+                    }
+                }
+
+                // Color
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Color" => {
+                    if let Ok(color) = parse_color(contents) {
+                        colors.push(color);
+                    } else {
+                        // Found color, but its contents is not in the right format
+                        return Err(PsyParseError::UnknownError(byte_offset));
+                    }
+                }
+
+                // Units
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Units" => {
+                    units = parse_light_units(contents, byte_offset)?;
+                }
+
+                _ => {}
+            }
+        }
+
+        if radii.is_empty() || lengths.is_empty() {
+            return Err(PsyParseError::MissingNode(
+                tree.byte_offset(),
+                "TubeLight must have both a Radius and a Length.",
+            ));
+        }
+
+        return Ok(TubeLight::new(arena, &radii, &lengths, &colors, units));
+    } else {
+        return Err(PsyParseError::UnknownError(tree.byte_offset()));
+    }
+}
+
+pub fn parse_point_light<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+) -> Result<PointLight<'a>, PsyParseError> {
+    if let DataTree::Internal { ref children, .. } = *tree {
+        let mut colors = Vec::new();
+        let mut units = LightUnits::default();
+
+        // Parse
+        for child in children.iter() {
+            match *child {
+                // Color
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Color" => {
+                    if let Ok(color) = parse_color(contents) {
+                        colors.push(color);
+                    } else {
+                        // Found color, but its contents is not in the right format
+                        return Err(PsyParseError::UnknownError(byte_offset));
+                    }
+                }
+
+                // Units
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Units" => {
+                    units = parse_light_units(contents, byte_offset)?;
+                    if units == LightUnits::Radiance {
+                        return Err(PsyParseError::UnknownVariant(
+                            byte_offset,
+                            "PointLight doesn't support radiance units.",
+                        ));
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        return Ok(PointLight::new(arena, &colors, units));
+    } else {
+        return Err(PsyParseError::UnknownError(tree.byte_offset()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn radius_is_zero_for_all_or_no_time_samples() {
+        let arena = Arena::new();
+        let parse = |radii: &[&str]| {
+            let mut text = String::from("SphereLight { Color [rec709, 1 1 1] ");
+            for radius in radii {
+                text.push_str(&format!("Radius [{}] ", radius));
+            }
+            text.push('}');
+            let tree = DataTree::from_str(&text).unwrap();
+            let light = tree.iter_children().next().unwrap();
+            parse_sphere_light(&arena, light).is_ok()
+        };
+
+        assert!(parse(&["0.5", "1.0"]));
+        assert!(parse(&["0", "0"]));
+        assert!(!parse(&["0.5", "0"]));
+        assert!(!parse(&["0", "1.0"]));
+    }
+}
@@ -1,19 +1,24 @@
 #![allow(dead_code)]
 
-use std::result::Result;
+use std::{collections::HashMap, result::Result};
 
 use nom::{sequence::tuple, IResult};
 
 use kioku::Arena;
 
 use crate::{
-    math::{Normal, Point},
-    surface::triangle_mesh::TriangleMesh,
+    math::{cross, dot, Normal, Point},
+    render_settings::Sanitize,
+    surface::{
+        primvar::{Interpolation, Primvar, PrimvarData, PrimvarType},
+        triangle_mesh::TriangleMesh,
+    },
 };
 
 use super::{
-    basics::{ws_f32, ws_usize},
+    basics::{ws_f32, ws_i32, ws_usize},
     psy::PsyParseError,
+    psy_schema::PsyParseWarning,
     DataTree,
 };
 
@@ -24,9 +29,15 @@ use super::{
 //    accel: BVH,
 // }
 
+/// Parses a mesh, checking its geometry according to `sanitize`.
+///
+/// Problems that the mesh can still be rendered with are added to
+/// `warnings`.
 pub fn parse_mesh_surface<'a>(
     arena: &'a Arena,
     tree: &'a DataTree,
+    sanitize: Sanitize,
+    warnings: &mut Vec<PsyParseWarning>,
 ) -> Result<TriangleMesh<'a>, PsyParseError> {
     let mut verts = Vec::new(); // Vec of vecs, one for each time sample
     let mut normals = Vec::new(); // Vec of vecs, on for each time sample
@@ -92,10 +103,55 @@ pub fn parse_mesh_surface<'a>(
         }
     }
 
+    // Get face groups, each bound to a shader.  Faces that are in more
+    // than one group end up in the last.
+    let mut group_shader_names = Vec::new();
+    let mut face_groups = vec![None; face_vert_counts.len()];
+    for group in tree.iter_children_with_type("FaceGroup") {
+        let shader_name = match group
+            .iter_leaf_children_with_type("SurfaceShaderBind")
+            .next()
+        {
+            Some((_, contents, _)) => contents.trim(),
+            None => {
+                return Err(PsyParseError::MissingNode(
+                    group.byte_offset(),
+                    "FaceGroup should have a SurfaceShaderBind.",
+                ))
+            }
+        };
+        for (_, mut text, byte_offset) in group.iter_leaf_children_with_type("Faces") {
+            while let IResult::Ok((remaining, face)) = ws_usize(text) {
+                text = remaining;
+
+                if face >= face_groups.len() {
+                    return Err(PsyParseError::IncorrectLeafData(
+                        byte_offset,
+                        "FaceGroup has a face index that's out of range.",
+                    ));
+                }
+                face_groups[face] = Some(group_shader_names.len());
+            }
+        }
+        group_shader_names.push(shader_name);
+    }
+
+    // Get primvars
+    let mut primvars = Vec::new();
+    for child in tree.iter_children_with_type("Primvar") {
+        primvars.push(parse_primvar(
+            arena,
+            child,
+            (face_vert_counts.len(), vert_count, face_vert_indices.len()),
+        )?);
+    }
+
     // Build triangle mesh
     let mut tri_vert_indices = Vec::new();
+    let mut tri_faces = Vec::new();
+    let mut tri_face_verts = Vec::new();
     let mut ii = 0;
-    for fvc in &face_vert_counts {
+    for (face_i, fvc) in face_vert_counts.iter().enumerate() {
         if *fvc >= 3 {
             // Store the polygon, split up into triangles if >3 verts
             let v1 = ii;
@@ -105,6 +161,8 @@ pub fn parse_mesh_surface<'a>(
                     face_vert_indices[v1 + vi + 1],
                     face_vert_indices[v1 + vi + 2],
                 ));
+                tri_faces.push(face_i);
+                tri_face_verts.push((v1, v1 + vi + 1, v1 + vi + 2));
             }
         } else {
             // TODO: proper error
@@ -114,7 +172,64 @@ pub fn parse_mesh_surface<'a>(
         ii += *fvc;
     }
 
-    Ok(TriangleMesh::from_verts_and_indices(
+    if sanitize != Sanitize::Off {
+        let name = match *tree {
+            DataTree::Internal {
+                ident: Some(ident), ..
+            } => ident,
+            _ => "",
+        };
+        let offset = tree.byte_offset();
+        let problems = check_triangles(&verts, &normals, &tri_vert_indices)
+            .map_err(|problem| PsyParseError::BadGeometry(offset, name.to_string(), problem))?;
+
+        if !problems.degenerate.is_empty() {
+            if sanitize == Sanitize::Fix {
+                warnings.push(PsyParseWarning::BadGeometry(
+                    offset,
+                    name.to_string(),
+                    format!(
+                        "removed {} degenerate triangle(s)",
+                        problems.degenerate.len()
+                    ),
+                ));
+                remove_sorted(&mut tri_vert_indices, &problems.degenerate);
+                remove_sorted(&mut tri_faces, &problems.degenerate);
+                remove_sorted(&mut tri_face_verts, &problems.degenerate);
+            } else {
+                warnings.push(PsyParseWarning::BadGeometry(
+                    offset,
+                    name.to_string(),
+                    format!(
+                        "{} degenerate triangle(s), which --sanitize fix removes",
+                        problems.degenerate.len()
+                    ),
+                ));
+            }
+        }
+        if problems.inconsistent_edges > 0 {
+            warnings.push(PsyParseWarning::BadGeometry(
+                offset,
+                name.to_string(),
+                format!(
+                    "{} edge(s) between triangles with inconsistent winding",
+                    problems.inconsistent_edges
+                ),
+            ));
+        }
+        if problems.flipped > 0 {
+            warnings.push(PsyParseWarning::BadGeometry(
+                offset,
+                name.to_string(),
+                format!(
+                    "{} triangle(s) wound opposite to their normals",
+                    problems.flipped
+                ),
+            ));
+        }
+    }
+
+    let mesh = TriangleMesh::from_verts_and_indices(
         arena,
         &verts,
         &if normals.is_empty() {
@@ -123,5 +238,293 @@ pub fn parse_mesh_surface<'a>(
             Some(normals)
         },
         &tri_vert_indices,
-    ))
+    );
+    let mesh = if group_shader_names.is_empty() {
+        mesh
+    } else {
+        let tri_groups: Vec<_> = tri_faces.iter().map(|&f| face_groups[f]).collect();
+        mesh.with_face_groups(arena, &tri_groups, &group_shader_names)
+    };
+    if primvars.is_empty() {
+        Ok(mesh)
+    } else {
+        Ok(mesh.with_primvars(arena, &primvars, &tri_faces, &tri_face_verts))
+    }
+}
+
+/// Parses a surface's primvar.  `counts` are the surface's face, vertex,
+/// and face-vertex counts, which the primvar's value count has to match,
+/// depending on its interpolation.
+pub fn parse_primvar<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+    counts: (usize, usize, usize),
+) -> Result<Primvar<'a>, PsyParseError> {
+    let name = match *tree {
+        DataTree::Internal {
+            ident: Some(ident), ..
+        } => ident.trim_start_matches('$'),
+        _ => return Err(PsyParseError::UnknownError(tree.byte_offset())),
+    };
+
+    let leaf = |type_name: &'static str, error: &'static str| {
+        tree.iter_leaf_children_with_type(type_name)
+            .next()
+            .ok_or_else(|| PsyParseError::MissingNode(tree.byte_offset(), error))
+    };
+
+    let (_, contents, byte_offset) = leaf("Type", "Primvar should have a Type.")?;
+    let primvar_type = match contents.trim() {
+        "float" => PrimvarType::Float,
+        "float2" => PrimvarType::Float2,
+        "float3" => PrimvarType::Float3,
+        "int" => PrimvarType::Int,
+        _ => {
+            return Err(PsyParseError::UnknownVariant(
+                byte_offset,
+                "Unknown primvar type.  Should be float, float2, float3, or int.",
+            ))
+        }
+    };
+
+    let (_, contents, byte_offset) =
+        leaf("Interpolation", "Primvar should have an Interpolation.")?;
+    let (interpolation, count) = match contents.trim() {
+        "uniform" => (Interpolation::Uniform, counts.0),
+        "varying" => (Interpolation::Varying, counts.1),
+        "facevarying" => (Interpolation::FaceVarying, counts.2),
+        _ => {
+            return Err(PsyParseError::UnknownVariant(
+                byte_offset,
+                "Unknown primvar interpolation.  Should be uniform, varying, or facevarying.",
+            ))
+        }
+    };
+
+    let (_, mut text, byte_offset) = leaf("Values", "Primvar should have Values.")?;
+    let data = if primvar_type == PrimvarType::Int {
+        let mut values = Vec::new();
+        while let IResult::Ok((remaining, value)) = ws_i32(text) {
+            text = remaining;
+            values.push(value);
+        }
+        PrimvarData::Int(arena.copy_slice(&values))
+    } else {
+        let mut values = Vec::new();
+        while let IResult::Ok((remaining, value)) = ws_f32(text) {
+            text = remaining;
+            values.push(value);
+        }
+        PrimvarData::Float(arena.copy_slice(&values))
+    };
+    let value_count = match data {
+        PrimvarData::Float(values) => values.len(),
+        PrimvarData::Int(values) => values.len(),
+    };
+    if value_count != count * primvar_type.components() {
+        return Err(PsyParseError::IncorrectLeafData(
+            byte_offset,
+            "Primvar has the wrong number of values for its type and interpolation.",
+        ));
+    }
+
+    Ok(Primvar {
+        name: name,
+        primvar_type: primvar_type,
+        interpolation: interpolation,
+        data: data,
+    })
+}
+
+/// Removes the elements at the given indices, which must be sorted.
+fn remove_sorted<T>(v: &mut Vec<T>, indices: &[usize]) {
+    let mut i = 0;
+    v.retain(|_| {
+        i += 1;
+        indices.binary_search(&(i - 1)).is_err()
+    });
+}
+
+/// Problems found in a mesh's triangles.
+#[derive(Debug, Default)]
+struct MeshProblems {
+    degenerate: Vec<usize>, // Indices of triangles with no area at any time sample
+    inconsistent_edges: usize, // Edges that two triangles traverse in the same direction
+    flipped: usize,         // Triangles facing away from their vertex normals
+}
+
+/// Checks a mesh's triangles for the problems that bad exports cause.
+///
+/// Non-finite vertices or normals can't be worked around, and are
+/// returned as an error.
+fn check_triangles(
+    verts: &[Vec<Point>],
+    normals: &[Vec<Normal>],
+    triangles: &[(usize, usize, usize)],
+) -> Result<MeshProblems, String> {
+    for (time, tverts) in verts.iter().enumerate() {
+        if let Some(i) = tverts
+            .iter()
+            .position(|v| !(v.x().is_finite() && v.y().is_finite() && v.z().is_finite()))
+        {
+            return Err(format!(
+                "vertex {} at time sample {} is NaN or infinite",
+                i, time
+            ));
+        }
+    }
+    for (time, tnormals) in normals.iter().enumerate() {
+        if let Some(i) = tnormals.iter().position(|n| {
+            !(n.x().is_finite() && n.y().is_finite() && n.z().is_finite())
+                || n.into_vector().length2() == 0.0
+        }) {
+            return Err(format!(
+                "normal {} at time sample {} is zero length, NaN or infinite",
+                i, time
+            ));
+        }
+    }
+
+    let mut problems = MeshProblems::default();
+    let mut edges = HashMap::new();
+    for (tri_i, &(i1, i2, i3)) in triangles.iter().enumerate() {
+        // Degenerate, relative to the triangle's size so that slivers
+        // that only have area due to rounding count too.
+        let is_degenerate = verts.iter().all(|tverts| {
+            let (a, b, c) = (tverts[i1], tverts[i2], tverts[i3]);
+            let longest = (b - a)
+                .length2()
+                .max((c - b).length2())
+                .max((a - c).length2());
+            cross(b - a, c - a).length() <= longest * f32::EPSILON
+        });
+        if is_degenerate {
+            problems.degenerate.push(tri_i);
+            continue;
+        }
+
+        // Facing away from the normals.  This matches the geometric
+        // normal that the mesh computes at hits.
+        if let Some(tnormals) = normals.first() {
+            let (a, b, c) = (verts[0][i1], verts[0][i2], verts[0][i3]);
+            let geo_normal = -cross(a - b, a - c);
+            let normal = tnormals[i1].into_vector()
+                + tnormals[i2].into_vector()
+                + tnormals[i3].into_vector();
+            if dot(geo_normal, normal) < 0.0 {
+                problems.flipped += 1;
+            }
+        }
+
+        // Neighbors with consistent winding traverse their shared edge in
+        // opposite directions.
+        for &edge in &[(i1, i2), (i2, i3), (i3, i1)] {
+            let count = edges.entry(edge).or_insert(0);
+            *count += 1;
+            if *count == 2 {
+                problems.inconsistent_edges += 1;
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_mesh_problems() {
+        // A quad split into two triangles, with the second one wound the
+        // wrong way, plus a triangle with all its vertices in a line.
+        let verts = vec![vec![
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(1.0, 0.0, 0.0),
+            Point::new(0.0, 1.0, 0.0),
+            Point::new(1.0, 1.0, 0.0),
+            Point::new(2.0, 0.0, 0.0),
+        ]];
+        let triangles = [(0, 1, 2), (1, 2, 3), (0, 1, 4)];
+        let problems = check_triangles(&verts, &[], &triangles).unwrap();
+        assert_eq!(problems.degenerate, vec![2]);
+        assert_eq!(problems.inconsistent_edges, 1);
+        assert_eq!(problems.flipped, 0);
+
+        // The two halves of the quad face opposite ways, so one of them
+        // disagrees with any normals.
+        let normals = vec![vec![Normal::new(0.0, 0.0, -1.0); 5]];
+        let problems = check_triangles(&verts, &normals, &triangles).unwrap();
+        assert_eq!(problems.flipped, 1);
+    }
+
+    #[test]
+    fn non_finite_vertices_are_errors() {
+        let verts = vec![vec![
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(1.0, 0.0, 0.0),
+            Point::new(0.0, f32::NAN, 0.0),
+        ]];
+        let error = check_triangles(&verts, &[], &[(0, 1, 2)]).unwrap_err();
+        assert_eq!(error, "vertex 2 at time sample 0 is NaN or infinite");
+    }
+
+    #[test]
+    fn zero_length_normals_are_errors() {
+        let verts = vec![vec![
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(1.0, 0.0, 0.0),
+            Point::new(0.0, 1.0, 0.0),
+        ]];
+        let normals = vec![
+            vec![Normal::new(0.0, 0.0, 1.0); 3],
+            vec![
+                Normal::new(0.0, 0.0, 1.0),
+                Normal::new(0.0, 0.0, 0.0),
+                Normal::new(0.0, 0.0, 1.0),
+            ],
+        ];
+        let error = check_triangles(&verts, &normals, &[(0, 1, 2)]).unwrap_err();
+        assert_eq!(
+            error,
+            "normal 1 at time sample 1 is zero length, NaN or infinite"
+        );
+    }
+
+    #[test]
+    fn primvar_value_counts() {
+        let arena = Arena::new();
+        let mesh_text = |primvar: &str| {
+            format!(
+                "MeshSurface $mesh {{
+                    Vertices [0 0 0  1 0 0  0 1 0  1 1 0]
+                    FaceVertCounts [4]
+                    FaceVertIndices [0 1 3 2]
+                    {}
+                }}",
+                primvar
+            )
+        };
+        let parse = |text: &str| {
+            let tree = DataTree::from_str(text).unwrap();
+            let mesh = tree.iter_children().next().unwrap();
+            parse_mesh_surface(&arena, mesh, Sanitize::Off, &mut Vec::new()).map(|_| ())
+        };
+
+        // One value per vertex, face, and face-vertex respectively.
+        let text = mesh_text(
+            "Primvar $uv { Type [float2] Interpolation [varying] Values [0 0 1 0 0 1 1 1] }
+             Primvar $id { Type [int] Interpolation [uniform] Values [7] }
+             Primvar $w { Type [float] Interpolation [facevarying] Values [1 2 3 4] }",
+        );
+        assert!(parse(&text).is_ok());
+
+        let text = mesh_text(
+            "Primvar $uv { Type [float2] Interpolation [facevarying] Values [0 0 1 0 0 1] }",
+        );
+        assert!(matches!(
+            parse(&text),
+            Err(PsyParseError::IncorrectLeafData(..))
+        ));
+    }
 }
@@ -0,0 +1,526 @@
+//! The node types the psy parser knows about.
+//!
+//! Scenes can contain nodes the parser doesn't know, e.g. from exporters
+//! newer than the renderer, or from typos.  The parser skips those, and
+//! this is used to find them so they can be reported.  It's also printed
+//! by `--schema`, so that exporters can check what they write against it.
+
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use super::{
+    psy::{line_count_to_byte_offset, PsyParseError},
+    DataTree,
+};
+
+/// How many of a node a section may contain.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Count {
+    One,
+    Optional,
+    OneOrMore, // E.g. time samples
+    Any,
+}
+
+impl Count {
+    fn is_required(self) -> bool {
+        matches!(self, Count::One | Count::OneOrMore)
+    }
+
+    fn is_repeatable(self) -> bool {
+        matches!(self, Count::OneOrMore | Count::Any)
+    }
+}
+
+/// A type of node that a section may contain.
+#[derive(Copy, Clone, Debug)]
+pub struct NodeSchema {
+    pub type_name: &'static str,
+    pub count: Count,
+
+    /// For leaves, the form of their contents.  `None` for sections, whose
+    /// contents are listed in `SECTIONS`.
+    pub grammar: Option<&'static str>,
+
+    /// Whether the node needs a `$name` identifier.
+    pub named: bool,
+}
+
+const fn leaf(type_name: &'static str, count: Count, grammar: &'static str) -> NodeSchema {
+    NodeSchema {
+        type_name: type_name,
+        count: count,
+        grammar: Some(grammar),
+        named: false,
+    }
+}
+
+const fn section(type_name: &'static str, count: Count, named: bool) -> NodeSchema {
+    NodeSchema {
+        type_name: type_name,
+        count: count,
+        grammar: None,
+        named: named,
+    }
+}
+
+const COLOR: &str = "[rec709, R G B] | [blackbody, temperature factor] | \
+                     [color_temperature, temperature factor]";
+const TRANSFORM: &str = "[# # # # # # # # # # # # # # # #]";
+const BOOL: &str = "[true] | [false]";
+const LIGHT_UNITS: &str = "[normalized] | [power] | [radiance]";
+
+/// The top-level nodes of a psy file.
+const FILE: &[NodeSchema] = &[section("Scene", Count::Any, false)];
+
+/// Each type of section, with the types of nodes it may contain.
+///
+/// This has to be kept in sync with the parser.  Nodes that aren't listed
+/// here are reported as unknown.
+const SECTIONS: &[(&str, &[NodeSchema])] = &[
+    (
+        "Scene",
+        &[
+            leaf("Name", Count::Optional, "[\"name\"]"),
+            leaf("UnitsScale", Count::Optional, "[scale]"),
+            leaf("UpAxis", Count::Optional, "[Y] | [Z]"),
+            section("Output", Count::One, false),
+            section("RenderSettings", Count::One, false),
+            section("Camera", Count::One, false),
+            section("World", Count::One, false),
+            section("Assembly", Count::One, false),
+        ],
+    ),
+    (
+        "Output",
+        &[
+            leaf("Path", Count::One, "[\"path\"]"),
+            leaf("BitDepth", Count::Optional, "[8] | [16]"),
+            leaf("Transfer", Count::Optional, "[srgb] | [linear] | [pq]"),
+        ],
+    ),
+    (
+        "RenderSettings",
+        &[
+            leaf("Resolution", Count::One, "[width height]"),
+            leaf("Overscan", Count::Optional, "[pixels]"),
+            leaf("PixelAspect", Count::Optional, "[aspect]"),
+            leaf("SamplesPerPixel", Count::One, "[samples]"),
+            leaf("Seed", Count::Optional, "[seed]"),
+            leaf("MaxBounces", Count::Optional, "[bounces]"),
+            leaf("DicingRate", Count::Optional, "[rate]"),
+            leaf(
+                "SensorResponse",
+                Count::Optional,
+                "[cie1931] | [cmos] | [\"path.csv\"]",
+            ),
+            leaf("ImportanceMask", Count::Optional, "[\"path.pfm\"]"),
+            leaf(
+                "WhiteBalance",
+                Count::Optional,
+                "[e|d50|d55|d65|d75|a] | [kelvin] | [kelvin tint]",
+            ),
+        ],
+    ),
+    (
+        "Camera",
+        &[
+            leaf("Fov", Count::OneOrMore, "[degrees]"),
+            leaf("FocalDistance", Count::Any, "[distance]"),
+            leaf("ApertureRadius", Count::Any, "[radius]"),
+            leaf("Transform", Count::OneOrMore, TRANSFORM),
+            leaf("Iso", Count::Optional, "[iso]"),
+            leaf("ShutterSpeed", Count::Optional, "[seconds]"),
+            leaf("FStop", Count::Optional, "[f_stop]"),
+            leaf("SensorWidth", Count::Optional, "[width]"),
+        ],
+    ),
+    (
+        "World",
+        &[
+            section("BackgroundShader", Count::One, false),
+            section("DistantDiskLight", Count::Any, false),
+            section("EnvironmentLight", Count::Any, false),
+            section("Atmosphere", Count::Optional, false),
+            leaf("Backplate", Count::Optional, "[\"path.pfm\"]"),
+        ],
+    ),
+    (
+        "Atmosphere",
+        &[
+            leaf("Scattering", Count::One, COLOR),
+            leaf("Absorption", Count::Optional, COLOR),
+            leaf("Anisotropy", Count::Optional, "[g]"),
+        ],
+    ),
+    (
+        "BackgroundShader",
+        &[
+            leaf("Type", Count::One, "[Color]"),
+            leaf("Color", Count::One, COLOR),
+        ],
+    ),
+    (
+        "DistantDiskLight",
+        &[
+            leaf("Radius", Count::Any, "[radius]"),
+            leaf("Direction", Count::OneOrMore, "[x y z]"),
+            leaf("Color", Count::Any, COLOR),
+            leaf("Irradiance", Count::Any, COLOR),
+            leaf("Units", Count::Optional, "[normalized] | [radiance]"),
+            leaf("Preset", Count::Optional, "[sun]"),
+        ],
+    ),
+    (
+        "EnvironmentLight",
+        &[
+            leaf("Image", Count::One, "[\"path.pfm\"]"),
+            leaf(
+                "Mapping",
+                Count::Optional,
+                "[equirect] | [mirror_ball] | [angular]",
+            ),
+            leaf("Rotation", Count::Optional, "[degrees]"),
+            leaf("Intensity", Count::Optional, "[factor]"),
+            leaf("Saturation", Count::Optional, "[factor]"),
+            leaf("CameraVisible", Count::Optional, "[true] | [false]"),
+        ],
+    ),
+    (
+        "Assembly",
+        &[
+            section("Assembly", Count::Any, true),
+            section("Instance", Count::Any, false),
+            section("InstanceArray", Count::Any, false),
+            section("Space", Count::Any, true),
+            section("SurfaceShader", Count::Any, true),
+            section("MeshSurface", Count::Any, true),
+            section("CurveSurface", Count::Any, true),
+            section("GroomSurface", Count::Any, true),
+            section("BilinearPatch", Count::Any, true),
+            section("PointLight", Count::Any, true),
+            section("SphereLight", Count::Any, true),
+            section("RectangleLight", Count::Any, true),
+            section("DiskLight", Count::Any, true),
+            section("TubeLight", Count::Any, true),
+        ],
+    ),
+    (
+        "Instance",
+        &[
+            leaf("Data", Count::One, "[$name]"),
+            leaf("SurfaceShaderBind", Count::Optional, "[$name]"),
+            leaf("SurfaceShaderOverride", Count::Optional, "[$name]"),
+            leaf("Proxy", Count::Optional, "[$name]"),
+            leaf("Transform", Count::Any, TRANSFORM),
+        ],
+    ),
+    (
+        "InstanceArray",
+        &[
+            leaf("Data", Count::One, "[$name]"),
+            leaf("SurfaceShaderBind", Count::Optional, "[$name]"),
+            leaf(
+                "Transforms",
+                Count::OneOrMore,
+                "[# # # # # # # # # # # # # # # #  ...]",
+            ),
+        ],
+    ),
+    ("Space", &[leaf("Transform", Count::OneOrMore, TRANSFORM)]),
+    (
+        "SurfaceShader",
+        &[
+            leaf("Type", Count::One, "[Lambert] | [GGX] | [Hair] | [Emit]"),
+            leaf("Color", Count::Optional, COLOR), // Required for all but Hair
+            leaf("Roughness", Count::Optional, "[roughness]"), // Required for GGX and Hair
+            leaf("Fresnel", Count::Optional, "[fresnel]"), // Required for GGX
+            leaf("Melanin", Count::Optional, "[amount]"), // Required for Hair
+            leaf("MelaninRedness", Count::Optional, "[fraction]"),
+            leaf("AzimuthalRoughness", Count::Optional, "[roughness]"),
+            leaf("DoubleSided", Count::Optional, BOOL),
+            leaf("BackfaceCull", Count::Optional, BOOL),
+            leaf("DisplacementBound", Count::Optional, "[amount]"),
+            leaf("Opacity", Count::Optional, "[opacity]"),
+            leaf("HueVariation", Count::Optional, "[turns]"),
+            leaf("RoughnessVariation", Count::Optional, "[amount]"),
+        ],
+    ),
+    (
+        "MeshSurface",
+        &[
+            leaf("Vertices", Count::OneOrMore, "[x y z  x y z ...]"),
+            leaf("Normals", Count::Any, "[x y z  x y z ...]"),
+            leaf("FaceVertCounts", Count::One, "[count count ...]"),
+            leaf("FaceVertIndices", Count::One, "[index index ...]"),
+            section("FaceGroup", Count::Any, false),
+            section("Primvar", Count::Any, true),
+        ],
+    ),
+    (
+        "Primvar",
+        &[
+            leaf("Type", Count::One, "[float] | [float2] | [float3] | [int]"),
+            leaf(
+                "Interpolation",
+                Count::One,
+                "[uniform] | [varying] | [facevarying]",
+            ),
+            leaf("Values", Count::One, "[value value ...]"),
+        ],
+    ),
+    (
+        "FaceGroup",
+        &[
+            leaf("SurfaceShaderBind", Count::One, "[$name]"),
+            leaf("Faces", Count::Any, "[index index ...]"),
+        ],
+    ),
+    (
+        "CurveSurface",
+        &[
+            leaf("Vertices", Count::One, "[x y z  x y z ...]"),
+            leaf("Radii", Count::One, "[radius] | [radius radius ...]"),
+            leaf("StrandVertCounts", Count::One, "[count count ...]"),
+            section("Primvar", Count::Any, true),
+        ],
+    ),
+    (
+        "GroomSurface",
+        &[
+            leaf("Vertices", Count::One, "[x y z  x y z ...]"), // Of the guides
+            leaf("Radii", Count::One, "[radius] | [radius radius ...]"),
+            leaf("StrandVertCounts", Count::One, "[count count ...]"),
+            leaf("ScalpVertices", Count::One, "[x y z  x y z ...]"),
+            leaf("ScalpFaceVertCounts", Count::One, "[count count ...]"),
+            leaf("ScalpFaceVertIndices", Count::One, "[index index ...]"),
+            leaf("ChildCount", Count::One, "[count]"),
+            leaf("Clump", Count::Optional, "[amount]"),
+            leaf("Frizz", Count::Optional, "[amount]"),
+            leaf("FrizzFrequency", Count::Optional, "[waves]"),
+            leaf("Seed", Count::Optional, "[seed]"),
+        ],
+    ),
+    (
+        "BilinearPatch",
+        &[leaf(
+            "Vertices",
+            Count::OneOrMore,
+            "[x y z  x y z  x y z  x y z]",
+        )],
+    ),
+    (
+        "PointLight",
+        &[
+            leaf("Color", Count::OneOrMore, COLOR),
+            leaf("Units", Count::Optional, LIGHT_UNITS),
+        ],
+    ),
+    (
+        "SphereLight",
+        &[
+            leaf("Radius", Count::OneOrMore, "[radius]"),
+            leaf("Color", Count::OneOrMore, COLOR),
+            leaf("Units", Count::Optional, LIGHT_UNITS),
+        ],
+    ),
+    (
+        "RectangleLight",
+        &[
+            leaf("Dimensions", Count::OneOrMore, "[width height]"),
+            leaf("Color", Count::OneOrMore, COLOR),
+            leaf("TwoSided", Count::Optional, BOOL),
+            leaf("Spread", Count::Optional, "[degrees]"),
+            leaf("Units", Count::Optional, LIGHT_UNITS),
+        ],
+    ),
+    (
+        "DiskLight",
+        &[
+            leaf("Radius", Count::OneOrMore, "[radius]"),
+            leaf("Color", Count::OneOrMore, COLOR),
+            leaf("TwoSided", Count::Optional, BOOL),
+            leaf("Units", Count::Optional, LIGHT_UNITS),
+        ],
+    ),
+    (
+        "TubeLight",
+        &[
+            leaf("Radius", Count::OneOrMore, "[radius]"),
+            leaf("Length", Count::OneOrMore, "[length]"),
+            leaf("Color", Count::OneOrMore, COLOR),
+            leaf("Units", Count::Optional, LIGHT_UNITS),
+        ],
+    ),
+];
+
+/// A problem in a scene that the parser worked around.
+#[derive(Debug)]
+pub enum PsyParseWarning {
+    // The first usize for all warnings is their byte offset
+    // into the psy content where they occured.
+    UnknownNode(usize, String, String), // Node type, section type
+    BadGeometry(usize, String, String), // Object name, problem
+}
+
+impl PsyParseWarning {
+    /// Returns a human-readable description of the warning, including
+    /// the line it occured on.
+    pub fn message(&self, psy_content: &str) -> String {
+        match *self {
+            PsyParseWarning::UnknownNode(offset, ref type_name, ref section) => {
+                let line = line_count_to_byte_offset(psy_content, offset);
+                format!(
+                    "Line {}: Unknown {} in {}, skipped.",
+                    line, type_name, section
+                )
+            }
+            PsyParseWarning::BadGeometry(offset, ref name, ref problem) => {
+                let line = line_count_to_byte_offset(psy_content, offset);
+                format!("Line {}: {}: {}.", line, name, problem)
+            }
+        }
+    }
+
+    /// The equivalent error, for when warnings aren't tolerated.
+    pub fn into_error(self) -> PsyParseError {
+        match self {
+            PsyParseWarning::UnknownNode(offset, type_name, section) => {
+                PsyParseError::UnknownNode(offset, type_name, section)
+            }
+            PsyParseWarning::BadGeometry(offset, name, problem) => {
+                PsyParseError::BadGeometry(offset, name, problem)
+            }
+        }
+    }
+}
+
+/// Whether a node of type `type_name` is known in a section of type
+/// `section`.  Nodes in unknown sections are never known.
+pub fn is_known_node(section: &str, type_name: &str) -> bool {
+    SECTIONS
+        .iter()
+        .find(|(s, _)| *s == section)
+        .map(|(_, children)| children.iter().any(|c| c.type_name == type_name))
+        .unwrap_or(false)
+}
+
+/// Returns everything in the schema as JSON, for checking scenes against
+/// what this version of the parser accepts.
+pub fn schema_json() -> String {
+    let mut sections = BTreeMap::new();
+    for (section, children) in SECTIONS {
+        sections.insert(section.to_string(), nodes_json(children));
+    }
+
+    let mut schema = BTreeMap::new();
+    schema.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_json());
+    schema.insert("file".to_string(), nodes_json(FILE));
+    schema.insert("sections".to_string(), Json::Object(sections));
+    Json::Object(schema).to_string()
+}
+
+fn nodes_json(nodes: &[NodeSchema]) -> Json {
+    let nodes = nodes.iter().map(|node| {
+        let mut fields = BTreeMap::new();
+        fields.insert("type".to_string(), node.type_name.to_json());
+        if let Some(grammar) = node.grammar {
+            fields.insert("kind".to_string(), "leaf".to_json());
+            fields.insert("grammar".to_string(), grammar.to_json());
+        } else {
+            fields.insert("kind".to_string(), "section".to_json());
+        }
+        fields.insert("named".to_string(), node.named.to_json());
+        fields.insert("required".to_string(), node.count.is_required().to_json());
+        fields.insert(
+            "repeatable".to_string(),
+            node.count.is_repeatable().to_json(),
+        );
+        Json::Object(fields)
+    });
+    Json::Array(nodes.collect())
+}
+
+/// Finds the nodes in a Scene that the parser doesn't know, and so skips.
+///
+/// Unknown nodes' children aren't checked.
+pub fn find_unknown_nodes(scene: &DataTree) -> Vec<PsyParseWarning> {
+    let mut warnings = Vec::new();
+    find_unknown_children(scene, &mut warnings);
+    warnings
+}
+
+fn find_unknown_children(tree: &DataTree, warnings: &mut Vec<PsyParseWarning>) {
+    for child in tree.iter_children() {
+        if is_known_node(tree.type_name(), child.type_name()) {
+            find_unknown_children(child, warnings);
+        } else {
+            warnings.push(PsyParseWarning::UnknownNode(
+                child.byte_offset(),
+                child.type_name().to_string(),
+                tree.type_name().to_string(),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_unknown_nodes() {
+        let text = r#"
+            Scene {
+                Output { Path [""] Compression [zip] }
+                Assembly {
+                    SubdivisionSurface $sub { Vertices [0 0 0] Creases [] }
+                    MeshSurface $mesh { Vertices [0 0 0] }
+                }
+            }
+        "#;
+        let tree = DataTree::from_str(text).unwrap();
+        let scene = tree.iter_children().next().unwrap();
+
+        let warnings = find_unknown_nodes(scene);
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(
+            warnings[0].message(text),
+            "Line 3: Unknown Compression in Output, skipped."
+        );
+        assert_eq!(
+            warnings[1].message(text),
+            "Line 5: Unknown SubdivisionSurface in Assembly, skipped."
+        );
+    }
+
+    #[test]
+    fn schema_json_has_every_section() {
+        let json = schema_json();
+        assert!(json.starts_with("{\"file\":[{\"kind\":\"section\""));
+        assert!(json.ends_with(&format!("\"version\":\"{}\"}}", env!("CARGO_PKG_VERSION"))));
+        for (section, _) in SECTIONS {
+            assert!(json.contains(&format!("\"{}\":[", section)));
+        }
+        assert!(json.contains(
+            "{\"grammar\":\"[\\\"path\\\"]\",\"kind\":\"leaf\",\"named\":false,\
+             \"repeatable\":false,\"required\":true,\"type\":\"Path\"}"
+        ));
+    }
+
+    #[test]
+    fn sections_are_all_described() {
+        // Every section that can be contained somewhere has its own entry.
+        let nested = FILE
+            .iter()
+            .chain(SECTIONS.iter().flat_map(|(_, children)| children.iter()));
+        for node in nested.filter(|n| n.grammar.is_none()) {
+            assert!(
+                SECTIONS.iter().any(|(s, _)| *s == node.type_name),
+                "{}",
+                node.type_name
+            );
+        }
+    }
+}
@@ -6,10 +6,16 @@ use nom::{combinator::all_consuming, IResult};
 
 use kioku::Arena;
 
-use crate::shading::{SimpleSurfaceShader, SurfaceShader};
+use crate::{
+    color::{rec709_e_to_xyz, Color},
+    shading::{
+        DisplacedSurfaceShader, OpacitySurfaceShader, SidedSurfaceShader, SimpleSurfaceShader,
+        SurfaceShader, VariedSurfaceShader,
+    },
+};
 
 use super::{
-    basics::ws_f32,
+    basics::{ws_bool, ws_f32},
     psy::{parse_color, PsyParseError},
     DataTree,
 };
@@ -52,7 +58,7 @@ pub fn parse_surface_shader<'a>(
                 ));
             };
 
-            arena.alloc(SimpleSurfaceShader::Lambert { color: color })
+            SimpleSurfaceShader::Lambert { color: color }
         }
 
         "GGX" => {
@@ -105,11 +111,75 @@ pub fn parse_surface_shader<'a>(
                 ));
             };
 
-            arena.alloc(SimpleSurfaceShader::GGX {
+            SimpleSurfaceShader::GGX {
                 color: color,
                 roughness: roughness,
                 fresnel: fresnel,
-            })
+            }
+        }
+
+        "Hair" => {
+            // Color, which dyes the hair on top of its melanin
+            let color = if let Some((_, contents, byte_offset)) =
+                tree.iter_leaf_children_with_type("Color").nth(0)
+            {
+                if let Ok(color) = parse_color(contents) {
+                    color
+                } else {
+                    // Found color, but its contents is not in the right format
+                    return Err(PsyParseError::UnknownError(byte_offset));
+                }
+            } else {
+                Color::new_xyz(rec709_e_to_xyz((1.0, 1.0, 1.0)))
+            };
+
+            // Melanin
+            let melanin = parse_fraction(
+                tree,
+                "Melanin",
+                "Melanin should be a number between 0.0 and 1.0, in the form '[amount]'.",
+            )?
+            .ok_or(PsyParseError::MissingNode(
+                tree.byte_offset(),
+                "Expected a Melanin field in Hair SurfaceShader.",
+            ))?;
+
+            // MelaninRedness
+            let melanin_redness = parse_fraction(
+                tree,
+                "MelaninRedness",
+                "MelaninRedness should be a number between 0.0 and 1.0, in the form \
+                 '[fraction]'.",
+            )?
+            .unwrap_or(0.0);
+
+            // Roughness
+            let roughness = parse_fraction(
+                tree,
+                "Roughness",
+                "Roughness should be a number between 0.0 and 1.0, in the form '[roughness]'.",
+            )?
+            .ok_or(PsyParseError::MissingNode(
+                tree.byte_offset(),
+                "Expected a Roughness field in Hair SurfaceShader.",
+            ))?;
+
+            // AzimuthalRoughness
+            let azimuthal_roughness = parse_fraction(
+                tree,
+                "AzimuthalRoughness",
+                "AzimuthalRoughness should be a number between 0.0 and 1.0, in the form \
+                 '[roughness]'.",
+            )?
+            .unwrap_or(roughness);
+
+            SimpleSurfaceShader::Hair {
+                color: color,
+                melanin: melanin,
+                melanin_redness: melanin_redness,
+                roughness: roughness,
+                azimuthal_roughness: azimuthal_roughness,
+            }
         }
 
         "Emit" => {
@@ -129,11 +199,136 @@ pub fn parse_surface_shader<'a>(
                 ));
             };
 
-            arena.alloc(SimpleSurfaceShader::Emit { color: color })
+            SimpleSurfaceShader::Emit { color: color }
         }
 
-        _ => unimplemented!(),
+        _ => {
+            return Err(PsyParseError::UnknownVariant(
+                tree.byte_offset(),
+                "The specified SurfaceShader Type isn't a recognized type.",
+            ))
+        }
     };
 
-    Ok(shader)
+    // DoubleSided
+    let double_sided = if let Some((_, contents, byte_offset)) =
+        tree.iter_leaf_children_with_type("DoubleSided").next()
+    {
+        if let IResult::Ok((_, b)) = all_consuming(ws_bool)(contents) {
+            b
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "DoubleSided should be either 'true' or 'false'.",
+            ));
+        }
+    } else {
+        true
+    };
+
+    // BackfaceCull
+    let backface_cull = if let Some((_, contents, byte_offset)) =
+        tree.iter_leaf_children_with_type("BackfaceCull").next()
+    {
+        if let IResult::Ok((_, b)) = all_consuming(ws_bool)(contents) {
+            b
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "BackfaceCull should be either 'true' or 'false'.",
+            ));
+        }
+    } else {
+        false
+    };
+
+    // DisplacementBound
+    let displacement_bound = if let Some((_, contents, byte_offset)) = tree
+        .iter_leaf_children_with_type("DisplacementBound")
+        .next()
+    {
+        match all_consuming(ws_f32)(contents) {
+            IResult::Ok((_, bound)) if bound >= 0.0 && bound.is_finite() => bound,
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "DisplacementBound should be a non-negative distance, in the form \
+                     '[amount]'.",
+                ));
+            }
+        }
+    } else {
+        0.0
+    };
+
+    // Opacity
+    let opacity = if let Some((_, contents, byte_offset)) =
+        tree.iter_leaf_children_with_type("Opacity").next()
+    {
+        match all_consuming(ws_f32)(contents) {
+            IResult::Ok((_, opacity)) if (0.0..=1.0).contains(&opacity) => opacity,
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Opacity should be a number between 0.0 and 1.0, in the form \
+                     '[opacity]'.",
+                ));
+            }
+        }
+    } else {
+        1.0
+    };
+
+    // Variation between instances
+    let hue_variation = parse_fraction(
+        tree,
+        "HueVariation",
+        "HueVariation should be a number of turns between 0.0 and 1.0, in the form \
+         '[turns]'.",
+    )?
+    .unwrap_or(0.0);
+    let roughness_variation = parse_fraction(
+        tree,
+        "RoughnessVariation",
+        "RoughnessVariation should be a number between 0.0 and 1.0, in the form '[amount]'.",
+    )?
+    .unwrap_or(0.0);
+
+    let shader = OpacitySurfaceShader {
+        shader: SidedSurfaceShader {
+            shader: VariedSurfaceShader {
+                shader: shader,
+                hue_variation: hue_variation,
+                roughness_variation: roughness_variation,
+            },
+            double_sided: double_sided,
+            backface_cull: backface_cull,
+        },
+        opacity: opacity,
+    };
+    if displacement_bound > 0.0 {
+        Ok(arena.alloc(DisplacedSurfaceShader {
+            shader: shader,
+            displacement_bound: displacement_bound,
+        }))
+    } else {
+        Ok(arena.alloc(shader))
+    }
+}
+
+/// Parses the first leaf of the given type as a number in [0, 1], if
+/// there is one.
+fn parse_fraction<'a>(
+    tree: &'a DataTree,
+    leaf_type: &'static str,
+    error: &'static str,
+) -> Result<Option<f32>, PsyParseError> {
+    if let Some((_, contents, byte_offset)) = tree.iter_leaf_children_with_type(leaf_type).next() {
+        match all_consuming(ws_f32)(contents) {
+            IResult::Ok((_, n)) if (0.0..=1.0).contains(&n) => Ok(Some(n)),
+            _ => Err(PsyParseError::IncorrectLeafData(byte_offset, error)),
+        }
+    } else {
+        Ok(None)
+    }
 }
@@ -0,0 +1,100 @@
+//! A hash grid of photons, for finding the photons near a point.
+
+use hash_grid::HashGrid;
+
+use crate::{
+    color::XYZ,
+    math::{Point, Vector},
+};
+
+/// Light that a photon path left on a surface.
+#[derive(Debug, Copy, Clone)]
+pub struct Photon {
+    pub pos: Point,
+    pub dir: Vector, // The direction the light was travelling in
+    pub flux: XYZ,
+}
+
+/// Photons in a hash grid, for gathering the ones near a point.
+#[derive(Debug)]
+pub struct PhotonMap {
+    grid: HashGrid<Photon>,
+}
+
+impl PhotonMap {
+    /// Builds a photon map.
+    ///
+    /// `cell_size` is the size of the grid cells, which should be about
+    /// the largest radius that will be searched.
+    pub fn new(photons: &[Photon], cell_size: f32) -> PhotonMap {
+        PhotonMap {
+            grid: HashGrid::new(photons.iter().cloned(), cell_size, |photon| {
+                [photon.pos.x(), photon.pos.y(), photon.pos.z()]
+            }),
+        }
+    }
+
+    /// Calls `f` with every photon within `radius` of `pos`.
+    pub fn for_each_near<F>(&self, pos: Point, radius: f32, f: F)
+    where
+        F: FnMut(&Photon),
+    {
+        self.grid
+            .for_each_near([pos.x(), pos.y(), pos.z()], radius, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::hash_u32_to_f32;
+
+    fn random_point(i: u32, seed: u32) -> Point {
+        Point::new(
+            hash_u32_to_f32(i, seed) * 4.0 - 2.0,
+            hash_u32_to_f32(i, seed + 1) * 4.0 - 2.0,
+            hash_u32_to_f32(i, seed + 2) * 4.0 - 2.0,
+        )
+    }
+
+    fn photons(count: u32) -> Vec<Photon> {
+        (0..count)
+            .map(|i| Photon {
+                pos: random_point(i, 0),
+                dir: Vector::new(0.0, 0.0, -1.0),
+                flux: XYZ::new(i as f32, 0.0, 0.0),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_same_photons_as_brute_force() {
+        let photons = photons(5000);
+        let map = PhotonMap::new(&photons, 0.25);
+
+        for i in 0..200 {
+            let pos = random_point(i, 10);
+            let radius = 0.05 + hash_u32_to_f32(i, 20) * 0.2;
+
+            let mut found = Vec::new();
+            map.for_each_near(pos, radius, |photon| found.push(photon.flux.x as u32));
+            found.sort_unstable();
+
+            let expected: Vec<u32> = photons
+                .iter()
+                .filter(|photon| (photon.pos - pos).length2() <= radius * radius)
+                .map(|photon| photon.flux.x as u32)
+                .collect();
+
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn empty_map() {
+        let map = PhotonMap::new(&[], 1.0);
+        let mut found = 0;
+        map.for_each_near(Point::new(0.0, 0.0, 0.0), 1.0, |_| found += 1);
+        assert_eq!(found, 0);
+    }
+}
@@ -0,0 +1,196 @@
+//! Light probes: the light arriving at points in a scene, baked into
+//! spherical harmonics for use as global illumination in game engines.
+
+use std::io::{self, BufRead, Write};
+
+use crate::{
+    bbox::BBox,
+    math::{Point, Vector},
+    sampling::blue_noise::poisson_disk_box,
+};
+
+/// The most probes that `spaced_positions()` will place.
+const MAX_SPACED_PROBES: f32 = 1.0e6;
+
+/// The number of spherical harmonic coefficients per color channel,
+/// which is all of the bands up to and including band 2.
+pub const SH_COEFFICIENTS: usize = 9;
+
+/// A baked light probe.
+#[derive(Debug, Copy, Clone)]
+pub struct Probe {
+    pub pos: Point,
+    pub sh: [(f32, f32, f32); SH_COEFFICIENTS], // Linear rec709 radiance
+}
+
+/// The real spherical harmonic basis functions up to band 2, in the usual
+/// order (l, m) = (0, 0), (1, -1), (1, 0), (1, 1), (2, -2) ... (2, 2),
+/// evaluated for the normalized direction `dir`.
+pub fn sh_basis(dir: Vector) -> [f32; SH_COEFFICIENTS] {
+    let (x, y, z) = (dir.x(), dir.y(), dir.z());
+    [
+        0.282_095,
+        0.488_603 * y,
+        0.488_603 * z,
+        0.488_603 * x,
+        1.092_548 * x * y,
+        1.092_548 * y * z,
+        0.315_392 * ((3.0 * z * z) - 1.0),
+        1.092_548 * x * z,
+        0.546_274 * ((x * x) - (y * y)),
+    ]
+}
+
+/// Reads probe positions from `input`, one per line as `x y z`.  Blank
+/// lines and lines starting with `#` are skipped.
+pub fn read_positions<R: BufRead>(input: R) -> io::Result<Vec<Point>> {
+    let mut positions = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let vals = line
+            .split_whitespace()
+            .map(|s| s.parse::<f32>().ok())
+            .collect::<Option<Vec<_>>>()
+            .filter(|vals| vals.len() == 3)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: expected 'x y z'", i + 1),
+                )
+            })?;
+        positions.push(Point::new(vals[0], vals[1], vals[2]));
+    }
+    Ok(positions)
+}
+
+/// Probe positions spread evenly but irregularly through `bounds`, at
+/// least `spacing` apart, as a Poisson disk set.
+pub fn spaced_positions(bounds: BBox, spacing: f32) -> Result<Vec<Point>, String> {
+    let size = bounds.max - bounds.min;
+    if !(size.x() >= 0.0 && size.y() >= 0.0 && size.z() >= 0.0) {
+        return Err("there's nothing in the scene to place probes around".to_string());
+    }
+    if !(spacing > 0.0) {
+        return Err(format!("probe spacing must be positive, not {}", spacing));
+    }
+    // A Poisson disk set has at most about one point per spacing^3/1.4.
+    let estimate = (size.x() + spacing) * (size.y() + spacing) * (size.z() + spacing) * 1.4
+        / (spacing * spacing * spacing);
+    if !(estimate <= MAX_SPACED_PROBES) {
+        return Err(format!(
+            "a spacing of {} would place around {:.0} probes, more than the limit of {}",
+            spacing, estimate, MAX_SPACED_PROBES
+        ));
+    }
+    Ok(poisson_disk_box(bounds, spacing, 0))
+}
+
+/// Writes the probes as JSON: an object with the coefficient order and
+/// a list of probes, each with its position and its coefficients as
+/// [r, g, b] triples.
+pub fn write_json<W: Write>(mut output: W, probes: &[Probe]) -> io::Result<()> {
+    writeln!(output, "{{")?;
+    writeln!(output, "  \"basis\": \"real_sh_l2\",")?;
+    writeln!(output, "  \"color_space\": \"linear_rec709\",")?;
+    writeln!(output, "  \"probes\": [")?;
+    for (i, probe) in probes.iter().enumerate() {
+        let sh = probe
+            .sh
+            .iter()
+            .map(|c| format!("[{}, {}, {}]", c.0, c.1, c.2))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(
+            output,
+            "    {{\"position\": [{}, {}, {}], \"sh\": [{}]}}{}",
+            probe.pos.x(),
+            probe.pos.y(),
+            probe.pos.z(),
+            sh,
+            if i + 1 < probes.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(output, "  ]")?;
+    writeln!(output, "}}")?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::uniform_sample_sphere;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn sh_basis_is_orthonormal() {
+        // Integrate products of the basis functions over a grid of
+        // stratified directions.
+        let n = 256;
+        let mut products = [[0.0f32; SH_COEFFICIENTS]; SH_COEFFICIENTS];
+        for i in 0..n {
+            for j in 0..n {
+                let u = (i as f32 + 0.5) / n as f32;
+                let v = (j as f32 + 0.5) / n as f32;
+                let basis = sh_basis(uniform_sample_sphere(u, v));
+                for a in 0..SH_COEFFICIENTS {
+                    for b in 0..SH_COEFFICIENTS {
+                        products[a][b] += basis[a] * basis[b];
+                    }
+                }
+            }
+        }
+
+        let weight = 4.0 * PI / (n * n) as f32;
+        for a in 0..SH_COEFFICIENTS {
+            for b in 0..SH_COEFFICIENTS {
+                let expected = if a == b { 1.0 } else { 0.0 };
+                assert!((products[a][b] * weight - expected).abs() < 0.001);
+            }
+        }
+    }
+
+    #[test]
+    fn read_positions_lines() {
+        let input = "# Probes\n0 0 1\n\n1.5 -2 3\n";
+        let positions = read_positions(input.as_bytes()).unwrap();
+        assert_eq!(
+            positions,
+            vec![Point::new(0.0, 0.0, 1.0), Point::new(1.5, -2.0, 3.0)]
+        );
+
+        assert!(read_positions("1 2".as_bytes()).is_err());
+        assert!(read_positions("1 2 x".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn spaced_probe_positions() {
+        let bounds = BBox::from_points(Point::new(0.0, 0.0, 0.0), Point::new(4.0, 2.0, 1.0));
+        let positions = spaced_positions(bounds, 0.5).unwrap();
+        assert!(positions.len() > 20);
+        assert!(positions
+            .iter()
+            .all(|p| p.x() < 4.0 && p.y() < 2.0 && p.z() < 1.0));
+
+        assert!(spaced_positions(BBox::new(), 0.5).is_err());
+        assert!(spaced_positions(bounds, 0.0).is_err());
+        assert!(spaced_positions(bounds, 0.0001).is_err());
+    }
+
+    #[test]
+    fn write_json_probes() {
+        let probe = Probe {
+            pos: Point::new(1.0, 2.0, 3.0),
+            sh: [(0.5, 0.25, 0.0); SH_COEFFICIENTS],
+        };
+        let mut output = Vec::new();
+        write_json(&mut output, &[probe, probe]).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\"position\": [1, 2, 3], \"sh\": [[0.5, 0.25, 0]"));
+        assert_eq!(output.matches("\"position\"").count(), 2);
+        assert_eq!(output.matches("]},\n").count(), 1);
+    }
+}
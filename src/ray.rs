@@ -2,12 +2,17 @@
 
 use glam::Vec4Mask;
 
-use crate::math::{Matrix4x4, Point, Vector};
+use crate::{
+    lerp::lerp_slice,
+    math::{Matrix4x4, Point, Vector},
+};
 
 type RayIndexType = u16;
 type FlagType = u8;
 const OCCLUSION_FLAG: FlagType = 1;
 const DONE_FLAG: FlagType = 1 << 1;
+const CAMERA_FLAG: FlagType = 1 << 2;
+const PROXY_FLAG: FlagType = 1 << 3;
 
 /// This is never used directly in ray tracing--it's only used as a convenience
 /// for filling the RayBatch structure.
@@ -36,6 +41,7 @@ struct RayCold {
     orig: Point, // World-space ray origin
     dir: Vector, // World-space ray direction
     wavelength: f32,
+    spread: f32, // Growth in width of the ray's footprint per unit of t
 }
 
 /// A batch of rays, separated into hot and cold parts.
@@ -75,6 +81,7 @@ impl RayBatch {
             orig: ray.orig,
             dir: ray.dir,
             wavelength: ray.wavelength,
+            spread: 0.0,
         });
     }
 
@@ -95,6 +102,7 @@ impl RayBatch {
         self.cold[idx].orig = ray.orig;
         self.cold[idx].dir = ray.dir;
         self.cold[idx].wavelength = ray.wavelength;
+        self.cold[idx].spread = 0.0;
     }
 
     pub fn truncate(&mut self, len: usize) {
@@ -119,6 +127,7 @@ impl RayBatch {
     ///
     /// This should be called when entering (and exiting) traversal of a
     /// new transform space.
+    #[inline(always)]
     pub fn update_local(&mut self, idx: usize, xform: &Matrix4x4) {
         self.hot[idx].orig_local = self.cold[idx].orig * *xform;
         self.hot[idx].dir_inv_local = Vector {
@@ -126,6 +135,48 @@ impl RayBatch {
         };
     }
 
+    /// Updates the accel data of the rays in the next task of `ray_stack`
+    /// with the world-to-local-space transform `xforms`, interpolated at
+    /// each ray's time.  If `xforms` is empty, they're set to world space.
+    ///
+    /// Dispatched to a copy compiled for the best SIMD instruction set the
+    /// CPU supports.
+    pub fn update_local_for_task(&mut self, ray_stack: &mut RayStack, xforms: &[Matrix4x4]) {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if crate::cpu::simd_level().has_avx2() {
+                // SAFETY: the CPU supports AVX2.
+                unsafe { self.update_local_for_task_avx2(ray_stack, xforms) };
+                return;
+            }
+        }
+
+        self.update_local_for_task_impl(ray_stack, xforms);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn update_local_for_task_avx2(
+        &mut self,
+        ray_stack: &mut RayStack,
+        xforms: &[Matrix4x4],
+    ) {
+        self.update_local_for_task_impl(ray_stack, xforms);
+    }
+
+    #[inline(always)]
+    fn update_local_for_task_impl(&mut self, ray_stack: &mut RayStack, xforms: &[Matrix4x4]) {
+        if xforms.is_empty() {
+            let ident = Matrix4x4::new();
+            ray_stack.do_next_task(|ray_idx| self.update_local(ray_idx, &ident));
+        } else {
+            ray_stack.do_next_task(|ray_idx| {
+                let xform = lerp_slice(xforms, self.time(ray_idx));
+                self.update_local(ray_idx, &xform);
+            });
+        }
+    }
+
     //==========================================================
     // Data access
 
@@ -169,6 +220,22 @@ impl RayBatch {
         self.cold[idx].wavelength
     }
 
+    /// How fast the footprint of the given ray (at index `idx`) widens
+    /// with distance: a cheap stand-in for ray differentials.  Zero means
+    /// the footprint is unknown.
+    #[inline(always)]
+    pub fn spread(&self, idx: usize) -> f32 {
+        self.cold[idx].spread
+    }
+
+    /// Sets the spread of the given ray (at index `idx`).
+    ///
+    /// This is cleared by `set_from_ray()`, like the camera flag.
+    #[inline(always)]
+    pub fn set_spread(&mut self, idx: usize, spread: f32) {
+        self.cold[idx].spread = spread;
+    }
+
     /// Returns whether the given ray (at index `idx`) is an occlusion ray.
     #[inline(always)]
     pub fn is_occlusion(&self, idx: usize) -> bool {
@@ -181,6 +248,19 @@ impl RayBatch {
         (self.hot[idx].flags & DONE_FLAG) != 0
     }
 
+    /// Returns whether the given ray (at index `idx`) is a camera ray.
+    #[inline(always)]
+    pub fn is_camera(&self, idx: usize) -> bool {
+        (self.hot[idx].flags & CAMERA_FLAG) != 0
+    }
+
+    /// Returns whether the given ray (at index `idx`) sees proxy geometry
+    /// in place of the surfaces that have it.
+    #[inline(always)]
+    pub fn uses_proxy(&self, idx: usize) -> bool {
+        (self.hot[idx].flags & PROXY_FLAG) != 0
+    }
+
     /// Marks the given ray (at index `idx`) as an occlusion ray.
     #[inline(always)]
     pub fn mark_occlusion(&mut self, idx: usize) {
@@ -192,6 +272,22 @@ impl RayBatch {
     pub fn mark_done(&mut self, idx: usize) {
         self.hot[idx].flags |= DONE_FLAG
     }
+
+    /// Marks the given ray (at index `idx`) as a camera ray.
+    ///
+    /// This is cleared by `set_from_ray()`, so bounce rays don't inherit it.
+    #[inline(always)]
+    pub fn mark_camera(&mut self, idx: usize) {
+        self.hot[idx].flags |= CAMERA_FLAG
+    }
+
+    /// Marks the given ray (at index `idx`) as seeing proxy geometry.
+    ///
+    /// This is cleared by `set_from_ray()`, like the camera flag.
+    #[inline(always)]
+    pub fn mark_proxy(&mut self, idx: usize) {
+        self.hot[idx].flags |= PROXY_FLAG
+    }
 }
 
 /// A structure used for tracking traversal of a ray batch through a scene.
@@ -311,6 +407,44 @@ impl RayStack {
         self.lanes[l].end_len = self.lanes[l].idxs.len();
     }
 
+    /// Splits the next task in two: the rays that `pred` is false for,
+    /// and then the rays it's true for as the new next task.  Tasks that
+    /// would be empty are left out.
+    ///
+    /// Returns how many rays `pred` was false and true for.
+    pub fn partition_next_task<F>(&mut self, mut pred: F) -> (usize, usize)
+    where
+        F: FnMut(usize) -> bool,
+    {
+        let task = self.tasks.pop().unwrap();
+        let lane = &mut self.lanes[task.lane];
+        let idxs = &mut lane.idxs[task.start_idx..lane.end_len];
+
+        let mut split = 0;
+        for i in 0..idxs.len() {
+            if !pred(idxs[i] as usize) {
+                idxs.swap(split, i);
+                split += 1;
+            }
+        }
+        let count = idxs.len();
+
+        if split > 0 {
+            self.tasks.push(RayTask {
+                lane: task.lane,
+                start_idx: task.start_idx,
+            });
+        }
+        if split < count {
+            self.tasks.push(RayTask {
+                lane: task.lane,
+                start_idx: task.start_idx + split,
+            });
+        }
+
+        (split, count - split)
+    }
+
     // Pops the next task off the stack.
     pub fn pop_task(&mut self) {
         let task = self.tasks.pop().unwrap();
@@ -319,6 +453,7 @@ impl RayStack {
     }
 
     // Executes a task without popping it from the task stack.
+    #[inline(always)]
     pub fn do_next_task<F>(&mut self, mut handle_ray: F)
     where
         F: FnMut(usize),
@@ -399,3 +534,67 @@ struct RayTask {
     lane: usize,
     start_idx: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ray_batch() -> RayBatch {
+        let mut rays = RayBatch::new();
+        for i in 0..8 {
+            let f = i as f32;
+            rays.push(
+                Ray {
+                    orig: Point::new(f, 1.0 - f, 0.5 * f),
+                    dir: Vector::new(1.0, f - 3.5, 0.25),
+                    time: f / 7.0,
+                    wavelength: 550.0,
+                    max_t: std::f32::INFINITY,
+                },
+                false,
+            );
+        }
+        rays
+    }
+
+    fn task_of_all(rays: &RayBatch) -> RayStack {
+        let mut ray_stack = RayStack::new();
+        ray_stack.ensure_lane_count(1);
+        for i in 0..rays.len() {
+            ray_stack.push_ray_index(i, 0);
+        }
+        ray_stack.push_lane_to_task(0);
+        ray_stack
+    }
+
+    #[test]
+    fn update_local_for_task_copies_agree() {
+        let xforms = [
+            Matrix4x4::from_location(Point::new(1.0, 2.0, 3.0)),
+            Matrix4x4::new_from_values(
+                0.0, 1.0, 0.0, -1.0, 2.0, 0.0, 0.0, 0.5, 0.0, 0.0, 1.0, 4.0, 0.0, 0.0, 0.0, 1.0,
+            ),
+        ];
+        for &xforms in &[&xforms[..], &[]] {
+            let mut expected = ray_batch();
+            let mut ray_stack = task_of_all(&expected);
+            expected.update_local_for_task_impl(&mut ray_stack, xforms);
+            for i in 0..expected.len() {
+                let xform = if xforms.is_empty() {
+                    Matrix4x4::new()
+                } else {
+                    lerp_slice(xforms, expected.time(i))
+                };
+                assert_eq!(expected.orig_local(i), expected.orig(i) * xform);
+            }
+
+            // The dispatched copy, whichever it is.
+            let mut rays = ray_batch();
+            rays.update_local_for_task(&mut ray_stack, xforms);
+            for i in 0..rays.len() {
+                assert_eq!(rays.orig_local(i), expected.orig_local(i));
+                assert_eq!(rays.dir_inv_local(i), expected.dir_inv_local(i));
+            }
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! A deliberately simple and slow path tracer, for validating the other
+//! integrators against.
+//!
+//! Paths only find light by running into it: there's no light sampling,
+//! MIS, splitting, or irradiance caching, and nothing is clamped.  Each
+//! pixel's samples are summed in double precision.  This converges far
+//! more slowly than path tracing, but leaves little room for mistakes, so
+//! where a path traced image differs from a reference render by more than
+//! noise, the path tracer is biased.
+//!
+//! Point lights can't be run into, so their light is missing.  So is the
+//! atmosphere, if any.
+
+use std::{
+    io::{self, Write},
+    sync::Mutex,
+};
+
+use glam::Vec4;
+use scoped_threadpool::Pool;
+
+use crate::{
+    color::{map_0_1_to_wavelength, SpectralSample, XYZ},
+    image::{Accumulation, Image, PixelSum},
+    logger::{Event, Logger},
+    output::Checkpointer,
+    ray::{Ray, RayBatch},
+    render_settings::RenderSettings,
+    renderer::{get_sample, RenderStats, Renderer},
+    sampling::dims::{self, Dims},
+    scene::Scene,
+    shading::surface_closure::SurfaceClosure,
+    sppm::sample_closure,
+    surface,
+    timer::Timer,
+    tracer::Tracer,
+};
+
+/// How many image rows each job renders.
+const ROWS_PER_JOB: usize = 4;
+
+/// The most samples of a pixel that are traced together.
+const SAMPLES_PER_BATCH: usize = 4096;
+
+/// Renders the scene with the reference path tracer.
+///
+/// Samples are taken from the same sequence as path tracing, and the
+/// result likewise only depends on the scene and render settings.
+pub fn render(
+    renderer: &Renderer,
+    crop: Option<(u32, u32, u32, u32)>,
+    thread_count: u32,
+    do_blender_output: bool,
+    checkpointer: Option<&Checkpointer>,
+    log: &Logger,
+) -> (Image, RenderStats) {
+    let settings = &renderer.settings;
+    let mut total_timer = Timer::new();
+    let mut tpool = Pool::new(thread_count);
+
+    let image = renderer.new_image();
+    let (width, height, start_x, start_y) = renderer.render_region(crop);
+
+    log.log(&Event::RenderStarted {
+        total_pixels: width * height,
+        spp: settings.spp,
+        thread_count: thread_count,
+    });
+    log.detail("\tReference integrator: no light sampling, double precision sums");
+    if settings.max_time.is_some() {
+        log.warning("the reference integrator renders all samples regardless of the time limit.");
+    }
+    if renderer.scene.world.atmosphere.is_some() {
+        log.warning("the atmosphere is only rendered when path tracing, and is ignored.");
+    }
+
+    let stats = Mutex::new(RenderStats::new());
+    let pixels_rendered = Mutex::new(0);
+    tpool.scoped(|scope| {
+        for job_y in (0..height).step_by(ROWS_PER_JOB) {
+            let image = &image;
+            let stats = &stats;
+            let pixels_rendered = &pixels_rendered;
+            scope.execute(move || {
+                let (x, y) = (start_x as u32, (start_y + job_y) as u32);
+                let (w, h) = (width as u32, ROWS_PER_JOB.min(height - job_y) as u32);
+                let (pixels, mut job_stats) = trace_pixels(renderer, (x, y, w, h));
+
+                let mut merge_timer = Timer::new();
+                let min = (x, y);
+                let max = (x + w, y + h);
+                let mut img_bucket = image.get_bucket(min, max);
+                for (i, pixel) in pixels.iter().enumerate() {
+                    img_bucket.set(x + (i as u32 % w), y + (i as u32 / w), pixel.total());
+                }
+                job_stats.merge_time += merge_timer.tick() as f64;
+
+                let base64_enc = if do_blender_output {
+                    use crate::color::xyz_to_rec709_e;
+                    Some(img_bucket.rgba_base64(xyz_to_rec709_e))
+                } else {
+                    None
+                };
+                drop(img_bucket);
+
+                {
+                    let mut pr = pixels_rendered.lock().unwrap();
+                    *pr += pixels.len();
+
+                    log.log(&Event::BucketDone {
+                        x: x,
+                        y: y,
+                        w: w,
+                        h: h,
+                        rays: job_stats.ray_count,
+                    });
+
+                    if let Some(bucket_data) = base64_enc {
+                        let percentage = *pr as f64 / (width * height) as f64 * 100.0;
+                        println!("DIV");
+                        println!("{:.2}%", percentage);
+                        println!("{} {} {} {}", min.0, min.1, max.0, max.1);
+                        println!("{}", bucket_data);
+                        println!("BUCKET_END");
+                        println!("DIV");
+                        let _ = io::stdout().flush();
+                    }
+                }
+                stats.lock().unwrap().collect(job_stats);
+
+                if let Some(checkpointer) = checkpointer {
+                    if checkpointer.bucket_done() {
+                        let mut checkpoint_timer = Timer::new();
+                        match checkpointer.write(image) {
+                            Ok(()) => log.log(&Event::CheckpointWritten {
+                                path: checkpointer.path(),
+                                seconds: checkpoint_timer.tick(),
+                            }),
+                            Err(e) => log.warning(&format!("checkpoint failed: {}", e)),
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let mut stats = stats.into_inner().unwrap();
+    stats.total_time += total_timer.tick() as f64;
+    stats.spp = settings.spp;
+    (image, stats)
+}
+
+/// Traces all of the samples of the pixels in the given (x, y, width,
+/// height) region, returning the pixels' sums in scanline order.
+fn trace_pixels(renderer: &Renderer, region: (u32, u32, u32, u32)) -> (Vec<PixelSum>, RenderStats) {
+    let settings = &renderer.settings;
+    let scene = &renderer.scene;
+    let (x0, y0, w, h) = region;
+    let mut stats = RenderStats::new();
+    let mut timer = Timer::new();
+    let mut tracer = Tracer::from_assembly(&scene.root);
+    let mut paths = Vec::with_capacity(SAMPLES_PER_BATCH);
+    let mut rays = RayBatch::new();
+    let mut colors = vec![XYZ::new(0.0, 0.0, 0.0); SAMPLES_PER_BATCH];
+    let mut pixels = Vec::with_capacity((w * h) as usize);
+    let sample_scale = scene.camera.exposure() / settings.spp as f32;
+
+    for y in y0..(y0 + h) {
+        for x in x0..(x0 + w) {
+            let mut sum = PixelSum::new();
+            for batch_start in (0..settings.spp).step_by(SAMPLES_PER_BATCH) {
+                let batch_end = (batch_start + SAMPLES_PER_BATCH).min(settings.spp);
+                paths.clear();
+                rays.clear();
+                for si in batch_start..batch_end {
+                    let path = ReferencePath::new(renderer, (x, y), si as u32);
+                    rays.push(path.camera_ray(renderer), false);
+                    rays.mark_camera(rays.len() - 1);
+                    paths.push(path);
+                }
+                stats.initial_ray_generation_time += timer.tick() as f64;
+
+                let mut pi = paths.len();
+                while pi > 0 {
+                    let isects = tracer.trace(&mut rays);
+                    stats.trace_time += timer.tick() as f64;
+
+                    let mut new_end = 0;
+                    for i in 0..pi {
+                        if paths[i].next(scene, settings, &isects[i], &mut rays, i) {
+                            paths.swap(new_end, i);
+                            rays.swap(new_end, i);
+                            new_end += 1;
+                        }
+                    }
+                    rays.truncate(new_end);
+                    pi = new_end;
+                    stats.ray_generation_time += timer.tick() as f64;
+                }
+
+                // Sum the samples in order, regardless of how tracing
+                // shuffled them.
+                for path in &paths {
+                    let col = SpectralSample::from_parts(path.color, path.wavelength);
+                    colors[path.sample_number as usize - batch_start] = settings
+                        .white_balance
+                        .apply(settings.sensor_response.to_xyz(&col))
+                        * sample_scale;
+                }
+                for col in &colors[..(batch_end - batch_start)] {
+                    sum.add(*col, Accumulation::Double);
+                }
+                stats.sample_writing_time += timer.tick() as f64;
+            }
+            pixels.push(sum);
+        }
+    }
+    stats.ray_count = tracer.rays_traced();
+
+    (pixels, stats)
+}
+
+/// A path from the camera, gathering the light it runs into.
+#[derive(Debug)]
+struct ReferencePath {
+    pixel_co: (u32, u32),
+    sample_number: u32,
+    seed: u32,
+    time: f32,
+    wavelength: f32,
+
+    bounce_count: u32,
+    throughput: Vec4,
+    color: Vec4,
+}
+
+impl ReferencePath {
+    fn new(renderer: &Renderer, pixel_co: (u32, u32), sample_number: u32) -> ReferencePath {
+        let seed = renderer.settings.seed;
+        ReferencePath {
+            pixel_co: pixel_co,
+            sample_number: sample_number,
+            seed: seed,
+            time: get_sample(dims::TIME.dim(0), sample_number, pixel_co, seed),
+            wavelength: map_0_1_to_wavelength(get_sample(
+                dims::WAVELENGTH.dim(0),
+                sample_number,
+                pixel_co,
+                seed,
+            )),
+
+            bounce_count: 0,
+            throughput: Vec4::splat(1.0),
+            color: Vec4::splat(0.0),
+        }
+    }
+
+    /// Gets dimension `i` of `dims` at the path's current vertex.
+    fn vertex_samp(&self, dims: Dims, i: u32) -> f32 {
+        get_sample(
+            dims::vertex_dim(self.bounce_count, dims, i),
+            self.sample_number,
+            self.pixel_co,
+            self.seed,
+        )
+    }
+
+    fn camera_ray(&self, renderer: &Renderer) -> Ray {
+        let (x, y) = self.pixel_co;
+        let samp = |dims: Dims, i| get_sample(dims.dim(i), self.sample_number, (x, y), self.seed);
+        let filter = &renderer.settings.filter;
+        let filter_x = filter.sample(samp(dims::FILTER, 0)) + 0.5;
+        let filter_y = filter.sample(samp(dims::FILTER, 1)) + 0.5;
+        let (img_x, img_y) = renderer.image_plane_co(filter_x + x as f32, filter_y + y as f32);
+        renderer.scene.camera.generate_ray(
+            img_x,
+            img_y,
+            self.time,
+            self.wavelength,
+            samp(dims::LENS, 0),
+            samp(dims::LENS, 1),
+        )
+    }
+
+    /// Processes the result of the path's last ray, and sets up its next
+    /// ray if it has one.  Returns whether the path is still alive.
+    fn next(
+        &mut self,
+        scene: &Scene,
+        settings: &RenderSettings,
+        isect: &surface::SurfaceIntersection,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+    ) -> bool {
+        // The path tracer's light sampling finds light one bounce past
+        // `max_bounces`.  Paths go that one bounce further too, but only
+        // pick up what light sampling could have found on it, so that
+        // both integrators take the same set of light paths into account.
+        let light_only = self.bounce_count > settings.max_bounces;
+
+        if let surface::SurfaceIntersection::Hit {
+            intersection_data: ref idata,
+            ref closure,
+        } = *isect
+        {
+            if let SurfaceClosure::Emit(color) = *closure {
+                if !light_only || idata.sample_pdf > 0.0 {
+                    self.color += color.to_spectral_sample(self.wavelength).e * self.throughput;
+                }
+                return false;
+            }
+            if light_only {
+                return false;
+            }
+
+            let uv = (
+                self.vertex_samp(dims::BSDF, 0),
+                self.vertex_samp(dims::BSDF, 1),
+            );
+            if let Some((mut ray, factor)) = sample_closure(idata, closure, uv, self.wavelength) {
+                ray.time = self.time;
+                rays.set_from_ray(&ray, false, ray_idx);
+                self.throughput *= factor;
+                self.bounce_count += 1;
+                return true;
+            }
+            return false;
+        } else {
+            let camera_ray = self.bounce_count == 0;
+            if !light_only {
+                let background = if camera_ray {
+                    scene
+                        .world
+                        .camera_background(settings.pixel_uv(self.pixel_co))
+                } else {
+                    scene.world.background_color
+                };
+                self.color += background.to_spectral_sample(self.wavelength).e * self.throughput;
+            }
+            let throughput = self.throughput;
+            let color = &mut self.color;
+            scene.world_lights_from_direction(
+                rays.dir(ray_idx),
+                self.wavelength,
+                self.time,
+                camera_ray,
+                |light_color, _| *color += light_color.e * throughput,
+            );
+            return false;
+        }
+    }
+}
@@ -0,0 +1,863 @@
+//! Render settings, and the layering of command line overrides on top of
+//! the settings parsed from a scene file.
+
+use std::{path::Path, str::FromStr};
+
+use crate::{
+    hilbert,
+    image::{read_pfm, Accumulation},
+    math::fast_logit,
+    mis::MisHeuristic,
+    sensor::{SensorResponse, WhiteBalance},
+};
+
+/// The pixel reconstruction filter, sampled by offsetting each camera
+/// ray's position on the image plane.
+///
+/// This is filter importance sampling: samples are distributed according
+/// to the filter, and each one counts fully and only toward the pixel it
+/// was taken for.  So nothing is splatted across neighboring pixels, and
+/// every pixel is reconstructed from its own samples alone.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PixelFilter {
+    Box(f32),      // Width in pixels
+    Gaussian(f32), // Width in pixels
+}
+
+impl PixelFilter {
+    /// Maps a sample in [0, 1) to a pixel offset along one axis.  The
+    /// returned offset is relative to the pixel center.
+    #[inline(always)]
+    pub fn sample(&self, u: f32) -> f32 {
+        match *self {
+            PixelFilter::Box(width) => (u - 0.5) * width,
+            PixelFilter::Gaussian(width) => fast_logit(u, width),
+        }
+    }
+
+    /// Parses a filter specification of the form `name` or `name:width`,
+    /// e.g. `box` or `gaussian:2`.
+    pub fn from_spec(spec: &str) -> Result<PixelFilter, String> {
+        let mut parts = spec.splitn(2, ':');
+        let name = parts.next().unwrap().trim();
+        let width = if let Some(w) = parts.next() {
+            let w = f32::from_str(w.trim())
+                .map_err(|_| format!("invalid filter width '{}'", w.trim()))?;
+            if w <= 0.0 || w.is_nan() {
+                return Err("filter width must be positive".to_string());
+            }
+            Some(w)
+        } else {
+            None
+        };
+
+        match name {
+            "box" => Ok(PixelFilter::Box(width.unwrap_or(1.0))),
+            "gaussian" => Ok(PixelFilter::Gaussian(width.unwrap_or(1.5))),
+            _ => Err(format!(
+                "unknown filter '{}', expected 'box' or 'gaussian'",
+                name
+            )),
+        }
+    }
+}
+
+/// The light transport algorithm used to render.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Integrator {
+    PathTracing,
+    Sppm,      // Stochastic progressive photon mapping
+    Reference, // Brute force path tracing, for validation
+}
+
+impl Integrator {
+    pub fn from_spec(spec: &str) -> Result<Integrator, String> {
+        match spec {
+            "path" => Ok(Integrator::PathTracing),
+            "sppm" => Ok(Integrator::Sppm),
+            "reference" => Ok(Integrator::Reference),
+            _ => Err(format!(
+                "unknown integrator '{}', expected 'path', 'sppm' or 'reference'",
+                spec
+            )),
+        }
+    }
+}
+
+/// The order the image's buckets are rendered in.
+///
+/// This doesn't change the rendered image, only how it fills in and how
+/// well consecutive buckets share caches.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BucketOrder {
+    Hilbert,  // Along a generalized Hilbert curve, for locality
+    Scanline, // Row by row, top to bottom
+}
+
+impl BucketOrder {
+    pub fn from_spec(spec: &str) -> Result<BucketOrder, String> {
+        match spec {
+            "hilbert" => Ok(BucketOrder::Hilbert),
+            "scanline" => Ok(BucketOrder::Scanline),
+            _ => Err(format!(
+                "unknown bucket order '{}', expected 'hilbert' or 'scanline'",
+                spec
+            )),
+        }
+    }
+
+    /// Returns the (x, y) coordinates of every bucket in a grid of
+    /// `count_x` x `count_y` buckets, in render order.
+    pub fn buckets(&self, count_x: u32, count_y: u32) -> Vec<(u32, u32)> {
+        match *self {
+            BucketOrder::Hilbert => hilbert::generalized_curve(count_x, count_y),
+            BucketOrder::Scanline => (0..count_y)
+                .flat_map(|y| (0..count_x).map(move |x| (x, y)))
+                .collect(),
+        }
+    }
+}
+
+/// A replacement for every surface shader in the scene, for checking
+/// lighting and geometry without the scene's materials.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MaterialOverride {
+    Clay,             // Neutral gray diffuse
+    AmbientOcclusion, // White diffuse, lit only by a uniform white background
+    Normal,           // Shading normals as colors
+}
+
+impl MaterialOverride {
+    /// Parses an override name, where 'none' means no override.
+    pub fn from_spec(spec: &str) -> Result<Option<MaterialOverride>, String> {
+        match spec {
+            "none" => Ok(None),
+            "clay" => Ok(Some(MaterialOverride::Clay)),
+            "ao" => Ok(Some(MaterialOverride::AmbientOcclusion)),
+            "normal" => Ok(Some(MaterialOverride::Normal)),
+            _ => Err(format!(
+                "unknown material override '{}', expected 'clay', 'ao', 'normal' or 'none'",
+                spec
+            )),
+        }
+    }
+}
+
+/// How much checking and cleanup of geometry is done as the scene is
+/// built, to catch bad exports before they show up as NaNs and fireflies.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Sanitize {
+    Off,  // No checks
+    Warn, // Non-finite vertices are errors, other problems are warnings
+    Fix,  // Like Warn, and degenerate triangles are also removed
+}
+
+impl Sanitize {
+    pub fn from_spec(spec: &str) -> Result<Sanitize, String> {
+        match spec {
+            "off" => Ok(Sanitize::Off),
+            "warn" => Ok(Sanitize::Warn),
+            "fix" => Ok(Sanitize::Fix),
+            _ => Err(format!(
+                "unknown sanitize level '{}', expected 'off', 'warn' or 'fix'",
+                spec
+            )),
+        }
+    }
+}
+
+/// Which kinds of rays see proxy geometry in place of the surfaces that
+/// have it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ProxyRays {
+    pub camera: bool,
+    pub specular: bool, // Bounces off smooth surfaces
+    pub diffuse: bool,  // All other bounces
+    pub shadow: bool,
+}
+
+impl ProxyRays {
+    /// Parses a comma separated list of ray kinds, or "none".
+    pub fn from_spec(spec: &str) -> Result<ProxyRays, String> {
+        let mut rays = ProxyRays {
+            camera: false,
+            specular: false,
+            diffuse: false,
+            shadow: false,
+        };
+        if spec == "none" {
+            return Ok(rays);
+        }
+        for kind in spec.split(',') {
+            match kind.trim() {
+                "camera" => rays.camera = true,
+                "specular" => rays.specular = true,
+                "diffuse" => rays.diffuse = true,
+                "shadow" => rays.shadow = true,
+                kind => {
+                    return Err(format!(
+                        "unknown ray kind '{}', expected 'none' or a list of 'camera', \
+                         'specular', 'diffuse' and 'shadow'",
+                        kind
+                    ));
+                }
+            }
+        }
+        Ok(rays)
+    }
+}
+
+/// A grayscale map of how many samples each part of the image takes, so
+/// that render time goes where it matters.
+///
+/// White (1.0) is the full spp, and darker areas take proportionally
+/// fewer samples, but always at least one.  The mask is stretched over
+/// the image, so it needn't be the same resolution as the render.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportanceMask {
+    width: usize,
+    height: usize,
+    values: Vec<f32>, // Scanlines from the top, each in [0, 1]
+}
+
+impl ImportanceMask {
+    pub fn new(width: usize, height: usize, values: Vec<f32>) -> ImportanceMask {
+        assert!(width > 0 && height > 0);
+        assert_eq!(values.len(), width * height);
+        ImportanceMask {
+            width: width,
+            height: height,
+            values: values.iter().map(|v| v.max(0.0).min(1.0)).collect(),
+        }
+    }
+
+    /// Parses an importance mask setting: "none", or the path of a PFM
+    /// image whose channels are averaged for the mask.
+    pub fn from_spec(spec: &str) -> Result<Option<ImportanceMask>, String> {
+        if spec == "none" {
+            return Ok(None);
+        }
+        let image = read_pfm(Path::new(spec))
+            .map_err(|e| format!("couldn't read importance mask '{}': {}", spec, e))?;
+        let values = image
+            .data
+            .iter()
+            .map(|&(r, g, b)| (r + g + b) / 3.0)
+            .collect();
+        Ok(Some(ImportanceMask::new(image.width, image.height, values)))
+    }
+
+    /// The mask's value at `uv` on the image, from (0, 0) at its top left
+    /// to (1, 1) at its bottom right.  Outside of that, the nearest edge's
+    /// value is used.
+    pub fn value(&self, uv: (f32, f32)) -> f32 {
+        let x = (uv.0 * self.width as f32).max(0.0) as usize;
+        let y = (uv.1 * self.height as f32).max(0.0) as usize;
+        self.values[(y.min(self.height - 1) * self.width) + x.min(self.width - 1)]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    pub resolution: (usize, usize),
+    pub overscan: usize,   // Extra pixels rendered on each side of the image
+    pub pixel_aspect: f32, // Width of each pixel over its height
+    pub spp: usize,
+    pub seed: u32,
+    pub max_bounces: u32,
+    pub roulette_bounces: Option<u32>, // Bounces after which paths face Russian roulette, if ever
+    pub filter: PixelFilter,
+    pub mis: MisHeuristic,
+    pub light_splits: u32,  // Light samples per camera ray hit
+    pub bounce_splits: u32, // Bounce samples per camera ray hit
+    pub irradiance_cache: bool,
+    pub restir: bool,     // Whether to resample direct lighting at camera ray hits
+    pub ic_accuracy: f32, // Irradiance cache interpolation error limit
+    pub ic_samples: u32,  // Hemisphere samples per irradiance cache record
+    pub integrator: Integrator,
+    pub photons: u32,       // Photons per SPPM iteration, or 0 for one per pixel
+    pub photon_radius: f32, // Initial SPPM gather radius, or 0 to fit to pixels
+    pub bucket_order: BucketOrder,
+    pub split_buckets: bool, // Whether to split the last buckets of a pass for idle threads
+    pub auto_buckets: bool,  // Whether to size buckets by the render times of earlier passes
+    pub check_numerics: bool, // Whether to look for NaN/Inf samples
+    pub numerics_color: (f32, f32, f32), // Rec.709 color of pixels with NaN/Inf samples
+    pub dicing_rate: f32,    // Target micropolygon edge length, in pixels
+    pub material_override: Option<MaterialOverride>,
+    pub sanitize: Sanitize,
+    pub proxy_rays: ProxyRays,
+    pub accumulation: Accumulation, // Precision of each pixel's sample sums
+    pub max_time: Option<f32>,      // Seconds to stop rendering after, if any
+    pub progressive: bool,          // Whether to render in passes of doubling spp
+    pub sensor_response: SensorResponse, // How the camera turns spectra into colors
+    pub white_balance: WhiteBalance, // Which white comes out neutral in the image
+    pub importance_mask: Option<ImportanceMask>, // Where to take more or fewer samples
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            resolution: (0, 0),
+            overscan: 0,
+            pixel_aspect: 1.0,
+            spp: 1,
+            seed: 0,
+            max_bounces: 2,
+            roulette_bounces: None,
+            filter: PixelFilter::Gaussian(1.5),
+            mis: MisHeuristic::default(),
+            light_splits: 1,
+            bounce_splits: 1,
+            irradiance_cache: false,
+            restir: false,
+            ic_accuracy: 0.25,
+            ic_samples: 512,
+            integrator: Integrator::PathTracing,
+            photons: 0,
+            photon_radius: 0.0,
+            bucket_order: BucketOrder::Hilbert,
+            split_buckets: true,
+            auto_buckets: false,
+            check_numerics: false,
+            numerics_color: (1.0, 0.0, 1.0),
+            dicing_rate: 1.0,
+            material_override: None,
+            sanitize: Sanitize::Warn,
+            proxy_rays: ProxyRays {
+                camera: false,
+                specular: false,
+                diffuse: true,
+                shadow: true,
+            },
+            accumulation: Accumulation::Single,
+            max_time: None,
+            progressive: false,
+            sensor_response: SensorResponse::default(),
+            white_balance: WhiteBalance::default(),
+            importance_mask: None,
+        }
+    }
+}
+
+impl RenderSettings {
+    /// The size of the image that's rendered: the resolution, plus the
+    /// overscan on each side.  Pixel coordinates are within this, so the
+    /// display window starts at (overscan, overscan).
+    pub fn image_size(&self) -> (usize, usize) {
+        (
+            self.resolution.0 + (self.overscan * 2),
+            self.resolution.1 + (self.overscan * 2),
+        )
+    }
+
+    /// Where the center of the pixel at the given pixel coordinates is on
+    /// the image, from (0, 0) at its top left to (1, 1) at its bottom
+    /// right.  Overscan pixels are outside of that range.
+    pub fn pixel_uv(&self, pixel_co: (u32, u32)) -> (f32, f32) {
+        (
+            (pixel_co.0 as f32 + 0.5 - self.overscan as f32) / self.resolution.0 as f32,
+            (pixel_co.1 as f32 + 0.5 - self.overscan as f32) / self.resolution.1 as f32,
+        )
+    }
+
+    /// How many of the `spp` samples the pixel at the given pixel
+    /// coordinates takes, which is fewer where the importance mask is
+    /// darker.
+    pub fn pixel_spp(&self, pixel_co: (u32, u32)) -> usize {
+        if let Some(ref mask) = self.importance_mask {
+            let fraction = mask.value(self.pixel_uv(pixel_co));
+            ((self.spp as f32 * fraction).ceil() as usize)
+                .max(1)
+                .min(self.spp)
+        } else {
+            self.spp
+        }
+    }
+
+    /// Overrides a single setting by name, parsing the value from a string.
+    ///
+    /// This is the layer the command line's `--set key=value` is applied
+    /// through, after the scene file has been parsed.
+    pub fn apply_override(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        match key.trim() {
+            "resolution" => {
+                let mut dims = value.splitn(2, ['x', 'X']);
+                let w = dims.next().and_then(|w| usize::from_str(w.trim()).ok());
+                let h = dims.next().and_then(|h| usize::from_str(h.trim()).ok());
+                if let (Some(w), Some(h)) = (w, h) {
+                    if w == 0 || h == 0 {
+                        return Err("resolution must be at least 1x1".to_string());
+                    }
+                    self.resolution = (w, h);
+                } else {
+                    return Err("resolution must be in the form 'WIDTHxHEIGHT'".to_string());
+                }
+            }
+            "spp" => {
+                let spp = parse_value(key, value)?;
+                if spp == 0 {
+                    return Err("spp must be at least 1".to_string());
+                }
+                self.spp = spp;
+            }
+            "seed" => {
+                self.seed = parse_value(key, value)?;
+            }
+            "max_bounces" => {
+                self.max_bounces = parse_value(key, value)?;
+            }
+            "roulette" => {
+                self.roulette_bounces = if value == "off" {
+                    None
+                } else {
+                    Some(parse_value(key, value)?)
+                };
+            }
+            "overscan" => {
+                self.overscan = parse_value(key, value)?;
+            }
+            "pixel_aspect" => {
+                let aspect: f32 = parse_value(key, value)?;
+                if aspect <= 0.0 || !aspect.is_finite() {
+                    return Err("pixel_aspect must be positive".to_string());
+                }
+                self.pixel_aspect = aspect;
+            }
+            "filter" => {
+                self.filter = PixelFilter::from_spec(value)?;
+            }
+            "mis" => {
+                self.mis = MisHeuristic::from_spec(value)?;
+            }
+            "light_splits" => {
+                self.light_splits = parse_splits(key, value)?;
+            }
+            "bounce_splits" => {
+                self.bounce_splits = parse_splits(key, value)?;
+            }
+            "irradiance_cache" => {
+                self.irradiance_cache = parse_switch(key, value)?;
+            }
+            "restir" => {
+                self.restir = parse_switch(key, value)?;
+            }
+            "ic_accuracy" => {
+                let accuracy: f32 = parse_value(key, value)?;
+                if accuracy <= 0.0 || accuracy.is_nan() {
+                    return Err("ic_accuracy must be positive".to_string());
+                }
+                self.ic_accuracy = accuracy;
+            }
+            "ic_samples" => {
+                let samples: u32 = parse_value(key, value)?;
+                if samples < 16 {
+                    return Err("ic_samples must be at least 16".to_string());
+                }
+                self.ic_samples = samples;
+            }
+            "integrator" => {
+                self.integrator = Integrator::from_spec(value)?;
+            }
+            "photons" => {
+                self.photons = parse_value(key, value)?;
+            }
+            "photon_radius" => {
+                let radius: f32 = parse_value(key, value)?;
+                if radius < 0.0 || !radius.is_finite() {
+                    return Err("photon_radius must not be negative".to_string());
+                }
+                self.photon_radius = radius;
+            }
+            "bucket_order" => {
+                self.bucket_order = BucketOrder::from_spec(value)?;
+            }
+            "split_buckets" => {
+                self.split_buckets = parse_switch(key, value)?;
+            }
+            "auto_buckets" => {
+                self.auto_buckets = parse_switch(key, value)?;
+            }
+            "progressive" => {
+                self.progressive = parse_switch(key, value)?;
+            }
+            "check_numerics" => {
+                self.check_numerics = parse_switch(key, value)?;
+            }
+            "numerics_color" => {
+                let rgb: Vec<_> = value.split(',').map(|c| f32::from_str(c.trim())).collect();
+                if let [Ok(r), Ok(g), Ok(b)] = rgb[..] {
+                    self.numerics_color = (r, g, b);
+                } else {
+                    return Err("numerics_color must be in the form 'R,G,B'".to_string());
+                }
+            }
+            "dicing_rate" => {
+                let rate: f32 = parse_value(key, value)?;
+                if rate <= 0.0 || !rate.is_finite() {
+                    return Err("dicing_rate must be positive".to_string());
+                }
+                self.dicing_rate = rate;
+            }
+            "material_override" => {
+                self.material_override = MaterialOverride::from_spec(value)?;
+            }
+            "sanitize" => {
+                self.sanitize = Sanitize::from_spec(value)?;
+            }
+            "proxy_rays" => {
+                self.proxy_rays = ProxyRays::from_spec(value)?;
+            }
+            "accumulation" => {
+                self.accumulation = Accumulation::from_spec(value)?;
+            }
+            "sensor_response" => {
+                self.sensor_response = SensorResponse::from_spec(value)?;
+            }
+            "white_balance" => {
+                self.white_balance = WhiteBalance::from_spec(value)?;
+            }
+            "importance_mask" => {
+                self.importance_mask = ImportanceMask::from_spec(value)?;
+            }
+            _ => {
+                return Err(format!("unknown render setting '{}'", key.trim()));
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Same as `apply_override()`, but takes a `key=value` string.
+    pub fn apply_override_str(&mut self, key_value: &str) -> Result<(), String> {
+        let mut parts = key_value.splitn(2, '=');
+        let key = parts.next().unwrap();
+        if let Some(value) = parts.next() {
+            self.apply_override(key, value)
+        } else {
+            Err(format!("expected 'key=value', got '{}'", key_value))
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    T::from_str(value).map_err(|_| format!("invalid value '{}' for '{}'", value, key.trim()))
+}
+
+fn parse_switch(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "true" => Ok(true),
+        "off" | "false" => Ok(false),
+        _ => Err(format!("{} must be 'on' or 'off'", key.trim())),
+    }
+}
+
+fn parse_splits(key: &str, value: &str) -> Result<u32, String> {
+    let n: u32 = parse_value(key, value)?;
+    if n == 0 {
+        return Err(format!("'{}' must be at least 1", key.trim()));
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn override_basic() {
+        let mut settings = RenderSettings::default();
+        settings.apply_override_str("spp=64").unwrap();
+        settings.apply_override_str("max_bounces=4").unwrap();
+        settings.apply_override_str("resolution=640x480").unwrap();
+        assert_eq!(settings.spp, 64);
+        assert_eq!(settings.max_bounces, 4);
+        assert_eq!(settings.resolution, (640, 480));
+
+        assert!(settings.apply_override_str("spp=0").is_err());
+        assert!(settings.apply_override_str("resolution=0x480").is_err());
+        assert!(settings.apply_override_str("resolution=640x0").is_err());
+        assert_eq!(settings.spp, 64);
+        assert_eq!(settings.resolution, (640, 480));
+    }
+
+    #[test]
+    fn override_overscan() {
+        let mut settings = RenderSettings::default();
+        settings.apply_override_str("resolution=640x480").unwrap();
+        assert_eq!(settings.image_size(), (640, 480));
+        settings.apply_override_str("overscan=16").unwrap();
+        assert_eq!(settings.overscan, 16);
+        assert_eq!(settings.resolution, (640, 480));
+        assert_eq!(settings.image_size(), (672, 512));
+        assert_eq!(settings.pixel_uv((16, 16)), (0.5 / 640.0, 0.5 / 480.0));
+        assert!(settings.pixel_uv((0, 0)).0 < 0.0);
+        assert!(settings.apply_override_str("overscan=-1").is_err());
+    }
+
+    #[test]
+    fn importance_mask_samples() {
+        let mut settings = RenderSettings::default();
+        settings.resolution = (4, 2);
+        settings.spp = 16;
+        assert_eq!(settings.pixel_spp((3, 1)), 16);
+
+        settings.importance_mask = Some(ImportanceMask::new(2, 1, vec![1.0, 0.2]));
+        assert_eq!(settings.pixel_spp((0, 0)), 16);
+        assert_eq!(settings.pixel_spp((1, 1)), 16);
+        assert_eq!(settings.pixel_spp((2, 0)), 4);
+        settings.importance_mask = Some(ImportanceMask::new(1, 1, vec![-1.0]));
+        assert_eq!(settings.pixel_spp((0, 0)), 1);
+
+        settings.apply_override_str("importance_mask=none").unwrap();
+        assert_eq!(settings.importance_mask, None);
+        assert!(settings
+            .apply_override_str("importance_mask=no_such_mask.pfm")
+            .is_err());
+    }
+
+    #[test]
+    fn override_pixel_aspect() {
+        let mut settings = RenderSettings::default();
+        assert_eq!(settings.pixel_aspect, 1.0);
+        settings.apply_override_str("pixel_aspect=2").unwrap();
+        assert_eq!(settings.pixel_aspect, 2.0);
+        assert!(settings.apply_override_str("pixel_aspect=0").is_err());
+        assert!(settings.apply_override_str("pixel_aspect=wide").is_err());
+    }
+
+    #[test]
+    fn override_later_wins() {
+        let mut settings = RenderSettings::default();
+        settings.apply_override_str("seed=1").unwrap();
+        settings.apply_override_str("seed=7").unwrap();
+        assert_eq!(settings.seed, 7);
+    }
+
+    #[test]
+    fn override_filter() {
+        let mut settings = RenderSettings::default();
+        settings.apply_override_str("filter=gaussian:2").unwrap();
+        assert_eq!(settings.filter, PixelFilter::Gaussian(2.0));
+        settings.apply_override_str("filter=box").unwrap();
+        assert_eq!(settings.filter, PixelFilter::Box(1.0));
+        assert!(settings.apply_override_str("filter=box:-1").is_err());
+        assert!(settings.apply_override_str("filter=sinc").is_err());
+    }
+
+    #[test]
+    fn filter_sampling_is_inverse_cdf() {
+        // Offsets increase with the sample value, are centered on the
+        // pixel (up to the approximate log in the gaussian), and stay
+        // within the filter's support.
+        for filter in &[PixelFilter::Box(1.0), PixelFilter::Gaussian(1.5)] {
+            let offsets: Vec<f32> = (0..=100).map(|i| filter.sample(i as f32 / 100.0)).collect();
+            assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(offsets[50].abs() < 0.02);
+            assert!((offsets[0] + offsets[100]).abs() < 0.02);
+        }
+        assert_eq!(PixelFilter::Box(2.0).sample(0.0), -1.0);
+        assert!(PixelFilter::Box(2.0).sample(0.999) < 1.0);
+        assert!(PixelFilter::Gaussian(1.5).sample(0.0).abs() < 1.5 * 1.1);
+    }
+
+    #[test]
+    fn override_mis() {
+        let mut settings = RenderSettings::default();
+        assert_eq!(settings.mis, MisHeuristic::Power(2.0));
+        settings.apply_override_str("mis=balance").unwrap();
+        assert_eq!(settings.mis, MisHeuristic::Balance);
+        settings.apply_override_str("mis=power:3").unwrap();
+        assert_eq!(settings.mis, MisHeuristic::Power(3.0));
+        assert!(settings.apply_override_str("mis=cheese").is_err());
+    }
+
+    #[test]
+    fn override_splits() {
+        let mut settings = RenderSettings::default();
+        assert_eq!((settings.light_splits, settings.bounce_splits), (1, 1));
+        settings.apply_override_str("light_splits=8").unwrap();
+        settings.apply_override_str("bounce_splits=2").unwrap();
+        assert_eq!((settings.light_splits, settings.bounce_splits), (8, 2));
+        assert!(settings.apply_override_str("light_splits=0").is_err());
+        assert!(settings.apply_override_str("bounce_splits=-1").is_err());
+    }
+
+    #[test]
+    fn override_irradiance_cache() {
+        let mut settings = RenderSettings::default();
+        assert!(!settings.irradiance_cache);
+        settings.apply_override_str("irradiance_cache=on").unwrap();
+        settings.apply_override_str("ic_accuracy=0.1").unwrap();
+        settings.apply_override_str("ic_samples=1024").unwrap();
+        assert!(settings.irradiance_cache);
+        assert_eq!(settings.ic_accuracy, 0.1);
+        assert_eq!(settings.ic_samples, 1024);
+        settings.apply_override_str("irradiance_cache=off").unwrap();
+        assert!(!settings.irradiance_cache);
+        assert!(settings
+            .apply_override_str("irradiance_cache=maybe")
+            .is_err());
+        assert!(settings.apply_override_str("ic_accuracy=0").is_err());
+        assert!(settings.apply_override_str("ic_samples=4").is_err());
+    }
+
+    #[test]
+    fn override_sppm() {
+        let mut settings = RenderSettings::default();
+        assert_eq!(settings.integrator, Integrator::PathTracing);
+        settings.apply_override_str("integrator=sppm").unwrap();
+        settings.apply_override_str("photons=100000").unwrap();
+        settings.apply_override_str("photon_radius=0.05").unwrap();
+        assert_eq!(settings.integrator, Integrator::Sppm);
+        assert_eq!(settings.photons, 100000);
+        assert_eq!(settings.photon_radius, 0.05);
+        settings.apply_override_str("integrator=reference").unwrap();
+        assert_eq!(settings.integrator, Integrator::Reference);
+        settings.apply_override_str("integrator=path").unwrap();
+        assert_eq!(settings.integrator, Integrator::PathTracing);
+        assert!(settings.apply_override_str("integrator=bdpt").is_err());
+        assert!(settings.apply_override_str("photon_radius=-1").is_err());
+    }
+
+    #[test]
+    fn override_bucket_order() {
+        let mut settings = RenderSettings::default();
+        assert_eq!(settings.bucket_order, BucketOrder::Hilbert);
+        settings
+            .apply_override_str("bucket_order=scanline")
+            .unwrap();
+        assert_eq!(settings.bucket_order, BucketOrder::Scanline);
+        assert_eq!(
+            settings.bucket_order.buckets(2, 2),
+            vec![(0, 0), (1, 0), (0, 1), (1, 1)]
+        );
+        assert!(settings.apply_override_str("bucket_order=spiral").is_err());
+    }
+
+    #[test]
+    fn override_check_numerics() {
+        let mut settings = RenderSettings::default();
+        assert!(!settings.check_numerics);
+        settings.apply_override_str("check_numerics=on").unwrap();
+        settings
+            .apply_override_str("numerics_color=0, 1, 0.5")
+            .unwrap();
+        assert!(settings.check_numerics);
+        assert_eq!(settings.numerics_color, (0.0, 1.0, 0.5));
+        assert!(settings.apply_override_str("check_numerics=yes").is_err());
+        assert!(settings.apply_override_str("numerics_color=1,0").is_err());
+        assert!(settings
+            .apply_override_str("numerics_color=1,0,pink")
+            .is_err());
+    }
+
+    #[test]
+    fn override_dicing_rate() {
+        let mut settings = RenderSettings::default();
+        assert_eq!(settings.dicing_rate, 1.0);
+        settings.apply_override_str("dicing_rate=4").unwrap();
+        assert_eq!(settings.dicing_rate, 4.0);
+        assert!(settings.apply_override_str("dicing_rate=0").is_err());
+        assert!(settings.apply_override_str("dicing_rate=inf").is_err());
+    }
+
+    #[test]
+    fn override_restir() {
+        let mut settings = RenderSettings::default();
+        assert!(!settings.restir);
+        settings.apply_override_str("restir=on").unwrap();
+        assert!(settings.restir);
+        assert!(settings.apply_override_str("restir=2").is_err());
+    }
+
+    #[test]
+    fn override_roulette() {
+        let mut settings = RenderSettings::default();
+        assert_eq!(settings.roulette_bounces, None);
+        settings.apply_override_str("roulette=3").unwrap();
+        assert_eq!(settings.roulette_bounces, Some(3));
+        settings.apply_override_str("roulette=off").unwrap();
+        assert_eq!(settings.roulette_bounces, None);
+        assert!(settings.apply_override_str("roulette=-1").is_err());
+    }
+
+    #[test]
+    fn override_split_buckets() {
+        let mut settings = RenderSettings::default();
+        assert!(settings.split_buckets);
+        settings.apply_override_str("split_buckets=off").unwrap();
+        assert!(!settings.split_buckets);
+
+        assert!(!settings.auto_buckets);
+        settings.apply_override_str("auto_buckets=on").unwrap();
+        assert!(settings.auto_buckets);
+    }
+
+    #[test]
+    fn override_accumulation() {
+        let mut settings = RenderSettings::default();
+        assert_eq!(settings.accumulation, Accumulation::Single);
+        settings.apply_override_str("accumulation=kahan").unwrap();
+        assert_eq!(settings.accumulation, Accumulation::Kahan);
+        settings.apply_override_str("accumulation=f64").unwrap();
+        assert_eq!(settings.accumulation, Accumulation::Double);
+        assert!(settings.apply_override_str("accumulation=f16").is_err());
+    }
+
+    #[test]
+    fn override_material() {
+        let mut settings = RenderSettings::default();
+        assert_eq!(settings.material_override, None);
+        settings.apply_override_str("material_override=ao").unwrap();
+        assert_eq!(
+            settings.material_override,
+            Some(MaterialOverride::AmbientOcclusion)
+        );
+        settings
+            .apply_override_str("material_override=none")
+            .unwrap();
+        assert_eq!(settings.material_override, None);
+        assert!(settings
+            .apply_override_str("material_override=chrome")
+            .is_err());
+    }
+
+    #[test]
+    fn override_sanitize() {
+        let mut settings = RenderSettings::default();
+        assert_eq!(settings.sanitize, Sanitize::Warn);
+        settings.apply_override_str("sanitize=fix").unwrap();
+        assert_eq!(settings.sanitize, Sanitize::Fix);
+        settings.apply_override_str("sanitize=off").unwrap();
+        assert_eq!(settings.sanitize, Sanitize::Off);
+        assert!(settings.apply_override_str("sanitize=strict").is_err());
+    }
+
+    #[test]
+    fn override_proxy_rays() {
+        let mut settings = RenderSettings::default();
+        assert!(settings.proxy_rays.diffuse && settings.proxy_rays.shadow);
+        assert!(!settings.proxy_rays.camera && !settings.proxy_rays.specular);
+
+        settings
+            .apply_override_str("proxy_rays=camera, specular")
+            .unwrap();
+        assert!(settings.proxy_rays.camera && settings.proxy_rays.specular);
+        assert!(!settings.proxy_rays.diffuse && !settings.proxy_rays.shadow);
+
+        settings.apply_override_str("proxy_rays=none").unwrap();
+        assert_eq!(settings.proxy_rays, ProxyRays::from_spec("none").unwrap());
+        assert!(!settings.proxy_rays.camera && !settings.proxy_rays.shadow);
+        assert!(settings.apply_override_str("proxy_rays=glossy").is_err());
+    }
+
+    #[test]
+    fn override_errors() {
+        let mut settings = RenderSettings::default();
+        assert!(settings.apply_override_str("spp").is_err());
+        assert!(settings.apply_override_str("spp=lots").is_err());
+        assert!(settings.apply_override_str("nonsense=1").is_err());
+        assert!(settings.apply_override_str("resolution=640").is_err());
+    }
+}
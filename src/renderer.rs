@@ -1,39 +1,60 @@
 use std::{
     cell::Cell,
-    cmp,
     cmp::min,
+    f32::consts::PI as PI_32,
     io::{self, Write},
-    sync::{Mutex, RwLock},
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, RwLock,
+    },
 };
 
-use crossbeam::sync::MsQueue;
 use scoped_threadpool::Pool;
 
 use glam::Vec4;
 
 use crate::{
     accel::ACCEL_NODE_RAY_TESTS,
-    color::{map_0_1_to_wavelength, SpectralSample, XYZ},
-    fp_utils::robust_ray_origin,
+    bbox::BBox,
+    color::{map_0_1_to_wavelength, rec709_e_to_xyz, xyz_to_rec709_e, Color, SpectralSample, XYZ},
+    cost_map::CostMap,
+    fp_utils::{ray_point_err, robust_ray_origin},
     hash::hash_u32,
-    hilbert,
-    image::Image,
-    math::{fast_logit, upper_power_of_two},
-    mis::power_heuristic,
+    image::{Image, ImageEncoding, PixelSum},
+    irradiance_cache::{GatherSample, Hemisphere, IrradianceCache, IrradianceRecord},
+    logger::{Event, Logger},
+    math::{dot, max_element, Matrix4x4, Normal, Point, Vector},
+    output::Checkpointer,
+    probes::{sh_basis, Probe, SH_COEFFICIENTS},
     ray::{Ray, RayBatch},
-    scene::{Scene, SceneLightSample},
-    surface,
+    reference,
+    render_settings::{Integrator, RenderSettings},
+    restir::{self, LightSampleValues},
+    sampling::{
+        dims::{self, Dims},
+        uniform_sample_sphere,
+    },
+    scene::{
+        cos_between, sample_equiangular, sample_exponential, Atmosphere, Scene, SceneLightSample,
+    },
+    shading::surface_closure::SurfaceClosure,
+    sppm,
+    surface::{
+        self,
+        dicing_cache::{self, DICED_MICROPOLYS},
+    },
     timer::Timer,
     tracer::Tracer,
     transform_stack::TransformStack,
+    work_queue::WorkQueue,
 };
 
 #[derive(Debug)]
 pub struct Renderer<'a> {
     pub output_file: String,
-    pub resolution: (usize, usize),
-    pub spp: usize,
-    pub seed: u32,
+    pub output_encoding: ImageEncoding,
+    pub settings: RenderSettings,
     pub scene: Scene<'a>,
 }
 
@@ -41,165 +62,407 @@ pub struct Renderer<'a> {
 pub struct RenderStats {
     pub trace_time: f64,
     pub accel_node_visits: u64,
+    pub diced_micropolys: u64, // Including any diced again after being evicted from the cache
     pub ray_count: u64,
     pub initial_ray_generation_time: f64,
     pub ray_generation_time: f64,
     pub sample_writing_time: f64,
+    pub merge_time: f64, // Time spent copying finished buckets into the image
     pub total_time: f64,
+    pub idle_time: f64, // Time threads spent waiting for others to finish, in total
+    pub max_idle_time: f64, // Idle time of the thread that waited the most
+    pub nonfinite_samples: u64, // NaN/Inf samples found, when checking for them
+    pub spp: usize,     // Samples per pixel reached, set by the integrator rather than collected
+
+    // Number of paths that ended after each number of bounces, and the
+    // time spent on paths while they were at each number of bounces.  The
+    // last bin also counts all longer paths.
+    pub path_lengths: [u64; PATH_LENGTH_BINS],
+    pub bounce_times: [f64; PATH_LENGTH_BINS],
 }
 
 impl RenderStats {
-    fn new() -> RenderStats {
+    pub(crate) fn new() -> RenderStats {
         RenderStats {
             trace_time: 0.0,
             accel_node_visits: 0,
+            diced_micropolys: 0,
             ray_count: 0,
             initial_ray_generation_time: 0.0,
             ray_generation_time: 0.0,
             sample_writing_time: 0.0,
+            merge_time: 0.0,
             total_time: 0.0,
+            idle_time: 0.0,
+            max_idle_time: 0.0,
+            nonfinite_samples: 0,
+            spp: 0,
+            path_lengths: [0; PATH_LENGTH_BINS],
+            bounce_times: [0.0; PATH_LENGTH_BINS],
         }
     }
 
-    fn collect(&mut self, other: RenderStats) {
+    pub(crate) fn collect(&mut self, other: RenderStats) {
         self.trace_time += other.trace_time;
         self.accel_node_visits += other.accel_node_visits;
+        self.diced_micropolys += other.diced_micropolys;
         self.ray_count += other.ray_count;
         self.initial_ray_generation_time += other.initial_ray_generation_time;
         self.ray_generation_time += other.ray_generation_time;
         self.sample_writing_time += other.sample_writing_time;
+        self.merge_time += other.merge_time;
         self.total_time += other.total_time;
+        self.idle_time += other.idle_time;
+        self.max_idle_time = self.max_idle_time.max(other.max_idle_time);
+        self.nonfinite_samples += other.nonfinite_samples;
+        for i in 0..PATH_LENGTH_BINS {
+            self.path_lengths[i] += other.path_lengths[i];
+            self.bounce_times[i] += other.bounce_times[i];
+        }
     }
 }
 
 impl<'a> Renderer<'a> {
+    /// Renders the scene.
+    ///
+    /// The result only depends on the scene and render settings: every
+    /// sample is determined by its pixel, sample index, and the seed, and
+    /// each pixel's samples are accumulated in sample index order by the
+    /// single thread that owns its bucket.  So the same seed and spp give
+    /// bit-identical images regardless of thread count, bucket size, or
+    /// scheduling.
+    ///
+    /// With a time limit, the samples are instead taken over a number of
+    /// passes over the whole image, and rendering stops early if the next
+    /// pass isn't expected to finish in time.  The spp reached is in the
+    /// returned stats.
     pub fn render(
         &self,
         max_samples_per_bucket: u32,
         crop: Option<(u32, u32, u32, u32)>,
         thread_count: u32,
         do_blender_output: bool,
+        checkpointer: Option<&Checkpointer>,
+        log: &Logger,
     ) -> (Image, RenderStats) {
-        let mut tpool = Pool::new(thread_count);
+        if self.settings.importance_mask.is_some()
+            && self.settings.integrator != Integrator::PathTracing
+        {
+            log.warning("importance_mask is only used by the path tracer.");
+        }
+        if self.settings.progressive && self.settings.integrator != Integrator::PathTracing {
+            log.warning("progressive is only used by the path tracer.");
+        }
+        if self.settings.auto_buckets && self.settings.integrator != Integrator::PathTracing {
+            log.warning("auto_buckets is only used by the path tracer.");
+        }
+
+        // ReSTIR shares light samples between the pixels of a bucket, and
+        // auto bucket sizing lays out the buckets by measured times, so
+        // the image would depend on timing.
+        let auto_buckets = self.settings.auto_buckets && !self.settings.restir;
+        if self.settings.auto_buckets && self.settings.restir {
+            log.warning("auto_buckets is ignored with restir.");
+        }
+
+        match self.settings.integrator {
+            Integrator::PathTracing => {}
+            Integrator::Sppm => {
+                return sppm::render(
+                    self,
+                    crop,
+                    thread_count,
+                    do_blender_output,
+                    checkpointer,
+                    log,
+                );
+            }
+            Integrator::Reference => {
+                return reference::render(
+                    self,
+                    crop,
+                    thread_count,
+                    do_blender_output,
+                    checkpointer,
+                    log,
+                );
+            }
+        }
 
-        let image = Image::new(self.resolution.0, self.resolution.1);
-        let (img_width, img_height) = (image.width(), image.height());
+        let mut tpool = Pool::new(thread_count);
+        let render_timer = Timer::new();
 
-        let all_jobs_queued = RwLock::new(false);
+        let mut image = self.new_image();
 
         let collective_stats = RwLock::new(RenderStats::new());
 
-        // Set up job queue
-        let job_queue = MsQueue::new();
-
-        // For printing render progress
+        // For reporting render progress
         let pixels_rendered = Mutex::new(Cell::new(0));
 
-        // Calculate dimensions and coordinates of what we're rendering.  This
-        // accounts for cropping.
-        let (width, height, start_x, start_y) = if let Some((x1, y1, x2, y2)) = crop {
-            let x1 = min(x1 as usize, img_width - 1);
-            let y1 = min(y1 as usize, img_height - 1);
-            let x2 = min(x2 as usize, img_width - 1);
-            let y2 = min(y2 as usize, img_height - 1);
-            (x2 - x1 + 1, y2 - y1 + 1, x1, y1)
+        // How many NaN/Inf samples have been reported, when checking for
+        // them.
+        let numerics_reported = AtomicUsize::new(0);
+
+        let (width, height, start_x, start_y) = self.render_region(crop);
+        let mut passes = sample_passes(
+            self.settings.spp as u32,
+            self.settings.max_time.is_some(),
+            self.settings.progressive,
+        );
+        // Auto bucket sizing needs a pass to measure before it can size
+        // the buckets of the rest.
+        if auto_buckets && passes.len() == 1 && passes[0].1 > 1 {
+            passes = vec![(0, 1), (1, passes[0].1)];
+        }
+        let total_pixels = width * height * passes.len();
+
+        log.log(&Event::RenderStarted {
+            total_pixels: total_pixels,
+            spp: self.settings.spp,
+            thread_count: thread_count,
+        });
+
+        // Fill the irradiance cache before rendering, if it's used.
+        let irradiance_cache = if self.settings.irradiance_cache && self.settings.max_bounces > 0 {
+            let mut timer = Timer::new();
+            let cache =
+                self.build_irradiance_cache((start_x, start_y, width, height), thread_count);
+            log.detail(&format!(
+                "\tIrradiance cache: {} records in {:.3}s",
+                cache.record_count(),
+                timer.tick()
+            ));
+            Some(cache)
         } else {
-            (img_width, img_height, 0, 0)
+            None
+        };
+
+        // Direct lighting can only be resampled where camera ray hits take
+        // a single light sample, and every pixel has the same number of
+        // them.
+        let resample_lights = self.settings.restir
+            && self.settings.light_splits == 1
+            && self.settings.bounce_splits == 1
+            && irradiance_cache.is_none()
+            && self.settings.importance_mask.is_none();
+        if self.settings.restir && !resample_lights {
+            log.warning(
+                "restir is ignored when splitting, using the irradiance cache, or using \
+                 an importance mask.",
+            );
+        }
+
+        // How long each part of the image takes, for auto bucket sizing.
+        let cost_map = if auto_buckets {
+            Some(Mutex::new(CostMap::new(
+                (start_x as u32, start_y as u32),
+                (width as u32, height as u32),
+            )))
+        } else {
+            None
         };
 
         // Render
-        tpool.scoped(|scope| {
-            // Spawn worker tasks
-            for _ in 0..thread_count {
-                let jq = &job_queue;
-                let ajq = &all_jobs_queued;
-                let img = &image;
-                let pixrenref = &pixels_rendered;
-                let cstats = &collective_stats;
-                scope.execute(move || {
-                    self.render_job(
-                        jq,
-                        ajq,
-                        img,
-                        width * height,
-                        pixrenref,
-                        cstats,
-                        do_blender_output,
-                    )
-                });
+        let sampling_timer = Timer::new();
+        let mut spp_done = 0;
+        let mut thread_idle_times = vec![0.0f64; thread_count as usize];
+        for (pass_i, &samples) in passes.iter().enumerate() {
+            // Stop when the next pass would go over the time limit.  The
+            // first pass is always rendered, so there's an image.
+            if let (Some(max_time), true) = (self.settings.max_time, pass_i > 0) {
+                let pass_estimate =
+                    sampling_timer.elapsed() / spp_done as f32 * (samples.1 - samples.0) as f32;
+                if render_timer.elapsed() + pass_estimate > max_time {
+                    log.detail(&format!(
+                        "\tTime limit: stopping after {} of {} spp",
+                        spp_done, self.settings.spp
+                    ));
+                    break;
+                }
             }
 
-            // Print initial 0.00% progress
-            print!("0.00%");
-            let _ = io::stdout().flush();
-
             // Determine bucket size based on the per-thread maximum number of samples to
             // calculate at a time.
             let (bucket_w, bucket_h) = {
-                let target_pixels_per_bucket = max_samples_per_bucket as f64 / self.spp as f64;
-                let target_bucket_dim = if target_pixels_per_bucket.sqrt() < 1.0 {
+                let target_pixels_per_bucket =
+                    max_samples_per_bucket as f64 / (samples.1 - samples.0) as f64;
+                let mut target_bucket_dim = if target_pixels_per_bucket.sqrt() < 1.0 {
                     1usize
                 } else {
                     target_pixels_per_bucket.sqrt() as usize
                 };
+                if cost_map.is_some() {
+                    target_bucket_dim *= AUTO_BUCKET_SCALE;
+                }
 
                 (target_bucket_dim, target_bucket_dim)
             };
-
-            // Populate job queue
-            let bucket_n = {
-                let bucket_count_x = ((width / bucket_w) + 1) as u32;
-                let bucket_count_y = ((height / bucket_h) + 1) as u32;
-                let larger = cmp::max(bucket_count_x, bucket_count_y);
-                let pow2 = upper_power_of_two(larger);
-                pow2 * pow2
-            };
-            for hilbert_d in 0..bucket_n {
-                let (bx, by) = hilbert::d2xy(hilbert_d);
-
-                let x = bx as usize * bucket_w;
-                let y = by as usize * bucket_h;
-                let w = if width >= x {
-                    min(bucket_w, width - x)
-                } else {
-                    bucket_w
-                };
-                let h = if height >= y {
-                    min(bucket_h, height - y)
-                } else {
-                    bucket_h
-                };
-                if x < width && y < height && w > 0 && h > 0 {
-                    job_queue.push(BucketJob {
+            log.detail(&format!(
+                "\tBucket size: {}x{}, order: {:?}",
+                bucket_w, bucket_h, self.settings.bucket_order
+            ));
+
+            // Lay out the buckets.  The region is never empty (see
+            // `render_region()`), which this relies on.
+            debug_assert!(width > 0 && height > 0);
+            let bucket_count_x = ((width - 1) / bucket_w + 1) as u32;
+            let bucket_count_y = ((height - 1) / bucket_h + 1) as u32;
+            let mut buckets: Vec<BucketJob> = self
+                .settings
+                .bucket_order
+                .buckets(bucket_count_x, bucket_count_y)
+                .into_iter()
+                .map(|(bx, by)| {
+                    let x = bx as usize * bucket_w;
+                    let y = by as usize * bucket_h;
+                    BucketJob {
                         x: (start_x + x) as u32,
                         y: (start_y + y) as u32,
-                        w: w as u32,
-                        h: h as u32,
+                        w: min(bucket_w, width - x) as u32,
+                        h: min(bucket_h, height - y) as u32,
+                        samples: samples,
+                    }
+                })
+                .collect();
+
+            // Split the buckets that the earlier passes found to be slow,
+            // so that no one bucket holds up the pass.
+            if let Some(cost_map) = cost_map.as_ref() {
+                let cost_map = cost_map.lock().unwrap();
+                let pass_samples = samples.1 - samples.0;
+                let rect = (start_x as u32, start_y as u32, width as u32, height as u32);
+                if let Some(pass_estimate) = cost_map.estimate(rect, pass_samples) {
+                    let max_bucket_time =
+                        pass_estimate / (thread_count as f64 * AUTO_BUCKETS_PER_THREAD);
+                    let bucket_count = buckets.len();
+                    buckets = split_slow_buckets(buckets, max_bucket_time, |b| {
+                        cost_map
+                            .estimate((b.x, b.y, b.w, b.h), pass_samples)
+                            .unwrap()
                     });
+                    log.detail(&format!(
+                        "\tAuto buckets: split slow buckets into {} more",
+                        buckets.len() - bucket_count
+                    ));
                 }
             }
 
-            // Mark done queuing jobs
-            *all_jobs_queued.write().unwrap() = true;
-        });
+            // Populate job queue
+            let job_queue = WorkQueue::new(thread_count as usize);
+            job_queue.deal(buckets);
+
+            let pass_timer = Timer::new();
+            let finish_times = Mutex::new(vec![0.0f32; thread_count as usize]);
+            tpool.scoped(|scope| {
+                // Spawn worker tasks
+                for thread_i in 0..(thread_count as usize) {
+                    let jq = &job_queue;
+                    let img = &image;
+                    let pixrenref = &pixels_rendered;
+                    let cstats = &collective_stats;
+                    let ic = irradiance_cache.as_ref();
+                    let cmap = cost_map.as_ref();
+                    let nrep = &numerics_reported;
+                    let ft = &finish_times;
+                    scope.execute(move || {
+                        self.render_job(
+                            jq,
+                            thread_i,
+                            img,
+                            total_pixels,
+                            pixrenref,
+                            cstats,
+                            ic,
+                            cmap,
+                            nrep,
+                            resample_lights,
+                            do_blender_output,
+                            checkpointer,
+                            log,
+                        );
+                        ft.lock().unwrap()[thread_i] = pass_timer.elapsed();
+                    });
+                }
+            });
+
+            // Every thread waits from when it runs out of buckets until
+            // the last one is done.
+            let pass_time = pass_timer.elapsed();
+            for (idle, &finish) in thread_idle_times
+                .iter_mut()
+                .zip(finish_times.lock().unwrap().iter())
+            {
+                *idle += (pass_time - finish).max(0.0) as f64;
+            }
+            spp_done = samples.1 as usize;
+
+            if let Some(cost_map) = cost_map.as_ref() {
+                if let Some((mean, std_dev)) = cost_map.lock().unwrap().take_bucket_times() {
+                    log.detail(&format!(
+                        "\tBucket times: mean {:.3}s, std. dev. {:.3}s",
+                        mean, std_dev
+                    ));
+                }
+            }
+
+            // Write the image so far, when rendering progressively.
+            if let (true, Some(checkpointer)) = (self.settings.progressive, checkpointer) {
+                let mut write_timer = Timer::new();
+                let mut pass_image = image.snapshot();
+                self.scale_to_full_spp(&mut pass_image, spp_done);
+                match checkpointer.write_pass(&mut pass_image, spp_done, self.settings.spp) {
+                    Ok(path) => log.log(&Event::CheckpointWritten {
+                        path: &path,
+                        seconds: write_timer.tick(),
+                    }),
+                    Err(e) => log.warning(&format!("writing pass image failed: {}", e)),
+                }
+            }
+        }
 
-        // Clear percentage progress print
-        print!("\r                \r",);
+        self.scale_to_full_spp(&mut image, spp_done);
 
         // Return the rendered image and stats
-        return (image, *collective_stats.read().unwrap());
+        let mut stats = *collective_stats.read().unwrap();
+        stats.spp = spp_done;
+        stats.idle_time = thread_idle_times.iter().sum();
+        stats.max_idle_time = thread_idle_times.iter().cloned().fold(0.0, f64::max);
+        return (image, stats);
+    }
+
+    /// Samples are scaled for each pixel's full spp, so this makes up for
+    /// any that weren't taken when only `spp_done` of them were.
+    fn scale_to_full_spp(&self, image: &mut Image, spp_done: usize) {
+        if spp_done >= self.settings.spp {
+            return;
+        }
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                let pixel_spp = self.settings.pixel_spp((x as u32, y as u32));
+                if spp_done < pixel_spp {
+                    let col = image.get(x, y);
+                    image.set(x, y, col * (pixel_spp as f32 / spp_done as f32));
+                }
+            }
+        }
     }
 
-    /// Waits for buckets in the job queue to render and renders them when available.
+    /// Renders buckets from the job queue until there are none left.
     fn render_job(
         &self,
-        job_queue: &MsQueue<BucketJob>,
-        all_jobs_queued: &RwLock<bool>,
+        job_queue: &WorkQueue<BucketJob>,
+        thread_i: usize,
         image: &Image,
         total_pixels: usize,
         pixels_rendered: &Mutex<Cell<usize>>,
         collected_stats: &RwLock<RenderStats>,
+        irradiance_cache: Option<&IrradianceCache>,
+        cost_map: Option<&Mutex<CostMap>>,
+        numerics_reported: &AtomicUsize,
+        resample_lights: bool,
         do_blender_output: bool,
+        checkpointer: Option<&Checkpointer>,
+        log: &Logger,
     ) {
         let mut stats = RenderStats::new();
         let mut timer = Timer::new();
@@ -207,67 +470,53 @@ impl<'a> Renderer<'a> {
 
         let mut paths = Vec::new();
         let mut rays = RayBatch::new();
+        let mut bucket_pixels = Vec::new();
+        let mut bad_pixels = Vec::new();
+        let numerics_color = {
+            let (x, y, z) = rec709_e_to_xyz(self.settings.numerics_color);
+            XYZ::new(x, y, z)
+        };
         let mut tracer = Tracer::from_assembly(&self.scene.root);
         let mut xform_stack = TransformStack::new();
 
-        // Pre-calculate some useful values related to the image plane
-        let cmpx = 1.0 / self.resolution.0 as f32;
-        let cmpy = 1.0 / self.resolution.1 as f32;
-        let min_x = -1.0;
-        let max_x = 1.0;
-        let min_y = -(self.resolution.1 as f32 / self.resolution.0 as f32);
-        let max_y = self.resolution.1 as f32 / self.resolution.0 as f32;
-        let x_extent = max_x - min_x;
-        let y_extent = max_y - min_y;
-
         // Render
         'render_loop: loop {
             paths.clear();
             rays.clear();
 
             // Get bucket, or exit if no more jobs left
-            let bucket: BucketJob;
-            loop {
-                if let Some(b) = job_queue.try_pop() {
-                    bucket = b;
-                    break;
-                } else if *all_jobs_queued.read().unwrap() {
-                    break 'render_loop;
+            let split = |bucket: &BucketJob| {
+                // ReSTIR shares light samples between the pixels of a
+                // bucket, so splitting would make the image depend on
+                // thread timing.
+                if self.settings.split_buckets && !resample_lights {
+                    bucket.split()
+                } else {
+                    None
                 }
-            }
+            };
+            let bucket = match job_queue.pop(thread_i, split) {
+                Some(bucket) => bucket,
+                None => break 'render_loop,
+            };
 
             timer.tick();
+            let bucket_timer = Timer::new();
+            let rays_before = tracer.rays_traced();
             // Generate light paths and initial rays
+            let spread = self.camera_ray_spread();
             for y in bucket.y..(bucket.y + bucket.h) {
                 for x in bucket.x..(bucket.x + bucket.w) {
-                    for si in 0..self.spp {
-                        // Calculate image plane x and y coordinates
-                        let (img_x, img_y) = {
-                            let filter_x =
-                                fast_logit(get_sample(4, si as u32, (x, y), self.seed), 1.5) + 0.5;
-                            let filter_y =
-                                fast_logit(get_sample(5, si as u32, (x, y), self.seed), 1.5) + 0.5;
-                            let samp_x = (filter_x + x as f32) * cmpx;
-                            let samp_y = (filter_y + y as f32) * cmpy;
-                            ((samp_x - 0.5) * x_extent, (0.5 - samp_y) * y_extent)
-                        };
-
-                        // Create the light path and initial ray for this sample
-                        let (path, ray) = LightPath::new(
-                            &self.scene,
-                            self.seed,
-                            (x, y),
-                            (img_x, img_y),
-                            (
-                                get_sample(2, si as u32, (x, y), self.seed),
-                                get_sample(3, si as u32, (x, y), self.seed),
-                            ),
-                            get_sample(1, si as u32, (x, y), self.seed),
-                            map_0_1_to_wavelength(get_sample(0, si as u32, (x, y), self.seed)),
-                            si as u32,
-                        );
+                    let pixel_spp = self.settings.pixel_spp((x, y)) as u32;
+                    for si in bucket.samples.0..bucket.samples.1.min(pixel_spp) {
+                        let (path, ray) = self.camera_path(x, y, si);
                         paths.push(path);
                         rays.push(ray, false);
+                        rays.mark_camera(rays.len() - 1);
+                        rays.set_spread(rays.len() - 1, spread);
+                        if self.settings.proxy_rays.camera {
+                            rays.mark_proxy(rays.len() - 1);
+                        }
                     }
                 }
             }
@@ -275,73 +524,173 @@ impl<'a> Renderer<'a> {
 
             // Trace the paths!
             let mut pi = paths.len();
+            let mut first_trace = true;
             while pi > 0 {
                 // Test rays against scene
                 let isects = tracer.trace(&mut rays);
-                stats.trace_time += timer.tick() as f64;
+                let step_trace_time = timer.tick() as f64;
+                stats.trace_time += step_trace_time;
+
+                // The camera rays' hits pick their light samples together,
+                // before any path moves on.
+                if resample_lights && first_trace {
+                    self.resample_direct_lighting(&mut xform_stack, &bucket, &mut paths, isects);
+                }
+                first_trace = false;
 
                 // Determine next rays to shoot based on result
                 let mut new_end = 0;
+                let mut bounce_paths = [0u32; PATH_LENGTH_BINS];
                 for i in 0..pi {
-                    if paths[i].next(&mut xform_stack, &self.scene, &isects[i], &mut rays, i) {
+                    bounce_paths[path_length_bin(paths[i].bounce_count)] += 1;
+                    if paths[i].next(
+                        &mut xform_stack,
+                        &self.scene,
+                        &self.settings,
+                        irradiance_cache,
+                        &isects[i],
+                        &mut rays,
+                        i,
+                    ) {
                         paths.swap(new_end, i);
                         rays.swap(new_end, i);
                         new_end += 1;
+                    } else {
+                        stats.path_lengths[path_length_bin(paths[i].bounce_count)] += 1;
                     }
                 }
                 rays.truncate(new_end);
+                let step_generation_time = timer.tick() as f64;
+                stats.ray_generation_time += step_generation_time;
+
+                // Share the time of this step, tracing and all, between
+                // the paths by how many bounces they were at.
+                let step_time = step_trace_time + step_generation_time;
+                for (time, &count) in stats.bounce_times.iter_mut().zip(bounce_paths.iter()) {
+                    *time += step_time * count as f64 / pi as f64;
+                }
                 pi = new_end;
-                stats.ray_generation_time += timer.tick() as f64;
             }
 
             {
-                // Calculate color based on ray hits and save to image
-                let min = (bucket.x, bucket.y);
-                let max = (bucket.x + bucket.w, bucket.y + bucket.h);
-                let mut img_bucket = image.get_bucket(min, max);
+                // Put the paths back in a canonical order, since tracing
+                // shuffles them.  This keeps the floating point summation
+                // order of each pixel's samples fixed.
+                paths.sort_unstable_by_key(|path| {
+                    (path.pixel_co.1, path.pixel_co.0, path.sample_number)
+                });
+
+                // Calculate color based on ray hits, accumulating it in a
+                // buffer local to this thread.
+                bucket_pixels.clear();
+                bucket_pixels.resize(bucket.w as usize * bucket.h as usize, PixelSum::new());
+                let exposure = self.scene.camera.exposure();
                 for path in &paths {
                     let path_col = SpectralSample::from_parts(path.color, path.wavelength);
-                    let mut col = img_bucket.get(path.pixel_co.0, path.pixel_co.1);
-                    col += XYZ::from_spectral_sample(&path_col) / self.spp as f32;
-                    img_bucket.set(path.pixel_co.0, path.pixel_co.1, col);
+                    let i = ((path.pixel_co.1 - bucket.y) * bucket.w + (path.pixel_co.0 - bucket.x))
+                        as usize;
+                    let sample_scale = exposure / self.settings.pixel_spp(path.pixel_co) as f32;
+                    let col = self
+                        .settings
+                        .white_balance
+                        .apply(self.settings.sensor_response.to_xyz(&path_col))
+                        * sample_scale;
+                    if self.settings.check_numerics && !col.is_finite() {
+                        stats.nonfinite_samples += 1;
+                        if numerics_reported.fetch_add(1, Ordering::Relaxed) < MAX_NUMERICS_REPORTS
+                        {
+                            log.warning(&format!(
+                                "non-finite sample {} in pixel ({}, {}): {}",
+                                path.sample_number,
+                                path.pixel_co.0,
+                                path.pixel_co.1,
+                                path.describe()
+                            ));
+                        }
+                        bad_pixels.push(i);
+                        continue;
+                    }
+                    bucket_pixels[i].add(col, self.settings.accumulation);
+                }
+
+                // Mark the pixels that had NaN/Inf samples.
+                for &i in &bad_pixels {
+                    bucket_pixels[i] = PixelSum::from_xyz(numerics_color);
                 }
+                bad_pixels.clear();
                 stats.sample_writing_time += timer.tick() as f64;
 
+                // Merge the finished bucket into the image in one go, so
+                // that it's only checked out for as long as that takes.
+                let min = (bucket.x, bucket.y);
+                let max = (bucket.x + bucket.w, bucket.y + bucket.h);
+                let mut img_bucket = image.get_bucket(min, max);
+                for (i, col) in bucket_pixels.iter().enumerate() {
+                    let x = bucket.x + (i as u32 % bucket.w);
+                    let y = bucket.y + (i as u32 / bucket.w);
+                    if bucket.samples.0 == 0 {
+                        img_bucket.set(x, y, col.total());
+                    } else {
+                        let prev = img_bucket.get(x, y);
+                        img_bucket.set(x, y, prev + col.total());
+                    }
+                }
+                stats.merge_time += timer.tick() as f64;
+
+                if let Some(cost_map) = cost_map {
+                    cost_map.lock().unwrap().record(
+                        (bucket.x, bucket.y, bucket.w, bucket.h),
+                        bucket.samples.1 - bucket.samples.0,
+                        bucket_timer.elapsed() as f64,
+                    );
+                }
+
                 // Pre-calculate base64 encoding if needed
                 let base64_enc = if do_blender_output {
-                    use crate::color::xyz_to_rec709_e;
                     Some(img_bucket.rgba_base64(xyz_to_rec709_e))
                 } else {
                     None
                 };
 
-                // Print render progress, and image data if doing blender output
+                // Report render progress, and image data if doing blender output
                 let guard = pixels_rendered.lock().unwrap();
                 let mut pr = (*guard).get();
-                let percentage_old = pr as f64 / total_pixels as f64 * 100.0;
-
                 pr += bucket.w as usize * bucket.h as usize;
                 (*guard).set(pr);
-                let percentage_new = pr as f64 / total_pixels as f64 * 100.0;
 
-                let old_string = format!("{:.2}%", percentage_old);
-                let new_string = format!("{:.2}%", percentage_new);
+                log.log(&Event::BucketDone {
+                    x: bucket.x,
+                    y: bucket.y,
+                    w: bucket.w,
+                    h: bucket.h,
+                    rays: tracer.rays_traced() - rays_before,
+                });
 
                 if let Some(bucket_data) = base64_enc {
-                    // If doing Blender output
+                    let percentage = pr as f64 / total_pixels as f64 * 100.0;
                     println!("DIV");
-                    println!("{}", new_string);
+                    println!("{:.2}%", percentage);
                     println!("{} {} {} {}", min.0, min.1, max.0, max.1);
                     println!("{}", bucket_data);
                     println!("BUCKET_END");
                     println!("DIV");
-                } else {
-                    // If doing console output
-                    if new_string != old_string {
-                        print!("\r{}", new_string);
+                    let _ = io::stdout().flush();
+                }
+            }
+
+            // Write a checkpoint if it's time.  This is done after the
+            // bucket is returned to the image, so that it's included.
+            if let Some(checkpointer) = checkpointer {
+                if checkpointer.bucket_done() {
+                    let mut checkpoint_timer = Timer::new();
+                    match checkpointer.write(image) {
+                        Ok(()) => log.log(&Event::CheckpointWritten {
+                            path: checkpointer.path(),
+                            seconds: checkpoint_timer.tick(),
+                        }),
+                        Err(e) => log.warning(&format!("checkpoint failed: {}", e)),
                     }
                 }
-                let _ = io::stdout().flush();
             }
         }
 
@@ -351,331 +700,1009 @@ impl<'a> Renderer<'a> {
             stats.accel_node_visits = anv.get();
             anv.set(0);
         });
+        DICED_MICROPOLYS.with(|dm| {
+            stats.diced_micropolys = dm.get();
+            dm.set(0);
+        });
 
         // Collect stats
         collected_stats.write().unwrap().collect(stats);
     }
-}
-
-#[derive(Debug)]
-enum LightPathEvent {
-    CameraRay,
-    BounceRay,
-    ShadowRay,
-}
-
-#[derive(Debug)]
-pub struct LightPath {
-    event: LightPathEvent,
-    bounce_count: u32,
 
-    sampling_seed: u32,
-    pixel_co: (u32, u32),
-    sample_number: u32, // Which sample in the LDS sequence this is.
-    dim_offset: Cell<u32>,
-    time: f32,
-    wavelength: f32,
+    /// Picks the light sample of each of a bucket's camera ray hits by
+    /// resampling, for `restir=on`.
+    ///
+    /// `paths` must still be in the order they were generated in, and
+    /// `isects` are the hits of their camera rays.
+    fn resample_direct_lighting(
+        &self,
+        xform_stack: &mut TransformStack,
+        bucket: &BucketJob,
+        paths: &mut [LightPath],
+        isects: &[surface::SurfaceIntersection],
+    ) {
+        let points: Vec<_> = paths
+            .iter()
+            .zip(isects)
+            .map(|(path, isect)| match *isect {
+                surface::SurfaceIntersection::Hit {
+                    intersection_data,
+                    closure,
+                } => {
+                    if let SurfaceClosure::Emit(_) = closure {
+                        None
+                    } else {
+                        Some(restir::ShadingPoint {
+                            idata: intersection_data,
+                            closure: closure,
+                            wavelength: path.wavelength,
+                            time: path.time,
+                            light_sample: path.light_sample_values(0),
+                        })
+                    }
+                }
+                _ => None,
+            })
+            .collect();
+
+        let resampled = restir::resample(
+            xform_stack,
+            &self.scene,
+            &self.settings,
+            &points,
+            (bucket.x, bucket.y, bucket.w, bucket.h),
+            bucket.samples,
+        );
+        for (path, light) in paths.iter_mut().zip(resampled) {
+            path.resampled_light = light;
+        }
+    }
 
-    next_bounce_ray: Option<Ray>,
-    next_attenuation_fac: Vec4,
+    /// Estimates the memory needed to render, in bytes: the scene, the
+    /// image, and each thread's ray buffers and diced geometry cache.
+    pub fn memory_estimate(&self, max_samples_per_bucket: u32, thread_count: u32) -> usize {
+        let (width, height) = self.settings.image_size();
+        let image = width * height * mem::size_of::<XYZ>();
+        let bucket_samples = (max_samples_per_bucket as usize).max(self.settings.spp);
+        let per_sample = mem::size_of::<LightPath>()
+            + mem::size_of::<Ray>()
+            + mem::size_of::<surface::SurfaceIntersection>();
+        let per_thread = (bucket_samples * per_sample) + dicing_cache::CACHE_SIZE;
+
+        self.scene.size_in_bytes() + image + (per_thread * thread_count as usize)
+    }
 
-    closure_sample_pdf: f32,
-    light_attenuation: Vec4,
-    pending_color_addition: Vec4,
-    color: Vec4,
-}
+    /// Traces sample `si` of pixel (`x`, `y`) with the path tracer,
+    /// returning a description of each event along the path, and the
+    /// sample's color.
+    ///
+    /// The irradiance cache isn't used, so the path takes all of its
+    /// bounces itself.
+    pub fn trace_pixel(&self, x: u32, y: u32, si: u32) -> (Vec<String>, XYZ) {
+        let mut tracer = Tracer::from_assembly(&self.scene.root);
+        let mut xform_stack = TransformStack::new();
+        let mut rays = RayBatch::new();
 
-#[allow(clippy::new_ret_no_self)]
-impl LightPath {
-    fn new(
-        scene: &Scene,
-        sampling_seed: u32,
-        pixel_co: (u32, u32),
-        image_plane_co: (f32, f32),
-        lens_uv: (f32, f32),
-        time: f32,
-        wavelength: f32,
-        sample_number: u32,
-    ) -> (LightPath, Ray) {
-        (
-            LightPath {
-                event: LightPathEvent::CameraRay,
-                bounce_count: 0,
+        let (mut path, ray) = self.camera_path(x, y, si);
+        path.trace = Some(Vec::new());
+        path.trace(|p| {
+            format!(
+                "pixel ({}, {}), sample {}: wavelength {:.1}nm, time {:.3}",
+                x, y, si, p.wavelength, p.time
+            )
+        });
+        path.trace(|_| {
+            format!(
+                "camera ray: origin {}, direction {}",
+                fmt_xyz(ray.orig.x(), ray.orig.y(), ray.orig.z()),
+                fmt_xyz(ray.dir.x(), ray.dir.y(), ray.dir.z()),
+            )
+        });
+        rays.push(ray, false);
+        rays.mark_camera(0);
+        rays.set_spread(0, self.camera_ray_spread());
+        if self.settings.proxy_rays.camera {
+            rays.mark_proxy(0);
+        }
 
-                sampling_seed: sampling_seed,
-                pixel_co: pixel_co,
-                sample_number: sample_number,
-                dim_offset: Cell::new(6),
-                time: time,
-                wavelength: wavelength,
+        loop {
+            let isects = tracer.trace(&mut rays);
+            if !path.next(
+                &mut xform_stack,
+                &self.scene,
+                &self.settings,
+                None,
+                &isects[0],
+                &mut rays,
+                0,
+            ) {
+                break;
+            }
+        }
 
-                next_bounce_ray: None,
-                next_attenuation_fac: Vec4::splat(1.0),
+        let col = self.settings.white_balance.apply(
+            self.settings
+                .sensor_response
+                .to_xyz(&SpectralSample::from_parts(path.color, path.wavelength)),
+        ) * self.scene.camera.exposure();
+        path.trace(|p| format!("final color {:?}", p.color));
 
-                closure_sample_pdf: 1.0,
-                light_attenuation: Vec4::splat(1.0),
-                pending_color_addition: Vec4::splat(0.0),
-                color: Vec4::splat(0.0),
-            },
-            scene.camera.generate_ray(
-                image_plane_co.0,
-                image_plane_co.1,
-                time,
-                wavelength,
-                lens_uv.0,
-                lens_uv.1,
-            ),
-        )
+        (path.trace.take().unwrap(), col)
     }
 
-    fn next_lds_samp(&self) -> f32 {
-        let dimension = self.dim_offset.get();
-        self.dim_offset.set(dimension + 1);
-        get_sample(
-            dimension,
-            self.sample_number,
-            self.pixel_co,
-            self.sampling_seed,
-        )
-    }
+    /// Bakes the light arriving at each of `positions` into spherical
+    /// harmonics, path tracing `spp` directions spread over the sphere
+    /// around each.  The probes are baked in parallel.
+    pub fn bake_probes(&self, positions: &[Point], thread_count: u32) -> Vec<Probe> {
+        let probes = Mutex::new(Vec::with_capacity(positions.len()));
+        let mut tpool = Pool::new(thread_count);
+        tpool.scoped(|scope| {
+            for (i, &pos) in positions.iter().enumerate() {
+                let probes = &probes;
+                scope.execute(move || {
+                    let probe = self.bake_probe(pos, i as u32);
+                    probes.lock().unwrap().push((i, probe));
+                });
+            }
+        });
 
-    fn next(
-        &mut self,
-        xform_stack: &mut TransformStack,
-        scene: &Scene,
-        isect: &surface::SurfaceIntersection,
-        rays: &mut RayBatch,
-        ray_idx: usize,
-    ) -> bool {
-        match self.event {
-            //--------------------------------------------------------------------
-            // Result of Camera or bounce ray, prepare next bounce and light rays
-            LightPathEvent::CameraRay | LightPathEvent::BounceRay => {
-                if let surface::SurfaceIntersection::Hit {
-                    intersection_data: ref idata,
-                    ref closure,
-                } = *isect
-                {
-                    // Hit something!  Do the stuff
-
-                    // If it's an emission closure, handle specially:
-                    // - Collect light from the emission.
-                    // - Terminate the path.
-                    use crate::shading::surface_closure::SurfaceClosure;
-                    if let SurfaceClosure::Emit(color) = *closure {
-                        let color = color.to_spectral_sample(self.wavelength).e;
-                        if let LightPathEvent::CameraRay = self.event {
-                            self.color += color;
-                        } else {
-                            let mis_pdf =
-                                power_heuristic(self.closure_sample_pdf, idata.sample_pdf);
-                            self.color += color * self.light_attenuation / mis_pdf;
-                        };
-
-                        return false;
-                    }
+        let mut probes = probes.into_inner().unwrap();
+        probes.sort_unstable_by_key(|(i, _)| *i);
+        probes.into_iter().map(|(_, probe)| probe).collect()
+    }
 
-                    // Roll the previous closure pdf into the attenauation
-                    self.light_attenuation /= self.closure_sample_pdf;
+    /// Bakes one light probe.  `probe_i` decorrelates its samples from
+    /// those of the other probes.
+    fn bake_probe(&self, pos: Point, probe_i: u32) -> Probe {
+        let seed = hash_u32(self.settings.seed, PROBE_SEED);
+        let pixel_co = (probe_i, 0);
+        let spp = self.settings.spp.max(1);
+        let mut tracer = Tracer::from_assembly(&self.scene.root);
+        let mut xform_stack = TransformStack::new();
+        let mut paths = Vec::with_capacity(PROBE_SAMPLES_PER_BATCH);
+        let mut rays = RayBatch::new();
+        let mut dirs = Vec::with_capacity(PROBE_SAMPLES_PER_BATCH);
+        let mut sh = [XYZ::new(0.0, 0.0, 0.0); SH_COEFFICIENTS];
 
-                    // Prepare light ray
-                    let light_n = self.next_lds_samp();
-                    let light_uvw = (
-                        self.next_lds_samp(),
-                        self.next_lds_samp(),
-                        self.next_lds_samp(),
-                    );
-                    xform_stack.clear();
-                    let light_info = scene.sample_lights(
-                        xform_stack,
-                        light_n,
-                        light_uvw,
-                        self.wavelength,
-                        self.time,
-                        isect,
-                    );
-                    let found_light = if light_info.is_none()
-                        || light_info.pdf() <= 0.0
-                        || light_info.selection_pdf() <= 0.0
-                    {
-                        false
-                    } else {
-                        let light_pdf = light_info.pdf();
-                        let light_sel_pdf = light_info.selection_pdf();
-
-                        // Calculate the shadow ray and surface closure stuff
-                        let (attenuation, closure_pdf, shadow_ray) = match light_info {
-                            SceneLightSample::None => unreachable!(),
-
-                            // Distant light
-                            SceneLightSample::Distant { direction, .. } => {
-                                let (attenuation, closure_pdf) = closure.evaluate(
-                                    rays.dir(ray_idx),
-                                    direction,
-                                    idata.nor,
-                                    idata.nor_g,
-                                    self.wavelength,
-                                );
-                                let shadow_ray = {
-                                    // Calculate the shadow ray for testing if the light is
-                                    // in shadow or not.
-                                    let offset_pos = robust_ray_origin(
-                                        idata.pos,
-                                        idata.pos_err,
-                                        idata.nor_g.normalized(),
-                                        direction,
-                                    );
-                                    Ray {
-                                        orig: offset_pos,
-                                        dir: direction,
-                                        time: self.time,
-                                        wavelength: self.wavelength,
-                                        max_t: std::f32::INFINITY,
-                                    }
-                                };
-                                (attenuation, closure_pdf, shadow_ray)
-                            }
-
-                            // Surface light
-                            SceneLightSample::Surface { sample_geo, .. } => {
-                                let dir = sample_geo.0 - idata.pos;
-                                let (attenuation, closure_pdf) = closure.evaluate(
-                                    rays.dir(ray_idx),
-                                    dir,
-                                    idata.nor,
-                                    idata.nor_g,
-                                    self.wavelength,
-                                );
-                                let shadow_ray = {
-                                    // Calculate the shadow ray for testing if the light is
-                                    // in shadow or not.
-                                    let offset_pos = robust_ray_origin(
-                                        idata.pos,
-                                        idata.pos_err,
-                                        idata.nor_g.normalized(),
-                                        dir,
-                                    );
-                                    let offset_end = robust_ray_origin(
-                                        sample_geo.0,
-                                        sample_geo.2,
-                                        sample_geo.1.normalized(),
-                                        -dir,
-                                    );
-                                    Ray {
-                                        orig: offset_pos,
-                                        dir: offset_end - offset_pos,
-                                        time: self.time,
-                                        wavelength: self.wavelength,
-                                        max_t: 1.0,
-                                    }
-                                };
-                                (attenuation, closure_pdf, shadow_ray)
-                            }
-                        };
-
-                        // If there's any possible contribution, set up for a
-                        // light ray.
-                        if attenuation.e.max_element() <= 0.0 {
-                            false
-                        } else {
-                            // Calculate and store the light that will be contributed
-                            // to the film plane if the light is not in shadow.
-                            let light_mis_pdf = power_heuristic(light_pdf, closure_pdf);
-                            self.pending_color_addition =
-                                light_info.color().e * attenuation.e * self.light_attenuation
-                                    / (light_mis_pdf * light_sel_pdf);
-
-                            rays.set_from_ray(&shadow_ray, true, ray_idx);
-
-                            true
-                        }
-                    };
-
-                    // Prepare bounce ray
-                    let do_bounce = if self.bounce_count < 2 {
-                        self.bounce_count += 1;
-
-                        // Sample closure
-                        let (dir, filter, pdf) = {
-                            let u = self.next_lds_samp();
-                            let v = self.next_lds_samp();
-                            closure.sample(
-                                idata.incoming,
-                                idata.nor,
-                                idata.nor_g,
-                                (u, v),
-                                self.wavelength,
-                            )
-                        };
-
-                        // Check if pdf is zero, to avoid NaN's.
-                        if (pdf > 0.0) && (filter.e.max_element() > 0.0) {
-                            // Account for the additional light attenuation from
-                            // this bounce
-                            self.next_attenuation_fac = filter.e;
-                            self.closure_sample_pdf = pdf;
-
-                            // Calculate the ray for this bounce
-                            let offset_pos = robust_ray_origin(
-                                idata.pos,
-                                idata.pos_err,
-                                idata.nor_g.normalized(),
-                                dir,
-                            );
-                            self.next_bounce_ray = Some(Ray {
-                                orig: offset_pos,
-                                dir: dir,
-                                time: self.time,
-                                wavelength: self.wavelength,
-                                max_t: std::f32::INFINITY,
-                            });
-
-                            true
-                        } else {
-                            false
-                        }
-                    } else {
-                        self.next_bounce_ray = None;
-                        false
-                    };
+        for batch_start in (0..spp).step_by(PROBE_SAMPLES_PER_BATCH) {
+            let batch_end = (batch_start + PROBE_SAMPLES_PER_BATCH).min(spp);
+            paths.clear();
+            rays.clear();
+            dirs.clear();
+            for si in batch_start..batch_end {
+                let s = si as u32;
+                let samp = |dims: Dims, i| get_sample(dims.dim(i), s, pixel_co, seed);
+                let wavelength = map_0_1_to_wavelength(samp(dims::WAVELENGTH, 0));
+                let time = samp(dims::TIME, 0);
+                let dir =
+                    uniform_sample_sphere(samp(dims::GATHER_DIR, 0), samp(dims::GATHER_DIR, 1));
+                paths.push(LightPath::new_probe(seed, pixel_co, s, time, wavelength));
+                rays.push(
+                    Ray {
+                        orig: pos,
+                        dir: dir,
+                        time: time,
+                        wavelength: wavelength,
+                        max_t: f32::INFINITY,
+                    },
+                    false,
+                );
+                if self.settings.proxy_rays.camera {
+                    rays.mark_proxy(rays.len() - 1);
+                }
+                dirs.push(dir);
+            }
 
-                    // Book keeping for next event
-                    if found_light {
-                        self.event = LightPathEvent::ShadowRay;
-                        return true;
-                    } else if do_bounce {
-                        rays.set_from_ray(&self.next_bounce_ray.unwrap(), false, ray_idx);
-                        self.event = LightPathEvent::BounceRay;
-                        self.light_attenuation *= self.next_attenuation_fac;
-                        return true;
-                    } else {
-                        return false;
+            let mut pi = paths.len();
+            while pi > 0 {
+                let isects = tracer.trace(&mut rays);
+                let mut new_end = 0;
+                for i in 0..pi {
+                    if paths[i].next(
+                        &mut xform_stack,
+                        &self.scene,
+                        &self.settings,
+                        None,
+                        &isects[i],
+                        &mut rays,
+                        i,
+                    ) {
+                        paths.swap(new_end, i);
+                        rays.swap(new_end, i);
+                        new_end += 1;
                     }
-                } else {
-                    // Didn't hit anything, so background color
-                    self.color += scene
-                        .world
-                        .background_color
-                        .to_spectral_sample(self.wavelength)
-                        .e
-                        * self.light_attenuation
-                        / self.closure_sample_pdf;
-                    return false;
                 }
+                rays.truncate(new_end);
+                pi = new_end;
             }
 
-            //--------------------------------------------------------------------
-            // Result of shadow ray from sampling a light
-            LightPathEvent::ShadowRay => {
-                // If the light was not in shadow, add it's light to the film
-                // plane.
-                if let surface::SurfaceIntersection::Miss = *isect {
-                    self.color += self.pending_color_addition;
+            // Project each sample's radiance onto the basis functions.
+            // Directions are sampled uniformly, so each sample is weighted
+            // by the sphere's area over the sample count.
+            paths.sort_unstable_by_key(|path| path.sample_number);
+            let weight = 4.0 * PI_32 / spp as f32;
+            for (path, &dir) in paths.iter().zip(dirs.iter()) {
+                let radiance = XYZ::from_spectral_sample(&SpectralSample::from_parts(
+                    path.color,
+                    path.wavelength,
+                )) * weight;
+                for (coefficient, basis) in sh.iter_mut().zip(sh_basis(dir).iter()) {
+                    *coefficient += radiance * *basis;
+                }
+            }
+        }
+
+        let mut probe = Probe {
+            pos: pos,
+            sh: [(0.0, 0.0, 0.0); SH_COEFFICIENTS],
+        };
+        for (rgb, xyz) in probe.sh.iter_mut().zip(sh.iter()) {
+            *rgb = xyz_to_rec709_e(xyz.to_tuple());
+        }
+        probe
+    }
+
+    /// Creates the light path and initial camera ray for sample `si` of
+    /// pixel (`x`, `y`).
+    fn camera_path(&self, x: u32, y: u32, si: u32) -> (LightPath, Ray) {
+        // Calculate image plane x and y coordinates
+        let (img_x, img_y) = {
+            let filter_x = self.settings.filter.sample(get_sample(
+                dims::FILTER.dim(0),
+                si,
+                (x, y),
+                self.settings.seed,
+            )) + 0.5;
+            let filter_y = self.settings.filter.sample(get_sample(
+                dims::FILTER.dim(1),
+                si,
+                (x, y),
+                self.settings.seed,
+            )) + 0.5;
+            self.image_plane_co(filter_x + x as f32, filter_y + y as f32)
+        };
+
+        LightPath::new(
+            &self.scene,
+            self.settings.seed,
+            (x, y),
+            (img_x, img_y),
+            (
+                get_sample(dims::LENS.dim(0), si, (x, y), self.settings.seed),
+                get_sample(dims::LENS.dim(1), si, (x, y), self.settings.seed),
+            ),
+            get_sample(dims::TIME.dim(0), si, (x, y), self.settings.seed),
+            map_0_1_to_wavelength(get_sample(
+                dims::WAVELENGTH.dim(0),
+                si,
+                (x, y),
+                self.settings.seed,
+            )),
+            si,
+        )
+    }
+
+    /// Calculates the dimensions and coordinates of the part of the image
+    /// we're rendering, accounting for cropping.  The image includes any
+    /// overscan.
+    ///
+    /// Returns (width, height, start_x, start_y).
+    pub(crate) fn render_region(
+        &self,
+        crop: Option<(u32, u32, u32, u32)>,
+    ) -> (usize, usize, usize, usize) {
+        let (img_width, img_height) = self.settings.image_size();
+        // Empty images are rejected when the settings are parsed, and
+        // crops are clamped to the image, so there's always at least one
+        // pixel.
+        debug_assert!(img_width > 0 && img_height > 0);
+        if let Some((x1, y1, x2, y2)) = crop {
+            let x1 = min(x1 as usize, img_width - 1);
+            let y1 = min(y1 as usize, img_height - 1);
+            let x2 = min(x2 as usize, img_width - 1);
+            let y2 = min(y2 as usize, img_height - 1);
+            (x2 - x1 + 1, y2 - y1 + 1, x1, y1)
+        } else {
+            (img_width, img_height, 0, 0)
+        }
+    }
+
+    /// A blank image to render into, with the render's size, overscan, and
+    /// pixel shape.
+    pub(crate) fn new_image(&self) -> Image {
+        let (width, height) = self.settings.image_size();
+        Image::new(width, height)
+            .with_overscan(self.settings.overscan)
+            .with_pixel_aspect(self.settings.pixel_aspect)
+    }
+
+    /// Maps a position in pixel coordinates to the camera's image plane.
+    /// Overscan pixels map to beyond the edges of the image plane.
+    pub(crate) fn image_plane_co(&self, x: f32, y: f32) -> (f32, f32) {
+        let x = x - self.settings.overscan as f32;
+        let y = y - self.settings.overscan as f32;
+        let cmpx = 1.0 / self.settings.resolution.0 as f32;
+        let cmpy = 1.0 / self.settings.resolution.1 as f32;
+
+        // The image plane is 2 wide, and as tall as the pixels' shape
+        // makes the image.
+        let aspect = self.settings.resolution.1 as f32
+            / (self.settings.resolution.0 as f32 * self.settings.pixel_aspect);
+        let min_x = -1.0;
+        let max_x = 1.0;
+        let min_y = -aspect;
+        let max_y = aspect;
+        let x_extent = max_x - min_x;
+        let y_extent = max_y - min_y;
+
+        let samp_x = x * cmpx;
+        let samp_y = y * cmpy;
+        ((samp_x - 0.5) * x_extent, (0.5 - samp_y) * y_extent)
+    }
+
+    /// The angle between the camera rays of neighboring pixels, roughly,
+    /// for the spread of camera rays.  This is exact at the center of the
+    /// image for the narrowest field of view over the shutter.
+    pub(crate) fn camera_ray_spread(&self) -> f32 {
+        2.0 * self.scene.camera.min_half_width() / self.settings.resolution.0.max(1) as f32
+    }
+
+    /// Fills an irradiance cache for the diffuse surfaces seen directly by
+    /// the camera within the given pixel region.
+    ///
+    /// Candidate record locations are on a grid of pixels.  They're
+    /// visited from a coarse grid to a fine one, and a record is only made
+    /// at a candidate if the records so far don't already cover it.  The
+    /// records of each pass are computed in parallel, but added in a fixed
+    /// order, so the cache doesn't depend on the thread count.
+    fn build_irradiance_cache(
+        &self,
+        region: (usize, usize, usize, usize),
+        thread_count: u32,
+    ) -> IrradianceCache {
+        let (start_x, start_y, width, height) = region;
+        let finest = IC_GRID_SPACINGS[IC_GRID_SPACINGS.len() - 1];
+        let coarsest = IC_GRID_SPACINGS[0];
+
+        // Find the candidates, by tracing a camera ray through the center
+        // of each pixel on the finest grid.
+        let mut candidates = Vec::new();
+        {
+            let mut tracer = Tracer::from_assembly(&self.scene.root);
+            let mut rays = RayBatch::new();
+            let mut grid_cos = Vec::new();
+            let mut footprints = Vec::new();
+            for (gy, y) in (start_y..(start_y + height)).step_by(finest).enumerate() {
+                for (gx, x) in (start_x..(start_x + width)).step_by(finest).enumerate() {
+                    let ray = self.ic_camera_ray(x as f32 + 0.5, y as f32 + 0.5);
+                    let next_ray = self.ic_camera_ray(x as f32 + 1.5, y as f32 + 0.5);
+                    let pixel_angle = dot(ray.dir.normalized(), next_ray.dir.normalized())
+                        .min(1.0)
+                        .acos();
+                    rays.push(ray, false);
+                    rays.mark_camera(rays.len() - 1);
+                    rays.set_spread(rays.len() - 1, pixel_angle);
+                    if self.settings.proxy_rays.camera {
+                        rays.mark_proxy(rays.len() - 1);
+                    }
+                    grid_cos.push(((gx, gy), (x as u32, y as u32)));
+                    footprints.push(pixel_angle);
+                }
+            }
+
+            let isects = tracer.trace(&mut rays);
+            for (i, isect) in isects.iter().enumerate() {
+                if let surface::SurfaceIntersection::Hit {
+                    intersection_data: idata,
+                    closure: SurfaceClosure::Lambert(_),
+                } = *isect
+                {
+                    let (grid_co, pixel_co) = grid_cos[i];
+                    candidates.push(IcCandidate {
+                        grid_co: grid_co,
+                        pixel_co: pixel_co,
+                        idata: idata,
+                        nor: facing_nor(&idata),
+                        pixel_size: footprints[i] * idata.t * rays.dir(i).length(),
+                    });
+                }
+            }
+        }
+
+        let mut bounds = BBox::new();
+        for cand in &candidates {
+            bounds.min = bounds.min.min(cand.idata.pos);
+            bounds.max = bounds.max.max(cand.idata.pos);
+        }
+        if candidates.is_empty() {
+            bounds = BBox::from_points(bounds.max, bounds.max);
+        }
+        // The padding is relative to the scene's size, so that it neither
+        // swamps jewelry-scale scenes nor vanishes on city-scale ones.
+        let scene_radius = self.scene.bounding_sphere().1;
+        let pad = ((bounds.max - bounds.min).length() * 0.01).max(scene_radius * 0.0001);
+        let pad = Vector::new(pad, pad, pad);
+        let bounds = BBox::from_points(bounds.min - pad, bounds.max + pad);
+        let mut cache = IrradianceCache::new(bounds, self.settings.ic_accuracy);
+
+        // Make the records, coarse to fine.
+        let mut tpool = Pool::new(thread_count);
+        for &spacing in &IC_GRID_SPACINGS {
+            let step = spacing / finest;
+            let todo: Vec<&IcCandidate> = candidates
+                .iter()
+                .filter(|cand| {
+                    cand.grid_co.0 % step == 0
+                        && cand.grid_co.1 % step == 0
+                        && cache.irradiance(cand.idata.pos, cand.nor).is_none()
+                })
+                .collect();
+
+            let new_records = Mutex::new(Vec::new());
+            tpool.scoped(|scope| {
+                for (batch_i, batch) in todo.chunks(IC_RECORDS_PER_JOB).enumerate() {
+                    let new_records = &new_records;
+                    scope.execute(move || {
+                        let records = self.ic_gather_records(batch, (finest, coarsest));
+                        new_records.lock().unwrap().push((batch_i, records));
+                    });
+                }
+            });
+
+            let mut new_records = new_records.into_inner().unwrap();
+            new_records.sort_unstable_by_key(|(batch_i, _)| *batch_i);
+            for (_, records) in new_records {
+                for record in records {
+                    cache.insert(record);
+                }
+            }
+        }
+
+        cache
+    }
+
+    /// A camera ray through the given pixel coordinates, with no lens or
+    /// motion blur, for placing irradiance cache records.
+    fn ic_camera_ray(&self, x: f32, y: f32) -> Ray {
+        let (img_x, img_y) = self.image_plane_co(x, y);
+        self.scene
+            .camera
+            .generate_ray(img_x, img_y, 0.5, 550.0, 0.5, 0.5)
+    }
+
+    /// Computes the irradiance cache records for a batch of candidates, by
+    /// path tracing the radiance arriving from a stratified hemisphere
+    /// above each.
+    ///
+    /// `grid_spacing` is the finest and coarsest spacing of the candidate
+    /// grid in pixels, which the records' radii are kept within.
+    fn ic_gather_records(
+        &self,
+        candidates: &[&IcCandidate],
+        grid_spacing: (usize, usize),
+    ) -> Vec<IrradianceRecord> {
+        let seed = hash_u32(self.settings.seed, IC_SEED);
+        let mut tracer = Tracer::from_assembly(&self.scene.root);
+        let mut xform_stack = TransformStack::new();
+        let mut paths = Vec::new();
+        let mut rays = RayBatch::new();
+        let mut gather_samples = Vec::new();
+        let mut hemispheres = Vec::new();
+
+        for cand in candidates {
+            let hemi = Hemisphere::new(cand.nor, self.settings.ic_samples as usize);
+            for si in 0..hemi.sample_count() {
+                let s = si as u32;
+                let samp = |dims: Dims, i| get_sample(dims.dim(i), s, cand.pixel_co, seed);
+                let wavelength = map_0_1_to_wavelength(samp(dims::WAVELENGTH, 0));
+                let time = samp(dims::TIME, 0);
+                let dir = hemi.dir(si, (samp(dims::GATHER_DIR, 0), samp(dims::GATHER_DIR, 1)));
+                let idata = &cand.idata;
+                let orig =
+                    robust_ray_origin(idata.pos, idata.pos_err, idata.nor_g.normalized(), dir);
+                paths.push(LightPath::new_gather(
+                    seed,
+                    cand.pixel_co,
+                    s,
+                    time,
+                    wavelength,
+                ));
+                rays.push(
+                    Ray {
+                        orig: orig,
+                        dir: dir,
+                        time: time,
+                        wavelength: wavelength,
+                        max_t: f32::INFINITY,
+                    },
+                    false,
+                );
+                if self.settings.proxy_rays.diffuse {
+                    rays.mark_proxy(rays.len() - 1);
+                }
+                gather_samples.push(GatherSample {
+                    dir: dir,
+                    radiance: XYZ::new(0.0, 0.0, 0.0),
+                    distance: f32::INFINITY,
+                });
+            }
+            hemispheres.push(hemi);
+        }
+
+        // Trace the paths, noting the distance to each path's first hit.
+        let mut first_trace = true;
+        let mut pi = paths.len();
+        while pi > 0 {
+            let isects = tracer.trace(&mut rays);
+            if first_trace {
+                for (sample, isect) in gather_samples.iter_mut().zip(isects.iter()) {
+                    if let surface::SurfaceIntersection::Hit {
+                        intersection_data: ref idata,
+                        ..
+                    } = *isect
+                    {
+                        sample.distance = idata.t;
+                    }
+                }
+                first_trace = false;
+            }
+
+            let mut new_end = 0;
+            for i in 0..pi {
+                if paths[i].next(
+                    &mut xform_stack,
+                    &self.scene,
+                    &self.settings,
+                    None,
+                    &isects[i],
+                    &mut rays,
+                    i,
+                ) {
+                    paths.swap(new_end, i);
+                    rays.swap(new_end, i);
+                    new_end += 1;
+                }
+            }
+            rays.truncate(new_end);
+            pi = new_end;
+        }
+
+        // The paths were created in canonical order, so sorting them back
+        // into it lines them up with their samples again.
+        paths.sort_unstable_by_key(|path| (path.pixel_co.1, path.pixel_co.0, path.sample_number));
+        for (sample, path) in gather_samples.iter_mut().zip(paths.iter()) {
+            let radiance = SpectralSample::from_parts(path.color, path.wavelength);
+            sample.radiance = XYZ::from_spectral_sample(&radiance);
+        }
+
+        let mut records = Vec::new();
+        let mut samples = &gather_samples[..];
+        for (cand, hemi) in candidates.iter().zip(hemispheres.iter()) {
+            let (cand_samples, rest) = samples.split_at(hemi.sample_count());
+            samples = rest;
+
+            // Keep the area a record is used over on screen between the
+            // finest and twice the coarsest grid spacing.
+            let accuracy = self.settings.ic_accuracy;
+            let radius_range = (
+                grid_spacing.0 as f32 * cand.pixel_size / accuracy,
+                grid_spacing.1 as f32 * 2.0 * cand.pixel_size / accuracy,
+            );
+            records.push(hemi.record(cand.idata.pos, cand_samples, radius_range));
+        }
+
+        records
+    }
+}
+
+/// Grid spacings, in pixels, of the irradiance cache's record placement
+/// passes.  Each must be a multiple of the next.
+const IC_GRID_SPACINGS: [usize; 4] = [32, 16, 8, 4];
+
+/// How many samples of a light probe are traced at once.
+const PROBE_SAMPLES_PER_BATCH: usize = 256;
+
+/// Mixed into the seed for light probes' samples.
+const PROBE_SEED: u32 = 0x9b0b_e5a1;
+
+/// How many irradiance cache records each job computes at once.
+const IC_RECORDS_PER_JOB: usize = 16;
+
+/// Mixed into the seed for the irradiance cache's samples, so they aren't
+/// correlated with the samples of the pixels the records are made at.
+const IC_SEED: u32 = 0x1ca5_4e11;
+
+/// A place the irradiance cache might put a record.
+#[derive(Debug)]
+struct IcCandidate {
+    grid_co: (usize, usize),
+    pixel_co: (u32, u32),
+    idata: surface::SurfaceIntersectionData,
+    nor: Vector,     // Shading normal, facing the camera
+    pixel_size: f32, // Approximate size of a pixel at the candidate
+}
+
+/// The shading normal of a hit, flipped to the side it was hit from.
+fn facing_nor(idata: &surface::SurfaceIntersectionData) -> Vector {
+    if dot(idata.nor_g.into_vector(), idata.incoming) <= 0.0 {
+        idata.nor.normalized().into_vector()
+    } else {
+        -idata.nor.normalized().into_vector()
+    }
+}
+
+/// When rendering with a time limit, the most samples a pass takes is
+/// this fraction of the spp.
+const TIME_LIMITED_PASSES: u32 = 16;
+
+/// The highest chance of a path surviving Russian roulette.
+const MAX_ROULETTE_SURVIVAL: f32 = 0.95;
+
+/// The number of bins of the path length statistics.
+pub const PATH_LENGTH_BINS: usize = 16;
+
+/// The bin of the path length statistics for a number of bounces.
+fn path_length_bin(bounce_count: u32) -> usize {
+    (bounce_count as usize).min(PATH_LENGTH_BINS - 1)
+}
+
+/// How many NaN/Inf samples are reported individually per render when
+/// checking for them.  Any more are only counted.
+const MAX_NUMERICS_REPORTS: usize = 32;
+
+/// The narrowest that the last buckets of a pass are split down to, in
+/// pixels, when `split_buckets` is on.
+const MIN_SPLIT_BUCKET_DIM: u32 = 4;
+
+/// How many times wider and taller than usual buckets start out with
+/// `auto_buckets`, before the slow ones are split.
+const AUTO_BUCKET_SCALE: usize = 2;
+
+/// With `auto_buckets`, buckets are split until each is expected to take
+/// at most this fraction of the time that each thread will spend on the
+/// pass.
+const AUTO_BUCKETS_PER_THREAD: f64 = 8.0;
+
+#[derive(Debug, Copy, Clone)]
+enum LightPathEvent {
+    CameraRay,
+    BounceRay,
+    ShadowRay,
+    AtmosphereShadowRay,
+}
+
+/// A path's first hit, kept around when splitting so that the path can
+/// return to it for each of its light and bounce samples.
+#[derive(Debug, Copy, Clone)]
+struct SplitHit {
+    idata: surface::SurfaceIntersectionData,
+    closure: SurfaceClosure,
+    light_attenuation: Vec4,
+    light_samples: u32,
+    bounce_samples: u32,
+    light_samples_left: u32,
+    bounce_samples_left: u32,
+}
+
+#[derive(Debug)]
+pub struct LightPath {
+    event: LightPathEvent,
+    bounce_count: u32,
+
+    sampling_seed: u32,
+    pixel_co: (u32, u32),
+    sample_number: u32, // Which sample in the LDS sequence this is.
+    vertex_sample: u32, // Which sample the vertex dimensions come from, which differs when splitting
+    time: f32,
+    wavelength: f32,
+
+    next_bounce_ray: Option<Ray>,
+    next_bounce_specular: bool, // Whether the next bounce ray is off a smooth surface
+    next_attenuation_fac: Vec4,
+
+    closure_sample_pdf: f32,
+    mis_light_samples: f32, // Light samples taken where the current bounce ray started
+    skip_light_hits: bool,  // Whether the current ray ignores lights that light sampling finds
+    light_attenuation: Vec4,
+    pending_color_addition: Vec4,
+    color: Vec4,
+
+    split_hit: Option<SplitHit>,
+    resampled_light: Option<(LightSampleValues, f32)>, // First hit light sample, with restir
+    segment_end: Option<(LightPathEvent, surface::SurfaceIntersection, Vector)>, // Result of the ray scattered from, with an atmosphere
+
+    trace: Option<Vec<String>>, // A description of each event, when tracing
+}
+
+#[allow(clippy::new_ret_no_self)]
+impl LightPath {
+    fn new(
+        scene: &Scene,
+        sampling_seed: u32,
+        pixel_co: (u32, u32),
+        image_plane_co: (f32, f32),
+        lens_uv: (f32, f32),
+        time: f32,
+        wavelength: f32,
+        sample_number: u32,
+    ) -> (LightPath, Ray) {
+        (
+            LightPath {
+                event: LightPathEvent::CameraRay,
+                bounce_count: 0,
+
+                sampling_seed: sampling_seed,
+                pixel_co: pixel_co,
+                sample_number: sample_number,
+                vertex_sample: sample_number,
+                time: time,
+                wavelength: wavelength,
+
+                next_bounce_ray: None,
+                next_bounce_specular: false,
+                next_attenuation_fac: Vec4::splat(1.0),
+
+                closure_sample_pdf: 1.0,
+                mis_light_samples: 1.0,
+                skip_light_hits: false,
+                light_attenuation: Vec4::splat(1.0),
+                pending_color_addition: Vec4::splat(0.0),
+                color: Vec4::splat(0.0),
+
+                split_hit: None,
+                resampled_light: None,
+                segment_end: None,
+
+                trace: None,
+            },
+            scene.camera.generate_ray(
+                image_plane_co.0,
+                image_plane_co.1,
+                time,
+                wavelength,
+                lens_uv.0,
+                lens_uv.1,
+            ),
+        )
+    }
+
+    /// Creates a path that gathers the light arriving along a ray for the
+    /// irradiance cache.  The path's color ends up as the radiance from
+    /// the ray's direction, minus the direct light from anything light
+    /// sampling would find, which is left to the camera paths.
+    ///
+    /// The cache point counts as the path's vertex 0, so the ray's hit is
+    /// vertex 1.
+    fn new_gather(
+        sampling_seed: u32,
+        pixel_co: (u32, u32),
+        sample_number: u32,
+        time: f32,
+        wavelength: f32,
+    ) -> LightPath {
+        LightPath {
+            event: LightPathEvent::BounceRay,
+            bounce_count: 1,
+
+            sampling_seed: sampling_seed,
+            pixel_co: pixel_co,
+            sample_number: sample_number,
+            vertex_sample: sample_number,
+            time: time,
+            wavelength: wavelength,
+
+            next_bounce_ray: None,
+            next_bounce_specular: false,
+            next_attenuation_fac: Vec4::splat(1.0),
+
+            closure_sample_pdf: 1.0,
+            mis_light_samples: 1.0,
+            skip_light_hits: true,
+            light_attenuation: Vec4::splat(1.0),
+            pending_color_addition: Vec4::splat(0.0),
+            color: Vec4::splat(0.0),
+
+            split_hit: None,
+            resampled_light: None,
+            segment_end: None,
+
+            trace: None,
+        }
+    }
+
+    /// Creates a path that gathers all of the light arriving along a ray
+    /// from a light probe.  Its first hit is treated like a camera ray's.
+    fn new_probe(
+        sampling_seed: u32,
+        pixel_co: (u32, u32),
+        sample_number: u32,
+        time: f32,
+        wavelength: f32,
+    ) -> LightPath {
+        LightPath {
+            event: LightPathEvent::CameraRay,
+            bounce_count: 0,
+            skip_light_hits: false,
+            ..LightPath::new_gather(sampling_seed, pixel_co, sample_number, time, wavelength)
+        }
+    }
+
+    /// Describes the state the path ended in, for tracking down where bad
+    /// values came from.
+    fn describe(&self) -> String {
+        format!(
+            "wavelength {:.1}nm, time {:.3}, {} bounce(s), last event {:?}, \
+             closure pdf {}, attenuation {:?}, color {:?}",
+            self.wavelength,
+            self.time,
+            self.bounce_count,
+            self.event,
+            self.closure_sample_pdf,
+            self.light_attenuation,
+            self.color,
+        )
+    }
+
+    /// Adds a line to the path's trace, if it's being traced.
+    fn trace<F: FnOnce(&LightPath) -> String>(&mut self, line: F) {
+        if self.trace.is_some() {
+            let line = line(self);
+            if let Some(ref mut trace) = self.trace {
+                trace.push(line);
+            }
+        }
+    }
+
+    /// Gets dimension `i` of `dims` at path vertex `vertex`.
+    fn vertex_samp(&self, vertex: u32, dims: Dims, i: u32) -> f32 {
+        get_sample(
+            dims::vertex_dim(vertex, dims, i),
+            self.vertex_sample,
+            self.pixel_co,
+            self.sampling_seed,
+        )
+    }
+
+    /// Gets the light selection and light sampling sample values at path
+    /// vertex `vertex`.
+    fn light_sample_values(&self, vertex: u32) -> LightSampleValues {
+        (
+            self.vertex_samp(vertex, dims::LIGHT_SELECT, 0),
+            (
+                self.vertex_samp(vertex, dims::LIGHT_POINT, 0),
+                self.vertex_samp(vertex, dims::LIGHT_POINT, 1),
+                self.vertex_samp(vertex, dims::LIGHT_POINT, 2),
+            ),
+        )
+    }
+
+    /// Processes the result of the path's last ray, and sets up its next
+    /// ray if it has one.  Returns whether the path is still alive.
+    fn next(
+        &mut self,
+        xform_stack: &mut TransformStack,
+        scene: &Scene,
+        settings: &RenderSettings,
+        irradiance_cache: Option<&IrradianceCache>,
+        isect: &surface::SurfaceIntersection,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+    ) -> bool {
+        if self.step(
+            xform_stack,
+            scene,
+            settings,
+            irradiance_cache,
+            isect,
+            rays,
+            ray_idx,
+        ) {
+            true
+        } else {
+            // When splitting, a path isn't done until all of the samples
+            // at its first hit are.
+            self.next_split(xform_stack, scene, settings, rays, ray_idx)
+        }
+    }
+
+    fn step(
+        &mut self,
+        xform_stack: &mut TransformStack,
+        scene: &Scene,
+        settings: &RenderSettings,
+        irradiance_cache: Option<&IrradianceCache>,
+        isect: &surface::SurfaceIntersection,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+    ) -> bool {
+        match self.event {
+            //--------------------------------------------------------------------
+            // Result of Camera or bounce ray, prepare next bounce and light rays
+            LightPathEvent::CameraRay | LightPathEvent::BounceRay => {
+                let dir = rays.dir(ray_idx);
+
+                // Light scattered toward the path by the atmosphere along
+                // the ray is gathered first, with a shadow ray of its own.
+                if let Some(ref atmosphere) = scene.world.atmosphere {
+                    let orig = rays.orig(ray_idx);
+                    if self.sample_atmosphere(
+                        xform_stack,
+                        scene,
+                        settings,
+                        atmosphere,
+                        (orig, dir),
+                        isect,
+                        rays,
+                        ray_idx,
+                    ) {
+                        self.segment_end = Some((self.event, *isect, dir));
+                        self.event = LightPathEvent::AtmosphereShadowRay;
+                        return true;
+                    }
+                }
+
+                self.end_segment(
+                    xform_stack,
+                    scene,
+                    settings,
+                    irradiance_cache,
+                    isect,
+                    dir,
+                    rays,
+                    ray_idx,
+                )
+            }
+
+            //--------------------------------------------------------------------
+            // Result of shadow ray from a point in the atmosphere, then carry
+            // on with the result of the ray it was scattered from
+            LightPathEvent::AtmosphereShadowRay => {
+                if let surface::SurfaceIntersection::Miss = *isect {
+                    self.color += self.pending_color_addition;
+                    self.trace(|p| {
+                        format!(
+                            "  atmosphere shadow ray unoccluded, adding {:?}",
+                            p.pending_color_addition
+                        )
+                    });
+                } else {
+                    self.trace(|_| "  atmosphere shadow ray occluded".to_string());
+                }
+
+                let (event, isect, dir) = self.segment_end.take().unwrap();
+                self.event = event;
+                self.end_segment(
+                    xform_stack,
+                    scene,
+                    settings,
+                    irradiance_cache,
+                    &isect,
+                    dir,
+                    rays,
+                    ray_idx,
+                )
+            }
+
+            //--------------------------------------------------------------------
+            // Result of shadow ray from sampling a light
+            LightPathEvent::ShadowRay => {
+                // If the light was not in shadow, add it's light to the film
+                // plane.
+                if let surface::SurfaceIntersection::Miss = *isect {
+                    self.color += self.pending_color_addition;
+                    self.trace(|p| {
+                        format!(
+                            "  shadow ray unoccluded, adding {:?}",
+                            p.pending_color_addition
+                        )
+                    });
+                } else {
+                    self.trace(|_| "  shadow ray occluded".to_string());
                 }
 
                 // Set up for the next bounce, if any
-                if let Some(ref nbr) = self.next_bounce_ray {
-                    rays.set_from_ray(nbr, false, ray_idx);
+                if self.next_bounce_ray.is_some() {
+                    self.start_bounce_ray(settings, rays, ray_idx);
                     self.light_attenuation *= self.next_attenuation_fac;
                     self.event = LightPathEvent::BounceRay;
                     return true;
@@ -685,13 +1712,898 @@ impl LightPath {
             }
         }
     }
+
+    /// Handles the result of a camera or bounce ray, whose direction was
+    /// `dir`: adds any light it found, and sets up the path's next light
+    /// and bounce rays.  Returns whether the path is still alive.
+    fn end_segment(
+        &mut self,
+        xform_stack: &mut TransformStack,
+        scene: &Scene,
+        settings: &RenderSettings,
+        irradiance_cache: Option<&IrradianceCache>,
+        isect: &surface::SurfaceIntersection,
+        dir: Vector,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+    ) -> bool {
+        let skip_light_hits = self.skip_light_hits;
+        self.skip_light_hits = false;
+
+        if let surface::SurfaceIntersection::Hit {
+            intersection_data: ref idata,
+            ref closure,
+        } = *isect
+        {
+            // Hit something!  Do the stuff
+
+            // If it's an emission closure, handle specially:
+            // - Collect light from the emission.
+            // - Terminate the path.
+            if let SurfaceClosure::Emit(color) = *closure {
+                let color = color.to_spectral_sample(self.wavelength).e;
+                let added = if skip_light_hits && idata.sample_pdf > 0.0 {
+                    // Light sampling finds this one.
+                    Vec4::splat(0.0)
+                } else if let LightPathEvent::CameraRay = self.event {
+                    color * self.light_attenuation
+                } else {
+                    let mis_pdf = settings.mis.mis_pdf(
+                        self.closure_sample_pdf,
+                        idata.sample_pdf * self.mis_light_samples,
+                    );
+                    color * self.light_attenuation / mis_pdf
+                };
+                self.color += added;
+                self.trace(|p| {
+                    format!(
+                        "vertex {}: hit emitter at {}, t {}, emission {:?}, adding {:?}",
+                        p.bounce_count,
+                        fmt_xyz(idata.pos.x(), idata.pos.y(), idata.pos.z()),
+                        idata.t,
+                        color,
+                        added,
+                    )
+                });
+
+                return false;
+            }
+
+            // Roll the previous closure pdf into the attenauation
+            self.light_attenuation /= self.closure_sample_pdf;
+            self.trace(|p| {
+                let nor = idata.nor.normalized();
+                format!(
+                    "vertex {}: hit at {}, t {}, normal {}, closure {:?}, throughput {:?}",
+                    p.bounce_count,
+                    fmt_xyz(idata.pos.x(), idata.pos.y(), idata.pos.z()),
+                    idata.t,
+                    fmt_xyz(nor.x(), nor.y(), nor.z()),
+                    closure,
+                    p.light_attenuation,
+                )
+            });
+
+            // At the first hit, split into multiple light and bounce
+            // samples if requested.  Those are then traced one after
+            // another by `next_split()`.
+            if let LightPathEvent::CameraRay = self.event {
+                let light_samples = split_light_samples(closure, settings.light_splits);
+                let mut bounce_samples = settings.bounce_splits;
+
+                // Diffuse surfaces take their indirect light from
+                // the irradiance cache when it has it, leaving
+                // only light sampling to do.
+                if let (Some(cache), SurfaceClosure::Lambert(color)) = (irradiance_cache, *closure)
+                {
+                    if let Some(irradiance) = cache.irradiance(idata.pos, facing_nor(idata)) {
+                        let irradiance = Color::new_xyz(irradiance.to_tuple())
+                            .to_spectral_sample(self.wavelength)
+                            .e;
+                        let albedo = color.to_spectral_sample(self.wavelength).e;
+                        self.color +=
+                            albedo * irradiance * self.light_attenuation / std::f32::consts::PI;
+                        bounce_samples = 0;
+                    }
+                }
+
+                if light_samples != 1 || bounce_samples != 1 {
+                    self.trace(|_| {
+                        format!(
+                            "  splitting into {} light sample(s) and {} bounce sample(s)",
+                            light_samples, bounce_samples
+                        )
+                    });
+                    self.split_hit = Some(SplitHit {
+                        idata: *idata,
+                        closure: *closure,
+                        light_attenuation: self.light_attenuation,
+                        light_samples: light_samples,
+                        bounce_samples: bounce_samples,
+                        light_samples_left: light_samples,
+                        bounce_samples_left: bounce_samples,
+                    });
+                    return false;
+                }
+            }
+
+            // Prepare light ray
+            let vertex = self.bounce_count;
+            let resampled = self.resampled_light.take();
+            let found_light = self.sample_light(
+                xform_stack,
+                scene,
+                settings,
+                vertex,
+                idata,
+                closure,
+                (1.0, 1.0),
+                resampled,
+                rays,
+                ray_idx,
+            );
+
+            // A resampled light sample accounts for all of the
+            // light that light sampling can find, so the bounce
+            // ray leaves it out rather than weighting it by MIS.
+            if resampled.is_some() {
+                self.skip_light_hits = true;
+            }
+
+            // Prepare bounce ray
+            let do_bounce = if self.bounce_count < settings.max_bounces {
+                self.bounce_count += 1;
+                self.sample_bounce(vertex, idata, closure, (1.0, 1.0))
+                    && self.survive_roulette(vertex, settings)
+            } else {
+                self.trace(|_| "  no bounce: max bounces reached".to_string());
+                self.next_bounce_ray = None;
+                false
+            };
+
+            // Book keeping for next event
+            if found_light {
+                self.event = LightPathEvent::ShadowRay;
+                return true;
+            } else if do_bounce {
+                self.start_bounce_ray(settings, rays, ray_idx);
+                self.event = LightPathEvent::BounceRay;
+                self.light_attenuation *= self.next_attenuation_fac;
+                return true;
+            } else {
+                return false;
+            }
+        } else {
+            let color_before = self.color;
+
+            // Didn't hit anything, so background color.  Camera rays see
+            // the backplate instead, if there is one.
+            let background = if let LightPathEvent::CameraRay = self.event {
+                scene
+                    .world
+                    .camera_background(settings.pixel_uv(self.pixel_co))
+            } else {
+                scene.world.background_color
+            };
+            self.color += background.to_spectral_sample(self.wavelength).e * self.light_attenuation
+                / self.closure_sample_pdf;
+
+            // Rays that escape can also find world lights.  Camera
+            // rays see them directly, while bounce rays compete with
+            // light sampling.
+            if let LightPathEvent::CameraRay = self.event {
+                let light_attenuation = self.light_attenuation;
+                let color = &mut self.color;
+                scene.world_lights_from_direction(
+                    dir,
+                    self.wavelength,
+                    self.time,
+                    true,
+                    |light_color, _| {
+                        *color += light_color.e * light_attenuation;
+                    },
+                );
+            } else if !skip_light_hits {
+                let closure_pdf = self.closure_sample_pdf;
+                let light_samples = self.mis_light_samples;
+                let light_attenuation = self.light_attenuation;
+                let color = &mut self.color;
+                scene.world_lights_from_direction(
+                    dir,
+                    self.wavelength,
+                    self.time,
+                    false,
+                    |light_color, light_pdf| {
+                        let mis_pdf = settings.mis.mis_pdf(closure_pdf, light_pdf * light_samples);
+                        *color += light_color.e * light_attenuation / mis_pdf;
+                    },
+                );
+            }
+            self.trace(|p| {
+                format!(
+                    "vertex {}: escaped toward {}, adding {:?}",
+                    p.bounce_count,
+                    fmt_xyz(dir.x(), dir.y(), dir.z()),
+                    p.color - color_before,
+                )
+            });
+            return false;
+        }
+    }
+
+    /// Sets up the path's next ray from its first hit when splitting, one
+    /// light sample or bounce sample at a time.  Returns false when there's
+    /// nothing left to trace.
+    fn next_split(
+        &mut self,
+        xform_stack: &mut TransformStack,
+        scene: &Scene,
+        settings: &RenderSettings,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+    ) -> bool {
+        let mut split = if let Some(split) = self.split_hit {
+            split
+        } else {
+            return false;
+        };
+        let sample_counts = (split.light_samples as f32, split.bounce_samples as f32);
+
+        // Each split sample takes its dimensions from its own sample of the
+        // sequence, so that the splits are stratified with respect to each
+        // other, and the rest of each bounce sample's path is independent.
+        while split.light_samples_left > 0 {
+            split.light_samples_left -= 1;
+            self.split_hit = Some(split);
+            self.light_attenuation = split.light_attenuation;
+            self.vertex_sample = (self.sample_number * split.light_samples)
+                + (split.light_samples - split.light_samples_left - 1);
+            if self.sample_light(
+                xform_stack,
+                scene,
+                settings,
+                0,
+                &split.idata,
+                &split.closure,
+                sample_counts,
+                None,
+                rays,
+                ray_idx,
+            ) {
+                self.next_bounce_ray = None;
+                self.event = LightPathEvent::ShadowRay;
+                return true;
+            }
+        }
+
+        while split.bounce_samples_left > 0 && settings.max_bounces > 0 {
+            split.bounce_samples_left -= 1;
+            self.split_hit = Some(split);
+            self.light_attenuation = split.light_attenuation;
+            self.bounce_count = 1;
+            self.vertex_sample = (self.sample_number * split.bounce_samples)
+                + (split.bounce_samples - split.bounce_samples_left - 1);
+            if self.sample_bounce(0, &split.idata, &split.closure, sample_counts) {
+                self.start_bounce_ray(settings, rays, ray_idx);
+                self.event = LightPathEvent::BounceRay;
+                self.light_attenuation *= self.next_attenuation_fac;
+                return true;
+            }
+        }
+
+        self.split_hit = None;
+        false
+    }
+
+    /// Samples a point in the atmosphere along the path's last ray, and a
+    /// light to illuminate it, setting up the shadow ray and the light that
+    /// will be added if it's not in shadow.  Also attenuates the path by
+    /// the atmosphere along the ray.  Returns whether there's a shadow ray
+    /// to trace.
+    ///
+    /// `ray` is the origin and direction of the ray, and `isect` is what
+    /// it hit.
+    fn sample_atmosphere(
+        &mut self,
+        xform_stack: &mut TransformStack,
+        scene: &Scene,
+        settings: &RenderSettings,
+        atmosphere: &Atmosphere,
+        ray: (Point, Vector),
+        isect: &surface::SurfaceIntersection,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+    ) -> bool {
+        let (orig, dir) = ray;
+        let max_t = if let surface::SurfaceIntersection::Hit {
+            intersection_data: ref idata,
+            ..
+        } = *isect
+        {
+            idata.t
+        } else {
+            f32::INFINITY
+        };
+        let (near, far) = if let Some(extent) = atmosphere.extent(orig, dir, max_t) {
+            extent
+        } else {
+            return false;
+        };
+
+        // The light scattered at any point along the ray is attenuated
+        // like anything the ray finds past it, up to that point.
+        let throughput = self.light_attenuation / self.closure_sample_pdf;
+        let transmittance = atmosphere.transmittance_over(far - near, self.wavelength);
+        self.light_attenuation *= transmittance;
+        self.trace(|_| {
+            format!(
+                "  atmosphere: from {} to {} along the ray, transmittance {:?}",
+                near, far, transmittance
+            )
+        });
+
+        let (scattering, extinction) = atmosphere.coefficients(self.wavelength);
+        if max_element(scattering) <= 0.0 {
+            return false;
+        }
+        let segment = self.bounce_count;
+        let samp = |dims: Dims, i| {
+            get_sample(
+                dims::segment_dim(segment, dims, i),
+                self.vertex_sample,
+                self.pixel_co,
+                self.sampling_seed,
+            )
+        };
+        let dir = dir.normalized();
+
+        // Pick the distance to scatter at.  Light from a light near the
+        // ray mostly scatters close to it, so sampling in proportion to
+        // the inverse squared distance to a point on a light does well.
+        // Light from world lights scatters evenly, so then the distance is
+        // sampled in proportion to transmittance instead.
+        let (start, start_err) = ray_point_err(orig, dir, near);
+        xform_stack.clear();
+        let center = scene.sample_lights(
+            xform_stack,
+            samp(dims::ATMOSPHERE_CENTER, 0),
+            (
+                samp(dims::ATMOSPHERE_CENTER, 1),
+                samp(dims::ATMOSPHERE_CENTER, 2),
+                samp(dims::ATMOSPHERE_CENTER, 3),
+            ),
+            self.wavelength,
+            self.time,
+            &atmosphere_point(start, start_err, dir),
+        );
+        let u = samp(dims::ATMOSPHERE_DISTANCE, 0);
+        let (dist, dist_pdf) = match center {
+            SceneLightSample::Surface { sample_geo, .. } => {
+                let to_center = sample_geo.0 - orig;
+                let center_dist = dot(to_center, dir);
+                let center_offset = (to_center - (dir * center_dist)).length();
+                if center_offset > 0.0 {
+                    sample_equiangular(near, far, center_dist, center_offset, u)
+                } else {
+                    sample_exponential(near, far, extinction.dot(Vec4::splat(0.25)), u)
+                }
+            }
+            _ => sample_exponential(near, far, extinction.dot(Vec4::splat(0.25)), u),
+        };
+        if dist_pdf <= 0.0 || !dist_pdf.is_finite() {
+            return false;
+        }
+
+        // Sample a light to illuminate the scattering point.
+        let (pos, pos_err) = ray_point_err(orig, dir, dist);
+        xform_stack.clear();
+        let light_info = scene.sample_lights(
+            xform_stack,
+            samp(dims::ATMOSPHERE_LIGHT, 0),
+            (
+                samp(dims::ATMOSPHERE_LIGHT, 1),
+                samp(dims::ATMOSPHERE_LIGHT, 2),
+                samp(dims::ATMOSPHERE_LIGHT, 3),
+            ),
+            self.wavelength,
+            self.time,
+            &atmosphere_point(pos, pos_err, dir),
+        );
+        if light_info.is_none() || light_info.pdf() <= 0.0 || light_info.selection_pdf() <= 0.0 {
+            self.trace(|_| "  atmosphere light sample: none".to_string());
+            return false;
+        }
+        // The scattering point can be right at the surface the ray hit, so
+        // the shadow ray is offset from it like from any other hit.
+        let nor = Normal::new(-dir.x(), -dir.y(), -dir.z());
+        let (to_light, shadow_ray) = match light_info {
+            SceneLightSample::None => unreachable!(),
+            SceneLightSample::Distant { direction, .. } => (
+                direction,
+                Ray {
+                    orig: robust_ray_origin(pos, pos_err, nor, direction),
+                    dir: direction,
+                    time: self.time,
+                    wavelength: self.wavelength,
+                    max_t: f32::INFINITY,
+                },
+            ),
+            SceneLightSample::Surface { sample_geo, .. } => {
+                let to_light = sample_geo.0 - pos;
+                let offset_end = robust_ray_origin(
+                    sample_geo.0,
+                    sample_geo.2,
+                    sample_geo.1.normalized(),
+                    -to_light,
+                );
+                let offset_pos = robust_ray_origin(pos, pos_err, nor, to_light);
+                (
+                    to_light,
+                    Ray {
+                        orig: offset_pos,
+                        dir: offset_end - offset_pos,
+                        time: self.time,
+                        wavelength: self.wavelength,
+                        max_t: 1.0,
+                    },
+                )
+            }
+        };
+
+        // Light travels from the light toward `pos`, and from there back
+        // along the ray, so it's deflected by the angle between the two.
+        let phase = atmosphere.phase(cos_between(dir, to_light));
+        let light = light_info.color().e
+            * atmosphere.transmittance(
+                shadow_ray.orig,
+                shadow_ray.dir,
+                shadow_ray.max_t,
+                self.wavelength,
+            )
+            / (light_info.pdf() * light_info.selection_pdf());
+        self.pending_color_addition = throughput
+            * atmosphere.transmittance_over(dist - near, self.wavelength)
+            * scattering
+            * phase
+            * light
+            / dist_pdf;
+        if max_element(self.pending_color_addition) <= 0.0 {
+            return false;
+        }
+        self.trace(|p| {
+            format!(
+                "  atmosphere: scattering at {}, shadow ray toward {}, would add {:?}",
+                dist,
+                fmt_xyz(shadow_ray.dir.x(), shadow_ray.dir.y(), shadow_ray.dir.z()),
+                p.pending_color_addition,
+            )
+        });
+        start_shadow_ray(&shadow_ray, settings, rays, ray_idx);
+        true
+    }
+
+    /// Samples a light to illuminate a hit, setting up the shadow ray and
+    /// the light that will be added if it's not in shadow.  Returns whether
+    /// there's anything to trace.
+    ///
+    /// `vertex` is the hit's vertex number along the path, and
+    /// `sample_counts` is the number of light and bounce samples taken at
+    /// the hit, for MIS.  `resampled` is the sample values and weight of a
+    /// resampled light sample to use instead of the vertex's own, which
+    /// isn't weighted by MIS.
+    fn sample_light(
+        &mut self,
+        xform_stack: &mut TransformStack,
+        scene: &Scene,
+        settings: &RenderSettings,
+        vertex: u32,
+        idata: &surface::SurfaceIntersectionData,
+        closure: &SurfaceClosure,
+        sample_counts: (f32, f32),
+        resampled: Option<(LightSampleValues, f32)>,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+    ) -> bool {
+        let (samples, sample_counts, weight) = match resampled {
+            Some((samples, weight)) => (samples, (1.0, 0.0), weight),
+            None => (self.light_sample_values(vertex), sample_counts, 1.0),
+        };
+        if weight <= 0.0 {
+            self.trace(|_| "  light sample: none".to_string());
+            return false;
+        }
+        if let Some((shadow_ray, light)) = sample_light_ray(
+            xform_stack,
+            scene,
+            settings,
+            idata,
+            closure,
+            sample_counts,
+            samples,
+            self.wavelength,
+            self.time,
+        ) {
+            let light = if let Some(ref atmosphere) = scene.world.atmosphere {
+                light
+                    * atmosphere.transmittance(
+                        shadow_ray.orig,
+                        shadow_ray.dir,
+                        shadow_ray.max_t,
+                        self.wavelength,
+                    )
+            } else {
+                light
+            };
+            self.pending_color_addition = light * weight * self.light_attenuation;
+            self.trace(|p| {
+                format!(
+                    "  light sample: shadow ray toward {}, max t {}, would add {:?}",
+                    fmt_xyz(shadow_ray.dir.x(), shadow_ray.dir.y(), shadow_ray.dir.z()),
+                    shadow_ray.max_t,
+                    p.pending_color_addition,
+                )
+            });
+            start_shadow_ray(&shadow_ray, settings, rays, ray_idx);
+            true
+        } else {
+            self.trace(|_| "  light sample: none".to_string());
+            false
+        }
+    }
+
+    /// Russian roulette for the bounce sampled at vertex `vertex`, from
+    /// vertex `roulette_bounces` on.  Returns whether the path survives,
+    /// and if so weights the bounce to make up for the paths that didn't.
+    ///
+    /// The chance of surviving follows the path's throughput, but is at
+    /// most `MAX_ROULETTE_SURVIVAL`, so that even paths that lose no light
+    /// (e.g. between perfect mirrors) end after a few more bounces on
+    /// average.
+    fn survive_roulette(&mut self, vertex: u32, settings: &RenderSettings) -> bool {
+        match settings.roulette_bounces {
+            Some(start) if vertex >= start => {}
+            _ => return true,
+        }
+
+        let throughput = max_element(self.light_attenuation * self.next_attenuation_fac)
+            / self.closure_sample_pdf;
+        let survival = throughput.min(MAX_ROULETTE_SURVIVAL);
+        let n = get_sample(
+            dims::roulette_dim(vertex),
+            self.vertex_sample,
+            self.pixel_co,
+            self.sampling_seed,
+        );
+        if n < survival {
+            self.next_attenuation_fac /= survival;
+            true
+        } else {
+            self.trace(|_| format!("  no bounce: ended by roulette, survival {}", survival));
+            self.next_bounce_ray = None;
+            false
+        }
+    }
+
+    /// Makes the path's next bounce ray the ray it traces next.
+    fn start_bounce_ray(&self, settings: &RenderSettings, rays: &mut RayBatch, ray_idx: usize) {
+        rays.set_from_ray(&self.next_bounce_ray.unwrap(), false, ray_idx);
+        let proxy = if self.next_bounce_specular {
+            settings.proxy_rays.specular
+        } else {
+            settings.proxy_rays.diffuse
+        };
+        if proxy {
+            rays.mark_proxy(ray_idx);
+        }
+    }
+
+    /// Samples the closure of a hit for the path's next bounce, storing the
+    /// bounce ray and its attenuation.  Returns whether there is a bounce.
+    ///
+    /// `vertex` is the hit's vertex number along the path, and
+    /// `sample_counts` is the number of light and bounce samples taken at
+    /// the hit, for MIS.
+    fn sample_bounce(
+        &mut self,
+        vertex: u32,
+        idata: &surface::SurfaceIntersectionData,
+        closure: &SurfaceClosure,
+        sample_counts: (f32, f32),
+    ) -> bool {
+        // Sample closure
+        let (dir, filter, pdf) = {
+            let u = self.vertex_samp(vertex, dims::BSDF, 0);
+            let v = self.vertex_samp(vertex, dims::BSDF, 1);
+            closure.sample(
+                idata.incoming,
+                idata.nor,
+                idata.nor_g,
+                (u, v),
+                self.wavelength,
+            )
+        };
+
+        // Check if pdf is zero, to avoid NaN's.
+        if (pdf > 0.0) && (max_element(filter.e) > 0.0) {
+            // Account for the additional light attenuation from
+            // this bounce
+            self.next_attenuation_fac = filter.e;
+            self.closure_sample_pdf = pdf * sample_counts.1;
+            self.mis_light_samples = sample_counts.0;
+
+            // Calculate the ray for this bounce
+            let offset_pos =
+                robust_ray_origin(idata.pos, idata.pos_err, idata.nor_g.normalized(), dir);
+            self.next_bounce_ray = Some(Ray {
+                orig: offset_pos,
+                dir: dir,
+                time: self.time,
+                wavelength: self.wavelength,
+                max_t: f32::INFINITY,
+            });
+            self.next_bounce_specular = is_specular(closure);
+            self.trace(|p| {
+                format!(
+                    "  bounce: direction {}, pdf {}, filter {:?}",
+                    fmt_xyz(dir.x(), dir.y(), dir.z()),
+                    p.closure_sample_pdf,
+                    filter.e,
+                )
+            });
+
+            true
+        } else {
+            self.trace(|_| format!("  bounce: none, pdf {}, filter {:?}", pdf, filter.e));
+            self.next_bounce_ray = None;
+            false
+        }
+    }
+}
+
+/// Samples a light to illuminate a hit.  Returns the shadow ray, and the
+/// light that arrives along it if it's not in shadow, already filtered by
+/// the closure and divided by the sample's MIS-weighted pdf.  Returns
+/// `None` if there's nothing to trace.
+///
+/// `sample_counts` is the number of light and bounce samples taken at the
+/// hit, for MIS, and `samples` are the light selection and light sampling
+/// sample values.
+pub(crate) fn sample_light_ray(
+    xform_stack: &mut TransformStack,
+    scene: &Scene,
+    settings: &RenderSettings,
+    idata: &surface::SurfaceIntersectionData,
+    closure: &SurfaceClosure,
+    sample_counts: (f32, f32),
+    samples: (f32, (f32, f32, f32)),
+    wavelength: f32,
+    time: f32,
+) -> Option<(Ray, Vec4)> {
+    let (light_n, light_uvw) = samples;
+    xform_stack.clear();
+    let light_info = scene.sample_lights(
+        xform_stack,
+        light_n,
+        light_uvw,
+        wavelength,
+        time,
+        &surface::SurfaceIntersection::Hit {
+            intersection_data: *idata,
+            closure: *closure,
+        },
+    );
+    if light_info.is_none() || light_info.pdf() <= 0.0 || light_info.selection_pdf() <= 0.0 {
+        return None;
+    }
+    let light_pdf = light_info.pdf();
+    let light_sel_pdf = light_info.selection_pdf();
+
+    // Calculate the shadow ray and surface closure stuff
+    let (attenuation, closure_pdf, shadow_ray) = match light_info {
+        SceneLightSample::None => unreachable!(),
+
+        // Distant light
+        SceneLightSample::Distant { direction, .. } => {
+            let (attenuation, closure_pdf) = closure.evaluate(
+                idata.incoming,
+                direction,
+                idata.nor,
+                idata.nor_g,
+                wavelength,
+            );
+            let shadow_ray = {
+                // Calculate the shadow ray for testing if the light is
+                // in shadow or not.
+                let offset_pos = robust_ray_origin(
+                    idata.pos,
+                    idata.pos_err,
+                    idata.nor_g.normalized(),
+                    direction,
+                );
+                Ray {
+                    orig: offset_pos,
+                    dir: direction,
+                    time: time,
+                    wavelength: wavelength,
+                    max_t: f32::INFINITY,
+                }
+            };
+            (attenuation, closure_pdf, shadow_ray)
+        }
+
+        // Surface light
+        SceneLightSample::Surface { sample_geo, .. } => {
+            let dir = sample_geo.0 - idata.pos;
+            let (attenuation, closure_pdf) =
+                closure.evaluate(idata.incoming, dir, idata.nor, idata.nor_g, wavelength);
+            let shadow_ray = {
+                // Calculate the shadow ray for testing if the light is
+                // in shadow or not.
+                let offset_pos =
+                    robust_ray_origin(idata.pos, idata.pos_err, idata.nor_g.normalized(), dir);
+                let offset_end =
+                    robust_ray_origin(sample_geo.0, sample_geo.2, sample_geo.1.normalized(), -dir);
+                Ray {
+                    orig: offset_pos,
+                    dir: offset_end - offset_pos,
+                    time: time,
+                    wavelength: wavelength,
+                    max_t: 1.0,
+                }
+            };
+            (attenuation, closure_pdf, shadow_ray)
+        }
+    };
+
+    // If there's no possible contribution, don't bother.
+    if max_element(attenuation.e) <= 0.0 {
+        return None;
+    }
+
+    // Calculate and store the light that will be contributed
+    // to the film plane if the light is not in shadow.
+    let (light_samples, bounce_samples) = sample_counts;
+    let light_mis_pdf = if light_info.is_delta() {
+        // Delta lights can't be hit by bounce rays.
+        light_pdf * light_sel_pdf * light_samples
+    } else if let SceneLightSample::Distant { .. } = light_info {
+        // Bounce rays that escape the scene find world
+        // lights with a known selection pdf, so it's
+        // included in the weights.
+        settings.mis.mis_pdf(
+            light_pdf * light_sel_pdf * light_samples,
+            closure_pdf * bounce_samples,
+        )
+    } else {
+        // The selection pdf of a local light depends on
+        // the point being lit, and isn't known when a
+        // bounce ray hits the light.  So it's left out of
+        // the weights for both strategies, which still
+        // sum to one.
+        settings
+            .mis
+            .mis_pdf(light_pdf * light_samples, closure_pdf * bounce_samples)
+            * light_sel_pdf
+    };
+    Some((
+        shadow_ray,
+        light_info.color().e * attenuation.e / light_mis_pdf,
+    ))
+}
+
+/// Makes `shadow_ray` the ray a path traces next.
+fn start_shadow_ray(
+    shadow_ray: &Ray,
+    settings: &RenderSettings,
+    rays: &mut RayBatch,
+    ray_idx: usize,
+) {
+    rays.set_from_ray(shadow_ray, true, ray_idx);
+    if settings.proxy_rays.shadow {
+        rays.mark_proxy(ray_idx);
+    }
+}
+
+/// Closures smoother than this scatter specular rays, which see proxy
+/// geometry according to `ProxyRays::specular` rather than `diffuse`.
+const SPECULAR_ROUGHNESS: f32 = 0.1;
+
+fn is_specular(closure: &SurfaceClosure) -> bool {
+    match *closure {
+        SurfaceClosure::GGX { roughness, .. } => roughness < SPECULAR_ROUGHNESS,
+        _ => false,
+    }
+}
+
+/// A stand-in hit at a point in the atmosphere, for picking lights to
+/// illuminate it.  `dir` is the direction of the ray it's on.
+fn atmosphere_point(pos: Point, pos_err: f32, dir: Vector) -> surface::SurfaceIntersection {
+    let nor = Normal::new(-dir.x(), -dir.y(), -dir.z());
+    let (dpdu, dpdv) = surface::arbitrary_tangents(nor);
+    surface::SurfaceIntersection::Hit {
+        intersection_data: surface::SurfaceIntersectionData {
+            incoming: dir,
+            pos: pos,
+            pos_err: pos_err,
+            nor: nor,
+            nor_g: nor,
+            uv: (0.0, 0.0),
+            dpdu: dpdu,
+            dpdv: dpdv,
+            local_space: Matrix4x4::new(),
+            t: 0.0,
+            footprint: 0.0,
+            sample_pdf: 0.0,
+            object_id: 0,
+            instance_id: 0,
+            instance_index: 0,
+        },
+        closure: SurfaceClosure::Emit(Color::new_xyz((0.0, 0.0, 0.0))),
+    }
+}
+
+/// The number of light samples to take at a first hit when splitting.
+///
+/// This is scaled down by the roughness of glossy closures, since light
+/// samples are mostly wasted on smooth surfaces, where bounce samples
+/// find the lights better anyway.
+fn split_light_samples(closure: &SurfaceClosure, light_splits: u32) -> u32 {
+    match *closure {
+        SurfaceClosure::GGX { roughness, .. } => ((light_splits as f32 * roughness).ceil() as u32)
+            .max(1)
+            .min(light_splits),
+        _ => light_splits,
+    }
+}
+
+/// Formats coordinates for a path trace.
+fn fmt_xyz(x: f32, y: f32, z: f32) -> String {
+    format!("({}, {}, {})", x, y, z)
+}
+
+/// Splits the samples of each pixel into the ranges of sample indices
+/// that are rendered in each pass over the image.
+///
+/// Normally that's a single pass.  With a time limit the image is refined
+/// over several, so rendering can stop between them with every pixel
+/// having the same number of samples.  The first pass is a single sample
+/// per pixel, so there's a full image as soon as possible, and the passes
+/// then double in size up to 1 / TIME_LIMITED_PASSES of the spp.
+///
+/// Progressive rendering instead doubles the total samples each pass, so
+/// the image after each one can be compared to the last.
+fn sample_passes(spp: u32, time_limited: bool, progressive: bool) -> Vec<(u32, u32)> {
+    if progressive {
+        let mut passes = Vec::new();
+        let mut start = 0;
+        while start < spp {
+            let end = (start * 2).max(1).min(spp);
+            passes.push((start, end));
+            start = end;
+        }
+        return passes;
+    }
+    if !time_limited {
+        return vec![(0, spp)];
+    }
+    let max_pass_spp = (spp.max(1) - 1) / TIME_LIMITED_PASSES + 1;
+    let mut passes = Vec::new();
+    let mut pass_spp = 1;
+    let mut start = 0;
+    while start < spp {
+        let end = (start + pass_spp).min(spp);
+        passes.push((start, end));
+        start = end;
+        pass_spp = (pass_spp * 2).min(max_pass_spp);
+    }
+    passes
 }
 
 /// Gets a sample, using LDS samples for lower dimensions,
 /// and switching to random samples at higher dimensions where
 /// LDS samples aren't available.
 #[inline(always)]
-fn get_sample(dimension: u32, i: u32, pixel_co: (u32, u32), seed: u32) -> f32 {
+pub(crate) fn get_sample(dimension: u32, i: u32, pixel_co: (u32, u32), seed: u32) -> f32 {
     // A unique random scramble value for every pixel coordinate up to
     // a resolution of 65536 x 65536.  Also further randomized by a seed.
     let scramble = hash_u32(pixel_co.0 ^ (pixel_co.1 << 16), seed);
@@ -722,10 +2634,129 @@ fn get_sample(dimension: u32, i: u32, pixel_co: (u32, u32), seed: u32) -> f32 {
     }
 }
 
-#[derive(Debug)]
+/// Splits each bucket that's estimated by `estimate` to take longer than
+/// `max_time`, for as long as `BucketJob::split()` allows.  The parts of
+/// a bucket stay where it was in the order.
+fn split_slow_buckets<F>(buckets: Vec<BucketJob>, max_time: f64, estimate: F) -> Vec<BucketJob>
+where
+    F: Fn(&BucketJob) -> f64,
+{
+    let mut split_buckets = Vec::with_capacity(buckets.len());
+    let mut stack = Vec::new();
+    for bucket in buckets {
+        stack.push(bucket);
+        while let Some(bucket) = stack.pop() {
+            match bucket.split() {
+                Some((a, b)) if estimate(&bucket) > max_time => {
+                    stack.push(b);
+                    stack.push(a);
+                }
+                _ => split_buckets.push(bucket),
+            }
+        }
+    }
+    split_buckets
+}
+
+#[derive(Debug, Copy, Clone)]
 struct BucketJob {
     x: u32,
     y: u32,
     w: u32,
     h: u32,
+    samples: (u32, u32), // Range of sample indices to take in each pixel
+}
+
+impl BucketJob {
+    /// Splits the bucket in two across its longer side, unless that would
+    /// make buckets narrower than `MIN_SPLIT_BUCKET_DIM` pixels.
+    fn split(&self) -> Option<(BucketJob, BucketJob)> {
+        if self.w >= self.h && self.w >= (MIN_SPLIT_BUCKET_DIM * 2) {
+            let w = self.w / 2;
+            Some((
+                BucketJob { w: w, ..*self },
+                BucketJob {
+                    x: self.x + w,
+                    w: self.w - w,
+                    ..*self
+                },
+            ))
+        } else if self.h >= (MIN_SPLIT_BUCKET_DIM * 2) {
+            let h = self.h / 2;
+            Some((
+                BucketJob { h: h, ..*self },
+                BucketJob {
+                    y: self.y + h,
+                    h: self.h - h,
+                    ..*self
+                },
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_passes_cover_all_samples() {
+        assert_eq!(sample_passes(100, false, false), vec![(0, 100)]);
+        assert_eq!(
+            sample_passes(4, true, false),
+            vec![(0, 1), (1, 2), (2, 3), (3, 4)]
+        );
+        assert_eq!(sample_passes(0, true, false), vec![]);
+        assert_eq!(
+            sample_passes(20, false, true),
+            vec![(0, 1), (1, 2), (2, 4), (4, 8), (8, 16), (16, 20)]
+        );
+        assert_eq!(
+            sample_passes(20, true, true),
+            sample_passes(20, false, true)
+        );
+
+        let passes = sample_passes(1000, true, false);
+        assert_eq!(&passes[..4], &[(0, 1), (1, 3), (3, 7), (7, 15)]);
+        assert_eq!(passes[6], (63, 126));
+        assert_eq!(passes.last(), Some(&(945, 1000)));
+        for pair in passes.windows(2) {
+            assert_eq!(pair[0].1, pair[1].0);
+        }
+        assert!(passes.iter().all(|&(start, end)| end - start <= 63));
+    }
+
+    #[test]
+    fn slow_buckets_are_split() {
+        let bucket = |x, y| BucketJob {
+            x: x,
+            y: y,
+            w: 64,
+            h: 64,
+            samples: (0, 16),
+        };
+        let buckets = vec![bucket(0, 0), bucket(64, 0), bucket(128, 0)];
+
+        // Only the middle bucket is slow, taking a second per pixel.
+        let estimate = |b: &BucketJob| {
+            let overlap = (b.x + b.w).min(128).saturating_sub(b.x.max(64));
+            (overlap * b.h) as f64
+        };
+        let split = split_slow_buckets(buckets, 1024.0, estimate);
+        assert_eq!(split.len(), 2 + 4);
+        assert_eq!((split[0].x, split[0].w), (0, 64));
+        assert_eq!((split[5].x, split[5].w), (128, 64));
+        for b in &split[1..5] {
+            assert_eq!((b.w, b.h), (32, 32));
+            assert!(b.x >= 64 && b.x < 128);
+        }
+        let area: u32 = split.iter().map(|b| b.w * b.h).sum();
+        assert_eq!(area, 3 * 64 * 64);
+
+        // Buckets aren't split below the minimum size.
+        let split = split_slow_buckets(vec![bucket(64, 0)], 0.0, estimate);
+        assert_eq!(split.len(), (64 / MIN_SPLIT_BUCKET_DIM as usize).pow(2));
+    }
 }
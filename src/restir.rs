@@ -0,0 +1,365 @@
+//! Reservoir-based importance resampling of direct lighting, after
+//! "Spatiotemporal reservoir resampling for real-time ray tracing with
+//! dynamic direct lighting" by Bitterli et al.
+//!
+//! Each camera ray hit draws several light samples, and keeps one of them
+//! with probability proportional to its unshadowed contribution.  It then
+//! also considers the samples kept by a few similar hits nearby in the
+//! same bucket, so that each hit's one shadow ray goes toward a light
+//! chosen out of dozens of candidates.  In scenes with many lights this
+//! is far less noisy than plain light sampling at the same sample count.
+//!
+//! Light samples are kept as the sample values that light sampling turns
+//! into a point on a light, rather than as the point itself.  A sample
+//! from one hit can then be used at another simply by sampling the lights
+//! from there with the same values, without any change of measure.  The
+//! combined samples are weighted by how many of the hits could have
+//! produced them, which keeps the result unbiased.
+//!
+//! There's no temporal reuse, since frames aren't rendered in sequence.
+//! And because neighbors come from the same bucket, results depend on the
+//! bucket size, unlike with plain path tracing.
+
+use glam::Vec4;
+
+use crate::{
+    hash::{hash_u32, hash_u32_to_f32},
+    math::dot,
+    ray::Ray,
+    render_settings::RenderSettings,
+    renderer::sample_light_ray,
+    scene::Scene,
+    shading::surface_closure::SurfaceClosure,
+    surface::SurfaceIntersectionData,
+    transform_stack::TransformStack,
+};
+
+/// Light samples drawn at each hit.
+const CANDIDATES: u32 = 8;
+
+/// Neighboring hits whose samples each hit considers.
+const NEIGHBORS: u32 = 4;
+
+/// How far away neighbors can be, in pixels.
+const NEIGHBOR_RADIUS: i32 = 3;
+
+/// The light selection and light sampling sample values that light
+/// sampling turns into a light sample.
+pub type LightSampleValues = (f32, (f32, f32, f32));
+
+/// A camera ray hit to resample the direct lighting of.
+#[derive(Debug, Copy, Clone)]
+pub struct ShadingPoint {
+    pub idata: SurfaceIntersectionData,
+    pub closure: SurfaceClosure,
+    pub wavelength: f32,
+    pub time: f32,
+    pub light_sample: LightSampleValues, // The hit's own light sample
+}
+
+/// A single light sample, chosen out of a stream of candidates with
+/// probability proportional to their weights.
+#[derive(Debug, Copy, Clone)]
+pub struct Reservoir {
+    pub sample: LightSampleValues,
+    pub target: f32, // Target function of `sample` where it was chosen
+    pub weight_sum: f32,
+    pub count: u32, // Candidates seen, including those within combined reservoirs
+}
+
+impl Reservoir {
+    pub fn new() -> Reservoir {
+        Reservoir {
+            sample: (0.0, (0.0, 0.0, 0.0)),
+            target: 0.0,
+            weight_sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Considers a candidate sample, which stands for `count` candidates.
+    /// `u` is a random number in [0, 1).  Returns whether the candidate
+    /// replaced the current sample.
+    pub fn update(
+        &mut self,
+        sample: LightSampleValues,
+        weight: f32,
+        target: f32,
+        count: u32,
+        u: f32,
+    ) -> bool {
+        self.count += count;
+        if weight <= 0.0 {
+            return false;
+        }
+        self.weight_sum += weight;
+        if u * self.weight_sum < weight {
+            self.sample = sample;
+            self.target = target;
+            return true;
+        }
+        return false;
+    }
+
+    /// The reservoir's unbiased contribution weight: what its sample's
+    /// contribution is multiplied by, in place of dividing by a pdf.
+    ///
+    /// `count` is the number of candidates that could have produced the
+    /// sample, which is less than `self.count` when some of the combined
+    /// reservoirs' hits couldn't have.
+    pub fn contribution_weight(&self, count: u32) -> f32 {
+        if self.target > 0.0 && count > 0 {
+            self.weight_sum / (count as f32 * self.target)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// The function that light samples are resampled toward: the luminance
+/// of their unshadowed contribution, in primary sample space.
+pub fn target_function(contribution: Vec4) -> f32 {
+    let sum = contribution.dot(Vec4::splat(1.0));
+    if sum.is_finite() {
+        sum.max(0.0)
+    } else {
+        0.0
+    }
+}
+
+/// Resamples the light samples of the hits of a bucket's camera rays.
+///
+/// `points` has an entry for each of the bucket's paths, in the order the
+/// renderer generates them: by row, then column, then sample number.
+/// `bucket` is the bucket's (x, y, width, height) and `samples` the range
+/// of sample numbers in each pixel.
+///
+/// Returns, for each hit, the chosen light sample values and the
+/// contribution weight to multiply that sample's (non-MIS) contribution
+/// by.
+pub fn resample(
+    xform_stack: &mut TransformStack,
+    scene: &Scene,
+    settings: &RenderSettings,
+    points: &[Option<ShadingPoint>],
+    bucket: (u32, u32, u32, u32),
+    samples: (u32, u32),
+) -> Vec<Option<(LightSampleValues, f32)>> {
+    let (bx, by, bw, bh) = bucket;
+    let spp = samples.1 - samples.0;
+    debug_assert_eq!(points.len(), (bw * bh * spp) as usize);
+    let location = |i: usize| {
+        let i = i as u32;
+        let pixel = i / spp;
+        ((bx + pixel % bw, by + pixel / bw), samples.0 + i % spp)
+    };
+    let mut target = |point: &ShadingPoint, sample: LightSampleValues| {
+        evaluate(xform_stack, scene, settings, point, sample)
+            .map(|(_, contribution)| target_function(contribution))
+            .unwrap_or(0.0)
+    };
+
+    // Each hit's own candidates.
+    let mut initial = Vec::with_capacity(points.len());
+    for (i, point) in points.iter().enumerate() {
+        initial.push(point.map(|point| {
+            let (pixel_co, sample_number) = location(i);
+            let mut rng = Rng::new(pixel_co, sample_number, settings.seed);
+            let mut reservoir = Reservoir::new();
+            for c in 0..CANDIDATES {
+                let sample = if c == 0 {
+                    point.light_sample
+                } else {
+                    (rng.next(), (rng.next(), rng.next(), rng.next()))
+                };
+                let t = target(&point, sample);
+                reservoir.update(sample, t, t, 1, rng.next());
+            }
+            reservoir
+        }));
+    }
+
+    // Combine them with their neighbors'.
+    let mut resampled = Vec::with_capacity(points.len());
+    for (i, point) in points.iter().enumerate() {
+        let point = if let Some(ref point) = *point {
+            point
+        } else {
+            resampled.push(None);
+            continue;
+        };
+        let (pixel_co, sample_number) = location(i);
+        let mut rng = Rng::new(pixel_co, sample_number, settings.seed ^ 0x5bd1_e995);
+
+        let own = initial[i].unwrap();
+        let mut combined = Reservoir::new();
+        combined.update(
+            own.sample,
+            own.weight_sum,
+            own.target,
+            own.count,
+            rng.next(),
+        );
+        let mut used = [i; NEIGHBORS as usize + 1];
+        let mut used_count = 1;
+        for _ in 0..NEIGHBORS {
+            let dx = (rng.next() * (2 * NEIGHBOR_RADIUS + 1) as f32) as i32 - NEIGHBOR_RADIUS;
+            let dy = (rng.next() * (2 * NEIGHBOR_RADIUS + 1) as f32) as i32 - NEIGHBOR_RADIUS;
+            let ds = ((rng.next() * spp as f32) as u32).min(spp - 1);
+            let (x, y) = (pixel_co.0 as i32 + dx, pixel_co.1 as i32 + dy);
+            if x < bx as i32 || y < by as i32 || x >= (bx + bw) as i32 || y >= (by + bh) as i32 {
+                continue;
+            }
+            let n = ((y as u32 - by) * bw + (x as u32 - bx)) * spp + ds;
+            let n = n as usize;
+            if used[..used_count].contains(&n) {
+                continue;
+            }
+            let (neighbor, reservoir) = match (points[n], initial[n]) {
+                (Some(ref neighbor), Some(reservoir)) => (*neighbor, reservoir),
+                _ => continue,
+            };
+            if !similar(point, &neighbor) {
+                continue;
+            }
+
+            let t = target(point, reservoir.sample);
+            let weight =
+                t * reservoir.contribution_weight(reservoir.count) * reservoir.count as f32;
+            combined.update(reservoir.sample, weight, t, reservoir.count, rng.next());
+            used[used_count] = n;
+            used_count += 1;
+        }
+
+        // Only count the candidates of hits that could have produced the
+        // chosen sample.
+        let mut count = 0;
+        if combined.target > 0.0 {
+            for &n in &used[..used_count] {
+                if n == i || target(points[n].as_ref().unwrap(), combined.sample) > 0.0 {
+                    count += initial[n].unwrap().count;
+                }
+            }
+        }
+
+        resampled.push(Some((combined.sample, combined.contribution_weight(count))));
+    }
+
+    resampled
+}
+
+/// Evaluates a light sample at a hit, without MIS.  Returns the shadow
+/// ray and the light's contribution divided by the sample's pdf.
+pub fn evaluate(
+    xform_stack: &mut TransformStack,
+    scene: &Scene,
+    settings: &RenderSettings,
+    point: &ShadingPoint,
+    sample: LightSampleValues,
+) -> Option<(Ray, Vec4)> {
+    sample_light_ray(
+        xform_stack,
+        scene,
+        settings,
+        &point.idata,
+        &point.closure,
+        (1.0, 0.0),
+        sample,
+        point.wavelength,
+        point.time,
+    )
+}
+
+/// Whether two hits are alike enough for their light samples to be worth
+/// sharing.  This only affects noise, not correctness.
+fn similar(a: &ShadingPoint, b: &ShadingPoint) -> bool {
+    let depth_close = (a.idata.t - b.idata.t).abs() <= a.idata.t * 0.1;
+    let facing_alike = dot(a.idata.nor.normalized(), b.idata.nor.normalized()) >= 0.9;
+    depth_close && facing_alike
+}
+
+/// Random numbers for resampling, unique to each sample of each pixel.
+struct Rng {
+    scramble: u32,
+    n: u32,
+}
+
+impl Rng {
+    fn new(pixel_co: (u32, u32), sample_number: u32, seed: u32) -> Rng {
+        Rng {
+            scramble: hash_u32(
+                pixel_co.0 ^ (pixel_co.1 << 16),
+                hash_u32(sample_number, seed),
+            ),
+            n: 0,
+        }
+    }
+
+    /// Returns a number in [0, 1).
+    fn next(&mut self) -> f32 {
+        self.n += 1;
+        hash_u32_to_f32(self.n, self.scramble).min(1.0 - f32::EPSILON)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservoir_picks_proportionally() {
+        let weights = [1.0, 0.0, 3.0, 4.0];
+        let mut picks = [0u32; 4];
+        let mut rng = Rng::new((1, 2), 3, 4);
+        for _ in 0..20000 {
+            let mut r = Reservoir::new();
+            for (i, &w) in weights.iter().enumerate() {
+                r.update((i as f32, (0.0, 0.0, 0.0)), w, w, 1, rng.next());
+            }
+            assert_eq!(r.count, 4);
+            assert_eq!(r.weight_sum, 8.0);
+            picks[(r.sample.0) as usize] += 1;
+        }
+        assert_eq!(picks[1], 0);
+        for &i in &[0, 2, 3] {
+            let expected = 20000.0 * weights[i] / 8.0;
+            assert!((picks[i] as f32 - expected).abs() < expected * 0.05);
+        }
+    }
+
+    #[test]
+    fn combined_reservoirs_are_unbiased() {
+        // Two "hits" with different integrands over [0, 1), one of which
+        // is zero on half of the domain.  The hits' reservoirs are
+        // combined for the first one, whose integral is 2.
+        let f0 = |u: f32| 1.0 + 2.0 * u;
+        let f1 = |u: f32| if u < 0.5 { 4.0 } else { 0.0 };
+        let sample = |u: f32| (u, (0.0, 0.0, 0.0));
+
+        let mut rng = Rng::new((5, 6), 7, 8);
+        let trials = 40000;
+        let mut sum = 0.0f64;
+        for _ in 0..trials {
+            let mut r0 = Reservoir::new();
+            let mut r1 = Reservoir::new();
+            for _ in 0..4 {
+                let u = rng.next();
+                r0.update(sample(u), f0(u), f0(u), 1, rng.next());
+                let u = rng.next();
+                r1.update(sample(u), f1(u), f1(u), 1, rng.next());
+            }
+
+            let mut combined = Reservoir::new();
+            combined.update(r0.sample, r0.weight_sum, r0.target, r0.count, rng.next());
+            let t = f0(r1.sample.0);
+            let w = t * r1.contribution_weight(r1.count) * r1.count as f32;
+            combined.update(r1.sample, w, t, r1.count, rng.next());
+
+            let u = combined.sample.0;
+            let count = r0.count + if f1(u) > 0.0 { r1.count } else { 0 };
+            sum += (f0(u) * combined.contribution_weight(count)) as f64;
+        }
+        let estimate = sum / trials as f64;
+        assert!((estimate - 2.0).abs() < 0.01, "estimate {}", estimate);
+    }
+}
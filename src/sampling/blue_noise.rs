@@ -0,0 +1,217 @@
+//! Blue noise point sets: points that are spread out evenly but without
+//! any regular structure, so that no two are too close together and
+//! there are no large gaps.
+//!
+//! These are for placing things, such as light probes through a scene,
+//! rather than for the per-sample random numbers of the renderer, which
+//! come from the low-discrepancy sequences in `dims`.
+
+use crate::{
+    bbox::BBox,
+    hash::hash_u32_to_f32,
+    math::{Point, Vector},
+};
+
+use super::uniform_sample_sphere;
+
+/// How many candidates are tried around each point of a Poisson disk set
+/// before it's considered surrounded, as suggested by Bridson.
+const POISSON_ATTEMPTS: u32 = 30;
+
+/// A Poisson disk point set in the unit square: points no closer than
+/// `radius` to each other, added until no more fit.
+///
+/// Uses Bridson's algorithm, "Fast Poisson Disk Sampling in Arbitrary
+/// Dimensions".  The number of points depends on the radius, about
+/// 0.7 / radius^2.
+#[allow(dead_code)]
+pub fn poisson_disk_square(radius: f32, seed: u32) -> Vec<(f32, f32)> {
+    poisson_disk([1.0, 1.0, 0.0], 2, radius, seed)
+        .iter()
+        .map(|p| (p[0], p[1]))
+        .collect()
+}
+
+/// A Poisson disk point set in a box: points no closer than `radius` to
+/// each other, added until no more fit.
+pub fn poisson_disk_box(bounds: BBox, radius: f32, seed: u32) -> Vec<Point> {
+    let size = bounds.max - bounds.min;
+    poisson_disk([size.x(), size.y(), size.z()], 3, radius, seed)
+        .iter()
+        .map(|p| bounds.min + Vector::new(p[0], p[1], p[2]))
+        .collect()
+}
+
+/// Bridson's algorithm in a box from the origin to `size`, in the first
+/// `dims` dimensions (2 or 3).
+fn poisson_disk(size: [f32; 3], dims: usize, radius: f32, seed: u32) -> Vec<[f32; 3]> {
+    assert!(radius > 0.0);
+    debug_assert!(dims == 2 || dims == 3);
+
+    // A grid with cells small enough to hold at most one point each.
+    let cell_size = radius / (dims as f32).sqrt();
+    let mut res = [1usize; 3];
+    for d in 0..dims {
+        res[d] = ((size[d] / cell_size).ceil() as usize).max(1);
+    }
+    let cell_of = |p: &[f32; 3]| {
+        let mut cell = [0usize; 3];
+        for d in 0..dims {
+            cell[d] = ((p[d] / cell_size) as usize).min(res[d] - 1);
+        }
+        cell
+    };
+    let grid_index = |c: [usize; 3]| (((c[2] * res[1]) + c[1]) * res[0]) + c[0];
+    let mut grid: Vec<Option<usize>> = vec![None; res[0] * res[1] * res[2]];
+
+    let mut rng = Rng::new(seed);
+    let mut points: Vec<[f32; 3]> = Vec::new();
+    let mut active: Vec<usize> = Vec::new();
+    let mut first = [0.0f32; 3];
+    for d in 0..dims {
+        first[d] = rng.next() * size[d];
+    }
+    grid[grid_index(cell_of(&first))] = Some(0);
+    points.push(first);
+    active.push(0);
+
+    while !active.is_empty() {
+        let ai = ((rng.next() * active.len() as f32) as usize).min(active.len() - 1);
+        let center = points[active[ai]];
+
+        let mut placed = false;
+        for _ in 0..POISSON_ATTEMPTS {
+            // Uniformly distributed between one and two radii away.
+            let (u, v, w) = (rng.next(), rng.next(), rng.next());
+            let offset = if dims == 2 {
+                let r = radius * (1.0 + (3.0 * u)).sqrt();
+                let angle = v * std::f32::consts::PI * 2.0;
+                [r * angle.cos(), r * angle.sin(), 0.0]
+            } else {
+                let r = radius * (1.0 + (7.0 * u)).cbrt();
+                let dir = uniform_sample_sphere(v, w);
+                [r * dir.x(), r * dir.y(), r * dir.z()]
+            };
+            let mut candidate = [0.0f32; 3];
+            let mut inside = true;
+            for d in 0..dims {
+                candidate[d] = center[d] + offset[d];
+                inside &= candidate[d] >= 0.0 && candidate[d] < size[d];
+            }
+            if !inside {
+                continue;
+            }
+
+            // Points closer than the radius are at most two cells away.
+            let cell = cell_of(&candidate);
+            let mut lo = [0usize; 3];
+            let mut hi = [0usize; 3];
+            for d in 0..dims {
+                lo[d] = cell[d].saturating_sub(2);
+                hi[d] = (cell[d] + 2).min(res[d] - 1);
+            }
+            let mut too_close = false;
+            'search: for z in lo[2]..=hi[2] {
+                for y in lo[1]..=hi[1] {
+                    for x in lo[0]..=hi[0] {
+                        if let Some(i) = grid[grid_index([x, y, z])] {
+                            let p = points[i];
+                            let dist2: f32 = (0..dims).map(|d| (p[d] - candidate[d]).powi(2)).sum();
+                            if dist2 < radius * radius {
+                                too_close = true;
+                                break 'search;
+                            }
+                        }
+                    }
+                }
+            }
+            if too_close {
+                continue;
+            }
+
+            grid[grid_index(cell)] = Some(points.len());
+            active.push(points.len());
+            points.push(candidate);
+            placed = true;
+            break;
+        }
+
+        // No room left around this point.
+        if !placed {
+            active.swap_remove(ai);
+        }
+    }
+
+    points
+}
+
+/// A stream of random numbers in [0, 1).
+struct Rng {
+    n: u32,
+    seed: u32,
+}
+
+impl Rng {
+    fn new(seed: u32) -> Rng {
+        Rng { n: 0, seed: seed }
+    }
+
+    fn next(&mut self) -> f32 {
+        self.n += 1;
+        hash_u32_to_f32(self.n, self.seed).min(1.0 - std::f32::EPSILON)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dist2(a: (f32, f32), b: (f32, f32)) -> f32 {
+        ((a.0 - b.0) * (a.0 - b.0)) + ((a.1 - b.1) * (a.1 - b.1))
+    }
+
+    fn min_dist2(points: &[(f32, f32)]) -> f32 {
+        let mut min = std::f32::INFINITY;
+        for i in 0..points.len() {
+            for j in (i + 1)..points.len() {
+                min = min.min(dist2(points[i], points[j]));
+            }
+        }
+        min
+    }
+
+    #[test]
+    fn poisson_disk_square_spacing() {
+        let radius = 0.05;
+        let points = poisson_disk_square(radius, 3);
+        assert!(points
+            .iter()
+            .all(|p| p.0 >= 0.0 && p.0 < 1.0 && p.1 >= 0.0 && p.1 < 1.0));
+        assert!(min_dist2(&points) >= radius * radius);
+
+        // It fills the square: nowhere is far from a point.
+        for i in 0..500 {
+            let q = (hash_u32_to_f32(i, 10), hash_u32_to_f32(i, 11));
+            assert!(points.iter().any(|&p| dist2(p, q) < 4.0 * radius * radius));
+        }
+
+        // Different seeds give different sets.
+        assert_ne!(points, poisson_disk_square(radius, 4));
+    }
+
+    #[test]
+    fn poisson_disk_box_spacing() {
+        let bounds = BBox::from_points(Point::new(-1.0, 0.0, 2.0), Point::new(1.0, 0.5, 3.0));
+        let radius = 0.2;
+        let points = poisson_disk_box(bounds, radius, 0);
+        assert!(points.len() > 10);
+        for (i, a) in points.iter().enumerate() {
+            assert!(a.x() >= -1.0 && a.x() < 1.0);
+            assert!(a.y() >= 0.0 && a.y() < 0.5);
+            assert!(a.z() >= 2.0 && a.z() < 3.0);
+            for b in &points[(i + 1)..] {
+                assert!((*a - *b).length2() >= radius * radius);
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Which sampler dimensions each random decision along a path uses.
+//!
+//! Every decision gets its own dimensions of the low-discrepancy sequence,
+//! so that the decisions are well distributed with respect to each other.
+//! Two decisions sharing a dimension are correlated, which shows up as
+//! artifacts in the image, so all of the assignments live here rather
+//! than being counted out by the code that uses them.
+//!
+//! Camera paths use the first `CAMERA_DIMS` dimensions for the camera ray.
+//! After that each path vertex, i.e. each hit the path continues from,
+//! gets a block of `VERTEX_DIMS` dimensions.  Photon paths are laid out
+//! the same way, with their own tables.
+//!
+//! Each ray of a camera path, i.e. each segment between vertices, also
+//! gets a block of `SEGMENT_DIMS` dimensions for the atmosphere.  Those
+//! start at `SEGMENTS_START`, past the vertex dimensions of any practical
+//! path length, so that they don't shift the dimensions of scenes without
+//! an atmosphere.  Russian roulette at each camera path vertex likewise
+//! gets a dimension from `ROULETTE_START` on.
+
+/// A run of consecutive dimensions used for one decision.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Dims {
+    pub start: u32,
+    pub count: u32,
+}
+
+impl Dims {
+    const fn new(start: u32, count: u32) -> Dims {
+        Dims {
+            start: start,
+            count: count,
+        }
+    }
+
+    /// The `i`th dimension of the run.
+    #[inline]
+    pub fn dim(&self, i: u32) -> u32 {
+        debug_assert!(i < self.count);
+        self.start + i
+    }
+}
+
+// Camera ray.
+pub const WAVELENGTH: Dims = Dims::new(0, 1);
+pub const TIME: Dims = Dims::new(1, 1);
+pub const LENS: Dims = Dims::new(2, 2);
+pub const FILTER: Dims = Dims::new(4, 2);
+pub const CAMERA_DIMS: u32 = 6;
+
+/// Irradiance cache gather rays and light probe rays don't start at the
+/// camera, so they use the lens dimensions for their direction.
+pub const GATHER_DIR: Dims = LENS;
+
+// Each camera path vertex, relative to the vertex's first dimension.
+pub const LIGHT_SELECT: Dims = Dims::new(0, 1);
+pub const LIGHT_POINT: Dims = Dims::new(1, 3);
+pub const BSDF: Dims = Dims::new(4, 2);
+pub const VERTEX_DIMS: u32 = 6;
+
+// Each camera path segment, relative to the segment's first dimension.
+pub const ATMOSPHERE_DISTANCE: Dims = Dims::new(0, 1);
+pub const ATMOSPHERE_CENTER: Dims = Dims::new(1, 4); // Light select and point
+pub const ATMOSPHERE_LIGHT: Dims = Dims::new(5, 4); // Light select and point
+pub const SEGMENT_DIMS: u32 = 9;
+pub const SEGMENTS_START: u32 = 1 << 12;
+
+// Russian roulette at each camera path vertex, one dimension each.
+pub const ROULETTE_START: u32 = 1 << 13;
+
+// Photon emission.
+pub const PHOTON_WAVELENGTH: Dims = Dims::new(0, 1);
+pub const PHOTON_TIME: Dims = Dims::new(1, 1);
+pub const PHOTON_LIGHT_SELECT: Dims = Dims::new(2, 1);
+pub const PHOTON_POINT: Dims = Dims::new(3, 2);
+pub const PHOTON_DIR: Dims = Dims::new(5, 2);
+pub const PHOTON_DIMS: u32 = 7;
+
+// Each photon path vertex, relative to the vertex's first dimension.
+pub const PHOTON_BSDF: Dims = Dims::new(0, 2);
+pub const PHOTON_ROULETTE: Dims = Dims::new(2, 1);
+pub const PHOTON_VERTEX_DIMS: u32 = 3;
+
+/// Dimension `i` of `dims` at camera path vertex `vertex`, where vertex 0
+/// is the camera ray's hit.
+#[inline]
+pub fn vertex_dim(vertex: u32, dims: Dims, i: u32) -> u32 {
+    CAMERA_DIMS + (vertex * VERTEX_DIMS) + dims.dim(i)
+}
+
+/// Dimension `i` of `dims` along camera path segment `segment`, where
+/// segment 0 is the camera ray.
+#[inline]
+pub fn segment_dim(segment: u32, dims: Dims, i: u32) -> u32 {
+    SEGMENTS_START + (segment * SEGMENT_DIMS) + dims.dim(i)
+}
+
+/// The Russian roulette dimension at camera path vertex `vertex`.
+#[inline]
+pub fn roulette_dim(vertex: u32) -> u32 {
+    ROULETTE_START + vertex
+}
+
+/// Dimension `i` of `dims` at photon path vertex `vertex`, where vertex 0
+/// is the emitted photon's first hit.
+#[inline]
+pub fn photon_vertex_dim(vertex: u32, dims: Dims, i: u32) -> u32 {
+    PHOTON_DIMS + (vertex * PHOTON_VERTEX_DIMS) + dims.dim(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that a table's runs cover the dimensions `0..size` with no
+    /// gaps or overlaps.
+    fn check_table(table: &[Dims], size: u32) {
+        let mut used = vec![false; size as usize];
+        for dims in table {
+            assert!(dims.count > 0);
+            for i in 0..dims.count {
+                let d = dims.dim(i) as usize;
+                assert!(d < used.len(), "{:?} is outside the table", dims);
+                assert!(!used[d], "{:?} overlaps another run", dims);
+                used[d] = true;
+            }
+        }
+        assert!(used.iter().all(|&u| u), "table has unused dimensions");
+    }
+
+    #[test]
+    fn tables_are_disjoint() {
+        check_table(&[WAVELENGTH, TIME, LENS, FILTER], CAMERA_DIMS);
+        check_table(&[LIGHT_SELECT, LIGHT_POINT, BSDF], VERTEX_DIMS);
+        check_table(
+            &[ATMOSPHERE_DISTANCE, ATMOSPHERE_CENTER, ATMOSPHERE_LIGHT],
+            SEGMENT_DIMS,
+        );
+        check_table(
+            &[
+                PHOTON_WAVELENGTH,
+                PHOTON_TIME,
+                PHOTON_LIGHT_SELECT,
+                PHOTON_POINT,
+                PHOTON_DIR,
+            ],
+            PHOTON_DIMS,
+        );
+        check_table(&[PHOTON_BSDF, PHOTON_ROULETTE], PHOTON_VERTEX_DIMS);
+    }
+
+    #[test]
+    fn vertices_are_disjoint() {
+        assert_eq!(vertex_dim(0, LIGHT_SELECT, 0), CAMERA_DIMS);
+        assert_eq!(vertex_dim(0, BSDF, 1) + 1, vertex_dim(1, LIGHT_SELECT, 0));
+        assert_eq!(
+            segment_dim(0, ATMOSPHERE_LIGHT, 3) + 1,
+            segment_dim(1, ATMOSPHERE_DISTANCE, 0)
+        );
+        assert!(segment_dim(100, ATMOSPHERE_LIGHT, 3) < roulette_dim(0));
+        assert_eq!(photon_vertex_dim(0, PHOTON_BSDF, 0), PHOTON_DIMS);
+        assert_eq!(
+            photon_vertex_dim(0, PHOTON_ROULETTE, 0) + 1,
+            photon_vertex_dim(1, PHOTON_BSDF, 0)
+        );
+    }
+}
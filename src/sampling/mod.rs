@@ -1,7 +1,10 @@
+pub mod blue_noise;
+pub mod dims;
 mod monte_carlo;
 
 pub use self::monte_carlo::{
     cosine_sample_hemisphere, spherical_triangle_solid_angle, square_to_circle,
     triangle_surface_area, uniform_sample_cone, uniform_sample_cone_pdf, uniform_sample_hemisphere,
     uniform_sample_sphere, uniform_sample_spherical_triangle, uniform_sample_triangle,
+    SphericalRectangle,
 };
@@ -198,3 +198,116 @@ pub fn uniform_sample_spherical_triangle(
 
     (vb * z) + ((vc_2 - (vb * dot(vc_2, vb))).normalized() * (1.0 - (z * z)).sqrt())
 }
+
+/// A rectangle as seen from a point, set up for uniformly sampling the
+/// solid angle it subtends.
+///
+/// This is from the paper "An Area-Preserving Parametrization for
+/// Spherical Rectangles" by Ureña et al.  Unlike splitting the rectangle
+/// into two spherical triangles, every sample is a single cheap warp of
+/// the unit square, which stratifies well over the whole rectangle.
+#[derive(Debug, Copy, Clone)]
+pub struct SphericalRectangle {
+    x: Vector, // Local frame, with z pointing away from the rectangle
+    y: Vector,
+    z: Vector,
+    x0: f32, // Rectangle's extent in the local frame
+    x1: f32,
+    y0: f32,
+    y1: f32,
+    z0: f32,
+    b0: f32,
+    b1: f32,
+    k: f32,
+    solid_angle: f32,
+}
+
+impl SphericalRectangle {
+    /// `corner` is one corner of the rectangle relative to the point it's
+    /// seen from, and `ex` and `ey` are its perpendicular edges leaving
+    /// that corner.
+    pub fn new(corner: Vector, ex: Vector, ey: Vector) -> SphericalRectangle {
+        let ex_len = ex.length();
+        let ey_len = ey.length();
+        let x = ex / ex_len;
+        let y = ey / ey_len;
+        let mut z = cross(x, y);
+
+        let mut z0 = dot(corner, z);
+        if z0 > 0.0 {
+            z = -z;
+            z0 = -z0;
+        }
+        let x0 = dot(corner, x);
+        let y0 = dot(corner, y);
+        let x1 = x0 + ex_len;
+        let y1 = y0 + ey_len;
+
+        // Normals of the planes through the point and each edge.
+        let v00 = Vector::new(x0, y0, z0);
+        let v01 = Vector::new(x0, y1, z0);
+        let v10 = Vector::new(x1, y0, z0);
+        let v11 = Vector::new(x1, y1, z0);
+        let n0 = cross(v00, v10).normalized();
+        let n1 = cross(v10, v11).normalized();
+        let n2 = cross(v11, v01).normalized();
+        let n3 = cross(v01, v00).normalized();
+
+        // The rectangle's internal angles, and from them its solid angle.
+        let angle = |a: Vector, b: Vector| (-dot(a, b)).max(-1.0).min(1.0).acos();
+        let g0 = angle(n0, n1);
+        let g1 = angle(n1, n2);
+        let g2 = angle(n2, n3);
+        let g3 = angle(n3, n0);
+        let k = (2.0 * PI_32) - g2 - g3;
+
+        SphericalRectangle {
+            x: x,
+            y: y,
+            z: z,
+            x0: x0,
+            x1: x1,
+            y0: y0,
+            y1: y1,
+            z0: z0,
+            b0: n0.z(),
+            b1: n2.z(),
+            k: k,
+            solid_angle: (g0 + g1 - k).max(0.0),
+        }
+    }
+
+    pub fn solid_angle(&self) -> f32 {
+        self.solid_angle
+    }
+
+    /// Samples a point on the rectangle uniformly by solid angle, given two
+    /// uniform random variables in [0, 1].  The point is relative to the
+    /// point the rectangle is seen from.
+    pub fn sample(&self, u: f32, v: f32) -> Vector {
+        // Choose the x coordinate.
+        let au = (u * self.solid_angle) + self.k;
+        let fu = ((au.cos() * self.b0) - self.b1) / au.sin();
+        let cu = (1.0 / ((fu * fu) + (self.b0 * self.b0)).sqrt())
+            .copysign(fu)
+            .max(-1.0)
+            .min(1.0);
+        let xu = (-(cu * self.z0) / (1.0 - (cu * cu)).max(0.0).sqrt())
+            .max(self.x0)
+            .min(self.x1);
+
+        // Choose the y coordinate.
+        let d = ((xu * xu) + (self.z0 * self.z0)).sqrt();
+        let h0 = self.y0 / ((d * d) + (self.y0 * self.y0)).sqrt();
+        let h1 = self.y1 / ((d * d) + (self.y1 * self.y1)).sqrt();
+        let hv = h0 + (v * (h1 - h0));
+        let hv2 = hv * hv;
+        let yv = if hv2 < (1.0 - 1.0e-6) {
+            ((hv * d) / (1.0 - hv2).sqrt()).max(self.y0).min(self.y1)
+        } else {
+            self.y1
+        };
+
+        (self.x * xu) + (self.y * yv) + (self.z * self.z0)
+    }
+}
@@ -10,17 +10,19 @@ use crate::{
     color::SpectralSample,
     lerp::lerp_slice,
     light::SurfaceLight,
-    math::{Matrix4x4, Normal, Point},
-    shading::SurfaceShader,
+    math::{Matrix4x4, Normal, Point, Vector},
+    shading::{spaces::NamedSpace, SurfaceShader},
     surface::{Surface, SurfaceIntersection},
     transform_stack::TransformStack,
 };
 
+use super::instance_array::InstanceArray;
+
 #[derive(Copy, Clone, Debug)]
 pub struct Assembly<'a> {
     // Instance list
     pub instances: &'a [Instance],
-    pub light_instances: &'a [Instance],
+    pub light_instances: &'a [u32], // Indices into `instances` of lights and assemblies with lights
     pub xforms: &'a [Matrix4x4],
 
     // Surface shader list
@@ -28,15 +30,26 @@ pub struct Assembly<'a> {
 
     // Object list
     pub objects: &'a [Object<'a>],
+    pub object_group_shaders: &'a [&'a [&'a dyn SurfaceShader]], // See `Surface::shader_groups()`
 
     // Assembly list
     pub assemblies: &'a [Assembly<'a>],
 
+    // Instance array list
+    pub instance_arrays: &'a [InstanceArray<'a>],
+
     // Object accel
     pub object_accel: BVH4<'a>,
 
     // Light accel
     pub light_accel: LightTree<'a>,
+
+    // Names of the objects and assemblies, for reporting
+    pub object_names: &'a [&'a str],
+    pub assembly_names: &'a [&'a str],
+
+    // Named coordinate spaces, for shaders
+    pub spaces: &'a [NamedSpace<'a>],
 }
 
 // TODO: actually fix this clippy warning, rather than `allow`ing it.
@@ -51,17 +64,13 @@ impl<'a> Assembly<'a> {
         wavelength: f32,
         time: f32,
         intr: &SurfaceIntersection,
-    ) -> Option<(SpectralSample, (Point, Normal, f32), f32, f32)> {
+    ) -> Option<(SpectralSample, (Point, Normal, f32), f32, f32, bool)> {
         if let SurfaceIntersection::Hit {
             intersection_data: idata,
             closure,
         } = *intr
         {
-            let sel_xform = if !xform_stack.top().is_empty() {
-                lerp_slice(xform_stack.top(), time)
-            } else {
-                Matrix4x4::new()
-            };
+            let sel_xform = xform_stack.top_at(time);
             if let Some((light_i, sel_pdf, whittled_n)) = self.light_accel.select(
                 idata.incoming * sel_xform,
                 idata.pos * sel_xform,
@@ -71,40 +80,26 @@ impl<'a> Assembly<'a> {
                 time,
                 n,
             ) {
-                let inst = self.light_instances[light_i];
+                let inst = self.instances[self.light_instances[light_i] as usize];
                 match inst.instance_type {
                     InstanceType::Object => {
                         match self.objects[inst.data_index] {
                             Object::SurfaceLight(light) => {
-                                // Get the world-to-object space transform of the light
-                                let xform = if let Some((a, b)) = inst.transform_indices {
-                                    let pxforms = xform_stack.top();
-                                    let xform = lerp_slice(&self.xforms[a..b], time);
-                                    if !pxforms.is_empty() {
-                                        lerp_slice(pxforms, time) * xform
-                                    } else {
-                                        xform
-                                    }
-                                } else {
-                                    let pxforms = xform_stack.top();
-                                    if !pxforms.is_empty() {
-                                        lerp_slice(pxforms, time)
-                                    } else {
-                                        Matrix4x4::new()
-                                    }
-                                };
+                                let xform = self.light_xform(&inst, xform_stack, time);
 
                                 // Sample the light
                                 let (color, sample_geo, pdf) = light.sample_from_point(
                                     &xform, idata.pos, uvw.0, uvw.1, wavelength, time,
                                 );
-                                return Some((color, sample_geo, pdf, sel_pdf));
+                                return Some((color, sample_geo, pdf, sel_pdf, light.is_delta()));
                             }
 
                             _ => unimplemented!(),
                         }
                     }
 
+                    InstanceType::Array => unreachable!("Instance arrays can't be lights."),
+
                     InstanceType::Assembly => {
                         // Push the world-to-object space transforms of the assembly onto
                         // the transform stack.
@@ -128,7 +123,9 @@ impl<'a> Assembly<'a> {
                         }
 
                         // Return sample
-                        return sample.map(|(ss, v, pdf, spdf)| (ss, v, pdf, spdf * sel_pdf));
+                        return sample.map(|(ss, v, pdf, spdf, is_delta)| {
+                            (ss, v, pdf, spdf * sel_pdf, is_delta)
+                        });
                     }
                 }
             } else {
@@ -138,6 +135,70 @@ impl<'a> Assembly<'a> {
             None
         }
     }
+
+    /// Selects a light in proportion to its power and samples light
+    /// leaving it, for tracing paths from the lights.
+    ///
+    /// Returns (flux, (sample_point, normal, point_err), direction,
+    /// selection_pdf)
+    pub fn sample_emission(
+        &self,
+        xform_stack: &mut TransformStack,
+        n: f32,
+        uv: (f32, f32),
+        dir_uv: (f32, f32),
+        wavelength: f32,
+        time: f32,
+    ) -> Option<(SpectralSample, (Point, Normal, f32), Vector, f32)> {
+        let (light_i, sel_pdf, whittled_n) = self.light_accel.select_by_energy(n)?;
+        let inst = self.instances[self.light_instances[light_i] as usize];
+        match inst.instance_type {
+            InstanceType::Object => match self.objects[inst.data_index] {
+                Object::SurfaceLight(light) => {
+                    let xform = self.light_xform(&inst, xform_stack, time);
+                    let (flux, sample_geo, dir) =
+                        light.sample_emission(&xform, uv, dir_uv, wavelength, time);
+                    Some((flux, sample_geo, dir, sel_pdf))
+                }
+
+                _ => unreachable!("Only surface lights are in the light instances."),
+            },
+
+            InstanceType::Array => unreachable!("Instance arrays can't be lights."),
+
+            InstanceType::Assembly => {
+                if let Some((a, b)) = inst.transform_indices {
+                    xform_stack.push(&self.xforms[a..b]);
+                }
+
+                let sample = self.assemblies[inst.data_index].sample_emission(
+                    xform_stack,
+                    whittled_n,
+                    uv,
+                    dir_uv,
+                    wavelength,
+                    time,
+                );
+
+                if inst.transform_indices.is_some() {
+                    xform_stack.pop();
+                }
+
+                sample.map(|(flux, geo, dir, spdf)| (flux, geo, dir, spdf * sel_pdf))
+            }
+        }
+    }
+
+    /// Returns the world-to-object space transform of a light instance at
+    /// `time`, given the transforms of the assemblies it's nested in.
+    fn light_xform(&self, inst: &Instance, xform_stack: &TransformStack, time: f32) -> Matrix4x4 {
+        let pxform = xform_stack.top_at(time);
+        if let Some((a, b)) = inst.transform_indices {
+            pxform * lerp_slice(&self.xforms[a..b], time)
+        } else {
+            pxform
+        }
+    }
 }
 
 impl<'a> Boundable for Assembly<'a> {
@@ -165,6 +226,12 @@ pub struct AssemblyBuilder<'a> {
     // Assembly list
     assemblies: Vec<Assembly<'a>>,
     assembly_map: HashMap<String, usize>, // map Name -> Index
+
+    // Instance array list
+    instance_arrays: Vec<InstanceArray<'a>>,
+
+    // Named coordinate spaces
+    spaces: Vec<NamedSpace<'a>>,
 }
 
 impl<'a> AssemblyBuilder<'a> {
@@ -179,6 +246,8 @@ impl<'a> AssemblyBuilder<'a> {
             object_map: HashMap::new(),
             assemblies: Vec::new(),
             assembly_map: HashMap::new(),
+            instance_arrays: Vec::new(),
+            spaces: Vec::new(),
         }
     }
 
@@ -220,10 +289,38 @@ impl<'a> AssemblyBuilder<'a> {
         self.assemblies.push(asmb);
     }
 
+    /// Adds a named coordinate space, given its assembly-to-space
+    /// transform samples.
+    ///
+    /// Spaces have their own names, separate from objects and assemblies,
+    /// and can't be named "world" or "object", which are built in.
+    pub fn add_space(&mut self, name: &str, xforms: &[Matrix4x4]) {
+        // Make sure the name hasn't already been used.
+        if self.space_exists(name) {
+            panic!("Attempted to add space to assembly with a name that already exists.");
+        }
+        assert!(!xforms.is_empty());
+
+        self.spaces.push(NamedSpace {
+            name: self.arena.copy_str(name),
+            xforms: self.arena.copy_slice(xforms),
+        });
+    }
+
+    /// Adds an instance of the named object or assembly.
+    ///
+    /// `surface_shader_name` binds a shader to an instanced object.
+    /// `surface_shader_override` replaces the shaders of every surface
+    /// under the instance, including those in nested assemblies, taking
+    /// precedence over their own bindings and over overrides further down.
+    /// Only the bounds of object instances are padded by the override's
+    /// displacement bound, though: an instanced assembly's BVH is already
+    /// built, so it can't be padded for one instance of it.
     pub fn add_instance(
         &mut self,
         name: &str,
         surface_shader_name: Option<&str>,
+        surface_shader_override: Option<&str>,
         xforms: Option<&[Matrix4x4]>,
     ) {
         // Make sure name exists
@@ -242,17 +339,21 @@ impl<'a> AssemblyBuilder<'a> {
             None
         };
 
+        let shader_index = |name: &str| {
+            *self
+                .surface_shader_map
+                .get(name)
+                .unwrap_or_else(|| panic!("Unknown surface shader '{}'.", name))
+        };
+
         // Create instance
         let instance = if self.object_map.contains_key(name) {
             Instance {
                 instance_type: InstanceType::Object,
                 data_index: self.object_map[name],
-                surface_shader_index: surface_shader_name.map(|name| {
-                    *self
-                        .surface_shader_map
-                        .get(name)
-                        .unwrap_or_else(|| panic!("Unknown surface shader '{}'.", name))
-                }),
+                surface_shader_index: surface_shader_name.map(shader_index),
+                surface_shader_override_index: surface_shader_override.map(shader_index),
+                proxy_index: None,
                 id: self.instances.len(),
                 transform_indices: xforms
                     .map(|xf| (self.xforms.len(), self.xforms.len() + xf.len())),
@@ -261,12 +362,9 @@ impl<'a> AssemblyBuilder<'a> {
             Instance {
                 instance_type: InstanceType::Assembly,
                 data_index: self.assembly_map[name],
-                surface_shader_index: surface_shader_name.map(|name| {
-                    *self
-                        .surface_shader_map
-                        .get(name)
-                        .unwrap_or_else(|| panic!("Unknown surface shader '{}'.", name))
-                }),
+                surface_shader_index: surface_shader_name.map(shader_index),
+                surface_shader_override_index: surface_shader_override.map(shader_index),
+                proxy_index: None,
                 id: self.instances.len(),
                 transform_indices: xforms
                     .map(|xf| (self.xforms.len(), self.xforms.len() + xf.len())),
@@ -281,10 +379,97 @@ impl<'a> AssemblyBuilder<'a> {
         }
     }
 
+    /// Adds an array of instances of the named surface, one for each group
+    /// of `sample_count` transform samples in `xforms`.
+    ///
+    /// The whole array is a single instance in the assembly, with its own
+    /// BVH over its elements, so that huge numbers of copies are cheap.
+    /// `surface_shader_name` binds a shader to all of them.
+    pub fn add_instance_array(
+        &mut self,
+        name: &str,
+        surface_shader_name: Option<&str>,
+        xforms: &[Matrix4x4],
+        sample_count: usize,
+    ) {
+        if !self.is_surface(name) {
+            panic!("Attempted to add instance array of something that isn't a surface.");
+        }
+        let object_index = self.object_map[name];
+        let surface_shader_index = surface_shader_name.map(|name| {
+            *self
+                .surface_shader_map
+                .get(name)
+                .unwrap_or_else(|| panic!("Unknown surface shader '{}'.", name))
+        });
+
+        let bounds = self.surface_bounds(object_index, surface_shader_index, None);
+        self.instances.push(Instance {
+            instance_type: InstanceType::Array,
+            data_index: self.instance_arrays.len(),
+            surface_shader_index: surface_shader_index,
+            surface_shader_override_index: None,
+            proxy_index: None,
+            id: self.instances.len(),
+            transform_indices: None,
+        });
+        self.instance_arrays.push(InstanceArray::new(
+            self.arena,
+            object_index,
+            &bounds,
+            xforms,
+            sample_count,
+        ));
+    }
+
+    /// Gives the most recently added instance, which must be of a surface,
+    /// the surface `proxy_name` as proxy geometry.  Rays that see proxies
+    /// (see `RayBatch::mark_proxy()`) hit the proxy in its place.
+    pub fn set_instance_proxy(&mut self, proxy_name: &str) {
+        let proxy_index = match self.object_map.get(proxy_name) {
+            Some(&i) if matches!(self.objects[i], Object::Surface(_)) => i,
+            _ => panic!("Unknown surface '{}' for proxy.", proxy_name),
+        };
+        let inst = self
+            .instances
+            .last_mut()
+            .expect("Attempted to set the proxy of an instance before adding one.");
+        match (inst.instance_type, self.objects.get(inst.data_index)) {
+            (InstanceType::Object, Some(Object::Surface(_))) => {}
+            _ => panic!("Only instances of surfaces can have proxies."),
+        }
+        inst.proxy_index = Some(proxy_index);
+    }
+
+    /// Whether `name` is a surface object, which can be used as a proxy.
+    pub fn is_surface(&self, name: &str) -> bool {
+        match self.object_map.get(name) {
+            Some(&i) => matches!(self.objects[i], Object::Surface(_)),
+            None => false,
+        }
+    }
+
     pub fn name_exists(&self, name: &str) -> bool {
         self.object_map.contains_key(name) || self.assembly_map.contains_key(name)
     }
 
+    pub fn surface_shader_exists(&self, name: &str) -> bool {
+        self.surface_shader_map.contains_key(name)
+    }
+
+    /// Whether `name` is a space, including the built-in ones.
+    pub fn space_exists(&self, name: &str) -> bool {
+        name == "world" || name == "object" || self.spaces.iter().any(|space| space.name == name)
+    }
+
+    /// Whether `name` is a light object.
+    pub fn is_light(&self, name: &str) -> bool {
+        match self.object_map.get(name) {
+            Some(&i) => matches!(self.objects[i], Object::SurfaceLight(_)),
+            None => false,
+        }
+    }
+
     pub fn build(mut self) -> Assembly<'a> {
         // Calculate instance bounds, used for building object accel and light accel.
         let (bis, bbs) = self.instance_bounds();
@@ -295,11 +480,13 @@ impl<'a> AssemblyBuilder<'a> {
         });
 
         // Get list of instances that are for light sources or assemblies that contain light
-        // sources.
-        let mut light_instances: Vec<_> = self
+        // sources.  These are stored as indices into the instances, rather than
+        // as copies of them, since scenes can have a great many lights.
+        let mut light_instances: Vec<u32> = self
             .instances
             .iter()
-            .filter(|inst| match inst.instance_type {
+            .enumerate()
+            .filter(|(_, inst)| match inst.instance_type {
                 InstanceType::Object => {
                     if let Object::SurfaceLight(_) = self.objects[inst.data_index] {
                         true
@@ -314,12 +501,15 @@ impl<'a> AssemblyBuilder<'a> {
                         .approximate_energy()
                         > 0.0
                 }
+
+                InstanceType::Array => false,
             })
-            .cloned()
+            .map(|(i, _)| i as u32)
             .collect();
 
         // Build light accel
-        let light_accel = LightTree::from_objects(self.arena, &mut light_instances[..], |inst| {
+        let light_accel = LightTree::from_objects(self.arena, &mut light_instances[..], |&i| {
+            let inst = &self.instances[i as usize];
             let bounds = &bbs[bis[inst.id]..bis[inst.id + 1]];
             let energy = match inst.instance_type {
                 InstanceType::Object => {
@@ -333,19 +523,96 @@ impl<'a> AssemblyBuilder<'a> {
                 InstanceType::Assembly => self.assemblies[inst.data_index]
                     .light_accel
                     .approximate_energy(),
+
+                InstanceType::Array => 0.0,
             };
             (bounds, energy)
         });
 
+        // Names, in the same order as the objects and assemblies.
+        let names = |map: &HashMap<String, usize>, count: usize| {
+            let mut names = vec![""; count];
+            for (name, &i) in map {
+                names[i] = &*self.arena.copy_str(name);
+            }
+            names
+        };
+        let object_names = names(&self.object_map, self.objects.len());
+        let assembly_names = names(&self.assembly_map, self.assemblies.len());
+
+        // The shaders bound to groups of each object's primitives.
+        let object_group_shaders: Vec<&[&dyn SurfaceShader]> = self
+            .objects
+            .iter()
+            .map(|obj| &*self.arena.copy_slice(&self.group_shaders(obj)))
+            .collect();
+
         Assembly {
             instances: self.arena.copy_slice(&self.instances),
             light_instances: self.arena.copy_slice(&light_instances),
             xforms: self.arena.copy_slice(&self.xforms),
             surface_shaders: self.arena.copy_slice(&self.surface_shaders),
             objects: self.arena.copy_slice(&self.objects),
+            object_group_shaders: self.arena.copy_slice(&object_group_shaders),
             assemblies: self.arena.copy_slice(&self.assemblies),
+            instance_arrays: self.arena.copy_slice(&self.instance_arrays),
             object_accel: object_accel,
             light_accel: light_accel,
+            object_names: self.arena.copy_slice(&object_names),
+            assembly_names: self.arena.copy_slice(&assembly_names),
+            spaces: self.arena.copy_slice(&self.spaces),
+        }
+    }
+
+    /// The shaders bound to groups of the object's primitives, in the
+    /// order of `Surface::shader_groups()`.
+    fn group_shaders(&self, obj: &Object<'a>) -> Vec<&'a dyn SurfaceShader> {
+        let names = match *obj {
+            Object::Surface(surface) => surface.shader_groups(),
+            Object::SurfaceLight(_) => &[],
+        };
+        names
+            .iter()
+            .map(|name| {
+                let i = *self
+                    .surface_shader_map
+                    .get(*name)
+                    .unwrap_or_else(|| panic!("Unknown surface shader '{}'.", name));
+                self.surface_shaders[i]
+            })
+            .collect()
+    }
+
+    /// The bounds of a surface object with the given shaders bound to it.
+    ///
+    /// They're padded by how far the shaders may displace the surface, so
+    /// that the displaced surface stays inside them.  The leaves of the
+    /// surface's own BVH aren't padded, since it's built before it's known
+    /// which shaders the surface is bound to.
+    fn surface_bounds(
+        &self,
+        object_index: usize,
+        surface_shader_index: Option<usize>,
+        surface_shader_override_index: Option<usize>,
+    ) -> Vec<BBox> {
+        let obj = &self.objects[object_index];
+        let surface = match *obj {
+            Object::Surface(s) => s,
+            Object::SurfaceLight(_) => panic!("Object isn't a surface."),
+        };
+        let pad = match surface_shader_override_index {
+            Some(i) => self.surface_shaders[i].displacement_bound(),
+            None => self
+                .group_shaders(obj)
+                .iter()
+                .map(|shader| shader.displacement_bound())
+                .chain(surface_shader_index.map(|i| self.surface_shaders[i].displacement_bound()))
+                .fold(0.0, f32::max),
+        };
+        if pad > 0.0 {
+            surface.bounds().iter().map(|b| b.expanded(pad)).collect()
+        } else {
+            surface.bounds().to_vec()
         }
     }
 
@@ -363,11 +630,33 @@ impl<'a> AssemblyBuilder<'a> {
             match inst.instance_type {
                 InstanceType::Object => {
                     // Push bounds onto bbs
-                    let obj = &self.objects[inst.data_index];
-                    match *obj {
-                        Object::Surface(s) => bbs.extend(s.bounds()),
+                    match self.objects[inst.data_index] {
+                        Object::Surface(_) => bbs.extend(self.surface_bounds(
+                            inst.data_index,
+                            inst.surface_shader_index,
+                            inst.surface_shader_override_index,
+                        )),
                         Object::SurfaceLight(l) => bbs.extend(l.bounds()),
                     }
+
+                    // Rays may hit the proxy instead, so the bounds must
+                    // hold both.
+                    if let Some(Object::Surface(proxy)) = inst.proxy_index.map(|i| self.objects[i])
+                    {
+                        let proxy_bounds = proxy.bounds();
+                        if proxy_bounds.len() == bbs.len() {
+                            for (bb, proxy_bb) in bbs.iter_mut().zip(proxy_bounds.iter()) {
+                                *bb = *bb | *proxy_bb;
+                            }
+                        } else {
+                            let all = bbs
+                                .iter()
+                                .chain(proxy_bounds.iter())
+                                .fold(BBox::new(), |all, bb| all | *bb);
+                            bbs.clear();
+                            bbs.push(all);
+                        }
+                    }
                 }
 
                 InstanceType::Assembly => {
@@ -375,6 +664,10 @@ impl<'a> AssemblyBuilder<'a> {
                     let asmb = &self.assemblies[inst.data_index];
                     bbs.extend(asmb.bounds());
                 }
+
+                InstanceType::Array => {
+                    bbs.extend(self.instance_arrays[inst.data_index].bounds());
+                }
             }
 
             // Transform the bounding boxes, if necessary
@@ -406,6 +699,8 @@ pub struct Instance {
     pub instance_type: InstanceType,
     pub data_index: usize,
     pub surface_shader_index: Option<usize>,
+    pub surface_shader_override_index: Option<usize>,
+    pub proxy_index: Option<usize>, // Object seen in place of this one by some rays
     pub id: usize,
     pub transform_indices: Option<(usize, usize)>,
 }
@@ -414,4 +709,311 @@ pub struct Instance {
 pub enum InstanceType {
     Object,
     Assembly,
+    Array, // Of an object, in `Assembly::instance_arrays`
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color::Color,
+        light::{LightUnits, PointLight},
+        ray::{Ray, RayBatch},
+        shading::{surface_closure::SurfaceClosure, DisplacedSurfaceShader, SimpleSurfaceShader},
+        surface::{triangle_mesh::TriangleMesh, SurfaceIntersectionData},
+        tracer::Tracer,
+    };
+
+    /// A square on the XY plane at height `z`.
+    fn square_at<'a>(arena: &'a Arena, z: f32) -> &'a TriangleMesh<'a> {
+        arena.alloc(TriangleMesh::from_verts_and_indices(
+            arena,
+            &[vec![
+                Point::new(-1.0, -1.0, z),
+                Point::new(1.0, -1.0, z),
+                Point::new(-1.0, 1.0, z),
+                Point::new(1.0, 1.0, z),
+            ]],
+            &None,
+            &[(0, 1, 2), (1, 3, 2)],
+        ))
+    }
+
+    #[test]
+    fn proxies_are_hit_by_proxy_rays() {
+        let arena = Arena::new();
+        let mut builder = AssemblyBuilder::new(&arena);
+        builder.add_object("$full", Object::Surface(square_at(&arena, 0.0)));
+        builder.add_object("$proxy", Object::Surface(square_at(&arena, 1.0)));
+        builder.add_instance("$full", None, None, None);
+        builder.set_instance_proxy("$proxy");
+        let assembly = builder.build();
+
+        // The bounds hold both the object and its proxy.
+        let bounds = assembly.bounds()[0];
+        assert_eq!(bounds.min.z(), 0.0);
+        assert_eq!(bounds.max.z(), 1.0);
+
+        let mut rays = RayBatch::new();
+        for i in 0..4 {
+            rays.push(
+                Ray {
+                    orig: Point::new(0.1 * i as f32, 0.0, 5.0),
+                    dir: Vector::new(0.0, 0.0, -1.0),
+                    time: 0.5,
+                    wavelength: 550.0,
+                    max_t: std::f32::INFINITY,
+                },
+                false,
+            );
+            if i % 2 == 1 {
+                rays.mark_proxy(i);
+            }
+        }
+
+        let mut tracer = Tracer::from_assembly(&assembly);
+        let isects = tracer.trace(&mut rays);
+        for (i, isect) in isects.iter().enumerate() {
+            if let SurfaceIntersection::Hit {
+                intersection_data: idata,
+                ..
+            } = *isect
+            {
+                let expected_t = if i % 2 == 1 { 4.0 } else { 5.0 };
+                assert!((idata.t - expected_t).abs() < 0.0001);
+            } else {
+                panic!("ray {} missed", i);
+            }
+        }
+    }
+
+    #[test]
+    fn instance_array_elements_are_hit() {
+        let arena = Arena::new();
+        let mut builder = AssemblyBuilder::new(&arena);
+        builder.add_object("$square", Object::Surface(square_at(&arena, 0.0)));
+        let xforms: Vec<_> = [-3.0, 0.0, 3.0]
+            .iter()
+            .map(|&x| Matrix4x4::from_location(Point::new(x, 0.0, 0.0)))
+            .collect();
+        builder.add_instance_array("$square", None, &xforms, 1);
+        let assembly = builder.build();
+        assert_eq!(assembly.instances.len(), 1);
+
+        let mut rays = RayBatch::new();
+        for &x in &[-3.0, 0.0, 3.0, 1.5] {
+            rays.push(
+                Ray {
+                    orig: Point::new(x, 0.5, 5.0),
+                    dir: Vector::new(0.0, 0.0, -1.0),
+                    time: 0.5,
+                    wavelength: 550.0,
+                    max_t: std::f32::INFINITY,
+                },
+                false,
+            );
+        }
+
+        // Each element is hit as its own instance of the object, and the
+        // gaps between them are missed.
+        let mut tracer = Tracer::from_assembly(&assembly);
+        let isects = tracer.trace(&mut rays);
+        let mut ids = Vec::new();
+        for isect in &isects[..3] {
+            if let SurfaceIntersection::Hit {
+                intersection_data: idata,
+                ..
+            } = *isect
+            {
+                assert!((idata.t - 5.0).abs() < 0.0001);
+                ids.push((idata.object_id, idata.instance_id));
+            } else {
+                panic!("element missed");
+            }
+        }
+        assert!(ids.iter().all(|id| id.0 == ids[0].0));
+        assert!(ids[0].1 != ids[1].1 && ids[1].1 != ids[2].1 && ids[0].1 != ids[2].1);
+        assert!(matches!(isects[3], SurfaceIntersection::Miss));
+    }
+
+    #[test]
+    fn displacement_bound_pads_instance_bounds() {
+        let arena = Arena::new();
+        let mesh = arena.alloc(TriangleMesh::from_verts_and_indices(
+            &arena,
+            &[vec![
+                Point::new(0.0, 0.0, 0.0),
+                Point::new(1.0, 0.0, 0.0),
+                Point::new(0.0, 1.0, 0.0),
+            ]],
+            &None,
+            &[(0, 1, 2)],
+        ));
+        let shader = arena.alloc(DisplacedSurfaceShader {
+            shader: SimpleSurfaceShader::Lambert {
+                color: Color::new_xyz((0.5, 0.5, 0.5)),
+            },
+            displacement_bound: 0.25,
+        });
+
+        let mut builder = AssemblyBuilder::new(&arena);
+        builder.add_surface_shader("$shader", shader);
+        builder.add_object("$mesh", Object::Surface(mesh));
+        builder.add_instance("$mesh", Some("$shader"), None, None);
+        let assembly = builder.build();
+
+        let bounds = assembly.bounds()[0];
+        assert_eq!(bounds.min.x(), -0.25);
+        assert_eq!(bounds.min.z(), -0.25);
+        assert_eq!(bounds.max.y(), 1.25);
+        assert_eq!(bounds.max.z(), 0.25);
+    }
+
+    #[test]
+    fn shader_override_takes_precedence() {
+        let arena = Arena::new();
+        let mesh = arena.alloc(TriangleMesh::from_verts_and_indices(
+            &arena,
+            &[vec![
+                Point::new(0.0, 0.0, 0.0),
+                Point::new(1.0, 0.0, 0.0),
+                Point::new(0.0, 1.0, 0.0),
+            ]],
+            &None,
+            &[(0, 1, 2)],
+        ));
+        let lambert = SimpleSurfaceShader::Lambert {
+            color: Color::new_xyz((0.5, 0.5, 0.5)),
+        };
+        let displaced = arena.alloc(DisplacedSurfaceShader {
+            shader: lambert,
+            displacement_bound: 0.25,
+        });
+
+        let mut builder = AssemblyBuilder::new(&arena);
+        builder.add_surface_shader("$plain", arena.alloc(lambert));
+        builder.add_surface_shader("$displaced", displaced);
+        builder.add_object("$mesh", Object::Surface(mesh));
+        builder.add_instance("$mesh", Some("$plain"), Some("$displaced"), None);
+        let assembly = builder.build();
+
+        let inst = assembly.instances[0];
+        assert_eq!(inst.surface_shader_index, Some(0));
+        assert_eq!(inst.surface_shader_override_index, Some(1));
+
+        // The overriding shader's displacement bound is the one that counts.
+        assert_eq!(assembly.bounds()[0].min.z(), -0.25);
+    }
+
+    #[test]
+    fn face_group_shaders() {
+        let arena = Arena::new();
+        let mesh = arena.alloc(
+            TriangleMesh::from_verts_and_indices(
+                &arena,
+                &[vec![
+                    Point::new(0.0, 0.0, 0.0),
+                    Point::new(1.0, 0.0, 0.0),
+                    Point::new(0.0, 1.0, 0.0),
+                    Point::new(1.0, 1.0, 0.0),
+                ]],
+                &None,
+                &[(0, 1, 2), (1, 3, 2)],
+            )
+            .with_face_groups(&arena, &[None, Some(0)], &["$displaced"]),
+        );
+        let lambert = SimpleSurfaceShader::Lambert {
+            color: Color::new_xyz((0.5, 0.5, 0.5)),
+        };
+        let displaced = arena.alloc(DisplacedSurfaceShader {
+            shader: lambert,
+            displacement_bound: 0.25,
+        });
+
+        let mut builder = AssemblyBuilder::new(&arena);
+        builder.add_surface_shader("$plain", arena.alloc(lambert));
+        builder.add_surface_shader("$displaced", displaced);
+        builder.add_object("$mesh", Object::Surface(mesh));
+        builder.add_instance("$mesh", Some("$plain"), None, None);
+        let assembly = builder.build();
+
+        // The group's shader is found by name, and its displacement bound
+        // pads the instance's bounds along with the instance's shader.
+        assert_eq!(assembly.object_group_shaders[0].len(), 1);
+        assert_eq!(
+            assembly.object_group_shaders[0][0].displacement_bound(),
+            0.25
+        );
+        assert_eq!(assembly.bounds()[0].min.z(), -0.25);
+    }
+
+    #[test]
+    fn lights_in_animated_assemblies() {
+        let arena = Arena::new();
+        let light = arena.alloc(PointLight::new(
+            &arena,
+            &[Color::new_xyz((1.0, 1.0, 1.0))],
+            LightUnits::Normalized,
+        ));
+
+        // A headlight on a car that turns while it drives, with the two
+        // animated with different numbers of time samples.
+        let car_xforms = [
+            Matrix4x4::new(),
+            Matrix4x4::new_from_values(
+                0.0, -1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+            ),
+        ];
+        let light_xforms = [
+            Matrix4x4::from_location(Point::new(-2.0, 0.0, -1.0)),
+            Matrix4x4::from_location(Point::new(-6.0, 0.0, -1.0)),
+            Matrix4x4::from_location(Point::new(-6.0, -4.0, -1.0)),
+        ];
+        let mut car = AssemblyBuilder::new(&arena);
+        car.add_object("$headlight", Object::SurfaceLight(light));
+        car.add_instance("$headlight", None, None, Some(&light_xforms));
+        let mut root = AssemblyBuilder::new(&arena);
+        root.add_assembly("$car", car.build());
+        root.add_instance("$car", None, None, Some(&car_xforms));
+        let root = root.build();
+
+        let nor = Normal::new(0.0, 0.0, 1.0);
+        let intr = SurfaceIntersection::Hit {
+            intersection_data: SurfaceIntersectionData {
+                incoming: Vector::new(0.0, 0.0, -1.0),
+                pos: Point::new(0.0, 0.0, 0.0),
+                pos_err: 0.0,
+                nor: nor,
+                nor_g: nor,
+                uv: (0.0, 0.0),
+                dpdu: Vector::new(1.0, 0.0, 0.0),
+                dpdv: Vector::new(0.0, 1.0, 0.0),
+                local_space: Matrix4x4::new(),
+                t: 1.0,
+                footprint: 0.0,
+                sample_pdf: 0.0,
+                object_id: 0,
+                instance_id: 0,
+                instance_index: 0,
+            },
+            closure: SurfaceClosure::Lambert(Color::new_xyz((0.5, 0.5, 0.5))),
+        };
+
+        let mut xform_stack = TransformStack::new();
+        for &time in &[0.0, 0.3, 0.5, 0.8, 1.0] {
+            let expected = Point::new(0.0, 0.0, 0.0)
+                * (lerp_slice(&car_xforms, time) * lerp_slice(&light_xforms, time)).inverse();
+
+            let (_, (pos, _, _), _, sel_pdf, _) = root
+                .sample_lights(&mut xform_stack, 0.5, (0.5, 0.5, 0.5), 550.0, time, &intr)
+                .unwrap();
+            assert_eq!(sel_pdf, 1.0);
+            assert!((pos - expected).length() < 1.0e-4);
+
+            let (_, (pos, _, _), _, _) = root
+                .sample_emission(&mut xform_stack, 0.5, (0.5, 0.5), (0.5, 0.5), 550.0, time)
+                .unwrap();
+            assert!((pos - expected).length() < 1.0e-4);
+        }
+    }
 }
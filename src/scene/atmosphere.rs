@@ -0,0 +1,235 @@
+use std::f32::consts::PI;
+
+use glam::Vec4;
+
+use crate::{
+    bbox::BBox,
+    color::Color,
+    math::{dot, Point, Vector},
+};
+
+/// A uniform fog filling the scene, for depth haze and light shafts.
+///
+/// The fog only scatters light once: paths pick up the light that gets
+/// scattered toward them along each of their rays, but don't change
+/// direction in it themselves.  Light passing through it is attenuated,
+/// along shadow rays as well.  It fills the scene's bounding box, so that
+/// rays leaving the scene, and light from world lights, only pass through
+/// a limited amount of it.
+#[derive(Debug, Copy, Clone)]
+pub struct Atmosphere {
+    pub scattering: Color, // Per unit distance
+    pub absorption: Color, // Per unit distance
+    pub anisotropy: f32,   // Henyey-Greenstein g, from -1 (backward) to 1 (forward)
+    pub bounds: BBox,
+}
+
+impl Atmosphere {
+    /// The scattering and extinction coefficients at the given hero
+    /// wavelength.
+    pub fn coefficients(&self, wavelength: f32) -> (Vec4, Vec4) {
+        let scattering = self.scattering.to_spectral_sample(wavelength).e;
+        let absorption = self.absorption.to_spectral_sample(wavelength).e;
+        (scattering, scattering + absorption)
+    }
+
+    /// The part of a ray up to `max_t` that's inside the atmosphere, as a
+    /// range of distances along the ray.  The distances are in the units
+    /// of the scene, regardless of the length of `dir`.
+    pub fn extent(&self, orig: Point, dir: Vector, max_t: f32) -> Option<(f32, f32)> {
+        let len = dir.length();
+        if len <= 0.0 {
+            return None;
+        }
+        let mut near = 0.0f32;
+        let mut far = max_t * len;
+        for axis in 0..3 {
+            let d = dir.get_n(axis) / len;
+            let o = orig.get_n(axis);
+            let (lo, hi) = (self.bounds.min.get_n(axis), self.bounds.max.get_n(axis));
+            if d == 0.0 {
+                if o < lo || o > hi {
+                    return None;
+                }
+                continue;
+            }
+            let t1 = (lo - o) / d;
+            let t2 = (hi - o) / d;
+            near = near.max(t1.min(t2));
+            far = far.min(t1.max(t2));
+        }
+        if near < far {
+            Some((near, far))
+        } else {
+            None
+        }
+    }
+
+    /// The fraction of light that makes it through the atmosphere along a
+    /// ray, up to `max_t`.
+    pub fn transmittance(&self, orig: Point, dir: Vector, max_t: f32, wavelength: f32) -> Vec4 {
+        if let Some((near, far)) = self.extent(orig, dir, max_t) {
+            self.transmittance_over(far - near, wavelength)
+        } else {
+            Vec4::splat(1.0)
+        }
+    }
+
+    /// The fraction of light that makes it through the given distance
+    /// inside the atmosphere.
+    pub fn transmittance_over(&self, distance: f32, wavelength: f32) -> Vec4 {
+        let (_, extinction) = self.coefficients(wavelength);
+        exp4(extinction * -distance)
+    }
+
+    /// The Henyey-Greenstein phase function, for light scattering from
+    /// one direction into another.  `cos_theta` is the cosine of the angle
+    /// between the direction the light travels in before and after.
+    pub fn phase(&self, cos_theta: f32) -> f32 {
+        let g = self.anisotropy;
+        let denom = 1.0 + (g * g) - (2.0 * g * cos_theta);
+        (1.0 - (g * g)) / (4.0 * PI * denom * denom.sqrt())
+    }
+}
+
+/// Samples a distance in `[near, far)` in proportion to the inverse
+/// squared distance to `center`, which is at `center_dist` along the ray
+/// and `center_offset` away from it.  This is equiangular sampling, which
+/// works well for light from a point near the ray.  `far` may be infinite.
+///
+/// Returns the distance and its pdf.
+pub fn sample_equiangular(
+    near: f32,
+    far: f32,
+    center_dist: f32,
+    center_offset: f32,
+    u: f32,
+) -> (f32, f32) {
+    let theta_near = ((near - center_dist) / center_offset).atan();
+    let theta_far = ((far - center_dist) / center_offset).atan();
+    let theta = theta_near + ((theta_far - theta_near) * u);
+    let dist = (center_dist + (center_offset * theta.tan()))
+        .max(near)
+        .min(far);
+    let d = dist - center_dist;
+    let pdf =
+        center_offset / ((theta_far - theta_near) * ((center_offset * center_offset) + (d * d)));
+    (dist, pdf)
+}
+
+/// Samples a distance in `[near, far)` in proportion to the transmittance
+/// of a medium with the given extinction coefficient.  `far` may be
+/// infinite.
+///
+/// Returns the distance and its pdf.
+pub fn sample_exponential(near: f32, far: f32, extinction: f32, u: f32) -> (f32, f32) {
+    // The fraction of samples that land inside the range, which is less
+    // than one when it's finite.
+    let inside = 1.0 - (-extinction * (far - near)).exp();
+    let d = -(1.0 - (u * inside)).ln() / extinction;
+    let dist = (near + d).min(far);
+    let pdf = extinction * (-extinction * d).exp() / inside;
+    (dist, pdf)
+}
+
+/// Component-wise exponential.
+fn exp4(v: Vec4) -> Vec4 {
+    let v: [f32; 4] = v.into();
+    Vec4::new(v[0].exp(), v[1].exp(), v[2].exp(), v[3].exp())
+}
+
+/// Cosine of the angle between two directions.
+pub fn cos_between(a: Vector, b: Vector) -> f32 {
+    dot(a.normalized(), b.normalized())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fog() -> Atmosphere {
+        Atmosphere {
+            scattering: Color::new_xyz((0.5, 0.5, 0.5)),
+            absorption: Color::new_xyz((0.0, 0.0, 0.0)),
+            anisotropy: 0.6,
+            bounds: BBox::from_points(Point::new(-1.0, -1.0, -1.0), Point::new(1.0, 1.0, 1.0)),
+        }
+    }
+
+    #[test]
+    fn extent_is_clipped_to_bounds() {
+        let fog = fog();
+        let orig = Point::new(-3.0, 0.0, 0.0);
+
+        // The direction's length doesn't matter.
+        let (near, far) = fog
+            .extent(orig, Vector::new(2.0, 0.0, 0.0), f32::INFINITY)
+            .unwrap();
+        assert!((near - 2.0).abs() < 1.0e-5 && (far - 4.0).abs() < 1.0e-5);
+
+        // Stopping inside, missing entirely, and starting inside.
+        let (_, far) = fog.extent(orig, Vector::new(1.0, 0.0, 0.0), 2.5).unwrap();
+        assert!((far - 2.5).abs() < 1.0e-5);
+        assert!(fog.extent(orig, Vector::new(0.0, 1.0, 0.0), 10.0).is_none());
+        let (near, far) = fog
+            .extent(Point::new(0.0, 0.0, 0.0), Vector::new(0.0, 0.0, -1.0), 10.0)
+            .unwrap();
+        assert!(near == 0.0 && (far - 1.0).abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn phase_is_normalized() {
+        // Integrate over the sphere, which only depends on the angle.
+        let fog = fog();
+        let steps = 10000;
+        let mut total = 0.0;
+        for i in 0..steps {
+            let cos_theta = -1.0 + ((i as f32 + 0.5) / steps as f32 * 2.0);
+            total += fog.phase(cos_theta) * 2.0 * PI * (2.0 / steps as f32);
+        }
+        assert!((total - 1.0).abs() < 1.0e-3);
+        assert!(fog.phase(1.0) > fog.phase(-1.0));
+    }
+
+    #[test]
+    fn distance_sampling_pdfs() {
+        // Each pdf should integrate to one over its range, which is
+        // checked by averaging 1/pdf over stratified samples.
+        let n = 20000;
+        let mut lengths = (0.0, 0.0);
+        for i in 0..n {
+            let u = (i as f32 + 0.5) / n as f32;
+            let (d, pdf) = sample_equiangular(0.5, 4.0, 2.0, 0.3, u);
+            assert!((0.5..=4.0).contains(&d));
+            lengths.0 += 1.0 / pdf / n as f32;
+            let (d, pdf) = sample_exponential(0.5, 4.0, 0.7, u);
+            assert!((0.5..=4.0).contains(&d));
+            lengths.1 += 1.0 / pdf / n as f32;
+        }
+        assert!((lengths.0 - 3.5).abs() < 0.01, "{}", lengths.0);
+        assert!((lengths.1 - 3.5).abs() < 0.01, "{}", lengths.1);
+
+        // Unbounded, the mean of exponential sampling is the mean free
+        // path.
+        let mean = (0..n)
+            .map(|i| sample_exponential(1.0, f32::INFINITY, 0.7, (i as f32 + 0.5) / n as f32).0)
+            .sum::<f32>()
+            / n as f32;
+        assert!((mean - (1.0 + (1.0 / 0.7))).abs() < 0.01, "{}", mean);
+    }
+
+    #[test]
+    fn transmittance_through_box() {
+        let fog = fog();
+        let t = fog.transmittance(
+            Point::new(-3.0, 0.0, 0.0),
+            Vector::new(1.0, 0.0, 0.0),
+            f32::INFINITY,
+            550.0,
+        );
+        let (_, extinction) = fog.coefficients(550.0);
+        let expected = exp4(extinction * -2.0);
+        assert!(crate::math::max_element((t - expected).abs()) < 1.0e-5);
+        assert!(crate::math::max_element(t) < 1.0);
+    }
+}
@@ -0,0 +1,309 @@
+//! A summary of a built scene, for checking exports without rendering.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::accel::BVHStats;
+
+use super::{Assembly, InstanceType, Object, Scene};
+
+/// Totals for the whole scene, with instancing taken into account.
+#[derive(Debug, Default)]
+struct Totals {
+    // Primitive type -> (count, count including instancing)
+    primitives: BTreeMap<&'static str, (usize, usize)>,
+    // Light type -> count including instancing
+    lights: BTreeMap<&'static str, usize>,
+    instances: usize, // Including instancing of the assemblies they're in
+    bytes: usize,
+    light_bytes: usize, // Of the structures for choosing lights, included in `bytes`
+}
+
+impl<'a> Scene<'a> {
+    /// Describes the scene as an indented tree: the camera, the world,
+    /// the contents of each assembly, and totals for the whole scene
+    /// including an estimate of the memory it uses.
+    pub fn info(&self) -> String {
+        let mut out = String::new();
+        let mut totals = Totals::default();
+
+        let _ = writeln!(out, "Camera: {}", self.camera.describe());
+        let _ = writeln!(out, "World: {} distant light(s)", self.world.lights.len());
+        if let Some(ref atmosphere) = self.world.atmosphere {
+            let _ = writeln!(
+                out,
+                "    Atmosphere: anisotropy {}, mean free path at 550nm {}",
+                atmosphere.anisotropy,
+                1.0 / atmosphere
+                    .coefficients(550.0)
+                    .1
+                    .dot(glam::Vec4::splat(0.25)),
+            );
+        }
+        let _ = writeln!(out, "Assembly (root)");
+        assembly_info(&self.root, 1, 1, &mut out, &mut totals);
+
+        let _ = writeln!(out, "Totals:");
+        let _ = writeln!(out, "    Instances: {}", totals.instances);
+        for (primitive_type, (count, instanced)) in &totals.primitives {
+            let _ = writeln!(
+                out,
+                "    {}: {} ({} with instancing)",
+                primitive_type, count, instanced
+            );
+        }
+        if totals.lights.is_empty() && self.world.lights.is_empty() {
+            let _ = writeln!(out, "    Lights: none");
+        }
+        for (light_type, count) in &totals.lights {
+            let _ = writeln!(out, "    {}s: {}", light_type, count);
+        }
+        let _ = writeln!(out, "    Estimated memory: {}", format_bytes(totals.bytes));
+        let _ = writeln!(
+            out,
+            "        Light structures: {}",
+            format_bytes(totals.light_bytes)
+        );
+
+        out
+    }
+}
+
+impl<'a> Scene<'a> {
+    /// Describes the quality of the BVHs in each assembly: the one over
+    /// its instances, and those of its objects.
+    pub fn bvh_info(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "BVHs:");
+        let _ = writeln!(out, "    Assembly (root)");
+        assembly_bvh_info(&self.root, 2, &mut out);
+        out
+    }
+}
+
+impl<'a> Scene<'a> {
+    /// Estimates the memory used by the scene's geometry, lights, and
+    /// acceleration structures, in bytes.
+    pub fn size_in_bytes(&self) -> usize {
+        let mut totals = Totals::default();
+        assembly_info(&self.root, 1, 1, &mut String::new(), &mut totals);
+        totals.bytes
+    }
+}
+
+/// Describes the contents of an assembly that's instanced `multiplier`
+/// times in the scene, adding them to `totals`.
+fn assembly_info(
+    assembly: &Assembly,
+    multiplier: usize,
+    depth: usize,
+    out: &mut String,
+    totals: &mut Totals,
+) {
+    let indent = "    ".repeat(depth);
+
+    // Count the instances of each object and sub-assembly.  Each element
+    // of an instance array counts as an instance of its object.
+    let mut object_instances = vec![0; assembly.objects.len()];
+    let mut assembly_instances = vec![0; assembly.assemblies.len()];
+    let mut instance_count = 0;
+    for inst in assembly.instances {
+        let (counts, i, count) = match inst.instance_type {
+            InstanceType::Object => (&mut object_instances, inst.data_index, 1),
+            InstanceType::Assembly => (&mut assembly_instances, inst.data_index, 1),
+            InstanceType::Array => {
+                let array = &assembly.instance_arrays[inst.data_index];
+                (&mut object_instances, array.object_index, array.len())
+            }
+        };
+        counts[i] += count;
+        instance_count += count;
+    }
+
+    totals.instances += instance_count * multiplier;
+    let light_bytes =
+        std::mem::size_of_val(assembly.light_instances) + assembly.light_accel.size_in_bytes();
+    totals.light_bytes += light_bytes;
+    totals.bytes += std::mem::size_of_val(assembly.instances)
+        + std::mem::size_of_val(assembly.xforms)
+        + assembly.object_accel.size_in_bytes()
+        + assembly
+            .instance_arrays
+            .iter()
+            .map(|array| {
+                std::mem::size_of_val(array.xforms)
+                    + std::mem::size_of_val(array.elements)
+                    + array.accel.size_in_bytes()
+            })
+            .sum::<usize>()
+        + light_bytes;
+
+    let _ = writeln!(
+        out,
+        "{}{} object(s), {} assembly(s), {} shader(s), {} instance(s)",
+        indent,
+        assembly.objects.len(),
+        assembly.assemblies.len(),
+        assembly.surface_shaders.len(),
+        assembly.instances.len(),
+    );
+
+    for (i, object) in assembly.objects.iter().enumerate() {
+        let (stats, is_light) = match *object {
+            Object::Surface(surface) => (surface.stats(), false),
+            Object::SurfaceLight(light) => (light.stats(), true),
+        };
+        let instanced = object_instances[i] * multiplier;
+        totals.bytes += stats.bytes;
+
+        if is_light {
+            *totals.lights.entry(stats.primitive_type).or_insert(0) += instanced;
+            let _ = writeln!(
+                out,
+                "{}{}: {}, {} instance(s)",
+                indent, assembly.object_names[i], stats.primitive_type, object_instances[i],
+            );
+        } else {
+            let counts = totals
+                .primitives
+                .entry(stats.primitive_type)
+                .or_insert((0, 0));
+            counts.0 += stats.primitive_count;
+            counts.1 += stats.primitive_count * instanced;
+            let _ = writeln!(
+                out,
+                "{}{}: {} {}, {} instance(s), {}",
+                indent,
+                assembly.object_names[i],
+                stats.primitive_count,
+                stats.primitive_type,
+                object_instances[i],
+                format_bytes(stats.bytes),
+            );
+        }
+    }
+
+    for (i, sub_assembly) in assembly.assemblies.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "{}Assembly {}, {} instance(s)",
+            indent, assembly.assembly_names[i], assembly_instances[i],
+        );
+        assembly_info(
+            sub_assembly,
+            multiplier * assembly_instances[i],
+            depth + 1,
+            out,
+            totals,
+        );
+    }
+}
+
+fn assembly_bvh_info(assembly: &Assembly, depth: usize, out: &mut String) {
+    let indent = "    ".repeat(depth);
+
+    let _ = writeln!(
+        out,
+        "{}Instances: {}",
+        indent,
+        describe_bvh(&assembly.object_accel.stats())
+    );
+    for (i, object) in assembly.objects.iter().enumerate() {
+        let stats = match *object {
+            Object::Surface(surface) => surface.stats(),
+            Object::SurfaceLight(light) => light.stats(),
+        };
+        if let Some(ref bvh) = stats.bvh {
+            let _ = writeln!(
+                out,
+                "{}{}: {}",
+                indent,
+                assembly.object_names[i],
+                describe_bvh(bvh)
+            );
+        }
+    }
+
+    for (i, sub_assembly) in assembly.assemblies.iter().enumerate() {
+        let _ = writeln!(out, "{}Assembly {}", indent, assembly.assembly_names[i]);
+        assembly_bvh_info(sub_assembly, depth + 1, out);
+    }
+}
+
+fn describe_bvh(stats: &BVHStats) -> String {
+    format!(
+        "SAH cost {:.2}, {} leaf(s) of {:.2} objects on average, max depth {}, {:.1}% overlap",
+        stats.sah_cost,
+        stats.leaf_count,
+        stats.average_leaf_size(),
+        stats.max_depth,
+        stats.overlap * 100.0,
+    )
+}
+
+fn format_bytes(bytes: usize) -> String {
+    let mib = bytes as f64 / 1_048_576.0;
+    if mib >= 1.0 {
+        format!("{:.1} MiB", mib)
+    } else {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kioku::Arena;
+
+    use crate::{
+        math::{Matrix4x4, Point},
+        scene::AssemblyBuilder,
+        surface::triangle_mesh::TriangleMesh,
+    };
+
+    #[test]
+    fn totals_include_nested_instancing() {
+        let arena = Arena::new();
+        let mesh = arena.alloc(TriangleMesh::from_verts_and_indices(
+            &arena,
+            &[vec![
+                Point::new(0.0, 0.0, 0.0),
+                Point::new(1.0, 0.0, 0.0),
+                Point::new(0.0, 1.0, 0.0),
+                Point::new(1.0, 1.0, 0.0),
+            ]],
+            &None,
+            &[(0, 1, 2), (2, 1, 3)],
+        ));
+
+        // A mesh instanced three times in an assembly that's instanced
+        // twice, two of them through an instance array.
+        let mut inner = AssemblyBuilder::new(&arena);
+        inner.add_object("$mesh", Object::Surface(mesh));
+        inner.add_instance("$mesh", None, None, None);
+        let array_xforms: Vec<_> = (0..2)
+            .map(|i| Matrix4x4::from_location(Point::new(0.0, i as f32 * 2.0, 0.0)))
+            .collect();
+        inner.add_instance_array("$mesh", None, &array_xforms, 1);
+        let mut root = AssemblyBuilder::new(&arena);
+        root.add_assembly("$inner", inner.build());
+        for i in 0..2 {
+            let xform = Matrix4x4::from_location(Point::new(i as f32 * 2.0, 0.0, 0.0));
+            root.add_instance("$inner", None, None, Some(&[xform]));
+        }
+        let root = root.build();
+
+        let mut out = String::new();
+        let mut totals = Totals::default();
+        assembly_info(&root, 1, 1, &mut out, &mut totals);
+
+        assert_eq!(totals.instances, 2 + (3 * 2));
+        assert_eq!(totals.primitives["triangles"], (2, 2 * 3 * 2));
+        assert!(out.contains("$mesh: 2 triangles, 3 instance(s)"));
+        assert!(out.contains("Assembly $inner, 2 instance(s)"));
+
+        let mut out = String::new();
+        assembly_bvh_info(&root, 1, &mut out);
+        assert!(out.contains("$mesh: SAH cost"));
+        assert!(out.contains("    Assembly $inner\n        Instances: SAH cost"));
+    }
+}
@@ -0,0 +1,81 @@
+use kioku::Arena;
+
+use crate::{
+    accel::BVH4,
+    bbox::{transform_bbox_slice_from, BBox},
+    boundable::Boundable,
+    math::Matrix4x4,
+};
+
+/// Many instances of one object, each with its own transform, stored
+/// compactly with a BVH over them.
+///
+/// This is what makes scattering huge numbers of copies of an object
+/// cheap: the whole array is a single instance in its assembly.
+#[derive(Copy, Clone, Debug)]
+pub struct InstanceArray<'a> {
+    pub object_index: usize,
+    pub sample_count: usize,     // Transform samples per element
+    pub xforms: &'a [Matrix4x4], // `sample_count` samples per element, element after element
+    pub elements: &'a [u32],     // Element indices, in the order of the BVH's leaves
+    pub accel: BVH4<'a>,
+}
+
+impl<'a> InstanceArray<'a> {
+    /// Builds an array of instances of the object `object_index`, which
+    /// has the bounds `object_bounds`.
+    ///
+    /// `xforms` are the world-to-object transforms of the elements,
+    /// `sample_count` samples per element.
+    pub fn new(
+        arena: &'a Arena,
+        object_index: usize,
+        object_bounds: &[BBox],
+        xforms: &[Matrix4x4],
+        sample_count: usize,
+    ) -> InstanceArray<'a> {
+        assert!(sample_count > 0);
+        assert_eq!(xforms.len() % sample_count, 0);
+        let element_count = xforms.len() / sample_count;
+
+        // Element bounds.
+        let mut indices = vec![0];
+        let mut bounds = Vec::new();
+        let mut bbs = Vec::new();
+        for element_xforms in xforms.chunks(sample_count) {
+            transform_bbox_slice_from(object_bounds, element_xforms, &mut bbs);
+            bounds.extend_from_slice(&bbs);
+            indices.push(bounds.len());
+        }
+
+        let mut elements: Vec<u32> = (0..element_count as u32).collect();
+        let accel = BVH4::from_objects(arena, &mut elements[..], 1, |&e| {
+            &bounds[indices[e as usize]..indices[e as usize + 1]]
+        });
+
+        InstanceArray {
+            object_index: object_index,
+            sample_count: sample_count,
+            xforms: arena.copy_slice(xforms),
+            elements: arena.copy_slice(&elements),
+            accel: accel,
+        }
+    }
+
+    /// The number of elements in the array.
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// The transform samples of an element.
+    pub fn element_xforms(&self, element: usize) -> &'a [Matrix4x4] {
+        let start = element * self.sample_count;
+        &self.xforms[start..(start + self.sample_count)]
+    }
+}
+
+impl<'a> Boundable for InstanceArray<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.accel.bounds()
+    }
+}
@@ -1,25 +1,36 @@
 mod assembly;
+mod atmosphere;
+mod info;
+mod instance_array;
 mod world;
 
+use std::f32::consts::PI as PI_32;
+
 use crate::{
     accel::LightAccel,
     algorithm::weighted_choice,
+    bbox::BBox,
+    boundable::Boundable,
     camera::Camera,
     color::SpectralSample,
-    math::{Normal, Point, Vector},
+    fp_utils::robust_ray_origin,
+    math::{coordinate_system_from_vector, Normal, Point, Vector},
+    ray::Ray,
+    sampling::{square_to_circle, uniform_sample_sphere},
     surface::SurfaceIntersection,
     transform_stack::TransformStack,
 };
 
 pub use self::{
     assembly::{Assembly, AssemblyBuilder, InstanceType, Object},
-    world::World,
+    atmosphere::{cos_between, sample_equiangular, sample_exponential, Atmosphere},
+    world::{Backplate, World},
 };
 
 #[derive(Debug)]
 pub struct Scene<'a> {
     pub name: Option<String>,
-    pub camera: Camera<'a>,
+    pub camera: &'a dyn Camera,
     pub world: World<'a>,
     pub root: Assembly<'a>,
 }
@@ -34,37 +45,8 @@ impl<'a> Scene<'a> {
         time: f32,
         intr: &SurfaceIntersection,
     ) -> SceneLightSample {
-        // TODO: this just selects between world lights and local lights
-        // with a 50/50 chance.  We should do something more sophisticated
-        // than this, accounting for the estimated impact of the lights
-        // on the point being lit.
-
-        // Calculate relative probabilities of traversing into world lights
-        // or local lights.
-        let wl_energy = if self
-            .world
-            .lights
-            .iter()
-            .fold(0.0, |energy, light| energy + light.approximate_energy())
-            <= 0.0
-        {
-            0.0
-        } else {
-            1.0
-        };
-        let ll_energy = if self.root.light_accel.approximate_energy() <= 0.0 {
-            0.0
-        } else {
-            1.0
-        };
-        let tot_energy = wl_energy + ll_energy;
-
         // Decide either world or local lights, and select and sample a light.
-        if tot_energy <= 0.0 {
-            return SceneLightSample::None;
-        } else {
-            let wl_prob = wl_energy / tot_energy;
-
+        if let Some(wl_prob) = self.world_light_prob() {
             if n < wl_prob {
                 // World lights
                 let n = n / wl_prob;
@@ -76,12 +58,13 @@ impl<'a> Scene<'a> {
                     direction: sv,
                     pdf: pdf,
                     selection_pdf: p * wl_prob,
+                    is_delta: self.world.lights[i].is_delta(),
                 };
             } else {
                 // Local lights
                 let n = (n - wl_prob) / (1.0 - wl_prob);
 
-                if let Some((ss, sgeo, pdf, spdf)) =
+                if let Some((ss, sgeo, pdf, spdf, is_delta)) =
                     self.root
                         .sample_lights(xform_stack, n, uvw, wavelength, time, intr)
                 {
@@ -90,15 +73,234 @@ impl<'a> Scene<'a> {
                         sample_geo: sgeo,
                         pdf: pdf,
                         selection_pdf: spdf * (1.0 - wl_prob),
+                        is_delta: is_delta,
                     };
                 } else {
                     return SceneLightSample::None;
                 }
             }
+        } else {
+            return SceneLightSample::None;
+        }
+    }
+
+    /// Calls `f` with the light arriving from each world light in the
+    /// direction `dir`, for a ray that escapes the scene.
+    ///
+    /// Along with the light, `f` is given the pdf of `sample_lights()`
+    /// choosing the same sample from any point, including the light
+    /// selection probability.  World light selection doesn't depend on the
+    /// point being lit, so unlike for local lights that's always known.
+    ///
+    /// Camera rays skip the lights that camera rays don't see, and all of
+    /// them when there's a backplate.
+    pub fn world_lights_from_direction<F>(
+        &self,
+        dir: Vector,
+        wavelength: f32,
+        time: f32,
+        camera_ray: bool,
+        mut f: F,
+    ) where
+        F: FnMut(SpectralSample, f32),
+    {
+        let wl_prob = match self.world_light_prob() {
+            Some(p) if p > 0.0 => p,
+            _ => return,
+        };
+        if camera_ray && self.world.backplate.is_some() {
+            return;
+        }
+        let total_energy = self
+            .world
+            .lights
+            .iter()
+            .fold(0.0, |energy, light| energy + light.approximate_energy());
+
+        for light in self.world.lights.iter() {
+            if light.is_delta() || (camera_ray && !light.is_camera_visible()) {
+                continue;
+            }
+            let (color, pdf) = light.evaluate(dir, wavelength, time);
+            if pdf > 0.0 {
+                let selection_pdf = light.approximate_energy() / total_energy * wl_prob;
+                f(color, pdf * selection_pdf);
+            }
+        }
+    }
+
+    /// Selects a light source in proportion to its approximate power, and
+    /// samples light leaving it, for tracing paths from the lights.
+    ///
+    /// Besides the lights, the background is also a source of light here.
+    /// World lights and the background are infinitely far away, so their
+    /// light is emitted from a disk just outside the scene's bounding
+    /// sphere, facing into the scene.
+    pub fn sample_emission(
+        &self,
+        xform_stack: &mut TransformStack,
+        n: f32,
+        uv: (f32, f32),
+        dir_uv: (f32, f32),
+        wavelength: f32,
+        time: f32,
+    ) -> Option<EmissionSample> {
+        let (center, radius) = self.bounding_sphere();
+        let disk_area = PI_32 * radius * radius;
+
+        // Approximate power of each kind of light source.  Normalized
+        // light colors are the power divided by PI, and world lights and
+        // the background deliver their light through the area of the disk.
+        let local_power = self.root.light_accel.approximate_energy() * PI_32;
+        let world_power = self
+            .world
+            .lights
+            .iter()
+            .fold(0.0, |energy, light| energy + light.approximate_energy())
+            * disk_area;
+        let background_power =
+            self.world.background_color.approximate_energy() * 4.0 * PI_32 * disk_area;
+        let total_power = local_power + world_power + background_power;
+        if total_power <= 0.0 || !total_power.is_finite() {
+            return None;
+        }
+
+        let local_prob = local_power / total_power;
+        let world_prob = world_power / total_power;
+        let offset_disk = |w: Vector| {
+            // A point on the disk facing into the scene from direction w.
+            let (_, t1, t2) = coordinate_system_from_vector(w);
+            let (x, y) = square_to_circle((dir_uv.0 * 2.0) - 1.0, (dir_uv.1 * 2.0) - 1.0);
+            center + (w * radius) + (t1 * (x * radius)) + (t2 * (y * radius))
+        };
+
+        if n < local_prob {
+            // Local lights
+            let n = n / local_prob;
+            let (flux, sample_geo, dir, sel_pdf) =
+                self.root
+                    .sample_emission(xform_stack, n, uv, dir_uv, wavelength, time)?;
+            let orig =
+                robust_ray_origin(sample_geo.0, sample_geo.2, sample_geo.1.normalized(), dir);
+            Some(EmissionSample {
+                flux: flux * (1.0 / (sel_pdf * local_prob)),
+                ray: Ray {
+                    orig: orig,
+                    dir: dir,
+                    time: time,
+                    wavelength: wavelength,
+                    max_t: f32::INFINITY,
+                },
+                from_background: false,
+            })
+        } else if n < (local_prob + world_prob) {
+            // World lights
+            let n = ((n - local_prob) / world_prob).min(0.999_999);
+            let (i, p) = weighted_choice(self.world.lights, n, |l| l.approximate_energy());
+            let (color, shadow_vec, pdf) =
+                self.world.lights[i].sample_from_point(uv.0, uv.1, wavelength, time);
+            if pdf <= 0.0 {
+                return None;
+            }
+            let w = shadow_vec.normalized();
+            Some(EmissionSample {
+                flux: color * (disk_area / (pdf * p * world_prob)),
+                ray: Ray {
+                    orig: offset_disk(w),
+                    dir: -w,
+                    time: time,
+                    wavelength: wavelength,
+                    max_t: f32::INFINITY,
+                },
+                from_background: false,
+            })
+        } else {
+            // Background
+            let background_prob = 1.0 - local_prob - world_prob;
+            let w = uniform_sample_sphere(uv.0, uv.1).normalized();
+            let color = self.world.background_color.to_spectral_sample(wavelength);
+            Some(EmissionSample {
+                flux: color * (4.0 * PI_32 * disk_area / background_prob),
+                ray: Ray {
+                    orig: offset_disk(w),
+                    dir: -w,
+                    time: time,
+                    wavelength: wavelength,
+                    max_t: f32::INFINITY,
+                },
+                from_background: true,
+            })
+        }
+    }
+
+    /// Returns the center and radius of a sphere bounding the scene over
+    /// the whole shutter interval.
+    pub fn bounding_sphere(&self) -> (Point, f32) {
+        let bounds = self
+            .root
+            .bounds()
+            .iter()
+            .fold(BBox::new(), |bounds, &bb| bounds | bb);
+        let radius = bounds.diagonal() * 0.5;
+        if radius.is_finite() {
+            (bounds.center(), radius)
+        } else {
+            (Point::new(0.0, 0.0, 0.0), 0.0)
+        }
+    }
+
+    /// Returns the probability of `sample_lights()` choosing to sample the
+    /// world lights rather than the local lights, or `None` if there are
+    /// no lights at all.
+    fn world_light_prob(&self) -> Option<f32> {
+        // TODO: this just selects between world lights and local lights
+        // with a 50/50 chance.  We should do something more sophisticated
+        // than this, accounting for the estimated impact of the lights
+        // on the point being lit.
+
+        // Calculate relative probabilities of traversing into world lights
+        // or local lights.
+        let wl_energy = if self
+            .world
+            .lights
+            .iter()
+            .fold(0.0, |energy, light| energy + light.approximate_energy())
+            <= 0.0
+        {
+            0.0
+        } else {
+            1.0
+        };
+        let ll_energy = if self.root.light_accel.approximate_energy() <= 0.0 {
+            0.0
+        } else {
+            1.0
+        };
+        let tot_energy = wl_energy + ll_energy;
+
+        if tot_energy <= 0.0 {
+            None
+        } else {
+            Some(wl_energy / tot_energy)
         }
     }
 }
 
+/// Light leaving a light source, from `Scene::sample_emission()`.
+#[derive(Debug, Copy, Clone)]
+pub struct EmissionSample {
+    /// The flux carried by the light, already divided by the pdf of
+    /// choosing it.
+    pub flux: SpectralSample,
+
+    /// The ray the light leaves along.
+    pub ray: Ray,
+
+    /// Whether the light is from the background, which `sample_lights()`
+    /// can't find.
+    pub from_background: bool,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum SceneLightSample {
     None,
@@ -107,12 +309,14 @@ pub enum SceneLightSample {
         direction: Vector,
         pdf: f32,
         selection_pdf: f32,
+        is_delta: bool,
     },
     Surface {
         color: SpectralSample,
         sample_geo: (Point, Normal, f32),
         pdf: f32,
         selection_pdf: f32,
+        is_delta: bool,
     },
 }
 
@@ -148,4 +352,15 @@ impl SceneLightSample {
             SceneLightSample::Surface { selection_pdf, .. } => selection_pdf,
         }
     }
+
+    /// Whether the sample is from a delta light, which can't be found by
+    /// any other sampling strategy and therefore shouldn't use MIS.  False
+    /// when there's no sample.
+    pub fn is_delta(&self) -> bool {
+        match *self {
+            SceneLightSample::None => false,
+            SceneLightSample::Distant { is_delta, .. } => is_delta,
+            SceneLightSample::Surface { is_delta, .. } => is_delta,
+        }
+    }
 }
@@ -1,7 +1,118 @@
-use crate::{color::Color, light::WorldLightSource};
+use kioku::Arena;
+
+use crate::{
+    color::{rec709_e_to_xyz, Color},
+    image::RgbImage,
+    light::WorldLightSource,
+};
+
+use super::Atmosphere;
 
 #[derive(Debug)]
 pub struct World<'a> {
     pub background_color: Color,
     pub lights: &'a [&'a dyn WorldLightSource],
+    pub atmosphere: Option<Atmosphere>,
+    pub backplate: Option<Backplate<'a>>, // What camera rays see instead of the background
+}
+
+impl<'a> World<'a> {
+    /// The light that a camera ray through the given position on the image
+    /// sees when it escapes the scene, aside from world lights.
+    ///
+    /// `image_uv` is from (0, 0) at the image's top left to (1, 1) at its
+    /// bottom right.
+    pub fn camera_background(&self, image_uv: (f32, f32)) -> Color {
+        if let Some(ref backplate) = self.backplate {
+            backplate.color(image_uv)
+        } else {
+            self.background_color
+        }
+    }
+}
+
+/// An image stretched over the frame behind the scene, seen only by camera
+/// rays.  It doesn't light the scene, and hides the background and world
+/// lights from the camera.
+#[derive(Copy, Clone, Debug)]
+pub struct Backplate<'a> {
+    width: usize,
+    height: usize,
+    texels: &'a [Color], // Row after row from the top
+}
+
+impl<'a> Backplate<'a> {
+    /// Makes a backplate from an image of linear rec709 colors with an
+    /// equal-energy white point.
+    pub fn new(arena: &'a Arena, image: &RgbImage) -> Backplate<'a> {
+        assert!(image.width > 0 && image.height > 0);
+        let texels: Vec<Color> = image
+            .data
+            .iter()
+            .map(|&rgb| Color::new_xyz(rec709_e_to_xyz(rgb)))
+            .collect();
+
+        Backplate {
+            width: image.width,
+            height: image.height,
+            texels: arena.copy_slice(&texels),
+        }
+    }
+
+    /// The backplate's color at a position on the image, from (0, 0) at
+    /// its top left to (1, 1) at its bottom right.  Positions outside of
+    /// the image get the color at its nearest edge.
+    pub fn color(&self, uv: (f32, f32)) -> Color {
+        let x = (uv.0 * self.width as f32).max(0.0) as usize;
+        let y = (uv.1 * self.height as f32).max(0.0) as usize;
+        self.texels[(y.min(self.height - 1) * self.width) + x.min(self.width - 1)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xyz(color: Color) -> (f32, f32, f32) {
+        match color {
+            Color::XYZ(x, y, z) => (x, y, z),
+            _ => panic!("Expected an XYZ color."),
+        }
+    }
+
+    #[test]
+    fn backplate_lookup() {
+        let arena = Arena::new();
+        let image = RgbImage {
+            width: 2,
+            height: 2,
+            data: vec![
+                (1.0, 0.0, 0.0),
+                (0.0, 1.0, 0.0),
+                (0.0, 0.0, 1.0),
+                (1.0, 1.0, 1.0),
+            ],
+        };
+        let backplate = Backplate::new(&arena, &image);
+        let red = rec709_e_to_xyz((1.0, 0.0, 0.0));
+        let white = rec709_e_to_xyz((1.0, 1.0, 1.0));
+        assert_eq!(xyz(backplate.color((0.25, 0.25))), red);
+        assert_eq!(xyz(backplate.color((0.75, 0.75))), white);
+
+        // Overscan clamps to the edges.
+        assert_eq!(xyz(backplate.color((-0.1, -0.1))), red);
+        assert_eq!(xyz(backplate.color((1.1, 1.1))), white);
+
+        let world = World {
+            background_color: Color::new_xyz((0.0, 0.0, 0.0)),
+            lights: &[],
+            atmosphere: None,
+            backplate: Some(backplate),
+        };
+        assert_eq!(xyz(world.camera_background((0.25, 0.25))), red);
+        assert_eq!(
+            xyz(world.camera_background((0.75, 0.25))),
+            rec709_e_to_xyz((0.0, 1.0, 0.0))
+        );
+    }
 }
@@ -0,0 +1,568 @@
+//! The spectral response of the camera's sensor, which turns the light
+//! arriving at each pixel into a color.
+//!
+//! By default this is the CIE 1931 standard observer, so renders show
+//! colors as a person would see them.  To match a plate shot on a real
+//! camera, the camera's own spectral sensitivity functions (SSFs) can be
+//! used instead.  The sensor's raw responses are then mapped to XYZ with
+//! the 3x3 matrix that best fits them to the standard observer, as a
+//! camera's color processing would, so that the colors it can't tell
+//! apart (its metamers) come out the same in the render as on the plate.
+//!
+//! White balance then adapts the sensor's colors so that the light of a
+//! chosen illuminant or color temperature comes out neutral.
+
+use std::{fs, str::FromStr};
+
+use crate::color::{plancks_law, x_1931, y_1931, z_1931, SpectralSample, XYZ};
+
+// The range and spacing of the tabulated sensitivities, in nanometers.
+// This matches the range of wavelengths that are rendered.
+const TABLE_MIN: f32 = 380.0;
+const TABLE_MAX: f32 = 700.0;
+const TABLE_STEP: f32 = 5.0;
+const TABLE_LEN: usize = ((TABLE_MAX - TABLE_MIN) / TABLE_STEP) as usize + 1;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum SensorResponse {
+    /// The CIE 1931 standard observer.
+    #[default]
+    Cie1931,
+
+    /// A camera with the given spectral sensitivities.
+    Camera(CameraResponse),
+}
+
+impl SensorResponse {
+    /// Parses a sensor response name: 'cie1931', 'cmos', or otherwise the
+    /// path of a file of measured sensitivities, as described in
+    /// `CameraResponse::from_csv()`.
+    pub fn from_spec(spec: &str) -> Result<SensorResponse, String> {
+        match spec.trim() {
+            "cie1931" => Ok(SensorResponse::Cie1931),
+            "cmos" => Ok(SensorResponse::Camera(CameraResponse::generic_cmos())),
+            path => {
+                let text = fs::read_to_string(path).map_err(|e| {
+                    format!(
+                        "unknown sensor response '{}', expected 'cie1931', 'cmos', or the path \
+                         of a sensitivity file ({})",
+                        path, e
+                    )
+                })?;
+                Ok(SensorResponse::Camera(CameraResponse::from_csv(&text)?))
+            }
+        }
+    }
+
+    /// Converts the light of a spectral sample to XYZ as the sensor sees
+    /// it.
+    pub fn to_xyz(&self, ss: &SpectralSample) -> XYZ {
+        match *self {
+            SensorResponse::Cie1931 => XYZ::from_spectral_sample(ss),
+            SensorResponse::Camera(ref camera) => camera.to_xyz(ss),
+        }
+    }
+}
+
+/// A camera's spectral sensitivities, and the matrix that maps its
+/// responses to XYZ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraResponse {
+    table: Vec<[f32; 3]>, // Red, green, and blue sensitivities every TABLE_STEP nm
+    to_xyz: [[f32; 3]; 3],
+}
+
+impl CameraResponse {
+    /// Makes a camera response from sensitivity functions, which are
+    /// evaluated across the rendered wavelengths.
+    pub fn from_fn<F>(ssf: F) -> CameraResponse
+    where
+        F: Fn(f32) -> [f32; 3],
+    {
+        let table: Vec<[f32; 3]> = (0..TABLE_LEN)
+            .map(|i| ssf(TABLE_MIN + (i as f32 * TABLE_STEP)))
+            .collect();
+        let to_xyz = fit_to_xyz(&table);
+        CameraResponse {
+            table: table,
+            to_xyz: to_xyz,
+        }
+    }
+
+    /// A generic consumer CMOS sensor with a Bayer color filter array and
+    /// an infrared cut filter.
+    ///
+    /// This isn't any particular camera: it's a smooth approximation of
+    /// the general shape of such sensors' measured sensitivities, with
+    /// broad, overlapping channels and a red channel that's cut off short
+    /// of the infrared.  It's a reasonable stand-in when a plate's camera
+    /// hasn't been measured.
+    pub fn generic_cmos() -> CameraResponse {
+        let lobe = |wl: f32, center: f32, width_below: f32, width_above: f32| {
+            let t = (wl - center)
+                / if wl < center {
+                    width_below
+                } else {
+                    width_above
+                };
+            (-0.5 * t * t).exp()
+        };
+        CameraResponse::from_fn(|wl| {
+            let ir_cut = 1.0 / (1.0 + ((wl - 655.0) / 8.0).exp());
+            [
+                ((0.95 * lobe(wl, 600.0, 22.0, 40.0)) + (0.06 * lobe(wl, 450.0, 20.0, 20.0)))
+                    * ir_cut,
+                (1.0 * lobe(wl, 530.0, 35.0, 38.0)) + (0.04 * lobe(wl, 420.0, 15.0, 15.0)),
+                (0.85 * lobe(wl, 460.0, 25.0, 30.0)) + (0.03 * lobe(wl, 560.0, 20.0, 20.0)),
+            ]
+        })
+    }
+
+    /// Reads measured sensitivities from comma separated text, with one
+    /// line per wavelength of the form `wavelength, red, green, blue`.
+    /// The wavelengths are in nanometers, in increasing order.  Lines
+    /// starting with '#' and lines that don't start with a number, like a
+    /// header, are skipped.
+    ///
+    /// Sensitivities are interpolated between the given wavelengths, and
+    /// taken to be zero outside of them.
+    pub fn from_csv(text: &str) -> Result<CameraResponse, String> {
+        let mut samples: Vec<(f32, [f32; 3])> = Vec::new();
+        for (line_i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<_> = line.split(',').map(|f| f32::from_str(f.trim())).collect();
+            match fields[..] {
+                [Ok(wl), Ok(r), Ok(g), Ok(b)] => {
+                    if let Some(&(last_wl, _)) = samples.last() {
+                        if wl <= last_wl {
+                            return Err(format!(
+                                "sensitivity wavelengths must increase, but line {} doesn't",
+                                line_i + 1
+                            ));
+                        }
+                    }
+                    samples.push((wl, [r, g, b]));
+                }
+                [Err(_), ..] => {} // A header
+                _ => {
+                    return Err(format!(
+                        "line {} of the sensitivities should be 'wavelength, red, green, blue'",
+                        line_i + 1
+                    ));
+                }
+            }
+        }
+        if samples.len() < 2 {
+            return Err("sensitivities need at least two wavelengths".to_string());
+        }
+
+        Ok(CameraResponse::from_fn(|wl| {
+            let i = samples.partition_point(|&(s_wl, _)| s_wl <= wl);
+            if i == 0 || i == samples.len() {
+                // Outside the measurements, except for exactly the last.
+                return if wl == samples[samples.len() - 1].0 {
+                    samples[samples.len() - 1].1
+                } else {
+                    [0.0; 3]
+                };
+            }
+            let (wl0, s0) = samples[i - 1];
+            let (wl1, s1) = samples[i];
+            let alpha = (wl - wl0) / (wl1 - wl0);
+            [
+                s0[0] + ((s1[0] - s0[0]) * alpha),
+                s0[1] + ((s1[1] - s0[1]) * alpha),
+                s0[2] + ((s1[2] - s0[2]) * alpha),
+            ]
+        }))
+    }
+
+    /// The camera's responses at a wavelength.
+    fn response(&self, wavelength: f32) -> [f32; 3] {
+        let x = ((wavelength - TABLE_MIN) / TABLE_STEP)
+            .max(0.0)
+            .min((TABLE_LEN - 1) as f32);
+        let i = (x as usize).min(TABLE_LEN - 2);
+        let alpha = x - i as f32;
+        let (s0, s1) = (self.table[i], self.table[i + 1]);
+        [
+            s0[0] + ((s1[0] - s0[0]) * alpha),
+            s0[1] + ((s1[1] - s0[1]) * alpha),
+            s0[2] + ((s1[2] - s0[2]) * alpha),
+        ]
+    }
+
+    fn to_xyz(&self, ss: &SpectralSample) -> XYZ {
+        // Like `XYZ::from_spectral_sample()`, but through the camera.
+        let wls = ss.wavelengths();
+        let mut rgb = [0.0f32; 3];
+        for &(wl, e) in &[
+            (wls.x(), ss.e.x()),
+            (wls.y(), ss.e.y()),
+            (wls.z(), ss.e.z()),
+            (wls.w(), ss.e.w()),
+        ] {
+            let response = self.response(wl);
+            for c in 0..3 {
+                rgb[c] += response[c] * e;
+            }
+        }
+        let m = &self.to_xyz;
+        let xyz = |row: usize| {
+            ((m[row][0] * rgb[0]) + (m[row][1] * rgb[1]) + (m[row][2] * rgb[2])) * 0.75
+        };
+        XYZ::new(xyz(0), xyz(1), xyz(2))
+    }
+}
+
+/// White balance, which adapts colors so that light of a chosen white
+/// comes out neutral in the output image.
+///
+/// The adaptation is a von Kries transform in Bradford cone space, from
+/// the chosen white to the equal-energy white that the output color
+/// spaces are relative to.  So the default, equal-energy white, leaves
+/// colors as they are.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WhiteBalance {
+    white: (f32, f32),         // CIE xy chromaticity of the white
+    adaptation: [[f32; 3]; 3], // XYZ to adapted XYZ
+}
+
+impl Default for WhiteBalance {
+    fn default() -> WhiteBalance {
+        WhiteBalance::from_chromaticity(1.0 / 3.0, 1.0 / 3.0)
+    }
+}
+
+impl WhiteBalance {
+    /// Parses a white balance: an illuminant name ('e', 'd50', 'd55',
+    /// 'd65', 'd75', or 'a'), or a color temperature in kelvin optionally
+    /// followed by a tint, separated by a comma or space.
+    ///
+    /// The tint moves the white off of the blackbody locus by that
+    /// distance in the CIE 1960 uv plane, toward green if positive and
+    /// toward magenta if negative.
+    pub fn from_spec(spec: &str) -> Result<WhiteBalance, String> {
+        let (x, y) = match spec.trim() {
+            "e" | "none" => (1.0 / 3.0, 1.0 / 3.0),
+            "d50" => (0.345_67, 0.358_50),
+            "d55" => (0.332_42, 0.347_43),
+            "d65" => (0.312_71, 0.329_02),
+            "d75" => (0.299_02, 0.314_85),
+            "a" => (0.447_57, 0.407_45),
+            _ => {
+                let error = || {
+                    format!(
+                        "unknown white balance '{}', expected 'e', 'd50', 'd55', 'd65', 'd75', \
+                         'a', or a temperature in kelvin and optional tint, e.g. '5600,0.002'",
+                        spec
+                    )
+                };
+                let numbers: Vec<f32> = spec
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|s| !s.is_empty())
+                    .map(f32::from_str)
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| error())?;
+                match numbers[..] {
+                    [temperature] if (1000.0..=40000.0).contains(&temperature) => {
+                        temperature_white(temperature, 0.0)
+                    }
+                    [temperature, tint]
+                        if (1000.0..=40000.0).contains(&temperature) && tint.abs() < 0.05 =>
+                    {
+                        temperature_white(temperature, tint)
+                    }
+                    _ => return Err(error()),
+                }
+            }
+        };
+        Ok(WhiteBalance::from_chromaticity(x, y))
+    }
+
+    /// White balance for the white with the CIE xy chromaticity (x, y).
+    pub fn from_chromaticity(x: f32, y: f32) -> WhiteBalance {
+        const BRADFORD: [[f64; 3]; 3] = [
+            [0.8951, 0.2664, -0.1614],
+            [-0.7502, 1.7135, 0.0367],
+            [0.0389, -0.0685, 1.0296],
+        ];
+        let (xf, yf) = (x as f64, y as f64);
+        let white = [xf / yf, 1.0, (1.0 - xf - yf) / yf];
+        let mut scale = [[0.0f64; 3]; 3];
+        for row in 0..3 {
+            // The cones' responses to equal-energy white over their
+            // responses to the chosen white.
+            let cone_e: f64 = BRADFORD[row].iter().sum();
+            let cone_white: f64 = (0..3).map(|i| BRADFORD[row][i] * white[i]).sum();
+            scale[row][row] = cone_e / cone_white;
+        }
+        let bradford_inv = invert_3x3(BRADFORD).unwrap();
+        let m = mul_3x3(bradford_inv, mul_3x3(scale, BRADFORD));
+
+        let mut adaptation = [[0.0f32; 3]; 3];
+        for row in 0..3 {
+            for col in 0..3 {
+                adaptation[row][col] = m[row][col] as f32;
+            }
+        }
+        WhiteBalance {
+            white: (x, y),
+            adaptation: adaptation,
+        }
+    }
+
+    /// The CIE xy chromaticity of the white.
+    #[allow(dead_code)]
+    pub fn white(&self) -> (f32, f32) {
+        self.white
+    }
+
+    /// Adapts a color.
+    pub fn apply(&self, xyz: XYZ) -> XYZ {
+        let m = &self.adaptation;
+        let row = |r: usize| (m[r][0] * xyz.x) + (m[r][1] * xyz.y) + (m[r][2] * xyz.z);
+        XYZ::new(row(0), row(1), row(2))
+    }
+}
+
+/// The xy chromaticity of a blackbody at `temperature` kelvin, as seen
+/// across the rendered wavelengths, moved `tint` off of the blackbody
+/// locus in the CIE 1960 uv plane.
+///
+/// Using the renderer's own blackbody spectra means a light with a given
+/// temperature comes out exactly neutral when balanced for it.
+fn temperature_white(temperature: f32, tint: f32) -> (f32, f32) {
+    let uv = |temperature: f32| {
+        let (mut x, mut y, mut z) = (0.0f64, 0.0f64, 0.0f64);
+        let mut wl = TABLE_MIN;
+        while wl <= TABLE_MAX {
+            let e = plancks_law(temperature, wl) as f64;
+            x += e * x_1931(wl) as f64;
+            y += e * y_1931(wl) as f64;
+            z += e * z_1931(wl) as f64;
+            wl += 1.0;
+        }
+        let d = x + (15.0 * y) + (3.0 * z);
+        ((4.0 * x) / d, (6.0 * y) / d)
+    };
+    let (mut u, mut v) = uv(temperature);
+    if tint != 0.0 {
+        // Step along the locus by a small change in mireds to find its
+        // direction, and move perpendicular to it, toward green (up in v).
+        let (u2, v2) = uv(1.0e6 / ((1.0e6 / temperature) - 1.0));
+        let (du, dv) = (u2 - u, v2 - v);
+        let len = ((du * du) + (dv * dv)).sqrt();
+        let (mut nu, mut nv) = (-dv / len, du / len);
+        if nv < 0.0 {
+            nu = -nu;
+            nv = -nv;
+        }
+        u += nu * tint as f64;
+        v += nv * tint as f64;
+    }
+    let d = (2.0 * u) - (8.0 * v) + 4.0;
+    (((3.0 * u) / d) as f32, ((2.0 * v) / d) as f32)
+}
+
+/// Finds the matrix that maps the camera responses in `table` to XYZ.
+///
+/// It's the least squares fit of the camera's sensitivities to the CIE
+/// 1931 color matching functions, with the channels then scaled so that
+/// equal-energy white maps to exactly the same XYZ as for the standard
+/// observer.  That's the camera being white balanced, so that neutral
+/// colors stay neutral.
+fn fit_to_xyz(table: &[[f32; 3]]) -> [[f32; 3]; 3] {
+    // Normal equations: M = (sum of cmf * c^T) * (sum of c * c^T)^-1.
+    let mut cc = [[0.0f64; 3]; 3];
+    let mut xc = [[0.0f64; 3]; 3];
+    let mut white_camera = [0.0f64; 3];
+    let mut white_xyz = [0.0f64; 3];
+    for (i, c) in table.iter().enumerate() {
+        let wl = TABLE_MIN + (i as f32 * TABLE_STEP);
+        let cmf = [x_1931(wl), y_1931(wl), z_1931(wl)];
+        for row in 0..3 {
+            for col in 0..3 {
+                cc[row][col] += c[row] as f64 * c[col] as f64;
+                xc[row][col] += cmf[row] as f64 * c[col] as f64;
+            }
+            white_camera[row] += c[row] as f64;
+            white_xyz[row] += cmf[row] as f64;
+        }
+    }
+    let m = if let Some(cc_inv) = invert_3x3(cc) {
+        mul_3x3(xc, cc_inv)
+    } else {
+        // The channels aren't independent, so there's no good fit.  Fall
+        // back to treating them as XYZ, which at least keeps white white.
+        [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+    };
+
+    // White balance: scale the channels so the camera's white maps to
+    // the standard observer's.
+    let target = invert_3x3(m).map(|m_inv| {
+        let mut t = [0.0f64; 3];
+        for row in 0..3 {
+            for col in 0..3 {
+                t[row] += m_inv[row][col] * white_xyz[col];
+            }
+        }
+        t
+    });
+    let mut result = [[0.0f32; 3]; 3];
+    for row in 0..3 {
+        for col in 0..3 {
+            let balance = match target {
+                Some(t) if white_camera[col] > 0.0 && t[col] > 0.0 => t[col] / white_camera[col],
+                _ => 1.0,
+            };
+            result[row][col] = (m[row][col] * balance) as f32;
+        }
+    }
+    result
+}
+
+fn mul_3x3(a: [[f64; 3]; 3], b: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut m = [[0.0f64; 3]; 3];
+    for row in 0..3 {
+        for col in 0..3 {
+            m[row][col] = (0..3).map(|i| a[row][i] * b[i][col]).sum();
+        }
+    }
+    m
+}
+
+fn invert_3x3(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| {
+        (m[r0][c0] * m[r1][c1]) - (m[r0][c1] * m[r1][c0])
+    };
+    let adjugate = [
+        [
+            cofactor(1, 2, 1, 2),
+            -cofactor(0, 2, 1, 2),
+            cofactor(0, 1, 1, 2),
+        ],
+        [
+            -cofactor(1, 2, 0, 2),
+            cofactor(0, 2, 0, 2),
+            -cofactor(0, 1, 0, 2),
+        ],
+        [
+            cofactor(1, 2, 0, 1),
+            -cofactor(0, 2, 0, 1),
+            cofactor(0, 1, 0, 1),
+        ],
+    ];
+    let det = (m[0][0] * adjugate[0][0]) + (m[0][1] * adjugate[1][0]) + (m[0][2] * adjugate[2][0]);
+    if det.abs() < 1.0e-12 {
+        return None;
+    }
+    let mut inv = [[0.0f64; 3]; 3];
+    for row in 0..3 {
+        for col in 0..3 {
+            inv[row][col] = adjugate[row][col] / det;
+        }
+    }
+    Some(inv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec4;
+
+    fn assert_close(a: XYZ, b: XYZ, tolerance: f32) {
+        let scale = b.x.abs().max(b.y.abs()).max(b.z.abs()).max(1.0e-6);
+        assert!(
+            ((a.x - b.x).abs() / scale) < tolerance
+                && ((a.y - b.y).abs() / scale) < tolerance
+                && ((a.z - b.z).abs() / scale) < tolerance,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn standard_observer_as_a_camera() {
+        // A camera whose sensitivities are the color matching functions
+        // sees just what the standard observer does.
+        let camera = SensorResponse::Camera(CameraResponse::from_fn(|wl| {
+            [x_1931(wl), y_1931(wl), z_1931(wl)]
+        }));
+        for &wl in &[400.0, 455.5, 530.0, 612.0, 690.0] {
+            let ss = SpectralSample::from_parts(Vec4::new(1.0, 0.5, 2.0, 0.25), wl);
+            assert_close(
+                camera.to_xyz(&ss),
+                SensorResponse::Cie1931.to_xyz(&ss),
+                1.0e-2,
+            );
+        }
+    }
+
+    #[test]
+    fn cameras_keep_white_white() {
+        let camera = SensorResponse::from_spec("cmos").unwrap();
+        let mut camera_white = XYZ::new(0.0, 0.0, 0.0);
+        let mut cie_white = XYZ::new(0.0, 0.0, 0.0);
+        for i in 0..256 {
+            let ss = SpectralSample::from_value(1.0, 380.0 + (i as f32 * 320.0 / 256.0));
+            camera_white += camera.to_xyz(&ss);
+            cie_white += SensorResponse::Cie1931.to_xyz(&ss);
+        }
+        assert_close(camera_white, cie_white, 2.0e-2);
+    }
+
+    #[test]
+    fn csv_sensitivities() {
+        let camera = CameraResponse::from_csv(
+            "wavelength, r, g, b\n\
+             # Made up\n\
+             400, 0.0, 0.1, 1.0\n\
+             500, 0.1, 1.0, 0.2\n\
+             600, 1.0, 0.2, 0.0\n",
+        )
+        .unwrap();
+        let r = camera.response(550.0);
+        assert!((r[0] - 0.55).abs() < 1.0e-5 && (r[1] - 0.6).abs() < 1.0e-5);
+        assert_eq!(camera.response(650.0), [0.0; 3]);
+
+        assert!(CameraResponse::from_csv("400, 1, 1\n").is_err());
+        assert!(CameraResponse::from_csv("500, 1, 1, 1\n400, 1, 1, 1\n").is_err());
+        assert!(SensorResponse::from_spec("no_such_file.csv").is_err());
+    }
+
+    #[test]
+    fn white_balance_neutralizes_its_white() {
+        for &spec in &["d65", "a", "3200", "5600, 0.01", "9000 -0.01"] {
+            let wb = WhiteBalance::from_spec(spec).unwrap();
+            let (x, y) = wb.white();
+            let white = wb.apply(XYZ::new(x / y, 1.0, (1.0 - x - y) / y));
+            assert_close(white, XYZ::new(1.0, 1.0, 1.0), 1.0e-4);
+        }
+
+        // Equal-energy white is the output's own white, so it does nothing.
+        let color = XYZ::new(0.2, 0.5, 0.9);
+        assert_close(WhiteBalance::default().apply(color), color, 1.0e-5);
+    }
+
+    #[test]
+    fn white_balance_temperatures() {
+        // Close to the published chromaticity of a 6500 K blackbody,
+        // give or take the truncated rendered wavelength range.
+        let (x, y) = WhiteBalance::from_spec("6500").unwrap().white();
+        assert!((x - 0.3135).abs() < 0.01 && (y - 0.3237).abs() < 0.01);
+
+        // Green tints are above the locus, and magenta below.
+        let green = WhiteBalance::from_spec("6500, 0.01").unwrap().white();
+        let magenta = WhiteBalance::from_spec("6500, -0.01").unwrap().white();
+        assert!(green.1 > y && magenta.1 < y);
+
+        assert!(WhiteBalance::from_spec("sunny").is_err());
+        assert!(WhiteBalance::from_spec("20").is_err());
+        assert!(WhiteBalance::from_spec("5600, 1.0").is_err());
+    }
+}
@@ -0,0 +1,258 @@
+//! Statistical tests for the surface closures.
+//!
+//! - White furnace tests check that a closure with a white color doesn't
+//!   reflect more energy than it receives, and that `sample()` and
+//!   `evaluate()` agree about how much it reflects.
+//! - Chi-square tests check that `sample()` actually generates directions
+//!   with the pdf that it (and `evaluate()`) report.
+//!
+//! Any new closure should be added to `test_closures()`.
+
+use std::f64::consts::PI as PI_64;
+
+use crate::{
+    color::Color,
+    hash::hash_u32_to_f32,
+    math::{clamp, Normal, Vector},
+};
+
+use super::SurfaceClosure;
+
+const WAVELENGTH: f32 = 550.0;
+
+/// The closures to test, all with a white color (or no melanin).
+fn test_closures() -> Vec<SurfaceClosure> {
+    let white = Color::new_xyz((1.0, 1.0, 1.0));
+    let mut closures = vec![SurfaceClosure::Lambert(white)];
+    for &roughness in &[0.1, 0.3, 0.6, 1.0] {
+        for &fresnel in &[0.0, 1.0] {
+            closures.push(SurfaceClosure::GGX {
+                color: white,
+                roughness: roughness,
+                fresnel: fresnel,
+            });
+        }
+    }
+    // Tangents in and across the plane of the incoming rays, so that hits
+    // both at the middle and towards the edges of the fiber get tested.
+    for &tangent in &[Vector::new(1.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0)] {
+        for &(roughness, azimuthal_roughness) in &[(0.3, 0.3), (0.6, 0.8)] {
+            closures.push(SurfaceClosure::Hair {
+                color: white,
+                melanin: 0.0,
+                melanin_redness: 0.0,
+                roughness: roughness,
+                azimuthal_roughness: azimuthal_roughness,
+                tangent: tangent,
+            });
+        }
+    }
+    closures
+}
+
+/// Incoming light directions to test with, at various angles to the
+/// surface normal, which is always +z.
+fn test_incoming() -> Vec<Vector> {
+    [0.0f32, 30.0, 60.0, 80.0]
+        .iter()
+        .map(|deg| {
+            let theta = deg.to_radians();
+            Vector::new(-theta.sin(), 0.0, -theta.cos())
+        })
+        .collect()
+}
+
+fn normal() -> Normal {
+    Normal::new(0.0, 0.0, 1.0)
+}
+
+/// Pseudo-random sample values in [0, 1).
+fn uv(i: u32, seed: u32) -> (f32, f32) {
+    (hash_u32_to_f32(i, seed), hash_u32_to_f32(i, seed + 1))
+}
+
+/// The color of white, for normalizing away the spectral upsampling.
+fn white_value() -> f32 {
+    Color::new_xyz((1.0, 1.0, 1.0))
+        .to_spectral_sample(WAVELENGTH)
+        .e
+        .x()
+}
+
+/// Estimates the fraction of light reflected by the closure, using its
+/// own importance sampling.
+fn albedo_sampled(closure: &SurfaceClosure, inc: Vector, n: u32) -> f64 {
+    let mut sum = 0.0f64;
+    for i in 0..n {
+        let (_, filter, pdf) = closure.sample(inc, normal(), normal(), uv(i, 1), WAVELENGTH);
+        if pdf > 0.0 {
+            sum += (filter.e.x() / pdf) as f64;
+        }
+    }
+    sum / n as f64 / white_value() as f64
+}
+
+/// Integrates the filter returned by `evaluate()` over the sphere.
+fn albedo_evaluated(closure: &SurfaceClosure, inc: Vector) -> f64 {
+    let sum: f64 = integrate_bins(closure, inc, |filter, _| filter)
+        .iter()
+        .sum();
+    sum / white_value() as f64
+}
+
+#[test]
+fn white_furnace() {
+    const N: u32 = 100_000;
+    for closure in &test_closures() {
+        for &inc in &test_incoming() {
+            let sampled = albedo_sampled(closure, inc, N);
+            let evaluated = albedo_evaluated(closure, inc);
+
+            assert!(
+                sampled <= 1.01,
+                "{:?} gains energy with incoming {:?}: albedo {}",
+                closure,
+                inc,
+                sampled
+            );
+            assert!(
+                (sampled - evaluated).abs() <= 0.03,
+                "{:?} disagrees with itself about its albedo with incoming {:?}: \
+                 {} sampled, {} evaluated",
+                closure,
+                inc,
+                sampled,
+                evaluated
+            );
+            if let SurfaceClosure::Lambert(_) = *closure {
+                assert!((sampled - 1.0).abs() <= 0.01, "lambert albedo {}", sampled);
+            }
+        }
+    }
+}
+
+//----------------------------------------------------------------
+
+const THETA_BINS: usize = 10;
+const PHI_BINS: usize = 20;
+
+/// Index of the (cos theta, phi) bin that a direction falls in.
+fn bin_index(dir: Vector) -> usize {
+    let dir = dir.normalized();
+    let cos_theta = clamp(dir.z(), -1.0, 1.0);
+    let phi = dir.y().atan2(dir.x()) + std::f32::consts::PI;
+    let ti = (((cos_theta + 1.0) * 0.5 * THETA_BINS as f32) as usize).min(THETA_BINS - 1);
+    let pi = ((phi / (2.0 * std::f32::consts::PI) * PHI_BINS as f32) as usize).min(PHI_BINS - 1);
+    ti * PHI_BINS + pi
+}
+
+/// Integrates a function of `evaluate()`'s results over each bin.
+///
+/// Integration is done in theta rather than cos theta, so that narrow
+/// lobes near the poles are still resolved.  Phi is subdivided more finely
+/// for the hair lobes at grazing angles, which bunch up around the fiber.
+fn integrate_bins<F>(closure: &SurfaceClosure, inc: Vector, f: F) -> Vec<f64>
+where
+    F: Fn(f32, f32) -> f32,
+{
+    const THETA_SUBDIV: usize = 32;
+    const PHI_SUBDIV: usize = 64;
+    let mut sums = vec![0.0f64; THETA_BINS * PHI_BINS];
+    let d_phi = 2.0 * PI_64 / (PHI_BINS * PHI_SUBDIV) as f64;
+    for ti in 0..THETA_BINS {
+        let cos_a = -1.0 + (2.0 * ti as f64 / THETA_BINS as f64);
+        let cos_b = -1.0 + (2.0 * (ti + 1) as f64 / THETA_BINS as f64);
+        let (theta_a, theta_b) = (cos_b.acos(), cos_a.acos());
+        let d_theta = (theta_b - theta_a) / THETA_SUBDIV as f64;
+        for tj in 0..THETA_SUBDIV {
+            let theta = theta_a + (tj as f64 + 0.5) * d_theta;
+            let (sin_theta, cos_theta) = theta.sin_cos();
+            for pi in 0..(PHI_BINS * PHI_SUBDIV) {
+                let phi = (pi as f64 + 0.5) * d_phi - PI_64;
+                let out = Vector::new(
+                    (sin_theta * phi.cos()) as f32,
+                    (sin_theta * phi.sin()) as f32,
+                    cos_theta as f32,
+                );
+                let (filter, pdf) = closure.evaluate(inc, out, normal(), normal(), WAVELENGTH);
+                let value = f(filter.e.x(), pdf) as f64;
+                sums[ti * PHI_BINS + (pi / PHI_SUBDIV)] += value * sin_theta * d_theta * d_phi;
+            }
+        }
+    }
+    sums
+}
+
+/// Returns the chi-square statistic and degrees of freedom for the
+/// observed vs expected counts, pooling bins with small expected counts
+/// so the statistic stays valid.
+fn chi_square(observed: &[f64], expected: &[f64]) -> (f64, usize) {
+    const MIN_EXPECTED: f64 = 5.0;
+    let mut chi2 = 0.0;
+    let mut bins = 0;
+    let mut pooled = (0.0, 0.0);
+    for (&o, &e) in observed.iter().zip(expected.iter()) {
+        if e < MIN_EXPECTED {
+            pooled.0 += o;
+            pooled.1 += e;
+        } else {
+            chi2 += (o - e) * (o - e) / e;
+            bins += 1;
+        }
+    }
+    if pooled.1 > 0.0 {
+        chi2 += (pooled.0 - pooled.1) * (pooled.0 - pooled.1) / pooled.1;
+        bins += 1;
+    } else if pooled.0 > 0.0 {
+        // Samples where none were expected at all.
+        chi2 = f64::INFINITY;
+    }
+    (chi2, bins.max(2) - 1)
+}
+
+/// Converts a chi-square statistic into an approximate standard normal
+/// z-score with the Wilson-Hilferty transform.
+fn chi_square_z(chi2: f64, dof: usize) -> f64 {
+    let k = dof as f64;
+    let t = 2.0 / (9.0 * k);
+    ((chi2 / k).powf(1.0 / 3.0) - (1.0 - t)) / t.sqrt()
+}
+
+#[test]
+fn chi_square_sampling() {
+    const N: u32 = 100_000;
+    for closure in &test_closures() {
+        for &inc in &test_incoming() {
+            // Histogram the sampled directions.  Samples reported with a
+            // zero pdf are rejected, and get their own bin.
+            let mut observed = vec![0.0f64; THETA_BINS * PHI_BINS + 1];
+            for i in 0..N {
+                let (out, _, pdf) = closure.sample(inc, normal(), normal(), uv(i, 3), WAVELENGTH);
+                if pdf > 0.0 {
+                    observed[bin_index(out)] += 1.0;
+                } else {
+                    observed[THETA_BINS * PHI_BINS] += 1.0;
+                }
+            }
+
+            let mut expected: Vec<f64> = integrate_bins(closure, inc, |_, pdf| pdf)
+                .iter()
+                .map(|f| f * N as f64)
+                .collect();
+            let total: f64 = expected.iter().sum();
+            expected.push((N as f64 - total).max(0.0));
+
+            let (chi2, dof) = chi_square(&observed, &expected);
+            let z = chi_square_z(chi2, dof);
+            assert!(
+                z < 4.0,
+                "{:?} with incoming {:?} doesn't sample its reported pdf: \
+                 chi-square {} with {} degrees of freedom",
+                closure,
+                inc,
+                chi2,
+                dof
+            );
+        }
+    }
+}
@@ -1,16 +1,298 @@
+pub mod spaces;
 pub mod surface_closure;
 
-use std::fmt::Debug;
+#[cfg(test)]
+mod closure_tests;
 
-use crate::{color::Color, surface::SurfaceIntersectionData};
+use std::{f32::consts::PI as PI_32, fmt::Debug};
 
-pub use self::surface_closure::SurfaceClosure;
+use crate::{
+    color::{rec709_e_to_xyz, xyz_to_rec709_e, Color},
+    math::{clamp, dot, Point},
+    surface::{
+        primvar::{HitPrimvars, PrimvarValue},
+        SurfaceIntersectionData,
+    },
+};
+
+pub use self::{spaces::CoordinateSpaces, surface_closure::SurfaceClosure};
 
 /// Trait for surface shaders.
 pub trait SurfaceShader: Debug + Sync {
     /// Takes the result of a surface intersection and returns the surface
     /// closure to be evaluated at that intersection point.
-    fn shade(&self, data: &SurfaceIntersectionData, time: f32) -> SurfaceClosure;
+    ///
+    /// `spaces` are the coordinate spaces that can be looked up by name at
+    /// the intersection, for e.g. projections and procedural textures, and
+    /// `primvars` are the surface's primvars there.
+    fn shade(
+        &self,
+        data: &SurfaceIntersectionData,
+        spaces: &CoordinateSpaces,
+        primvars: &HitPrimvars,
+        time: f32,
+    ) -> SurfaceClosure;
+
+    /// Whether camera rays should pass through the backs of surfaces
+    /// that use this shader.
+    fn backface_cull(&self) -> bool {
+        false
+    }
+
+    /// The furthest this shader may displace a surface from its
+    /// geometry, in the surface's object space.  Bounds of surfaces that
+    /// use the shader are padded by this much.
+    fn displacement_bound(&self) -> f32 {
+        0.0
+    }
+
+    /// Whether surfaces that use this shader can be partly transparent,
+    /// and therefore need `opacity()` checked for each of their hits.
+    fn has_opacity(&self) -> bool {
+        false
+    }
+
+    /// The "any-hit" shader: how opaque the surface is at the given
+    /// world-space point, from 0.0 (invisible) to 1.0 (fully opaque).
+    /// `primvars` are the surface's primvars there.
+    ///
+    /// Unlike `shade()`, this is run for every hit a ray finds, including
+    /// hits that turn out not to be the nearest and the hits of occlusion
+    /// rays, so it should be cheap.
+    fn opacity(&self, _pos: Point, _primvars: &HitPrimvars, _time: f32) -> f32 {
+        1.0
+    }
+}
+
+/// Wraps another shader with a declared displacement bound.
+#[derive(Debug, Copy, Clone)]
+pub struct DisplacedSurfaceShader<S: SurfaceShader> {
+    pub shader: S,
+    pub displacement_bound: f32,
+}
+
+impl<S: SurfaceShader> SurfaceShader for DisplacedSurfaceShader<S> {
+    fn shade(
+        &self,
+        data: &SurfaceIntersectionData,
+        spaces: &CoordinateSpaces,
+        primvars: &HitPrimvars,
+        time: f32,
+    ) -> SurfaceClosure {
+        self.shader.shade(data, spaces, primvars, time)
+    }
+
+    fn backface_cull(&self) -> bool {
+        self.shader.backface_cull()
+    }
+
+    fn displacement_bound(&self) -> f32 {
+        self.displacement_bound
+    }
+
+    fn has_opacity(&self) -> bool {
+        self.shader.has_opacity()
+    }
+
+    fn opacity(&self, pos: Point, primvars: &HitPrimvars, time: f32) -> f32 {
+        self.shader.opacity(pos, primvars, time)
+    }
+}
+
+/// Wraps another shader with a uniform opacity, so that surfaces using
+/// it are partly see-through, shadows included.
+#[derive(Debug, Copy, Clone)]
+pub struct OpacitySurfaceShader<S: SurfaceShader> {
+    pub shader: S,
+    pub opacity: f32,
+}
+
+impl<S: SurfaceShader> SurfaceShader for OpacitySurfaceShader<S> {
+    fn shade(
+        &self,
+        data: &SurfaceIntersectionData,
+        spaces: &CoordinateSpaces,
+        primvars: &HitPrimvars,
+        time: f32,
+    ) -> SurfaceClosure {
+        self.shader.shade(data, spaces, primvars, time)
+    }
+
+    fn backface_cull(&self) -> bool {
+        self.shader.backface_cull()
+    }
+
+    fn displacement_bound(&self) -> f32 {
+        self.shader.displacement_bound()
+    }
+
+    fn has_opacity(&self) -> bool {
+        self.opacity < 1.0 || self.shader.has_opacity()
+    }
+
+    fn opacity(&self, pos: Point, primvars: &HitPrimvars, time: f32) -> f32 {
+        self.opacity * self.shader.opacity(pos, primvars, time)
+    }
+}
+
+/// Wraps another shader with options for how the backs of surfaces are
+/// treated.  The back of a surface is the side its geometric normal
+/// points away from.
+#[derive(Debug, Copy, Clone)]
+pub struct SidedSurfaceShader<S: SurfaceShader> {
+    pub shader: S,
+    pub double_sided: bool,  // If false, the backs of surfaces are black
+    pub backface_cull: bool, // If true, camera rays pass through the backs of surfaces
+}
+
+impl<S: SurfaceShader> SurfaceShader for SidedSurfaceShader<S> {
+    fn shade(
+        &self,
+        data: &SurfaceIntersectionData,
+        spaces: &CoordinateSpaces,
+        primvars: &HitPrimvars,
+        time: f32,
+    ) -> SurfaceClosure {
+        if !self.double_sided && dot(data.nor_g.into_vector(), data.incoming) > 0.0 {
+            return SurfaceClosure::Emit(Color::new_xyz((0.0, 0.0, 0.0)));
+        }
+        self.shader.shade(data, spaces, primvars, time)
+    }
+
+    fn backface_cull(&self) -> bool {
+        self.backface_cull
+    }
+
+    fn displacement_bound(&self) -> f32 {
+        self.shader.displacement_bound()
+    }
+
+    fn has_opacity(&self) -> bool {
+        self.shader.has_opacity()
+    }
+
+    fn opacity(&self, pos: Point, primvars: &HitPrimvars, time: f32) -> f32 {
+        self.shader.opacity(pos, primvars, time)
+    }
+}
+
+/// Wraps another shader with random variation of its hue and roughness
+/// for each instance, to break up the repetition of instanced objects.
+#[derive(Debug, Copy, Clone)]
+pub struct VariedSurfaceShader<S: SurfaceShader> {
+    pub shader: S,
+    pub hue_variation: f32, // Most the hue is rotated either way, in turns
+    pub roughness_variation: f32, // Most the roughness is changed either way
+}
+
+impl<S: SurfaceShader> SurfaceShader for VariedSurfaceShader<S> {
+    fn shade(
+        &self,
+        data: &SurfaceIntersectionData,
+        spaces: &CoordinateSpaces,
+        primvars: &HitPrimvars,
+        time: f32,
+    ) -> SurfaceClosure {
+        let closure = self.shader.shade(data, spaces, primvars, time);
+        if self.hue_variation == 0.0 && self.roughness_variation == 0.0 {
+            return closure;
+        }
+
+        let turns = (data.instance_random(0) * 2.0 - 1.0) * self.hue_variation;
+        let vary_roughness = |roughness: f32| {
+            let offset = (data.instance_random(1) * 2.0 - 1.0) * self.roughness_variation;
+            clamp(roughness + offset, 0.0, 1.0)
+        };
+        match closure {
+            SurfaceClosure::Lambert(color) => SurfaceClosure::Lambert(rotate_hue(color, turns)),
+            SurfaceClosure::GGX {
+                color,
+                roughness,
+                fresnel,
+            } => SurfaceClosure::GGX {
+                color: rotate_hue(color, turns),
+                roughness: vary_roughness(roughness),
+                fresnel: fresnel,
+            },
+            SurfaceClosure::Hair {
+                color,
+                melanin,
+                melanin_redness,
+                roughness,
+                azimuthal_roughness,
+                tangent,
+            } => SurfaceClosure::Hair {
+                color: rotate_hue(color, turns),
+                melanin: melanin,
+                melanin_redness: melanin_redness,
+                roughness: vary_roughness(roughness),
+                azimuthal_roughness: azimuthal_roughness,
+                tangent: tangent,
+            },
+            SurfaceClosure::Emit(color) => SurfaceClosure::Emit(rotate_hue(color, turns)),
+        }
+    }
+
+    fn backface_cull(&self) -> bool {
+        self.shader.backface_cull()
+    }
+
+    fn displacement_bound(&self) -> f32 {
+        self.shader.displacement_bound()
+    }
+
+    fn has_opacity(&self) -> bool {
+        self.shader.has_opacity()
+    }
+
+    fn opacity(&self, pos: Point, primvars: &HitPrimvars, time: f32) -> f32 {
+        self.shader.opacity(pos, primvars, time)
+    }
+}
+
+/// Rotates the hue of a color by `turns`, around the gray axis of
+/// Rec.709 with an equal-energy white point.  Only XYZ colors have a hue
+/// to rotate, and others are returned as they are.
+fn rotate_hue(color: Color, turns: f32) -> Color {
+    let (r, g, b) = match color {
+        Color::XYZ(x, y, z) if turns != 0.0 => xyz_to_rec709_e((x, y, z)),
+        _ => return color,
+    };
+
+    // Rotation about the (1, 1, 1) axis.
+    let (sin, cos) = (turns * 2.0 * PI_32).sin_cos();
+    let k = (1.0 - cos) / 3.0;
+    let s = sin * (1.0f32 / 3.0).sqrt();
+    let (m0, m1, m2) = (cos + k, k - s, k + s);
+    Color::new_xyz(rec709_e_to_xyz((
+        ((r * m0) + (g * m1) + (b * m2)).max(0.0),
+        ((r * m2) + (g * m0) + (b * m1)).max(0.0),
+        ((r * m1) + (g * m2) + (b * m0)).max(0.0),
+    )))
+}
+
+/// Shows surfaces' shading normals as colors, for checking geometry.
+///
+/// Each component of the normal is mapped from [-1, 1] to [0, 1] and
+/// emitted as Rec.709 red, green, and blue.
+#[derive(Debug, Copy, Clone)]
+pub struct NormalSurfaceShader;
+
+impl SurfaceShader for NormalSurfaceShader {
+    fn shade(
+        &self,
+        data: &SurfaceIntersectionData,
+        _spaces: &CoordinateSpaces,
+        _primvars: &HitPrimvars,
+        _time: f32,
+    ) -> SurfaceClosure {
+        let n = data.nor.normalized();
+        SurfaceClosure::Emit(Color::new_xyz(rec709_e_to_xyz((
+            (n.x() + 1.0) * 0.5,
+            (n.y() + 1.0) * 0.5,
+            (n.z() + 1.0) * 0.5,
+        ))))
+    }
 }
 
 /// Clearly we must eat this brownie before the world ends, lest it
@@ -37,11 +319,24 @@ pub enum SimpleSurfaceShader {
         roughness: f32,
         fresnel: f32,
     },
+    Hair {
+        color: Color,
+        melanin: f32,
+        melanin_redness: f32,
+        roughness: f32,
+        azimuthal_roughness: f32,
+    },
 }
 
 impl SurfaceShader for SimpleSurfaceShader {
-    fn shade(&self, data: &SurfaceIntersectionData, time: f32) -> SurfaceClosure {
-        let _ = (data, time); // Silence "unused" compiler warning
+    fn shade(
+        &self,
+        data: &SurfaceIntersectionData,
+        spaces: &CoordinateSpaces,
+        primvars: &HitPrimvars,
+        time: f32,
+    ) -> SurfaceClosure {
+        let _ = (data, spaces, primvars, time); // Silence "unused" compiler warning
 
         match *self {
             SimpleSurfaceShader::Emit { color } => SurfaceClosure::Emit(color),
@@ -57,6 +352,157 @@ impl SurfaceShader for SimpleSurfaceShader {
                 roughness: roughness,
                 fresnel: fresnel,
             },
+
+            // Strands can vary their color and melanin with primvars.
+            SimpleSurfaceShader::Hair {
+                color,
+                melanin,
+                melanin_redness,
+                roughness,
+                azimuthal_roughness,
+            } => SurfaceClosure::Hair {
+                color: match primvars.get("color") {
+                    Some(PrimvarValue::Float3(r, g, b)) => {
+                        Color::new_xyz(rec709_e_to_xyz((r, g, b)))
+                    }
+                    _ => color,
+                },
+                melanin: match primvars.get("melanin") {
+                    Some(PrimvarValue::Float(m)) => clamp(m, 0.0, 1.0),
+                    _ => melanin,
+                },
+                melanin_redness: melanin_redness,
+                roughness: roughness,
+                azimuthal_roughness: azimuthal_roughness,
+                tangent: data.dpdu,
+            },
+        }
+    }
+
+    fn has_opacity(&self) -> bool {
+        matches!(self, SimpleSurfaceShader::Hair { .. })
+    }
+
+    /// Hair can fade out towards its tips with an "opacity" primvar.
+    fn opacity(&self, _pos: Point, primvars: &HitPrimvars, _time: f32) -> f32 {
+        match (self, primvars.get("opacity")) {
+            (SimpleSurfaceShader::Hair { .. }, Some(PrimvarValue::Float(opacity))) => opacity,
+            _ => 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::{Matrix4x4, Normal, Point, Vector};
+
+    fn hit(incoming: Vector) -> SurfaceIntersectionData {
+        SurfaceIntersectionData {
+            incoming: incoming,
+            pos: Point::new(0.0, 0.0, 0.0),
+            pos_err: 0.0,
+            nor: Normal::new(0.0, 0.0, 1.0),
+            nor_g: Normal::new(0.0, 0.0, 1.0),
+            uv: (0.0, 0.0),
+            dpdu: Vector::new(1.0, 0.0, 0.0),
+            dpdv: Vector::new(0.0, 1.0, 0.0),
+            local_space: Matrix4x4::new(),
+            t: 1.0,
+            footprint: 0.0,
+            sample_pdf: 0.0,
+            object_id: 0,
+            instance_id: 0,
+            instance_index: 0,
         }
     }
+
+    fn is_lambert(closure: SurfaceClosure) -> bool {
+        matches!(closure, SurfaceClosure::Lambert(_))
+    }
+
+    #[test]
+    fn single_sided_backs_are_black() {
+        let front = hit(Vector::new(0.0, 0.0, -1.0));
+        let back = hit(Vector::new(0.0, 0.0, 1.0));
+        let spaces = CoordinateSpaces::builtin(0.0);
+        let primvars = HitPrimvars::none();
+        let lambert = SimpleSurfaceShader::Lambert {
+            color: Color::new_xyz((1.0, 1.0, 1.0)),
+        };
+
+        let double = SidedSurfaceShader {
+            shader: lambert,
+            double_sided: true,
+            backface_cull: false,
+        };
+        assert!(is_lambert(double.shade(&front, &spaces, &primvars, 0.0)));
+        assert!(is_lambert(double.shade(&back, &spaces, &primvars, 0.0)));
+
+        let single = SidedSurfaceShader {
+            shader: lambert,
+            double_sided: false,
+            backface_cull: true,
+        };
+        assert!(single.backface_cull());
+        assert!(is_lambert(single.shade(&front, &spaces, &primvars, 0.0)));
+        assert!(!is_lambert(single.shade(&back, &spaces, &primvars, 0.0)));
+    }
+
+    #[test]
+    fn instances_vary_hue_and_roughness() {
+        let spaces = CoordinateSpaces::builtin(0.0);
+        let primvars = HitPrimvars::none();
+        let shader = VariedSurfaceShader {
+            shader: SimpleSurfaceShader::GGX {
+                color: Color::new_xyz(rec709_e_to_xyz((0.8, 0.2, 0.1))),
+                roughness: 0.5,
+                fresnel: 1.0,
+            },
+            hue_variation: 0.5,
+            roughness_variation: 0.25,
+        };
+        let shade = |instance_id| {
+            let mut data = hit(Vector::new(0.0, 0.0, -1.0));
+            data.instance_id = instance_id;
+            match shader.shade(&data, &spaces, &primvars, 0.0) {
+                SurfaceClosure::GGX {
+                    color: Color::XYZ(x, y, z),
+                    roughness,
+                    ..
+                } => ((x, y, z), roughness),
+                _ => panic!("expected a GGX closure"),
+            }
+        };
+
+        // The same instance always looks the same, and different ones
+        // differ within the variation.
+        assert_eq!(shade(7), shade(7));
+        let looks: Vec<_> = (0..16).map(shade).collect();
+        assert!(looks.iter().any(|look| look.0 != looks[0].0));
+        assert!(looks.iter().any(|look| look.1 != looks[0].1));
+        assert!(looks.iter().all(|look| (look.1 - 0.5).abs() <= 0.25));
+    }
+
+    #[test]
+    fn hue_rotation() {
+        let red = Color::new_xyz(rec709_e_to_xyz((1.0, 0.0, 0.0)));
+        let rgb = |color| match color {
+            Color::XYZ(x, y, z) => xyz_to_rec709_e((x, y, z)),
+            _ => panic!("expected an XYZ color"),
+        };
+
+        // A third of a turn takes red to green, and a whole turn back.
+        let green = rgb(rotate_hue(red, 1.0 / 3.0));
+        assert!(green.0.abs() < 1.0e-3 && (green.1 - 1.0).abs() < 1.0e-3 && green.2.abs() < 1.0e-3);
+        let red_again = rgb(rotate_hue(red, 1.0));
+        assert!((red_again.0 - 1.0).abs() < 1.0e-3 && red_again.1.abs() < 1.0e-3);
+
+        // Grays have no hue.
+        let gray = rgb(rotate_hue(
+            Color::new_xyz(rec709_e_to_xyz((0.5, 0.5, 0.5))),
+            0.3,
+        ));
+        assert!((gray.0 - 0.5).abs() < 1.0e-3 && (gray.2 - 0.5).abs() < 1.0e-3);
+    }
 }
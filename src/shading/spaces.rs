@@ -0,0 +1,185 @@
+//! Coordinate spaces that shaders can look up by name.
+//!
+//! Besides the built-in "world" and "object" spaces, assemblies can declare
+//! their own named spaces, e.g. for a rig or a prop, so that projections
+//! and procedural textures can be anchored to them.  A space declared in an
+//! assembly is visible to the shading of everything in that assembly,
+//! including nested assemblies, which can shadow it with a space of the
+//! same name.
+
+#![allow(dead_code)]
+
+use crate::{
+    lerp::lerp_slice,
+    math::{Matrix4x4, Point},
+    surface::SurfaceIntersectionData,
+};
+
+/// A space declared in an assembly.
+#[derive(Copy, Clone, Debug)]
+pub struct NamedSpace<'a> {
+    pub name: &'a str,
+    pub xforms: &'a [Matrix4x4], // Assembly-to-space transform samples
+}
+
+/// An instance of an assembly with named spaces, on the path to a hit.
+#[derive(Copy, Clone, Debug)]
+pub struct SpaceScope<'a> {
+    pub spaces: &'a [NamedSpace<'a>],
+    pub xforms: (usize, usize), // World-to-assembly transform samples, in `CoordinateSpaces::xforms`
+    pub parent: Option<usize>,  // Index of the enclosing scope
+}
+
+/// The coordinate spaces visible to the shading of a hit.
+#[derive(Copy, Clone, Debug)]
+pub struct CoordinateSpaces<'a> {
+    scopes: &'a [SpaceScope<'a>],
+    xforms: &'a [Matrix4x4],
+    scope: Option<usize>, // Innermost scope of the hit
+    time: f32,
+}
+
+impl<'a> CoordinateSpaces<'a> {
+    pub fn new(
+        scopes: &'a [SpaceScope<'a>],
+        xforms: &'a [Matrix4x4],
+        scope: Option<usize>,
+        time: f32,
+    ) -> CoordinateSpaces<'a> {
+        CoordinateSpaces {
+            scopes: scopes,
+            xforms: xforms,
+            scope: scope,
+            time: time,
+        }
+    }
+
+    /// Only the built-in spaces, for shading outside of any assembly.
+    pub fn builtin(time: f32) -> CoordinateSpaces<'static> {
+        CoordinateSpaces {
+            scopes: &[],
+            xforms: &[],
+            scope: None,
+            time: time,
+        }
+    }
+
+    /// The world-to-space transform of the named space at the hit, or
+    /// `None` if there's no such space.
+    pub fn world_to(&self, name: &str, data: &SurfaceIntersectionData) -> Option<Matrix4x4> {
+        match name {
+            "world" => return Some(Matrix4x4::new()),
+            "object" => return Some(data.local_space),
+            _ => {}
+        }
+
+        let mut scope_i = self.scope;
+        while let Some(i) = scope_i {
+            let scope = &self.scopes[i];
+            if let Some(space) = scope.spaces.iter().find(|space| space.name == name) {
+                let to_space = lerp_slice(space.xforms, self.time);
+                let xforms = &self.xforms[scope.xforms.0..scope.xforms.1];
+                return Some(if xforms.is_empty() {
+                    to_space
+                } else {
+                    lerp_slice(xforms, self.time) * to_space
+                });
+            }
+            scope_i = scope.parent;
+        }
+
+        None
+    }
+
+    /// The hit's position in the named space, or `None` if there's no
+    /// such space.
+    pub fn point_in(&self, name: &str, data: &SurfaceIntersectionData) -> Option<Point> {
+        self.world_to(name, data).map(|xform| data.pos * xform)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::{Normal, Vector};
+
+    fn data_at(pos: Point) -> SurfaceIntersectionData {
+        let nor = Normal::new(0.0, 0.0, 1.0);
+        SurfaceIntersectionData {
+            incoming: Vector::new(0.0, 0.0, -1.0),
+            pos: pos,
+            pos_err: 0.0,
+            nor: nor,
+            nor_g: nor,
+            uv: (0.0, 0.0),
+            dpdu: Vector::new(1.0, 0.0, 0.0),
+            dpdv: Vector::new(0.0, 1.0, 0.0),
+            local_space: Matrix4x4::from_location(Point::new(0.0, 0.0, -5.0)),
+            t: 1.0,
+            footprint: 0.0,
+            sample_pdf: 0.0,
+            object_id: 0,
+            instance_id: 0,
+            instance_index: 0,
+        }
+    }
+
+    #[test]
+    fn builtin_spaces() {
+        let spaces = CoordinateSpaces::builtin(0.0);
+        let data = data_at(Point::new(1.0, 2.0, 3.0));
+
+        let p = spaces.point_in("world", &data).unwrap();
+        assert_eq!((p.x(), p.y(), p.z()), (1.0, 2.0, 3.0));
+        let p = spaces.point_in("object", &data).unwrap();
+        assert_eq!((p.x(), p.y(), p.z()), (1.0, 2.0, -2.0));
+        assert!(spaces.point_in("rig", &data).is_none());
+    }
+
+    #[test]
+    fn named_spaces_nest_and_shadow() {
+        let to_rig = [Matrix4x4::from_location(Point::new(10.0, 0.0, 0.0))];
+        let to_prop = [Matrix4x4::from_location(Point::new(0.0, 10.0, 0.0))];
+        let outer = [
+            NamedSpace {
+                name: "rig",
+                xforms: &to_rig,
+            },
+            NamedSpace {
+                name: "prop",
+                xforms: &to_prop,
+            },
+        ];
+        let inner = [NamedSpace {
+            name: "prop",
+            xforms: &to_rig,
+        }];
+        let xforms = [Matrix4x4::from_location(Point::new(0.0, 0.0, 1.0))];
+        let scopes = [
+            SpaceScope {
+                spaces: &outer,
+                xforms: (0, 0),
+                parent: None,
+            },
+            SpaceScope {
+                spaces: &inner,
+                xforms: (0, 1),
+                parent: Some(0),
+            },
+        ];
+        let data = data_at(Point::new(0.0, 0.0, 0.0));
+
+        // Outside the inner assembly, only the outer spaces are visible.
+        let spaces = CoordinateSpaces::new(&scopes, &xforms, Some(0), 0.0);
+        let p = spaces.point_in("prop", &data).unwrap();
+        assert_eq!((p.x(), p.y(), p.z()), (0.0, 10.0, 0.0));
+
+        // Inside it, its own "prop" shadows the outer one, and is placed
+        // by the inner assembly's instance transform.
+        let spaces = CoordinateSpaces::new(&scopes, &xforms, Some(1), 0.0);
+        let p = spaces.point_in("prop", &data).unwrap();
+        assert_eq!((p.x(), p.y(), p.z()), (10.0, 0.0, 1.0));
+        let p = spaces.point_in("rig", &data).unwrap();
+        assert_eq!((p.x(), p.y(), p.z()), (10.0, 0.0, 0.0));
+    }
+}
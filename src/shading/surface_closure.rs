@@ -7,7 +7,7 @@ use glam::Vec4;
 use crate::{
     color::{Color, SpectralSample},
     lerp::{lerp, Lerp},
-    math::{clamp, dot, zup_to_vec, Normal, Vector},
+    math::{clamp, coordinate_system_from_vector, cross, dot, zup_to_vec, Normal, Vector},
     sampling::cosine_sample_hemisphere,
 };
 
@@ -24,6 +24,14 @@ pub enum SurfaceClosure {
         roughness: f32,
         fresnel: f32, // [0.0, 1.0] determines how much fresnel reflection comes into play
     },
+    Hair {
+        color: Color,             // Dye on top of the melanin, white for none
+        melanin: f32,             // [0.0, 1.0] from white to black hair
+        melanin_redness: f32,     // [0.0, 1.0] fraction of the melanin that's pheomelanin
+        roughness: f32,           // [0.0, 1.0] longitudinal roughness
+        azimuthal_roughness: f32, // [0.0, 1.0]
+        tangent: Vector,          // Direction the fiber runs in
+    },
 
     // Special closures that need special handling by the renderer.
     Emit(Color),
@@ -40,6 +48,7 @@ impl SurfaceClosure {
         match *self {
             Lambert(_) => false,
             GGX { roughness, .. } => roughness == 0.0,
+            Hair { .. } => false,
             Emit(_) => false,
         }
     }
@@ -71,6 +80,27 @@ impl SurfaceClosure {
                 fresnel,
             } => ggx_closure::sample(color, roughness, fresnel, inc, nor, nor_g, uv, wavelength),
 
+            Hair {
+                color,
+                melanin,
+                melanin_redness,
+                roughness,
+                azimuthal_roughness,
+                tangent,
+            } => hair_closure::sample(
+                color,
+                melanin,
+                melanin_redness,
+                roughness,
+                azimuthal_roughness,
+                tangent,
+                inc,
+                nor,
+                nor_g,
+                uv,
+                wavelength,
+            ),
+
             Emit(color) => emit_closure::sample(color, inc, nor, nor_g, uv, wavelength),
         }
     }
@@ -102,6 +132,27 @@ impl SurfaceClosure {
                 fresnel,
             } => ggx_closure::evaluate(color, roughness, fresnel, inc, out, nor, nor_g, wavelength),
 
+            Hair {
+                color,
+                melanin,
+                melanin_redness,
+                roughness,
+                azimuthal_roughness,
+                tangent,
+            } => hair_closure::evaluate(
+                color,
+                melanin,
+                melanin_redness,
+                roughness,
+                azimuthal_roughness,
+                tangent,
+                inc,
+                out,
+                nor,
+                nor_g,
+                wavelength,
+            ),
+
             Emit(color) => emit_closure::evaluate(color, inc, out, nor, nor_g, wavelength),
         }
     }
@@ -143,6 +194,13 @@ impl SurfaceClosure {
                 nor,
                 nor_g,
             ),
+            Hair { .. } => hair_closure::estimate_eval_over_sphere_light(
+                inc,
+                to_light_center,
+                light_radius_squared,
+                nor,
+                nor_g,
+            ),
             Emit(color) => emit_closure::estimate_eval_over_sphere_light(
                 color,
                 inc,
@@ -163,6 +221,11 @@ impl SurfaceClosure {
                 + 2 // Fresnel
                 + color.compressed_size() // Color
             }
+            Hair { color, .. } => {
+                2 * 4 // Melanin, melanin redness, and roughnesses
+                + 4 // Tangent
+                + color.compressed_size() // Color
+            }
             Emit(color) => color.compressed_size(),
         }
     }
@@ -204,6 +267,29 @@ impl SurfaceClosure {
                 out_data[0] = 2; // Discriminant
                 color.write_compressed(&mut out_data[1..]);
             }
+            Hair {
+                color,
+                melanin,
+                melanin_redness,
+                roughness,
+                azimuthal_roughness,
+                tangent,
+            } => {
+                out_data[0] = 3; // Discriminant
+
+                let params = [melanin, melanin_redness, roughness, azimuthal_roughness];
+                for (i, param) in params.iter().enumerate() {
+                    let bytes =
+                        ((param.max(0.0).min(1.0) * std::u16::MAX as f32) as u16).to_le_bytes();
+                    out_data[1 + (i * 2)] = bytes[0];
+                    out_data[2 + (i * 2)] = bytes[1];
+                }
+
+                let tan = oct32norm::encode((tangent.x(), tangent.y(), tangent.z())).to_le_bytes();
+                out_data[9..13].copy_from_slice(&tan);
+
+                color.write_compressed(&mut out_data[13..]);
+            }
         }
         self.compressed_size()
     }
@@ -245,6 +331,29 @@ impl SurfaceClosure {
                 (SurfaceClosure::Emit(col), 1 + size)
             }
 
+            3 => {
+                // Hair
+                let param = |i: usize| {
+                    let bytes = [in_data[1 + (i * 2)], in_data[2 + (i * 2)]];
+                    u16::from_le_bytes(bytes) as f32 * (1.0 / std::u16::MAX as f32)
+                };
+                let mut tan = [0u8; 4];
+                tan.copy_from_slice(&in_data[9..13]);
+                let tan = oct32norm::decode(u32::from_le_bytes(tan));
+                let (col, size) = Color::from_compressed(&in_data[13..]);
+                (
+                    SurfaceClosure::Hair {
+                        color: col,
+                        melanin: param(0),
+                        melanin_redness: param(1),
+                        roughness: param(2),
+                        azimuthal_roughness: param(3),
+                        tangent: Vector::new(tan.0, tan.1, tan.2).normalized(),
+                    },
+                    13 + size,
+                )
+            }
+
             _ => unreachable!(),
         }
     }
@@ -270,6 +379,31 @@ impl Lerp for SurfaceClosure {
                 roughness: lerp(rgh1, rgh2, alpha),
                 fresnel: lerp(frs1, frs2, alpha),
             },
+            (
+                Hair {
+                    color: col1,
+                    melanin: mel1,
+                    melanin_redness: red1,
+                    roughness: rgh1,
+                    azimuthal_roughness: azi1,
+                    tangent: tan1,
+                },
+                Hair {
+                    color: col2,
+                    melanin: mel2,
+                    melanin_redness: red2,
+                    roughness: rgh2,
+                    azimuthal_roughness: azi2,
+                    tangent: tan2,
+                },
+            ) => Hair {
+                color: lerp(col1, col2, alpha),
+                melanin: lerp(mel1, mel2, alpha),
+                melanin_redness: lerp(red1, red2, alpha),
+                roughness: lerp(rgh1, rgh2, alpha),
+                azimuthal_roughness: lerp(azi1, azi2, alpha),
+                tangent: lerp(tan1, tan2, alpha),
+            },
             (Emit(col1), Emit(col2)) => Emit(lerp(col1, col2, alpha)),
 
             _ => panic!("Cannot lerp between different surface closure types."),
@@ -305,7 +439,7 @@ mod lambert_closure {
         if dot(flipped_nor_g, out) >= 0.0 {
             (out, color.to_spectral_sample(wavelength) * pdf, pdf)
         } else {
-            (out, SpectralSample::new(0.0), 0.0)
+            (out, SpectralSample::new(wavelength), 0.0)
         }
     }
 
@@ -327,7 +461,7 @@ mod lambert_closure {
             let fac = dot(nn, out.normalized()).max(0.0) * INV_PI;
             (color.to_spectral_sample(wavelength) * fac, fac)
         } else {
-            (SpectralSample::new(0.0), 0.0)
+            (SpectralSample::new(wavelength), 0.0)
         }
     }
 
@@ -449,7 +583,7 @@ mod ggx_closure {
             let (filter, pdf) = evaluate(col, roughness, fresnel, inc, out, nor, nor_g, wavelength);
             (out, filter, pdf)
         } else {
-            (out, SpectralSample::new(0.0), 0.0)
+            (out, SpectralSample::new(wavelength), 0.0)
         }
     }
 
@@ -477,7 +611,7 @@ mod ggx_closure {
 
         // Make sure everything's on the correct side of the surface
         if dot(nn, aa) < 0.0 || dot(nn, bb) < 0.0 || dot(flipped_nor_g, bb) < 0.0 {
-            return (SpectralSample::new(0.0), 0.0);
+            return (SpectralSample::new(wavelength), 0.0);
         }
 
         // Calculate needed dot products
@@ -521,14 +655,20 @@ mod ggx_closure {
             return (col_f, 0.0);
         } else {
             // Calculate D - Distribution
-            let dist = ggx_d(nh, roughness) / na;
+            let dist = ggx_d(nh, roughness);
 
             // Calculate G1 and G2- Geometric microfacet shadowing
             let g1 = ggx_g(ha, na, roughness);
             let g2 = ggx_g(hb, nb, roughness);
 
-            // Final result
-            (col_f * (dist * g1 * g2) * INV_PI, dist * INV_PI)
+            // Final result.  The filter is the brdf times the cosine
+            // factor, and the pdf is that of sampling the half vector
+            // proportional to `dist * nh`, converted to the pdf of the
+            // reflected direction.
+            (
+                col_f * (dist * g1 * g2 / (4.0 * na)),
+                dist * nh / (4.0 * hb),
+            )
         }
     }
 
@@ -632,6 +772,462 @@ mod ggx_closure {
     }
 }
 
+/// Hair closure code.
+///
+/// This is the hair model from "A Practical and Controllable Hair and Fur
+/// Model for Production Path Tracing" by Chiang et al., which builds on
+/// "An Energy-Conserving Hair Reflectance Model" by d'Eon et al.  Light
+/// is scattered by the fiber in lobes for each path through it: reflection
+/// off the surface (R), transmission straight through (TT), transmission
+/// after one internal reflection (TRT), and a catch-all for the remaining
+/// longer paths.  The hair's color comes from the melanin in it, along
+/// with an optional dye.
+///
+/// Rather than being on one side of a surface, the fiber scatters light in
+/// all directions, and the offset across it that the hit is at is found
+/// from the angle between the shading normal and the incoming ray.  Only
+/// the tangent and normal are used, so the geometric normal is ignored.
+mod hair_closure {
+    use super::*;
+
+    const ETA: f32 = 1.55; // Index of refraction of keratin
+    const SCALE_TILT: f32 = 2.0 * PI_32 / 180.0; // Angle the cuticle scales tilt the lobes by
+    const P_MAX: usize = 3; // Number of lobes with their own paths, the rest are lumped together
+    const MIN_ROUGHNESS: f32 = 0.01; // The lobes are degenerate at zero roughness
+    const SQRT_PI_OVER_8: f32 = 0.626_657_07;
+
+    pub fn sample(
+        color: Color,
+        melanin: f32,
+        melanin_redness: f32,
+        roughness: f32,
+        azimuthal_roughness: f32,
+        tangent: Vector,
+        inc: Vector,
+        nor: Normal,
+        nor_g: Normal,
+        uv: (f32, f32),
+        wavelength: f32,
+    ) -> (Vector, SpectralSample, f32) {
+        let _ = nor_g; // Not using this, silence warning
+
+        let fiber = Fiber::new(
+            color,
+            melanin,
+            melanin_redness,
+            roughness,
+            azimuthal_roughness,
+            tangent,
+            inc,
+            nor,
+            wavelength,
+        );
+
+        // Pick a lobe, and reuse what's left of the sample value for the
+        // azimuth.
+        let mut u_lobe = uv.0;
+        let mut p = 0;
+        while p < P_MAX && u_lobe >= fiber.lobe_pdf[p] {
+            u_lobe -= fiber.lobe_pdf[p];
+            p += 1;
+        }
+        let u_phi = if fiber.lobe_pdf[p] > 0.0 {
+            (u_lobe / fiber.lobe_pdf[p]).min(1.0)
+        } else {
+            0.5
+        };
+
+        // Longitudinal angle.
+        let (u_m0, u_m1) = demux_float(uv.1);
+        let v = fiber.v[p];
+        let u_m0 = u_m0.max(1.0e-5);
+        let cos_theta = 1.0 + v * (u_m0 + ((1.0 - u_m0) * (-2.0 / v).exp())).ln();
+        let sin_theta = (1.0 - (cos_theta * cos_theta)).max(0.0).sqrt();
+        let cos_phi = (2.0 * PI_32 * u_m1).cos();
+        let (sin_theta_op, cos_theta_op) = fiber.tilted(p);
+        let sin_theta_i = clamp(
+            (-cos_theta * sin_theta_op) + (sin_theta * cos_phi * cos_theta_op),
+            -1.0,
+            1.0,
+        );
+        let cos_theta_i = (1.0 - (sin_theta_i * sin_theta_i)).sqrt();
+
+        // Azimuthal angle.
+        let d_phi = if p < P_MAX {
+            lobe_phi(p, fiber.gamma_o, fiber.gamma_t)
+                + sample_trimmed_logistic(u_phi, fiber.s, -PI_32, PI_32)
+        } else {
+            2.0 * PI_32 * u_phi
+        };
+        let (sin_phi_i, cos_phi_i) = (fiber.phi_o + d_phi).sin_cos();
+
+        let out = (fiber.x * sin_theta_i)
+            + (fiber.y * (cos_theta_i * cos_phi_i))
+            + (fiber.z * (cos_theta_i * sin_phi_i));
+        let (filter, pdf) = fiber.evaluate(out);
+        (out, SpectralSample::from_parts(filter, wavelength), pdf)
+    }
+
+    pub fn evaluate(
+        color: Color,
+        melanin: f32,
+        melanin_redness: f32,
+        roughness: f32,
+        azimuthal_roughness: f32,
+        tangent: Vector,
+        inc: Vector,
+        out: Vector,
+        nor: Normal,
+        nor_g: Normal,
+        wavelength: f32,
+    ) -> (SpectralSample, f32) {
+        let _ = nor_g; // Not using this, silence warning
+
+        let fiber = Fiber::new(
+            color,
+            melanin,
+            melanin_redness,
+            roughness,
+            azimuthal_roughness,
+            tangent,
+            inc,
+            nor,
+            wavelength,
+        );
+        let (filter, pdf) = fiber.evaluate(out);
+        (SpectralSample::from_parts(filter, wavelength), pdf)
+    }
+
+    pub fn estimate_eval_over_sphere_light(
+        _inc: Vector,
+        to_light_center: Vector,
+        light_radius_squared: f32,
+        _nor: Normal,
+        _nor_g: Normal,
+    ) -> f32 {
+        // Treat the fiber as scattering uniformly over the sphere, which
+        // is crude, but never zero where the real lobes aren't.
+        let dist2 = to_light_center.length2();
+        if dist2 <= light_radius_squared {
+            return 1.0;
+        }
+        let sin_theta_max2 = (light_radius_squared / dist2).min(1.0);
+        let cos_theta_max = (1.0 - sin_theta_max2).sqrt();
+        (1.0 - cos_theta_max) * 0.5
+    }
+
+    //----------------------------------------------------
+
+    /// The fiber as seen from the incoming ray, with everything the lobes
+    /// need that doesn't depend on the outgoing direction.
+    ///
+    /// Angles are in a frame with x along the fiber and the incoming ray
+    /// in the x-z plane.  Theta is the angle out of the plane normal to the
+    /// fiber, and phi the angle around the fiber from y.
+    struct Fiber {
+        x: Vector,
+        y: Vector,
+        z: Vector,
+        sin_theta_o: f32,
+        cos_theta_o: f32,
+        phi_o: f32,
+        gamma_o: f32,               // Angle of incidence in the normal plane
+        gamma_t: f32,               // Angle of refraction in the normal plane
+        v: [f32; P_MAX + 1],        // Longitudinal variance of each lobe
+        s: f32,                     // Azimuthal logistic scale
+        ap: [Vec4; P_MAX + 1],      // Attenuation of each lobe, per wavelength
+        lobe_pdf: [f32; P_MAX + 1], // Probability of sampling each lobe
+    }
+
+    impl Fiber {
+        fn new(
+            color: Color,
+            melanin: f32,
+            melanin_redness: f32,
+            roughness: f32,
+            azimuthal_roughness: f32,
+            tangent: Vector,
+            inc: Vector,
+            nor: Normal,
+            wavelength: f32,
+        ) -> Fiber {
+            let nor = nor.into_vector();
+            let x = if tangent.length2() > 0.0 {
+                tangent.normalized()
+            } else {
+                coordinate_system_from_vector(nor.normalized()).1
+            };
+            let wo = -inc.normalized();
+            let wo_perp = wo - (x * dot(wo, x));
+            let z = if wo_perp.length2() > 1.0e-12 {
+                wo_perp.normalized()
+            } else {
+                coordinate_system_from_vector(x).1
+            };
+            let y = cross(z, x);
+
+            // Offset across the fiber, from -1 to 1.
+            let nor_perp = nor - (x * dot(nor, x));
+            let h = if nor_perp.length2() > 0.0 {
+                clamp(dot(nor_perp.normalized(), y), -1.0, 1.0)
+            } else {
+                0.0
+            };
+
+            let sin_theta_o = clamp(dot(wo, x), -1.0, 1.0);
+            let cos_theta_o = (1.0 - (sin_theta_o * sin_theta_o)).sqrt();
+            let phi_o = dot(wo, z).atan2(dot(wo, y));
+            let gamma_o = h.asin();
+            let cos_gamma_o = (1.0 - (h * h)).sqrt();
+
+            // The refracted ray, using the modified index of refraction
+            // for the projection into the normal plane.
+            let sin_theta_t = sin_theta_o / ETA;
+            let cos_theta_t = (1.0 - (sin_theta_t * sin_theta_t)).sqrt();
+            let etap = (ETA * ETA - (sin_theta_o * sin_theta_o)).sqrt() / cos_theta_o.max(1.0e-6);
+            let sin_gamma_t = h / etap;
+            let cos_gamma_t = (1.0 - (sin_gamma_t * sin_gamma_t)).sqrt();
+            let gamma_t = sin_gamma_t.asin();
+
+            // Attenuation of each lobe.
+            let bn = clamp(azimuthal_roughness, MIN_ROUGHNESS, 1.0);
+            let sigma_a = melanin_absorption(
+                melanin,
+                melanin_redness,
+                SpectralSample::new(wavelength).wavelengths(),
+            ) + dye_absorption(color.to_spectral_sample(wavelength).e, bn);
+            let path = 2.0 * cos_gamma_t / cos_theta_t;
+            let t = Vec4::new(
+                (-sigma_a.x() * path).exp(),
+                (-sigma_a.y() * path).exp(),
+                (-sigma_a.z() * path).exp(),
+                (-sigma_a.w() * path).exp(),
+            );
+            let f = dielectric_fresnel_from_fac(
+                ((ETA - 1.0) / (ETA + 1.0)) * ((ETA - 1.0) / (ETA + 1.0)),
+                cos_theta_o * cos_gamma_o,
+            );
+            let ap1 = t * ((1.0 - f) * (1.0 - f));
+            let ap2 = ap1 * t * f;
+            let ap3 = ap2 * t * f / (Vec4::splat(1.0) - (t * f));
+            let ap = [Vec4::splat(f), ap1, ap2, ap3];
+
+            let mut lobe_pdf = [0.0f32; P_MAX + 1];
+            let mut total = 0.0;
+            for p in 0..=P_MAX {
+                lobe_pdf[p] = ap[p].x() + ap[p].y() + ap[p].z() + ap[p].w();
+                total += lobe_pdf[p];
+            }
+            for pdf in lobe_pdf.iter_mut() {
+                *pdf /= total;
+            }
+
+            // Roughnesses, remapped to the lobes' variance and scale so
+            // that they're perceptually roughly linear.
+            let bm = clamp(roughness, MIN_ROUGHNESS, 1.0);
+            let v0 = {
+                let tmp = (0.726 * bm) + (0.812 * bm * bm) + (3.7 * bm.powi(20));
+                tmp * tmp
+            };
+            let s = SQRT_PI_OVER_8 * ((0.265 * bn) + (1.194 * bn * bn) + (5.372 * bn.powi(22)));
+
+            Fiber {
+                x: x,
+                y: y,
+                z: z,
+                sin_theta_o: sin_theta_o,
+                cos_theta_o: cos_theta_o,
+                phi_o: phi_o,
+                gamma_o: gamma_o,
+                gamma_t: gamma_t,
+                v: [v0, 0.25 * v0, 4.0 * v0, 4.0 * v0],
+                s: s,
+                ap: ap,
+                lobe_pdf: lobe_pdf,
+            }
+        }
+
+        /// The sine and cosine of the incoming longitudinal angle, tilted
+        /// by the cuticle scales for lobe `p`.
+        fn tilted(&self, p: usize) -> (f32, f32) {
+            let tilt = match p {
+                0 => -2.0 * SCALE_TILT,
+                1 => SCALE_TILT,
+                2 => 4.0 * SCALE_TILT,
+                _ => return (self.sin_theta_o, self.cos_theta_o),
+            };
+            let (sin_tilt, cos_tilt) = tilt.sin_cos();
+            (
+                (self.sin_theta_o * cos_tilt) + (self.cos_theta_o * sin_tilt),
+                ((self.cos_theta_o * cos_tilt) - (self.sin_theta_o * sin_tilt)).abs(),
+            )
+        }
+
+        /// Returns the filter and pdf for scattering in direction `out`.
+        fn evaluate(&self, out: Vector) -> (Vec4, f32) {
+            let out = out.normalized();
+            let sin_theta_i = clamp(dot(out, self.x), -1.0, 1.0);
+            let cos_theta_i = (1.0 - (sin_theta_i * sin_theta_i)).sqrt();
+            let phi = dot(out, self.z).atan2(dot(out, self.y)) - self.phi_o;
+
+            let mut filter = Vec4::splat(0.0);
+            let mut pdf = 0.0;
+            for p in 0..=P_MAX {
+                let (sin_theta_op, cos_theta_op) = self.tilted(p);
+                let m = mp(
+                    cos_theta_i,
+                    cos_theta_op,
+                    sin_theta_i,
+                    sin_theta_op,
+                    self.v[p],
+                );
+                let n = if p < P_MAX {
+                    np(phi, p, self.s, self.gamma_o, self.gamma_t)
+                } else {
+                    0.5 * INV_PI
+                };
+                filter += self.ap[p] * (m * n);
+                pdf += self.lobe_pdf[p] * m * n;
+            }
+
+            (filter, pdf)
+        }
+    }
+
+    /// Absorption coefficient of the fiber's interior at each of the
+    /// wavelengths, relative to its radius.
+    ///
+    /// The spectra of eumelanin and pheomelanin follow the power laws from
+    /// Donner and Jensen's skin model, scaled to d'Eon et al's absorption
+    /// per unit concentration at 550nm.  `melanin` is remapped to a
+    /// concentration that darkens roughly linearly to the eye.
+    fn melanin_absorption(melanin: f32, melanin_redness: f32, wavelengths: Vec4) -> Vec4 {
+        let concentration = -(1.0 - melanin).max(0.0001).ln();
+        let eumelanin = concentration * (1.0 - melanin_redness);
+        let pheomelanin = concentration * melanin_redness;
+        let sigma_a = |wl: f32| {
+            let wl = wl * (1.0 / 550.0);
+            (eumelanin * 0.697 * wl.powf(-3.33)) + (pheomelanin * 0.4 * wl.powf(-4.75))
+        };
+        Vec4::new(
+            sigma_a(wavelengths.x()),
+            sigma_a(wavelengths.y()),
+            sigma_a(wavelengths.z()),
+            sigma_a(wavelengths.w()),
+        )
+    }
+
+    /// Absorption coefficient that gives roughly the color `color` to hair
+    /// with azimuthal roughness `bn`, from Chiang et al.
+    fn dye_absorption(color: Vec4, bn: f32) -> Vec4 {
+        let denom = 5.969 - (0.215 * bn) + (2.532 * bn.powi(2)) - (10.73 * bn.powi(3))
+            + (5.574 * bn.powi(4))
+            + (0.245 * bn.powi(5));
+        let sigma_a = |c: f32| {
+            let tmp = clamp(c, 1.0e-4, 1.0).ln() / denom;
+            tmp * tmp
+        };
+        Vec4::new(
+            sigma_a(color.x()),
+            sigma_a(color.y()),
+            sigma_a(color.z()),
+            sigma_a(color.w()),
+        )
+    }
+
+    /// The longitudinal scattering function.
+    fn mp(cos_theta_i: f32, cos_theta_o: f32, sin_theta_i: f32, sin_theta_o: f32, v: f32) -> f32 {
+        let a = cos_theta_i * cos_theta_o / v;
+        let b = sin_theta_i * sin_theta_o / v;
+        if v <= 0.1 {
+            // Computed in log space, to avoid overflow at low roughness.
+            (log_i0(a) - b - (1.0 / v) + 0.6931 + (1.0 / (2.0 * v)).ln()).exp()
+        } else {
+            (-b).exp() * i0(a) / ((1.0 / v).sinh() * 2.0 * v)
+        }
+    }
+
+    /// The azimuthal scattering function for lobe `p`.
+    fn np(phi: f32, p: usize, s: f32, gamma_o: f32, gamma_t: f32) -> f32 {
+        let d_phi = (phi - lobe_phi(p, gamma_o, gamma_t) + PI_32).rem_euclid(2.0 * PI_32) - PI_32;
+        trimmed_logistic(d_phi, s, -PI_32, PI_32)
+    }
+
+    /// The azimuthal angle that lobe `p` exits the fiber at, relative to
+    /// the incoming ray.
+    fn lobe_phi(p: usize, gamma_o: f32, gamma_t: f32) -> f32 {
+        let p = p as f32;
+        (2.0 * p * gamma_t) - (2.0 * gamma_o) + (p * PI_32)
+    }
+
+    /// Modified Bessel function of the first kind, of order zero.
+    ///
+    /// The terms of the series peak around the `x / 2`th, so enough of
+    /// them are summed to be accurate up to where `log_i0()` switches to
+    /// the asymptotic form.
+    fn i0(x: f32) -> f32 {
+        let q = x * x * 0.25;
+        let mut term = 1.0;
+        let mut val = 1.0;
+        for i in 1..32 {
+            term *= q / (i * i) as f32;
+            val += term;
+        }
+        val
+    }
+
+    /// The log of `i0()`, which doesn't overflow for large `x`.
+    fn log_i0(x: f32) -> f32 {
+        if x > 12.0 {
+            // ln(e^x / sqrt(2 pi x) * (1 + 1 / 8x)), the start of the
+            // asymptotic expansion.
+            x - (0.5 * (2.0 * PI_32 * x).ln()) + (1.0 / (8.0 * x))
+        } else {
+            i0(x).ln()
+        }
+    }
+
+    fn logistic(x: f32, s: f32) -> f32 {
+        let e = (-x.abs() / s).exp();
+        e / (s * (1.0 + e) * (1.0 + e))
+    }
+
+    fn logistic_cdf(x: f32, s: f32) -> f32 {
+        1.0 / (1.0 + (-x / s).exp())
+    }
+
+    /// The logistic distribution, renormalized to the range [a, b].
+    fn trimmed_logistic(x: f32, s: f32, a: f32, b: f32) -> f32 {
+        logistic(x, s) / (logistic_cdf(b, s) - logistic_cdf(a, s))
+    }
+
+    fn sample_trimmed_logistic(u: f32, s: f32, a: f32, b: f32) -> f32 {
+        let k = logistic_cdf(b, s) - logistic_cdf(a, s);
+        let x = -s * ((1.0 / ((u * k) + logistic_cdf(a, s))) - 1.0).ln();
+        clamp(x, a, b)
+    }
+
+    /// Splits one sample value into two, by de-interleaving its bits.
+    fn demux_float(u: f32) -> (f32, f32) {
+        let bits = (u.max(0.0) as f64 * 4_294_967_296.0).min(4_294_967_295.0) as u64;
+        (
+            compact_bits(bits) as f32 / 65536.0,
+            compact_bits(bits >> 1) as f32 / 65536.0,
+        )
+    }
+
+    /// Packs the even bits of `x` together.
+    fn compact_bits(mut x: u64) -> u32 {
+        x &= 0x5555_5555_5555_5555;
+        x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+        x = (x | (x >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+        x = (x | (x >> 4)) & 0x00ff_00ff_00ff_00ff;
+        x = (x | (x >> 8)) & 0x0000_ffff_0000_ffff;
+        x = (x | (x >> 16)) & 0x0000_0000_ffff_ffff;
+        x as u32
+    }
+}
+
 /// Emit closure code.
 ///
 /// NOTE: this needs to be handled specially by the integrator!  It does not
@@ -677,8 +1273,9 @@ mod emit_closure {
         _nor: Normal,
         _nor_g: Normal,
     ) -> f32 {
-        // TODO: what to do here?
-        unimplemented!()
+        // Emitters aren't lit themselves, but points in the atmosphere use
+        // this closure to pick lights, scattering in all directions.
+        1.0
     }
 }
 
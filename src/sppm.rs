@@ -0,0 +1,861 @@
+//! Stochastic progressive photon mapping, after "Stochastic Progressive
+//! Photon Mapping" by Hachisuka and Jensen.
+//!
+//! This is an alternative to path tracing for scenes where light reaches
+//! the camera along paths that path tracing can't find, most importantly
+//! caustics seen via smooth surfaces (specular-diffuse-specular paths).
+//!
+//! Each iteration:
+//!
+//! 1. Traces a camera path for every pixel through smooth surfaces until
+//!    it hits a rough one, which becomes the pixel's visible point.  Light
+//!    the path finds on the way, and direct light at the visible point from
+//!    light sampling, goes straight to the pixel.
+//! 2. Traces photons from the lights, and stores them where they land on
+//!    rough surfaces after bouncing at least once.
+//! 3. Gathers the photons within each pixel's radius of its visible point,
+//!    and then shrinks the radius in proportion to how many were found.
+//!
+//! The image converges to the correct result as the radii shrink, with
+//! `spp` setting the number of iterations.
+//!
+//! Emissive surfaces that aren't lights only contribute light that reaches
+//! the camera via smooth surfaces, since they neither emit photons nor can
+//! be found by light sampling.
+
+use std::{
+    io::{self, Write},
+    sync::Mutex,
+};
+
+use glam::Vec4;
+use scoped_threadpool::Pool;
+
+use crate::{
+    color::{map_0_1_to_wavelength, Color, SpectralSample, XYZ},
+    fp_utils::robust_ray_origin,
+    hash::hash_u32,
+    image::Image,
+    logger::{Event, Logger},
+    math::{dot, max_element},
+    output::Checkpointer,
+    photon_map::{Photon, PhotonMap},
+    ray::{Ray, RayBatch},
+    renderer::{get_sample, sample_light_ray, RenderStats, Renderer},
+    sampling::dims::{self, Dims},
+    scene::Scene,
+    sensor::SensorResponse,
+    shading::surface_closure::SurfaceClosure,
+    surface,
+    timer::Timer,
+    tracer::Tracer,
+    transform_stack::TransformStack,
+};
+
+/// GGX surfaces smoother than this are followed by camera paths instead of
+/// getting visible points, since gathering photons on them is very noisy.
+const SMOOTH_ROUGHNESS: f32 = 0.1;
+
+/// The fraction of newly found photons that are kept when shrinking the
+/// gather radius.  Smaller values shrink the radius faster.
+const ALPHA: f32 = 2.0 / 3.0;
+
+/// Initial gather radius in pixel widths at the visible point, when
+/// fitting it to the pixels.
+const INITIAL_RADIUS_PIXELS: f32 = 2.0;
+
+/// How many image rows each camera pass job traces.
+const ROWS_PER_JOB: usize = 8;
+
+/// How many photons each photon pass job traces.
+const PHOTONS_PER_JOB: usize = 4096;
+
+/// How many pixels each gather job handles.
+const PIXELS_PER_JOB: usize = 1024;
+
+/// Mixed into the seed for the photons' samples, so they aren't correlated
+/// with the samples of the camera paths.
+const PHOTON_SEED: u32 = 0x5bb3_f0a1;
+
+/// Renders the scene with stochastic progressive photon mapping.
+///
+/// Like path tracing, the result only depends on the scene and render
+/// settings, not on the thread count.
+pub fn render(
+    renderer: &Renderer,
+    crop: Option<(u32, u32, u32, u32)>,
+    thread_count: u32,
+    do_blender_output: bool,
+    checkpointer: Option<&Checkpointer>,
+    log: &Logger,
+) -> (Image, RenderStats) {
+    let settings = &renderer.settings;
+    let mut total_timer = Timer::new();
+    let mut tpool = Pool::new(thread_count);
+
+    let image = renderer.new_image();
+    let (width, height, start_x, start_y) = renderer.render_region(crop);
+    let iterations = settings.spp.max(1);
+    let photon_count = if settings.photons > 0 {
+        settings.photons as usize
+    } else {
+        width * height
+    };
+
+    log.log(&Event::RenderStarted {
+        total_pixels: width * height,
+        spp: iterations,
+        thread_count: thread_count,
+    });
+    log.detail(&format!("\tSPPM: {} photons per iteration", photon_count));
+    if renderer.scene.world.atmosphere.is_some() {
+        log.warning("the atmosphere is only rendered when path tracing, and is ignored.");
+    }
+
+    let mut stats = RenderStats::new();
+    let mut pixels = vec![
+        SppmPixel {
+            radius: settings.photon_radius,
+            photon_count: 0.0,
+            flux: XYZ::new(0.0, 0.0, 0.0),
+            direct: XYZ::new(0.0, 0.0, 0.0),
+        };
+        width * height
+    ];
+    let pixel_angle = pixel_angle(renderer, start_x + width / 2, start_y + height / 2);
+    let mut rows_reported = 0;
+    let mut iterations_done = 0;
+
+    for iteration in 0..iterations {
+        // Stop when the next iteration would go over the time limit.  The
+        // image is the current estimate after every iteration, so it can
+        // stop after any of them.
+        if let (Some(max_time), true) = (settings.max_time, iteration > 0) {
+            let elapsed = total_timer.elapsed();
+            if elapsed + (elapsed / iteration as f32) > max_time {
+                log.detail(&format!(
+                    "\tTime limit: stopping after {} of {} iterations",
+                    iteration, iterations
+                ));
+                break;
+            }
+        }
+        let rays_before = stats.ray_count;
+
+        // Camera pass, finding the visible points.
+        let mut visible_points = vec![None; width * height];
+        {
+            let job_stats = Mutex::new(RenderStats::new());
+            let chunk_len = ROWS_PER_JOB * width;
+            tpool.scoped(|scope| {
+                for (chunk_i, (pixel_chunk, vp_chunk)) in pixels
+                    .chunks_mut(chunk_len)
+                    .zip(visible_points.chunks_mut(chunk_len))
+                    .enumerate()
+                {
+                    let job_stats = &job_stats;
+                    scope.execute(move || {
+                        let first_pixel = chunk_i * chunk_len;
+                        let region = (start_x, start_y, width);
+                        let s = trace_camera_paths(
+                            renderer,
+                            region,
+                            first_pixel,
+                            iteration as u32,
+                            pixel_angle,
+                            pixel_chunk,
+                            vp_chunk,
+                        );
+                        job_stats.lock().unwrap().collect(s);
+                    });
+                }
+            });
+            stats.collect(job_stats.into_inner().unwrap());
+        }
+
+        // Photon pass.
+        let photons = {
+            let job_stats = Mutex::new(RenderStats::new());
+            let job_photons = Mutex::new(Vec::new());
+            let first_photon = iteration * photon_count;
+            tpool.scoped(|scope| {
+                for job_start in (0..photon_count).step_by(PHOTONS_PER_JOB) {
+                    let job_end = (job_start + PHOTONS_PER_JOB).min(photon_count);
+                    let job_stats = &job_stats;
+                    let job_photons = &job_photons;
+                    scope.execute(move || {
+                        let (photons, s) = trace_photons(
+                            renderer,
+                            (first_photon + job_start)..(first_photon + job_end),
+                        );
+                        job_photons.lock().unwrap().push((job_start, photons));
+                        job_stats.lock().unwrap().collect(s);
+                    });
+                }
+            });
+            stats.collect(job_stats.into_inner().unwrap());
+
+            // Put the photons in a fixed order, regardless of which jobs
+            // finished first.
+            let mut job_photons = job_photons.into_inner().unwrap();
+            job_photons.sort_unstable_by_key(|(job_start, _)| *job_start);
+            let mut photons = Vec::new();
+            for (_, p) in job_photons {
+                photons.extend(p);
+            }
+            photons
+        };
+
+        // Gather the photons at the visible points, and update the pixels.
+        {
+            let max_radius = pixels
+                .iter()
+                .zip(visible_points.iter())
+                .filter(|(_, vp)| vp.is_some())
+                .fold(0.0f32, |r, (pixel, _)| r.max(pixel.radius));
+            if max_radius > 0.0 {
+                let photon_map = PhotonMap::new(&photons, max_radius);
+                let photon_map = &photon_map;
+                tpool.scoped(|scope| {
+                    for (pixel_chunk, vp_chunk) in pixels
+                        .chunks_mut(PIXELS_PER_JOB)
+                        .zip(visible_points.chunks(PIXELS_PER_JOB))
+                    {
+                        scope.execute(move || {
+                            for (pixel, vp) in pixel_chunk.iter_mut().zip(vp_chunk.iter()) {
+                                if let Some(ref vp) = *vp {
+                                    pixel.gather(
+                                        vp,
+                                        photon_map,
+                                        &renderer.settings.sensor_response,
+                                    );
+                                }
+                            }
+                        });
+                    }
+                });
+            }
+        }
+
+        // Write the current estimate to the image.
+        {
+            let mut merge_timer = Timer::new();
+            let min = (start_x as u32, start_y as u32);
+            let max = ((start_x + width) as u32, (start_y + height) as u32);
+            let mut img_bucket = image.get_bucket(min, max);
+            let exposure = renderer.scene.camera.exposure();
+            let iter_count = (iteration + 1) as f32;
+            let flux_scale = 1.0 / (photon_count as f32 * iter_count);
+            for (i, pixel) in pixels.iter().enumerate() {
+                let mut col = pixel.direct / iter_count;
+                if pixel.radius > 0.0 {
+                    let area = std::f32::consts::PI * pixel.radius * pixel.radius;
+                    col += pixel.flux * (flux_scale / area);
+                }
+                img_bucket.set(
+                    (start_x + (i % width)) as u32,
+                    (start_y + (i / width)) as u32,
+                    renderer.settings.white_balance.apply(col) * exposure,
+                );
+            }
+            stats.merge_time += merge_timer.tick() as f64;
+
+            if do_blender_output && iteration + 1 == iterations {
+                use crate::color::xyz_to_rec709_e;
+                println!("DIV");
+                println!("{:.2}%", 100.0);
+                println!("{} {} {} {}", min.0, min.1, max.0, max.1);
+                println!("{}", img_bucket.rgba_base64(xyz_to_rec709_e));
+                println!("BUCKET_END");
+                println!("DIV");
+                let _ = io::stdout().flush();
+            }
+        }
+
+        // Progress is reported as a share of the rows per iteration.
+        let rows_done = height * (iteration + 1) / iterations;
+        if rows_done > rows_reported {
+            log.log(&Event::BucketDone {
+                x: start_x as u32,
+                y: (start_y + rows_reported) as u32,
+                w: width as u32,
+                h: (rows_done - rows_reported) as u32,
+                rays: stats.ray_count - rays_before,
+            });
+            rows_reported = rows_done;
+        }
+        iterations_done = iteration + 1;
+
+        if let Some(checkpointer) = checkpointer {
+            if checkpointer.bucket_done() {
+                let mut checkpoint_timer = Timer::new();
+                match checkpointer.write(&image) {
+                    Ok(()) => log.log(&Event::CheckpointWritten {
+                        path: checkpointer.path(),
+                        seconds: checkpoint_timer.tick(),
+                    }),
+                    Err(e) => log.warning(&format!("checkpoint failed: {}", e)),
+                }
+            }
+        }
+    }
+
+    stats.total_time += total_timer.tick() as f64;
+    stats.spp = iterations_done;
+    (image, stats)
+}
+
+/// A pixel's progressive photon mapping state.
+#[derive(Debug, Copy, Clone)]
+struct SppmPixel {
+    radius: f32,       // Gather radius, or 0 if not yet known
+    photon_count: f32, // Accumulated photon count, as reduced by ALPHA
+    flux: XYZ,         // Accumulated photon flux within the radius
+    direct: XYZ,       // Sum of the light found by the camera paths
+}
+
+impl SppmPixel {
+    /// Gathers the photons near a visible point, and shrinks the radius.
+    /// The gathered light is converted to color by `sensor`.
+    fn gather(&mut self, vp: &VisiblePoint, photon_map: &PhotonMap, sensor: &SensorResponse) {
+        let idata = &vp.idata;
+        let nor = idata.nor.normalized().into_vector();
+        let mut found = 0.0;
+        let mut flux = Vec4::splat(0.0);
+        photon_map.for_each_near(idata.pos, self.radius, |photon| {
+            found += 1.0;
+            let out = -photon.dir;
+            let (filter, _) =
+                vp.closure
+                    .evaluate(idata.incoming, out, idata.nor, idata.nor_g, vp.wavelength);
+            let cos = dot(nor, out).abs();
+            if cos > 0.0 && max_element(filter.e) > 0.0 {
+                let photon_flux = Color::new_xyz(photon.flux.to_tuple())
+                    .to_spectral_sample(vp.wavelength)
+                    .e;
+                flux += filter.e * photon_flux / cos;
+            }
+        });
+
+        if found > 0.0 {
+            let new_count = self.photon_count + (found * ALPHA);
+            let ratio = new_count / (self.photon_count + found);
+            let flux = sensor.to_xyz(&SpectralSample::from_parts(
+                flux * vp.throughput,
+                vp.wavelength,
+            ));
+            self.flux = (self.flux + flux) * ratio;
+            self.radius *= ratio.sqrt();
+            self.photon_count = new_count;
+        }
+    }
+}
+
+/// Where a pixel's camera path found a rough surface this iteration.
+#[derive(Debug, Copy, Clone)]
+struct VisiblePoint {
+    idata: surface::SurfaceIntersectionData,
+    closure: SurfaceClosure,
+    throughput: Vec4,
+    wavelength: f32,
+}
+
+/// Whether camera paths pass over a closure rather than stopping on it.
+fn is_smooth(closure: &SurfaceClosure) -> bool {
+    match *closure {
+        SurfaceClosure::GGX { roughness, .. } => roughness < SMOOTH_ROUGHNESS,
+        _ => false,
+    }
+}
+
+/// Samples a closure for the next direction of a camera path or photon,
+/// returning the direction and the factor to multiply the path's
+/// throughput by.
+///
+/// Unlike path tracing, this also follows perfect mirrors, whose filter
+/// is already the throughput factor.
+pub(crate) fn sample_closure(
+    idata: &surface::SurfaceIntersectionData,
+    closure: &SurfaceClosure,
+    uv: (f32, f32),
+    wavelength: f32,
+) -> Option<(Ray, Vec4)> {
+    let (dir, filter, pdf) = closure.sample(idata.incoming, idata.nor, idata.nor_g, uv, wavelength);
+    let factor = if closure.is_delta() {
+        filter.e
+    } else if pdf > 0.0 {
+        filter.e / pdf
+    } else {
+        return None;
+    };
+    if max_element(factor) <= 0.0 {
+        return None;
+    }
+
+    let orig = robust_ray_origin(idata.pos, idata.pos_err, idata.nor_g.normalized(), dir);
+    Some((
+        Ray {
+            orig: orig,
+            dir: dir,
+            time: 0.0,
+            wavelength: wavelength,
+            max_t: f32::INFINITY,
+        },
+        factor,
+    ))
+}
+
+/// The angle between the camera rays through the centers of neighboring
+/// pixels, for fitting the initial radius to the pixels.
+fn pixel_angle(renderer: &Renderer, x: usize, y: usize) -> f32 {
+    let ray = |x: f32| {
+        let (img_x, img_y) = renderer.image_plane_co(x, y as f32 + 0.5);
+        renderer
+            .scene
+            .camera
+            .generate_ray(img_x, img_y, 0.5, 550.0, 0.5, 0.5)
+            .dir
+            .normalized()
+    };
+    dot(ray(x as f32 + 0.5), ray(x as f32 + 1.5))
+        .min(1.0)
+        .acos()
+}
+
+//----------------------------------------------------------------
+
+/// Traces one camera path for each of a run of pixels, adding the light
+/// they find to `pixels` and storing their visible points.
+///
+/// `region` is the (start_x, start_y, width) of the region being rendered,
+/// and `first_pixel` is the index of the first of the pixels within it.
+fn trace_camera_paths(
+    renderer: &Renderer,
+    region: (usize, usize, usize),
+    first_pixel: usize,
+    iteration: u32,
+    pixel_angle: f32,
+    pixels: &mut [SppmPixel],
+    visible_points: &mut [Option<VisiblePoint>],
+) -> RenderStats {
+    let settings = &renderer.settings;
+    let scene = &renderer.scene;
+    let (start_x, start_y, width) = region;
+    let mut stats = RenderStats::new();
+    let mut timer = Timer::new();
+    let mut tracer = Tracer::from_assembly(&scene.root);
+    let mut xform_stack = TransformStack::new();
+    let mut paths = Vec::with_capacity(pixels.len());
+    let mut rays = RayBatch::new();
+
+    for i in 0..pixels.len() {
+        let pixel_i = first_pixel + i;
+        let (x, y) = (
+            (start_x + (pixel_i % width)) as u32,
+            (start_y + (pixel_i / width)) as u32,
+        );
+        let samp = |dims: Dims, i| get_sample(dims.dim(i), iteration, (x, y), settings.seed);
+        let (img_x, img_y) = {
+            let filter_x = settings.filter.sample(samp(dims::FILTER, 0)) + 0.5;
+            let filter_y = settings.filter.sample(samp(dims::FILTER, 1)) + 0.5;
+            renderer.image_plane_co(filter_x + x as f32, filter_y + y as f32)
+        };
+        let time = samp(dims::TIME, 0);
+        let wavelength = map_0_1_to_wavelength(samp(dims::WAVELENGTH, 0));
+        rays.push(
+            scene.camera.generate_ray(
+                img_x,
+                img_y,
+                time,
+                wavelength,
+                samp(dims::LENS, 0),
+                samp(dims::LENS, 1),
+            ),
+            false,
+        );
+        rays.mark_camera(rays.len() - 1);
+        paths.push(CameraPath {
+            index: i,
+            pixel_co: (x, y),
+            sample_number: iteration,
+            seed: settings.seed,
+            time: time,
+            wavelength: wavelength,
+            event: CameraPathEvent::Ray,
+            bounce_count: 0,
+            distance: 0.0,
+            throughput: Vec4::splat(1.0),
+            pending_color_addition: Vec4::splat(0.0),
+            color: Vec4::splat(0.0),
+            visible_point: None,
+        });
+    }
+    stats.initial_ray_generation_time += timer.tick() as f64;
+
+    let mut pi = paths.len();
+    while pi > 0 {
+        let isects = tracer.trace(&mut rays);
+        stats.trace_time += timer.tick() as f64;
+
+        let mut new_end = 0;
+        for i in 0..pi {
+            if paths[i].next(&mut xform_stack, scene, settings, &isects[i], &mut rays, i) {
+                paths.swap(new_end, i);
+                rays.swap(new_end, i);
+                new_end += 1;
+            }
+        }
+        rays.truncate(new_end);
+        pi = new_end;
+        stats.ray_generation_time += timer.tick() as f64;
+    }
+
+    for path in &paths {
+        let pixel = &mut pixels[path.index];
+        let col = SpectralSample::from_parts(path.color, path.wavelength);
+        pixel.direct += settings.sensor_response.to_xyz(&col);
+        if let Some(vp) = path.visible_point {
+            if pixel.radius <= 0.0 {
+                pixel.radius = INITIAL_RADIUS_PIXELS * pixel_angle * path.distance;
+            }
+            visible_points[path.index] = Some(vp);
+        }
+    }
+    stats.sample_writing_time += timer.tick() as f64;
+    stats.ray_count = tracer.rays_traced();
+
+    stats
+}
+
+#[derive(Debug)]
+enum CameraPathEvent {
+    Ray,
+    ShadowRay,
+}
+
+/// A path from the camera to a pixel's visible point.
+#[derive(Debug)]
+struct CameraPath {
+    index: usize, // Index of the path's pixel in the job
+    pixel_co: (u32, u32),
+    sample_number: u32,
+    seed: u32,
+    time: f32,
+    wavelength: f32,
+
+    event: CameraPathEvent,
+    bounce_count: u32,
+    distance: f32, // Length of the path so far
+    throughput: Vec4,
+    pending_color_addition: Vec4,
+    color: Vec4,
+    visible_point: Option<VisiblePoint>,
+}
+
+impl CameraPath {
+    /// Gets dimension `i` of `dims` at the path's current vertex.
+    fn samp(&self, dims: Dims, i: u32) -> f32 {
+        get_sample(
+            dims::vertex_dim(self.bounce_count, dims, i),
+            self.sample_number,
+            self.pixel_co,
+            self.seed,
+        )
+    }
+
+    /// Processes the result of the path's last ray, and sets up its next
+    /// ray if it has one.  Returns whether the path is still alive.
+    fn next(
+        &mut self,
+        xform_stack: &mut TransformStack,
+        scene: &Scene,
+        settings: &crate::render_settings::RenderSettings,
+        isect: &surface::SurfaceIntersection,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+    ) -> bool {
+        match self.event {
+            CameraPathEvent::Ray => {
+                if let surface::SurfaceIntersection::Hit {
+                    intersection_data: ref idata,
+                    ref closure,
+                } = *isect
+                {
+                    self.distance += idata.t * rays.dir(ray_idx).length();
+
+                    // Nothing lights the path's vertices but this, so
+                    // emission counts in full.
+                    if let SurfaceClosure::Emit(color) = *closure {
+                        self.color += color.to_spectral_sample(self.wavelength).e * self.throughput;
+                        return false;
+                    }
+
+                    // Follow smooth surfaces.
+                    if is_smooth(closure) && self.bounce_count < settings.max_bounces {
+                        let uv = (self.samp(dims::BSDF, 0), self.samp(dims::BSDF, 1));
+                        if let Some((mut ray, factor)) =
+                            sample_closure(idata, closure, uv, self.wavelength)
+                        {
+                            ray.time = self.time;
+                            rays.set_from_ray(&ray, false, ray_idx);
+                            self.throughput *= factor;
+                            self.bounce_count += 1;
+                            return true;
+                        } else {
+                            return false;
+                        }
+                    }
+
+                    // Anything else is the visible point.  Its direct
+                    // light comes from light sampling alone, since there
+                    // are no bounce rays to compete with.
+                    self.visible_point = Some(VisiblePoint {
+                        idata: *idata,
+                        closure: *closure,
+                        throughput: self.throughput,
+                        wavelength: self.wavelength,
+                    });
+                    let samples = (
+                        self.samp(dims::LIGHT_SELECT, 0),
+                        (
+                            self.samp(dims::LIGHT_POINT, 0),
+                            self.samp(dims::LIGHT_POINT, 1),
+                            self.samp(dims::LIGHT_POINT, 2),
+                        ),
+                    );
+                    if let Some((shadow_ray, light)) = sample_light_ray(
+                        xform_stack,
+                        scene,
+                        settings,
+                        idata,
+                        closure,
+                        (1.0, 0.0),
+                        samples,
+                        self.wavelength,
+                        self.time,
+                    ) {
+                        self.pending_color_addition = light * self.throughput;
+                        rays.set_from_ray(&shadow_ray, true, ray_idx);
+                        self.event = CameraPathEvent::ShadowRay;
+                        return true;
+                    }
+                    return false;
+                } else {
+                    // Didn't hit anything, so background color and world
+                    // lights.  Like with path tracing, camera rays see the
+                    // backplate instead, if there is one, and only the
+                    // world lights that are visible to the camera.
+                    let camera_ray = self.bounce_count == 0;
+                    let background = if camera_ray {
+                        scene
+                            .world
+                            .camera_background(settings.pixel_uv(self.pixel_co))
+                    } else {
+                        scene.world.background_color
+                    };
+                    self.color +=
+                        background.to_spectral_sample(self.wavelength).e * self.throughput;
+                    let throughput = self.throughput;
+                    let color = &mut self.color;
+                    scene.world_lights_from_direction(
+                        rays.dir(ray_idx),
+                        self.wavelength,
+                        self.time,
+                        camera_ray,
+                        |light_color, _| *color += light_color.e * throughput,
+                    );
+                    return false;
+                }
+            }
+
+            CameraPathEvent::ShadowRay => {
+                if let surface::SurfaceIntersection::Miss = *isect {
+                    self.color += self.pending_color_addition;
+                }
+                return false;
+            }
+        }
+    }
+}
+
+//----------------------------------------------------------------
+
+/// Traces the photons with the given indices, returning the photons they
+/// leave on rough surfaces.
+///
+/// Photon indices count up through all of the iterations, so each
+/// iteration's photons are new samples.
+fn trace_photons(
+    renderer: &Renderer,
+    indices: std::ops::Range<usize>,
+) -> (Vec<Photon>, RenderStats) {
+    let settings = &renderer.settings;
+    let scene = &renderer.scene;
+    let seed = hash_u32(settings.seed, PHOTON_SEED);
+    let mut stats = RenderStats::new();
+    let mut timer = Timer::new();
+    let mut tracer = Tracer::from_assembly(&scene.root);
+    let mut xform_stack = TransformStack::new();
+    let mut paths = Vec::with_capacity(indices.len());
+    let mut rays = RayBatch::new();
+    let mut photons = Vec::new();
+
+    for index in indices {
+        let index = index as u32;
+        let samp = |dims: Dims, i| get_sample(dims.dim(i), index, (0, 0), seed);
+        let wavelength = map_0_1_to_wavelength(samp(dims::PHOTON_WAVELENGTH, 0));
+        let time = samp(dims::PHOTON_TIME, 0);
+        xform_stack.clear();
+        if let Some(emission) = scene.sample_emission(
+            &mut xform_stack,
+            samp(dims::PHOTON_LIGHT_SELECT, 0),
+            (samp(dims::PHOTON_POINT, 0), samp(dims::PHOTON_POINT, 1)),
+            (samp(dims::PHOTON_DIR, 0), samp(dims::PHOTON_DIR, 1)),
+            wavelength,
+            time,
+        ) {
+            if max_element(emission.flux.e) > 0.0 {
+                rays.push(emission.ray, false);
+                paths.push(PhotonPath {
+                    index: index,
+                    seed: seed,
+                    time: time,
+                    wavelength: wavelength,
+                    bounce_count: 0,
+                    from_background: emission.from_background,
+                    flux: emission.flux.e,
+                });
+            }
+        }
+    }
+    stats.initial_ray_generation_time += timer.tick() as f64;
+
+    let mut pi = paths.len();
+    while pi > 0 {
+        let isects = tracer.trace(&mut rays);
+        stats.trace_time += timer.tick() as f64;
+
+        let mut new_end = 0;
+        for i in 0..pi {
+            if paths[i].next(settings.max_bounces, &isects[i], &mut rays, i, &mut photons) {
+                paths.swap(new_end, i);
+                rays.swap(new_end, i);
+                new_end += 1;
+            }
+        }
+        rays.truncate(new_end);
+        pi = new_end;
+        stats.ray_generation_time += timer.tick() as f64;
+    }
+    stats.ray_count = tracer.rays_traced();
+
+    (photons, stats)
+}
+
+/// A path from a light, leaving photons where it goes.
+#[derive(Debug)]
+struct PhotonPath {
+    index: u32,
+    seed: u32,
+    time: f32,
+    wavelength: f32,
+    bounce_count: u32,
+    from_background: bool,
+    flux: Vec4,
+}
+
+impl PhotonPath {
+    /// Gets dimension `i` of `dims` at the path's current vertex.
+    fn samp(&self, dims: Dims, i: u32) -> f32 {
+        get_sample(
+            dims::photon_vertex_dim(self.bounce_count, dims, i),
+            self.index,
+            (0, 0),
+            self.seed,
+        )
+    }
+
+    /// Processes the result of the path's last ray, storing a photon if it
+    /// hit a rough surface, and sets up its next ray if it has one.
+    /// Returns whether the path is still alive.
+    fn next(
+        &mut self,
+        max_bounces: u32,
+        isect: &surface::SurfaceIntersection,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+        photons: &mut Vec<Photon>,
+    ) -> bool {
+        let (idata, closure) = if let surface::SurfaceIntersection::Hit {
+            intersection_data: ref idata,
+            ref closure,
+        } = *isect
+        {
+            (idata, closure)
+        } else {
+            return false;
+        };
+
+        // Lights absorb photons.
+        if let SurfaceClosure::Emit(_) = *closure {
+            return false;
+        }
+
+        // Direct light is left to light sampling at the visible points,
+        // except from the background, which light sampling doesn't find.
+        if !is_smooth(closure) && (self.bounce_count > 0 || self.from_background) {
+            photons.push(Photon {
+                pos: idata.pos,
+                dir: rays.dir(ray_idx).normalized(),
+                flux: XYZ::from_spectral_sample(&SpectralSample::from_parts(
+                    self.flux,
+                    self.wavelength,
+                )),
+            });
+        }
+
+        if self.bounce_count >= max_bounces {
+            return false;
+        }
+        let uv = (
+            self.samp(dims::PHOTON_BSDF, 0),
+            self.samp(dims::PHOTON_BSDF, 1),
+        );
+        let (mut ray, factor) = match sample_closure(idata, closure, uv, self.wavelength) {
+            Some(sample) => sample,
+            None => return false,
+        };
+
+        // Russian roulette, keeping the photons' flux roughly constant.
+        let new_flux = self.flux * factor;
+        let survival = (max_element(new_flux) / max_element(self.flux)).min(1.0);
+        if self.samp(dims::PHOTON_ROULETTE, 0) >= survival {
+            return false;
+        }
+
+        self.flux = new_flux / survival;
+        self.bounce_count += 1;
+        ray.time = self.time;
+        rays.set_from_ray(&ray, false, ray_idx);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smooth_closures() {
+        let white = Color::new_xyz((1.0, 1.0, 1.0));
+        let ggx = |roughness| SurfaceClosure::GGX {
+            color: white,
+            roughness: roughness,
+            fresnel: 1.0,
+        };
+        assert!(is_smooth(&ggx(0.0)));
+        assert!(is_smooth(&ggx(0.05)));
+        assert!(!is_smooth(&ggx(0.5)));
+        assert!(!is_smooth(&SurfaceClosure::Lambert(white)));
+    }
+}
@@ -1,9 +1,28 @@
-use super::{point_order, PointOrder, Splitable, MAX_EDGE_DICE};
+use kioku::Arena;
+
+use super::{
+    arbitrary_tangents,
+    dicing_cache::{get_or_dice, new_surface_id, DicedGrid},
+    is_opaque_hit, point_order,
+    primvar::HitPrimvars,
+    ray_space, triangle, HitQuery, IntersectContext, PointOrder, ShadeContext, Splitable, Surface,
+    SurfaceHit, SurfaceIntersection, SurfaceIntersectionData, SurfaceStats, MAX_EDGE_DICE,
+};
 use crate::{
+    accel::BVH4,
+    bbox::BBox,
+    boundable::Boundable,
     lerp::{lerp, lerp_slice},
-    math::Point,
+    math::{cross, dot, Matrix4x4, Normal, Point},
 };
 
+/// The most micropolygons a patch is diced into along either direction.
+const MAX_PATCH_DICE: usize = 1024;
+
+/// The most micropolygons along either side of a leaf of a diced patch.
+const LEAF_DICE: usize = 4;
+const MAX_LEAF_VERTS: usize = (LEAF_DICE + 1) * (LEAF_DICE + 1);
+
 #[derive(Debug, Copy, Clone)]
 pub struct BilinearPatch<'a> {
     // The control points are stored in clockwise order, like this:
@@ -26,6 +45,421 @@ pub struct BilinearPatch<'a> {
     must_split: [bool; 4],
 }
 
+impl<'a> BilinearPatch<'a> {
+    /// Creates a patch from its control points, one set of four for each
+    /// time sample.
+    pub fn new<'b>(arena: &'b Arena, control_points: &[[Point; 4]]) -> BilinearPatch<'b> {
+        BilinearPatch {
+            control_points: arena.copy_slice(control_points),
+            must_split: [false; 4],
+        }
+    }
+
+    /// Prepares the patch to be diced into a grid of micropolygons.
+    ///
+    /// `metric` gives the number of micropolygons an edge between two
+    /// points should be diced into, and is evaluated on the patch's edges
+    /// at time 0.5.  The whole patch is diced uniformly, at the finest rate
+    /// needed by any of its edges, but the actual dicing is deferred until
+    /// rays reach each part of it.
+    pub fn dice<'b, F>(&self, arena: &'b Arena, metric: F) -> DicedBilinearPatch<'b>
+    where
+        F: Fn(Point, Point) -> f32,
+    {
+        let patch = lerp_slice(self.control_points, 0.5);
+        let rate = |a: f32, b: f32| {
+            let r = a.max(b).ceil();
+            if r >= 1.0 {
+                (r as usize).min(MAX_PATCH_DICE)
+            } else {
+                1
+            }
+        };
+        let dice_rate = (
+            rate(metric(patch[0], patch[1]), metric(patch[3], patch[2])),
+            rate(metric(patch[1], patch[2]), metric(patch[0], patch[3])),
+        );
+
+        // Split the grid into leaves, and bound each of them.  A leaf of a
+        // bilinear patch is itself a bilinear patch, so the bounds of its
+        // corners bound all of it.
+        let time_sample_count = self.control_points.len();
+        let mut leaves = Vec::new();
+        let mut bounds = Vec::new();
+        for v in (0..dice_rate.1).step_by(LEAF_DICE) {
+            for u in (0..dice_rate.0).step_by(LEAF_DICE) {
+                let leaf = PatchLeaf {
+                    u_range: (u, (u + LEAF_DICE).min(dice_rate.0)),
+                    v_range: (v, (v + LEAF_DICE).min(dice_rate.1)),
+                    index: leaves.len(),
+                };
+                for &patch in self.control_points {
+                    let mut bb = BBox::new();
+                    for &(u, v) in &[
+                        (leaf.u_range.0, leaf.v_range.0),
+                        (leaf.u_range.1, leaf.v_range.0),
+                        (leaf.u_range.1, leaf.v_range.1),
+                        (leaf.u_range.0, leaf.v_range.1),
+                    ] {
+                        let p = bilerp_point(patch, grid_uv(dice_rate, u, v));
+                        bb.min = bb.min.min(p);
+                        bb.max = bb.max.max(p);
+                    }
+                    bounds.push(bb);
+                }
+                leaves.push(leaf);
+            }
+        }
+
+        let accel = BVH4::from_objects(arena, &mut leaves[..], 1, |leaf| {
+            &bounds[(leaf.index * time_sample_count)..((leaf.index + 1) * time_sample_count)]
+        });
+
+        DicedBilinearPatch {
+            control_points: arena.copy_slice(self.control_points),
+            dice_rate: dice_rate,
+            leaves: arena.copy_slice(&leaves),
+            accel: accel,
+            cache_id: new_surface_id(),
+        }
+    }
+}
+
+/// A bilinear patch that's diced into micropolygons lazily, a leaf at a
+/// time, as rays reach each leaf.
+///
+/// Diced leaves are kept in the dicing cache.  All leaves are diced from
+/// the same uniform grid, so neighboring leaves have exactly the same
+/// vertices along their shared edges, and there are no cracks between
+/// them.
+#[derive(Copy, Clone, Debug)]
+pub struct DicedBilinearPatch<'a> {
+    control_points: &'a [[Point; 4]],
+    dice_rate: (usize, usize), // Micropolygons along u and v
+    leaves: &'a [PatchLeaf],
+    accel: BVH4<'a>,
+    cache_id: usize,
+}
+
+/// A rectangle of micropolygons in a diced patch's grid.
+#[derive(Copy, Clone, Debug)]
+struct PatchLeaf {
+    u_range: (usize, usize), // In micropolygons, inclusive of both ends' vertices
+    v_range: (usize, usize),
+    index: usize, // The leaf's index before the BVH reordered them
+}
+
+impl<'a> DicedBilinearPatch<'a> {
+    /// The number of micropolygons the patch is diced into along u and v.
+    pub fn dice_rate(&self) -> (usize, usize) {
+        self.dice_rate
+    }
+
+    fn dice_leaf(&self, leaf: &PatchLeaf) -> DicedGrid {
+        let res = (
+            leaf.u_range.1 - leaf.u_range.0 + 1,
+            leaf.v_range.1 - leaf.v_range.0 + 1,
+        );
+        let vert_count = res.0 * res.1 * self.control_points.len();
+        let mut vertices = Vec::with_capacity(vert_count);
+        let mut normals = Vec::with_capacity(vert_count);
+        for v in leaf.v_range.0..=leaf.v_range.1 {
+            for u in leaf.u_range.0..=leaf.u_range.1 {
+                let uv = grid_uv(self.dice_rate, u, v);
+                for &patch in self.control_points {
+                    vertices.push(bilerp_point(patch, uv));
+                    normals.push(bilerp_normal(patch, uv));
+                }
+            }
+        }
+
+        DicedGrid {
+            res: res,
+            time_sample_count: self.control_points.len(),
+            vertices: vertices,
+            normals: normals,
+        }
+    }
+}
+
+impl<'a> Boundable for DicedBilinearPatch<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.accel.bounds()
+    }
+}
+
+impl<'a> Surface for DicedBilinearPatch<'a> {
+    fn stats(&self) -> SurfaceStats {
+        // Diced grids are cached per thread as they're needed, rather than
+        // stored with the patch, so they aren't counted here.
+        SurfaceStats {
+            primitive_type: "micropolygons",
+            primitive_count: self.dice_rate.0 * self.dice_rate.1,
+            bytes: std::mem::size_of_val(self.control_points)
+                + std::mem::size_of_val(self.leaves)
+                + self.accel.size_in_bytes(),
+            bvh: Some(self.accel.stats()),
+        }
+    }
+
+    fn intersect_closest(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Closest);
+    }
+
+    fn intersect_any(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Any);
+    }
+
+    fn shade_hit(&self, ctx: ShadeContext) -> SurfaceIntersection {
+        let ShadeContext {
+            hit,
+            rays,
+            ray_idx,
+            shader,
+            space,
+            spaces,
+            object_id,
+            instance_id,
+            instance_index,
+            ..
+        } = ctx;
+        let ray_time = rays.time(ray_idx);
+        let (mat_space, winding) = ray_space(space, ray_time);
+        let leaf_i = hit.prim as usize;
+        let grid = get_or_dice((self.cache_id, leaf_i), || {
+            self.dice_leaf(&self.leaves[leaf_i])
+        });
+        let row = grid.res.0;
+        let tri_i = micropoly_triangle(row, hit.sub_prim as usize);
+        let vert = |vi: usize| world_vert(&grid, vi, ray_time, space, &mat_space);
+        let tri = (vert(tri_i.0), vert(tri_i.1), vert(tri_i.2));
+        let (b0, b1, b2) = hit.coords;
+
+        let (pos, pos_err) = triangle::surface_point(tri, (b0, b1, b2));
+        let geo_normal = cross(tri.1 - tri.0, tri.2 - tri.0).into_normal() * winding;
+
+        let tsc = grid.time_sample_count;
+        let normal = |vi: usize| {
+            lerp_slice(&grid.normals[(vi * tsc)..((vi + 1) * tsc)], ray_time).normalized()
+        };
+        let s_nor =
+            ((normal(tri_i.0) * b0) + (normal(tri_i.1) * b1) + (normal(tri_i.2) * b2)) * mat_space;
+        let shading_normal = if dot(s_nor, geo_normal) >= 0.0 {
+            s_nor
+        } else {
+            -s_nor
+        };
+
+        // Surface coordinates of the patch, from where the micropolygon's
+        // vertices were diced.
+        let leaf = &self.leaves[leaf_i];
+        let vert_uv = |vi: usize| {
+            grid_uv(
+                self.dice_rate,
+                leaf.u_range.0 + (vi % row),
+                leaf.v_range.0 + (vi / row),
+            )
+        };
+        let (uv0, uv1, uv2) = (vert_uv(tri_i.0), vert_uv(tri_i.1), vert_uv(tri_i.2));
+        let uv = (
+            (uv0.0 * b0) + (uv1.0 * b1) + (uv2.0 * b2),
+            (uv0.1 * b0) + (uv1.1 * b1) + (uv2.1 * b2),
+        );
+        let (dpdu, dpdv) = triangle::uv_derivatives(tri, (uv0, uv1, uv2))
+            .unwrap_or_else(|| arbitrary_tangents(geo_normal));
+
+        let intersection_data = SurfaceIntersectionData {
+            incoming: rays.dir(ray_idx),
+            t: hit.t,
+            pos: pos,
+            pos_err: pos_err,
+            nor: shading_normal,
+            nor_g: geo_normal,
+            uv: uv,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            local_space: mat_space,
+            footprint: rays.spread(ray_idx) * hit.t,
+            sample_pdf: 0.0,
+            object_id: object_id,
+            instance_id: instance_id,
+            instance_index: instance_index,
+        };
+
+        SurfaceIntersection::Hit {
+            intersection_data: intersection_data,
+            closure: shader.shade(&intersection_data, &spaces, &HitPrimvars::none(), ray_time),
+        }
+    }
+}
+
+impl<'a> DicedBilinearPatch<'a> {
+    fn intersect(&self, ctx: IntersectContext, query: HitQuery) {
+        let IntersectContext {
+            rays,
+            ray_stack,
+            hits,
+            shader,
+            space,
+            source,
+            ..
+        } = ctx;
+
+        // Precalculate transform for non-motion blur cases
+        let (static_mat_space, static_winding) = ray_space(space, 0.0);
+
+        // Whether hits need to run the shader's any-hit test.
+        let any_hit = shader.has_opacity();
+
+        self.accel
+            .traverse(rays, ray_stack, |idx_range, rays, ray_stack| {
+                for leaf_i in idx_range {
+                    let grid = get_or_dice((self.cache_id, leaf_i), || {
+                        self.dice_leaf(&self.leaves[leaf_i])
+                    });
+
+                    // Without motion blur, the leaf's vertices only need to be
+                    // put in world space once for all of the rays.
+                    let static_verts = if grid.time_sample_count == 1 && space.len() <= 1 {
+                        Some(world_verts(&grid, 0.0, space, &static_mat_space))
+                    } else {
+                        None
+                    };
+
+                    ray_stack.do_next_task(|ray_idx| {
+                        if rays.is_done(ray_idx) {
+                            return;
+                        }
+                        let ray_time = rays.time(ray_idx);
+
+                        // Calculate the ray space, if necessary.
+                        let (mat_space, winding) = if space.len() > 1 {
+                            // Per-ray transform, for motion blur
+                            ray_space(space, ray_time)
+                        } else {
+                            (static_mat_space, static_winding)
+                        };
+                        let verts = static_verts
+                            .unwrap_or_else(|| world_verts(&grid, ray_time, space, &mat_space));
+
+                        // Whether to skip micropolygons facing away from the ray.
+                        let cull = rays.is_camera(ray_idx) && shader.backface_cull();
+
+                        // Test the ray against each micropolygon's two triangles.
+                        let ray_pre = triangle::RayTriPrecompute::new(rays.dir(ray_idx));
+                        let row = grid.res.0;
+                        for tri_n in 0..((grid.res.0 - 1) * (grid.res.1 - 1) * 2) {
+                            let tri_i = micropoly_triangle(row, tri_n);
+                            let tri = (verts[tri_i.0], verts[tri_i.1], verts[tri_i.2]);
+                            if cull
+                                && dot(cross(tri.1 - tri.0, tri.2 - tri.0), rays.dir(ray_idx))
+                                    * winding
+                                    > 0.0
+                            {
+                                continue;
+                            }
+
+                            if let Some((t, b0, b1, b2)) = triangle::intersect_ray(
+                                rays.orig(ray_idx),
+                                ray_pre,
+                                rays.max_t(ray_idx),
+                                tri,
+                            ) {
+                                if any_hit {
+                                    let (pos, _) = triangle::surface_point(tri, (b0, b1, b2));
+                                    if !is_opaque_hit(
+                                        shader,
+                                        rays,
+                                        ray_idx,
+                                        t,
+                                        pos,
+                                        &HitPrimvars::none(),
+                                    ) {
+                                        continue;
+                                    }
+                                }
+
+                                let mut hit = SurfaceHit::new(t, leaf_i, (b0, b1, b2), source);
+                                hit.sub_prim = tri_n as u32;
+                                hits[ray_idx] = Some(hit);
+                                if query == HitQuery::Any {
+                                    rays.mark_done(ray_idx);
+                                    break;
+                                }
+                                rays.set_max_t(ray_idx, t);
+                            }
+                        }
+                    });
+                }
+                ray_stack.pop_task();
+            });
+    }
+}
+
+/// The vertex indices of the `n`th micropolygon triangle of a grid with
+/// `row` vertices per row.  Each micropolygon is split into two triangles.
+fn micropoly_triangle(row: usize, n: usize) -> (usize, usize, usize) {
+    let cells_per_row = row - 1;
+    let cell = n / 2;
+    let i = ((cell / cells_per_row) * row) + (cell % cells_per_row);
+    if n % 2 == 0 {
+        (i, i + 1, i + row + 1)
+    } else {
+        (i, i + row + 1, i + row)
+    }
+}
+
+/// The vertices of a diced grid at the given time, in world space.
+fn world_verts(
+    grid: &DicedGrid,
+    time: f32,
+    space: &[Matrix4x4],
+    mat_space: &Matrix4x4,
+) -> [Point; MAX_LEAF_VERTS] {
+    let mut verts = [Point::new(0.0, 0.0, 0.0); MAX_LEAF_VERTS];
+    for (i, vert) in verts[..(grid.res.0 * grid.res.1)].iter_mut().enumerate() {
+        *vert = world_vert(grid, i, time, space, mat_space);
+    }
+    verts
+}
+
+/// One vertex of a diced grid at the given time, in world space.
+fn world_vert(
+    grid: &DicedGrid,
+    vi: usize,
+    time: f32,
+    space: &[Matrix4x4],
+    mat_space: &Matrix4x4,
+) -> Point {
+    let tsc = grid.time_sample_count;
+    let vert = lerp_slice(&grid.vertices[(vi * tsc)..((vi + 1) * tsc)], time);
+    if space.is_empty() {
+        vert
+    } else {
+        vert * *mat_space
+    }
+}
+
+/// The patch coordinates of a vertex of a grid diced at `dice_rate`.
+fn grid_uv(dice_rate: (usize, usize), u: usize, v: usize) -> (f32, f32) {
+    (u as f32 / dice_rate.0 as f32, v as f32 / dice_rate.1 as f32)
+}
+
+/// The surface normal of a patch, facing the same way as the geometric
+/// normals of the micropolygons it's diced into.
+fn bilerp_normal(patch: [Point; 4], uv: (f32, f32)) -> Normal {
+    let dpdu = lerp(patch[1] - patch[0], patch[2] - patch[3], uv.1);
+    let dpdv = lerp(patch[3] - patch[0], patch[2] - patch[1], uv.0);
+    let n = cross(dpdu, dpdv);
+    let n = if n.length2() > 0.0 {
+        n
+    } else {
+        // Degenerate corner, so use the normal of the patch as a whole.
+        cross(patch[2] - patch[0], patch[3] - patch[1])
+    };
+    n.normalized().into_normal()
+}
+
 fn bilerp_point(patch: [Point; 4], uv: (f32, f32)) -> Point {
     let a = lerp(patch[0], patch[1], uv.0);
     let b = lerp(patch[3], patch[2], uv.0);
@@ -137,3 +571,45 @@ impl<'a> Splitable for BilinearSubPatch<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn diced_leaves_share_edge_vertices() {
+        let arena = Arena::new();
+        let patch = BilinearPatch::new(
+            &arena,
+            &[[
+                Point::new(-1.0, -1.0, 0.5),
+                Point::new(1.0, -1.0, -0.5),
+                Point::new(1.0, 1.0, 0.5),
+                Point::new(-1.0, 1.0, -0.5),
+            ]],
+        );
+        let diced = patch.dice(&arena, |p1, p2| (p2 - p1).length() * 10.0);
+        assert_eq!(diced.dice_rate(), (23, 23));
+        assert_eq!(diced.leaves.len(), 36);
+
+        // Every leaf must dice the grid vertices it shares with its
+        // neighbors identically, or there would be cracks between them.
+        let mut grid_verts = HashMap::new();
+        let mut micropoly_count = 0;
+        for leaf in diced.leaves {
+            let grid = diced.dice_leaf(leaf);
+            assert_eq!(grid.vertices.len(), grid.res.0 * grid.res.1);
+            micropoly_count += (grid.res.0 - 1) * (grid.res.1 - 1);
+
+            for (i, &vert) in grid.vertices.iter().enumerate() {
+                let u = leaf.u_range.0 + (i % grid.res.0);
+                let v = leaf.v_range.0 + (i / grid.res.0);
+                let shared = *grid_verts.entry((u, v)).or_insert(vert);
+                assert_eq!(shared.co, vert.co);
+            }
+        }
+        assert_eq!(micropoly_count, 23 * 23);
+        assert_eq!(grid_verts.len(), 24 * 24);
+    }
+}
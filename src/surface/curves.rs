@@ -0,0 +1,542 @@
+#![allow(dead_code)]
+
+use kioku::Arena;
+
+use crate::{
+    accel::CurveBVH,
+    bbox::BBox,
+    boundable::Boundable,
+    fp_utils::{fp_gamma, transform_point_err},
+    lerp::{lerp, lerp_slice},
+    math::{clamp, cross, dot, Matrix4x4, Point, Vector},
+};
+
+use super::{
+    is_opaque_hit,
+    primvar::{HitPrimvars, Primvar},
+    HitQuery, IntersectContext, ShadeContext, Surface, SurfaceHit, SurfaceIntersection,
+    SurfaceIntersectionData, SurfaceStats,
+};
+
+const MAX_LEAF_SEGMENT_COUNT: usize = 4;
+
+/// A set of strands, such as hair or fur.
+///
+/// Each strand is a polyline, and each of its segments is a round tube
+/// whose radius varies linearly between the segment's end points.
+///
+/// Strands can have primvars, for e.g. per-strand colors or opacity that
+/// fades towards the tips.  Uniform primvars have one value per strand,
+/// and varying and facevarying ones one per vertex, interpolated along
+/// the strand.
+#[derive(Copy, Clone, Debug)]
+pub struct Curves<'a> {
+    vertices: &'a [Point],
+    radii: &'a [f32],    // One per vertex
+    segments: &'a [u32], // Index of each segment's first vertex
+    accel: CurveBVH<'a>,
+
+    primvars: &'a [Primvar<'a>],
+    strand_starts: &'a [u32], // Index of each strand's first vertex, if there are primvars
+}
+
+impl<'a> Curves<'a> {
+    /// Creates a set of strands.
+    ///
+    /// `strand_vert_counts` gives the number of vertices in each strand,
+    /// each of which must be at least two.  The strands' vertices are
+    /// consecutive in `verts`, and `radii` has one radius per vertex.
+    pub fn from_strands<'b>(
+        arena: &'b Arena,
+        verts: &[Point],
+        radii: &[f32],
+        strand_vert_counts: &[usize],
+    ) -> Curves<'b> {
+        debug_assert_eq!(verts.len(), radii.len());
+        debug_assert_eq!(verts.len(), strand_vert_counts.iter().sum::<usize>());
+
+        let mut segments = Vec::new();
+        let mut first_vert = 0;
+        for &count in strand_vert_counts {
+            debug_assert!(count >= 2);
+            for i in 0..(count - 1) {
+                segments.push((first_vert + i) as u32);
+            }
+            first_vert += count;
+        }
+
+        let accel =
+            CurveBVH::from_objects(arena, &mut segments[..], MAX_LEAF_SEGMENT_COUNT, |&i| {
+                let i = i as usize;
+                (verts[i], verts[i + 1], radii[i].max(radii[i + 1]))
+            });
+
+        Curves {
+            vertices: arena.copy_slice(verts),
+            radii: arena.copy_slice(radii),
+            segments: arena.copy_slice(&segments),
+            accel: accel,
+
+            primvars: &[],
+            strand_starts: &[],
+        }
+    }
+
+    /// Adds primvars to the strands.
+    ///
+    /// `strand_vert_counts` must be the same as the strands were created
+    /// with.
+    pub fn with_primvars(
+        self,
+        arena: &'a Arena,
+        primvars: &[Primvar<'a>],
+        strand_vert_counts: &[usize],
+    ) -> Curves<'a> {
+        debug_assert_eq!(
+            self.vertices.len(),
+            strand_vert_counts.iter().sum::<usize>()
+        );
+
+        let mut strand_starts = Vec::with_capacity(strand_vert_counts.len());
+        let mut first_vert = 0;
+        for &count in strand_vert_counts {
+            strand_starts.push(first_vert as u32);
+            first_vert += count;
+        }
+
+        Curves {
+            primvars: arena.copy_slice(primvars),
+            strand_starts: arena.copy_slice(&strand_starts),
+            ..self
+        }
+    }
+}
+
+impl<'a> Boundable for Curves<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.accel.bounds()
+    }
+}
+
+impl<'a> Surface for Curves<'a> {
+    fn stats(&self) -> SurfaceStats {
+        SurfaceStats {
+            primitive_type: "curve segments",
+            primitive_count: self.segments.len(),
+            bytes: std::mem::size_of_val(self.vertices)
+                + std::mem::size_of_val(self.radii)
+                + std::mem::size_of_val(self.segments)
+                + std::mem::size_of_val(self.strand_starts)
+                + self.accel.size_in_bytes(),
+            bvh: None,
+        }
+    }
+
+    fn intersect_closest(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Closest);
+    }
+
+    fn intersect_any(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Any);
+    }
+
+    fn shade_hit(&self, ctx: ShadeContext) -> SurfaceIntersection {
+        let ShadeContext {
+            hit,
+            rays,
+            ray_idx,
+            shader,
+            space,
+            spaces,
+            object_id,
+            instance_id,
+            instance_index,
+            ..
+        } = ctx;
+        let time = rays.time(ray_idx);
+        let xform = local_space(space, time);
+        let orig = rays.orig(ray_idx) * xform;
+        let dir = rays.dir(ray_idx) * xform;
+
+        // Find the hit on the segment again, for its position and normal.
+        let vi = hit.prim as usize;
+        let (t, pos, pos_err, nor) = intersect_segment(
+            orig,
+            dir,
+            hit.t,
+            (self.vertices[vi], self.radii[vi]),
+            (self.vertices[vi + 1], self.radii[vi + 1]),
+        )
+        .unwrap_or((hit.t, orig + (dir * hit.t), 0.0, -dir.normalized()));
+
+        // u runs from the start to the end of the segment, and there's no
+        // v to speak of.
+        let axis = self.vertices[vi + 1] - self.vertices[vi];
+        let u = self.segment_u(vi, pos);
+
+        let inv_xform = xform.inverse();
+        let (pos, pos_err) = transform_point_err(pos, pos_err, &inv_xform);
+        let normal = nor.into_normal() * inv_xform;
+        let dpdu = axis * inv_xform;
+
+        let intersection_data = SurfaceIntersectionData {
+            incoming: rays.dir(ray_idx),
+            t: t,
+            pos: pos,
+            pos_err: pos_err,
+            nor: normal,
+            nor_g: normal,
+            uv: (u, 0.0),
+            dpdu: dpdu,
+            dpdv: cross(normal.into_vector(), dpdu),
+            local_space: xform,
+            footprint: rays.spread(ray_idx) * t,
+            sample_pdf: 0.0,
+            object_id: object_id,
+            instance_id: instance_id,
+            instance_index: instance_index,
+        };
+
+        SurfaceIntersection::Hit {
+            intersection_data: intersection_data,
+            closure: shader.shade(&intersection_data, &spaces, &self.hit_primvars(vi, u), time),
+        }
+    }
+}
+
+impl<'a> Curves<'a> {
+    fn intersect(&self, ctx: IntersectContext, query: HitQuery) {
+        let IntersectContext {
+            rays,
+            ray_stack,
+            hits,
+            shader,
+            space,
+            source,
+            ..
+        } = ctx;
+
+        // Whether hits need to run the shader's any-hit test.
+        let any_hit = shader.has_opacity();
+
+        ray_stack.pop_do_next_task(|ray_idx| {
+            if rays.is_done(ray_idx) {
+                return;
+            }
+
+            // Get the ray in local space
+            let xform = local_space(space, rays.time(ray_idx));
+            let orig = rays.orig(ray_idx) * xform;
+            let dir = rays.dir(ray_idx) * xform;
+
+            // Find the nearest hit
+            self.accel
+                .traverse(rays, ray_idx, orig, dir, |segment_range, rays| {
+                    for &vi in &self.segments[segment_range] {
+                        let vi = vi as usize;
+                        if let Some(seg_hit) = intersect_segment(
+                            orig,
+                            dir,
+                            rays.max_t(ray_idx),
+                            (self.vertices[vi], self.radii[vi]),
+                            (self.vertices[vi + 1], self.radii[vi + 1]),
+                        ) {
+                            if any_hit
+                                && !is_opaque_hit(
+                                    shader,
+                                    rays,
+                                    ray_idx,
+                                    seg_hit.0,
+                                    seg_hit.1 * xform.inverse(),
+                                    &self.hit_primvars(vi, self.segment_u(vi, seg_hit.1)),
+                                )
+                            {
+                                continue;
+                            }
+
+                            hits[ray_idx] =
+                                Some(SurfaceHit::new(seg_hit.0, vi, (0.0, 0.0, 0.0), source));
+                            if query == HitQuery::Any {
+                                rays.mark_done(ray_idx);
+                                return;
+                            }
+                            rays.set_max_t(ray_idx, seg_hit.0);
+                        }
+                    }
+                });
+        });
+    }
+
+    /// How far along the segment starting at vertex `vi` the local-space
+    /// point `pos` is, from 0.0 to 1.0.
+    fn segment_u(&self, vi: usize, pos: Point) -> f32 {
+        let axis = self.vertices[vi + 1] - self.vertices[vi];
+        clamp(
+            dot(pos - self.vertices[vi], axis) / dot(axis, axis),
+            0.0,
+            1.0,
+        )
+    }
+
+    /// The strands' primvars at `u` along the segment starting at vertex
+    /// `vi`.
+    fn hit_primvars(&self, vi: usize, u: f32) -> HitPrimvars<'a> {
+        if self.primvars.is_empty() {
+            return HitPrimvars::none();
+        }
+
+        let strand = match self.strand_starts.binary_search(&(vi as u32)) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let ends = (vi, vi + 1, vi + 1);
+        HitPrimvars::new(self.primvars, strand, ends, ends, (1.0 - u, u, 0.0))
+    }
+}
+
+/// The world-to-object transform of `space` at the given time.
+fn local_space(space: &[Matrix4x4], time: f32) -> Matrix4x4 {
+    if space.is_empty() {
+        Matrix4x4::new()
+    } else {
+        lerp_slice(space, time)
+    }
+}
+
+/// Intersects a ray with one segment of a strand.
+///
+/// The segment is treated as a tube whose radius varies linearly between
+/// its ends, which are open.  This is only approximate for segments whose
+/// radius varies, but hair tapers slowly enough for it not to matter.
+///
+/// Returns the t value, position, position error, and outward normal of
+/// the hit.
+pub fn intersect_segment(
+    orig: Point,
+    dir: Vector,
+    max_t: f32,
+    v0: (Point, f32),
+    v1: (Point, f32),
+) -> Option<(f32, Point, f32, Vector)> {
+    let (p0, r0) = v0;
+    let (p1, r1) = v1;
+
+    let axis = p1 - p0;
+    let w = orig - p0;
+    let a = dot(dir, dir);
+    let b = dot(dir, axis);
+    let c = dot(axis, axis);
+    let d = dot(dir, w);
+    let e = dot(axis, w);
+    let denom = (a * c) - (b * b);
+    if denom <= 0.0 || c <= 0.0 {
+        // Degenerate segment, or ray parallel to it.
+        return None;
+    }
+
+    // Closest approach of the ray to the segment's axis.
+    let s_close = ((a * e) - (b * d)) / denom;
+    let t_close = ((b * e) - (c * d)) / denom;
+    let radius = lerp(r0, r1, clamp(s_close, 0.0, 1.0));
+    let dist2 = ((w + (dir * t_close)) - (axis * s_close)).length2();
+    if dist2 >= (radius * radius) {
+        return None;
+    }
+
+    // Step back from the closest approach to the tube's surface.  The ray
+    // approaches the axis at the speed of its direction's component
+    // perpendicular to it.
+    let perp_speed2 = denom / c;
+    let t = t_close - (((radius * radius) - dist2) / perp_speed2).sqrt();
+    if t <= 0.0 || t > max_t {
+        return None;
+    }
+
+    // Find where along the segment the hit is, and re-project the hit
+    // position onto the tube's surface from there.
+    let s = dot(w + (dir * t), axis) / c;
+    if !(0.0..=1.0).contains(&s) {
+        return None;
+    }
+    let axis_point = p0 + (axis * s);
+    let nor = ((orig + (dir * t)) - axis_point).normalized();
+    let hit_radius = lerp(r0, r1, s);
+    let pos = axis_point + (nor * hit_radius);
+    let pos_err = fp_gamma(7) * (axis_point.co.abs().max_element() + hit_radius);
+
+    Some((t, pos, pos_err, nor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hash::hash_u32_to_f32, ray::RayBatch};
+
+    #[test]
+    fn segment_hit() {
+        let v0 = (Point::new(0.0, -1.0, 0.0), 0.1);
+        let v1 = (Point::new(0.0, 1.0, 0.0), 0.1);
+
+        // Straight at the axis.
+        let (t, pos, _, nor) = intersect_segment(
+            Point::new(0.0, 0.0, -2.0),
+            Vector::new(0.0, 0.0, 1.0),
+            10.0,
+            v0,
+            v1,
+        )
+        .unwrap();
+        assert!((t - 1.9).abs() < 1.0e-5);
+        assert!((pos.z() + 0.1).abs() < 1.0e-5);
+        assert!((nor.z() + 1.0).abs() < 1.0e-5);
+
+        // Grazing the side, with an unnormalized direction.
+        let (t, _, _, nor) = intersect_segment(
+            Point::new(0.06, 0.5, -2.0),
+            Vector::new(0.0, 0.0, 2.0),
+            10.0,
+            v0,
+            v1,
+        )
+        .unwrap();
+        assert!((t - 0.96).abs() < 1.0e-5);
+        assert!((nor.x() - 0.6).abs() < 1.0e-4);
+
+        // Misses: beside the tube, past its end, too short, and starting
+        // on its surface heading away.
+        let dir = Vector::new(0.0, 0.0, 1.0);
+        assert!(intersect_segment(Point::new(0.2, 0.0, -2.0), dir, 10.0, v0, v1).is_none());
+        assert!(intersect_segment(Point::new(0.0, 1.1, -2.0), dir, 10.0, v0, v1).is_none());
+        assert!(intersect_segment(Point::new(0.0, 0.0, -2.0), dir, 1.8, v0, v1).is_none());
+        assert!(intersect_segment(Point::new(0.0, 0.0, 0.1), dir, 10.0, v0, v1).is_none());
+    }
+
+    #[test]
+    fn bvh_finds_nearest_hit() {
+        let arena = Arena::new();
+
+        // A clump of diagonal strands.
+        let mut verts = Vec::new();
+        let mut radii = Vec::new();
+        let mut counts = Vec::new();
+        for i in 0..200 {
+            let root = Point::new(
+                hash_u32_to_f32(i, 0) - 0.5,
+                hash_u32_to_f32(i, 1) - 0.5,
+                hash_u32_to_f32(i, 2) - 0.5,
+            );
+            for j in 0..5 {
+                let f = j as f32 * 0.2;
+                verts.push(root + Vector::new(f, f, f * 0.5 + hash_u32_to_f32(i, 3) * 0.1));
+                radii.push(0.02);
+            }
+            counts.push(5);
+        }
+        let curves = Curves::from_strands(&arena, &verts, &radii, &counts);
+
+        let mut rays = RayBatch::new();
+        for i in 0..500 {
+            let orig = Point::new(
+                hash_u32_to_f32(i, 10) * 2.0 - 0.5,
+                hash_u32_to_f32(i, 11) * 2.0 - 0.5,
+                -3.0,
+            );
+            let dir = Vector::new(
+                hash_u32_to_f32(i, 12) - 0.5,
+                hash_u32_to_f32(i, 13) - 0.5,
+                4.0,
+            );
+
+            // Brute force.
+            let mut nearest = f32::INFINITY;
+            for &vi in curves.segments {
+                let vi = vi as usize;
+                if let Some((t, _, _, _)) = intersect_segment(
+                    orig,
+                    dir,
+                    nearest,
+                    (verts[vi], radii[vi]),
+                    (verts[vi + 1], radii[vi + 1]),
+                ) {
+                    nearest = t;
+                }
+            }
+
+            // BVH.
+            rays.clear();
+            rays.push(
+                crate::ray::Ray {
+                    orig: orig,
+                    dir: dir,
+                    time: 0.0,
+                    wavelength: 500.0,
+                    max_t: f32::INFINITY,
+                },
+                false,
+            );
+            curves
+                .accel
+                .traverse(&mut rays, 0, orig, dir, |range, rays| {
+                    for &vi in &curves.segments[range] {
+                        let vi = vi as usize;
+                        if let Some((t, _, _, _)) = intersect_segment(
+                            orig,
+                            dir,
+                            rays.max_t(0),
+                            (verts[vi], radii[vi]),
+                            (verts[vi + 1], radii[vi + 1]),
+                        ) {
+                            rays.set_max_t(0, t);
+                        }
+                    }
+                });
+
+            assert_eq!(rays.max_t(0), nearest);
+        }
+    }
+
+    #[test]
+    fn strand_primvars() {
+        use crate::surface::primvar::{Interpolation, PrimvarData, PrimvarType, PrimvarValue};
+
+        let arena = Arena::new();
+        let verts = [
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(0.0, 0.0, 1.0),
+            Point::new(0.0, 0.0, 2.0),
+            Point::new(1.0, 0.0, 0.0),
+            Point::new(1.0, 0.0, 1.0),
+        ];
+        let strand_ids = [7, 8];
+        let opacities = [1.0, 1.0, 0.0, 1.0, 0.5];
+        let primvars = [
+            Primvar {
+                name: "id",
+                primvar_type: PrimvarType::Int,
+                interpolation: Interpolation::Uniform,
+                data: PrimvarData::Int(&strand_ids),
+            },
+            Primvar {
+                name: "opacity",
+                primvar_type: PrimvarType::Float,
+                interpolation: Interpolation::Varying,
+                data: PrimvarData::Float(&opacities),
+            },
+        ];
+        let curves = Curves::from_strands(&arena, &verts, &[0.1; 5], &[3, 2]).with_primvars(
+            &arena,
+            &primvars,
+            &[3, 2],
+        );
+
+        // A quarter of the way along the first strand's second segment.
+        let hit = curves.hit_primvars(1, 0.25);
+        assert_eq!(hit.get("id"), Some(PrimvarValue::Int(7)));
+        assert_eq!(hit.get("opacity"), Some(PrimvarValue::Float(0.75)));
+
+        // Halfway along the second strand.
+        let hit = curves.hit_primvars(3, 0.5);
+        assert_eq!(hit.get("id"), Some(PrimvarValue::Int(8)));
+        assert_eq!(hit.get("opacity"), Some(PrimvarValue::Float(0.75)));
+    }
+}
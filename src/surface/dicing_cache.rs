@@ -0,0 +1,167 @@
+//! A per-thread cache of diced geometry.
+//!
+//! Surfaces that are diced into micropolygons don't dice all of themselves
+//! up front.  Instead each leaf of their acceleration structure is diced
+//! the first time a ray reaches it, and the resulting grid is kept here
+//! until it's evicted to make room for more recently used grids.
+//!
+//! Each render thread has its own cache, so that looking up a grid never
+//! has to wait on other threads.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::math::{Normal, Point};
+
+/// The most memory each thread's cache uses for diced grids, in bytes.
+pub const CACHE_SIZE: usize = 64 << 20;
+
+/// Identifies a diced grid: the id of the surface it's from (see
+/// `new_surface_id()`), and the index of the leaf within that surface.
+pub type GridKey = (usize, usize);
+
+/// A grid of micropolygon vertices.
+#[derive(Debug)]
+pub struct DicedGrid {
+    pub res: (usize, usize), // Vertex count along u and v
+    pub time_sample_count: usize,
+    pub vertices: Vec<Point>, // Row-major, with each vertex's time samples contiguous
+    pub normals: Vec<Normal>, // Organized the same as `vertices`
+}
+
+impl DicedGrid {
+    fn size_in_bytes(&self) -> usize {
+        std::mem::size_of::<DicedGrid>()
+            + (self.vertices.len() * std::mem::size_of::<Point>())
+            + (self.normals.len() * std::mem::size_of::<Normal>())
+    }
+}
+
+/// Returns a new unique id for a diced surface.
+///
+/// Ids are never reused, so grids left over from a previous scene can't be
+/// mistaken for ones from the current scene.
+pub fn new_surface_id() -> usize {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Returns the grid with the given key from this thread's cache, calling
+/// `dice` to create it if it isn't there.
+pub fn get_or_dice<F>(key: GridKey, dice: F) -> Rc<DicedGrid>
+where
+    F: FnOnce() -> DicedGrid,
+{
+    if let Some(grid) = CACHE.with(|cache| cache.borrow_mut().get(key)) {
+        return grid;
+    }
+
+    let grid = Rc::new(dice());
+    DICED_MICROPOLYS.with(|dm| {
+        let micropolys = (grid.res.0.max(1) - 1) * (grid.res.1.max(1) - 1);
+        dm.set(dm.get() + micropolys as u64);
+    });
+    CACHE.with(|cache| cache.borrow_mut().insert(key, grid.clone()));
+
+    grid
+}
+
+thread_local! {
+    static CACHE: RefCell<GridCache> = RefCell::new(GridCache::new(CACHE_SIZE));
+
+    /// The number of micropolygons diced by this thread, including any
+    /// that were diced again after being evicted.
+    pub static DICED_MICROPOLYS: Cell<u64> = Cell::new(0);
+}
+
+/// A least-recently-used cache of diced grids.
+#[derive(Debug)]
+struct GridCache {
+    grids: HashMap<GridKey, (Rc<DicedGrid>, u64)>, // Grid and its last use
+    by_last_use: BTreeMap<u64, GridKey>,
+    clock: u64,
+    size: usize,
+    max_size: usize,
+}
+
+impl GridCache {
+    fn new(max_size: usize) -> GridCache {
+        GridCache {
+            grids: HashMap::new(),
+            by_last_use: BTreeMap::new(),
+            clock: 0,
+            size: 0,
+            max_size: max_size,
+        }
+    }
+
+    fn get(&mut self, key: GridKey) -> Option<Rc<DicedGrid>> {
+        self.clock += 1;
+        let (grid, last_use) = self.grids.get_mut(&key)?;
+        self.by_last_use.remove(last_use);
+        self.by_last_use.insert(self.clock, key);
+        *last_use = self.clock;
+
+        Some(grid.clone())
+    }
+
+    fn insert(&mut self, key: GridKey, grid: Rc<DicedGrid>) {
+        self.clock += 1;
+        self.size += grid.size_in_bytes();
+        if let Some((old_grid, last_use)) = self.grids.insert(key, (grid, self.clock)) {
+            self.size -= old_grid.size_in_bytes();
+            self.by_last_use.remove(&last_use);
+        }
+        self.by_last_use.insert(self.clock, key);
+
+        // Evict the least recently used grids until there's room, always
+        // keeping the one just inserted.
+        while self.size > self.max_size && self.grids.len() > 1 {
+            let (&last_use, &old_key) = self.by_last_use.iter().next().unwrap();
+            self.by_last_use.remove(&last_use);
+            let (old_grid, _) = self.grids.remove(&old_key).unwrap();
+            self.size -= old_grid.size_in_bytes();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(res: usize) -> Rc<DicedGrid> {
+        Rc::new(DicedGrid {
+            res: (res, res),
+            time_sample_count: 1,
+            vertices: vec![Point::new(0.0, 0.0, 0.0); res * res],
+            normals: vec![Normal::new(0.0, 0.0, 1.0); res * res],
+        })
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let max_size = grid(5).size_in_bytes() * 3;
+        let mut cache = GridCache::new(max_size);
+        cache.insert((0, 0), grid(5));
+        cache.insert((0, 1), grid(5));
+        cache.insert((1, 0), grid(5));
+
+        // Using the first grid makes the second one the oldest.
+        assert!(cache.get((0, 0)).is_some());
+        cache.insert((1, 1), grid(5));
+        assert!(cache.get((0, 1)).is_none());
+        assert!(cache.get((0, 0)).is_some());
+        assert!(cache.get((1, 0)).is_some());
+        assert!(cache.get((1, 1)).is_some());
+        assert!(cache.size <= max_size);
+
+        // A grid bigger than the whole cache still gets cached, alone.
+        cache.insert((2, 0), grid(20));
+        assert_eq!(cache.grids.len(), 1);
+        assert!(cache.get((2, 0)).is_some());
+    }
+}
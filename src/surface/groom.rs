@@ -0,0 +1,279 @@
+//! Grooms: hair grown procedurally from a few guide strands.
+//!
+//! Only the guides and the scalp they grow from are stored in the scene.
+//! At load time, child strands are scattered over the scalp, and each
+//! takes its shape from the guides nearest to its root, optionally
+//! clumped towards the nearest one and made frizzy.
+
+use std::f32::consts::PI;
+
+use crate::{
+    hash::hash_u32_to_f32,
+    lerp::lerp,
+    math::{cross, Point, Vector},
+};
+
+/// The number of guides whose shapes each child strand blends.
+const BLEND_GUIDES: usize = 3;
+
+/// A set of strands, as polylines.
+#[derive(Debug, Clone, Default)]
+pub struct Strands {
+    pub verts: Vec<Point>,
+    pub radii: Vec<f32>,         // One per vertex
+    pub vert_counts: Vec<usize>, // Number of vertices in each strand
+}
+
+/// How child strands are grown from the guides.
+#[derive(Debug, Copy, Clone)]
+pub struct GroomSettings {
+    pub child_count: usize,
+    pub clump: f32, // [0.0, 1.0] how far tips are pulled towards the nearest guide
+    pub frizz: f32, // Size of the frizz at the tips, relative to strand length
+    pub frizz_frequency: f32, // Number of frizz waves along a strand
+    pub seed: u32,
+}
+
+/// Grows child strands from `guides`, with roots scattered uniformly
+/// over the scalp triangles.
+///
+/// Every child has as many vertices as the guide with the most.
+pub fn grow_children(
+    scalp: &[(Point, Point, Point)],
+    guides: &Strands,
+    settings: &GroomSettings,
+) -> Strands {
+    assert!(!guides.vert_counts.is_empty());
+    assert!(!scalp.is_empty());
+
+    // Resample the guides to the same number of vertices, so they can be
+    // blended vertex by vertex.
+    let vert_count = *guides.vert_counts.iter().max().unwrap();
+    let mut guide_verts = Vec::with_capacity(guides.vert_counts.len() * vert_count);
+    let mut guide_radii = Vec::with_capacity(guides.vert_counts.len() * vert_count);
+    let mut first_vert = 0;
+    for &count in &guides.vert_counts {
+        let range = first_vert..(first_vert + count);
+        resample(
+            &guides.verts[range.clone()],
+            &guides.radii[range],
+            vert_count,
+            &mut guide_verts,
+            &mut guide_radii,
+        );
+        first_vert += count;
+    }
+    let guide = |g: usize| {
+        let range = (g * vert_count)..((g + 1) * vert_count);
+        (&guide_verts[range.clone()], &guide_radii[range])
+    };
+
+    // For picking scalp triangles in proportion to their area.
+    let mut area_cdf = Vec::with_capacity(scalp.len());
+    let mut total_area = 0.0;
+    for tri in scalp {
+        total_area += cross(tri.1 - tri.0, tri.2 - tri.0).length() * 0.5;
+        area_cdf.push(total_area);
+    }
+
+    let mut children = Strands::default();
+    for child in 0..(settings.child_count as u32) {
+        let rand =
+            |dim: u32| hash_u32_to_f32(child, settings.seed.wrapping_add(dim)).min(0.999_999);
+
+        // Root.
+        let target = rand(0) * total_area;
+        let tri_i = area_cdf
+            .iter()
+            .position(|&a| target < a)
+            .unwrap_or(scalp.len() - 1);
+        let tri = scalp[tri_i];
+        let su = rand(1).sqrt();
+        let (b1, b2) = (su * (1.0 - rand(2)), su * rand(2));
+        let root = tri.0 + ((tri.1 - tri.0) * b1) + ((tri.2 - tri.0) * b2);
+
+        // The nearest guides, and their blend weights.
+        let mut nearest = [(std::f32::INFINITY, 0usize); BLEND_GUIDES];
+        for g in 0..guides.vert_counts.len() {
+            let d2 = (guide(g).0[0] - root).length2();
+            if d2 < nearest[BLEND_GUIDES - 1].0 {
+                nearest[BLEND_GUIDES - 1] = (d2, g);
+                nearest.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            }
+        }
+        let mut weights = [0.0f32; BLEND_GUIDES];
+        let mut weight_sum = 0.0;
+        for (w, &(d2, _)) in weights.iter_mut().zip(nearest.iter()) {
+            if d2.is_finite() {
+                *w = 1.0 / (d2 + 1.0e-12);
+                weight_sum += *w;
+            }
+        }
+
+        // Random phases for the frizz, which grows towards the tip.
+        let phases = (rand(3) * 2.0 * PI, rand(4) * 2.0 * PI, rand(5) * 2.0 * PI);
+        let clump_guide = guide(nearest[0].1);
+        let length = strand_length(clump_guide.0);
+
+        for i in 0..vert_count {
+            let t = i as f32 / (vert_count - 1) as f32;
+
+            let mut offset = Vector::new(0.0, 0.0, 0.0);
+            let mut radius = 0.0;
+            for (&w, &(_, g)) in weights.iter().zip(nearest.iter()) {
+                if w > 0.0 {
+                    let (verts, radii) = guide(g);
+                    offset = offset + ((verts[i] - verts[0]) * (w / weight_sum));
+                    radius += radii[i] * (w / weight_sum);
+                }
+            }
+            let mut pos = root + offset;
+
+            pos = lerp(pos, clump_guide.0[i], settings.clump * t);
+
+            let angle = settings.frizz_frequency * t * 2.0 * PI;
+            let frizz = Vector::new(
+                (angle + phases.0).sin(),
+                (angle + phases.1).sin(),
+                (angle + phases.2).sin(),
+            );
+            pos = pos + (frizz * (settings.frizz * length * t));
+
+            children.verts.push(pos);
+            children.radii.push(radius);
+        }
+        children.vert_counts.push(vert_count);
+    }
+
+    children
+}
+
+/// Resamples a polyline to `count` vertices evenly spaced along it,
+/// appending them to `out_verts` and `out_radii`.
+fn resample(
+    verts: &[Point],
+    radii: &[f32],
+    count: usize,
+    out_verts: &mut Vec<Point>,
+    out_radii: &mut Vec<f32>,
+) {
+    let length = strand_length(verts);
+    let mut seg = 0;
+    let mut seg_start = 0.0;
+    for i in 0..count {
+        let target = length * (i as f32 / (count - 1) as f32);
+        let mut seg_length = (verts[seg + 1] - verts[seg]).length();
+        while seg_start + seg_length < target && seg < verts.len() - 2 {
+            seg_start += seg_length;
+            seg += 1;
+            seg_length = (verts[seg + 1] - verts[seg]).length();
+        }
+        let alpha = if seg_length > 0.0 {
+            ((target - seg_start) / seg_length).max(0.0).min(1.0)
+        } else {
+            0.0
+        };
+        out_verts.push(lerp(verts[seg], verts[seg + 1], alpha));
+        out_radii.push(lerp(radii[seg], radii[seg + 1], alpha));
+    }
+}
+
+fn strand_length(verts: &[Point]) -> f32 {
+    verts.windows(2).map(|w| (w[1] - w[0]).length()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scalp() -> Vec<(Point, Point, Point)> {
+        vec![
+            (
+                Point::new(0.0, 0.0, 0.0),
+                Point::new(1.0, 0.0, 0.0),
+                Point::new(1.0, 1.0, 0.0),
+            ),
+            (
+                Point::new(0.0, 0.0, 0.0),
+                Point::new(1.0, 1.0, 0.0),
+                Point::new(0.0, 1.0, 0.0),
+            ),
+        ]
+    }
+
+    fn guide() -> Strands {
+        Strands {
+            verts: vec![
+                Point::new(0.5, 0.5, 0.0),
+                Point::new(0.5, 0.5, 1.0),
+                Point::new(0.5, 0.5, 2.0),
+            ],
+            radii: vec![0.02, 0.015, 0.01],
+            vert_counts: vec![3],
+        }
+    }
+
+    fn settings() -> GroomSettings {
+        GroomSettings {
+            child_count: 100,
+            clump: 0.0,
+            frizz: 0.0,
+            frizz_frequency: 1.0,
+            seed: 0,
+        }
+    }
+
+    #[test]
+    fn children_follow_guides() {
+        let children = grow_children(&scalp(), &guide(), &settings());
+        assert_eq!(children.vert_counts, vec![3; 100]);
+        assert_eq!(children.verts.len(), 300);
+
+        for strand in children.verts.chunks(3) {
+            let root = strand[0];
+            assert!(root.x() >= 0.0 && root.x() <= 1.0);
+            assert!(root.y() >= 0.0 && root.y() <= 1.0);
+            assert!(root.z().abs() < 1.0e-6);
+            let tip = strand[2] - root;
+            assert!(tip.x().abs() < 1.0e-5 && tip.y().abs() < 1.0e-5);
+            assert!((tip.z() - 2.0).abs() < 1.0e-5);
+        }
+        for (r, er) in children.radii[..3].iter().zip([0.02, 0.015, 0.01].iter()) {
+            assert!((r - er).abs() < 1.0e-6);
+        }
+    }
+
+    #[test]
+    fn clumped_tips_meet_the_guide() {
+        let clumped = GroomSettings {
+            clump: 1.0,
+            ..settings()
+        };
+        let children = grow_children(&scalp(), &guide(), &clumped);
+        for strand in children.verts.chunks(3) {
+            let tip = strand[2] - Point::new(0.5, 0.5, 2.0);
+            assert!(tip.length() < 1.0e-5);
+        }
+    }
+
+    #[test]
+    fn resampling_is_even() {
+        let (mut verts, mut radii) = (Vec::new(), Vec::new());
+        resample(
+            &[
+                Point::new(0.0, 0.0, 0.0),
+                Point::new(0.0, 0.0, 3.0),
+                Point::new(0.0, 0.0, 4.0),
+            ],
+            &[1.0, 0.25, 0.0],
+            5,
+            &mut verts,
+            &mut radii,
+        );
+        let expected = [(0.0, 1.0), (1.0, 0.75), (2.0, 0.5), (3.0, 0.25), (4.0, 0.0)];
+        for ((v, r), &(z, er)) in verts.iter().zip(radii.iter()).zip(expected.iter()) {
+            assert!((v.z() - z).abs() < 1.0e-5);
+            assert!((r - er).abs() < 1.0e-5);
+        }
+    }
+}
@@ -14,7 +14,7 @@ use crate::{
     shading::SurfaceClosure,
 };
 
-use super::{triangle, SurfaceIntersection, SurfaceIntersectionData};
+use super::{triangle, winding_sign, SurfaceIntersection, SurfaceIntersectionData};
 
 const MAX_LEAF_TRIANGLE_COUNT: usize = 3;
 
@@ -158,6 +158,7 @@ impl<'a> MicropolyBatch<'a> {
         } else {
             Matrix4x4::new()
         };
+        let static_winding = winding_sign(&static_mat_space);
 
         self.accel
             .traverse(rays, ray_stack, |idx_range, rays, ray_stack| {
@@ -203,11 +204,12 @@ impl<'a> MicropolyBatch<'a> {
                     let ray_time = rays.time(ray_idx);
 
                     // Calculate the ray space, if necessary.
-                    let mat_space = if space.len() > 1 {
+                    let (mat_space, winding) = if space.len() > 1 {
                         // Per-ray transform, for motion blur
-                        lerp_slice(space, ray_time).inverse()
+                        let mat_space = lerp_slice(space, ray_time).inverse();
+                        (mat_space, winding_sign(&mat_space))
                     } else {
-                        static_mat_space
+                        (static_mat_space, static_winding)
                     };
 
                     // Iterate through the triangles and test the ray against them.
@@ -292,8 +294,9 @@ impl<'a> MicropolyBatch<'a> {
                         let (pos, pos_err) = triangle::surface_point(hit_tri, (b0, b1, b2));
 
                         // Calculate geometric surface normal
-                        let geo_normal =
-                            cross(hit_tri.0 - hit_tri.1, hit_tri.0 - hit_tri.2).into_normal();
+                        let geo_normal = cross(hit_tri.0 - hit_tri.1, hit_tri.0 - hit_tri.2)
+                            .into_normal()
+                            * winding;
 
                         // Calculate interpolated surface normal
                         let shading_normal = {
@@ -339,8 +342,15 @@ impl<'a> MicropolyBatch<'a> {
                             pos_err: pos_err,
                             nor: shading_normal,
                             nor_g: geo_normal,
+                            uv: (b1, b2),
+                            dpdu: hit_tri.1 - hit_tri.0,
+                            dpdv: hit_tri.2 - hit_tri.0,
                             local_space: mat_space,
+                            footprint: rays.spread(ray_idx) * t,
                             sample_pdf: 0.0,
+                            object_id: 0,
+                            instance_id: 0,
+                            instance_index: 0,
                         };
 
                         // Fill in intersection data
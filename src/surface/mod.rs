@@ -2,31 +2,125 @@
 
 // pub mod micropoly_batch;
 pub mod bilinear_patch;
+pub mod curves;
+pub mod dicing_cache;
+pub mod groom;
 pub mod micropoly_batch;
+pub mod primvar;
 pub mod triangle;
 pub mod triangle_mesh;
 
 use std::fmt::Debug;
 
+use self::primvar::HitPrimvars;
+
 use crate::{
+    accel::BVHStats,
     boundable::Boundable,
-    math::{Matrix4x4, Normal, Point, Vector},
+    hash::{hash_u32, hash_u32_to_f32},
+    lerp::lerp_slice,
+    math::{coordinate_system_from_vector, Matrix4x4, Normal, Point, Vector},
     ray::{RayBatch, RayStack},
     shading::surface_closure::SurfaceClosure,
-    shading::SurfaceShader,
+    shading::{CoordinateSpaces, SurfaceShader},
 };
 
 const MAX_EDGE_DICE: u32 = 128;
 
 pub trait Surface: Boundable + Debug + Sync {
-    fn intersect_rays(
-        &self,
-        rays: &mut RayBatch,
-        ray_stack: &mut RayStack,
-        isects: &mut [SurfaceIntersection],
-        shader: &dyn SurfaceShader,
-        space: &[Matrix4x4],
-    );
+    /// A summary of the surface's geometry, for reporting.
+    fn stats(&self) -> SurfaceStats;
+
+    /// Finds the nearest hit of each ray in the next task of the context's
+    /// ray stack, shortening the ray to it and recording the hit.  Only
+    /// what's needed to find the hit again is recorded: the full shading
+    /// context is left to `shade_hit()`, for the hits that are still the
+    /// nearest once traversal is done.  The task is popped when done.
+    fn intersect_closest(&self, ctx: IntersectContext);
+
+    /// Finds whether each ray in the next task of the context's ray stack
+    /// hits the surface at all, recording a hit and marking the ray done
+    /// if so.  Any hit will do, so implementations can stop at the first
+    /// one found.  The task is popped when done.
+    fn intersect_any(&self, ctx: IntersectContext);
+
+    /// Computes the full shading context of a hit recorded by
+    /// `intersect_closest()`, and shades it.
+    fn shade_hit(&self, ctx: ShadeContext) -> SurfaceIntersection;
+
+    /// The names of the shaders bound to groups of the surface's
+    /// primitives, if it has any.  The contexts' `group_shaders` are these
+    /// shaders, in the same order.
+    fn shader_groups(&self) -> &[&str] {
+        &[]
+    }
+}
+
+/// Which hit of a ray a query is after.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HitQuery {
+    Closest, // The nearest hit, for shading
+    Any,     // Any hit at all, for shadow rays
+}
+
+/// What a surface is given to trace a task of rays against itself.
+pub struct IntersectContext<'a> {
+    pub rays: &'a mut RayBatch,
+    pub ray_stack: &'a mut RayStack,
+    pub hits: &'a mut [Option<SurfaceHit>], // Indexed the same as `rays`
+    pub shader: &'a dyn SurfaceShader,      // For any-hit tests and culling
+    pub group_shaders: &'a [&'a dyn SurfaceShader], // See `Surface::shader_groups()`
+    pub space: &'a [Matrix4x4],             // World-to-object transform samples, if any
+    pub source: u32,                        // Copied into hits, for the tracer
+}
+
+/// The minimal record of a hit kept during traversal.
+///
+/// What `prim`, `sub_prim`, and `coords` mean is up to the surface that
+/// recorded the hit, as long as it can compute the hit's shading context
+/// from them again.
+#[derive(Debug, Copy, Clone)]
+pub struct SurfaceHit {
+    pub t: f32,
+    pub prim: u32,               // Which primitive was hit
+    pub sub_prim: u32,           // Which part of the primitive, if they have parts
+    pub coords: (f32, f32, f32), // Where on the primitive, e.g. barycentric coordinates
+    pub source: u32,             // From `IntersectContext::source`
+}
+
+impl SurfaceHit {
+    pub fn new(t: f32, prim: usize, coords: (f32, f32, f32), source: u32) -> SurfaceHit {
+        SurfaceHit {
+            t: t,
+            prim: prim as u32,
+            sub_prim: 0,
+            coords: coords,
+            source: source,
+        }
+    }
+}
+
+/// What a surface is given to shade one of its hits.
+pub struct ShadeContext<'a> {
+    pub hit: SurfaceHit,
+    pub rays: &'a RayBatch,
+    pub ray_idx: usize,
+    pub shader: &'a dyn SurfaceShader,
+    pub group_shaders: &'a [&'a dyn SurfaceShader], // See `Surface::shader_groups()`
+    pub space: &'a [Matrix4x4],                     // The same as when the hit was found
+    pub spaces: CoordinateSpaces<'a>,               // For the shader
+    pub object_id: u32,                             // See `SurfaceIntersectionData`
+    pub instance_id: u32,
+    pub instance_index: u32,
+}
+
+/// A summary of a surface's geometry.
+#[derive(Copy, Clone, Debug)]
+pub struct SurfaceStats {
+    pub primitive_type: &'static str, // E.g. "triangles"
+    pub primitive_count: usize,
+    pub bytes: usize,          // Approximate memory used
+    pub bvh: Option<BVHStats>, // Quality of the surface's BVH, if it has one
 }
 
 pub trait Splitable: Copy {
@@ -36,6 +130,62 @@ pub trait Splitable: Copy {
         F: Fn(Point, Point) -> f32;
 }
 
+/// -1.0 if `xform` mirrors space, and 1.0 otherwise.
+///
+/// Mirroring reverses the winding of transformed triangles, so normals
+/// computed from their winding need flipping by this to face the way
+/// they did before the transform.
+pub fn winding_sign(xform: &Matrix4x4) -> f32 {
+    if xform.determinant() < 0.0 {
+        -1.0
+    } else {
+        1.0
+    }
+}
+
+/// The object-to-world transform at the given time of a surface whose
+/// world-to-object transform samples are `space`, and its winding sign.
+pub fn ray_space(space: &[Matrix4x4], time: f32) -> (Matrix4x4, f32) {
+    let mat_space = if space.is_empty() {
+        Matrix4x4::new()
+    } else {
+        lerp_slice(space, time).inverse()
+    };
+    (mat_space, winding_sign(&mat_space))
+}
+
+/// Runs the shader's any-hit test on a candidate hit at distance `t` and
+/// world-space position `pos`, with the surface's primvars there,
+/// returning whether the ray stops there.
+///
+/// Partly opaque surfaces stop rays at random, in proportion to their
+/// opacity.  The choice is a hash of the ray and the hit, so that it's
+/// the same every time the same ray finds the same hit.
+pub fn is_opaque_hit(
+    shader: &dyn SurfaceShader,
+    rays: &RayBatch,
+    ray_idx: usize,
+    t: f32,
+    pos: Point,
+    primvars: &HitPrimvars,
+) -> bool {
+    let opacity = shader.opacity(pos, primvars, rays.time(ray_idx));
+    if opacity >= 1.0 {
+        return true;
+    } else if opacity <= 0.0 {
+        return false;
+    }
+
+    let orig = rays.orig(ray_idx);
+    let dir = rays.dir(ray_idx);
+    let mut seed = t.to_bits();
+    for n in &[orig.x(), orig.y(), orig.z(), dir.x(), dir.y(), dir.z()] {
+        seed = hash_u32(n.to_bits(), seed);
+    }
+
+    hash_u32_to_f32(seed, 0) < opacity
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum PointOrder {
     AsIs,
@@ -78,6 +228,10 @@ pub enum SurfaceIntersection {
     },
 }
 
+/// The shading context of a ray hit: everything shaders get to know about
+/// the point they're shading.
+///
+/// Everything is in world space unless noted otherwise.
 #[derive(Debug, Copy, Clone)]
 pub struct SurfaceIntersectionData {
     pub incoming: Vector, // Direction of the incoming ray
@@ -86,7 +240,87 @@ pub struct SurfaceIntersectionData {
     // a cube centered around `pos` with dimensions of `2 * pos_err`.
     pub nor: Normal,            // Shading normal
     pub nor_g: Normal,          // True geometric normal
+    pub uv: (f32, f32),         // Surface coordinates of the intersection
+    pub dpdu: Vector,           // Rate of change of `pos` along u
+    pub dpdv: Vector,           // Rate of change of `pos` along v
     pub local_space: Matrix4x4, // Matrix from global space to local space
     pub t: f32,                 // Ray t-value at the intersection point
+    pub footprint: f32,         // Width of the ray at the intersection, or 0.0 if unknown
     pub sample_pdf: f32,        // The PDF of getting this point by explicitly sampling the surface
+
+    // Identify what was hit, for shading variation and ID passes.  The
+    // object id is the same for every instance of an object, and the
+    // instance id differs for each placement of it in the scene.  The
+    // instance index is the hit element's index in its instance array,
+    // and 0 for hits on other instances.
+    pub object_id: u32,
+    pub instance_id: u32,
+    pub instance_index: u32,
+}
+
+impl SurfaceIntersectionData {
+    /// A random value in [0, 1) for the instance that was hit, which is
+    /// the same for every hit on it and between renders, for varying the
+    /// look of instanced objects.  Use `seed` to get more of them.
+    pub fn instance_random(&self, seed: u32) -> f32 {
+        hash_u32_to_f32(self.instance_id, seed)
+    }
+}
+
+/// Tangents for surfaces without a natural parameterization, which give a
+/// uv of (0, 0) and these as `dpdu` and `dpdv`.
+pub fn arbitrary_tangents(nor: Normal) -> (Vector, Vector) {
+    let (_, dpdu, dpdv) = coordinate_system_from_vector(nor.into_vector().normalized());
+    (dpdu, dpdv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color::Color,
+        hash::hash_u32_to_f32,
+        ray::Ray,
+        shading::{OpacitySurfaceShader, SimpleSurfaceShader},
+    };
+
+    #[test]
+    fn partly_opaque_hits_stop_some_rays() {
+        let shader = OpacitySurfaceShader {
+            shader: SimpleSurfaceShader::Lambert {
+                color: Color::new_xyz((1.0, 1.0, 1.0)),
+            },
+            opacity: 0.25,
+        };
+        assert!(shader.has_opacity());
+
+        let mut rays = RayBatch::new();
+        for i in 0..4000 {
+            rays.push(
+                Ray {
+                    orig: Point::new(hash_u32_to_f32(i, 0), hash_u32_to_f32(i, 1), -1.0),
+                    dir: Vector::new(0.0, 0.0, 1.0),
+                    time: 0.0,
+                    wavelength: 500.0,
+                    max_t: f32::INFINITY,
+                },
+                true,
+            );
+        }
+
+        let pos = Point::new(0.0, 0.0, 0.0);
+        let none = HitPrimvars::none();
+        let stopped = (0..rays.len())
+            .filter(|&i| is_opaque_hit(&shader, &rays, i, 1.0, pos, &none))
+            .count();
+        assert!(stopped > 900 && stopped < 1100);
+
+        // The same ray and hit always give the same answer.
+        for i in 0..rays.len() {
+            assert_eq!(
+                is_opaque_hit(&shader, &rays, i, 1.0, pos, &none),
+                is_opaque_hit(&shader, &rays, i, 1.0, pos, &none)
+            );
+        }
+    }
 }
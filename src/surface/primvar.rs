@@ -0,0 +1,199 @@
+//! Primitive variables: named arrays of values attached to a surface's
+//! geometry, interpolated across it for shaders.
+
+/// The type of each value of a primvar.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PrimvarType {
+    Float,
+    Float2,
+    Float3,
+    Int,
+}
+
+impl PrimvarType {
+    /// The number of components in each value.
+    pub fn components(self) -> usize {
+        match self {
+            PrimvarType::Float | PrimvarType::Int => 1,
+            PrimvarType::Float2 => 2,
+            PrimvarType::Float3 => 3,
+        }
+    }
+}
+
+/// What a primvar's values are attached to, and therefore how they're
+/// interpolated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interpolation {
+    Uniform,     // One value per face, constant across it
+    Varying,     // One value per vertex, shared by the faces around it
+    FaceVarying, // One value per corner of each face, in face-vertex order
+}
+
+/// The values of a primvar.  Ints are kept separately, since they can't
+/// be blended, and take the value of the nearest vertex or corner instead.
+#[derive(Debug, Copy, Clone)]
+pub enum PrimvarData<'a> {
+    Float(&'a [f32]), // Components of each value stored contiguously
+    Int(&'a [i32]),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Primvar<'a> {
+    pub name: &'a str,
+    pub primvar_type: PrimvarType,
+    pub interpolation: Interpolation,
+    pub data: PrimvarData<'a>,
+}
+
+/// The value of a primvar at a point on a surface.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PrimvarValue {
+    Float(f32),
+    Float2(f32, f32),
+    Float3(f32, f32, f32),
+    Int(i32),
+}
+
+/// The primvars of a surface at a hit, for shaders to look up by name.
+#[derive(Debug, Copy, Clone)]
+pub struct HitPrimvars<'a> {
+    primvars: &'a [Primvar<'a>],
+    face: usize,
+    verts: (usize, usize, usize), // Vertex index of each corner of the hit triangle
+    face_verts: (usize, usize, usize), // Face-vertex index of each corner
+    weights: (f32, f32, f32),     // Barycentric weight of each corner
+}
+
+impl<'a> HitPrimvars<'a> {
+    pub fn new(
+        primvars: &'a [Primvar<'a>],
+        face: usize,
+        verts: (usize, usize, usize),
+        face_verts: (usize, usize, usize),
+        weights: (f32, f32, f32),
+    ) -> HitPrimvars<'a> {
+        HitPrimvars {
+            primvars: primvars,
+            face: face,
+            verts: verts,
+            face_verts: face_verts,
+            weights: weights,
+        }
+    }
+
+    /// For hits on surfaces without primvars.
+    pub fn none() -> HitPrimvars<'static> {
+        HitPrimvars {
+            primvars: &[],
+            face: 0,
+            verts: (0, 0, 0),
+            face_verts: (0, 0, 0),
+            weights: (1.0, 0.0, 0.0),
+        }
+    }
+
+    /// The value of the named primvar at the hit, or `None` if the surface
+    /// doesn't have it.
+    pub fn get(&self, name: &str) -> Option<PrimvarValue> {
+        let primvar = self.primvars.iter().find(|p| p.name == name)?;
+        let n = primvar.primvar_type.components();
+
+        let corners = match primvar.interpolation {
+            Interpolation::Uniform => (self.face, self.face, self.face),
+            Interpolation::Varying => self.verts,
+            Interpolation::FaceVarying => self.face_verts,
+        };
+        let (w0, w1, w2) = self.weights;
+
+        match primvar.data {
+            PrimvarData::Float(data) => {
+                let c = |i: usize| {
+                    (data[corners.0 * n + i] * w0)
+                        + (data[corners.1 * n + i] * w1)
+                        + (data[corners.2 * n + i] * w2)
+                };
+                Some(match primvar.primvar_type {
+                    PrimvarType::Float2 => PrimvarValue::Float2(c(0), c(1)),
+                    PrimvarType::Float3 => PrimvarValue::Float3(c(0), c(1), c(2)),
+                    _ => PrimvarValue::Float(c(0)),
+                })
+            }
+
+            PrimvarData::Int(data) => {
+                let nearest = if w0 >= w1 && w0 >= w2 {
+                    corners.0
+                } else if w1 >= w2 {
+                    corners.1
+                } else {
+                    corners.2
+                };
+                Some(PrimvarValue::Int(data[nearest]))
+            }
+        }
+    }
+
+    /// The primvars at one of the corners of the hit triangle, from 0 to 2.
+    pub fn at_corner(&self, corner: usize) -> HitPrimvars<'a> {
+        HitPrimvars {
+            weights: match corner {
+                0 => (1.0, 0.0, 0.0),
+                1 => (0.0, 1.0, 0.0),
+                _ => (0.0, 0.0, 1.0),
+            },
+            ..*self
+        }
+    }
+
+    /// The value of the named float2 primvar at the hit, e.g. for texture
+    /// coordinates.
+    pub fn get_float2(&self, name: &str) -> Option<(f32, f32)> {
+        match self.get(name) {
+            Some(PrimvarValue::Float2(a, b)) => Some((a, b)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolation() {
+        let face_shades = [0.5, 0.25];
+        let vert_uvs = [0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+        let corner_ids = [1, 2, 3, 4, 5, 6];
+        let primvars = [
+            Primvar {
+                name: "shade",
+                primvar_type: PrimvarType::Float,
+                interpolation: Interpolation::Uniform,
+                data: PrimvarData::Float(&face_shades),
+            },
+            Primvar {
+                name: "uv",
+                primvar_type: PrimvarType::Float2,
+                interpolation: Interpolation::Varying,
+                data: PrimvarData::Float(&vert_uvs),
+            },
+            Primvar {
+                name: "id",
+                primvar_type: PrimvarType::Int,
+                interpolation: Interpolation::FaceVarying,
+                data: PrimvarData::Int(&corner_ids),
+            },
+        ];
+
+        // Halfway along the edge between vertices 1 and 3, on the second
+        // face, whose corners are face-vertices 3, 4, and 5.
+        let hit = HitPrimvars::new(&primvars, 1, (1, 3, 2), (3, 4, 5), (0.5, 0.5, 0.0));
+
+        assert_eq!(hit.get("shade"), Some(PrimvarValue::Float(0.25)));
+        assert_eq!(hit.get_float2("uv"), Some((1.0, 0.5)));
+        assert_eq!(hit.get("id"), Some(PrimvarValue::Int(4)));
+        assert_eq!(hit.get("nope"), None);
+        assert_eq!(hit.at_corner(2).get_float2("uv"), Some((0.0, 1.0)));
+        assert_eq!(HitPrimvars::none().get("uv"), None);
+    }
+}
@@ -55,6 +55,10 @@ impl RayTriPrecompute {
 ///
 /// Uses the ray-triangle test from the paper "Watertight Ray/Triangle
 /// Intersection" by Woop et al.
+///
+/// Always inlined, so that it's compiled into the SIMD-dispatched copies
+/// of the meshes' intersection code.
+#[inline(always)]
 pub fn intersect_ray(
     ray_orig: Point,
     ray_pre: RayTriPrecompute,
@@ -168,6 +172,32 @@ pub fn surface_point(tri: (Point, Point, Point), bary: (f32, f32, f32)) -> (Poin
     (pos, pos_err)
 }
 
+/// Calculates the rate of change of position along u and v on a triangle
+/// with the given surface coordinates at its vertices.
+///
+/// Returns `None` if the surface coordinates are degenerate.
+pub fn uv_derivatives(
+    tri: (Point, Point, Point),
+    uvs: ((f32, f32), (f32, f32), (f32, f32)),
+) -> Option<(Vector, Vector)> {
+    let ((u0, v0), (u1, v1), (u2, v2)) = uvs;
+    let duv02 = (u0 - u2, v0 - v2);
+    let duv12 = (u1 - u2, v1 - v2);
+    let dp02 = tri.0 - tri.2;
+    let dp12 = tri.1 - tri.2;
+
+    let det = (duv02.0 * duv12.1) - (duv02.1 * duv12.0);
+    if det.abs() < 1.0e-12 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    Some((
+        ((dp02 * duv12.1) - (dp12 * duv02.1)) * inv_det,
+        ((dp12 * duv02.0) - (dp02 * duv12.0)) * inv_det,
+    ))
+}
+
 fn max_abs_3(a: f32, b: f32, c: f32) -> f32 {
     let a = a.abs();
     let b = b.abs();
@@ -8,13 +8,18 @@ use crate::{
     boundable::Boundable,
     lerp::lerp_slice,
     math::{cross, dot, Matrix4x4, Normal, Point},
-    ray::{RayBatch, RayStack},
     shading::SurfaceShader,
 };
 
-use super::{triangle, Surface, SurfaceIntersection, SurfaceIntersectionData};
+use super::{
+    is_opaque_hit,
+    primvar::{HitPrimvars, Primvar},
+    ray_space, triangle, HitQuery, IntersectContext, ShadeContext, Surface, SurfaceHit,
+    SurfaceIntersection, SurfaceIntersectionData, SurfaceStats,
+};
 
 const MAX_LEAF_TRIANGLE_COUNT: usize = 3;
+const NO_GROUP: u32 = std::u32::MAX;
 
 #[derive(Copy, Clone, Debug)]
 pub struct TriangleMesh<'a> {
@@ -23,6 +28,16 @@ pub struct TriangleMesh<'a> {
     normals: Option<&'a [Normal]>, // Vertex normals, organized the same as `vertices`
     indices: &'a [(u32, u32, u32, u32)], // (v0_idx, v1_idx, v2_idx, original_tri_idx)
     accel: BVH4<'a>,
+
+    // Shader groups
+    face_groups: &'a [u32], // Group of each triangle by original index, or empty if no groups
+    group_shader_names: &'a [&'a str],
+
+    // Primvars, and where each triangle's corners are in them.  The
+    // corners are in the same order as in `indices`.
+    primvars: &'a [Primvar<'a>],
+    tri_faces: &'a [u32], // Face of each triangle by original index
+    tri_face_verts: &'a [(u32, u32, u32)], // Face-vertex index of each corner, the same
 }
 
 impl<'a> TriangleMesh<'a> {
@@ -111,6 +126,72 @@ impl<'a> TriangleMesh<'a> {
             normals: normals,
             indices: indices,
             accel: accel,
+            face_groups: &[],
+            group_shader_names: &[],
+            primvars: &[],
+            tri_faces: &[],
+            tri_face_verts: &[],
+        }
+    }
+
+    /// Binds groups of the mesh's triangles to named shaders.
+    ///
+    /// `tri_groups` gives the index into `shader_names` of the group of
+    /// each triangle, in the order they were given to the mesh in.
+    /// Triangles that aren't in a group use the shader bound to the mesh's
+    /// instance.
+    pub fn with_face_groups(
+        self,
+        arena: &'a Arena,
+        tri_groups: &[Option<usize>],
+        shader_names: &[&str],
+    ) -> TriangleMesh<'a> {
+        assert_eq!(tri_groups.len(), self.indices.len());
+
+        let face_groups: Vec<u32> = tri_groups
+            .iter()
+            .map(|g| g.map(|g| g as u32).unwrap_or(NO_GROUP))
+            .collect();
+        let group_shader_names: Vec<&str> = shader_names
+            .iter()
+            .map(|name| &*arena.copy_str(name))
+            .collect();
+
+        TriangleMesh {
+            face_groups: arena.copy_slice(&face_groups),
+            group_shader_names: arena.copy_slice(&group_shader_names),
+            ..self
+        }
+    }
+
+    /// Adds primvars to the mesh.
+    ///
+    /// `tri_faces` and `tri_face_verts` give the face each triangle is part
+    /// of, and the face-vertex index of each of its corners, in the order
+    /// the triangles and their vertices were given to the mesh in.
+    pub fn with_primvars(
+        self,
+        arena: &'a Arena,
+        primvars: &[Primvar<'a>],
+        tri_faces: &[usize],
+        tri_face_verts: &[(usize, usize, usize)],
+    ) -> TriangleMesh<'a> {
+        assert_eq!(tri_faces.len(), self.indices.len());
+        assert_eq!(tri_face_verts.len(), self.indices.len());
+
+        // The corners are reordered to match the reversed winding of the
+        // stored triangles.
+        let tri_faces: Vec<u32> = tri_faces.iter().map(|&f| f as u32).collect();
+        let tri_face_verts: Vec<_> = tri_face_verts
+            .iter()
+            .map(|&(a, b, c)| (a as u32, c as u32, b as u32))
+            .collect();
+
+        TriangleMesh {
+            primvars: arena.copy_slice(primvars),
+            tri_faces: arena.copy_slice(&tri_faces),
+            tri_face_verts: arena.copy_slice(&tri_face_verts),
+            ..self
         }
     }
 }
@@ -122,21 +203,165 @@ impl<'a> Boundable for TriangleMesh<'a> {
 }
 
 impl<'a> Surface for TriangleMesh<'a> {
-    fn intersect_rays(
-        &self,
-        rays: &mut RayBatch,
-        ray_stack: &mut RayStack,
-        isects: &mut [SurfaceIntersection],
-        shader: &dyn SurfaceShader,
-        space: &[Matrix4x4],
-    ) {
-        // Precalculate transform for non-motion blur cases
-        let static_mat_space = if space.len() == 1 {
-            lerp_slice(space, 0.0).inverse()
+    fn stats(&self) -> SurfaceStats {
+        SurfaceStats {
+            primitive_type: "triangles",
+            primitive_count: self.indices.len(),
+            bytes: std::mem::size_of_val(self.vertices)
+                + self.normals.map(std::mem::size_of_val).unwrap_or(0)
+                + std::mem::size_of_val(self.indices)
+                + std::mem::size_of_val(self.face_groups)
+                + std::mem::size_of_val(self.tri_faces)
+                + std::mem::size_of_val(self.tri_face_verts)
+                + self.accel.size_in_bytes(),
+            bvh: Some(self.accel.stats()),
+        }
+    }
+
+    fn intersect_closest(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Closest);
+    }
+
+    fn intersect_any(&self, ctx: IntersectContext) {
+        self.intersect(ctx, HitQuery::Any);
+    }
+
+    fn shade_hit(&self, ctx: ShadeContext) -> SurfaceIntersection {
+        let ShadeContext {
+            hit,
+            rays,
+            ray_idx,
+            shader,
+            group_shaders,
+            space,
+            spaces,
+            object_id,
+            instance_id,
+            instance_index,
+        } = ctx;
+        let ray_time = rays.time(ray_idx);
+        let (mat_space, winding) = ray_space(space, ray_time);
+        let tri_indices = self.indices[hit.prim as usize];
+        let shader = self.tri_shader(tri_indices.3, shader, group_shaders);
+        let hit_tri = self.triangle(tri_indices, ray_time, space, &mat_space);
+        let (b0, b1, b2) = hit.coords;
+
+        // Calculate intersection point and error magnitudes
+        let (pos, pos_err) = triangle::surface_point(hit_tri, (b0, b1, b2));
+
+        // Calculate geometric surface normal.  The triangles are stored with
+        // their winding reversed, so this faces the side they were
+        // originally counter-clockwise from.
+        let geo_normal =
+            -cross(hit_tri.0 - hit_tri.1, hit_tri.0 - hit_tri.2).into_normal() * winding;
+
+        // Calculate interpolated surface normal, if any
+        let shading_normal = if let Some(normals) = self.normals {
+            let tsc = self.time_sample_count;
+            let normal = |vi: u32| {
+                let vi = vi as usize;
+                lerp_slice(&normals[(vi * tsc)..((vi + 1) * tsc)], ray_time).normalized()
+            };
+            let n0 = normal(tri_indices.0);
+            let n1 = normal(tri_indices.1);
+            let n2 = normal(tri_indices.2);
+
+            let s_nor = ((n0 * b0) + (n1 * b1) + (n2 * b2)) * mat_space;
+            if dot(s_nor, geo_normal) >= 0.0 {
+                s_nor
+            } else {
+                -s_nor
+            }
         } else {
-            Matrix4x4::new()
+            geo_normal
+        };
+
+        // The surface coordinates come from the "uv" primvar if there is
+        // one, and are otherwise the barycentric coordinates of the second
+        // and third vertices.
+        let primvars = self.hit_primvars(tri_indices, (b0, b1, b2));
+        let (uv, dpdu, dpdv) = match primvars.get_float2("uv") {
+            Some(uv) => {
+                let corner_uv = |i| primvars.at_corner(i).get_float2("uv").unwrap();
+                let (dpdu, dpdv) =
+                    triangle::uv_derivatives(hit_tri, (corner_uv(0), corner_uv(1), corner_uv(2)))
+                        .unwrap_or((hit_tri.1 - hit_tri.0, hit_tri.2 - hit_tri.0));
+                (uv, dpdu, dpdv)
+            }
+            None => ((b1, b2), hit_tri.1 - hit_tri.0, hit_tri.2 - hit_tri.0),
+        };
+
+        let intersection_data = SurfaceIntersectionData {
+            incoming: rays.dir(ray_idx),
+            t: hit.t,
+            pos: pos,
+            pos_err: pos_err,
+            nor: shading_normal,
+            nor_g: geo_normal,
+            uv: uv,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            local_space: mat_space,
+            footprint: rays.spread(ray_idx) * hit.t,
+            sample_pdf: 0.0,
+            object_id: object_id,
+            instance_id: instance_id,
+            instance_index: instance_index,
         };
 
+        SurfaceIntersection::Hit {
+            intersection_data: intersection_data,
+            closure: shader.shade(&intersection_data, &spaces, &primvars, ray_time),
+        }
+    }
+
+    fn shader_groups(&self) -> &[&str] {
+        self.group_shader_names
+    }
+}
+
+impl<'a> TriangleMesh<'a> {
+    /// Tests the rays of the next task against the mesh's triangles.
+    ///
+    /// Dispatched to a copy compiled for the best SIMD instruction set the
+    /// CPU supports.
+    fn intersect(&self, ctx: IntersectContext, query: HitQuery) {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if crate::cpu::simd_level().has_avx2() {
+                // SAFETY: the CPU supports AVX2.
+                unsafe { self.intersect_avx2(ctx, query) };
+                return;
+            }
+        }
+
+        self.intersect_impl(ctx, query);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn intersect_avx2(&self, ctx: IntersectContext, query: HitQuery) {
+        self.intersect_impl(ctx, query);
+    }
+
+    #[inline(always)]
+    fn intersect_impl(&self, ctx: IntersectContext, query: HitQuery) {
+        let IntersectContext {
+            rays,
+            ray_stack,
+            hits,
+            shader,
+            group_shaders,
+            space,
+            source,
+        } = ctx;
+
+        // Precalculate transform for non-motion blur cases
+        let (static_mat_space, static_winding) = ray_space(space, 0.0);
+
+        // Whether hits need to run the shaders' any-hit tests.
+        let any_hit = shader.has_opacity() || group_shaders.iter().any(|s| s.has_opacity());
+
         self.accel
             .traverse(rays, ray_stack, |idx_range, rays, ray_stack| {
                 let tri_count = idx_range.end - idx_range.start;
@@ -153,19 +378,8 @@ impl<'a> Surface for TriangleMesh<'a> {
 
                         // For static triangles with static transforms, cache them.
                         unsafe {
-                            *tri_cache[i].as_mut_ptr() = (
-                                self.vertices[tri_indices.0 as usize],
-                                self.vertices[tri_indices.1 as usize],
-                                self.vertices[tri_indices.2 as usize],
-                            );
-                            if !space.is_empty() {
-                                (*tri_cache[i].as_mut_ptr()).0 =
-                                    (*tri_cache[i].as_mut_ptr()).0 * static_mat_space;
-                                (*tri_cache[i].as_mut_ptr()).1 =
-                                    (*tri_cache[i].as_mut_ptr()).1 * static_mat_space;
-                                (*tri_cache[i].as_mut_ptr()).2 =
-                                    (*tri_cache[i].as_mut_ptr()).2 * static_mat_space;
-                            }
+                            *tri_cache[i].as_mut_ptr() =
+                                self.triangle(tri_indices, 0.0, space, &static_mat_space);
                         }
                     }
                 }
@@ -181,62 +395,38 @@ impl<'a> Surface for TriangleMesh<'a> {
                     let ray_time = rays.time(ray_idx);
 
                     // Calculate the ray space, if necessary.
-                    let mat_space = if space.len() > 1 {
+                    let (mat_space, winding) = if space.len() > 1 {
                         // Per-ray transform, for motion blur
-                        lerp_slice(space, ray_time).inverse()
+                        ray_space(space, ray_time)
                     } else {
-                        static_mat_space
+                        (static_mat_space, static_winding)
                     };
 
+                    // Camera rays skip triangles facing away from them, if
+                    // the triangles' shaders ask for it.
+                    let is_camera = rays.is_camera(ray_idx);
+
                     // Iterate through the triangles and test the ray against them.
-                    let mut non_shadow_hit = false;
-                    let mut hit_tri = std::mem::MaybeUninit::uninit();
-                    let mut hit_tri_indices = std::mem::MaybeUninit::uninit();
-                    let mut hit_tri_data = std::mem::MaybeUninit::uninit();
                     let ray_pre = triangle::RayTriPrecompute::new(rays.dir(ray_idx));
                     for tri_idx in idx_range.clone() {
-                        let tri_indices = self.indices[tri_idx];
-
                         // Get triangle if necessary
                         let tri = if is_cached {
                             let i = tri_idx - idx_range.start;
                             unsafe { tri_cache[i].assume_init() }
                         } else {
-                            let mut tri = if self.time_sample_count == 1 {
-                                // No deformation motion blur, so fast-path it.
-                                (
-                                    self.vertices[tri_indices.0 as usize],
-                                    self.vertices[tri_indices.1 as usize],
-                                    self.vertices[tri_indices.2 as usize],
-                                )
-                            } else {
-                                // Deformation motion blur, need to interpolate.
-                                let p0_slice = &self.vertices[(tri_indices.0 as usize
-                                    * self.time_sample_count)
-                                    ..((tri_indices.0 as usize + 1) * self.time_sample_count)];
-                                let p1_slice = &self.vertices[(tri_indices.1 as usize
-                                    * self.time_sample_count)
-                                    ..((tri_indices.1 as usize + 1) * self.time_sample_count)];
-                                let p2_slice = &self.vertices[(tri_indices.2 as usize
-                                    * self.time_sample_count)
-                                    ..((tri_indices.2 as usize + 1) * self.time_sample_count)];
-
-                                let p0 = lerp_slice(p0_slice, ray_time);
-                                let p1 = lerp_slice(p1_slice, ray_time);
-                                let p2 = lerp_slice(p2_slice, ray_time);
-
-                                (p0, p1, p2)
-                            };
-
-                            if !space.is_empty() {
-                                tri.0 = tri.0 * mat_space;
-                                tri.1 = tri.1 * mat_space;
-                                tri.2 = tri.2 * mat_space;
-                            }
-
-                            tri
+                            self.triangle(self.indices[tri_idx], ray_time, space, &mat_space)
                         };
 
+                        let tri_shader =
+                            self.tri_shader(self.indices[tri_idx].3, shader, group_shaders);
+                        if is_camera
+                            && tri_shader.backface_cull()
+                            && dot(cross(tri.0 - tri.1, tri.0 - tri.2), rays.dir(ray_idx)) * winding
+                                < 0.0
+                        {
+                            continue;
+                        }
+
                         // Test ray against triangle
                         if let Some((t, b0, b1, b2)) = triangle::intersect_ray(
                             rays.orig(ray_idx),
@@ -244,80 +434,111 @@ impl<'a> Surface for TriangleMesh<'a> {
                             rays.max_t(ray_idx),
                             tri,
                         ) {
-                            if rays.is_occlusion(ray_idx) {
-                                isects[ray_idx] = SurfaceIntersection::Occlude;
+                            if any_hit {
+                                let (pos, _) = triangle::surface_point(tri, (b0, b1, b2));
+                                let primvars =
+                                    self.hit_primvars(self.indices[tri_idx], (b0, b1, b2));
+                                if !is_opaque_hit(tri_shader, rays, ray_idx, t, pos, &primvars) {
+                                    continue;
+                                }
+                            }
+
+                            hits[ray_idx] = Some(SurfaceHit::new(t, tri_idx, (b0, b1, b2), source));
+                            if query == HitQuery::Any {
                                 rays.mark_done(ray_idx);
                                 break;
                             } else {
-                                non_shadow_hit = true;
                                 rays.set_max_t(ray_idx, t);
-                                unsafe {
-                                    *hit_tri.as_mut_ptr() = tri;
-                                    *hit_tri_indices.as_mut_ptr() = tri_indices;
-                                    *hit_tri_data.as_mut_ptr() = (t, b0, b1, b2);
-                                }
                             }
                         }
                     }
-
-                    // Calculate intersection data if necessary.
-                    if non_shadow_hit {
-                        let hit_tri = unsafe { hit_tri.assume_init() };
-                        let (t, b0, b1, b2) = unsafe { hit_tri_data.assume_init() };
-
-                        // Calculate intersection point and error magnitudes
-                        let (pos, pos_err) = triangle::surface_point(hit_tri, (b0, b1, b2));
-
-                        // Calculate geometric surface normal
-                        let geo_normal =
-                            cross(hit_tri.0 - hit_tri.1, hit_tri.0 - hit_tri.2).into_normal();
-
-                        // Calculate interpolated surface normal, if any
-                        let shading_normal = if let Some(normals) = self.normals {
-                            let hit_tri_indices = unsafe { hit_tri_indices.assume_init() };
-                            let n0_slice = &normals[(hit_tri_indices.0 as usize
-                                * self.time_sample_count)
-                                ..((hit_tri_indices.0 as usize + 1) * self.time_sample_count)];
-                            let n1_slice = &normals[(hit_tri_indices.1 as usize
-                                * self.time_sample_count)
-                                ..((hit_tri_indices.1 as usize + 1) * self.time_sample_count)];
-                            let n2_slice = &normals[(hit_tri_indices.2 as usize
-                                * self.time_sample_count)
-                                ..((hit_tri_indices.2 as usize + 1) * self.time_sample_count)];
-
-                            let n0 = lerp_slice(n0_slice, ray_time).normalized();
-                            let n1 = lerp_slice(n1_slice, ray_time).normalized();
-                            let n2 = lerp_slice(n2_slice, ray_time).normalized();
-
-                            let s_nor = ((n0 * b0) + (n1 * b1) + (n2 * b2)) * mat_space;
-                            if dot(s_nor, geo_normal) >= 0.0 {
-                                s_nor
-                            } else {
-                                -s_nor
-                            }
-                        } else {
-                            geo_normal
-                        };
-
-                        let intersection_data = SurfaceIntersectionData {
-                            incoming: rays.dir(ray_idx),
-                            t: t,
-                            pos: pos,
-                            pos_err: pos_err,
-                            nor: shading_normal,
-                            nor_g: geo_normal,
-                            local_space: mat_space,
-                            sample_pdf: 0.0,
-                        };
-
-                        // Fill in intersection data
-                        isects[ray_idx] = SurfaceIntersection::Hit {
-                            intersection_data: intersection_data,
-                            closure: shader.shade(&intersection_data, ray_time),
-                        };
-                    }
                 });
                 ray_stack.pop_task();
             });
     }
+
+    /// The mesh's primvars at a point on a triangle, given its indices as
+    /// stored in `indices`, and the point's barycentric coordinates.
+    fn hit_primvars(
+        &self,
+        tri_indices: (u32, u32, u32, u32),
+        coords: (f32, f32, f32),
+    ) -> HitPrimvars<'a> {
+        if self.primvars.is_empty() {
+            return HitPrimvars::none();
+        }
+
+        let tri_i = tri_indices.3 as usize;
+        let face_verts = self.tri_face_verts[tri_i];
+        HitPrimvars::new(
+            self.primvars,
+            self.tri_faces[tri_i] as usize,
+            (
+                tri_indices.0 as usize,
+                tri_indices.1 as usize,
+                tri_indices.2 as usize,
+            ),
+            (
+                face_verts.0 as usize,
+                face_verts.1 as usize,
+                face_verts.2 as usize,
+            ),
+            coords,
+        )
+    }
+
+    /// The shader of the triangle with the given original index: its
+    /// group's, if it's in one that has a shader, and otherwise `shader`.
+    fn tri_shader<'b>(
+        &self,
+        original_tri_idx: u32,
+        shader: &'b dyn SurfaceShader,
+        group_shaders: &'b [&'b dyn SurfaceShader],
+    ) -> &'b dyn SurfaceShader {
+        match self.face_groups.get(original_tri_idx as usize) {
+            Some(&group) if group != NO_GROUP => {
+                group_shaders.get(group as usize).copied().unwrap_or(shader)
+            }
+            _ => shader,
+        }
+    }
+
+    /// The vertices of a triangle at the given time, in world space.
+    /// `mat_space` is the object-to-world transform at that time.
+    fn triangle(
+        &self,
+        tri_indices: (u32, u32, u32, u32),
+        time: f32,
+        space: &[Matrix4x4],
+        mat_space: &Matrix4x4,
+    ) -> (Point, Point, Point) {
+        let mut tri = if self.time_sample_count == 1 {
+            // No deformation motion blur, so fast-path it.
+            (
+                self.vertices[tri_indices.0 as usize],
+                self.vertices[tri_indices.1 as usize],
+                self.vertices[tri_indices.2 as usize],
+            )
+        } else {
+            // Deformation motion blur, need to interpolate.
+            let tsc = self.time_sample_count;
+            let vert = |vi: u32| {
+                let vi = vi as usize;
+                lerp_slice(&self.vertices[(vi * tsc)..((vi + 1) * tsc)], time)
+            };
+            (
+                vert(tri_indices.0),
+                vert(tri_indices.1),
+                vert(tri_indices.2),
+            )
+        };
+
+        if !space.is_empty() {
+            tri.0 = tri.0 * *mat_space;
+            tri.1 = tri.1 * *mat_space;
+            tri.2 = tri.2 * *mat_space;
+        }
+
+        tri
+    }
 }
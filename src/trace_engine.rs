@@ -0,0 +1,274 @@
+//! Tracing arbitrary batches of rays against a scene, independent of any
+//! integrator.
+//!
+//! This is for tools that just want to know what rays hit: baking,
+//! ambient occlusion probes, collision queries, and testing changes to
+//! traversal.
+
+use std::io::{self, BufRead, Write};
+
+use crate::{
+    math::{dot, Normal, Point, Vector},
+    ray::{Ray, RayBatch},
+    scene::Assembly,
+    surface::SurfaceIntersection,
+    tracer::Tracer,
+};
+
+/// The most rays traced in one batch, which is limited by the size of
+/// the ray indices used during traversal.
+const MAX_BATCH_SIZE: usize = 1 << 16;
+
+/// What a ray hit.  Everything is in world space.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TraceHit {
+    pub object_id: u32,      // Hash of the name of the object that was hit
+    pub instance_id: u32,    // Hash of the instance path it was hit through
+    pub instance_index: u32, // Index of the element, for hits on instance arrays
+    pub t: f32,              // Distance along the ray, in units of its direction
+    pub pos: Point,
+    pub uv: (f32, f32),
+    pub nor_g: Normal, // Geometric normal, facing the side the ray came from
+}
+
+pub struct TraceEngine<'a> {
+    tracer: Tracer<'a>,
+    rays: RayBatch,
+}
+
+impl<'a> TraceEngine<'a> {
+    pub fn new(root: &'a Assembly<'a>) -> TraceEngine<'a> {
+        TraceEngine {
+            tracer: Tracer::from_assembly(root),
+            rays: RayBatch::new(),
+        }
+    }
+
+    /// Finds the closest hit of each ray, if any.
+    pub fn trace(&mut self, rays: &[Ray]) -> Vec<Option<TraceHit>> {
+        let mut hits = Vec::with_capacity(rays.len());
+        for batch in rays.chunks(MAX_BATCH_SIZE) {
+            self.fill(batch, false);
+            let isects = self.tracer.trace(&mut self.rays);
+            hits.extend(isects.iter().map(|isect| match *isect {
+                SurfaceIntersection::Hit {
+                    intersection_data: ref idata,
+                    ..
+                } => Some(TraceHit {
+                    object_id: idata.object_id,
+                    instance_id: idata.instance_id,
+                    instance_index: idata.instance_index,
+                    t: idata.t,
+                    pos: idata.pos,
+                    uv: idata.uv,
+                    nor_g: facing(idata.nor_g, idata.incoming),
+                }),
+                _ => None,
+            }));
+        }
+        hits
+    }
+
+    /// Finds whether anything is hit by each ray before its `max_t`.
+    ///
+    /// This is cheaper than `trace()`, since the search can stop at the
+    /// first hit found.
+    #[allow(dead_code)]
+    pub fn occluded(&mut self, rays: &[Ray]) -> Vec<bool> {
+        let mut occluded = Vec::with_capacity(rays.len());
+        for batch in rays.chunks(MAX_BATCH_SIZE) {
+            self.fill(batch, true);
+            let isects = self.tracer.trace(&mut self.rays);
+            occluded.extend(
+                isects
+                    .iter()
+                    .map(|isect| !matches!(*isect, SurfaceIntersection::Miss)),
+            );
+        }
+        occluded
+    }
+
+    #[allow(dead_code)]
+    pub fn rays_traced(&self) -> u64 {
+        self.tracer.rays_traced()
+    }
+
+    fn fill(&mut self, rays: &[Ray], is_occlusion: bool) {
+        self.rays.clear();
+        for ray in rays {
+            self.rays.push(*ray, is_occlusion);
+        }
+    }
+}
+
+fn facing(nor: Normal, incoming: Vector) -> Normal {
+    if dot(nor.into_vector(), incoming) > 0.0 {
+        -nor
+    } else {
+        nor
+    }
+}
+
+/// Traces rays read from `input`, one per line, and writes what each hit
+/// to `output`, one line per ray in the same order.
+///
+/// Rays are written as `ox oy oz dx dy dz`, optionally followed by the
+/// time.  Hits are written as `hit object_id instance_id t u v nx ny nz`,
+/// and misses as `miss`.  Blank lines and lines starting with `#` are
+/// skipped.
+pub fn serve<R: BufRead, W: Write>(
+    engine: &mut TraceEngine,
+    input: R,
+    mut output: W,
+) -> io::Result<()> {
+    let mut rays = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        rays.push(parse_ray(line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: expected 'ox oy oz dx dy dz [time]'", i + 1),
+            )
+        })?);
+    }
+
+    for hit in engine.trace(&rays) {
+        match hit {
+            Some(hit) => writeln!(
+                output,
+                "hit {} {} {} {} {} {} {} {}",
+                hit.object_id,
+                hit.instance_id,
+                hit.t,
+                hit.uv.0,
+                hit.uv.1,
+                hit.nor_g.x(),
+                hit.nor_g.y(),
+                hit.nor_g.z()
+            )?,
+            None => writeln!(output, "miss")?,
+        }
+    }
+    Ok(())
+}
+
+fn parse_ray(line: &str) -> Option<Ray> {
+    let vals = line
+        .split_whitespace()
+        .map(|s| s.parse::<f32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if vals.len() != 6 && vals.len() != 7 {
+        return None;
+    }
+    Some(Ray {
+        orig: Point::new(vals[0], vals[1], vals[2]),
+        dir: Vector::new(vals[3], vals[4], vals[5]),
+        time: vals.get(6).copied().unwrap_or(0.5),
+        wavelength: 550.0,
+        max_t: std::f32::INFINITY,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kioku::Arena;
+
+    use crate::{
+        scene::{AssemblyBuilder, Object},
+        surface::triangle_mesh::TriangleMesh,
+    };
+
+    fn quad_assembly(arena: &Arena) -> Assembly {
+        let mesh = arena.alloc(TriangleMesh::from_verts_and_indices(
+            arena,
+            &[vec![
+                Point::new(-1.0, -1.0, 0.0),
+                Point::new(1.0, -1.0, 0.0),
+                Point::new(-1.0, 1.0, 0.0),
+                Point::new(1.0, 1.0, 0.0),
+            ]],
+            &None,
+            &[(0, 1, 2), (1, 3, 2)],
+        ));
+        let mut builder = AssemblyBuilder::new(arena);
+        builder.add_object("$quad", Object::Surface(mesh));
+        builder.add_instance("$quad", None, None, None);
+        builder.build()
+    }
+
+    fn ray(orig: (f32, f32, f32), dir: (f32, f32, f32), max_t: f32) -> Ray {
+        Ray {
+            orig: Point::new(orig.0, orig.1, orig.2),
+            dir: Vector::new(dir.0, dir.1, dir.2),
+            time: 0.5,
+            wavelength: 550.0,
+            max_t: max_t,
+        }
+    }
+
+    #[test]
+    fn trace_hits_and_misses() {
+        let arena = Arena::new();
+        let root = quad_assembly(&arena);
+        let mut engine = TraceEngine::new(&root);
+
+        let hits = engine.trace(&[
+            ray((0.5, 0.0, 2.0), (0.0, 0.0, -1.0), std::f32::INFINITY),
+            ray((0.0, 0.0, -2.0), (0.0, 0.0, 2.0), std::f32::INFINITY),
+            ray((3.0, 0.0, 2.0), (0.0, 0.0, -1.0), std::f32::INFINITY),
+        ]);
+        assert_eq!(hits.len(), 3);
+
+        let above = hits[0].unwrap();
+        assert!((above.t - 2.0).abs() < 0.0001);
+        assert!((above.pos - Point::new(0.5, 0.0, 0.0)).length() < 0.0001);
+        assert!(above.nor_g.normalized().z() > 0.9999);
+
+        // Normals face whichever side the ray came from.
+        let below = hits[1].unwrap();
+        assert!((below.t - 1.0).abs() < 0.0001);
+        assert!(below.nor_g.normalized().z() < -0.9999);
+
+        assert!(hits[2].is_none());
+        assert_eq!(engine.rays_traced(), 3);
+    }
+
+    #[test]
+    fn occlusion_respects_max_t() {
+        let arena = Arena::new();
+        let root = quad_assembly(&arena);
+        let mut engine = TraceEngine::new(&root);
+
+        let occluded = engine.occluded(&[
+            ray((0.0, 0.0, 2.0), (0.0, 0.0, -1.0), 3.0),
+            ray((0.0, 0.0, 2.0), (0.0, 0.0, -1.0), 1.0),
+        ]);
+        assert_eq!(occluded, vec![true, false]);
+    }
+
+    #[test]
+    fn serve_lines() {
+        let arena = Arena::new();
+        let root = quad_assembly(&arena);
+        let mut engine = TraceEngine::new(&root);
+
+        let input = "# A hit and a miss.\n0 0 1 0 0 -1\n\n5 5 1 0 0 -1 0.25\n";
+        let mut output = Vec::new();
+        serve(&mut engine, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let hit: Vec<_> = lines[0].split_whitespace().collect();
+        assert_eq!(hit.len(), 9);
+        assert_eq!(hit[0], "hit");
+        assert!((hit[3].parse::<f32>().unwrap() - 1.0).abs() < 0.0001);
+        assert_eq!(lines[1], "miss");
+
+        assert!(serve(&mut engine, "0 0 1 0 0".as_bytes(), &mut Vec::new()).is_err());
+    }
+}
@@ -3,12 +3,15 @@ use std::iter;
 use crate::{
     accel::ray_code,
     color::{rec709_to_xyz, Color},
-    lerp::lerp_slice,
+    hash::hash_u32,
     math::Matrix4x4,
     ray::{RayBatch, RayStack},
     scene::{Assembly, InstanceType, Object},
-    shading::{SimpleSurfaceShader, SurfaceShader},
-    surface::SurfaceIntersection,
+    shading::{
+        spaces::{CoordinateSpaces, SpaceScope},
+        SimpleSurfaceShader, SurfaceShader,
+    },
+    surface::{HitQuery, IntersectContext, ShadeContext, Surface, SurfaceHit, SurfaceIntersection},
     transform_stack::TransformStack,
 };
 
@@ -26,7 +29,14 @@ impl<'a> Tracer<'a> {
             inner: TracerInner {
                 root: assembly,
                 xform_stack: TransformStack::new(),
+                hits: Vec::new(),
+                hit_sources: Vec::new(),
+                shading_xforms: Vec::new(),
+                space_scopes: Vec::new(),
+                space_scope: None,
                 isects: Vec::new(),
+                query: HitQuery::Closest,
+                instance_id: 0,
             },
         }
     }
@@ -44,7 +54,27 @@ impl<'a> Tracer<'a> {
 struct TracerInner<'a> {
     root: &'a Assembly<'a>,
     xform_stack: TransformStack,
+    hits: Vec<Option<SurfaceHit>>, // Nearest hit so far of each ray
+    hit_sources: Vec<HitSource<'a>>,
+    shading_xforms: Vec<Matrix4x4>, // Transforms of the hit sources and space scopes
+    space_scopes: Vec<SpaceScope<'a>>,
+    space_scope: Option<usize>, // Innermost scope of the assembly being traversed
     isects: Vec<SurfaceIntersection>,
+    query: HitQuery,  // The query of the pass being traced
+    instance_id: u32, // Id of the instance path being traversed
+}
+
+/// What the hits found by one surface intersection call are on, for
+/// shading them once traversal is done.
+struct HitSource<'a> {
+    object: Object<'a>,
+    shader: Option<&'a dyn SurfaceShader>,
+    group_shaders: &'a [&'a dyn SurfaceShader],
+    xforms: (usize, usize), // Range in `shading_xforms`
+    space_scope: Option<usize>,
+    object_id: u32,
+    instance_id: u32,
+    instance_index: u32, // Of the element, for hits on instance arrays
 }
 
 impl<'a> TracerInner<'a> {
@@ -55,11 +85,14 @@ impl<'a> TracerInner<'a> {
     ) -> &'b [SurfaceIntersection] {
         ray_stack.clear();
 
-        // Ready the isects
-        self.isects.clear();
-        self.isects.reserve(rays.len());
-        self.isects
-            .extend(iter::repeat(SurfaceIntersection::Miss).take(rays.len()));
+        // Ready the hits
+        self.hits.clear();
+        self.hits.extend(iter::repeat(None).take(rays.len()));
+        self.hit_sources.clear();
+        self.shading_xforms.clear();
+        self.space_scopes.clear();
+        self.space_scope = None;
+        self.push_space_scope(self.root);
 
         // Prep the accel part of the rays.
         {
@@ -69,24 +102,74 @@ impl<'a> TracerInner<'a> {
             }
         }
 
-        // Divide the rays into 8 different lanes by direction.
+        // Closest-hit and occlusion rays are traced in separate passes, so
+        // that surfaces only ever see one kind of query at a time.
         ray_stack.ensure_lane_count(8);
-        for i in 0..rays.len() {
-            ray_stack.push_ray_index(i, ray_code(rays.dir(i)));
+        for &query in &[HitQuery::Closest, HitQuery::Any] {
+            // Divide the rays into 8 different lanes by direction.
+            for i in 0..rays.len() {
+                if rays.is_occlusion(i) == (query == HitQuery::Any) {
+                    ray_stack.push_ray_index(i, ray_code(rays.dir(i)));
+                }
+            }
+            ray_stack.push_lanes_to_tasks(&[0, 1, 2, 3, 4, 5, 6, 7]);
+
+            // Trace each of the 8 lanes separately.
+            self.query = query;
+            while !ray_stack.is_empty() {
+                self.trace_assembly(self.root, None, rays, ray_stack);
+            }
         }
-        ray_stack.push_lanes_to_tasks(&[0, 1, 2, 3, 4, 5, 6, 7]);
 
-        // Trace each of the 8 lanes separately.
-        while !ray_stack.is_empty() {
-            self.trace_assembly(self.root, rays, ray_stack);
+        // Only now that the closest hits are known are they shaded, so that
+        // no work is wasted on hits that turn out to be behind others.
+        // Surfaces without a shader show up magenta.
+        let unassigned_shader = SimpleSurfaceShader::Emit {
+            color: Color::new_xyz(rec709_to_xyz((1.0, 0.0, 1.0))),
+        };
+        self.isects.clear();
+        self.isects.reserve(rays.len());
+        for (ray_idx, hit) in self.hits.iter().enumerate() {
+            let isect = match *hit {
+                None => SurfaceIntersection::Miss,
+                Some(_) if rays.is_occlusion(ray_idx) => SurfaceIntersection::Occlude,
+                Some(hit) => {
+                    let source = &self.hit_sources[hit.source as usize];
+                    let ctx = ShadeContext {
+                        hit: hit,
+                        rays: rays,
+                        ray_idx: ray_idx,
+                        shader: source.shader.unwrap_or(&unassigned_shader),
+                        group_shaders: source.group_shaders,
+                        space: &self.shading_xforms[source.xforms.0..source.xforms.1],
+                        spaces: CoordinateSpaces::new(
+                            &self.space_scopes,
+                            &self.shading_xforms,
+                            source.space_scope,
+                            rays.time(ray_idx),
+                        ),
+                        object_id: source.object_id,
+                        instance_id: source.instance_id,
+                        instance_index: source.instance_index,
+                    };
+                    match source.object {
+                        Object::Surface(surface) => surface.shade_hit(ctx),
+                        Object::SurfaceLight(surface) => surface.shade_hit(ctx),
+                    }
+                }
+            };
+            self.isects.push(isect);
         }
 
         &self.isects
     }
 
+    /// `shader_override`, if any, is used for all surfaces in the
+    /// assembly in place of their own shaders.
     fn trace_assembly<'b>(
         &'b mut self,
-        assembly: &Assembly,
+        assembly: &'a Assembly<'a>,
+        shader_override: Option<&'a dyn SurfaceShader>,
         rays: &mut RayBatch,
         ray_stack: &mut RayStack,
     ) {
@@ -95,97 +178,217 @@ impl<'a> TracerInner<'a> {
             .traverse(rays, ray_stack, |idx_range, rays, ray_stack| {
                 let inst = &assembly.instances[idx_range.start];
 
+                // Every placement of an instance gets its own id, from the
+                // instances on the way to it.
+                let parent_instance_id = self.instance_id;
+                self.instance_id = hash_u32(idx_range.start as u32, parent_instance_id);
+
                 // Transform rays if needed
                 if let Some((xstart, xend)) = inst.transform_indices {
-                    // Push transforms to stack
-                    self.xform_stack.push(&assembly.xforms[xstart..xend]);
-
-                    // Do transforms
-                    // TODO: re-divide rays based on direction (maybe?).
-                    let xforms = self.xform_stack.top();
-                    ray_stack.do_next_task(|ray_idx| {
-                        let t = rays.time(ray_idx);
-                        rays.update_local(ray_idx, &lerp_slice(xforms, t));
-                    });
-                    ray_stack.duplicate_next_task();
+                    self.push_xforms(&assembly.xforms[xstart..xend], rays, ray_stack);
                 }
 
+                // Overrides from further up take precedence.
+                let shader_override = shader_override.or_else(|| {
+                    inst.surface_shader_override_index
+                        .map(|i| assembly.surface_shaders[i])
+                });
+
+                // Overrides replace the shaders of primitive groups too.
+                let group_shaders = |object_index: usize| -> &'a [&'a dyn SurfaceShader] {
+                    if shader_override.is_some() {
+                        &[]
+                    } else {
+                        assembly.object_group_shaders[object_index]
+                    }
+                };
+                let shader = shader_override.or_else(|| {
+                    inst.surface_shader_index
+                        .map(|i| assembly.surface_shaders[i])
+                });
+
                 // Trace rays
                 match inst.instance_type {
                     InstanceType::Object => {
-                        self.trace_object(
-                            &assembly.objects[inst.data_index],
-                            inst.surface_shader_index
-                                .map(|i| assembly.surface_shaders[i]),
-                            rays,
-                            ray_stack,
-                        );
+                        let object_id = name_id(assembly.object_names[inst.data_index]);
+
+                        // Rays that see proxies are split off and traced
+                        // against the proxy instead, which is hit as if it
+                        // were the object itself.
+                        let (full_count, proxy_count) = if inst.proxy_index.is_some() {
+                            ray_stack.partition_next_task(|ray_idx| rays.uses_proxy(ray_idx))
+                        } else {
+                            (1, 0)
+                        };
+                        if proxy_count > 0 {
+                            let proxy_index = inst.proxy_index.unwrap();
+                            self.trace_object(
+                                assembly.objects[proxy_index],
+                                object_id,
+                                0,
+                                shader,
+                                group_shaders(proxy_index),
+                                rays,
+                                ray_stack,
+                            );
+                        }
+                        if full_count > 0 {
+                            self.trace_object(
+                                assembly.objects[inst.data_index],
+                                object_id,
+                                0,
+                                shader,
+                                group_shaders(inst.data_index),
+                                rays,
+                                ray_stack,
+                            );
+                        }
                     }
 
                     InstanceType::Assembly => {
-                        self.trace_assembly(&assembly.assemblies[inst.data_index], rays, ray_stack);
+                        let sub_assembly = &assembly.assemblies[inst.data_index];
+                        let parent_space_scope = self.space_scope;
+                        self.push_space_scope(sub_assembly);
+                        self.trace_assembly(sub_assembly, shader_override, rays, ray_stack);
+                        self.space_scope = parent_space_scope;
+                    }
+
+                    InstanceType::Array => {
+                        let array = &assembly.instance_arrays[inst.data_index];
+                        let object = assembly.objects[array.object_index];
+                        let object_id = name_id(assembly.object_names[array.object_index]);
+                        let group_shaders = group_shaders(array.object_index);
+
+                        // Each element is its own instance, with its own
+                        // id.
+                        let array_instance_id = self.instance_id;
+                        array
+                            .accel
+                            .traverse(rays, ray_stack, |idx_range, rays, ray_stack| {
+                                let element = array.elements[idx_range.start];
+                                self.instance_id = hash_u32(element, array_instance_id);
+                                self.push_xforms(
+                                    array.element_xforms(element as usize),
+                                    rays,
+                                    ray_stack,
+                                );
+                                self.trace_object(
+                                    object,
+                                    object_id,
+                                    element,
+                                    shader,
+                                    group_shaders,
+                                    rays,
+                                    ray_stack,
+                                );
+                                self.pop_xforms(rays, ray_stack);
+                            });
                     }
                 }
 
+                self.instance_id = parent_instance_id;
+
                 // Un-transform rays if needed
                 if inst.transform_indices.is_some() {
-                    // Pop transforms off stack
-                    self.xform_stack.pop();
-
-                    // Undo transforms
-                    let xforms = self.xform_stack.top();
-                    if !xforms.is_empty() {
-                        ray_stack.pop_do_next_task(|ray_idx| {
-                            let t = rays.time(ray_idx);
-                            rays.update_local(ray_idx, &lerp_slice(xforms, t));
-                        });
-                    } else {
-                        let ident = Matrix4x4::new();
-                        ray_stack.pop_do_next_task(|ray_idx| {
-                            rays.update_local(ray_idx, &ident);
-                        });
-                    }
+                    self.pop_xforms(rays, ray_stack);
                 }
             });
     }
 
+    /// Pushes transforms onto the transform stack, and transforms the rays
+    /// of the next task into the space they lead to.
+    fn push_xforms(&mut self, xforms: &[Matrix4x4], rays: &mut RayBatch, ray_stack: &mut RayStack) {
+        self.xform_stack.push(xforms);
+
+        // TODO: re-divide rays based on direction (maybe?).
+        rays.update_local_for_task(ray_stack, self.xform_stack.top());
+        ray_stack.duplicate_next_task();
+    }
+
+    /// Undoes `push_xforms()`.
+    fn pop_xforms(&mut self, rays: &mut RayBatch, ray_stack: &mut RayStack) {
+        self.xform_stack.pop();
+        rays.update_local_for_task(ray_stack, self.xform_stack.top());
+        ray_stack.pop_task();
+    }
+
+    /// Makes the named spaces of `assembly`, placed where it's currently
+    /// being traversed, the innermost ones visible to shaders.
+    fn push_space_scope(&mut self, assembly: &'a Assembly<'a>) {
+        if assembly.spaces.is_empty() {
+            return;
+        }
+
+        let xforms = self.xform_stack.top();
+        let xforms_start = self.shading_xforms.len();
+        self.shading_xforms.extend_from_slice(xforms);
+        self.space_scopes.push(SpaceScope {
+            spaces: assembly.spaces,
+            xforms: (xforms_start, self.shading_xforms.len()),
+            parent: self.space_scope,
+        });
+        self.space_scope = Some(self.space_scopes.len() - 1);
+    }
+
+    /// `instance_index` is the index of the element being traced, for
+    /// instance arrays, and otherwise 0.
     fn trace_object<'b>(
         &'b mut self,
-        obj: &Object,
-        surface_shader: Option<&dyn SurfaceShader>,
+        obj: Object<'a>,
+        object_id: u32,
+        instance_index: u32,
+        surface_shader: Option<&'a dyn SurfaceShader>,
+        group_shaders: &'a [&'a dyn SurfaceShader],
         rays: &mut RayBatch,
         ray_stack: &mut RayStack,
     ) {
-        match *obj {
-            Object::Surface(surface) => {
-                let unassigned_shader = SimpleSurfaceShader::Emit {
-                    color: Color::new_xyz(rec709_to_xyz((1.0, 0.0, 1.0))),
-                };
-                let shader = surface_shader.unwrap_or(&unassigned_shader);
-
-                surface.intersect_rays(
-                    rays,
-                    ray_stack,
-                    &mut self.isects,
-                    shader,
-                    self.xform_stack.top(),
-                );
-            }
+        // Note what the hits found here are on.
+        let xforms = self.xform_stack.top();
+        let xforms_start = self.shading_xforms.len();
+        self.shading_xforms.extend_from_slice(xforms);
+        self.hit_sources.push(HitSource {
+            object: obj,
+            shader: surface_shader,
+            group_shaders: group_shaders,
+            xforms: (xforms_start, self.shading_xforms.len()),
+            space_scope: self.space_scope,
+            object_id: object_id,
+            instance_id: self.instance_id,
+            instance_index: instance_index,
+        });
 
-            Object::SurfaceLight(surface) => {
-                // Lights don't use shaders
-                let bogus_shader = SimpleSurfaceShader::Emit {
-                    color: Color::new_xyz(rec709_to_xyz((1.0, 0.0, 1.0))),
-                };
+        // The shader is only needed here for any-hit tests, which surfaces
+        // without one don't have.
+        let unassigned_shader = SimpleSurfaceShader::Emit {
+            color: Color::new_xyz(rec709_to_xyz((1.0, 0.0, 1.0))),
+        };
+        let ctx = IntersectContext {
+            rays: rays,
+            ray_stack: ray_stack,
+            hits: &mut self.hits,
+            shader: surface_shader.unwrap_or(&unassigned_shader),
+            group_shaders: group_shaders,
+            space: xforms,
+            source: (self.hit_sources.len() - 1) as u32,
+        };
 
-                surface.intersect_rays(
-                    rays,
-                    ray_stack,
-                    &mut self.isects,
-                    &bogus_shader,
-                    self.xform_stack.top(),
-                );
-            }
+        match obj {
+            Object::Surface(surface) => intersect(surface, self.query, ctx),
+            Object::SurfaceLight(surface) => intersect(surface, self.query, ctx),
         }
     }
 }
+
+/// An id for an object from its name, so that it's the same between
+/// renders of the same scene.
+fn name_id(name: &str) -> u32 {
+    name.bytes().fold(0, |id, b| hash_u32(b as u32, id))
+}
+
+/// Runs `query` for the next task of rays against `surface`.
+fn intersect<S: Surface + ?Sized>(surface: &S, query: HitQuery, ctx: IntersectContext) {
+    match query {
+        HitQuery::Closest => surface.intersect_closest(ctx),
+        HitQuery::Any => surface.intersect_any(ctx),
+    }
+}
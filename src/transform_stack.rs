@@ -3,11 +3,15 @@ use std::{
     mem::{transmute, MaybeUninit},
 };
 
-use crate::{algorithm::merge_slices_to, math::Matrix4x4};
+use crate::{algorithm::merge_slices_to, lerp::lerp_slice, math::Matrix4x4};
 
 pub struct TransformStack {
     stack: Vec<MaybeUninit<Matrix4x4>>,
     stack_indices: Vec<usize>,
+
+    // The transforms of each level as they were pushed, before merging.
+    levels: Vec<Matrix4x4>,
+    level_indices: Vec<usize>,
 }
 
 impl TransformStack {
@@ -15,10 +19,13 @@ impl TransformStack {
         let mut ts = TransformStack {
             stack: Vec::new(),
             stack_indices: Vec::new(),
+            levels: Vec::new(),
+            level_indices: Vec::new(),
         };
 
         ts.stack_indices.push(0);
         ts.stack_indices.push(0);
+        ts.level_indices.push(0);
 
         ts
     }
@@ -28,11 +35,17 @@ impl TransformStack {
         self.stack_indices.clear();
         self.stack_indices.push(0);
         self.stack_indices.push(0);
+        self.levels.clear();
+        self.level_indices.clear();
+        self.level_indices.push(0);
     }
 
     pub fn push(&mut self, xforms: &[Matrix4x4]) {
         assert!(!xforms.is_empty());
 
+        self.levels.extend(xforms);
+        self.level_indices.push(self.levels.len());
+
         if self.stack.is_empty() {
             let xforms: &[MaybeUninit<Matrix4x4>] = unsafe { transmute(xforms) };
             self.stack.extend(xforms);
@@ -71,6 +84,9 @@ impl TransformStack {
 
         self.stack.truncate(sl - (i2 - i1));
         self.stack_indices.pop();
+
+        self.level_indices.pop();
+        self.levels.truncate(*self.level_indices.last().unwrap());
     }
 
     pub fn top(&self) -> &[Matrix4x4] {
@@ -80,4 +96,54 @@ impl TransformStack {
 
         unsafe { transmute(&self.stack[i1..i2]) }
     }
+
+    /// The combined transform of the whole stack at `time`, or the
+    /// identity if the stack is empty.
+    ///
+    /// Each level is interpolated at `time` before they're combined, so
+    /// this is exact for levels that are animated differently.  In
+    /// contrast, interpolating `top()` only matches it at the time samples.
+    pub fn top_at(&self, time: f32) -> Matrix4x4 {
+        self.level_indices
+            .windows(2)
+            .fold(Matrix4x4::new(), |xform, range| {
+                xform * lerp_slice(&self.levels[range[0]..range[1]], time)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::Point;
+
+    #[test]
+    fn top_at_interpolates_each_level() {
+        let spin = [
+            Matrix4x4::new(),
+            Matrix4x4::new_from_values(
+                0.0, -1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+            ),
+        ];
+        let slide = [
+            Matrix4x4::from_location(Point::new(0.0, 0.0, 0.0)),
+            Matrix4x4::from_location(Point::new(4.0, 0.0, 0.0)),
+            Matrix4x4::from_location(Point::new(4.0, 2.0, 0.0)),
+        ];
+
+        let mut stack = TransformStack::new();
+        assert!(stack.top_at(0.5).aprx_eq(Matrix4x4::new(), 0.0));
+
+        stack.push(&spin);
+        stack.push(&slide);
+        for &time in &[0.0, 0.3, 0.5, 1.0] {
+            let expected = lerp_slice(&spin, time) * lerp_slice(&slide, time);
+            assert!(stack.top_at(time).aprx_eq(expected, 1.0e-5));
+        }
+
+        stack.pop();
+        assert!(stack.top_at(0.3).aprx_eq(lerp_slice(&spin, 0.3), 1.0e-5));
+        stack.pop();
+        assert!(stack.top_at(0.3).aprx_eq(Matrix4x4::new(), 0.0));
+    }
 }
@@ -0,0 +1,157 @@
+//! A work-stealing queue, for spreading render buckets over threads.
+//!
+//! Each thread has its own queue of jobs, which it takes from the front
+//! of.  Threads that run out steal from the back of the fullest queue of
+//! another thread, so expensive jobs that pile up on one thread get
+//! picked up by the others.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+pub struct WorkQueue<T> {
+    queues: Vec<Mutex<VecDeque<T>>>,
+    remaining: AtomicUsize, // Jobs queued, or taken but not yet handed out
+}
+
+impl<T> WorkQueue<T> {
+    pub fn new(thread_count: usize) -> WorkQueue<T> {
+        assert!(thread_count > 0);
+        WorkQueue {
+            queues: (0..thread_count)
+                .map(|_| Mutex::new(VecDeque::new()))
+                .collect(),
+            remaining: AtomicUsize::new(0),
+        }
+    }
+
+    /// Deals the jobs out to the threads one by one, in order, so that
+    /// every thread starts near the start of the list.
+    pub fn deal<I: IntoIterator<Item = T>>(&self, jobs: I) {
+        for (i, job) in jobs.into_iter().enumerate() {
+            self.push(i % self.queues.len(), job);
+        }
+    }
+
+    /// Adds a job to the back of a thread's queue.
+    pub fn push(&self, thread: usize, job: T) {
+        self.remaining.fetch_add(1, Ordering::SeqCst);
+        self.queues[thread].lock().unwrap().push_back(job);
+    }
+
+    /// Takes the next job for `thread`, or returns `None` when there are
+    /// no jobs left.
+    ///
+    /// When fewer jobs are left than there are threads, the job is split
+    /// with `split` for as long as that gives two jobs, and the second
+    /// halves are queued for other threads to steal.  That way the last
+    /// jobs of a batch don't leave threads idle while one thread renders
+    /// a big one.
+    pub fn pop<F>(&self, thread: usize, split: F) -> Option<T>
+    where
+        F: Fn(&T) -> Option<(T, T)>,
+    {
+        loop {
+            if let Some(mut job) = self.take(thread) {
+                while self.remaining.load(Ordering::SeqCst) <= self.queues.len() {
+                    if let Some((a, b)) = split(&job) {
+                        self.push(thread, b);
+                        job = a;
+                    } else {
+                        break;
+                    }
+                }
+
+                // Only now is the job no longer counted, so that no thread
+                // gives up while the split-off jobs are still being queued.
+                self.remaining.fetch_sub(1, Ordering::SeqCst);
+                return Some(job);
+            } else if self.remaining.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+
+            // Another thread took the last job, and may be about to queue
+            // parts of it.
+            thread::yield_now();
+        }
+    }
+
+    /// Takes a job from the front of the thread's own queue, or else from
+    /// the back of the fullest other queue.
+    fn take(&self, thread: usize) -> Option<T> {
+        if let Some(job) = self.queues[thread].lock().unwrap().pop_front() {
+            return Some(job);
+        }
+
+        loop {
+            let fullest = (0..self.queues.len())
+                .filter(|&i| i != thread)
+                .map(|i| (self.queues[i].lock().unwrap().len(), i))
+                .max()?;
+            if fullest.0 == 0 {
+                return None;
+            }
+            // The queue may have been emptied since its length was
+            // checked, in which case look again.
+            if let Some(job) = self.queues[fullest.1].lock().unwrap().pop_back() {
+                return Some(job);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_jobs_first_then_stolen() {
+        let queue = WorkQueue::new(2);
+        queue.deal(vec![0, 1, 2, 3, 4, 5]);
+
+        let no_split = |_: &u32| None;
+        assert_eq!(queue.pop(0, no_split), Some(0));
+        assert_eq!(queue.pop(0, no_split), Some(2));
+        assert_eq!(queue.pop(0, no_split), Some(4));
+        // Stolen from the back of thread 1's queue.
+        assert_eq!(queue.pop(0, no_split), Some(5));
+        assert_eq!(queue.pop(1, no_split), Some(1));
+        assert_eq!(queue.pop(1, no_split), Some(3));
+        assert_eq!(queue.pop(1, no_split), None);
+        assert_eq!(queue.pop(0, no_split), None);
+    }
+
+    #[test]
+    fn last_jobs_are_split() {
+        let queue = WorkQueue::new(4);
+        queue.deal(vec![16u32]);
+
+        // Halve jobs down to a size of 2.
+        let halve = |&n: &u32| {
+            if n >= 4 {
+                Some((n / 2, n / 2))
+            } else {
+                None
+            }
+        };
+        let mut total = 0;
+        let mut sizes = Vec::new();
+        for thread in (0..4).cycle() {
+            match queue.pop(thread, halve) {
+                Some(n) => {
+                    total += n;
+                    sizes.push(n);
+                }
+                None => break,
+            }
+        }
+        assert_eq!(total, 16);
+        assert!(sizes.len() > 4);
+        assert!(sizes.iter().all(|&n| n <= 8));
+    }
+}
@@ -0,0 +1,38 @@
+use bencher::{benchmark_group, benchmark_main, black_box, Bencher};
+use halton::{sample, MAX_DIMENSION};
+
+//----
+
+fn sample_1000_values_dim_0(bench: &mut Bencher) {
+    bench.iter(|| {
+        for i in 0..1000 {
+            black_box(sample(black_box(0), i));
+        }
+    });
+}
+
+fn sample_1000_values_dim_1(bench: &mut Bencher) {
+    bench.iter(|| {
+        for i in 0..1000 {
+            black_box(sample(black_box(1), i));
+        }
+    });
+}
+
+fn sample_1000_values_last_dim(bench: &mut Bencher) {
+    bench.iter(|| {
+        for i in 0..1000 {
+            black_box(sample(black_box(MAX_DIMENSION - 1), i));
+        }
+    });
+}
+
+//----
+
+benchmark_group!(
+    benches,
+    sample_1000_values_dim_0,
+    sample_1000_values_dim_1,
+    sample_1000_values_last_dim,
+);
+benchmark_main!(benches);
@@ -0,0 +1,47 @@
+//! Checks that the Halton sequence is well distributed, by the numbers.
+//!
+//! Set `SAMPLE_STATS_DIR` to a directory to also get the 2D projections
+//! written there as CSV files, for plotting.
+
+use sample_stats::pairwise_stats;
+
+/// The number of dimensions to check every pair of.
+const DIMENSIONS: u32 = 8;
+
+/// The number of points per projection.
+const COUNT: u32 = 1024;
+
+// For reference, uniform random points at this count have a star
+// discrepancy of about 0.025 to 0.065, and a correlation of about 0.03
+// (and up to about 0.1) between a pair of dimensions.  The Halton
+// sequence isn't scrambled, but 1024 points only fill out the first few
+// digits of its larger prime bases, so their pairs still measure a
+// correlation of up to about 0.03.  The correlation limit is loose to
+// allow for that, and is mainly there to catch dimensions that are
+// copies of each other.
+const MAX_STAR_DISCREPANCY: f64 = 0.02;
+const MAX_CORRELATION: f64 = 0.05;
+
+#[test]
+fn projections_are_well_distributed() {
+    for stats in pairwise_stats("halton", halton::sample, DIMENSIONS, COUNT) {
+        assert!(stats.star_discrepancy < MAX_STAR_DISCREPANCY, "{:?}", stats);
+        assert!(stats.correlation.abs() < MAX_CORRELATION, "{:?}", stats);
+    }
+}
+
+#[test]
+fn higher_dimensions_are_well_distributed() {
+    // The Faure permutations keep the projections of the large prime
+    // bases from being lines, but they're still not as good as the
+    // first dimensions.
+    let sample = |d: u32, i: u32| halton::sample(halton::MAX_DIMENSION - 4 + d, i);
+    for stats in pairwise_stats("halton_high", sample, 4, COUNT) {
+        assert!(
+            stats.star_discrepancy < MAX_STAR_DISCREPANCY * 3.0,
+            "{:?}",
+            stats
+        );
+        assert!(stats.correlation.abs() < MAX_CORRELATION, "{:?}", stats);
+    }
+}
@@ -0,0 +1,57 @@
+use bencher::{benchmark_group, benchmark_main, black_box, Bencher};
+use hash_grid::HashGrid;
+use rand::{rngs::SmallRng, FromEntropy, Rng};
+
+//----
+
+fn random_points(rng: &mut SmallRng, count: usize) -> Vec<[f32; 3]> {
+    (0..count)
+        .map(|_| [rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()])
+        .collect()
+}
+
+fn build_100000_points(bench: &mut Bencher) {
+    let mut rng = SmallRng::from_entropy();
+    let points = random_points(&mut rng, 100_000);
+    bench.iter(|| {
+        black_box(HashGrid::new(points.iter().cloned(), 0.01, |&p| p));
+    });
+}
+
+fn query_100_near_100000_points(bench: &mut Bencher) {
+    let mut rng = SmallRng::from_entropy();
+    let points = random_points(&mut rng, 100_000);
+    let grid = HashGrid::new(points.iter().cloned(), 0.01, |&p| p);
+    bench.iter(|| {
+        let mut found = 0;
+        for _ in 0..100 {
+            let pos = [rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()];
+            grid.for_each_near(black_box(pos), 0.01, |_| found += 1);
+        }
+        black_box(found);
+    });
+}
+
+fn query_100_wide_near_100000_points(bench: &mut Bencher) {
+    let mut rng = SmallRng::from_entropy();
+    let points = random_points(&mut rng, 100_000);
+    let grid = HashGrid::new(points.iter().cloned(), 0.01, |&p| p);
+    bench.iter(|| {
+        let mut found = 0;
+        for _ in 0..100 {
+            let pos = [rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()];
+            grid.for_each_near(black_box(pos), 0.04, |_| found += 1);
+        }
+        black_box(found);
+    });
+}
+
+//----
+
+benchmark_group!(
+    benches,
+    build_100000_points,
+    query_100_near_100000_points,
+    query_100_wide_near_100000_points,
+);
+benchmark_main!(benches);
@@ -0,0 +1,248 @@
+//! A spatial hash grid, for finding the items near a point.
+//!
+//! Items are placed in a uniform grid by their position, and the grid
+//! cells are hashed into a table with about as many buckets as there are
+//! items.  So the grid is unbounded, and its size only depends on the
+//! item count rather than on how spread out the items are.  Cells that
+//! collide share a bucket, which only costs some extra distance tests.
+//!
+//! This is the structure used for gathering photons in photon mapping,
+//! but it works for anything with a position: cached radiance records,
+//! probes, and so on.
+
+/// Items bucketed by the grid cell their position is in.
+#[derive(Debug, Clone)]
+pub struct HashGrid<T> {
+    cell_size: f32,
+    bucket_starts: Vec<usize>, // Index of each bucket's first item, plus the end
+    positions: Vec<[f32; 3]>,  // Sorted by bucket
+    items: Vec<T>,             // Sorted by bucket, in the same order as `positions`
+}
+
+impl<T> HashGrid<T> {
+    /// Builds a hash grid of `items`, where `position` gives each item's
+    /// position.
+    ///
+    /// `cell_size` is the size of the grid cells.  Searches are fastest
+    /// when it's about the radius that will be searched: any radius works,
+    /// but up to `cell_size` at most three cells are visited along each
+    /// axis.
+    pub fn new<I, F>(items: I, cell_size: f32, position: F) -> HashGrid<T>
+    where
+        I: IntoIterator<Item = T>,
+        F: Fn(&T) -> [f32; 3],
+    {
+        assert!(cell_size > 0.0);
+        let items: Vec<T> = items.into_iter().collect();
+        let bucket_count = items.len().max(1).next_power_of_two();
+        let mut grid = HashGrid {
+            cell_size,
+            bucket_starts: vec![0; bucket_count + 1],
+            positions: Vec::with_capacity(items.len()),
+            items: Vec::with_capacity(items.len()),
+        };
+
+        // Counting sort of the items into their buckets.
+        let positions: Vec<[f32; 3]> = items.iter().map(position).collect();
+        let buckets: Vec<usize> = positions
+            .iter()
+            .map(|&pos| grid.bucket(grid.cell(pos)))
+            .collect();
+        for &bucket in &buckets {
+            grid.bucket_starts[bucket + 1] += 1;
+        }
+        for i in 1..grid.bucket_starts.len() {
+            grid.bucket_starts[i] += grid.bucket_starts[i - 1];
+        }
+        let mut next = grid.bucket_starts.clone();
+        let mut sorted: Vec<Option<(T, [f32; 3])>> = (0..items.len()).map(|_| None).collect();
+        for ((item, pos), &bucket) in items.into_iter().zip(positions).zip(buckets.iter()) {
+            sorted[next[bucket]] = Some((item, pos));
+            next[bucket] += 1;
+        }
+        for (item, pos) in sorted.drain(..).map(|entry| entry.unwrap()) {
+            grid.items.push(item);
+            grid.positions.push(pos);
+        }
+
+        grid
+    }
+
+    /// The number of items in the grid.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The items, in no particular order.
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Calls `f` with every item within `radius` of `pos`.
+    pub fn for_each_near<F>(&self, pos: [f32; 3], radius: f32, mut f: F)
+    where
+        F: FnMut(&T),
+    {
+        let radius2 = radius * radius;
+        let min = self.cell([pos[0] - radius, pos[1] - radius, pos[2] - radius]);
+        let max = self.cell([pos[0] + radius, pos[1] + radius, pos[2] + radius]);
+        let mut search_bucket = |bucket: usize| {
+            let range = self.bucket_starts[bucket]..self.bucket_starts[bucket + 1];
+            for (item, p) in self.items[range.clone()]
+                .iter()
+                .zip(self.positions[range].iter())
+            {
+                let d = [p[0] - pos[0], p[1] - pos[1], p[2] - pos[2]];
+                if ((d[0] * d[0]) + (d[1] * d[1]) + (d[2] * d[2])) <= radius2 {
+                    f(item);
+                }
+            }
+        };
+
+        // Each bucket is only searched once, even if several of the cells
+        // hash to it.
+        let extent = |a: i32, b: i32| (b as i64 - a as i64 + 1) as u64;
+        let cell_count = extent(min.0, max.0)
+            .saturating_mul(extent(min.1, max.1))
+            .saturating_mul(extent(min.2, max.2));
+        let bucket_count = self.bucket_starts.len() - 1;
+        if cell_count <= 27 {
+            let mut searched = [0usize; 27];
+            let mut searched_count = 0;
+            for cell in cells(min, max) {
+                let bucket = self.bucket(cell);
+                if !searched[..searched_count].contains(&bucket) {
+                    searched[searched_count] = bucket;
+                    searched_count += 1;
+                    search_bucket(bucket);
+                }
+            }
+        } else if cell_count < bucket_count as u64 {
+            let mut buckets: Vec<usize> = cells(min, max).map(|cell| self.bucket(cell)).collect();
+            buckets.sort_unstable();
+            buckets.dedup();
+            for bucket in buckets {
+                search_bucket(bucket);
+            }
+        } else {
+            // The search covers more cells than there are buckets, so
+            // every bucket is likely to be in it anyway.
+            for bucket in 0..bucket_count {
+                search_bucket(bucket);
+            }
+        }
+    }
+
+    /// The grid cell a point is in.
+    fn cell(&self, pos: [f32; 3]) -> (i32, i32, i32) {
+        let inv_size = 1.0 / self.cell_size;
+        (
+            (pos[0] * inv_size).floor() as i32,
+            (pos[1] * inv_size).floor() as i32,
+            (pos[2] * inv_size).floor() as i32,
+        )
+    }
+
+    /// The bucket a grid cell hashes to.
+    fn bucket(&self, cell: (i32, i32, i32)) -> usize {
+        let hash = (cell.0 as u32).wrapping_mul(73_856_093)
+            ^ (cell.1 as u32).wrapping_mul(19_349_663)
+            ^ (cell.2 as u32).wrapping_mul(83_492_791);
+        hash as usize & (self.bucket_starts.len() - 2)
+    }
+}
+
+/// All of the cells from `min` to `max`, inclusive.
+fn cells(min: (i32, i32, i32), max: (i32, i32, i32)) -> impl Iterator<Item = (i32, i32, i32)> {
+    (min.2..=max.2).flat_map(move |z| {
+        (min.1..=max.1).flat_map(move |y| (min.0..=max.0).map(move |x| (x, y, z)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A simple deterministic hash to [0, 1), so the tests don't need a
+    // random number generator.
+    fn hash_to_unit(n: u32, seed: u32) -> f32 {
+        let mut h = n.wrapping_mul(0x9e37_79b9) ^ seed.wrapping_mul(0x85eb_ca6b);
+        h ^= h >> 16;
+        h = h.wrapping_mul(0x7feb_352d);
+        h ^= h >> 15;
+        h = h.wrapping_mul(0x846c_a68b);
+        h ^= h >> 16;
+        (h >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn random_point(i: u32, seed: u32) -> [f32; 3] {
+        [
+            hash_to_unit(i, seed) * 4.0 - 2.0,
+            hash_to_unit(i, seed + 1) * 4.0 - 2.0,
+            hash_to_unit(i, seed + 2) * 4.0 - 2.0,
+        ]
+    }
+
+    fn distance2(a: [f32; 3], b: [f32; 3]) -> f32 {
+        let d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+        (d[0] * d[0]) + (d[1] * d[1]) + (d[2] * d[2])
+    }
+
+    fn check_against_brute_force(points: &[[f32; 3]], cell_size: f32, max_radius: f32) {
+        let grid = HashGrid::new(0..points.len(), cell_size, |&i| points[i]);
+        assert_eq!(grid.len(), points.len());
+
+        for i in 0..100 {
+            let pos = random_point(i, 10);
+            let radius = hash_to_unit(i, 20) * max_radius;
+
+            let mut found = Vec::new();
+            grid.for_each_near(pos, radius, |&i| found.push(i));
+            found.sort_unstable();
+
+            let expected: Vec<usize> = (0..points.len())
+                .filter(|&i| distance2(points[i], pos) <= radius * radius)
+                .collect();
+
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn finds_same_items_as_brute_force() {
+        let points: Vec<_> = (0..5000).map(|i| random_point(i, 0)).collect();
+        check_against_brute_force(&points, 0.25, 0.25);
+    }
+
+    #[test]
+    fn radius_larger_than_cells() {
+        let points: Vec<_> = (0..5000).map(|i| random_point(i, 0)).collect();
+        check_against_brute_force(&points, 0.1, 0.5);
+
+        // More cells searched than there are buckets.
+        check_against_brute_force(&points[..20], 0.1, 2.0);
+    }
+
+    #[test]
+    fn items_at_the_same_position() {
+        let grid = HashGrid::new(vec!["a", "b", "c"], 1.0, |_| [0.5, -3.0, 7.25]);
+        let mut found = Vec::new();
+        grid.for_each_near([0.5, -3.0, 7.0], 0.5, |&s| found.push(s));
+        found.sort_unstable();
+        assert_eq!(found, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn empty_grid() {
+        let grid: HashGrid<u32> = HashGrid::new(vec![], 1.0, |_| [0.0; 3]);
+        assert!(grid.is_empty());
+        let mut found = 0;
+        grid.for_each_near([0.0; 3], 1.0, |_| found += 1);
+        grid.for_each_near([0.0; 3], 100.0, |_| found += 1);
+        assert_eq!(found, 0);
+    }
+}
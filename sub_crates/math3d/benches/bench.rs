@@ -0,0 +1,132 @@
+//! The `_glam` benchmarks do the same as the ones without, but with
+//! glam's own operations.  On aarch64 those are glam's scalar fallback,
+//! while math3d's use NEON, so comparing them shows what NEON gains.
+//! Elsewhere they're both glam's SSE2 versions.
+
+use bencher::{benchmark_group, benchmark_main, black_box, Bencher};
+use math3d::{Matrix4x4, Point, Vector};
+use rand::{rngs::SmallRng, FromEntropy, Rng};
+
+//----
+
+fn random_matrix(rng: &mut SmallRng) -> Matrix4x4 {
+    let mut v = [0.0f32; 16];
+    for x in v.iter_mut() {
+        *x = rng.gen::<f32>() - 0.5;
+    }
+    Matrix4x4::new_from_values(
+        v[0], v[1], v[2], v[3], v[4], v[5], v[6], v[7], v[8], v[9], v[10], v[11], v[12], v[13],
+        v[14], v[15],
+    )
+}
+
+fn random_point(rng: &mut SmallRng) -> Point {
+    Point::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>())
+}
+
+fn transform_100_points(bench: &mut Bencher) {
+    let mut rng = SmallRng::from_entropy();
+    let m = random_matrix(&mut rng);
+    let points: Vec<Point> = (0..100).map(|_| random_point(&mut rng)).collect();
+    bench.iter(|| {
+        for p in points.iter() {
+            black_box(black_box(*p) * m);
+        }
+    });
+}
+
+fn transform_100_points_glam(bench: &mut Bencher) {
+    let mut rng = SmallRng::from_entropy();
+    let m = random_matrix(&mut rng);
+    let points: Vec<Point> = (0..100).map(|_| random_point(&mut rng)).collect();
+    bench.iter(|| {
+        for p in points.iter() {
+            black_box(m.0.mul_vec4(black_box(p.co)));
+        }
+    });
+}
+
+fn transform_100_vectors(bench: &mut Bencher) {
+    let mut rng = SmallRng::from_entropy();
+    let m = random_matrix(&mut rng);
+    let vectors: Vec<Vector> = (0..100)
+        .map(|_| random_point(&mut rng).into_vector())
+        .collect();
+    bench.iter(|| {
+        for v in vectors.iter() {
+            black_box(black_box(*v) * m);
+        }
+    });
+}
+
+fn transform_100_vectors_glam(bench: &mut Bencher) {
+    let mut rng = SmallRng::from_entropy();
+    let m = random_matrix(&mut rng);
+    let vectors: Vec<Vector> = (0..100)
+        .map(|_| random_point(&mut rng).into_vector())
+        .collect();
+    bench.iter(|| {
+        for v in vectors.iter() {
+            black_box(m.0.transform_vector3(black_box(v.co)));
+        }
+    });
+}
+
+fn multiply_100_matrices(bench: &mut Bencher) {
+    let mut rng = SmallRng::from_entropy();
+    let matrices: Vec<Matrix4x4> = (0..101).map(|_| random_matrix(&mut rng)).collect();
+    bench.iter(|| {
+        for pair in matrices.windows(2) {
+            black_box(black_box(pair[0]) * pair[1]);
+        }
+    });
+}
+
+fn multiply_100_matrices_glam(bench: &mut Bencher) {
+    let mut rng = SmallRng::from_entropy();
+    let matrices: Vec<Matrix4x4> = (0..101).map(|_| random_matrix(&mut rng)).collect();
+    bench.iter(|| {
+        for pair in matrices.windows(2) {
+            black_box(pair[1].0.mul_mat4(&black_box(pair[0]).0));
+        }
+    });
+}
+
+// Blending matrices, the way time samples of a transform are
+// interpolated for motion blur.
+fn lerp_100_matrices(bench: &mut Bencher) {
+    let mut rng = SmallRng::from_entropy();
+    let matrices: Vec<Matrix4x4> = (0..101).map(|_| random_matrix(&mut rng)).collect();
+    let alpha = rng.gen::<f32>();
+    bench.iter(|| {
+        for pair in matrices.windows(2) {
+            black_box((black_box(pair[0]) * (1.0 - alpha)) + (pair[1] * alpha));
+        }
+    });
+}
+
+fn lerp_100_matrices_glam(bench: &mut Bencher) {
+    let mut rng = SmallRng::from_entropy();
+    let matrices: Vec<Matrix4x4> = (0..101).map(|_| random_matrix(&mut rng)).collect();
+    let alpha = rng.gen::<f32>();
+    bench.iter(|| {
+        for pair in matrices.windows(2) {
+            black_box((black_box(pair[0]).0 * (1.0 - alpha)) + (pair[1].0 * alpha));
+        }
+    });
+}
+
+//----
+
+benchmark_group!(
+    benches,
+    transform_100_points,
+    transform_100_points_glam,
+    transform_100_vectors,
+    transform_100_vectors_glam,
+    multiply_100_matrices,
+    multiply_100_matrices_glam,
+    lerp_100_matrices,
+    lerp_100_matrices_glam,
+);
+benchmark_main!(benches);
@@ -2,6 +2,7 @@
 
 mod matrix;
 mod normal;
+mod ops;
 mod point;
 mod vector;
 
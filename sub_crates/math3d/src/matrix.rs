@@ -5,7 +5,7 @@ use std::ops::{Add, Mul};
 use approx::RelativeEq;
 use glam::{Mat4, Vec4};
 
-use super::Point;
+use super::{ops, Point};
 
 /// A 4x4 matrix, used for transforms
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -71,11 +71,108 @@ impl Matrix4x4 {
         Matrix4x4(self.0.transpose())
     }
 
+    /// Returns the determinant of the matrix.  It's negative for
+    /// transforms that mirror space, i.e. change its handedness.
+    #[inline]
+    pub fn determinant(&self) -> f32 {
+        self.0.determinant()
+    }
+
     /// Returns the inverse of the Matrix
     #[inline]
     pub fn inverse(&self) -> Matrix4x4 {
         Matrix4x4(self.0.inverse())
     }
+
+    /// Returns the inverse of the matrix, or `None` if the matrix is
+    /// singular or too close to singular to invert meaningfully.
+    ///
+    /// This is much slower than `inverse()`, which doesn't check anything
+    /// and just produces infs and NaNs for singular matrices.  It's meant
+    /// for validating matrices up front, e.g. when parsing a scene.
+    ///
+    /// Uses Gauss-Jordan elimination in double precision with scaled
+    /// partial pivoting, so a row's pivot is judged relative to the row's
+    /// largest element.  The translation column isn't counted towards that,
+    /// so that a large translation doesn't make a small scale look singular.
+    pub fn try_inverse(&self) -> Option<Matrix4x4> {
+        // The smallest allowed pivot, relative to its row's largest element.
+        const MIN_RELATIVE_PIVOT: f64 = 1.0e-6;
+
+        // The matrix augmented with the identity, as rows.
+        let cols = self.0.to_cols_array_2d();
+        let mut m = [[0.0f64; 8]; 4];
+        for (r, row) in m.iter_mut().enumerate() {
+            for c in 0..4 {
+                if !cols[c][r].is_finite() {
+                    return None;
+                }
+                row[c] = cols[c][r] as f64;
+            }
+            row[4 + r] = 1.0;
+        }
+
+        let max_abs = |xs: &[f64]| xs.iter().fold(0.0f64, |a, b| a.max(b.abs()));
+        let mut scale = [0.0f64; 4];
+        for (r, row) in m.iter().enumerate() {
+            scale[r] = max_abs(&row[..3]);
+            if scale[r] == 0.0 {
+                // Nothing but translation, e.g. the bottom row of an
+                // affine transform.
+                scale[r] = max_abs(&row[..4]);
+            }
+            if scale[r] == 0.0 {
+                return None;
+            }
+        }
+
+        for col in 0..4 {
+            // Find the pivot row.
+            let mut pivot_row = col;
+            let mut pivot_size = 0.0;
+            for r in col..4 {
+                let size = m[r][col].abs() / scale[r];
+                if size > pivot_size {
+                    pivot_row = r;
+                    pivot_size = size;
+                }
+            }
+            if pivot_size < MIN_RELATIVE_PIVOT {
+                return None;
+            }
+            m.swap(col, pivot_row);
+            scale.swap(col, pivot_row);
+
+            // Normalize the pivot row and eliminate the column from the
+            // other rows.
+            let pivot = m[col][col];
+            for x in m[col].iter_mut() {
+                *x /= pivot;
+            }
+            let pivot_row = m[col];
+            for (r, row) in m.iter_mut().enumerate() {
+                let factor = row[col];
+                if r != col && factor != 0.0 {
+                    for (x, p) in row.iter_mut().zip(pivot_row.iter()) {
+                        *x -= factor * p;
+                    }
+                }
+            }
+        }
+
+        let mut inv = [[0.0f32; 4]; 4];
+        for (r, row) in m.iter().enumerate() {
+            for c in 0..4 {
+                inv[c][r] = row[4 + c] as f32;
+            }
+        }
+        let inv = Matrix4x4(Mat4::from_cols_array_2d(&inv));
+        if inv.0.to_cols_array().iter().all(|x| x.is_finite()) {
+            Some(inv)
+        } else {
+            None
+        }
+    }
 }
 
 impl Default for Matrix4x4 {
@@ -90,7 +187,7 @@ impl Mul for Matrix4x4 {
 
     #[inline]
     fn mul(self, other: Self) -> Self {
-        Self(other.0.mul_mat4(&self.0))
+        Self(ops::mul_mat4(&other.0, &self.0))
     }
 }
 
@@ -100,7 +197,7 @@ impl Mul<f32> for Matrix4x4 {
 
     #[inline]
     fn mul(self, other: f32) -> Self {
-        Self(self.0 * other)
+        Self(ops::mul_scalar(&self.0, other))
     }
 }
 
@@ -110,7 +207,7 @@ impl Add for Matrix4x4 {
 
     #[inline]
     fn add(self, other: Self) -> Self {
-        Self(self.0 + other.0)
+        Self(ops::add(&self.0, &other.0))
     }
 }
 
@@ -130,6 +227,18 @@ mod tests {
         assert!(a != c);
     }
 
+    #[test]
+    fn determinant_sign() {
+        let scale = Matrix4x4::new_from_values(
+            2.0, 0.0, 0.0, 0.0, 0.0, 3.0, 0.0, 0.0, 0.0, 0.0, 4.0, 0.0, 1.0, 2.0, 3.0, 1.0,
+        );
+        let mirror = Matrix4x4::new_from_values(
+            -1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+        );
+        assert_eq!(scale.determinant(), 24.0);
+        assert_eq!((scale * mirror).determinant(), -24.0);
+    }
+
     #[test]
     fn approximate_equality_test() {
         let a = Matrix4x4::new();
@@ -178,6 +287,89 @@ mod tests {
         assert!((dbg!(a * b)).aprx_eq(dbg!(c), 0.0000001));
     }
 
+    #[test]
+    fn try_inverse_test() {
+        let a = Matrix4x4::new_from_values(
+            1.0, 0.33, 0.0, -2.0, 0.0, 1.0, 0.0, 0.0, 2.1, 0.7, 1.3, 0.0, 0.0, 0.0, 0.0, -1.0,
+        );
+        let b = a.try_inverse().unwrap();
+
+        assert!(b.aprx_eq(a.inverse(), 0.000001));
+        assert!((a * b).aprx_eq(Matrix4x4::new(), 0.0000001));
+    }
+
+    #[test]
+    fn try_inverse_large_translation() {
+        // Tiny scale with a huge translation: badly scaled, but invertible.
+        let a = Matrix4x4::new_from_values(
+            0.001, 0.0, 0.0, 100000.0, 0.0, 0.001, 0.0, -100000.0, 0.0, 0.0, 0.001, 50000.0, 0.0,
+            0.0, 0.0, 1.0,
+        );
+        let b = a.try_inverse().unwrap();
+        let c = Matrix4x4::new_from_values(
+            1000.0,
+            0.0,
+            0.0,
+            -100000000.0,
+            0.0,
+            1000.0,
+            0.0,
+            100000000.0,
+            0.0,
+            0.0,
+            1000.0,
+            -50000000.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+
+        assert!(b.aprx_eq(c, 0.000001));
+    }
+
+    #[test]
+    fn try_inverse_singular() {
+        // Zero scale on an axis.
+        let a = Matrix4x4::new_from_values(
+            1.0, 0.0, 0.0, 3.0, 0.0, 0.0, 0.0, 4.0, 0.0, 0.0, 1.0, 5.0, 0.0, 0.0, 0.0, 1.0,
+        );
+        assert_eq!(a.try_inverse(), None);
+
+        // Two rows that are multiples of each other.
+        let b = Matrix4x4::new_from_values(
+            1.0, 2.0, 3.0, 4.0, 2.0, 4.0, 6.0, 8.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+        );
+        assert_eq!(b.try_inverse(), None);
+
+        // Nearly flattened onto a plane.
+        let c = Matrix4x4::new_from_values(
+            1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0000001, 0.0, 0.0, 0.0, 0.0, 1.0,
+        );
+        assert_eq!(c.try_inverse(), None);
+
+        // Not finite.
+        let d = Matrix4x4::new_from_values(
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            std::f32::NAN,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+        assert_eq!(d.try_inverse(), None);
+    }
+
     #[test]
     fn transpose_test() {
         let a = Matrix4x4::new_from_values(
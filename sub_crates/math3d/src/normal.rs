@@ -7,7 +7,7 @@ use std::{
 
 use glam::Vec3;
 
-use super::{CrossProduct, DotProduct, Matrix4x4, Vector};
+use super::{ops, CrossProduct, DotProduct, Matrix4x4, Vector};
 
 /// A surface normal in 3d homogeneous space.
 #[derive(Debug, Copy, Clone)]
@@ -133,7 +133,7 @@ impl Mul<Matrix4x4> for Normal {
     fn mul(self, other: Matrix4x4) -> Normal {
         let mat = other.0.inverse().transpose();
         Normal {
-            co: mat.transform_vector3(self.co),
+            co: ops::transform_vector3(&mat, self.co),
         }
     }
 }
@@ -0,0 +1,224 @@
+//! The matrix operations that transforms are built from.
+//!
+//! glam's only SIMD backend is SSE2, so on aarch64 it falls back to
+//! scalar math.  There these are written with NEON instead, and
+//! everywhere else (or with the `scalar_math` feature) they're just
+//! glam's.
+
+#[cfg(all(
+    target_arch = "aarch64",
+    target_feature = "neon",
+    not(feature = "scalar_math")
+))]
+pub(crate) use self::neon::*;
+
+#[cfg(not(all(
+    target_arch = "aarch64",
+    target_feature = "neon",
+    not(feature = "scalar_math")
+)))]
+pub(crate) use self::glam_ops::*;
+
+mod glam_ops {
+    use glam::{Mat4, Vec3, Vec4};
+
+    /// `m` times the column vector `v`.
+    #[inline(always)]
+    pub fn mul_vec4(m: &Mat4, v: Vec4) -> Vec4 {
+        m.mul_vec4(v)
+    }
+
+    /// `m` times the column vector `v`, ignoring translation.
+    #[inline(always)]
+    pub fn transform_vector3(m: &Mat4, v: Vec3) -> Vec3 {
+        m.transform_vector3(v)
+    }
+
+    /// `a` times `b`.
+    #[inline(always)]
+    pub fn mul_mat4(a: &Mat4, b: &Mat4) -> Mat4 {
+        a.mul_mat4(b)
+    }
+
+    #[inline(always)]
+    pub fn mul_scalar(m: &Mat4, s: f32) -> Mat4 {
+        *m * s
+    }
+
+    #[inline(always)]
+    pub fn add(a: &Mat4, b: &Mat4) -> Mat4 {
+        *a + *b
+    }
+}
+
+#[cfg(all(
+    target_arch = "aarch64",
+    target_feature = "neon",
+    not(feature = "scalar_math")
+))]
+mod neon {
+    use std::arch::aarch64::*;
+
+    use glam::{Mat4, Vec3, Vec4};
+
+    #[inline(always)]
+    unsafe fn load_cols(m: &Mat4) -> [float32x4_t; 4] {
+        let cols = m.to_cols_array_2d();
+        [
+            vld1q_f32(cols[0].as_ptr()),
+            vld1q_f32(cols[1].as_ptr()),
+            vld1q_f32(cols[2].as_ptr()),
+            vld1q_f32(cols[3].as_ptr()),
+        ]
+    }
+
+    #[inline(always)]
+    unsafe fn store_cols(cols: [float32x4_t; 4]) -> Mat4 {
+        let mut out = [[0.0f32; 4]; 4];
+        for (out, col) in out.iter_mut().zip(cols.iter()) {
+            vst1q_f32(out.as_mut_ptr(), *col);
+        }
+        Mat4::from_cols_array_2d(&out)
+    }
+
+    // The columns times the column vector (x, y, z, w).
+    #[inline(always)]
+    unsafe fn mul_cols(cols: &[float32x4_t; 4], x: f32, y: f32, z: f32, w: f32) -> float32x4_t {
+        let xy = vaddq_f32(vmulq_n_f32(cols[0], x), vmulq_n_f32(cols[1], y));
+        let zw = vaddq_f32(vmulq_n_f32(cols[2], z), vmulq_n_f32(cols[3], w));
+        vaddq_f32(xy, zw)
+    }
+
+    /// `m` times the column vector `v`.
+    #[inline(always)]
+    pub fn mul_vec4(m: &Mat4, v: Vec4) -> Vec4 {
+        let mut out = [0.0f32; 4];
+        unsafe {
+            let v = mul_cols(&load_cols(m), v.x(), v.y(), v.z(), v.w());
+            vst1q_f32(out.as_mut_ptr(), v);
+        }
+        Vec4::new(out[0], out[1], out[2], out[3])
+    }
+
+    /// `m` times the column vector `v`, ignoring translation.
+    #[inline(always)]
+    pub fn transform_vector3(m: &Mat4, v: Vec3) -> Vec3 {
+        // The translation column is left out entirely rather than
+        // multiplied by zero, so that infinities in it don't make NaNs.
+        let mut out = [0.0f32; 4];
+        unsafe {
+            let cols = load_cols(m);
+            let xy = vaddq_f32(vmulq_n_f32(cols[0], v.x()), vmulq_n_f32(cols[1], v.y()));
+            vst1q_f32(out.as_mut_ptr(), vaddq_f32(xy, vmulq_n_f32(cols[2], v.z())));
+        }
+        Vec3::new(out[0], out[1], out[2])
+    }
+
+    /// `a` times `b`.
+    #[inline(always)]
+    pub fn mul_mat4(a: &Mat4, b: &Mat4) -> Mat4 {
+        let b = b.to_cols_array_2d();
+        unsafe {
+            let a = load_cols(a);
+            store_cols([
+                mul_cols(&a, b[0][0], b[0][1], b[0][2], b[0][3]),
+                mul_cols(&a, b[1][0], b[1][1], b[1][2], b[1][3]),
+                mul_cols(&a, b[2][0], b[2][1], b[2][2], b[2][3]),
+                mul_cols(&a, b[3][0], b[3][1], b[3][2], b[3][3]),
+            ])
+        }
+    }
+
+    #[inline(always)]
+    pub fn mul_scalar(m: &Mat4, s: f32) -> Mat4 {
+        unsafe {
+            let m = load_cols(m);
+            store_cols([
+                vmulq_n_f32(m[0], s),
+                vmulq_n_f32(m[1], s),
+                vmulq_n_f32(m[2], s),
+                vmulq_n_f32(m[3], s),
+            ])
+        }
+    }
+
+    #[inline(always)]
+    pub fn add(a: &Mat4, b: &Mat4) -> Mat4 {
+        unsafe {
+            let a = load_cols(a);
+            let b = load_cols(b);
+            store_cols([
+                vaddq_f32(a[0], b[0]),
+                vaddq_f32(a[1], b[1]),
+                vaddq_f32(a[2], b[2]),
+                vaddq_f32(a[3], b[3]),
+            ])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::{Mat4, Vec3, Vec4};
+
+    // A simple deterministic hash to [-2, 2), so the tests don't need a
+    // random number generator.
+    fn hash_to_range(n: u32) -> f32 {
+        let mut h = n.wrapping_mul(0x9e37_79b9);
+        h ^= h >> 16;
+        h = h.wrapping_mul(0x7feb_352d);
+        h ^= h >> 15;
+        h = h.wrapping_mul(0x846c_a68b);
+        h ^= h >> 16;
+        (h >> 8) as f32 / (1 << 22) as f32 - 2.0
+    }
+
+    fn matrix(seed: u32) -> Mat4 {
+        let mut cols = [[0.0f32; 4]; 4];
+        for (i, x) in cols.iter_mut().flat_map(|col| col.iter_mut()).enumerate() {
+            *x = hash_to_range(seed * 16 + i as u32);
+        }
+        Mat4::from_cols_array_2d(&cols)
+    }
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!((a - b).abs() <= 1.0e-5 * (1.0 + b.abs()), "{} vs {}", a, b);
+        }
+    }
+
+    // Whichever versions are in use should agree with glam's, which on
+    // aarch64 are its scalar fallback.
+    #[test]
+    fn ops_match_glam() {
+        for i in 0..100 {
+            let a = matrix(i * 2);
+            let b = matrix(i * 2 + 1);
+            let v4 = Vec4::new(
+                hash_to_range(i + 5000),
+                hash_to_range(i + 6000),
+                hash_to_range(i + 7000),
+                1.0,
+            );
+            let v3 = v4.truncate();
+
+            let lanes4 = |v: Vec4| [v.x(), v.y(), v.z(), v.w()];
+            let lanes3 = |v: Vec3| [v.x(), v.y(), v.z()];
+            assert_close(&lanes4(mul_vec4(&a, v4)), &lanes4(a.mul_vec4(v4)));
+            assert_close(
+                &lanes3(transform_vector3(&a, v3)),
+                &lanes3(a.transform_vector3(v3)),
+            );
+            assert_close(
+                &mul_mat4(&a, &b).to_cols_array(),
+                &a.mul_mat4(&b).to_cols_array(),
+            );
+            assert_close(
+                &mul_scalar(&a, 0.3).to_cols_array(),
+                &(a * 0.3).to_cols_array(),
+            );
+            assert_close(&add(&a, &b).to_cols_array(), &(a + b).to_cols_array());
+        }
+    }
+}
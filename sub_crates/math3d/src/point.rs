@@ -7,7 +7,7 @@ use std::{
 
 use glam::Vec4;
 
-use super::{Matrix4x4, Vector};
+use super::{ops, Matrix4x4, Vector};
 
 /// A position in 3d homogeneous space.
 #[derive(Debug, Copy, Clone)]
@@ -146,7 +146,7 @@ impl Mul<Matrix4x4> for Point {
     #[inline]
     fn mul(self, other: Matrix4x4) -> Point {
         Point {
-            co: other.0.mul_vec4(self.co),
+            co: ops::mul_vec4(&other.0, self.co),
         }
     }
 }
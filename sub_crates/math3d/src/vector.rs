@@ -7,7 +7,7 @@ use std::{
 
 use glam::Vec3;
 
-use super::{CrossProduct, DotProduct, Matrix4x4, Normal, Point};
+use super::{ops, CrossProduct, DotProduct, Matrix4x4, Normal, Point};
 
 /// A direction vector in 3d homogeneous space.
 #[derive(Debug, Copy, Clone)]
@@ -146,7 +146,7 @@ impl Mul<Matrix4x4> for Vector {
     #[inline]
     fn mul(self, other: Matrix4x4) -> Vector {
         Vector {
-            co: other.0.transform_vector3(self.co),
+            co: ops::transform_vector3(&other.0, self.co),
         }
     }
 }
@@ -0,0 +1,272 @@
+//! Statistics for judging how well distributed a set of sample points is,
+//! so that changes to a sampler can be checked by the numbers rather than
+//! by looking at renders.
+//!
+//! The samplers (e.g. the `halton` and `sobol` crates) are all functions
+//! of a dimension and a sample index, so everything here works on the 2D
+//! projections of a sampler's dimensions: the points made from two of its
+//! dimensions over the same indices.  Badly correlated pairs of
+//! dimensions are what show up as patterns in renders.
+
+use std::{
+    env,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// The environment variable that `pairwise_stats()` checks for a
+/// directory to write the projections to, for plotting.
+pub const PLOT_DIR_VAR: &str = "SAMPLE_STATS_DIR";
+
+/// The statistics of one 2D projection of a sampler.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PairStats {
+    pub dimensions: (u32, u32),
+    pub star_discrepancy: f64,
+    pub correlation: f64,
+}
+
+/// The points made from dimensions `dimensions.0` and `dimensions.1` of
+/// `sample`, for the indices `0..count`.
+pub fn projection<F>(sample: F, dimensions: (u32, u32), count: u32) -> Vec<[f32; 2]>
+where
+    F: Fn(u32, u32) -> f32,
+{
+    (0..count)
+        .map(|i| [sample(dimensions.0, i), sample(dimensions.1, i)])
+        .collect()
+}
+
+/// Computes the statistics of every pair of the first `dimensions`
+/// dimensions of `sample`, using `count` points.
+///
+/// If the `SAMPLE_STATS_DIR` environment variable is set, each projection
+/// is also written to that directory as "{name}_{a}_{b}.csv", for
+/// plotting.
+pub fn pairwise_stats<F>(name: &str, sample: F, dimensions: u32, count: u32) -> Vec<PairStats>
+where
+    F: Fn(u32, u32) -> f32,
+{
+    let plot_dir = env::var_os(PLOT_DIR_VAR);
+    let mut stats = Vec::new();
+    for a in 0..dimensions {
+        for b in (a + 1)..dimensions {
+            let points = projection(&sample, (a, b), count);
+            if let Some(ref dir) = plot_dir {
+                let path = Path::new(dir).join(format!("{}_{}_{}.csv", name, a, b));
+                File::create(&path)
+                    .and_then(|f| write_csv(BufWriter::new(f), &points))
+                    .unwrap_or_else(|e| panic!("Couldn't write '{}': {}", path.display(), e));
+            }
+            stats.push(PairStats {
+                dimensions: (a, b),
+                star_discrepancy: star_discrepancy(&points),
+                correlation: correlation(&points),
+            });
+        }
+    }
+    stats
+}
+
+/// The star discrepancy of a set of points in the unit square: the
+/// largest difference, over all boxes with a corner at the origin,
+/// between the fraction of the points in the box and the box's area.
+///
+/// It's zero for a perfect distribution, and the lower the better.  Good
+/// low-discrepancy sets of N points have a discrepancy of about
+/// log(N)^2 / N, whereas uniform random points are about 1 / sqrt(N).
+///
+/// This is computed exactly, in O(N^2) time, so it's meant for sets
+/// of up to a few thousand points.
+pub fn star_discrepancy(points: &[[f32; 2]]) -> f64 {
+    if points.is_empty() {
+        return 0.0;
+    }
+    let n = points.len() as f64;
+
+    // The discrepancy is always largest at a box whose far corner has
+    // coordinates from the points or at the edge of the square, either
+    // just including or just excluding the points on its edges.
+    let mut by_x: Vec<[f64; 2]> = points.iter().map(|p| [p[0] as f64, p[1] as f64]).collect();
+    by_x.sort_by(|a, b| a[0].partial_cmp(&b[0]).unwrap());
+    let mut xs: Vec<f64> = by_x.iter().map(|p| p[0]).chain(Some(1.0)).collect();
+    xs.dedup();
+    let mut ys: Vec<f64> = by_x.iter().map(|p| p[1]).chain(Some(1.0)).collect();
+    ys.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    ys.dedup();
+
+    let mut discrepancy = 0.0f64;
+    let mut inside: Vec<f64> = Vec::with_capacity(points.len()); // Sorted y's of the points left of x
+    let mut next = 0;
+    for &x in &xs {
+        // Boxes that exclude the points on their edges.  Both lists are
+        // sorted, so the counts are found by walking them together.
+        let mut count = 0;
+        for &y in &ys {
+            while count < inside.len() && inside[count] < y {
+                count += 1;
+            }
+            discrepancy = discrepancy.max((x * y) - (count as f64 / n));
+        }
+
+        while next < by_x.len() && by_x[next][0] <= x {
+            let py = by_x[next][1];
+            let i = inside.partition_point(|&iy| iy < py);
+            inside.insert(i, py);
+            next += 1;
+        }
+
+        // Boxes that include the points on their edges.
+        let mut count = 0;
+        for &y in &ys {
+            while count < inside.len() && inside[count] <= y {
+                count += 1;
+            }
+            discrepancy = discrepancy.max((count as f64 / n) - (x * y));
+        }
+    }
+
+    discrepancy
+}
+
+/// The Pearson correlation coefficient between the two coordinates of
+/// the points: zero when they're uncorrelated, and one or negative one
+/// when they're entirely correlated.
+pub fn correlation(points: &[[f32; 2]]) -> f64 {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p[0] as f64).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p[1] as f64).sum::<f64>() / n;
+    let mut cov = 0.0;
+    let mut var_x = 0.0;
+    let mut var_y = 0.0;
+    for p in points {
+        let dx = p[0] as f64 - mean_x;
+        let dy = p[1] as f64 - mean_y;
+        cov += dx * dy;
+        var_x += dx * dx;
+        var_y += dy * dy;
+    }
+    if var_x == 0.0 || var_y == 0.0 {
+        0.0
+    } else {
+        cov / (var_x * var_y).sqrt()
+    }
+}
+
+/// Writes the points as CSV, with an "x,y" header and one point per line.
+pub fn write_csv<W: Write>(mut out: W, points: &[[f32; 2]]) -> io::Result<()> {
+    writeln!(out, "x,y")?;
+    for p in points {
+        writeln!(out, "{},{}", p[0], p[1])?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A simple deterministic hash to [0, 1), so the tests don't need a
+    // random number generator.
+    fn hash_to_unit(n: u32, seed: u32) -> f32 {
+        let mut h = n.wrapping_mul(0x9e37_79b9) ^ seed.wrapping_mul(0x85eb_ca6b);
+        h ^= h >> 16;
+        h = h.wrapping_mul(0x7feb_352d);
+        h ^= h >> 15;
+        h = h.wrapping_mul(0x846c_a68b);
+        h ^= h >> 16;
+        (h >> 8) as f32 / (1 << 24) as f32
+    }
+
+    // Star discrepancy by testing every candidate box directly, in
+    // O(N^3) time.
+    fn brute_force_star_discrepancy(points: &[[f32; 2]]) -> f64 {
+        let n = points.len() as f64;
+        let mut corners: Vec<f64> = points
+            .iter()
+            .flat_map(|p| p.to_vec())
+            .map(|c| c as f64)
+            .collect();
+        corners.push(1.0);
+        let mut discrepancy = 0.0f64;
+        for &x in &corners {
+            for &y in &corners {
+                let open = points
+                    .iter()
+                    .filter(|p| (p[0] as f64) < x && (p[1] as f64) < y)
+                    .count() as f64;
+                let closed = points
+                    .iter()
+                    .filter(|p| (p[0] as f64) <= x && (p[1] as f64) <= y)
+                    .count() as f64;
+                discrepancy = discrepancy.max((x * y) - (open / n));
+                discrepancy = discrepancy.max((closed / n) - (x * y));
+            }
+        }
+        discrepancy
+    }
+
+    #[test]
+    fn star_discrepancy_of_one_point() {
+        // The box up to and including the point has all of the points
+        // but only a quarter of the area.
+        assert_eq!(star_discrepancy(&[[0.5, 0.5]]), 0.75);
+        assert_eq!(star_discrepancy(&[[0.0, 0.0]]), 1.0);
+        assert_eq!(star_discrepancy(&[]), 0.0);
+    }
+
+    #[test]
+    fn star_discrepancy_matches_brute_force() {
+        for seed in 0..10 {
+            let points: Vec<[f32; 2]> = (0..50)
+                .map(|i| [hash_to_unit(i, seed * 2), hash_to_unit(i, seed * 2 + 1)])
+                .collect();
+            let expected = brute_force_star_discrepancy(&points);
+            assert!((star_discrepancy(&points) - expected).abs() < 1.0e-12);
+        }
+
+        // Points that share coordinates.
+        let grid: Vec<[f32; 2]> = (0..64)
+            .map(|i| [(i % 8) as f32 / 8.0, (i / 8) as f32 / 8.0])
+            .collect();
+        let expected = brute_force_star_discrepancy(&grid);
+        assert!((star_discrepancy(&grid) - expected).abs() < 1.0e-12);
+    }
+
+    #[test]
+    fn stratified_beats_random() {
+        let random: Vec<[f32; 2]> = (0..1024)
+            .map(|i| [hash_to_unit(i, 0), hash_to_unit(i, 1)])
+            .collect();
+        // The 2D Hammersley set.
+        let hammersley: Vec<[f32; 2]> = (0..1024u32)
+            .map(|i| [i as f32 / 1024.0, (i.reverse_bits() >> 22) as f32 / 1024.0])
+            .collect();
+        assert!(star_discrepancy(&hammersley) * 4.0 < star_discrepancy(&random));
+    }
+
+    #[test]
+    fn correlation_of_lines() {
+        let points: Vec<[f32; 2]> = (0..100).map(|i| [i as f32, i as f32 * 0.5]).collect();
+        assert!((correlation(&points) - 1.0).abs() < 1.0e-9);
+        let points: Vec<[f32; 2]> = (0..100).map(|i| [i as f32, -(i as f32)]).collect();
+        assert!((correlation(&points) + 1.0).abs() < 1.0e-9);
+        let points: Vec<[f32; 2]> = (0..100).map(|i| [i as f32, 3.0]).collect();
+        assert_eq!(correlation(&points), 0.0);
+    }
+
+    #[test]
+    fn pairwise_stats_covers_every_pair() {
+        let stats = pairwise_stats("test", |d, i| hash_to_unit(i, d), 4, 64);
+        let pairs: Vec<(u32, u32)> = stats.iter().map(|s| s.dimensions).collect();
+        assert_eq!(pairs, [(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)]);
+    }
+
+    #[test]
+    fn csv_output() {
+        let mut out = Vec::new();
+        write_csv(&mut out, &[[0.5, 0.25], [0.0, 1.0]]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "x,y\n0.5,0.25\n0,1\n");
+    }
+}
@@ -0,0 +1,47 @@
+use bencher::{benchmark_group, benchmark_main, black_box, Bencher};
+use sobol::{sample, sample_owen, sample_owen_cranley, sample_rd};
+
+//----
+
+fn sample_1000_values(bench: &mut Bencher) {
+    bench.iter(|| {
+        for i in 0..1000 {
+            black_box(sample(black_box(3), i));
+        }
+    });
+}
+
+fn sample_rd_1000_values(bench: &mut Bencher) {
+    bench.iter(|| {
+        for i in 0..1000 {
+            black_box(sample_rd(black_box(3), i, black_box(0x1234_5678)));
+        }
+    });
+}
+
+fn sample_owen_1000_values(bench: &mut Bencher) {
+    bench.iter(|| {
+        for i in 0..1000 {
+            black_box(sample_owen(black_box(3), i, black_box(0x1234_5678)));
+        }
+    });
+}
+
+fn sample_owen_cranley_1000_values(bench: &mut Bencher) {
+    bench.iter(|| {
+        for i in 0..1000 {
+            black_box(sample_owen_cranley(black_box(3), i, black_box(0x1234_5678)));
+        }
+    });
+}
+
+//----
+
+benchmark_group!(
+    benches,
+    sample_1000_values,
+    sample_rd_1000_values,
+    sample_owen_1000_values,
+    sample_owen_cranley_1000_values,
+);
+benchmark_main!(benches);
@@ -0,0 +1,58 @@
+//! Checks that the Sobol sequence and its scrambled variants are well
+//! distributed, by the numbers.
+//!
+//! Set `SAMPLE_STATS_DIR` to a directory to also get the 2D projections
+//! written there as CSV files, for plotting.
+
+use sample_stats::pairwise_stats;
+
+/// The number of dimensions to check every pair of.
+const DIMENSIONS: u32 = 8;
+
+/// The number of points per projection.
+const COUNT: u32 = 1024;
+
+// For reference, uniform random points at this count have a star
+// discrepancy of about 0.025 to 0.065, and a correlation of about 0.03
+// (and up to about 0.1) between a pair of dimensions.  The correlation
+// limit is loose, since scrambling makes it partly random too, and is
+// mainly there to catch dimensions that are copies of each other.
+const MAX_STAR_DISCREPANCY: f64 = 0.02;
+const MAX_CORRELATION: f64 = 0.05;
+
+/// A scramble value per dimension, like the renderer uses.
+fn scramble(dimension: u32, seed: u32) -> u32 {
+    let mut h = dimension.wrapping_mul(0x9e37_79b9) ^ seed.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^ (h >> 16)
+}
+
+#[test]
+fn projections_are_well_distributed() {
+    for stats in pairwise_stats("sobol", sobol::sample, DIMENSIONS, COUNT) {
+        assert!(stats.star_discrepancy < MAX_STAR_DISCREPANCY, "{:?}", stats);
+        assert!(stats.correlation.abs() < MAX_CORRELATION, "{:?}", stats);
+    }
+}
+
+#[test]
+fn scrambled_projections_are_well_distributed() {
+    for seed in 0..2 {
+        let owen = |d, i| sobol::sample_owen(d, i, scramble(d, seed));
+        let owen_cranley = |d, i| sobol::sample_owen_cranley(d, i, scramble(d, seed));
+        let mut all_stats = pairwise_stats(&format!("sobol_owen{}", seed), owen, DIMENSIONS, COUNT);
+        all_stats.extend(pairwise_stats(
+            &format!("sobol_owen_cranley{}", seed),
+            owen_cranley,
+            DIMENSIONS,
+            COUNT,
+        ));
+        for stats in all_stats {
+            assert!(stats.star_discrepancy < MAX_STAR_DISCREPANCY, "{:?}", stats);
+            assert!(stats.correlation.abs() < MAX_CORRELATION, "{:?}", stats);
+        }
+    }
+}